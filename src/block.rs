@@ -2,7 +2,7 @@ use std::{io::Cursor, collections::HashMap, cmp::max, sync::Arc};
 use std::io::Write;
 use ton_block::{
     Block, BlockIdExt, BlkPrevInfo, CatchainConfig, ConfigParams, Deserializable,
-    ShardIdent, ShardDescr, ShardHashes, ValidatorSet, HashmapAugType,
+    InMsg, ShardIdent, ShardDescr, ShardHashes, ValidatorSet, HashmapAugType,
 };
 use ton_types::{Cell, Result, types::UInt256, deserialize_tree_of_cells, error, fail, HashmapType};
 
@@ -236,6 +236,52 @@ impl BlockStuff {
         log::trace!("calculate_tr_count: transactions {}, TIME: {}ms, block: {}", tr_count, now.elapsed().as_millis(), self.id());
         Ok(tr_count)
     }
+
+    // Accounts, logical times and hashes of all transactions
+    pub fn transactions_ids(&self) -> Result<Vec<(UInt256, u64, UInt256)>> {
+        let mut result = Vec::new();
+        self.block.read_extra()?.read_account_blocks()?.iterate_objects(|account_block| {
+            let account = UInt256::from_slice(&account_block.account_id().get_bytestring(0));
+            account_block.transactions().iterate_slices_with_keys(|lt, transaction| {
+                result.push((account.clone(), lt, transaction.reference(0)?.repr_hash()));
+                Ok(true)
+            })?;
+            Ok(true)
+        })?;
+        Ok(result)
+    }
+
+    pub fn find_transaction(&self, account: &UInt256, lt: u64) -> Result<Option<Cell>> {
+        let mut found = None;
+        self.block.read_extra()?.read_account_blocks()?.iterate_objects(|account_block| {
+            if UInt256::from_slice(&account_block.account_id().get_bytestring(0)) != *account {
+                return Ok(true)
+            }
+            account_block.transactions().iterate_slices_with_keys(|tr_lt, transaction| {
+                if tr_lt == lt {
+                    found = Some(transaction.reference(0)?);
+                    return Ok(false)
+                }
+                Ok(true)
+            })?;
+            Ok(false)
+        })?;
+        Ok(found)
+    }
+
+    // Hashes of imported inbound external messages and transactions processed them
+    pub fn ext_in_messages(&self) -> Result<Vec<(UInt256, UInt256)>> {
+        let mut result = Vec::new();
+        self.block.read_extra()?.read_in_msg_descr()?.iterate_with_keys(|key, in_msg| {
+            if let InMsg::External(_) = in_msg {
+                if let Some(tr_cell) = in_msg.transaction_cell() {
+                    result.push((key, tr_cell.repr_hash()));
+                }
+            }
+            Ok(true)
+        })?;
+        Ok(result)
+    }
 }
 
 pub trait BlockIdExtExtention {
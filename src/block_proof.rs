@@ -142,6 +142,17 @@ impl BlockProofStuff {
         Ok(())
     }
 
+    // Hardfork block is trusted by its id in global config: it is signed by validators
+    // which can't be found from previous key block
+    pub fn check_hardfork_proof(&self) -> Result<()> {
+        log::trace!("Checking proof for hardfork block: {}", self.id());
+        let (virt_block, virt_block_info) = self.pre_check_block_proof()?;
+        if virt_block_info.key_block() {
+            self.pre_check_key_block_proof(&virt_block)?;
+        }
+        Ok(())
+    }
+
     pub fn check_proof_link(&self) -> Result<()> {
         let now = std::time::Instant::now();
         log::trace!("Checking proof for block: {}", self.id());
@@ -159,6 +170,8 @@ impl BlockProofStuff {
     pub async fn check_proof(&self, engine: &dyn EngineOperations) -> Result<()> {
         if self.is_link() {
             self.check_proof_link()?;
+        } else if engine.is_hardfork(self.id()) {
+            self.check_hardfork_proof()?;
         } else {
             let now = std::time::Instant::now();
             log::trace!("Checking proof for block: {}", self.id());
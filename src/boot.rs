@@ -1,15 +1,115 @@
 use crate::{
-    CHECK, block::BlockStuff, block_proof::BlockProofStuff, engine_traits::EngineOperations, 
-    shard_state::ShardStateStuff
+    CHECK, block::BlockStuff, block_proof::BlockProofStuff, engine_traits::EngineOperations,
+    error::{BootError, NodeError}, shard_state::ShardStateStuff
 };
 use adnl::common::KeyId;
-use std::{ops::Deref, sync::Arc, time::Duration};
+use futures::StreamExt;
+use std::{
+    collections::HashSet, ops::Deref, sync::{Arc, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant}
+};
 use storage::types::BlockHandle;
 use ton_block::{BlockIdExt, ShardIdent, SHARD_FULL};
 use ton_types::{error, fail, Result};
 
 pub const PSS_PERIOD_BITS_CLASSIC: u32 = 17;
 pub const PSS_PERIOD_BITS: u32 = 11;
+// Key block proofs downloaded ahead of their checks
+const KEY_BLOCK_PROOFS_DOWNLOAD_AHEAD: usize = 5;
+const KEY_BLOCK_PROOFS_CHECK_PARALLEL: usize = 8;
+
+/// check init block of node config against zero state and init block of global config
+pub fn check_trusted_init_block(
+    init_block: &BlockIdExt,
+    zero_state_id: &BlockIdExt,
+    global_init_block: Option<&BlockIdExt>
+) -> Result<()> {
+    if !init_block.shard().is_masterchain() {
+        fail!(NodeError::Boot(
+            BootError::WrongInitBlock, format!("init_block {} must be a masterchain block", init_block)
+        ))
+    }
+    if init_block.seq_no() == 0 && init_block != zero_state_id {
+        fail!(NodeError::Boot(BootError::WrongInitBlock, format!(
+            "init_block {} has seq_no 0 but differs from zero state {} of global config",
+            init_block, zero_state_id
+        )))
+    }
+    if let Some(global_init_block) = global_init_block {
+        if global_init_block.seq_no() == init_block.seq_no() && global_init_block != init_block {
+            fail!(NodeError::Boot(BootError::WrongInitBlock, format!(
+                "init_block {} differs from init block {} of global config, \
+                they belong to different networks",
+                init_block, global_init_block
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// init block must be of the same network as zero state if it is loaded (e.g. by --zerostate)
+async fn check_init_block_network(engine: &dyn EngineOperations, proof: &BlockProofStuff) -> Result<()> {
+    let zero_state_id = engine.zero_state_id();
+    match engine.load_block_handle(zero_state_id)? {
+        Some(handle) if handle.has_state() => (),
+        _ => return Ok(())
+    }
+    let zero_state = engine.load_state(zero_state_id).await?;
+    let (block, _) = proof.virtualize_block()?;
+    if block.global_id() != zero_state.state().global_id() {
+        fail!(NodeError::Boot(BootError::WrongInitBlock, format!(
+            "Init block {} belongs to network {}, but zero state {} belongs to network {}",
+            proof.id(), block.global_id(), zero_state_id, zero_state.state().global_id()
+        )))
+    }
+    Ok(())
+}
+
+/// check that key block chain goes from `prev` to `next` through all hardforks between them
+pub(crate) fn check_key_block_hardforks(
+    hardforks: &[BlockIdExt],
+    prev: &BlockIdExt,
+    next: &BlockIdExt
+) -> Result<()> {
+    for hardfork in hardforks {
+        if next.seq_no() == hardfork.seq_no() && next != hardfork {
+            fail!(NodeError::Boot(
+                BootError::Hardfork,
+                format!("Key block {} is on pre-fork branch of hardfork {}", next, hardfork)
+            ))
+        }
+        if prev.seq_no() < hardfork.seq_no() && next.seq_no() > hardfork.seq_no() {
+            fail!(NodeError::Boot(
+                BootError::Hardfork,
+                format!("Key block chain goes from {} to {} bypassing hardfork {}", prev, next, hardfork)
+            ))
+        }
+    }
+    Ok(())
+}
+
+/// check that masterchain block is neither on pre-fork branch nor built on it
+pub(crate) fn check_block_hardforks(
+    hardforks: &[BlockIdExt],
+    id: &BlockIdExt,
+    prev_id: &BlockIdExt
+) -> Result<()> {
+    for hardfork in hardforks {
+        if id.seq_no() == hardfork.seq_no() && id != hardfork {
+            fail!(NodeError::Boot(
+                BootError::Hardfork,
+                format!("Block {} is on pre-fork branch of hardfork {}", id, hardfork)
+            ))
+        }
+        if prev_id.seq_no() == hardfork.seq_no() && prev_id != hardfork {
+            fail!(NodeError::Boot(
+                BootError::Hardfork,
+                format!("Block {} is built on pre-fork block {} of hardfork {}", id, prev_id, hardfork)
+            ))
+        }
+    }
+    Ok(())
+}
 
 /// cold boot entry point
 /// download zero state or block proof link and check it
@@ -19,7 +119,14 @@ async fn run_cold(
     let block_id = engine.init_mc_block_id();
     log::info!(target: "boot", "cold boot start: init_block_id={}", block_id);
     CHECK!(block_id.shard().is_masterchain());
-    CHECK!(block_id.seq_no >= engine.get_last_fork_masterchain_seqno());
+    // Init block may be older than the last hardfork, then key block chain goes through it
+    if block_id.seq_no() < engine.get_last_fork_masterchain_seqno() {
+        log::info!(
+            target: "boot",
+            "init block {} is older than the last hardfork {}",
+            block_id, engine.get_last_fork_masterchain_seqno()
+        );
+    }
     if block_id.seq_no() == 0 {
         let (handle, zero_state) = download_zero_state(engine, &block_id).await?;
         Ok((handle, Some(zero_state), None))
@@ -40,6 +147,7 @@ async fn run_cold(
                     }
                 };
                 CHECK!(handle.is_key_block()?);
+                check_init_block_network(engine, &proof).await?;
                 return Ok((handle, None, Some(proof)))
             }
             Some(handle)
@@ -75,6 +183,7 @@ async fn run_cold(
             futures_timer::Delay::new(Duration::from_secs(1)).await;
         };
         CHECK!(handle.is_key_block()?);
+        check_init_block_network(engine, &proof).await?;
         Ok((handle, None, Some(proof)))
     }
 }
@@ -107,11 +216,29 @@ async fn get_key_blocks(
         };
         if let Some(block_id) = ids.last() {
             log::info!(target: "boot", "last key block is {}", block_id);
+            let mut next_ids = Vec::new();
+            let mut last_id = handle.id();
             for block_id in &ids {
+                // Chain starts from init block and goes only forward
+                if block_id.seq_no() <= last_id.seq_no() {
+                    log::warn!(
+                        target: "boot",
+                        "refusing key block {} which is not newer than {}", block_id, last_id
+                    );
+                    break
+                }
+                if let Err(err) = check_key_block_hardforks(engine.get_hardforks(), last_id, block_id) {
+                    log::warn!(target: "boot", "refusing key block: {}", err);
+                    break
+                }
+                next_ids.push(block_id.clone());
+                last_id = block_id;
+            }
+            let proofs = download_key_block_proofs(
+                engine, &next_ids, zero_state, prev_block_proof.as_ref()
+            ).await?;
+            for (next_handle, proof) in proofs {
                 //let prev_time = handle.gen_utime()?;
-                let (next_handle, proof) = download_key_block_proof(
-                    engine, block_id, zero_state, prev_block_proof.as_ref()
-                ).await?;
                 handle = next_handle;
                 CHECK!(handle.is_key_block()?);
                 CHECK!(handle.gen_utime()? != 0);
@@ -173,7 +300,7 @@ async fn choose_masterchain_state(
         }
     }
     CHECK!(key_blocks.is_empty());
-    fail!("Cannot boot node")
+    fail!(NodeError::Boot(BootError::NoState, "Cannot boot node".to_string()))
 }
 
 /// Download zerostate for all workchains
@@ -191,31 +318,45 @@ async fn download_wc_zerosates(engine: &dyn EngineOperations, zerostate: &ShardS
     Ok(())
 }
 
-/// Download persistent master block & state, enumerate shards and download block & state for each
+/// Download persistent master block, enumerate shards and download master and shard states
+/// in parallel
 async fn download_start_blocks_and_states(
     engine: &dyn EngineOperations, 
     master_id: &BlockIdExt
 ) -> Result<()> {
 
     let active_peers = Arc::new(lockfree::set::Set::new());
-    let (master_handle, init_mc_block) = 
-        download_block_and_state(engine, master_id, master_id, &active_peers).await?;
+    let (master_handle, init_mc_block) = download_block(engine, master_id).await?;
+    let shard_blocks = init_mc_block.shards_blocks()?;
+    let shards = shard_blocks.values().filter(|id| engine.is_shard_tracked(id.shard())).map(
+        |block_id| download_start_shard_state(engine, block_id, master_id, &active_peers)
+    );
+    futures::future::try_join(
+        download_state(engine, &master_handle, &init_mc_block, master_id, &active_peers),
+        futures::future::try_join_all(shards)
+    ).await?;
     CHECK!(master_handle.has_state());
     CHECK!(master_handle.is_applied());
-
-    for (_, block_id) in init_mc_block.shards_blocks()? {
-        let shard_handle = if block_id.seq_no() == 0 {
-            download_zero_state(engine, &block_id).await?.0
-        } else {
-            download_block_and_state(engine, &block_id, master_id, &active_peers).await?.0
-        };
-        CHECK!(shard_handle.has_state());
-        CHECK!(shard_handle.is_applied());
-    }
     Ok(())
 
 }
 
+async fn download_start_shard_state(
+    engine: &dyn EngineOperations,
+    block_id: &BlockIdExt,
+    master_id: &BlockIdExt,
+    active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+) -> Result<()> {
+    let shard_handle = if block_id.seq_no() == 0 {
+        download_zero_state(engine, block_id).await?.0
+    } else {
+        download_block_and_state(engine, block_id, master_id, active_peers).await?.0
+    };
+    CHECK!(shard_handle.has_state());
+    CHECK!(shard_handle.is_applied());
+    Ok(())
+}
+
 /// download zero state and store it
 pub(crate) async fn download_zero_state(
     engine: &dyn EngineOperations, 
@@ -255,13 +396,9 @@ async fn download_key_block_proof(
     }
     loop {
         let proof = engine.download_block_proof(block_id, false, true).await?;
-        let result = if let Some(prev_block_proof) = prev_block_proof {
-            proof.check_with_prev_key_block_proof(prev_block_proof)
-        } else if let Some(zero_state) = zero_state {
-            proof.check_with_master_state(zero_state)
-        } else {
-            unreachable!("Impossible variant")
-        };
+        let result = check_key_block_proof(
+            &proof, prev_block_proof, zero_state, engine.is_hardfork(block_id)
+        );
         match result {
             Ok(_) => {
                 let handle = engine.store_block_proof(block_id, None, &proof).await?
@@ -279,35 +416,195 @@ async fn download_key_block_proof(
     }
 }
 
+/// download key block proofs ahead, check them concurrently and store them in order.
+/// Chain is cut after the first proof failed to check, it is redownloaded then
+pub(crate) async fn download_key_block_proofs(
+    engine: &dyn EngineOperations,
+    ids: &[BlockIdExt],
+    zero_state: Option<&ShardStateStuff>,
+    prev_block_proof: Option<&BlockProofStuff>,
+) -> Result<Vec<(Arc<BlockHandle>, BlockProofStuff)>> {
+    let downloaded = futures::stream::iter(ids)
+        .map(|id| load_or_download_key_block_proof(engine, id))
+        .buffered(KEY_BLOCK_PROOFS_DOWNLOAD_AHEAD)
+        .collect::<Vec<_>>()
+        .await;
+    let mut handles = Vec::new();
+    let mut proofs = Vec::new();
+    for result in downloaded {
+        let (handle, proof) = result?;
+        handles.push(handle);
+        proofs.push(proof);
+    }
+
+    // Stored proofs are checked already
+    let stored = handles.iter().flatten().map(|handle| handle.id().clone()).collect::<HashSet<_>>();
+    let hardforks = engine.get_hardforks().to_vec();
+    let first_zero_state = match prev_block_proof {
+        Some(_) => None,
+        None => zero_state.cloned().map(Arc::new)
+    };
+    let results = check_chain_links(
+        prev_block_proof.cloned(),
+        &proofs,
+        KEY_BLOCK_PROOFS_CHECK_PARALLEL,
+        move |proof, prev| if stored.contains(proof.id()) {
+            Ok(())
+        } else {
+            check_key_block_proof(
+                proof, prev, first_zero_state.as_deref(), hardforks.contains(proof.id())
+            )
+        }
+    ).await?;
+
+    let mut checked: Vec<(Arc<BlockHandle>, BlockProofStuff)> = Vec::new();
+    for ((handle, proof), result) in handles.into_iter().zip(proofs).zip(results) {
+        if let Err(err) = result {
+            log::warn!(target: "boot", "check_proof error: {}", err);
+            let prev = checked.last().map(|(_, proof)| proof).or(prev_block_proof);
+            checked.push(download_key_block_proof(engine, proof.id(), zero_state, prev).await?);
+            break
+        }
+        let handle = match handle {
+            Some(handle) => handle,
+            None => engine.store_block_proof(proof.id(), None, &proof).await?
+                .as_non_created()
+                .ok_or_else(
+                    || error!("INTERNAL ERROR: Bad result in store block {} proof", proof.id())
+                )?
+        };
+        checked.push((handle, proof));
+    }
+    Ok(checked)
+}
+
+async fn load_or_download_key_block_proof(
+    engine: &dyn EngineOperations,
+    block_id: &BlockIdExt,
+) -> Result<(Option<Arc<BlockHandle>>, BlockProofStuff)> {
+    if let Some(handle) = engine.load_block_handle(block_id)? {
+        if let Ok(proof) = engine.load_block_proof(&handle, false).await {
+            return Ok((Some(handle), proof))
+        }
+    }
+    Ok((None, engine.download_block_proof(block_id, false, true).await?))
+}
+
+/// check key block proof with the previous key block proof, or with zero state for the first one
+fn check_key_block_proof(
+    proof: &BlockProofStuff,
+    prev_block_proof: Option<&BlockProofStuff>,
+    zero_state: Option<&ShardStateStuff>,
+    is_hardfork: bool,
+) -> Result<()> {
+    if is_hardfork {
+        proof.check_hardfork_proof()
+    } else if let Some(prev_block_proof) = prev_block_proof {
+        proof.check_with_prev_key_block_proof(prev_block_proof)
+    } else if let Some(zero_state) = zero_state {
+        proof.check_with_master_state(zero_state)
+    } else {
+        fail!("INTERNAL ERROR: No previous key block proof or zero state to check {}", proof.id())
+    }
+}
+
+/// Check links of chain on blocking threads, at most `max_parallel` at once: each item is
+/// checked with the previous one (`prev` for the first item). Signatures of an item depend
+/// only on data of the previous one, which is trusted after its own check, so results
+/// (in order of items) are valid up to the first failed link.
+async fn check_chain_links<T, F>(
+    prev: Option<T>,
+    items: &[T],
+    max_parallel: usize,
+    check: F
+) -> Result<Vec<Result<()>>>
+where
+    T: Clone + Send + 'static,
+    F: Fn(&T, Option<&T>) -> Result<()> + Clone + Send + 'static
+{
+    let started = Instant::now();
+    // Total time of checks in microseconds
+    let busy = Arc::new(AtomicU64::new(0));
+    let mut prev = prev;
+    let links = items.iter()
+        .map(|item| (item.clone(), prev.replace(item.clone())))
+        .collect::<Vec<_>>();
+    let results = futures::stream::iter(links)
+        .map(|(item, prev_item)| {
+            let check = check.clone();
+            let busy = busy.clone();
+            tokio::task::spawn_blocking(move || {
+                let now = Instant::now();
+                let result = check(&item, prev_item.as_ref());
+                busy.fetch_add(now.elapsed().as_micros() as u64, Ordering::Relaxed);
+                result
+            })
+        })
+        .buffered(max_parallel.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let mut checked = Vec::new();
+    for result in results {
+        checked.push(result?);
+    }
+    let elapsed = started.elapsed().as_micros().max(1) as f64;
+    log::info!(
+        target: "boot",
+        "{} links checked in {} ms, speedup {:.1}x against sequential check",
+        items.len(), elapsed as u64 / 1000, busy.load(Ordering::Relaxed) as f64 / elapsed
+    );
+    Ok(checked)
+}
+
 /// download any state, check its hash and store it, if need to download block and proof we trust them already
 async fn download_block_and_state(
     engine: &dyn EngineOperations, 
     block_id: &BlockIdExt, 
     master_id: &BlockIdExt,
     active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+) -> Result<(Arc<BlockHandle>, BlockStuff)> {
+    let (handle, block) = download_block(engine, block_id).await?;
+    download_state(engine, &handle, &block, master_id, active_peers).await?;
+    Ok((handle, block))
+}
+
+/// download block with proof if they are not stored yet
+pub(crate) async fn download_block(
+    engine: &dyn EngineOperations,
+    block_id: &BlockIdExt
 ) -> Result<(Arc<BlockHandle>, BlockStuff)> {
     let handle = engine.load_block_handle(block_id)?.filter(
         |handle| handle.has_data() && (handle.has_proof() || handle.has_proof_link())
     );
-    let (block, handle) = if let Some(handle) = handle {
-        (engine.load_block(&handle).await?, handle)
-    } else {
-        let (block, proof) = engine.download_block(block_id, None).await?;
-        let mut handle = engine.store_block(&block).await?.as_non_created().ok_or_else(
-            || error!("INTERNAL ERROR: mismatch in block {} store result during boot", block_id)
-        )?;
-        if !handle.has_proof() {
-            handle = engine.store_block_proof(block_id, Some(handle), &proof).await?
-                .as_non_created()
-                .ok_or_else(
-                    || error!(
-                        "INTERNAL ERROR: mismatch in block {} proof store result during boot",
-                        block_id
-                    )
-                )?;
-        }
-        (block, handle)
-    };
+    if let Some(handle) = handle {
+        let block = engine.load_block(&handle).await?;
+        return Ok((handle, block))
+    }
+    let (block, proof) = engine.download_block(block_id, None).await?;
+    let mut handle = engine.store_block(&block).await?.as_non_created().ok_or_else(
+        || error!("INTERNAL ERROR: mismatch in block {} store result during boot", block_id)
+    )?;
+    if !handle.has_proof() {
+        handle = engine.store_block_proof(block_id, Some(handle), &proof).await?
+            .as_non_created()
+            .ok_or_else(
+                || error!(
+                    "INTERNAL ERROR: mismatch in block {} proof store result during boot",
+                    block_id
+                )
+            )?;
+    }
+    Ok((handle, block))
+}
+
+/// download state of the block, check its hash, store it and mark block applied
+async fn download_state(
+    engine: &dyn EngineOperations,
+    handle: &Arc<BlockHandle>,
+    block: &BlockStuff,
+    master_id: &BlockIdExt,
+    active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+) -> Result<()> {
     if !handle.has_state() {
         let state_update = block.block().read_state_update()?;
         log::info!(target: "boot", "download state {}", handle.id());
@@ -316,19 +613,16 @@ async fn download_block_and_state(
         if state_update.new_hash != state_hash {
             fail!("root_hash {} of downloaded state {} is wrong", state_hash.to_hex_string(), handle.id())
         }
-        engine.store_state(&handle, &state).await?;
+        engine.store_state(handle, &state).await?;
         engine.process_full_state_in_ext_db(&state).await?;
     }
-    engine.set_applied(&handle, master_id.seq_no()).await?;
-    Ok((handle, block))
+    engine.set_applied(handle, master_id.seq_no()).await?;
+    Ok(())
 }
 
 /// Cold load best key block and its state
 /// Must be used only zero_state or key_block id
 pub async fn cold_boot(engine: Arc<dyn EngineOperations>) -> Result<BlockIdExt> {
-    // TODO: rewrite hard forks from opts to DB
-    // engine.get_hardforks();
-    // engine.update_hardforks();
     let (mut handle, zero_state, init_block_proof_link) = run_cold(engine.deref()).await?;
     
     let key_blocks = get_key_blocks(engine.deref(), handle, zero_state.as_ref(), init_block_proof_link).await?;
@@ -492,3 +786,108 @@ pub async fn cold_sync_stub(engine: Arc<Engine>) -> Result<BlockIdExt> {
     Ok(engine.init_mc_block_id().clone())
 }
 */
+
+#[test]
+fn test_hardforks() {
+    use ton_types::UInt256;
+    let id = |seq_no: u32, hash: u8| BlockIdExt::with_params(
+        ShardIdent::masterchain(), seq_no, UInt256::from_slice(&[hash; 32]), UInt256::from_slice(&[hash; 32])
+    );
+    let hardforks = vec!(id(100, 1), id(200, 2));
+
+    struct TestEngine(Vec<BlockIdExt>);
+    impl EngineOperations for TestEngine {
+        fn get_hardforks(&self) -> &[BlockIdExt] { &self.0 }
+    }
+    let engine = TestEngine(hardforks.clone());
+    assert_eq!(engine.get_last_fork_masterchain_seqno(), 200);
+    assert!(engine.is_hardfork(&id(100, 1)));
+    assert!(!engine.is_hardfork(&id(100, 0)));
+    assert_eq!(TestEngine(Vec::new()).get_last_fork_masterchain_seqno(), 0);
+
+    // Key block chain goes through hardforks only
+    check_key_block_hardforks(&hardforks, &id(50, 0), &id(100, 1)).unwrap();
+    check_key_block_hardforks(&hardforks, &id(100, 1), &id(150, 0)).unwrap();
+    check_key_block_hardforks(&hardforks, &id(200, 2), &id(250, 0)).unwrap();
+    assert!(check_key_block_hardforks(&hardforks, &id(50, 0), &id(100, 0)).is_err());
+    assert!(check_key_block_hardforks(&hardforks, &id(50, 0), &id(150, 0)).is_err());
+    assert!(check_key_block_hardforks(&hardforks, &id(150, 0), &id(250, 0)).is_err());
+
+    // Pre-fork branch and blocks built on it are discarded
+    check_block_hardforks(&hardforks, &id(100, 1), &id(99, 0)).unwrap();
+    check_block_hardforks(&hardforks, &id(101, 0), &id(100, 1)).unwrap();
+    check_block_hardforks(&hardforks, &id(150, 0), &id(149, 0)).unwrap();
+    assert!(check_block_hardforks(&hardforks, &id(100, 0), &id(99, 0)).is_err());
+    assert!(check_block_hardforks(&hardforks, &id(101, 0), &id(100, 0)).is_err());
+    check_block_hardforks(&[], &id(100, 0), &id(99, 0)).unwrap();
+}
+
+#[test]
+fn test_check_chain_links() {
+    const MAX_PARALLEL: usize = 4;
+    // Each link is signed by key of the previous one
+    #[derive(Clone)]
+    struct Link {
+        key: u32,
+        signed_by: u32,
+    }
+    let running = Arc::new(AtomicU64::new(0));
+    let max_running = Arc::new(AtomicU64::new(0));
+    // Count of entered checks: the first `parallel` ones wait for each other, so they are
+    // running at once if it is allowed. Timeout only breaks a hang of broken implementation.
+    let gate = Arc::new((std::sync::Mutex::new(0u64), std::sync::Condvar::new()));
+    let check = |parallel: u64| {
+        let running = running.clone();
+        let max_running = max_running.clone();
+        let gate = gate.clone();
+        move |link: &Link, prev: Option<&Link>| -> Result<()> {
+            let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(now_running, Ordering::SeqCst);
+            let (entered, entered_changed) = &*gate;
+            let mut entered = entered.lock().unwrap();
+            *entered += 1;
+            entered_changed.notify_all();
+            let _ = entered_changed.wait_timeout_while(
+                entered, Duration::from_secs(10), |entered| *entered < parallel
+            ).unwrap();
+            running.fetch_sub(1, Ordering::SeqCst);
+            if link.signed_by != prev.map(|prev| prev.key).unwrap_or(0) {
+                fail!("bad signature of link {} signed by {}", link.key, link.signed_by)
+            }
+            Ok(())
+        }
+    };
+    let mut chain = (1..=32).map(|key| Link { key, signed_by: key - 1 }).collect::<Vec<_>>();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    let results = rt.block_on(
+        check_chain_links(None, &chain, MAX_PARALLEL, check(MAX_PARALLEL as u64))
+    ).unwrap();
+    assert_eq!(results.len(), chain.len());
+    assert!(results.iter().all(|result| result.is_ok()));
+    assert_eq!(max_running.load(Ordering::SeqCst), MAX_PARALLEL as u64);
+
+    // Bad signature in the middle of chain is caught, results go in order of links
+    chain[9].signed_by = 100;
+    chain[20].signed_by = 200;
+    let prev = Link { key: 0, signed_by: 0 };
+    *gate.0.lock().unwrap() = 0;
+    let results = rt.block_on(
+        check_chain_links(Some(prev.clone()), &chain, MAX_PARALLEL, check(MAX_PARALLEL as u64))
+    ).unwrap();
+    let failed = results.iter()
+        .enumerate()
+        .filter_map(|(i, result)| result.as_ref().err().map(|e| (i, e.to_string())))
+        .collect::<Vec<_>>();
+    assert_eq!(failed, vec![
+        (9, "bad signature of link 10 signed by 100".to_string()),
+        (20, "bad signature of link 21 signed by 200".to_string())
+    ]);
+    // The first link is checked with the given previous one, sequentially this time
+    chain[0].signed_by = 1;
+    max_running.store(0, Ordering::SeqCst);
+    *gate.0.lock().unwrap() = 0;
+    let results = rt.block_on(check_chain_links(Some(prev), &chain, 1, check(1))).unwrap();
+    assert!(results[0].is_err());
+    assert_eq!(max_running.load(Ordering::SeqCst), 1);
+}
@@ -0,0 +1,262 @@
+use crate::{
+    block::BlockStuff, collator_test_bundle::CollatorTestBundle, engine_traits::EngineOperations,
+    shard_state::ShardStateStuff,
+    validator::{
+        collator, collator_sync, validate_query::ValidateQuery,
+        validator_utils::compute_validator_set_cc, BlockCandidate, CollatorSettings,
+    }
+};
+use std::{sync::Arc, time::Instant};
+use ton_block::{Block, ValidatorSet};
+use ton_types::{error, fail, Result};
+
+const REPLAY_COLLATION_TIMEOUT_MS: u32 = 60_000;
+
+// Collates and/or validates block from the bundle without network and database.
+// Returns false if collated block differs from the ethalon one.
+pub async fn replay_bundle(path: &str, collate: bool, validate: bool) -> Result<bool> {
+    let now = Instant::now();
+    let bundle = Arc::new(CollatorTestBundle::load(path)?);
+    println!("Bundle for {} loaded in {} ms", bundle.block_id(), now.elapsed().as_millis());
+
+    let engine: Arc<dyn EngineOperations> = bundle.clone();
+    let mc_state = engine.load_last_applied_mc_state().await?;
+    let validator_set = bundle_validator_set(&bundle, &mc_state)?;
+    let mut matches = true;
+
+    let mut candidate = bundle.candidate().cloned();
+    if collate {
+        let now = Instant::now();
+        let collated = collate_bundle(&bundle, validator_set.clone(), CollatorSettings::default()).await?;
+        println!(
+            "Collated block {} in {} ms", collated.block_id, now.elapsed().as_millis()
+        );
+        if let Some(ethalon) = bundle.ethalon_block()? {
+            if ethalon.id().root_hash() != collated.block_id.root_hash() {
+                println!(
+                    "Collated block root hash {:x} differs from ethalon {:x}",
+                    collated.block_id.root_hash(), ethalon.id().root_hash()
+                );
+                let block = BlockStuff::new(collated.block_id.clone(), collated.data.clone())?;
+                println!(
+                    "Differences: {}", block_differences(block.block(), ethalon.block())?.join(", ")
+                );
+                matches = false;
+            } else {
+                println!("Collated block is equal to ethalon");
+            }
+        }
+        if candidate.is_none() {
+            candidate = Some(collated);
+        }
+    }
+
+    if validate {
+        let candidate = candidate.ok_or_else(
+            || error!("Bundle has no candidate to validate, collate it first")
+        )?;
+        let block_id = candidate.block_id.clone();
+        let now = Instant::now();
+        ValidateQuery::new(
+            bundle.block_id().shard().clone(),
+            bundle.min_ref_mc_block_id().seq_no(),
+            bundle.prev_blocks_ids().clone(),
+            candidate,
+            validator_set,
+            engine,
+            bundle.is_fake(),
+            cfg!(feature = "async_validator"),
+        ).try_validate().await?;
+        println!("Validated block {} in {} ms", block_id, now.elapsed().as_millis());
+    }
+    Ok(matches)
+}
+
+async fn collate_bundle(
+    bundle: &Arc<CollatorTestBundle>,
+    validator_set: ValidatorSet,
+    settings: CollatorSettings
+) -> Result<BlockCandidate> {
+    let (candidate, _) = if cfg!(feature = "async_collator") {
+        collator::Collator::new(
+            bundle.block_id().shard().clone(),
+            bundle.min_ref_mc_block_id().clone(),
+            bundle.prev_blocks_ids().clone(),
+            validator_set,
+            bundle.created_by().clone(),
+            bundle.clone(),
+            bundle.rand_seed().cloned(),
+            settings
+        )?.collate(REPLAY_COLLATION_TIMEOUT_MS).await?
+    } else {
+        collator_sync::Collator::new(
+            bundle.block_id().shard().clone(),
+            bundle.min_ref_mc_block_id().clone(),
+            bundle.prev_blocks_ids().clone(),
+            validator_set,
+            bundle.created_by().clone(),
+            bundle.clone(),
+            bundle.rand_seed().cloned(),
+            settings
+        )?.collate().await?
+    };
+    Ok(candidate)
+}
+
+// Names of block's parts which differ between two blocks
+fn block_differences(block: &Block, ethalon: &Block) -> Result<Vec<&'static str>> {
+    let mut diff = vec![];
+    if block.read_info()? != ethalon.read_info()? {
+        diff.push("info");
+    }
+    if block.read_value_flow()? != ethalon.read_value_flow()? {
+        diff.push("value flow");
+    }
+    if block.read_state_update()? != ethalon.read_state_update()? {
+        diff.push("state update");
+    }
+    let (extra, ethalon_extra) = (block.read_extra()?, ethalon.read_extra()?);
+    if extra.read_in_msg_descr()? != ethalon_extra.read_in_msg_descr()? {
+        diff.push("inbound messages");
+    }
+    if extra.read_out_msg_descr()? != ethalon_extra.read_out_msg_descr()? {
+        diff.push("outbound messages");
+    }
+    if extra.read_account_blocks()? != ethalon_extra.read_account_blocks()? {
+        diff.push("transactions");
+    }
+    Ok(diff)
+}
+
+// The same subset the validator group of the shard gets at the bundle's time
+fn bundle_validator_set(
+    bundle: &CollatorTestBundle,
+    mc_state: &ShardStateStuff
+) -> Result<ValidatorSet> {
+    let shard = bundle.block_id().shard();
+    let mc_state_extra = mc_state.shard_state_extra()?;
+    let cc_seqno = if shard.is_masterchain() {
+        mc_state_extra.validator_info.catchain_seqno
+    } else {
+        mc_state_extra.shards().calc_shard_cc_seqno(shard)?
+    };
+    let mut cc_seqno_delta = 0;
+    let nodes = compute_validator_set_cc(
+        mc_state.config_params()?, shard, bundle.now(), cc_seqno, &mut cc_seqno_delta
+    )?;
+    if nodes.is_empty() {
+        fail!("Cannot compute validator set for {} from masterchain state", shard)
+    }
+    ValidatorSet::with_cc_seqno(0, 0, 0, cc_seqno_delta, nodes)
+}
+
+#[test]
+fn test_replay_bundle_without_config() {
+    use ton_block::{Serializable, ShardIdent, ShardStateUnsplit};
+
+    let dir = std::env::temp_dir().join(format!("test_replay_bundle_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let zerostate = dir.join("mc_zerostate");
+    let state = ShardStateUnsplit::with_ident(ShardIdent::masterchain());
+    let mut data = vec![];
+    ton_types::cells_serialization::serialize_tree_of_cells(&state.serialize().unwrap(), &mut data)
+        .unwrap();
+    std::fs::write(&zerostate, &data).unwrap();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let bundle = rt.block_on(
+        CollatorTestBundle::build_with_zero_state(zerostate.to_str().unwrap(), &[])
+    ).unwrap();
+    let prefix = dir.join("bundles");
+    bundle.save(prefix.to_str().unwrap(), Some(3)).unwrap();
+    let path = std::fs::read_dir(&prefix).unwrap().next().unwrap().unwrap().path();
+
+    // Bundle is loaded, but state without config can't give validator set to collate with
+    let err = rt.block_on(replay_bundle(path.to_str().unwrap(), true, true)).unwrap_err();
+    assert!(!err.to_string().contains("Directory not found"));
+    assert!(rt.block_on(replay_bundle(dir.join("none").to_str().unwrap(), true, true)).is_err());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+// Bundles of real blocks, saved by node with collator_test_bundles settings
+#[cfg(test)]
+const TEST_BUNDLES_DIR: &str = "src/tests/static/bundles";
+
+#[cfg(test)]
+fn test_bundle_paths() -> Vec<String> {
+    let mut paths = std::fs::read_dir(TEST_BUNDLES_DIR)
+        .unwrap_or_else(|e| panic!("Can't read test bundles from {}: {}", TEST_BUNDLES_DIR, e))
+        .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    paths.sort();
+    assert!(!paths.is_empty(), "No test bundles in {}", TEST_BUNDLES_DIR);
+    paths
+}
+
+#[test]
+#[ignore = "needs bundles of real blocks in src/tests/static/bundles"]
+fn test_parallel_collation_is_equivalent_to_sequential() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    for path in test_bundle_paths() {
+        rt.block_on(async {
+            let bundle = Arc::new(CollatorTestBundle::load(&path).unwrap());
+            let engine: Arc<dyn EngineOperations> = bundle.clone();
+            let mc_state = engine.load_last_applied_mc_state().await.unwrap();
+            let validator_set = bundle_validator_set(&bundle, &mc_state).unwrap();
+            let collate = |parallel_execution| {
+                let settings = CollatorSettings {
+                    parallel_execution: Some(parallel_execution),
+                    ..Default::default()
+                };
+                let bundle = bundle.clone();
+                let validator_set = validator_set.clone();
+                async move {
+                    collator::Collator::new(
+                        bundle.block_id().shard().clone(),
+                        bundle.min_ref_mc_block_id().clone(),
+                        bundle.prev_blocks_ids().clone(),
+                        validator_set,
+                        bundle.created_by().clone(),
+                        bundle.clone(),
+                        bundle.rand_seed().cloned(),
+                        settings
+                    ).unwrap().collate(REPLAY_COLLATION_TIMEOUT_MS).await.unwrap().0
+                }
+            };
+            let sequential = collate(false).await;
+            for _ in 0..3 {
+                let parallel = collate(true).await;
+                assert_eq!(
+                    parallel.block_id.root_hash(), sequential.block_id.root_hash(),
+                    "parallel collation of {} differs from sequential one", path
+                );
+            }
+        });
+    }
+}
+
+#[test]
+#[ignore = "needs bundles of real blocks in src/tests/static/bundles"]
+fn test_replay_bundle_matches_ethalon() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    for path in test_bundle_paths() {
+        let bundle = Arc::new(CollatorTestBundle::load(&path).unwrap());
+        let ethalon = bundle.ethalon_block().unwrap()
+            .unwrap_or_else(|| panic!("bundle {} has no ethalon block", path));
+        let collated = rt.block_on(async {
+            let engine: Arc<dyn EngineOperations> = bundle.clone();
+            let mc_state = engine.load_last_applied_mc_state().await.unwrap();
+            let validator_set = bundle_validator_set(&bundle, &mc_state).unwrap();
+            collate_bundle(&bundle, validator_set, CollatorSettings::default()).await.unwrap()
+        });
+        let block = BlockStuff::new(collated.block_id.clone(), collated.data.clone()).unwrap();
+        assert_eq!(block.id().seq_no(), ethalon.id().seq_no());
+        assert_eq!(block_differences(block.block(), ethalon.block()).unwrap(), Vec::<&str>::new(),
+            "block collated from bundle {} differs from ethalon", path);
+        assert_eq!(block.calculate_tr_count().unwrap(), ethalon.calculate_tr_count().unwrap());
+        assert!(block.calculate_tr_count().unwrap() > 0, "block of bundle {} has no transactions", path);
+        let matches = rt.block_on(replay_bundle(&path, true, true))
+            .unwrap_or_else(|e| panic!("can't replay bundle {}: {}", path, e));
+        assert!(matches, "block collated from bundle {} differs from ethalon", path);
+    }
+}
@@ -1,6 +1,7 @@
 use crate::{
     block::BlockStuff,
     engine_traits::EngineOperations,
+    ext_messages::ExtMessageStatus,
     shard_state::ShardStateStuff,
     types::top_block_descr::TopBlockDescrStuff,
     validator::{
@@ -9,7 +10,7 @@ use crate::{
     }
 };
 use std::{
-    collections::HashMap, convert::TryFrom, fs::{File, write}, 
+    collections::HashMap, convert::TryFrom, fs::write,
     io::Cursor, ops::Deref, sync::{Arc, Weak}, 
 };
 use std::{
@@ -143,6 +144,39 @@ impl CollatorTestBundleIndex {
     }
 }
 
+// Files of bundle are compressed with zstd and have this extension.
+// Old bundles have plain files without extension, they are still readable.
+const COMPRESSED_EXT: &str = "zst";
+
+fn read_bundle_file(filename: &str) -> Result<Vec<u8>> {
+    let compressed = format!("{}.{}", filename, COMPRESSED_EXT);
+    if std::path::Path::new(&compressed).exists() {
+        let data = read(&compressed).map_err(|_| error!("cannot read file {}", compressed))?;
+        Ok(zstd::stream::decode_all(Cursor::new(data))?)
+    } else {
+        read(filename).map_err(|_| error!("cannot read file {}", filename))
+    }
+}
+
+fn construct_from_bundle_file<T: Deserializable>(filename: &str) -> Result<T> {
+    T::construct_from_bytes(&read_bundle_file(filename)?)
+}
+
+// Writes compressed file if level is given, returns count of written bytes
+fn write_bundle_file(filename: &str, data: &[u8], level: Option<i32>) -> Result<u64> {
+    match level {
+        Some(level) => {
+            let data = zstd::stream::encode_all(data, level)?;
+            write(format!("{}.{}", filename, COMPRESSED_EXT), &data)?;
+            Ok(data.len() as u64)
+        }
+        None => {
+            write(filename, data)?;
+            Ok(data.len() as u64)
+        }
+    }
+}
+
 fn construct_from_file<T: Deserializable>(path: &str) -> Result<(T, UInt256, UInt256)> {
     let bytes = std::fs::read(path)?;
     let fh = UInt256::calc_file_hash(&bytes);
@@ -233,7 +267,7 @@ impl CollatorTestBundle {
         let mut top_shard_blocks = vec!();
         for id in index.top_shard_blocks.iter() {
             let filename = format!("{}/top_shard_blocks/{:x}", path, id.root_hash());
-            let tbd = TopBlockDescr::construct_from_file(filename)?;
+            let tbd = construct_from_bundle_file::<TopBlockDescr>(&filename)?;
             top_shard_blocks.push(Arc::new(TopBlockDescrStuff::new(tbd, id, index.fake)?));
         }
 
@@ -255,7 +289,7 @@ impl CollatorTestBundle {
         for id in index.external_messages.iter() {
             let filename = format!("{}/external_messages/{:x}", path, id);
             external_messages.push((
-                Arc::new(Message::construct_from_file(filename)?),
+                Arc::new(construct_from_bundle_file::<Message>(&filename)?),
                 id.clone()
             ));
         }
@@ -266,7 +300,7 @@ impl CollatorTestBundle {
         // all shardes states
         for ss_id in index.neighbors.iter().chain(index.prev_blocks.iter()) {
             let filename = format!("{}/states/{:x}", path, ss_id.root_hash());
-            let data = read(&filename).map_err(|_| error!("cannot read file {}", filename))?;
+            let data = read_bundle_file(&filename)?;
             let ss = if ss_id.seq_no() == 0 {
                 ShardStateStuff::deserialize_zerostate(ss_id.clone(), &data)?
             } else {
@@ -277,7 +311,7 @@ impl CollatorTestBundle {
         }
         if index.contains_ethalon && !index.id.shard().is_masterchain() {
             let filename = format!("{}/states/{:x}", path, index.id.root_hash());
-            let data = read(&filename).map_err(|_| error!("cannot read file {}", filename))?;
+            let data = read_bundle_file(&filename)?;
             states.insert(
                 index.id.clone(),
                 ShardStateStuff::deserialize(index.id.clone(), &data)?
@@ -287,7 +321,7 @@ impl CollatorTestBundle {
         // oldest mc state is saved full 
         let oldest_mc_state_id = index.oldest_mc_state();
         let filename = format!("{}/states/{:x}", path, oldest_mc_state_id.root_hash());
-        let data = read(&filename).map_err(|_| error!("cannot read file {}", filename))?;
+        let data = read_bundle_file(&filename)?;
         let oldest_mc_state = if oldest_mc_state_id.seq_no() == 0 {
             ShardStateStuff::deserialize_zerostate(oldest_mc_state_id.clone(), &data)?
         } else {
@@ -303,7 +337,7 @@ impl CollatorTestBundle {
                 let filename = format!("{}/states/mc_merkle_updates/{:x}", path, id.root_hash());
                 mc_merkle_updates.insert(
                     id.clone(),
-                    MerkleUpdate::construct_from_file(filename)?,
+                    construct_from_bundle_file::<MerkleUpdate>(&filename)?,
                 );
             }
         }
@@ -321,7 +355,7 @@ impl CollatorTestBundle {
         let mut blocks = HashMap::new();
         if index.contains_ethalon {
             let filename = format!("{}/blocks/{:x}", path, index.id.root_hash());
-            let data = read(&filename).map_err(|_| error!("cannot read file {}", filename))?;
+            let data = read_bundle_file(&filename)?;
             blocks.insert(
                 index.id.clone(),
                 BlockStuff::deserialize(index.id.clone(), data)?
@@ -330,7 +364,7 @@ impl CollatorTestBundle {
         for id in index.prev_blocks.iter() {
             if id.seq_no() != 0 {
                 let filename = format!("{}/blocks/{:x}", path, id.root_hash());
-                let data = read(&filename).map_err(|_| error!("cannot read file {}", filename))?;
+                let data = read_bundle_file(&filename)?;
                 blocks.insert(
                     id.clone(),
                     BlockStuff::deserialize(id.clone(), data)?
@@ -342,12 +376,12 @@ impl CollatorTestBundle {
             None
         } else {
             let path = format!("{}/candidate/", path);
-            let data = ton_api::ton::bytes(read(format!("{}/data", path))?);
+            let data = ton_api::ton::bytes(read_bundle_file(&format!("{}/data", path))?);
             Some(BlockCandidate {
                 block_id: index.id.clone(),
                 collated_file_hash: catchain::utils::get_hash(&data),
                 data: data.0,
-                collated_data: read(format!("{}/collated_data", path))?,
+                collated_data: read_bundle_file(&format!("{}/collated_data", path))?,
                 created_by: index.created_by.clone(),
             })
         };
@@ -408,6 +442,12 @@ impl CollatorTestBundle {
     pub fn min_ref_mc_seqno(&self) -> u32 { self.index.min_ref_mc_seqno }
     pub fn created_by(&self) -> &UInt256 { &self.index.created_by }
     pub fn rand_seed(&self) -> Option<&UInt256> { self.index.rand_seed.as_ref() }
+    pub fn is_fake(&self) -> bool { self.index.fake }
+    pub fn min_ref_mc_block_id(&self) -> &BlockIdExt {
+        self.index.mc_states.iter()
+            .find(|id| id.seq_no() == self.index.min_ref_mc_seqno)
+            .unwrap_or(&self.index.last_mc_state)
+    }
 // UNUSED
 //    pub fn notes(&self) -> &str { &self.index.notes }
 }
@@ -902,18 +942,20 @@ impl CollatorTestBundle {
         })
     }
 
-    pub fn save(&self, path: &str) -> Result<()> {
+    // Files are compressed with given zstd level, if any. Returns total size of written files.
+    pub fn save(&self, path: &str, level: Option<i32>) -> Result<u64> {
         // 📂 root directory
         let path = Self::build_filename(path, &self.index.id);
         log::info!("Saving {}", path);
         std::fs::create_dir_all(&path)?;
+        let mut size = 0;
 
         // ├─📂 top_shard_blocks
         for tbd in self.top_shard_blocks.iter() {
             let path = format!("{}/top_shard_blocks/", path);
             std::fs::create_dir_all(&path)?;
             let filename = format!("{}/{:x}", path, tbd.proof_for().root_hash());
-            tbd.top_block_descr().write_to_file(filename)?;
+            size += write_bundle_file(&filename, &tbd.top_block_descr().write_to_bytes()?, level)?;
         }
 
         // ├─📂 external_messages
@@ -921,7 +963,7 @@ impl CollatorTestBundle {
             let path = format!("{}/external_messages/", path);
             std::fs::create_dir_all(&path)?;
             let filename = format!("{}/{:x}", path, id);
-            m.write_to_file(filename)?;
+            size += write_bundle_file(&filename, &m.write_to_bytes()?, level)?;
         }
 
         // ├─📂 states
@@ -930,30 +972,33 @@ impl CollatorTestBundle {
         std::fs::create_dir_all(&path1)?;
         for ss_id in self.index.neighbors.iter().chain(self.index.prev_blocks.iter()) {
             let filename = format!("{}/{:x}", path1, ss_id.root_hash());
-            self.states.get(ss_id)
+            let data = self.states.get(ss_id)
                 .ok_or_else(|| error!("Bundle's internal error (state {})", ss_id))?
-                .write_to(&mut File::create(filename)?)?;
+                .serialize()?;
+            size += write_bundle_file(&filename, &data, level)?;
         }
         // ethalon state
         if self.index.contains_ethalon && !self.index.id.shard().is_masterchain() {
             let filename = format!("{}/{:x}", path1, self.index.id.root_hash());
-            self.states.get(&self.index.id)
+            let data = self.states.get(&self.index.id)
                 .ok_or_else(|| error!("Bundle's internal error (state {})", self.index.id))?
-                .write_to(&mut File::create(filename)?)?;
+                .serialize()?;
+            size += write_bundle_file(&filename, &data, level)?;
         }
         // oldest mc state is saved full 
         let oldest_mc_state = self.index.oldest_mc_state();
         let filename = format!("{}/{:x}", path1, oldest_mc_state.root_hash());
-        self.states.get(&oldest_mc_state)
+        let data = self.states.get(&oldest_mc_state)
             .ok_or_else(|| error!("Bundle's internal error (state {})", oldest_mc_state))?
-            .write_to(&mut File::create(filename)?)?;
+            .serialize()?;
+        size += write_bundle_file(&filename, &data, level)?;
 
         // merkle updates for all other mc states
         let path1 = format!("{}/states/mc_merkle_updates/", path);
         std::fs::create_dir_all(&path1)?;
         for (id, mu) in self.mc_merkle_updates.iter() {
             let filename = format!("{}/{:x}", path1, id.root_hash());
-            mu.write_to_file(filename)?;
+            size += write_bundle_file(&filename, &mu.write_to_bytes()?, level)?;
         }
 
         // ├─📂 blocks
@@ -961,7 +1006,7 @@ impl CollatorTestBundle {
             let path = format!("{}/blocks/", path);
             std::fs::create_dir_all(&path)?;
             let filename = format!("{}/{:x}", path, id.root_hash());
-            b.write_to(&mut File::create(filename)?)?;
+            size += write_bundle_file(&filename, b.data(), level)?;
         }
 
         // candidate
@@ -974,15 +1019,18 @@ impl CollatorTestBundle {
             }
             let path = format!("{}/candidate/", path);
             std::fs::create_dir_all(&path)?;
-            write(format!("{}/data", path), &candidate.data)?;
-            write(format!("{}/collated_data", path), &candidate.collated_data)?;
+            size += write_bundle_file(&format!("{}/data", path), &candidate.data, level)?;
+            size += write_bundle_file(
+                &format!("{}/collated_data", path), &candidate.collated_data, level
+            )?;
         }
 
         // 🗂 index
         let file = std::fs::File::create(format!("{}/index.json", path))?;
-        serde_json::to_writer_pretty(file, &CollatorTestBundleIndexJson::from(&self.index))?;
+        serde_json::to_writer_pretty(&file, &CollatorTestBundleIndexJson::from(&self.index))?;
+        size += file.metadata()?.len();
 
-        Ok(())
+        Ok(size)
     }
 
     pub fn exists(path: &str, block_id: &BlockIdExt) -> bool {
@@ -1099,4 +1147,42 @@ impl EngineOperations for CollatorTestBundle {
     fn complete_external_messages(&self, _to_delay: Vec<UInt256>, _to_delete: Vec<UInt256>) -> Result<()> {
         Ok(())
     }
+
+    fn set_external_message_status(&self, _id: &UInt256, _status: ExtMessageStatus) {
+    }
+}
+
+#[test]
+fn test_bundle_save_load_compressed_and_plain() {
+    let dir = std::env::temp_dir().join(format!("test_bundle_compression_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let zerostate = dir.join("mc_zerostate");
+    let state = ShardStateUnsplit::with_ident(ShardIdent::masterchain());
+    let mut data = vec![];
+    ton_types::cells_serialization::serialize_tree_of_cells(&state.serialize().unwrap(), &mut data)
+        .unwrap();
+    write(&zerostate, &data).unwrap();
+
+    let bundle = tokio::runtime::Runtime::new().unwrap().block_on(
+        CollatorTestBundle::build_with_zero_state(zerostate.to_str().unwrap(), &[])
+    ).unwrap();
+    let id = bundle.index.last_mc_state.clone();
+    for (name, level) in vec![("plain", None), ("compressed", Some(3))] {
+        let prefix = dir.join(name);
+        let prefix = prefix.to_str().unwrap();
+        let size = bundle.save(prefix, level).unwrap();
+        assert!(size > 0);
+        let path = CollatorTestBundle::build_filename(prefix, &bundle.index.id);
+        let state_file = format!("{}/states/{:x}", path, id.root_hash());
+        assert_eq!(std::path::Path::new(&state_file).exists(), level.is_none());
+        assert_eq!(std::path::Path::new(&format!("{}.zst", state_file)).exists(), level.is_some());
+
+        let loaded = CollatorTestBundle::load(&path).unwrap();
+        assert_eq!(loaded.index.id, bundle.index.id);
+        assert_eq!(
+            loaded.states.get(&id).unwrap().root_cell().repr_hash(),
+            bundle.states.get(&id).unwrap().root_cell().repr_hash()
+        );
+    }
+    std::fs::remove_dir_all(&dir).ok();
 }
@@ -1,6 +1,7 @@
 use crate::{
+    error::{ConfigError, NodeError}, internal_db::COMPACTABLE_DBS, keyring_cipher::KeyringCipher,
     network::node_network::NodeNetwork,
-    validator::validator_utils::mine_key_for_workchain,
+    validator::{CollatorSettings, validator_utils::mine_key_for_workchain},
 };
 use adnl::{from_slice, client::AdnlClientConfigJson,
     common::{add_object_to_map_with_update, KeyId, KeyOption, KeyOptionJson, Wait},
@@ -8,8 +9,8 @@ use adnl::{from_slice, client::AdnlClientConfigJson,
     server::{AdnlServerConfig, AdnlServerConfigJson}
 };
 use std::{
-    collections::HashMap, io::{BufReader}, fs::File, path::Path,
-    sync::{Arc, atomic::{self, AtomicI32} }
+    collections::HashMap, io::{BufReader}, fs::File, net::{IpAddr, Ipv4Addr, SocketAddr}, path::Path,
+    str::FromStr, sync::{Arc, atomic::{self, AtomicI32} }
 };
 use ton_api::{
     IntoBoxed, 
@@ -18,7 +19,9 @@ use ton_api::{
         dht::node::Node as DhtNodeConfig, pub_::publickey::Ed25519
     }
 };
-use ton_block::{BlockIdExt, ShardIdent, ValidatorSet, BASE_WORKCHAIN_ID, MASTERCHAIN_ID};
+use ton_block::{
+    BlockIdExt, MsgAddressInt, ShardIdent, ValidatorSet, BASE_WORKCHAIN_ID, MASTERCHAIN_ID
+};
 use ton_types::{error, fail, Result, UInt256};
 
 
@@ -45,15 +48,44 @@ pub trait KeyRing : Sync + Send  {
 
 pub fn default_cells_gc_interval_ms() -> u32 { 900_000 }
 
+pub fn default_cells_cache_size_mb() -> u64 { 256 }
+
+// Key of log_levels for targets without their own level
+pub const DEFAULT_LOG_TARGET: &str = "default";
+
+pub fn default_states_cache_size_mb() -> u64 { 2048 }
+
+pub fn default_block_handles_cache_size() -> usize { 100_000 }
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub struct TonNodeConfig {
     log_config_name: Option<String>,
+    // Used if there is no log config file: levels of log targets (e.g. "validator": "debug"),
+    // "default" one is for the rest of targets
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    log_levels: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_file: Option<LogFileConfig>,
     ton_global_config_name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     workchain: Option<i32>,
+    // Workchains written into external db: list of ids or "all". Follow "workchain" if not set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    front_workchains: Option<FrontWorkchains>,
+    // Fail if some of front workchains are not processed by the node, otherwise warn
+    #[serde(default)]
+    front_workchains_strict: bool,
     internal_db_path: Option<String>,
+    #[serde(default)]
+    resource_profile: ResourceProfile,
     #[serde(default = "default_cells_gc_interval_ms")]
     cells_gc_interval_ms: u32,
+    #[serde(default = "default_cells_cache_size_mb")]
+    cells_cache_size_mb: u64,
+    #[serde(default = "default_states_cache_size_mb")]
+    states_cache_size_mb: u64,
+    #[serde(default = "default_block_handles_cache_size")]
+    block_handles_cache_size: usize,
     #[serde(skip_serializing)]
     ip_address: Option<String>,
     adnl_node: Option<AdnlNodeConfigJson>,
@@ -63,20 +95,98 @@ pub struct TonNodeConfig {
     #[serde(skip_serializing)]
     control_server_port: Option<u16>,
     control_server: Option<AdnlServerConfigJson>,
+    // Clients of control server with their roles, added to its clients list
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    console_keys: Vec<ConsoleKeyJson>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    lite_server: Option<AdnlServerConfigJson>,
     kafka_consumer_config: Option<KafkaConsumerConfig>,
-    external_db_config: Option<ExternalDbConfig>,
+    external_db_config: Option<ExternalDbConfigs>,
     #[serde(default)]
     test_bundles_config: CollatorTestBundlesGeneralConfig,
+    #[serde(default)]
+    collator: CollatorSettings,
     #[serde(default = "default_connectivity_check_config")]
     connectivity_check_config: ConnectivityCheckBroadcastConfig,
+    #[serde(default)]
+    validator_manager: ValidatorManagerConfig,
+    #[serde(default)]
+    ext_messages: ExtMessagesConfig,
+    #[serde(default)]
+    db_compaction: DbCompactionConfig,
     gc: Option<GC>,
+    #[serde(default)]
+    blocks_retention: BlocksRetentionConfig,
+    #[serde(default)]
+    states_gc_policy: StatesGcPolicy,
+    #[serde(default)]
+    db_integrity_check: DbIntegrityCheckConfig,
+    #[serde(default)]
+    db_write_behind: DbWriteBehindConfig,
+    #[serde(default)]
+    persistent_states: PersistentStatesConfig,
+    #[serde(default)]
+    internal_db_paths: DbPathsConfig,
+    #[serde(default)]
+    index_transactions: bool,
+    #[serde(default)]
+    db_backup: DbBackupConfig,
+    #[serde(default)]
+    db_scrubber: DbScrubberConfig,
+    #[serde(default)]
+    archives_sync: ArchivesSyncConfig,
+    #[serde(default)]
+    bandwidth_limits: BandwidthLimitsConfig,
+    #[serde(default)]
+    archive_node: ArchiveNodeConfig,
+    #[serde(default)]
+    shard_catch_up: ShardCatchUpConfig,
+    #[serde(default)]
+    broadcast_dedup: BroadcastDedupConfig,
+    #[serde(default)]
+    network: NetworkConfig,
+    #[serde(default)]
+    tracing: TracingConfig,
+    #[serde(default)]
+    health_server: HealthServerConfig,
+    #[serde(default)]
+    shutdown: ShutdownConfig,
+    #[serde(default)]
+    supervisor: SupervisorConfig,
+    #[serde(default)]
+    metrics: MetricsConfig,
+    #[serde(default)]
+    runtime: RuntimeConfig,
+    #[serde(default)]
+    rocksdb: RocksDbConfig,
+    #[serde(default)]
+    keyring_encryption: KeyringEncryptionConfig,
+    // Partial mode: only these shards of processed workchain (and masterchain) are applied,
+    // "workchain:shard" with shard id in hex, e.g. "0:4000000000000000". Empty - all shards.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tracked_shards: Vec<String>,
+    // Deterministic randomness of subsystems for reproducible test runs, e.g. by
+    // TON_NODE__TEST_RNG_SEED. Refused for validator of public network.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    test_rng_seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    init_block: Option<InitBlockConfig>,
     validator_key_ring: Option<HashMap<String, KeyOptionJson>>,
     #[serde(skip)]
     configs_dir: String,
     #[serde(skip)]
     port: Option<u16>,
+    // Advertised instead of ip of ADNL node's ip_address, never saved
+    #[serde(skip)]
+    public_address: Option<IpAddr>,
+    #[serde(skip)]
+    file_name: String,
     #[serde(skip)]
-    file_name: String
+    env_overrides: Vec<EnvOverride>,
+    #[serde(skip)]
+    keyring_cipher: Option<Arc<KeyringCipher>>,
+    #[serde(skip)]
+    profile_values: Vec<ProfileValue>,
 }
 
 pub struct TonNodeGlobalConfig(TonNodeGlobalConfigJson);
@@ -109,199 +219,2165 @@ pub struct GC {
     archives_life_time_hours: Option<u32> // Hours
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
-pub struct KafkaProducerConfig {
-    pub enabled: bool,
-    pub brokers: String,
-    pub message_timeout_ms: u32,
-    pub topic: String,
-    pub attempt_timeout_ms: u32,
-    pub message_max_size: usize,
-    pub big_messages_storage: String,
-    pub external_message_ref_address_pattern: Option<String>
+// Blocks are kept if they are newer than keep_days or newer than keep_persistent_states-th
+// persistent state (the latest one is the first). Unset limits are not applied, both unset
+// disable pruning. Older blocks are pruned except key blocks and the zero state.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct BlocksRetentionConfig {
+    pub keep_days: Option<u32>,
+    pub keep_persistent_states: Option<u32>,
+    pub pruning_interval_sec: u64,
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+impl Default for BlocksRetentionConfig {
+    fn default() -> Self {
+        BlocksRetentionConfig {
+            keep_days: None,
+            keep_persistent_states: None,
+            pruning_interval_sec: 3600,
+        }
+    }
+}
+
+impl BlocksRetentionConfig {
+    pub fn enabled(&self) -> bool {
+        self.keep_days.is_some() || self.keep_persistent_states.is_some()
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.enabled() && self.pruning_interval_sec == 0 {
+            fail!("pruning_interval_sec can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+// States GC keeps states of the last keep_key_blocks key blocks (with their shards' top
+// blocks) and of not yet processed persistent state candidates, in addition to states
+// still referenced by the actual masterchain state.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, PartialEq)]
 #[serde(default)]
-pub struct ExternalDbConfig {
-    pub block_producer: KafkaProducerConfig,
-    pub raw_block_producer: KafkaProducerConfig,
-    pub message_producer: KafkaProducerConfig,
-    pub transaction_producer: KafkaProducerConfig,
-    pub account_producer: KafkaProducerConfig,
-    pub block_proof_producer: KafkaProducerConfig,
-    pub chain_range_producer: KafkaProducerConfig,
-    pub bad_blocks_storage: String,
+pub struct StatesGcPolicy {
+    pub keep_key_blocks: u32,
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+impl Default for StatesGcPolicy {
+    fn default() -> Self {
+        StatesGcPolicy {
+            keep_key_blocks: 1,
+        }
+    }
+}
+
+// Startup check of the last `depth` masterchain blocks and their shard blocks. Light check
+// is always done if `light_check_on_start` is set, full one (with hashes) - if node is run
+// with --check-db.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(default)]
-pub struct CollatorTestBundlesConfig {
-    build_for_unknown_errors: bool,
-    known_errors: Vec<String>,
-    build_for_errors: bool,
-    errors: Vec<String>,
-    path: String,
+pub struct DbIntegrityCheckConfig {
+    pub light_check_on_start: bool,
+    pub depth: u32,
 }
 
-impl CollatorTestBundlesConfig {
+impl Default for DbIntegrityCheckConfig {
+    fn default() -> Self {
+        DbIntegrityCheckConfig {
+            light_check_on_start: false,
+            depth: 16,
+        }
+    }
+}
 
-    pub fn is_enable(&self) -> bool {
-        self.build_for_unknown_errors ||
-            (self.build_for_errors && self.errors.len() > 0)
+impl DbIntegrityCheckConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.depth == 0 {
+            fail!("db integrity check depth can't have zero value");
+        }
+        Ok(())
     }
+}
 
-    pub fn need_to_build_for(&self, error: &str) -> bool {
-        self.build_for_unknown_errors &&
-            self.known_errors.iter().all(|e| !error.contains(e))
-        || self.build_for_errors && 
-            self.errors.iter().any(|e| error.contains(e))
+// Block handles are stored in batches across blocks, each batch is flushed after
+// `flush_interval_ms` or when it has `max_batch` updates. Applied block pointers are
+// flushed immediately together with all handle updates before them.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct DbWriteBehindConfig {
+    pub enabled: bool,
+    pub flush_interval_ms: u64,
+    pub max_batch: usize,
+}
+
+impl Default for DbWriteBehindConfig {
+    fn default() -> Self {
+        DbWriteBehindConfig {
+            enabled: false,
+            flush_interval_ms: 1000,
+            max_batch: 1000,
+        }
     }
+}
 
-    pub fn path(&self) -> &str {
-        &self.path
+impl DbWriteBehindConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.flush_interval_ms == 0 {
+            fail!("db write behind flush_interval_ms can't have zero value");
+        }
+        if self.max_batch == 0 {
+            fail!("db write behind max_batch can't have zero value");
+        }
+        Ok(())
     }
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+// Directories of internal DB data classes, not set ones are in `internal_db_path`
+// (see internal_db::layout for the mapping of databases to classes)
+#[derive(serde::Deserialize, serde::Serialize, Default, Debug, Clone)]
 #[serde(default)]
-pub struct ConnectivityCheckBroadcastConfig {
-    pub enabled: bool,
-    pub long_len: usize,
-    pub short_period_ms: u64,
-    pub long_mult: u8,
+pub struct DbPathsConfig {
+    pub blocks: Option<String>,
+    pub cells: Option<String>,
+    pub archives: Option<String>,
+    pub persistent_states: Option<String>,
 }
 
-pub fn default_connectivity_check_config() -> ConnectivityCheckBroadcastConfig {
-    ConnectivityCheckBroadcastConfig {
-        enabled: true,
-        long_len: 2 * 1024,
-        short_period_ms: 1000,
-        long_mult: 5,
+// Scheduled backups of internal DB. Backup is made into a new subdirectory of `path`
+// once a period at `start_hour` (UTC), only `keep_backups` latest ones are kept.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct DbBackupConfig {
+    pub path: Option<String>,
+    pub start_hour: u32,
+    pub period_hours: u32,
+    pub keep_backups: u32,
+}
+
+impl Default for DbBackupConfig {
+    fn default() -> Self {
+        DbBackupConfig {
+            path: None,
+            start_hour: 2,
+            period_hours: 24,
+            keep_backups: 2,
+        }
     }
 }
 
-impl ConnectivityCheckBroadcastConfig {
-    pub const LONG_BCAST_MIN_LEN: usize = 769;
+impl DbBackupConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.period_hours == 0 {
+            fail!("db backup period_hours can't have zero value");
+        }
+        if self.keep_backups == 0 {
+            fail!("db backup keep_backups can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+// Sync by archives: up to `download_window` archives are downloaded at once from distinct
+// peers. Download slower than `min_download_speed_kb` (KB/s, 0 - unlimited) is dropped
+// and retried from another peer. Proofs of up to `apply_pipeline_depth` blocks are checked
+// ahead while previous blocks are applied.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ArchivesSyncConfig {
+    pub download_window: usize,
+    pub min_download_speed_kb: u64,
+    pub apply_pipeline_depth: usize,
+}
+
+impl Default for ArchivesSyncConfig {
+    fn default() -> Self {
+        ArchivesSyncConfig {
+            download_window: 4,
+            min_download_speed_kb: 32,
+            apply_pipeline_depth: 8,
+        }
+    }
+}
 
+impl ArchivesSyncConfig {
     pub fn check(&self) -> Result<()> {
-        if self.long_len < Self::LONG_BCAST_MIN_LEN {
-            fail!("long_len should be >= {}", Self::LONG_BCAST_MIN_LEN);
+        if self.download_window == 0 {
+            fail!("archives sync download_window can't have zero value");
         }
-        if self.short_period_ms == 0 {
-            fail!("short_period_ms can't have zero value");
+        if self.apply_pipeline_depth == 0 {
+            fail!("archives sync apply_pipeline_depth can't have zero value");
         }
-        if self.short_period_ms > 1_000_000 {
-            fail!("short_period_ms should be <= 1_000_000");
+        Ok(())
+    }
+}
+
+// Token bucket limits of archive sync downloads and of archive and persistent state slices
+// served to other nodes, KB/s (0 - unlimited). Can be changed at runtime.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct BandwidthLimitsConfig {
+    pub download_kb_per_sec: u64,
+    pub serve_kb_per_sec: u64,
+}
+
+// Shard lagging behind masterchain by more than `max_lag_seqno` blocks or `max_lag_sec`
+// seconds is caught up by downloading archives instead of blocks one by one
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShardCatchUpConfig {
+    pub max_lag_seqno: u32,
+    pub max_lag_sec: u32,
+}
+
+impl Default for ShardCatchUpConfig {
+    fn default() -> Self {
+        ShardCatchUpConfig {
+            max_lag_seqno: 100,
+            max_lag_sec: 600,
         }
-        if self.short_period_ms < 100 {
-            fail!("short_period_ms should be >= 100");
+    }
+}
+
+// Overlay neighbours: up to `max_neighbours` peers of each overlay are queried. While there
+// are less than `min_neighbours` of them, free slots are filled every second. Otherwise one
+// neighbour is re-selected every `neighbours_reselect_interval_sec` (randomized by 50%),
+// the ones with worse peer scores are replaced more likely.
+// If block, proof or next block download gets no answer in `download_hedge_delay_ms`,
+// the same download is started in parallel (zero disables it), the first result is taken.
+// Node in partial mode checks block broadcasts of not tracked shards and relays them
+// to neighbours without storing, unless `relay_untracked_broadcasts` is off.
+// Up to `max_serve_queries` archive and persistent state slices are served to other nodes
+// at once, archive node role has its own limit.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub max_neighbours: usize,
+    pub min_neighbours: usize,
+    pub neighbours_reselect_interval_sec: u64,
+    pub download_hedge_delay_ms: u64,
+    pub relay_untracked_broadcasts: bool,
+    pub max_serve_queries: usize,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            max_neighbours: 16,
+            min_neighbours: 4,
+            neighbours_reselect_interval_sec: 20,
+            download_hedge_delay_ms: 300,
+            relay_untracked_broadcasts: true,
+            max_serve_queries: 64,
         }
-        if self.long_mult == 0 {
-            fail!("long_mult can't have zero value");
+    }
+}
+
+impl NetworkConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.max_neighbours == 0 {
+            fail!("max_neighbours can't have zero value");
+        }
+        if self.min_neighbours > self.max_neighbours {
+            fail!("min_neighbours can't be greater than max_neighbours");
+        }
+        if self.neighbours_reselect_interval_sec < 2 {
+            fail!("neighbours_reselect_interval_sec can't be less than 2");
+        }
+        if self.max_serve_queries == 0 {
+            fail!("max_serve_queries can't have zero value");
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+// Numbers of recently seen broadcasts of each kind kept to drop their duplicates
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 #[serde(default)]
-pub struct CollatorTestBundlesGeneralConfig {
-    pub collator: CollatorTestBundlesConfig,
-    pub validator: CollatorTestBundlesConfig,
+pub struct BroadcastDedupConfig {
+    pub block_capacity: usize,
+    pub shard_block_capacity: usize,
+    pub ext_msg_capacity: usize,
 }
 
-const LOCAL_HOST: &str = "127.0.0.1";
+impl Default for BroadcastDedupConfig {
+    fn default() -> Self {
+        BroadcastDedupConfig {
+            block_capacity: 1000,
+            shard_block_capacity: 1000,
+            ext_msg_capacity: 10_000,
+        }
+    }
+}
 
-impl TonNodeConfig {
-    pub fn front_workchain_ids(&self) -> Vec<i32> {
-        match self.workchain {
-            None | Some(0) | Some(-1) => vec![MASTERCHAIN_ID, BASE_WORKCHAIN_ID],
-            Some(workchain_id) => vec![workchain_id]
+impl BroadcastDedupConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.block_capacity == 0 || self.shard_block_capacity == 0 || self.ext_msg_capacity == 0 {
+            fail!("broadcast_dedup capacities can't have zero value");
         }
+        Ok(())
     }
-    pub fn workchain_id(&self) -> Option<i32> {
-        self.workchain
+}
+
+// Jaeger tracing of blocks processing (with "tracing" feature).
+// Share of blocks traced, key blocks are traced always.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub blocks_sampling_rate: f64,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        TracingConfig {
+            blocks_sampling_rate: 0.01,
+        }
     }
-    pub fn from_file(
-        configs_dir: &str,
-        json_file_name: &str,
-        adnl_config: Option<AdnlNodeConfigJson>,
-        default_config_name: &str,
-        client_console_key: Option<String>
-    ) -> Result<Self> { 
-        let config_file_path = TonNodeConfig::build_path(configs_dir, json_file_name)?;
-        let config_file = File::open(config_file_path.clone());
+}
 
-        let mut config_json = match config_file {
-            Ok(file) => {
-                let reader = BufReader::new(file);
-                let config: TonNodeConfig = serde_json::from_reader(reader)?;
+impl TracingConfig {
+    pub fn check(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.blocks_sampling_rate) {
+            fail!("tracing blocks_sampling_rate must be in range [0, 1]");
+        }
+        Ok(())
+    }
+}
 
-                if client_console_key.is_some() {
-                    println!("Can't generate console_config.json: delete config.json before");
-                }
-                config
-            }
-            Err(_) => {
-                // generate new config from default_config
-                let path = TonNodeConfig::build_path(configs_dir, default_config_name)?;
-                let default_config_file = File::open(&path)
-                    .map_err(|err| error!("Can`t open {}: {}", path, err))?;
+// HTTP health endpoints /live and /ready for orchestrators
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct HealthServerConfig {
+    // "ip:port" to listen on, None disables the server
+    pub address: Option<String>,
+    // node is ready while last applied masterchain block is not older
+    pub ready_max_timediff_sec: u32,
+    // node is live while last applied masterchain block changes at least this often,
+    // 0 - not checked
+    pub live_max_stall_sec: u32,
+}
 
-                let reader = BufReader::new(default_config_file);
-                let mut config: TonNodeConfig = serde_json::from_reader(reader)?;
-                // Set ADNL config
-                config.adnl_node = if let Some(adnl_config) = adnl_config {
-                    Some(adnl_config)
-                } else {
-                    let ip_address = if let Some(ip_address) = &config.ip_address {
-                        ip_address
-                    } else {
-                        fail!("IP address is not set in default config")
-                    };
-                    let (adnl_config, _) = AdnlNodeConfig::with_ip_address_and_key_type(
-                        ip_address, 
-                        KeyOption::KEY_ED25519,
-                        vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
-                    )?;
-                    Some(adnl_config)
-                };
-                config.create_and_save_console_configs(
-                    configs_dir,
-                    client_console_key
-                )?;
-                config.ip_address = None;
-                std::fs::write(config_file_path, serde_json::to_string_pretty(&config)?)?;
-                config
+impl Default for HealthServerConfig {
+    fn default() -> Self {
+        HealthServerConfig {
+            address: None,
+            ready_max_timediff_sec: 60,
+            live_max_stall_sec: 600,
+        }
+    }
+}
+
+impl HealthServerConfig {
+    pub fn check(&self) -> Result<()> {
+        if let Some(address) = &self.address {
+            if address.parse::<std::net::SocketAddr>().is_err() {
+                fail!("health_server address {} is not ip:port", address);
             }
-        };
+        }
+        Ok(())
+    }
+}
 
-        config_json.connectivity_check_config.check()?;
+// Graceful shutdown on SIGTERM/SIGINT: the process is force-exited if it takes longer
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    pub timeout_sec: u32,
+}
 
-        config_json.configs_dir = configs_dir.to_string();
-        config_json.file_name = json_file_name.to_string();
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        ShutdownConfig {
+            timeout_sec: 60,
+        }
+    }
+}
+
+impl ShutdownConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.timeout_sec == 0 {
+            fail!("timeout_sec must be positive")
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskFailurePolicy {
+    // task is started again with backoff, the process exits when restarts are exhausted
+    Restart,
+    // the process shuts down at once
+    Exit,
+}
+
+impl Default for TaskFailurePolicy {
+    fn default() -> Self {
+        TaskFailurePolicy::Restart
+    }
+}
+
+// What the engine does when its critical task (block clients, GC) terminates unexpectedly.
+// Node with terminated validator manager always shuts down.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct SupervisorConfig {
+    pub policy: TaskFailurePolicy,
+    pub max_restarts: u32,
+    // doubled after each restart up to max_restart_backoff_ms
+    pub restart_backoff_ms: u64,
+    pub max_restart_backoff_ms: u64,
+    // task running this long after restart is healthy again, its restarts are renewed
+    pub stable_run_sec: u64,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        SupervisorConfig {
+            policy: TaskFailurePolicy::default(),
+            max_restarts: 5,
+            restart_backoff_ms: 1000,
+            max_restart_backoff_ms: 60000,
+            stable_run_sec: 600,
+        }
+    }
+}
+
+impl SupervisorConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.restart_backoff_ms == 0 {
+            fail!("restart_backoff_ms must be positive")
+        }
+        if self.max_restart_backoff_ms < self.restart_backoff_ms {
+            fail!("max_restart_backoff_ms must not be less than restart_backoff_ms")
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum MetricsTagsFormat {
+    // DogStatsD extension: "name:1|c|#tag:value"
+    #[serde(rename = "datadog")]
+    Datadog,
+    // Tag values are put into metric name
+    #[serde(rename = "plain")]
+    Plain,
+}
+
+// Plain statsd does not understand tags extension
+impl Default for MetricsTagsFormat {
+    fn default() -> Self {
+        MetricsTagsFormat::Plain
+    }
+}
+
+// Tags of all metrics, to tell nodes reporting to one statsd apart
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    pub node_name: Option<String>,
+    pub network_id: Option<String>,
+    pub tags_format: MetricsTagsFormat,
+}
+
+// Archive node role: the node keeps all blocks, archives and persistent states to be a
+// bootstrap source for other nodes of the cluster. Blocks retention, archives GC and removal
+// of old persistent states are off, persistent states are always generated. Up to
+// `max_serve_queries` archive and persistent state slices are served at once, limited by
+// `serve_kb_per_sec` (KB/s, 0 - unlimited) instead of bandwidth_limits.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct ArchiveNodeConfig {
+    pub enabled: bool,
+    pub max_serve_queries: usize,
+    pub serve_kb_per_sec: u64,
+}
+
+impl Default for ArchiveNodeConfig {
+    fn default() -> Self {
+        ArchiveNodeConfig {
+            enabled: false,
+            max_serve_queries: 32,
+            serve_kb_per_sec: 0,
+        }
+    }
+}
+
+impl ArchiveNodeConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.max_serve_queries == 0 {
+            fail!("archive node max_serve_queries can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+// Log file written instead of stdout if logging is set by log_levels. It is rotated when it
+// grows over max_size_mb, up to max_files old files are kept as "{path}.{index}".
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct LogFileConfig {
+    pub path: String,
+    #[serde(default = "default_log_file_max_size_mb")]
+    pub max_size_mb: u64,
+    #[serde(default = "default_log_file_max_files")]
+    pub max_files: u32,
+}
+
+pub fn default_log_file_max_size_mb() -> u64 { 100 }
+
+pub fn default_log_file_max_files() -> u32 { 10 }
+
+impl LogFileConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.path.is_empty() {
+            fail!("log file path is empty");
+        }
+        if self.max_size_mb == 0 || self.max_files == 0 {
+            fail!("log file max_size_mb and max_files can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+// Private keys of validator_key_ring are stored encrypted with the passphrase taken from
+// `passphrase_env` variable or, if it is not set, from `passphrase_file`.
+// Existing plaintext keys are converted with `--encrypt-keys`.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct KeyringEncryptionConfig {
+    pub enabled: bool,
+    pub passphrase_env: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passphrase_file: Option<String>,
+}
+
+impl Default for KeyringEncryptionConfig {
+    fn default() -> Self {
+        KeyringEncryptionConfig {
+            enabled: false,
+            passphrase_env: "TON_NODE_KEYRING_PASSPHRASE".to_string(),
+            passphrase_file: None,
+        }
+    }
+}
+
+// Trusted masterchain key block to boot from when internal DB is empty, instead of zero
+// state or init block of global config. Key blocks before it are neither downloaded nor
+// checked. Hashes are in base64 or hex.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct InitBlockConfig {
+    pub seqno: u32,
+    pub root_hash: String,
+    pub file_hash: String,
+}
+
+impl InitBlockConfig {
+    pub fn block_id(&self) -> Result<BlockIdExt> {
+        let root_hash = UInt256::from_str(&self.root_hash)
+            .map_err(|e| error!("Invalid init_block root_hash {}: {}", self.root_hash, e))?;
+        let file_hash = UInt256::from_str(&self.file_hash)
+            .map_err(|e| error!("Invalid init_block file_hash {}: {}", self.file_hash, e))?;
+        Ok(BlockIdExt::with_params(ShardIdent::masterchain(), self.seqno, root_hash, file_hash))
+    }
+
+    pub fn check(&self) -> Result<()> {
+        self.block_id()?;
+        Ok(())
+    }
+}
+
+// Background verification of stored block proofs, `blocks_per_sec` blocks are checked
+// per second. Corrupted proofs are re-downloaded if `redownload` is set.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct DbScrubberConfig {
+    pub enabled: bool,
+    pub blocks_per_sec: u32,
+    pub redownload: bool,
+}
+
+impl Default for DbScrubberConfig {
+    fn default() -> Self {
+        DbScrubberConfig {
+            enabled: false,
+            blocks_per_sec: 10,
+            redownload: false,
+        }
+    }
+}
+
+impl DbScrubberConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.blocks_per_sec == 0 {
+            fail!("db scrubber blocks_per_sec can't have zero value");
+        }
+        if self.blocks_per_sec > 1_000_000 {
+            fail!("db scrubber blocks_per_sec can't be greater than 1000000");
+        }
+        Ok(())
+    }
+}
+
+// Generation of persistent states, only generated ones are offered to booting peers.
+// If `keep_states` is set, older persistent states are removed. Writing of state
+// is limited by `max_write_speed_mb` (MB/s) not to starve validation I/O.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+#[serde(default)]
+pub struct PersistentStatesConfig {
+    pub generate: bool,
+    pub keep_states: Option<u32>,
+    pub max_write_speed_mb: Option<u64>,
+}
+
+impl Default for PersistentStatesConfig {
+    fn default() -> Self {
+        PersistentStatesConfig {
+            generate: true,
+            keep_states: None,
+            max_write_speed_mb: None,
+        }
+    }
+}
+
+impl PersistentStatesConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.keep_states == Some(0) {
+            fail!("persistent states keep_states can't have zero value");
+        }
+        if self.max_write_speed_mb == Some(0) {
+            fail!("persistent states max_write_speed_mb can't have zero value");
+        }
+        Ok(())
+    }
+
+    // Bytes per second, 0 - unlimited
+    pub fn write_speed_limit(&self) -> u64 {
+        self.max_write_speed_mb.map(|mb| mb * 1024 * 1024).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaCompressionCodec {
+    None,
+    Gzip,
+    Snappy,
+    Lz4,
+    Zstd,
+}
+
+impl Default for KafkaCompressionCodec {
+    fn default() -> Self {
+        KafkaCompressionCodec::None
+    }
+}
+
+impl KafkaCompressionCodec {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KafkaCompressionCodec::None => "none",
+            KafkaCompressionCodec::Gzip => "gzip",
+            KafkaCompressionCodec::Snappy => "snappy",
+            KafkaCompressionCodec::Lz4 => "lz4",
+            KafkaCompressionCodec::Zstd => "zstd",
+        }
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+pub struct KafkaProducerConfig {
+    pub enabled: bool,
+    pub brokers: String,
+    pub message_timeout_ms: u32,
+    pub topic: String,
+    pub attempt_timeout_ms: u32,
+    pub message_max_size: usize,
+    pub big_messages_storage: String,
+    pub external_message_ref_address_pattern: Option<String>,
+    // zero means unlimited attempts
+    #[serde(default)]
+    pub max_attempts: u32,
+    // delay between attempts grows exponentially from attempt_timeout_ms up to this value
+    #[serde(default)]
+    pub max_backoff_ms: u32,
+    // failed records are retried in background while queue is not full, 
+    // zero disables the queue (records are retried in place);
+    // records of the same key are produced in the order they are written
+    // unless some of them are dead-lettered
+    #[serde(default)]
+    pub retry_queue_size: usize,
+    // if retry queue is full record is dead-lettered at once instead of being retried in place
+    #[serde(default)]
+    pub drop_on_full_retry_queue: bool,
+    // records which exhausted attempts are written here with error
+    pub dead_letter_topic: Option<String>,
+    // directory for records which can't be written to dead-letter topic
+    pub dead_letter_storage: Option<String>,
+    // librdkafka tuning, unset values keep librdkafka defaults
+    #[serde(default)]
+    pub compression_codec: KafkaCompressionCodec,
+    pub linger_ms: Option<u32>,
+    pub batch_size: Option<usize>,
+    pub max_in_flight: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalDbType {
+    Kafka,
+    Files,
+}
+
+impl Default for ExternalDbType {
+    fn default() -> Self {
+        ExternalDbType::Kafka
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FsyncPolicy {
+    Never,
+    OnRotate,
+    EveryWrite,
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::OnRotate
+    }
+}
+
+// Settings of file-based external db. Topics and enabled flags are taken from producers' configs,
+// every topic is written into its own subdirectory.
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct FilesDbConfig {
+    pub directory: String,
+    pub max_file_size: u64,
+    pub fsync: FsyncPolicy,
+    // retention per topic, zero means unlimited
+    pub max_files: usize,
+    pub max_total_bytes: u64,
+}
+
+impl Default for FilesDbConfig {
+    fn default() -> Self {
+        FilesDbConfig {
+            directory: "external_db".to_string(),
+            max_file_size: 64 << 20,
+            fsync: FsyncPolicy::default(),
+            max_files: 0,
+            max_total_bytes: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExternalDbAckTimeoutPolicy {
+    // block application fails
+    Fail,
+    // engine stops waiting for ack until restart
+    Degrade,
+}
+
+impl Default for ExternalDbAckTimeoutPolicy {
+    fn default() -> Self {
+        ExternalDbAckTimeoutPolicy::Fail
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ExternalDbAckConfig {
+    // block is marked as applied only after all its records are delivered
+    pub wait_for_external_db_ack: bool,
+    // must be set if ack is awaited, records can stay in retry queues forever
+    pub external_db_ack_timeout_ms: u64,
+    pub external_db_ack_timeout_policy: ExternalDbAckTimeoutPolicy,
+}
+
+impl Default for ExternalDbAckConfig {
+    fn default() -> Self {
+        ExternalDbAckConfig {
+            wait_for_external_db_ack: false,
+            external_db_ack_timeout_ms: 30000,
+            external_db_ack_timeout_policy: ExternalDbAckTimeoutPolicy::default(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ExternalDbHealthcheckConfig {
+    // zero disables periodic healthcheck
+    pub healthcheck_interval_sec: u64,
+    pub healthcheck_timeout_ms: u64,
+    // node doesn't start if external db is unhealthy
+    pub require_healthy_on_start: bool,
+}
+
+impl Default for ExternalDbHealthcheckConfig {
+    fn default() -> Self {
+        ExternalDbHealthcheckConfig {
+            healthcheck_interval_sec: 30,
+            healthcheck_timeout_ms: 5000,
+            require_healthy_on_start: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum MessageTypeSelector {
+    #[serde(rename = "ext-in")]
+    ExtIn,
+    #[serde(rename = "ext-out")]
+    ExtOut,
+    #[serde(rename = "int")]
+    Int,
+}
+
+// Filters of messages and transactions written into external db, empty lists disable filtering
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ExternalDbFilterConfig {
+    // "workchain:hex_prefix" of account address, e.g. "0:3f2a" or "-1:"
+    pub account_prefixes: Vec<String>,
+    pub message_types: Vec<MessageTypeSelector>,
+}
+
+// Hard limit of block size in bytes (block_limits.bytes.hard_limit of config param 22)
+const MAX_BLOCK_SIZE: usize = 2 << 20;
+// Block record JSON contains base64 encoded BOC and parsed fields, so it is several times bigger
+const DEFAULT_MAX_BLOCK_JSON_SIZE: usize = 4 * MAX_BLOCK_SIZE;
+
+impl ExternalDbConfig {
+    fn producers(&self) -> [(&'static str, &KafkaProducerConfig); 13] {
+        [
+            ("block_producer", &self.block_producer),
+            ("raw_block_producer", &self.raw_block_producer),
+            ("raw_block_proof_producer", &self.raw_block_proof_producer),
+            ("message_producer", &self.message_producer),
+            ("transaction_producer", &self.transaction_producer),
+            ("account_producer", &self.account_producer),
+            ("block_proof_producer", &self.block_proof_producer),
+            ("chain_range_producer", &self.chain_range_producer),
+            ("validator_status_producer", &self.validator_status_producer),
+            ("account_state_producer", &self.account_state_producer),
+            ("key_block_producer", &self.key_block_producer),
+            ("ext_msg_status_producer", &self.ext_msg_status_producer),
+            ("block_revoked_producer", &self.block_revoked_producer),
+        ]
+    }
+
+    pub fn check(&self) -> Result<()> {
+        if self.ack.wait_for_external_db_ack && self.ack.external_db_ack_timeout_ms == 0 {
+            fail!("external_db_ack_timeout_ms must be greater than zero if wait_for_external_db_ack is set");
+        }
+        if self.db_type == ExternalDbType::Files {
+            if self.files.directory.is_empty() {
+                fail!("files.directory must be set for files external db");
+            }
+            if self.files.max_file_size == 0 {
+                fail!("files.max_file_size must be greater than zero");
+            }
+            return Ok(())
+        }
+        for (name, producer) in self.producers().iter() {
+            if producer.enabled && (producer.topic.is_empty() || producer.brokers.is_empty()) {
+                fail!("{} is enabled, but its topic or brokers are not set", name);
+            }
+        }
+        // Oversized records can only go to big_messages_storage,
+        // so without it a whole block must fit into one message
+        let max_block_json_size = self.max_block_json_size.unwrap_or(DEFAULT_MAX_BLOCK_JSON_SIZE);
+        for (name, producer, max_size, kind) in [
+            ("block_producer", &self.block_producer, max_block_json_size, "max block JSON size"),
+            ("raw_block_producer", &self.raw_block_producer, MAX_BLOCK_SIZE, "max block size")
+        ].iter() {
+            if producer.enabled && producer.big_messages_storage.is_empty()
+                && producer.message_max_size < *max_size
+            {
+                fail!(
+                    "{} (topic {}): message_max_size {} is less than {} {} \
+                    and big_messages_storage is not set",
+                    name, producer.topic, producer.message_max_size, kind, max_size
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+// Single external db (as before) or list of independent sinks
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(untagged)]
+pub enum ExternalDbConfigs {
+    // must go first: struct can be deserialized from sequence as well
+    Multiple(Vec<ExternalDbConfig>),
+    Single(ExternalDbConfig),
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ExternalDbConfig {
+    // used in logs and stats, index in list by default
+    pub name: Option<String>,
+    // blocks are queued for sink in buffer of this size, so lagging sink doesn't block others;
+    // by default buffer is used only if there are several sinks
+    pub buffer_size: Option<usize>,
+    pub block_producer: KafkaProducerConfig,
+    pub raw_block_producer: KafkaProducerConfig,
+    pub raw_block_proof_producer: KafkaProducerConfig,
+    pub message_producer: KafkaProducerConfig,
+    pub transaction_producer: KafkaProducerConfig,
+    pub account_producer: KafkaProducerConfig,
+    pub block_proof_producer: KafkaProducerConfig,
+    pub chain_range_producer: KafkaProducerConfig,
+    pub validator_status_producer: KafkaProducerConfig,
+    // feed of changed account states of applied blocks
+    pub account_state_producer: KafkaProducerConfig,
+    // proofs of masterchain key blocks, intended for compacted topic
+    pub key_block_producer: KafkaProducerConfig,
+    // status transitions of external messages keyed by message hash
+    pub ext_msg_status_producer: KafkaProducerConfig,
+    // shard blocks dropped without being included into masterchain
+    pub block_revoked_producer: KafkaProducerConfig,
+    pub bad_blocks_storage: String,
+    // Size in bytes of the biggest block record JSON, block_producer's message_max_size
+    // must not be less if big_messages_storage is not set. 8 MiB by default.
+    pub max_block_json_size: Option<usize>,
+    pub filter: ExternalDbFilterConfig,
+    // write transactions in format preceding schema version 2 while consumers migrate
+    pub legacy_transaction_format: bool,
+    #[serde(flatten)]
+    pub ack: ExternalDbAckConfig,
+    #[serde(flatten)]
+    pub healthcheck: ExternalDbHealthcheckConfig,
+    #[serde(rename = "type")]
+    pub db_type: ExternalDbType,
+    pub files: FilesDbConfig,
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct CollatorTestBundlesConfig {
+    build_for_unknown_errors: bool,
+    known_errors: Vec<String>,
+    build_for_errors: bool,
+    errors: Vec<String>,
+    path: String,
+    compression_level: Option<i32>,
+}
+
+impl CollatorTestBundlesConfig {
+
+    const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+    pub fn is_enable(&self) -> bool {
+        self.build_for_unknown_errors ||
+            (self.build_for_errors && self.errors.len() > 0)
+    }
+
+    pub fn need_to_build_for(&self, error: &str) -> bool {
+        self.build_for_unknown_errors &&
+            self.known_errors.iter().all(|e| !error.contains(e))
+        || self.build_for_errors && 
+            self.errors.iter().any(|e| error.contains(e))
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn compression_level(&self) -> i32 {
+        self.compression_level.unwrap_or(Self::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ConnectivityCheckBroadcastConfig {
+    pub enabled: bool,
+    pub long_len: usize,
+    pub short_period_ms: u64,
+    pub long_mult: u8,
+}
+
+pub fn default_connectivity_check_config() -> ConnectivityCheckBroadcastConfig {
+    ConnectivityCheckBroadcastConfig {
+        enabled: true,
+        long_len: 2 * 1024,
+        short_period_ms: 1000,
+        long_mult: 5,
+    }
+}
+
+impl ConnectivityCheckBroadcastConfig {
+    pub const LONG_BCAST_MIN_LEN: usize = 769;
+
+    pub fn check(&self) -> Result<()> {
+        if self.long_len < Self::LONG_BCAST_MIN_LEN {
+            fail!("long_len should be >= {}", Self::LONG_BCAST_MIN_LEN);
+        }
+        if self.short_period_ms == 0 {
+            fail!("short_period_ms can't have zero value");
+        }
+        if self.short_period_ms > 1_000_000 {
+            fail!("short_period_ms should be <= 1_000_000");
+        }
+        if self.short_period_ms < 100 {
+            fail!("short_period_ms should be >= 100");
+        }
+        if self.long_mult == 0 {
+            fail!("long_mult can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+// Memory of RocksDB instances, MB: block cache shared by all instances and write buffer
+// of each one. RocksDB's own defaults are used if not set.
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct RocksDbConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub block_cache_mb: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_buffer_mb: Option<u64>,
+}
+
+impl RocksDbConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.block_cache_mb == Some(0) || self.write_buffer_mb == Some(0) {
+            fail!("block_cache_mb and write_buffer_mb can't have zero value");
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for RocksDbConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_default = |value: Option<u64>| {
+            value.map(|mb| format!("{} MB", mb)).unwrap_or_else(|| "default".to_string())
+        };
+        write!(
+            f, "block cache: {}, write buffer: {}",
+            or_default(self.block_cache_mb), or_default(self.write_buffer_mb)
+        )
+    }
+}
+
+// Coordinated defaults of memory and concurrency settings for small, usual and big hosts.
+// A value of the profile is used only if its field is neither in config.json nor set by
+// environment, so every setting can still be tuned on its own.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceProfile {
+    Low,
+    Default,
+    High,
+}
+
+impl Default for ResourceProfile {
+    fn default() -> Self {
+        ResourceProfile::Default
+    }
+}
+
+impl ResourceProfile {
+    // Paths of fields and their values
+    fn values(&self) -> Vec<(&'static str, serde_json::Value)> {
+        match self {
+            ResourceProfile::Low => vec![
+                ("cells_cache_size_mb", 64.into()),
+                ("states_cache_size_mb", 512.into()),
+                ("rocksdb.block_cache_mb", 128.into()),
+                ("rocksdb.write_buffer_mb", 16.into()),
+                ("ext_messages.max_total_bytes", (32 << 20).into()),
+                ("archives_sync.apply_pipeline_depth", 2.into()),
+                ("runtime.worker_threads", 4.into()),
+                ("runtime.max_blocking_threads", 64.into()),
+            ],
+            ResourceProfile::Default => vec![
+                ("cells_cache_size_mb", default_cells_cache_size_mb().into()),
+                ("states_cache_size_mb", default_states_cache_size_mb().into()),
+                ("ext_messages.max_total_bytes", (256 << 20).into()),
+                ("archives_sync.apply_pipeline_depth", 8.into()),
+            ],
+            ResourceProfile::High => vec![
+                ("cells_cache_size_mb", 1024.into()),
+                ("states_cache_size_mb", 8192.into()),
+                ("rocksdb.block_cache_mb", 2048.into()),
+                ("rocksdb.write_buffer_mb", 128.into()),
+                ("ext_messages.max_total_bytes", (1 << 30).into()),
+                ("archives_sync.apply_pipeline_depth", 16.into()),
+                ("runtime.max_blocking_threads", 1024.into()),
+            ],
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResourceProfile::Low => write!(f, "low"),
+            ResourceProfile::Default => write!(f, "default"),
+            ResourceProfile::High => write!(f, "high"),
+        }
+    }
+}
+
+// Field set by resource profile, it isn't written into the file while it keeps this value
+#[derive(Debug, Clone)]
+struct ProfileValue {
+    path: &'static str,
+    value: serde_json::Value,
+}
+
+impl ProfileValue {
+
+    fn set(&self, json: &mut serde_json::Value) {
+        let mut target = json;
+        for name in self.path.split('.') {
+            if !target.is_object() {
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            target = match target {
+                serde_json::Value::Object(map) => {
+                    map.entry(name.to_string()).or_insert(serde_json::Value::Null)
+                }
+                _ => return
+            };
+        }
+        *target = self.value.clone();
+    }
+
+    fn revert(&self, json: &mut serde_json::Value) {
+        let mut names: Vec<&str> = self.path.split('.').collect();
+        let name = match names.pop() {
+            Some(name) => name,
+            None => return
+        };
+        let parent = names.iter().try_fold(json, |json, name| json.get_mut(*name));
+        if let Some(serde_json::Value::Object(map)) = parent {
+            if map.get(name) == Some(&self.value) {
+                map.remove(name);
+            }
+        }
+    }
+}
+
+pub fn default_thread_stack_size() -> usize { 8 * 1024 * 1024 }
+
+const MAX_WORKER_THREADS: usize = 1024;
+const MAX_BLOCKING_THREADS: usize = 16384;
+const MIN_THREAD_STACK_SIZE: usize = 256 * 1024;
+const MAX_THREAD_STACK_SIZE: usize = 1024 * 1024 * 1024;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    pub worker_threads: Option<usize>, // tokio's default (number of cores) if not set
+    pub max_blocking_threads: Option<usize>, // tokio's default (512) if not set
+    pub thread_name: Option<String>, // name of all threads of the runtime
+    pub thread_stack_size: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            worker_threads: None,
+            max_blocking_threads: None,
+            thread_name: None,
+            thread_stack_size: default_thread_stack_size(),
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn check(&self) -> Result<()> {
+        if let Some(worker_threads) = self.worker_threads {
+            if worker_threads == 0 || worker_threads > MAX_WORKER_THREADS {
+                fail!("worker_threads should be in range 1..={}", MAX_WORKER_THREADS);
+            }
+        }
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            if max_blocking_threads == 0 || max_blocking_threads > MAX_BLOCKING_THREADS {
+                fail!("max_blocking_threads should be in range 1..={}", MAX_BLOCKING_THREADS);
+            }
+        }
+        if !(MIN_THREAD_STACK_SIZE..=MAX_THREAD_STACK_SIZE).contains(&self.thread_stack_size) {
+            fail!(
+                "thread_stack_size should be in range {}..={}",
+                MIN_THREAD_STACK_SIZE, MAX_THREAD_STACK_SIZE
+            );
+        }
+        if self.thread_name.as_ref().map(|name| name.is_empty()).unwrap_or(false) {
+            fail!("thread_name can't be empty");
+        }
+        Ok(())
+    }
+
+    pub fn build(&self) -> Result<tokio::runtime::Runtime> {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder
+            .enable_all()
+            .thread_stack_size(self.thread_stack_size);
+        if let Some(worker_threads) = self.worker_threads {
+            builder.worker_threads(worker_threads);
+        }
+        if let Some(max_blocking_threads) = self.max_blocking_threads {
+            builder.max_blocking_threads(max_blocking_threads);
+        }
+        if let Some(thread_name) = &self.thread_name {
+            builder.thread_name(thread_name);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+impl std::fmt::Display for RuntimeConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let or_default = |value: Option<usize>| {
+            value.map(|n| n.to_string()).unwrap_or_else(|| "default".to_string())
+        };
+        write!(
+            f, "worker threads: {}, max blocking threads: {}, thread name: {}, thread stack size: {}",
+            or_default(self.worker_threads),
+            or_default(self.max_blocking_threads),
+            self.thread_name.as_ref().map(|n| n.as_str()).unwrap_or("default"),
+            self.thread_stack_size
+        )
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ValidatorManagerConfig {
+    // Runtime of validator groups, node's runtime settings if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub runtime: Option<RuntimeConfig>,
+    // Private overlay of each validator list to propagate blocks and top shard blocks
+    pub validators_overlay: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExtMessagesOrdering {
+    // in order of arrival
+    Arrival,
+    // by importance score (import fee and destination weight), then in order of arrival
+    Priority,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ExtMessagesConfig {
+    pub ttl_sec: u32,
+    pub gc_interval_sec: u64,
+    pub dedup_capacity: usize,
+    pub dedup_period_sec: u32,
+    pub ordering: ExtMessagesOrdering,
+    // destination account address ("wc:hex") -> weight, default weight is 1
+    pub priority_weights: HashMap<String, u32>,
+    pub max_pending_per_dst: usize,
+    pub max_pending_per_src: usize,
+    // locally accepted messages are re-broadcast until they are committed
+    pub rebroadcast_interval_sec: u32,
+    pub rebroadcast_max_attempts: u32,
+    // dry-run messages against last applied state of destination account before queuing
+    pub precheck: bool,
+    // keep pending messages in internal db to restore them after restart
+    pub persist: bool,
+    pub persist_max_count: usize,
+    // number of messages which statuses are tracked
+    pub status_capacity: usize,
+    // messages are accumulated during window (zero disables batching) 
+    // or until total size limit is reached and then broadcast together.
+    // Overlay has no combined broadcast yet, so a batch is still sent message by message
+    // and the window only delays them; batching is off by default.
+    pub broadcast_batch_window_ms: u64,
+    pub broadcast_batch_max_bytes: usize,
+    // total serialized size of pending messages, lowest priority ones are evicted above it
+    pub max_total_bytes: usize,
+}
+
+impl Default for ExtMessagesConfig {
+    fn default() -> Self {
+        ExtMessagesConfig {
+            ttl_sec: 600,
+            gc_interval_sec: 10,
+            dedup_capacity: 100_000,
+            dedup_period_sec: 300,
+            ordering: ExtMessagesOrdering::Priority,
+            priority_weights: HashMap::new(),
+            max_pending_per_dst: 100,
+            max_pending_per_src: 1000,
+            rebroadcast_interval_sec: 15,
+            rebroadcast_max_attempts: 3,
+            precheck: false,
+            persist: false,
+            persist_max_count: 10_000,
+            status_capacity: 100_000,
+            broadcast_batch_window_ms: 0,
+            broadcast_batch_max_bytes: 1 << 20,
+            max_total_bytes: 256 << 20,
+        }
+    }
+}
+
+impl ExtMessagesConfig {
+    pub fn check(&self) -> Result<()> {
+        if self.ttl_sec == 0 {
+            fail!("ttl_sec can't have zero value");
+        }
+        if self.gc_interval_sec == 0 {
+            fail!("gc_interval_sec can't have zero value");
+        }
+        if self.dedup_capacity == 0 {
+            fail!("dedup_capacity can't have zero value");
+        }
+        if self.max_total_bytes == 0 {
+            fail!("max_total_bytes can't have zero value");
+        }
+        if self.status_capacity == 0 {
+            fail!("status_capacity can't have zero value");
+        }
+        if self.max_pending_per_dst == 0 || self.max_pending_per_src == 0 {
+            fail!("max_pending_per_dst and max_pending_per_src can't have zero value");
+        }
+        if self.rebroadcast_interval_sec == 0 {
+            fail!("rebroadcast_interval_sec can't have zero value");
+        }
+        if self.broadcast_batch_window_ms > 0 && self.broadcast_batch_max_bytes == 0 {
+            fail!("broadcast_batch_max_bytes can't have zero value if batching is enabled");
+        }
+        for address in self.priority_weights.keys() {
+            MsgAddressInt::from_str(address)
+                .map_err(|e| error!("invalid address {} in priority_weights: {}", address, e))?;
+        }
+        Ok(())
+    }
+
+    pub fn priority_weights(&self) -> Result<HashMap<String, u32>> {
+        let mut weights = HashMap::new();
+        for (address, weight) in self.priority_weights.iter() {
+            let address = MsgAddressInt::from_str(address)
+                .map_err(|e| error!("invalid address {} in priority_weights: {}", address, e))?;
+            weights.insert(address.to_string(), *weight);
+        }
+        Ok(weights)
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct DbCompactionConfig {
+    pub windows: Vec<DbCompactionWindow>,
+}
+
+#[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct DbCompactionWindow {
+    // one of internal databases (e.g. "shardstate_db"), all of them if not set
+    pub db: Option<String>,
+    // compaction starts at this hour (UTC) once a period
+    pub start_hour: u32,
+    pub period_hours: u32,
+}
+
+impl Default for DbCompactionWindow {
+    fn default() -> Self {
+        DbCompactionWindow {
+            db: None,
+            start_hour: 3,
+            period_hours: 24,
+        }
+    }
+}
+
+impl DbCompactionConfig {
+    pub fn check(&self) -> Result<()> {
+        for window in &self.windows {
+            if window.period_hours == 0 {
+                fail!("period_hours of db compaction window can't have zero value");
+            }
+            if let Some(db) = &window.db {
+                if !COMPACTABLE_DBS.contains(&db.as_str()) {
+                    fail!("unknown database {} in db compaction window", db);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct CollatorTestBundlesGeneralConfig {
+    pub collator: CollatorTestBundlesConfig,
+    pub validator: CollatorTestBundlesConfig,
+}
+
+const LOCAL_HOST: &str = "127.0.0.1";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsoleRole {
+    // All queries, including validator and console keys management
+    Admin,
+    // Node operations without keys management
+    Operator,
+    // Only queries which change nothing
+    ReadOnly,
+}
+
+// Client of control server: base64 of its ed25519 public key and role
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct ConsoleKeyJson {
+    pub pub_key: String,
+    pub role: ConsoleRole,
+}
+
+fn console_key_id(pub_key: &str) -> Result<[u8; 32]> {
+    let key = base64::decode(pub_key)
+        .map_err(|e| error!("console key {} is not base64: {}", pub_key, e))?;
+    if key.len() != 32 {
+        fail!("console key {} must be 32 bytes long", pub_key)
+    }
+    let key = &key[..32];
+    let key = KeyOption::from_type_and_public_key(KeyOption::KEY_ED25519, &from_slice!(key, 32));
+    Ok(key.id().data().clone())
+}
+
+// Roles of control server clients by key id
+#[derive(Debug, Default)]
+struct ConsoleAcl {
+    roles: HashMap<[u8; 32], ConsoleRole>,
+    // Control server accepts any client, they are admins as before roles were introduced
+    any_client: bool,
+}
+
+impl ConsoleAcl {
+
+    // Clients of control server without role are admins
+    fn with_config(config: &TonNodeConfig) -> Result<Self> {
+        let mut acl = Self::default();
+        if let Some(server) = &config.control_server {
+            let server = serde_json::to_value(server)?;
+            match server.pointer("/clients/list").and_then(|list| list.as_array()) {
+                Some(clients) => for client in clients {
+                    if let Some(pub_key) = client.get("pub_key").and_then(|key| key.as_str()) {
+                        acl.roles.insert(console_key_id(pub_key)?, ConsoleRole::Admin);
+                    }
+                }
+                None => acl.any_client = true
+            }
+        }
+        for key in config.console_keys.iter() {
+            acl.roles.insert(console_key_id(&key.pub_key)?, key.role);
+        }
+        Ok(acl)
+    }
+
+    fn role(&self, key_id: &[u8; 32]) -> Option<ConsoleRole> {
+        match self.roles.get(key_id) {
+            Some(role) => Some(*role),
+            None if self.any_client => Some(ConsoleRole::Admin),
+            None => None
+        }
+    }
+
+    fn has_admin(&self) -> bool {
+        self.any_client || self.roles.values().any(|role| *role == ConsoleRole::Admin)
+    }
+}
+
+const ENV_OVERRIDE_PREFIX: &str = "TON_NODE__";
+// Values of fields with these words in path are not logged
+const SECRET_WORDS: [&str; 4] = ["key", "password", "secret", "token"];
+
+// Config field set by environment variable, e.g. TON_NODE__CONTROL_SERVER__ADDRESS
+// for control_server.address; numbers in path are indexes of arrays
+#[derive(Debug, Clone, Default)]
+pub struct EnvOverride {
+    var: String,
+    path: Vec<String>,
+    value: String,
+    // The value in file is at path[..restore_len], None if it was absent
+    restore_len: usize,
+    original: Option<serde_json::Value>,
+}
+
+impl EnvOverride {
+
+    fn apply(json: &mut serde_json::Value, var: String, value: String) -> Result<Self> {
+        let path: Vec<String> = var[ENV_OVERRIDE_PREFIX.len()..].split("__")
+            .map(|name| name.to_lowercase())
+            .collect();
+        let mut env_override = EnvOverride { var, path, value, ..Default::default() };
+        env_override.set(json).map_err(|e| error!("{}: {}", env_override.var, e))?;
+        Ok(env_override)
+    }
+
+    fn set(&mut self, json: &mut serde_json::Value) -> Result<()> {
+        if self.path.iter().any(|name| name.is_empty()) {
+            fail!("empty field name")
+        }
+        let path = self.path.clone();
+        let mut target = json;
+        for (i, name) in path.iter().enumerate() {
+            if target.is_null() {
+                // Absent section is created
+                *target = serde_json::Value::Object(serde_json::Map::new());
+            }
+            match target {
+                serde_json::Value::Object(map) => if !map.contains_key(name) {
+                    map.insert(name.clone(), serde_json::Value::Null);
+                    self.remember(i, None);
+                }
+                serde_json::Value::Array(_) => (),
+                _ => fail!("{} is not a section", path[..i].join("."))
+            }
+            let child = Self::child(target, name)?;
+            if child.is_null() || i + 1 == path.len() {
+                self.remember(i, Some(child.clone()));
+            }
+            target = child;
+        }
+        *target = coerce_env_value(target, &self.value)?;
+        Ok(())
+    }
+
+    // The outermost changed value is kept
+    fn remember(&mut self, depth: usize, original: Option<serde_json::Value>) {
+        if self.restore_len == 0 {
+            self.restore_len = depth + 1;
+            self.original = original;
+        }
+    }
+
+    fn child<'a>(json: &'a mut serde_json::Value, name: &str) -> Result<&'a mut serde_json::Value> {
+        let child = match json {
+            serde_json::Value::Array(items) => {
+                let index = name.parse::<usize>()
+                    .map_err(|_| error!("{} is not an index of array", name))?;
+                items.get_mut(index)
+            }
+            json => json.get_mut(name)
+        };
+        child.ok_or_else(|| error!("{} is not found", name))
+    }
+
+    // Puts back the value from file
+    fn revert(&self, json: &mut serde_json::Value) {
+        let (name, parents) = match self.path[..self.restore_len].split_last() {
+            Some(last) => last,
+            None => return
+        };
+        let mut target = json;
+        for parent in parents {
+            target = match Self::child(target, parent) {
+                Ok(child) => child,
+                Err(_) => return
+            };
+        }
+        match (&self.original, target) {
+            (None, serde_json::Value::Object(map)) => {
+                map.remove(name);
+            }
+            (Some(original), target) => {
+                if let Ok(child) = Self::child(target, name) {
+                    *child = original.clone();
+                }
+            }
+            _ => ()
+        }
+    }
+
+    fn is_secret(&self) -> bool {
+        self.path.iter().any(|name| SECRET_WORDS.iter().any(|word| name.contains(word)))
+    }
+}
+
+impl std::fmt::Display for EnvOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = if self.is_secret() { "***" } else { &self.value };
+        write!(f, "{} = {} (from {})", self.path.join("."), value, self.var)
+    }
+}
+
+// Value of variable is taken as the type of the field it replaces
+fn coerce_env_value(current: &serde_json::Value, value: &str) -> Result<serde_json::Value> {
+    let parsed = serde_json::from_str::<serde_json::Value>(value).ok();
+    match current {
+        serde_json::Value::Bool(_) => match parsed {
+            Some(serde_json::Value::Bool(value)) => Ok(value.into()),
+            _ => fail!("{} is not a boolean", value)
+        }
+        serde_json::Value::Number(_) => match parsed {
+            Some(serde_json::Value::Number(value)) => Ok(value.into()),
+            _ => fail!("{} is not a number", value)
+        }
+        serde_json::Value::String(_) => Ok(value.into()),
+        // Type of absent field is unknown: number or boolean if it looks so
+        serde_json::Value::Null => match parsed {
+            Some(parsed @ serde_json::Value::Bool(_)) |
+            Some(parsed @ serde_json::Value::Number(_)) => Ok(parsed),
+            _ => Ok(value.into())
+        }
+        _ => fail!("only scalar fields can be overridden")
+    }
+}
+
+// Address field of ADNL json config must be "ip:port" with nonzero port
+fn check_json_address(config: &impl serde::Serialize, field: &str) -> Result<()> {
+    let config = serde_json::to_value(config)?;
+    let address = config.get(field).and_then(|address| address.as_str())
+        .ok_or_else(|| error!("{} is not set", field))?;
+    let address = address.parse::<std::net::SocketAddr>()
+        .map_err(|e| error!("{} {} is not ip:port: {}", field, address, e))?;
+    if address.port() == 0 {
+        fail!("{} {}: port must not be zero", field, address)
+    }
+    Ok(())
+}
+
+// Addresses of private, loopback, link-local, shared (CGNAT) and unspecified ranges
+// are not reachable from the internet
+pub fn check_public_ip(ip: IpAddr) -> Result<()> {
+    let private = match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()
+                || ip.is_broadcast() || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback() || ip.is_unspecified() || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+        }
+    };
+    if private {
+        fail!("{} is not a public address", ip)
+    }
+    Ok(())
+}
+
+impl TonNodeConfig {
+    // None - all workchains are written
+    pub fn front_workchain_ids(&self) -> Option<Vec<i32>> {
+        if let Some(front_workchains) = &self.front_workchains {
+            return front_workchains.ids()
+        }
+        match self.workchain {
+            None | Some(0) | Some(-1) => Some(vec![MASTERCHAIN_ID, BASE_WORKCHAIN_ID]),
+            Some(workchain_id) => Some(vec![workchain_id])
+        }
+    }
+    pub fn front_workchains_strict(&self) -> bool {
+        self.front_workchains_strict
+    }
+    pub fn workchain_id(&self) -> Option<i32> {
+        self.workchain
+    }
+    pub fn from_file(
+        configs_dir: &str,
+        json_file_name: &str,
+        adnl_config: Option<AdnlNodeConfigJson>,
+        default_config_name: &str,
+        client_console_key: Option<String>
+    ) -> Result<Self> { 
+        let config_file_path = TonNodeConfig::build_path(configs_dir, json_file_name)?;
+        let config_file = File::open(config_file_path.clone());
+
+        let mut generated = false;
+        let (config_json, file_json) = match config_file {
+            Ok(file) => {
+                let reader = BufReader::new(file);
+                let file_json: serde_json::Value = serde_json::from_reader(reader)
+                    .map_err(|e| error!("Can't parse {}: {}", config_file_path, e))?;
+                let config: TonNodeConfig = serde_json::from_value(file_json.clone())
+                    .map_err(|e| error!("Can't parse {}: {}", config_file_path, e))?;
+
+                if client_console_key.is_some() {
+                    println!("Can't generate console_config.json: delete config.json before");
+                }
+                (config, file_json)
+            }
+            Err(_) => {
+                // generate new config from default_config
+                let path = TonNodeConfig::build_path(configs_dir, default_config_name)?;
+                let default_config_file = File::open(&path)
+                    .map_err(|err| error!("Can`t open {}: {}", path, err))?;
+
+                let reader = BufReader::new(default_config_file);
+                let file_json: serde_json::Value = serde_json::from_reader(reader)
+                    .map_err(|e| error!("Can't parse {}: {}", path, e))?;
+                let config = TonNodeConfig::generate(
+                    configs_dir, &file_json, adnl_config, client_console_key
+                ).map_err(|e| error!("Can't generate config from {}: {}", path, e))?;
+                // Written when resource profile is applied
+                generated = true;
+                (config, file_json)
+            }
+        };
+        config_json.complete(configs_dir, json_file_name, &file_json, generated)
+    }
+
+    // New config.json from template (as default_config.json) with new ADNL keys,
+    // written only if the result is valid
+    pub fn generate_file(
+        configs_dir: &str,
+        json_file_name: &str,
+        template: &serde_json::Value,
+        client_console_key: Option<String>
+    ) -> Result<Self> {
+        TonNodeConfig::generate(configs_dir, template, None, client_console_key)?
+            .complete(configs_dir, json_file_name, template, true)
+    }
+
+    fn generate(
+        configs_dir: &str,
+        template: &serde_json::Value,
+        adnl_config: Option<AdnlNodeConfigJson>,
+        client_console_key: Option<String>
+    ) -> Result<Self> {
+        let mut config: TonNodeConfig = serde_json::from_value(template.clone())?;
+        // Set ADNL config
+        config.adnl_node = if let Some(adnl_config) = adnl_config {
+            Some(adnl_config)
+        } else {
+            let ip_address = if let Some(ip_address) = &config.ip_address {
+                ip_address
+            } else {
+                fail!("IP address is not set in default config")
+            };
+            let (adnl_config, _) = AdnlNodeConfig::with_ip_address_and_key_type(
+                ip_address,
+                KeyOption::KEY_ED25519,
+                vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
+            )?;
+            Some(adnl_config)
+        };
+        config.create_and_save_console_configs(
+            configs_dir,
+            client_console_key
+        )?;
+        config.ip_address = None;
+        Ok(config)
+    }
+
+    fn complete(
+        self,
+        configs_dir: &str,
+        json_file_name: &str,
+        file_json: &serde_json::Value,
+        generated: bool
+    ) -> Result<Self> {
+        let config_file_path = TonNodeConfig::build_path(configs_dir, json_file_name)?;
+        let env_vars = std::env::vars_os().filter_map(
+            |(var, value)| Some((var.into_string().ok()?, value.into_string().ok()?))
+        );
+        let mut config_json = self.with_env_overrides(env_vars)?
+            .with_resource_profile(file_json)?;
+        config_json.configs_dir = configs_dir.to_string();
+        config_json.file_name = json_file_name.to_string();
+        // Wrong passphrase is reported by validation of validator_key_ring
+        config_json.keyring_cipher = KeyringCipher::with_config(&config_json.keyring_encryption)?
+            .map(Arc::new);
+
+        let problems = config_json.validate();
+        if !problems.is_empty() {
+            fail!(NodeError::Config(ConfigError::Invalid, format!(
+                "{} problem(s) in {}:\n  {}", problems.len(), config_file_path, problems.join("\n  ")
+            )))
+        }
+        if generated {
+            config_json.save_to_file(json_file_name)?;
+        }
+        if config_json.archive_node.enabled {
+            let gc_enabled = config_json.gc.as_ref().map(|gc| gc.enable_for_archives).unwrap_or(false);
+            if gc_enabled || config_json.blocks_retention.enabled() ||
+                config_json.persistent_states.keep_states.is_some() ||
+                !config_json.persistent_states.generate
+            {
+                log::warn!(
+                    "Archive node keeps everything: gc, blocks_retention and persistent_states \
+                    settings are ignored"
+                );
+            }
+        }
+
+        Ok(config_json)
+    }
+
+    // Fields set by TON_NODE__<SECTION>__<FIELD> variables, the file keeps its own values
+    fn with_env_overrides(self, vars: impl Iterator<Item = (String, String)>) -> Result<Self> {
+        let mut vars: Vec<_> = vars.filter(|(var, _)| var.starts_with(ENV_OVERRIDE_PREFIX)).collect();
+        if vars.is_empty() {
+            return Ok(self)
+        }
+        vars.sort();
+        let mut json = serde_json::to_value(&self)?;
+        let mut env_overrides = Vec::new();
+        for (var, value) in vars {
+            env_overrides.push(EnvOverride::apply(&mut json, var, value)?);
+        }
+        let mut config: TonNodeConfig = serde_json::from_value(json)
+            .map_err(|e| error!("Can't apply environment overrides: {}", e))?;
+        config.env_overrides = env_overrides;
+        Ok(config)
+    }
+
+    pub fn env_overrides(&self) -> &[EnvOverride] {
+        &self.env_overrides
+    }
+
+    // Values of resource profile for fields absent in the file and not set by environment
+    fn with_resource_profile(self, file_json: &serde_json::Value) -> Result<Self> {
+        let mut json = serde_json::to_value(&self)?;
+        let mut profile_values = Vec::new();
+        for (path, value) in self.resource_profile.values() {
+            let in_file = path.split('.').try_fold(file_json, |json, name| json.get(name)).is_some();
+            let overridden = self.env_overrides.iter()
+                .any(|env_override| env_override.path.join(".") == path);
+            if !in_file && !overridden {
+                let profile_value = ProfileValue { path, value };
+                profile_value.set(&mut json);
+                profile_values.push(profile_value);
+            }
+        }
+        let mut config: TonNodeConfig = serde_json::from_value(json)
+            .map_err(|e| error!("Can't apply resource profile: {}", e))?;
+        // Not serialized fields
+        config.ip_address = self.ip_address;
+        config.control_server_port = self.control_server_port;
+        config.env_overrides = self.env_overrides;
+        config.profile_values = profile_values;
+        Ok(config)
+    }
+
+    // Profile and the values it sets along with ones set explicitly
+    pub fn resource_profile_summary(&self) -> String {
+        format!(
+            "{}, cells cache: {} MB, states cache: {} MB, rocksdb {}, \
+            ext messages: {} bytes, apply pipeline depth: {}, runtime {}",
+            self.resource_profile, self.cells_cache_size_mb, self.states_cache_size_mb,
+            self.rocksdb, self.ext_messages.max_total_bytes,
+            self.archives_sync.apply_pipeline_depth, self.runtime
+        )
+    }
+
+    pub fn rocksdb_config(&self) -> &RocksDbConfig {
+        &self.rocksdb
+    }
+
+    // Private key of validator key ring, decrypted if keyring encryption is on
+    fn validator_private_key(&self, key_id: &str, key: &KeyOptionJson) -> Result<KeyOption> {
+        let encrypted = KeyringCipher::is_encrypted(key)
+            .map_err(|e| error!("key {}: {}", key_id, e))?;
+        let decrypted;
+        let key = match (&self.keyring_cipher, encrypted) {
+            (Some(cipher), true) => {
+                decrypted = cipher.decrypt(key_id, key)?;
+                &decrypted
+            }
+            (Some(_), false) => fail!(
+                "key {} is stored unencrypted, convert config with --encrypt-keys", key_id
+            ),
+            (None, true) => fail!(
+                "key {} is encrypted, but keyring_encryption is not enabled", key_id
+            ),
+            (None, false) => key
+        };
+        KeyOption::from_private_key(key).map_err(|e| error!("key {}: {}", key_id, e))
+    }
+
+    // Encrypts plaintext keys of validator key ring in the config file and enables
+    // keyring encryption there. Other fields are kept as they are in the file.
+    pub fn encrypt_keys_in_file(configs_dir: &str, json_file_name: &str) -> Result<usize> {
+        let path = TonNodeConfig::build_path(configs_dir, json_file_name)?;
+        let mut json: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&path)?)
+            .map_err(|e| error!("Can't parse {}: {}", path, e))?;
+        let json = json.as_object_mut().ok_or_else(|| error!("{} is not a json object", path))?;
+        let mut encryption: KeyringEncryptionConfig = match json.get("keyring_encryption") {
+            Some(encryption) => serde_json::from_value(encryption.clone())?,
+            None => KeyringEncryptionConfig::default()
+        };
+        encryption.enabled = true;
+        let cipher = KeyringCipher::with_config(&encryption)?
+            .ok_or_else(|| error!("Keyring encryption is not enabled"))?;
+        let mut encrypted = 0;
+        if let Some(key_ring) = json.get_mut("validator_key_ring").and_then(|v| v.as_object_mut()) {
+            for (key_id, key) in key_ring.iter_mut() {
+                let key_json: KeyOptionJson = serde_json::from_value(key.clone())?;
+                if KeyringCipher::is_encrypted(&key_json)? {
+                    // All keys must have the same passphrase
+                    cipher.decrypt(key_id, &key_json)?;
+                } else {
+                    KeyOption::from_private_key(&key_json)
+                        .map_err(|e| error!("key {}: {}", key_id, e))?;
+                    *key = serde_json::to_value(cipher.encrypt(key_id, &key_json)?)?;
+                    encrypted += 1;
+                }
+            }
+        }
+        json.insert("keyring_encryption".to_string(), serde_json::to_value(&encryption)?);
+        std::fs::write(&path, serde_json::to_string_pretty(&json)?)?;
+        Ok(encrypted)
+    }
+
+    // All problems of the config at once, each prefixed with its section
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut report = |section: &str, result: Result<()>| {
+            if let Err(e) = result {
+                problems.push(format!("{}: {}", section, e))
+            }
+        };
+        report("connectivity_check_config", self.connectivity_check_config.check());
+        report("runtime", self.runtime.check());
+        report("rocksdb", self.rocksdb.check());
+        if let Some(runtime) = &self.validator_manager.runtime {
+            report("validator_manager.runtime", runtime.check());
+        }
+        report("ext_messages", self.ext_messages.check());
+        report("db_compaction", self.db_compaction.check());
+        report("blocks_retention", self.blocks_retention.check());
+        report("db_integrity_check", self.db_integrity_check.check());
+        report("db_write_behind", self.db_write_behind.check());
+        report("persistent_states", self.persistent_states.check());
+        report("db_backup", self.db_backup.check());
+        report("db_scrubber", self.db_scrubber.check());
+        report("archives_sync", self.archives_sync.check());
+        report("archive_node", self.archive_node.check());
+        report("broadcast_dedup", self.broadcast_dedup.check());
+        report("network", self.network.check());
+        report("tracing", self.tracing.check());
+        report("health_server", self.health_server.check());
+        report("shutdown", self.shutdown.check());
+        report("supervisor", self.supervisor.check());
+        if let Some(init_block) = &self.init_block {
+            report("init_block", init_block.check());
+        }
+        report("tracked_shards", self.check_tracked_shards());
+        if self.test_rng_seed.is_some() {
+            report("test_rng_seed", self.check_test_rng_seed());
+        }
+        if let Some(front_workchains) = &self.front_workchains {
+            report("front_workchains", front_workchains.check());
+        }
+
+        // Addresses and keys of network services
+        if let Some(adnl_node) = &self.adnl_node {
+            report("adnl_node", check_json_address(adnl_node, "ip_address"));
+            report("adnl_node", AdnlNodeConfig::from_json_config(adnl_node, true).map(|_| ()));
+        }
+        if self.control_server_port == Some(0) {
+            report("control_server_port", Err(error!("port must not be zero")));
+        }
+        for (section, server) in [
+            ("control_server", &self.control_server),
+            ("lite_server", &self.lite_server)
+        ].iter() {
+            if let Some(server) = server {
+                report(*section, check_json_address(server, "address"));
+                report(*section, AdnlServerConfig::from_json_config(server).map(|_| ()));
+            }
+        }
+        report("console_keys", ConsoleAcl::with_config(self).map(|_| ()));
+        if let Some(key_ring) = &self.validator_key_ring {
+            for (key_id, key) in key_ring.iter() {
+                report(
+                    "validator_key_ring",
+                    self.validator_private_key(key_id, key).map(|_| ())
+                );
+            }
+        }
+
+        report("log_levels", self.log_levels().map(|_| ()));
+        if let Some(log_file) = &self.log_file {
+            report("log_file", log_file.check());
+        }
+
+        // Referenced files
+        if let Some(path) = self.log_config_path() {
+            if !Path::new(&path).is_file() {
+                report("log_config_name", Err(error!("file {} is not found", path)));
+            }
+        }
+        report("ton_global_config_name", self.check_global_config());
+
+        for (i, config) in self.external_db_config().iter().enumerate() {
+            let name = config.name.clone().unwrap_or_else(|| i.to_string());
+            report(&format!("external_db_config {}", name), config.check());
+        }
+        if let Some(consumer) = &self.kafka_consumer_config {
+            if consumer.topic.is_empty() || consumer.brokers.is_empty() {
+                report("kafka_consumer_config", Err(error!("topic and brokers must be set")));
+            }
+        }
+        problems
+    }
+
+    fn check_tracked_shards(&self) -> Result<()> {
+        for shard in self.tracked_shards()? {
+            if shard.is_masterchain() {
+                fail!("tracked_shards can't contain masterchain, it is always tracked");
+            }
+            if let Some(workchain_id) = self.workchain {
+                if shard.workchain_id() != workchain_id {
+                    fail!("tracked shard {} is not in configured workchain {}", shard, workchain_id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_test_rng_seed(&self) -> Result<()> {
+        let validator = self.validator_keys.as_ref().map(|keys| !keys.is_empty()).unwrap_or(false);
+        if validator {
+            self.check_validator_randomness()?
+        }
+        Ok(())
+    }
+
+    // Validator of public network must not have deterministic randomness, it is checked
+    // at config load and when validator key is added in runtime
+    fn check_validator_randomness(&self) -> Result<()> {
+        if self.test_rng_seed.is_some() && self.load_global_config()?.is_public_network() {
+            fail!("deterministic randomness is refused for validator of public network")
+        }
+        Ok(())
+    }
 
-        Ok(config_json)
+    fn check_global_config(&self) -> Result<()> {
+        let global_config = self.load_global_config()?;
+        let zero_state = global_config.zero_state()?;
+        if !zero_state.shard().is_masterchain() || zero_state.seq_no() != 0 {
+            fail!("zero_state {} is not masterchain block with seqno 0", zero_state)
+        }
+        global_config.hardforks()?;
+        Ok(())
     }
 
+    // ADNL node listens on all interfaces at the port of its ip_address, so public address
+    // replaces only the ip there and the port is always the configured one
     pub fn adnl_node(&self) -> Result<AdnlNodeConfig> {
         let adnl_node = self.adnl_node.as_ref().ok_or_else(|| error!("ADNL node is not configured!"))?;
 
-        let mut ret = AdnlNodeConfig::from_json_config(&adnl_node, true)?;
+        let mut ret = match &self.public_address {
+            Some(ip) => {
+                let address = SocketAddr::new(*ip, self.configured_address()?.port());
+                let mut adnl_node = serde_json::to_value(adnl_node)?;
+                adnl_node["ip_address"] = address.to_string().into();
+                AdnlNodeConfig::from_json_config(&serde_json::from_value(adnl_node)?, true)?
+            }
+            None => AdnlNodeConfig::from_json_config(&adnl_node, true)?
+        };
         if let Some(port) = self.port {
             ret.set_port(port)
         }
         Ok(ret)
     }
 
+    // "ip" or "ip:port", the port must be the one ADNL node listens on: port mapping is not
+    // supported. Private addresses are allowed for test networks only.
+    pub fn set_public_address(&mut self, address: &str, allow_private: bool) -> Result<SocketAddr> {
+        let port = self.advertised_address()?.port();
+        let ip = match address.parse::<SocketAddr>() {
+            Ok(address) => {
+                if address.port() != port {
+                    fail!(
+                        "public address {}: port must be ADNL port {}, port mapping is not supported",
+                        address, port
+                    )
+                }
+                address.ip()
+            }
+            Err(_) => address.parse::<IpAddr>()
+                .map_err(|e| error!("public address {} is neither ip nor ip:port: {}", address, e))?
+        };
+        if !allow_private {
+            check_public_ip(ip)?;
+        }
+        self.public_address = Some(ip);
+        Ok(SocketAddr::new(ip, port))
+    }
+
+    // Address of the node in its address list and DHT
+    pub fn advertised_address(&self) -> Result<SocketAddr> {
+        let mut address = self.configured_address()?;
+        if let Some(ip) = self.public_address {
+            address.set_ip(ip)
+        }
+        if let Some(port) = self.port {
+            address.set_port(port)
+        }
+        Ok(address)
+    }
+
+    fn configured_address(&self) -> Result<SocketAddr> {
+        let adnl_node = self.adnl_node.as_ref().ok_or_else(|| error!("ADNL node is not configured!"))?;
+        let adnl_node = serde_json::to_value(adnl_node)?;
+        let address = adnl_node.get("ip_address").and_then(|address| address.as_str())
+            .ok_or_else(|| error!("ADNL node ip_address is not set"))?;
+        address.parse().map_err(|e| error!("ADNL node ip_address {} is invalid: {}", address, e))
+    }
+
     pub fn control_server(&self) -> Result<Option<AdnlServerConfig>> {
         match &self.control_server {
-            Some(cs) => Ok(Some(AdnlServerConfig::from_json_config(cs)?)),
+            Some(cs) => Ok(Some(AdnlServerConfig::from_json_config(&self.with_console_keys(cs)?)?)),
+            None => Ok(None)
+        }
+    }
+
+    // Console keys are added to clients of control server, unless it accepts any client
+    fn with_console_keys(&self, server: &AdnlServerConfigJson) -> Result<AdnlServerConfigJson> {
+        let mut server = serde_json::to_value(server)?;
+        if let Some(clients) = server.pointer_mut("/clients/list").and_then(|list| list.as_array_mut()) {
+            for key in self.console_keys.iter() {
+                if !clients.iter().any(|client| client["pub_key"] == key.pub_key.as_str()) {
+                    clients.push(serde_json::json!({
+                        "type_id": KeyOption::KEY_ED25519,
+                        "pub_key": key.pub_key
+                    }));
+                }
+            }
+        }
+        Ok(serde_json::from_value(server)?)
+    }
+
+    pub fn lite_server(&self) -> Result<Option<AdnlServerConfig>> {
+        match &self.lite_server {
+            Some(ls) => Ok(Some(AdnlServerConfig::from_json_config(ls)?)),
             None => Ok(None)
         }
     }
 
+    // Level of targets not set in log_levels (info by default) and levels of set targets
+    pub fn log_levels(&self) -> Result<(log::LevelFilter, Vec<(String, log::LevelFilter)>)> {
+        let mut default = log::LevelFilter::Info;
+        let mut levels = Vec::new();
+        for (target, level) in self.log_levels.iter() {
+            let level = log::LevelFilter::from_str(level)
+                .map_err(|_| error!("unknown level {} of target {}", level, target))?;
+            if target == DEFAULT_LOG_TARGET {
+                default = level;
+            } else {
+                levels.push((target.clone(), level));
+            }
+        }
+        levels.sort();
+        Ok((default, levels))
+    }
+
+    pub fn log_file_config(&self) -> Option<&LogFileConfig> {
+        self.log_file.as_ref()
+    }
+
     pub fn log_config_path(&self) -> Option<String> {
         if let Some(log_config_name) = &self.log_config_name {
             if let Ok(log_path) = TonNodeConfig::build_path(&self.configs_dir, &log_config_name) {
@@ -311,7 +2387,15 @@ impl TonNodeConfig {
         None
     }
 
+    // Directory and name of the file the config is loaded from
+    pub fn file_location(&self) -> (&str, &str) {
+        (&self.configs_dir, &self.file_name)
+    }
+
     pub fn gc_archives_life_time_hours(&self) -> Option<u32> {
+        if self.archive_node.enabled {
+            return None;
+        }
         match &self.gc {
             Some(gc) => {
                 if !gc.enable_for_archives {
@@ -337,27 +2421,185 @@ impl TonNodeConfig {
     pub fn cells_gc_interval_ms(&self) -> u32 {
         self.cells_gc_interval_ms
     }
+
+    pub fn cells_cache_size_mb(&self) -> u64 {
+        self.cells_cache_size_mb
+    }
+
+    pub fn states_cache_size_mb(&self) -> u64 {
+        self.states_cache_size_mb
+    }
+
+    pub fn block_handles_cache_size(&self) -> usize {
+        self.block_handles_cache_size
+    }
     
   
-    pub fn external_db_config(&self) -> Option<ExternalDbConfig> {
-        self.external_db_config.clone()
+    pub fn external_db_config(&self) -> Vec<ExternalDbConfig> {
+        match &self.external_db_config {
+            Some(ExternalDbConfigs::Multiple(configs)) => configs.clone(),
+            Some(ExternalDbConfigs::Single(config)) => vec!(config.clone()),
+            None => Vec::new()
+        }
+    }
+    // Engine-wide settings are taken from the first sink
+    pub fn external_db_healthcheck_config(&self) -> ExternalDbHealthcheckConfig {
+        self.external_db_config().first().map(|config| config.healthcheck.clone()).unwrap_or_default()
+    }
+    pub fn external_db_ack_config(&self) -> ExternalDbAckConfig {
+        self.external_db_config().first().map(|config| config.ack.clone()).unwrap_or_default()
     }
     pub fn test_bundles_config(&self) -> &CollatorTestBundlesGeneralConfig {
         &self.test_bundles_config
     }
+    pub fn collator_settings(&self) -> &CollatorSettings {
+        &self.collator
+    }
     pub fn connectivity_check_config(&self) -> &ConnectivityCheckBroadcastConfig {
         &self.connectivity_check_config
     }
     pub fn extensions(&self) -> Option<&NodeExtensions> {
         self.extensions.as_ref()
     }
+    pub fn validator_manager_config(&self) -> ValidatorManagerConfig {
+        let mut config = self.validator_manager.clone();
+        if config.runtime.is_none() {
+            config.runtime = Some(self.runtime.clone());
+        }
+        config
+    }
+
+    pub fn runtime_config(&self) -> &RuntimeConfig {
+        &self.runtime
+    }
+    pub fn ext_messages_config(&self) -> &ExtMessagesConfig {
+        &self.ext_messages
+    }
+    pub fn db_compaction_config(&self) -> &DbCompactionConfig {
+        &self.db_compaction
+    }
+    pub fn blocks_retention_config(&self) -> BlocksRetentionConfig {
+        let mut config = self.blocks_retention.clone();
+        if self.archive_node.enabled {
+            config.keep_days = None;
+            config.keep_persistent_states = None;
+        }
+        config
+    }
+    pub fn states_gc_policy(&self) -> &StatesGcPolicy {
+        &self.states_gc_policy
+    }
+    pub fn db_integrity_check_config(&self) -> &DbIntegrityCheckConfig {
+        &self.db_integrity_check
+    }
+
+    pub fn db_write_behind_config(&self) -> &DbWriteBehindConfig {
+        &self.db_write_behind
+    }
+
+    pub fn persistent_states_config(&self) -> PersistentStatesConfig {
+        let mut config = self.persistent_states.clone();
+        if self.archive_node.enabled {
+            config.generate = true;
+            config.keep_states = None;
+        }
+        config
+    }
+
+    pub fn internal_db_paths(&self) -> &DbPathsConfig {
+        &self.internal_db_paths
+    }
+
+    pub fn index_transactions(&self) -> bool {
+        self.index_transactions
+    }
+
+    pub fn db_backup_config(&self) -> &DbBackupConfig {
+        &self.db_backup
+    }
+
+    pub fn db_scrubber_config(&self) -> &DbScrubberConfig {
+        &self.db_scrubber
+    }
+
+    pub fn archives_sync_config(&self) -> &ArchivesSyncConfig {
+        &self.archives_sync
+    }
+
+    pub fn bandwidth_limits_config(&self) -> BandwidthLimitsConfig {
+        let mut config = self.bandwidth_limits.clone();
+        if self.archive_node.enabled {
+            config.serve_kb_per_sec = self.archive_node.serve_kb_per_sec;
+        }
+        config
+    }
+
+    pub fn archive_node_config(&self) -> &ArchiveNodeConfig {
+        &self.archive_node
+    }
+
+    pub fn shard_catch_up_config(&self) -> &ShardCatchUpConfig {
+        &self.shard_catch_up
+    }
+
+    pub fn broadcast_dedup_config(&self) -> &BroadcastDedupConfig {
+        &self.broadcast_dedup
+    }
+
+    pub fn network_config(&self) -> &NetworkConfig {
+        &self.network
+    }
+
+    pub fn tracing_config(&self) -> &TracingConfig {
+        &self.tracing
+    }
+
+    pub fn health_server_config(&self) -> &HealthServerConfig {
+        &self.health_server
+    }
+
+    pub fn shutdown_config(&self) -> &ShutdownConfig {
+        &self.shutdown
+    }
+
+    pub fn supervisor_config(&self) -> &SupervisorConfig {
+        &self.supervisor
+    }
+
+    pub fn test_rng_seed(&self) -> Option<u64> {
+        self.test_rng_seed
+    }
+
+    pub fn metrics_config(&self) -> &MetricsConfig {
+        &self.metrics
+    }
+
+    pub fn tracked_shards(&self) -> Result<Vec<ShardIdent>> {
+        self.tracked_shards.iter().map(|shard| {
+            let (workchain_id, prefix) = shard.split_once(':')
+                .ok_or_else(|| error!("tracked shard {} must be \"workchain:shard\"", shard))?;
+            let workchain_id = workchain_id.parse::<i32>()
+                .map_err(|e| error!("tracked shard {}: invalid workchain: {}", shard, e))?;
+            let prefix = u64::from_str_radix(prefix, 16)
+                .map_err(|e| error!("tracked shard {}: invalid shard id: {}", shard, e))?;
+            ShardIdent::with_tagged_prefix(workchain_id, prefix)
+        }).collect()
+    }
+
+    pub fn init_block(&self) -> Result<Option<BlockIdExt>> {
+        self.init_block.as_ref().map(|init_block| init_block.block_id()).transpose()
+    }
 
  
-    pub fn load_global_config(&self) -> Result<TonNodeGlobalConfig> {
+    pub fn global_config_path(&self) -> Result<String> {
         let name = self.ton_global_config_name.as_ref().ok_or_else(
             || error!("global config information not found in config.json!")
         )?;
-        let global_config_path = TonNodeConfig::build_path(&self.configs_dir, &name)?;
+        TonNodeConfig::build_path(&self.configs_dir, &name)
+    }
+
+    pub fn load_global_config(&self) -> Result<TonNodeGlobalConfig> {
+        let global_config_path = self.global_config_path()?;
 /*        
         let data = std::fs::read_to_string(global_config_path)
             .map_err(|err| error!("Global config file is not found! : {}", err))?;
@@ -396,17 +2638,21 @@ impl TonNodeConfig {
         std::fs::write(config_file_path, serde_json::to_string_pretty(&console_client_config)?)
             .map_err(|err| error!("Can`t create console_config.json: {}", err))?;
 
-        // generate and save server config
-        let client_keys = if let Some(client_key) = client_pub_key {
-            vec![serde_json::from_str(&client_key)?]
-        } else {
-            Vec::new()
-        };
+        // generate and save server config, console key given by command line is admin one
+        if let Some(client_key) = client_pub_key {
+            let client_key: serde_json::Value = serde_json::from_str(&client_key)?;
+            let pub_key = client_key.get("pub_key").and_then(|key| key.as_str())
+                .ok_or_else(|| error!("console key {} has no pub_key", client_key))?;
+            self.console_keys.push(ConsoleKeyJson {
+                pub_key: pub_key.to_string(),
+                role: ConsoleRole::Admin
+            });
+        }
 
         let console_server_config = AdnlServerConfigJson::with_params(
             server_address,
             server_private_key,
-            client_keys,
+            Vec::new(),
             None
         );
 
@@ -466,15 +2712,27 @@ impl TonNodeConfig {
 
     fn save_to_file(&self, file_name: &str) -> Result<()> {
         let config_file_path = TonNodeConfig::build_path(&self.configs_dir, file_name)?;
-        std::fs::write(config_file_path, serde_json::to_string_pretty(&self)?)?;
+        let mut json = serde_json::to_value(&self)?;
+        for env_override in self.env_overrides.iter().rev() {
+            env_override.revert(&mut json);
+        }
+        for profile_value in self.profile_values.iter() {
+            profile_value.revert(&mut json);
+        }
+        std::fs::write(config_file_path, serde_json::to_string_pretty(&json)?)?;
         Ok(())
     }
 
     fn generate_and_save_keys(&mut self) -> Result<([u8; 32], Arc<KeyOption>)> {
         let (private, public) = mine_key_for_workchain(self.workchain);
         let key_id = public.id().data();
+        let id = base64::encode(key_id);
+        let private = match &self.keyring_cipher {
+            Some(cipher) => cipher.encrypt(&id, &private)?,
+            None => private
+        };
         let key_ring = self.validator_key_ring.get_or_insert_with(|| HashMap::new());
-        key_ring.insert(base64::encode(key_id), private);
+        key_ring.insert(id, private);
         Ok((key_id.clone(), Arc::new(public)))
     }
 
@@ -499,6 +2757,7 @@ impl TonNodeConfig {
         if !self.is_correct_election_id(election_id) {
             fail!("Invalid arg: bad election_id!");
         }
+        self.check_validator_randomness()?;
         let added_key_info = self.get_validator_key_info_by_election_id(&election_id)?;
         match &mut self.validator_keys {
             Some(validator_keys) => {
@@ -570,6 +2829,11 @@ enum Task {
     GetKey([u8; 32]),
     StoreWorkchainId(i32),
     StoreStatesGcInterval(u32),
+    StoreStatesGcPolicy(StatesGcPolicy),
+    StoreCellsCacheSize(u64),
+    StoreBandwidthLimits(BandwidthLimitsConfig),
+    AddConsoleKey(ConsoleKeyJson),
+    RemoveConsoleKey([u8; 32]),
 }
 
 #[derive(Debug)]
@@ -590,6 +2854,7 @@ pub struct NodeConfigHandler {
     key_ring: Arc<lockfree::map::Map<String, Arc<KeyOption>>>,
     validator_keys: Arc<ValidatorKeys>,
     workchain_id: Option<i32>,
+    console_acl: std::sync::RwLock<ConsoleAcl>,
 }
 
 impl NodeConfigHandler {
@@ -604,6 +2869,7 @@ impl NodeConfigHandler {
             key_ring: Arc::new(lockfree::map::Map::new()),
             validator_keys: Arc::new(ValidatorKeys::new()),
             workchain_id: config.workchain,
+            console_acl: std::sync::RwLock::new(ConsoleAcl::with_config(&config)?),
         });
 
         Ok((config_handler, NodeConfigHandlerContext{reader, config}))
@@ -653,6 +2919,111 @@ impl NodeConfigHandler {
         }
     }
 
+    // None if the client is not allowed to use control server
+    pub fn console_role(&self, key_id: &[u8; 32]) -> Option<ConsoleRole> {
+        self.console_acl.read().unwrap().role(key_id)
+    }
+
+    // Adds console key or changes its role. Control server accepts connections
+    // with the new key after restart, its client list is fixed on start.
+    pub async fn add_console_key(&self, key: ConsoleKeyJson) -> Result<()> {
+        self.console_key_task(Task::AddConsoleKey(key), "AddConsoleKey").await
+    }
+
+    pub async fn remove_console_key(&self, key_id: &[u8; 32]) -> Result<()> {
+        self.console_key_task(Task::RemoveConsoleKey(*key_id), "RemoveConsoleKey").await
+    }
+
+    async fn console_key_task(&self, task: Task, name: &str) -> Result<()> {
+        let (wait, mut queue_reader) = Wait::new();
+        let pushed_task = Arc::new((wait.clone(), task));
+        wait.request();
+        if let Err(e) = self.sender.send(pushed_task) {
+            fail!("Error {}: {}", name, e);
+        }
+        match wait.wait(&mut queue_reader, true).await {
+            Some(None) => fail!("Answer was not set!"),
+            Some(Some(Answer::Result(result))) => result,
+            Some(Some(_)) => fail!("Bad answer ({})!", name),
+            None => fail!("Waiting returned an internal error!")
+        }
+    }
+
+    // Changed ACL is applied only if it keeps an admin and is saved
+    fn change_console_keys_and_save(
+        &self,
+        config: &mut TonNodeConfig,
+        name: &str,
+        change: impl FnOnce(&mut TonNodeConfig) -> Result<()>
+    ) -> Result<()> {
+        let console_keys = config.console_keys.clone();
+        let control_server = config.control_server.as_ref().map(serde_json::to_value).transpose()?;
+        let result = change(config).and_then(|_| {
+            let acl = ConsoleAcl::with_config(config)?;
+            if !acl.has_admin() {
+                fail!("At least one admin console key must be kept")
+            }
+            config.save_to_file(name)?;
+            Ok(acl)
+        });
+        match result {
+            Ok(acl) => {
+                *self.console_acl.write().unwrap() = acl;
+                Ok(())
+            }
+            Err(e) => {
+                config.console_keys = console_keys;
+                config.control_server = control_server.map(serde_json::from_value).transpose()?;
+                Err(e)
+            }
+        }
+    }
+
+    fn add_console_key_and_save(
+        &self,
+        config: &mut TonNodeConfig,
+        name: &str,
+        key: &ConsoleKeyJson
+    ) -> Result<()> {
+        let key_id = console_key_id(&key.pub_key)?;
+        self.change_console_keys_and_save(config, name, |config| {
+            config.console_keys.retain(|other| console_key_id(&other.pub_key).ok() != Some(key_id));
+            config.console_keys.push(key.clone());
+            Ok(())
+        })
+    }
+
+    fn remove_console_key_and_save(
+        &self,
+        config: &mut TonNodeConfig,
+        name: &str,
+        key_id: &[u8; 32]
+    ) -> Result<()> {
+        self.change_console_keys_and_save(config, name, |config| {
+            let is_removed = |pub_key: Option<&str>| {
+                pub_key.and_then(|pub_key| console_key_id(pub_key).ok()).as_ref() == Some(key_id)
+            };
+            let count = config.console_keys.len();
+            config.console_keys.retain(|key| !is_removed(Some(&key.pub_key)));
+            let mut removed = count != config.console_keys.len();
+            if let Some(server) = &config.control_server {
+                let mut server = serde_json::to_value(server)?;
+                if let Some(clients) = server.pointer_mut("/clients/list")
+                    .and_then(|list| list.as_array_mut())
+                {
+                    let count = clients.len();
+                    clients.retain(|client| !is_removed(client["pub_key"].as_str()));
+                    removed |= count != clients.len();
+                }
+                config.control_server = Some(serde_json::from_value(server)?);
+            }
+            if !removed {
+                fail!("Console key {} is not found", base64::encode(key_id))
+            }
+            Ok(())
+        })
+    }
+
     pub fn store_workchain(&self, workchain_id: i32) {
         let (wait, _) = Wait::new();
         let pushed_task = Arc::new((wait.clone(), Task::StoreWorkchainId(workchain_id)));
@@ -671,6 +3042,33 @@ impl NodeConfigHandler {
         }
     }
 
+    pub fn store_states_gc_policy(&self, policy: StatesGcPolicy) {
+        let (wait, _) = Wait::new();
+        let pushed_task = Arc::new((wait.clone(), Task::StoreStatesGcPolicy(policy)));
+        wait.request();
+        if let Err(e) = self.sender.send(pushed_task) {
+            log::warn!("Problem store states gc policy: {}", e);
+        }
+    }
+
+    pub fn store_cells_cache_size(&self, size_mb: u64) {
+        let (wait, _) = Wait::new();
+        let pushed_task = Arc::new((wait.clone(), Task::StoreCellsCacheSize(size_mb)));
+        wait.request();
+        if let Err(e) = self.sender.send(pushed_task) {
+            log::warn!("Problem store cells cache size: {}", e);
+        }
+    }
+
+    pub fn store_bandwidth_limits(&self, limits: BandwidthLimitsConfig) {
+        let (wait, _) = Wait::new();
+        let pushed_task = Arc::new((wait.clone(), Task::StoreBandwidthLimits(limits)));
+        wait.request();
+        if let Err(e) = self.sender.send(pushed_task) {
+            log::warn!("Problem store bandwidth limits: {}", e);
+        }
+    }
+
     /// returns validator's public key
     pub fn get_current_validator_key(&self, vset: &ValidatorSet) -> Option<[u8; 32]> {
         // search by adnl_id in validator_keys first
@@ -855,9 +3253,12 @@ impl NodeConfigHandler {
     fn get_key(config: &TonNodeConfig, key_id: [u8; 32]) -> Option<KeyOption> {
         if let Some(validator_key_ring) = &config.validator_key_ring {
             if let Some(key_data)  = validator_key_ring.get(&base64::encode(&key_id)) {
-                match KeyOption::from_private_key(&key_data) {
+                match config.validator_private_key(&base64::encode(&key_id), key_data) {
                     Ok(key) => { return Some(key)},
-                    _ => return None
+                    Err(e) => {
+                        log::warn!("Can't load key from key ring: {}", e);
+                        return None
+                    }
                 }
             }
         }
@@ -868,7 +3269,9 @@ impl NodeConfigHandler {
         // load key ring
         if let Some(key_ring) = &config.validator_key_ring {
             for (key_id, key) in key_ring.iter() {
-                if let Err(e) = self.add_key_to_dynamic_key_ring(key_id.to_string(), key) {
+                let result = config.validator_private_key(key_id, key)
+                    .and_then(|key| self.add_key_to_dynamic_key_ring(key_id.to_string(), key));
+                if let Err(e) = result {
                     log::warn!("fail added key from key ring: {}", e);
                 }
             }
@@ -903,8 +3306,8 @@ impl NodeConfigHandler {
         Ok(())
     }
 
-    fn add_key_to_dynamic_key_ring(&self, key_id: String, key_json: &KeyOptionJson) -> Result<()> {
-        if let Some(key) = self.key_ring.insert(key_id, Arc::new(KeyOption::from_private_key(key_json)?)) {
+    fn add_key_to_dynamic_key_ring(&self, key_id: String, key: KeyOption) -> Result<()> {
+        if let Some(key) = self.key_ring.insert(key_id, Arc::new(key)) {
             log::warn!("Added key was already in key ring collection (id: {})", key.key());
         }
         
@@ -961,6 +3364,27 @@ impl NodeConfigHandler {
                         let result = actual_config.save_to_file(&name);
                         Answer::Result(result)
                     }
+                    Task::StoreStatesGcPolicy(policy) => {
+                        actual_config.states_gc_policy = policy;
+                        let result = actual_config.save_to_file(&name);
+                        Answer::Result(result)
+                    }
+                    Task::StoreCellsCacheSize(size_mb) => {
+                        actual_config.cells_cache_size_mb = size_mb;
+                        let result = actual_config.save_to_file(&name);
+                        Answer::Result(result)
+                    }
+                    Task::StoreBandwidthLimits(ref limits) => {
+                        actual_config.bandwidth_limits = limits.clone();
+                        let result = actual_config.save_to_file(&name);
+                        Answer::Result(result)
+                    }
+                    Task::AddConsoleKey(ref key) => {
+                        Answer::Result(self.add_console_key_and_save(&mut actual_config, &name, key))
+                    }
+                    Task::RemoveConsoleKey(key_id) => Answer::Result(
+                        self.remove_console_key_and_save(&mut actual_config, &name, &key_id)
+                    ),
                 };
                 task.0.respond(Some(answer));
             }
@@ -1023,11 +3447,23 @@ impl TonNodeGlobalConfig {
     pub fn init_block(&self) -> Result<Option<BlockIdExt>> {
         self.0.init_block()
     }
+
+    pub fn hardforks(&self) -> Result<Vec<BlockIdExt>> {
+        self.0.hardforks()
+    }
     
     pub fn dht_nodes(&self) -> Result<Vec<DhtNodeConfig>> {
         self.0.get_dht_nodes_configs()
     }
 
+    // Test networks are run with static DHT nodes at private or loopback addresses only
+    pub fn is_public_network(&self) -> bool {
+        self.0.dht.static_nodes.nodes.iter()
+            .flat_map(|node| node.addr_list.addrs.iter())
+            .filter_map(|addr| addr.ip)
+            .any(|ip| check_public_ip(IpAddr::V4(Ipv4Addr::from(ip as u32))).is_ok())
+    }
+
 // Unused
 //    pub fn dht_param_a(&self) -> Result<i32> {
 //        self.0.dht.a.ok_or_else(|| error!("Dht param a is not set!"))
@@ -1114,6 +3550,7 @@ struct Validator {
     type_node : Option<String>,
     zero_state : ZeroState,
     init_block : Option<InitBlock>,
+    hardforks : Vec<InitBlock>,
 }
 
 #[derive(Debug, Default, serde::Deserialize)]
@@ -1136,6 +3573,35 @@ struct InitBlock {
     file_hash : Option<String>,
 }
 
+impl InitBlock {
+
+    fn block_id(&self, name: &str) -> Result<BlockIdExt> {
+        let workchain_id = self.workchain
+            .ok_or_else(|| error!("Unknown workchain id (of {})!", name))?;
+
+        let seqno = self.seqno
+            .ok_or_else(|| error!("Unknown workchain seqno (of {})!", name))?;
+
+        let shard = self.shard
+            .ok_or_else(|| error!("Unknown workchain shard (of {})!", name))?;
+
+        let root_hash = self.root_hash.as_ref()
+            .ok_or_else(|| error!("Unknown workchain root_hash (of {})!", name))?;
+        let root_hash = UInt256::from_str(&root_hash)?;
+
+        let file_hash = self.file_hash.as_ref()
+            .ok_or_else(|| error!("Unknown workchain file_hash (of {})!", name))?;
+        let file_hash = UInt256::from_str(&file_hash)?;
+
+        Ok(BlockIdExt {
+            shard_id: ShardIdent::with_tagged_prefix(workchain_id, shard as u64)?,
+            seq_no: seqno as u32,
+            root_hash,
+            file_hash,
+        })
+    }
+}
+
 pub const PUB_ED25519 : &str = "pub.ed25519";
 
 impl IdDhtNode {
@@ -1300,34 +3766,22 @@ impl TonNodeGlobalConfigJson {
     }
 
     pub fn init_block(&self) -> Result<Option<BlockIdExt>> {
-        let init_block = match self.validator.init_block {
-            Some(ref init_block) => init_block,
-            None => return Ok(None)
-        };
-        
-        let workchain_id = init_block.workchain
-            .ok_or_else(|| error!("Unknown workchain id (of zero_state)!"))?;
-
-        let seqno = init_block.seqno
-            .ok_or_else(|| error!("Unknown workchain seqno (of zero_state)!"))?;
-
-        let shard = init_block.shard
-            .ok_or_else(|| error!("Unknown workchain shard (of zero_state)!"))?;
-
-        let root_hash = init_block.root_hash.as_ref()
-            .ok_or_else(|| error!("Unknown workchain root_hash (of zero_state)!"))?;
-        let root_hash = UInt256::from_str(&root_hash)?;
-
-        let file_hash = init_block.file_hash.as_ref()
-            .ok_or_else(|| error!("Unknown workchain file_hash (of zero_state)!"))?;
-        let file_hash = UInt256::from_str(&file_hash)?;
+        self.validator.init_block.as_ref().map(|init_block| init_block.block_id("init_block")).transpose()
+    }
 
-        Ok(Some(BlockIdExt {
-            shard_id: ShardIdent::with_tagged_prefix(workchain_id, shard as u64)?,
-            seq_no: seqno as u32,
-            root_hash,
-            file_hash,
-        }))
+    // Masterchain blocks of hardforks in seqno order
+    pub fn hardforks(&self) -> Result<Vec<BlockIdExt>> {
+        let mut hardforks = self.validator.hardforks.iter()
+            .map(|hardfork| hardfork.block_id("hardfork"))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(id) = hardforks.iter().find(|id| !id.shard().is_masterchain()) {
+            fail!("Hardfork {} must be a masterchain block", id)
+        }
+        hardforks.sort_by_key(|id| id.seq_no());
+        if let Some(ids) = hardforks.windows(2).find(|ids| ids[0].seq_no() == ids[1].seq_no()) {
+            fail!("Hardforks {} and {} have the same seqno", ids[0], ids[1])
+        }
+        Ok(hardforks)
     }
 }
 
@@ -1484,3 +3938,490 @@ impl ValidatorKeys {
         }
     }
 }
+
+#[test]
+fn test_global_config_hardforks() {
+    let hardfork = |workchain: i32, seqno: u32, hash: u8| serde_json::json!({
+        "workchain": workchain,
+        "shard": i64::MIN,
+        "seqno": seqno,
+        "root_hash": base64::encode([hash; 32]),
+        "file_hash": base64::encode([hash; 32]),
+    });
+    let config = |hardforks| serde_json::from_value::<TonNodeGlobalConfigJson>(
+        serde_json::json!({ "validator": { "hardforks": hardforks } })
+    ).unwrap();
+
+    let hardforks = config(vec!(hardfork(-1, 200, 2), hardfork(-1, 100, 1))).hardforks().unwrap();
+    assert_eq!(hardforks.iter().map(|id| id.seq_no()).collect::<Vec<_>>(), vec!(100, 200));
+    assert!(hardforks[0].shard().is_masterchain());
+    assert_eq!(hardforks[0].root_hash, UInt256::from_slice(&[1; 32]));
+    assert!(config(Vec::new()).hardforks().unwrap().is_empty());
+
+    assert!(config(vec!(hardfork(0, 100, 1))).hardforks().is_err());
+    assert!(config(vec!(hardfork(-1, 100, 1), hardfork(-1, 100, 2))).hardforks().is_err());
+}
+
+#[test]
+fn test_config_validation_problems() {
+    let dir = std::env::temp_dir().join(format!("test_config_validation_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str, json: serde_json::Value| {
+        std::fs::write(dir.join(name), json.to_string()).unwrap()
+    };
+    let load = |json: serde_json::Value| {
+        write("config.json", json);
+        TonNodeConfig::from_file(dir.to_str().unwrap(), "config.json", None, "", None)
+            .map_err(|e| e.to_string())
+    };
+    let zero_state = |workchain: i32, seqno: u32| serde_json::json!({ "validator": { "zero_state": {
+        "workchain": workchain,
+        "shard": i64::MIN,
+        "seqno": seqno,
+        "root_hash": base64::encode([1; 32]),
+        "file_hash": base64::encode([2; 32]),
+    }}});
+    write("global.json", zero_state(-1, 0));
+    write("global_broken.json", zero_state(-1, 5));
+    std::fs::write(dir.join("log.yml"), "refresh_rate: 30 seconds\n").unwrap();
+    let (adnl_node, _) = AdnlNodeConfig::with_ip_address_and_key_type(
+        "127.0.0.1:30303",
+        KeyOption::KEY_ED25519,
+        vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
+    ).unwrap();
+    let valid = serde_json::json!({
+        "log_config_name": "log.yml",
+        "ton_global_config_name": "global.json",
+        "adnl_node": adnl_node,
+    });
+    load(valid.clone()).unwrap();
+
+    // All problems are reported at once
+    let mut broken = valid.clone();
+    broken["log_config_name"] = serde_json::json!("log_cfg.yml");
+    broken["ton_global_config_name"] = serde_json::json!("global_broken.json");
+    broken["adnl_node"]["ip_address"] = serde_json::json!("127.0.0.1:0");
+    broken["control_server_port"] = serde_json::json!(0);
+    broken["workchain"] = serde_json::json!(0);
+    broken["tracked_shards"] = serde_json::json!(["1:8000000000000000"]);
+    broken["health_server"] = serde_json::json!({ "address": "localhost" });
+    broken["external_db_config"] = serde_json::json!({ "block_producer": {
+        "enabled": true,
+        "brokers": "kafka",
+        "message_timeout_ms": 100,
+        "topic": "",
+        "attempt_timeout_ms": 100,
+        "message_max_size": 3000000,
+        "big_messages_storage": "big-blocks"
+    }});
+    let err = load(broken).unwrap_err();
+    assert!(err.contains("problem(s) in"), "{}", err);
+    let expected = [
+        "log_config_name: file",
+        "ton_global_config_name: zero_state",
+        "is not masterchain block with seqno 0",
+        "adnl_node: ip_address 127.0.0.1:0: port must not be zero",
+        "control_server_port: port must not be zero",
+        "tracked_shards: tracked shard",
+        "is not in configured workchain 0",
+        "health_server: health_server address localhost is not ip:port",
+        "external_db_config 0: block_producer is enabled, but its topic or brokers are not set",
+    ];
+    for message in &expected {
+        assert!(err.contains(message), "{} is not found in {}", message, err);
+    }
+
+    // Unparsable config names the file and position
+    std::fs::write(dir.join("config.json"), "{ \"workchain\": }").unwrap();
+    let err = TonNodeConfig::from_file(dir.to_str().unwrap(), "config.json", None, "", None)
+        .unwrap_err().to_string();
+    assert!(err.contains("config.json") && err.contains("line 1"), "{}", err);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_config_env_overrides() {
+    let vars = |vars: &[(&str, &str)]| {
+        vars.iter().map(|(var, value)| (var.to_string(), value.to_string())).collect::<Vec<_>>()
+    };
+    let file = serde_json::to_value(serde_json::from_value::<TonNodeConfig>(serde_json::json!({
+        "cells_gc_interval_ms": 900000,
+        "index_transactions": false,
+        "external_db_config": [{ "name": "main" }],
+    })).unwrap()).unwrap();
+    let load = || serde_json::from_value::<TonNodeConfig>(file.clone()).unwrap();
+
+    let config = load().with_env_overrides(vars(&[
+        ("TON_NODE__CELLS_GC_INTERVAL_MS", "600000"),
+        ("TON_NODE__INDEX_TRANSACTIONS", "true"),
+        ("TON_NODE__HEALTH_SERVER__ADDRESS", "0.0.0.0:8080"),
+        ("TON_NODE__EXTERNAL_DB_CONFIG__0__BLOCK_PRODUCER__BROKERS", "kafka2:9092"),
+        ("TON_NODE__METRICS__NODE_NAME", "node1"),
+        ("PATH", "/bin"),
+    ]).into_iter()).unwrap();
+    assert_eq!(config.cells_gc_interval_ms(), 600000);
+    assert!(config.index_transactions());
+    assert_eq!(config.health_server_config().address.as_deref(), Some("0.0.0.0:8080"));
+    assert_eq!(config.external_db_config()[0].block_producer.brokers, "kafka2:9092");
+    assert_eq!(config.external_db_config()[0].name.as_deref(), Some("main"));
+    assert_eq!(config.metrics_config().node_name.as_deref(), Some("node1"));
+    assert_eq!(config.env_overrides().len(), 5);
+    assert_eq!(
+        config.env_overrides()[0].to_string(),
+        "cells_gc_interval_ms = 600000 (from TON_NODE__CELLS_GC_INTERVAL_MS)"
+    );
+
+    // File keeps its own values
+    let mut json = serde_json::to_value(&config).unwrap();
+    for env_override in config.env_overrides().iter().rev() {
+        env_override.revert(&mut json);
+    }
+    assert_eq!(json, file);
+
+    // Secrets are not shown
+    let mut json = serde_json::json!({ "control_server": { "server_key": { "pvt_key": "a" } } });
+    let env_override = EnvOverride::apply(
+        &mut json, "TON_NODE__CONTROL_SERVER__SERVER_KEY__PVT_KEY".to_string(), "b".to_string()
+    ).unwrap();
+    assert_eq!(json["control_server"]["server_key"]["pvt_key"], "b");
+    assert_eq!(
+        env_override.to_string(),
+        "control_server.server_key.pvt_key = *** (from TON_NODE__CONTROL_SERVER__SERVER_KEY__PVT_KEY)"
+    );
+
+    let errors = [
+        ("TON_NODE__CELLS_GC_INTERVAL_MS", "often", "often is not a number"),
+        ("TON_NODE__CELLS_GC_INTERVAL_MS", "-1", "Can't apply environment overrides"),
+        ("TON_NODE__INDEX_TRANSACTIONS", "yes", "yes is not a boolean"),
+        ("TON_NODE__EXTERNAL_DB_CONFIG", "x", "only scalar fields can be overridden"),
+        ("TON_NODE__CELLS_GC_INTERVAL_MS__X", "1", "cells_gc_interval_ms is not a section"),
+        ("TON_NODE__EXTERNAL_DB_CONFIG__3__NAME", "x", "3 is not found"),
+        ("TON_NODE__EXTERNAL_DB_CONFIG__FIRST__NAME", "x", "first is not an index of array"),
+        ("TON_NODE____NAME", "x", "empty field name"),
+    ];
+    for (var, value, message) in &errors {
+        let err = load().with_env_overrides(vars(&[(*var, *value)]).into_iter()).unwrap_err();
+        assert!(err.to_string().contains(message), "{}: {}", var, err);
+    }
+}
+
+#[test]
+fn test_console_keys_acl() {
+    let pub_key = |byte: u8| base64::encode([byte; 32]);
+    let id = |byte: u8| console_key_id(&pub_key(byte)).unwrap();
+    let dir = std::env::temp_dir().join(format!("test_console_keys_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let (server_private_key, _) = KeyOption::with_type_id(KeyOption::KEY_ED25519).unwrap();
+    let legacy_client = serde_json::from_str(key_option_public_key!(pub_key(1))).unwrap();
+    let mut config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "console_keys": [
+            { "pub_key": pub_key(2), "role": "operator" },
+            { "pub_key": pub_key(3), "role": "read_only" },
+        ]
+    })).unwrap();
+    config.control_server = Some(AdnlServerConfigJson::with_params(
+        "127.0.0.1:4925".to_string(), server_private_key, vec![legacy_client], None
+    ));
+    config.configs_dir = dir.to_str().unwrap().to_string();
+
+    // Clients without role are admins, all keys can connect
+    let acl = ConsoleAcl::with_config(&config).unwrap();
+    assert_eq!(acl.role(&id(1)), Some(ConsoleRole::Admin));
+    assert_eq!(acl.role(&id(2)), Some(ConsoleRole::Operator));
+    assert_eq!(acl.role(&id(3)), Some(ConsoleRole::ReadOnly));
+    assert_eq!(acl.role(&id(4)), None);
+    let server = config.with_console_keys(config.control_server.as_ref().unwrap()).unwrap();
+    let server = serde_json::to_value(server).unwrap();
+    assert_eq!(server.pointer("/clients/list").unwrap().as_array().unwrap().len(), 3);
+    assert!(config.control_server().unwrap().is_some());
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (handler, mut context) = NodeConfigHandler::create(config, rt.handle().clone()).unwrap();
+    let config = &mut context.config;
+    let err = handler.remove_console_key_and_save(config, "config.json", &id(1)).unwrap_err();
+    assert!(err.to_string().contains("admin console key must be kept"), "{}", err);
+    assert_eq!(handler.console_role(&id(1)), Some(ConsoleRole::Admin));
+    assert_eq!(ConsoleAcl::with_config(config).unwrap().role(&id(1)), Some(ConsoleRole::Admin));
+    let err = handler.add_console_key_and_save(
+        config, "config.json", &ConsoleKeyJson { pub_key: pub_key(1), role: ConsoleRole::ReadOnly }
+    ).unwrap_err();
+    assert!(err.to_string().contains("admin console key must be kept"), "{}", err);
+
+    let admin = ConsoleKeyJson { pub_key: pub_key(4), role: ConsoleRole::Admin };
+    handler.add_console_key_and_save(config, "config.json", &admin).unwrap();
+    assert_eq!(handler.console_role(&id(4)), Some(ConsoleRole::Admin));
+    handler.remove_console_key_and_save(config, "config.json", &id(1)).unwrap();
+    assert_eq!(handler.console_role(&id(1)), None);
+    handler.remove_console_key_and_save(config, "config.json", &id(3)).unwrap();
+    assert_eq!(handler.console_role(&id(3)), None);
+    assert!(handler.remove_console_key_and_save(config, "config.json", &id(5)).is_err());
+
+    let saved: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(dir.join("config.json")).unwrap()
+    ).unwrap();
+    assert_eq!(saved["console_keys"], serde_json::json!([
+        { "pub_key": pub_key(2), "role": "operator" },
+        { "pub_key": pub_key(4), "role": "admin" },
+    ]));
+    assert_eq!(saved.pointer("/control_server/clients/list").unwrap().as_array().unwrap().len(), 0);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_keyring_encryption_migration() {
+    let dir = std::env::temp_dir().join(format!("test_keyring_encryption_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let configs_dir = dir.to_str().unwrap();
+    let passphrase_file = dir.join("passphrase");
+    std::fs::write(&passphrase_file, "correct horse\n").unwrap();
+
+    let (private, public) = mine_key_for_workchain(None);
+    let key_id = base64::encode(public.id().data());
+    let mut key_ring = serde_json::Map::new();
+    key_ring.insert(key_id.clone(), serde_json::to_value(private).unwrap());
+    std::fs::write(dir.join("config.json"), serde_json::json!({
+        "keyring_encryption": {
+            "passphrase_env": "TEST_KEYRING_PASSPHRASE_NOT_SET",
+            "passphrase_file": passphrase_file.to_str().unwrap(),
+        },
+        "validator_key_ring": key_ring,
+    }).to_string()).unwrap();
+
+    assert_eq!(TonNodeConfig::encrypt_keys_in_file(configs_dir, "config.json").unwrap(), 1);
+    // already encrypted keys are only checked
+    assert_eq!(TonNodeConfig::encrypt_keys_in_file(configs_dir, "config.json").unwrap(), 0);
+    let saved = std::fs::read_to_string(dir.join("config.json")).unwrap();
+    let mut config: TonNodeConfig = serde_json::from_str(&saved).unwrap();
+    assert!(config.keyring_encryption.enabled);
+    let key = &config.validator_key_ring.as_ref().unwrap()[&key_id];
+    assert!(KeyringCipher::is_encrypted(key).unwrap());
+
+    let err = config.validator_private_key(&key_id, key).unwrap_err();
+    assert!(err.to_string().contains("keyring_encryption is not enabled"), "{}", err);
+    config.keyring_cipher = KeyringCipher::with_config(&config.keyring_encryption).unwrap()
+        .map(Arc::new);
+    let loaded = config.validator_private_key(&key_id, key).unwrap();
+    assert_eq!(loaded.id().data(), public.id().data());
+    let (id, _) = config.generate_and_save_keys().unwrap();
+    let key = &config.validator_key_ring.as_ref().unwrap()[&base64::encode(id)];
+    assert!(KeyringCipher::is_encrypted(key).unwrap());
+
+    std::fs::write(&passphrase_file, "battery staple").unwrap();
+    let err = TonNodeConfig::encrypt_keys_in_file(configs_dir, "config.json").unwrap_err();
+    assert!(err.to_string().contains("keyring passphrase is wrong"), "{}", err);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_resource_profile() {
+    let file_json = serde_json::json!({
+        "resource_profile": "low",
+        "states_cache_size_mb": 1024,
+        "runtime": { "worker_threads": null }
+    });
+    let vars = vec![
+        ("TON_NODE__ARCHIVES_SYNC__APPLY_PIPELINE_DEPTH".to_string(), "4".to_string())
+    ];
+    let config: TonNodeConfig = serde_json::from_value(file_json.clone()).unwrap();
+    let mut config = config.with_env_overrides(vars.into_iter()).unwrap()
+        .with_resource_profile(&file_json).unwrap();
+    // profile values, explicit ones and environment are kept
+    assert_eq!(config.cells_cache_size_mb(), 64);
+    assert_eq!(config.states_cache_size_mb(), 1024);
+    assert_eq!(config.rocksdb_config().block_cache_mb, Some(128));
+    assert_eq!(config.ext_messages_config().max_total_bytes, 32 << 20);
+    assert_eq!(config.archives_sync_config().apply_pipeline_depth, 4);
+    assert_eq!(config.runtime_config().worker_threads, None);
+    assert_eq!(config.runtime_config().max_blocking_threads, Some(64));
+    assert!(config.resource_profile_summary().starts_with("low, cells cache: 64 MB"));
+
+    // profile values aren't written into the file, changed ones are
+    let dir = std::env::temp_dir().join(format!("test_resource_profile_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    config.configs_dir = dir.to_str().unwrap().to_string();
+    config.cells_cache_size_mb = 100;
+    config.save_to_file("config.json").unwrap();
+    let saved: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(dir.join("config.json")).unwrap()
+    ).unwrap();
+    assert_eq!(saved["cells_cache_size_mb"], 100);
+    assert_eq!(saved["states_cache_size_mb"], 1024);
+    assert!(saved["rocksdb"].get("block_cache_mb").is_none());
+    assert!(saved["ext_messages"].get("max_total_bytes").is_none());
+    assert!(saved["runtime"].get("max_blocking_threads").is_none());
+    assert!(saved["runtime"]["worker_threads"].is_null());
+
+    // default profile keeps the former defaults
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+    let config = config.with_resource_profile(&serde_json::json!({})).unwrap();
+    assert_eq!(config.cells_cache_size_mb(), default_cells_cache_size_mb());
+    assert_eq!(
+        config.ext_messages_config().max_total_bytes, ExtMessagesConfig::default().max_total_bytes
+    );
+    assert_eq!(config.archives_sync_config().apply_pipeline_depth, 8);
+    assert_eq!(config.rocksdb_config().block_cache_mb, None);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_front_workchains() {
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+    assert_eq!(config.front_workchain_ids(), Some(vec![MASTERCHAIN_ID, BASE_WORKCHAIN_ID]));
+    assert!(!config.front_workchains_strict());
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "workchain": 1, "front_workchains": "all", "front_workchains_strict": true
+    })).unwrap();
+    assert_eq!(config.front_workchain_ids(), None);
+    assert!(config.front_workchains_strict());
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "front_workchains": [0, 1]
+    })).unwrap();
+    assert_eq!(config.front_workchain_ids(), Some(vec![0, 1]));
+    let front_problems = |config: &TonNodeConfig| config.validate().into_iter()
+        .filter(|problem| problem.starts_with("front_workchains: "))
+        .count();
+    assert_eq!(front_problems(&config), 0);
+
+    for wrong in [serde_json::json!("any"), serde_json::json!([])].iter() {
+        let config: TonNodeConfig = serde_json::from_value(
+            serde_json::json!({ "front_workchains": wrong })
+        ).unwrap();
+        assert_eq!(front_problems(&config), 1);
+    }
+
+    // node processing workchain 0 never writes blocks of workchain 1
+    assert_eq!(unprocessed_front_workchains(Some(&[0, 1]), 0), vec![1]);
+    assert_eq!(unprocessed_front_workchains(Some(&[-1, 1]), 1), Vec::<i32>::new());
+    assert_eq!(unprocessed_front_workchains(Some(&[1]), 0), vec![1]);
+    assert!(unprocessed_front_workchains(None, 0).is_empty());
+}
+
+#[test]
+fn test_public_address() {
+    let (adnl_node, _) = AdnlNodeConfig::with_ip_address_and_key_type(
+        "10.0.0.5:30303",
+        KeyOption::KEY_ED25519,
+        vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
+    ).unwrap();
+    let mut config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "adnl_node": adnl_node
+    })).unwrap();
+    assert_eq!(config.advertised_address().unwrap(), "10.0.0.5:30303".parse().unwrap());
+
+    // port of ADNL node is kept
+    assert_eq!(
+        config.set_public_address("1.2.3.4", false).unwrap(), "1.2.3.4:30303".parse().unwrap()
+    );
+    assert_eq!(
+        config.set_public_address("1.2.3.5:30303", false).unwrap(),
+        "1.2.3.5:30303".parse().unwrap()
+    );
+    assert_eq!(config.advertised_address().unwrap(), "1.2.3.5:30303".parse().unwrap());
+    // no port mapping, the node listens on its own port only
+    assert!(config.set_public_address("1.2.3.4:40404", false).is_err());
+    assert_eq!(config.advertised_address().unwrap(), "1.2.3.5:30303".parse().unwrap());
+    assert!(config.adnl_node().is_ok());
+    // not saved
+    let saved = serde_json::to_value(&config).unwrap();
+    assert_eq!(saved["adnl_node"]["ip_address"], "10.0.0.5:30303");
+
+    let private = ["192.168.1.1", "127.0.0.1:30303", "100.64.0.1", "0.0.0.0", "::1", "fd00::1"];
+    for private in private.iter() {
+        assert!(config.set_public_address(private, false).is_err(), "{}", private);
+    }
+    assert!(config.set_public_address("1.2.3.4:0", false).is_err());
+    assert!(config.set_public_address("example.com", false).is_err());
+    assert_eq!(
+        config.set_public_address("192.168.1.1", true).unwrap(), "192.168.1.1:30303".parse().unwrap()
+    );
+}
+
+#[test]
+fn test_global_config_is_public_network() {
+    let global_config = |ips: &[Ipv4Addr]| {
+        let addrs = ips.iter()
+            .map(|ip| serde_json::json!({ "ip": u32::from(*ip) as i32, "port": 3000 }))
+            .collect::<Vec<_>>();
+        TonNodeGlobalConfig(serde_json::from_value(serde_json::json!({
+            "dht": { "static_nodes": { "nodes": [{ "addr_list": { "addrs": addrs } }] } }
+        })).unwrap())
+    };
+    assert!(!global_config(&[]).is_public_network());
+    let local = [Ipv4Addr::new(127, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)];
+    assert!(!global_config(&local).is_public_network());
+    let public = [Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(185, 1, 2, 3)];
+    assert!(global_config(&public).is_public_network());
+}
+
+#[test]
+fn test_validator_key_with_test_rng_seed() {
+    let dir = std::env::temp_dir().join(format!("test_config_rng_seed_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let global_config = |ip: Ipv4Addr| serde_json::json!({
+        "dht": { "static_nodes": { "nodes": [{ "addr_list": { "addrs": [
+            { "ip": u32::from(ip) as i32, "port": 3000 }
+        ]}}]}},
+        "validator": { "zero_state": {
+            "workchain": -1,
+            "shard": i64::MIN,
+            "seqno": 0,
+            "root_hash": base64::encode([1; 32]),
+            "file_hash": base64::encode([2; 32]),
+        }}
+    });
+    std::fs::write(dir.join("global_public.json"), global_config(Ipv4Addr::new(185, 1, 2, 3)).to_string()).unwrap();
+    std::fs::write(dir.join("global_local.json"), global_config(Ipv4Addr::new(10, 0, 0, 2)).to_string()).unwrap();
+    std::fs::write(dir.join("log.yml"), "refresh_rate: 30 seconds\n").unwrap();
+    let (adnl_node, _) = AdnlNodeConfig::with_ip_address_and_key_type(
+        "127.0.0.1:30303",
+        KeyOption::KEY_ED25519,
+        vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
+    ).unwrap();
+    let load = |global_config: &str| {
+        std::fs::write(dir.join("config.json"), serde_json::json!({
+            "log_config_name": "log.yml",
+            "ton_global_config_name": global_config,
+            "adnl_node": adnl_node,
+            "test_rng_seed": 7,
+        }).to_string()).unwrap();
+        TonNodeConfig::from_file(dir.to_str().unwrap(), "config.json", None, "", None).unwrap()
+    };
+
+    // Node without validator keys is loaded, but becoming validator is refused
+    let mut config = load("global_public.json");
+    let err = config.add_validator_key(&[1; 32], 100).unwrap_err();
+    assert!(err.to_string().contains("deterministic randomness is refused"), "{}", err);
+    assert!(config.validator_keys.is_none());
+
+    let mut config = load("global_local.json");
+    config.add_validator_key(&[1; 32], 100).unwrap();
+    assert_eq!(config.validator_keys.as_ref().unwrap().len(), 1);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_external_db_block_size_limits() {
+    let producer = |message_max_size: usize| serde_json::json!({
+        "enabled": true,
+        "brokers": "kafka",
+        "message_timeout_ms": 100,
+        "topic": "blocks",
+        "attempt_timeout_ms": 100,
+        "message_max_size": message_max_size,
+        "big_messages_storage": ""
+    });
+    let check = |config: serde_json::Value| {
+        serde_json::from_value::<ExternalDbConfig>(config).unwrap().check().map_err(|e| e.to_string())
+    };
+    // Raw BOC fits, but its JSON record doesn't
+    let err = check(serde_json::json!({ "block_producer": producer(3000000) })).unwrap_err();
+    assert!(err.contains("max block JSON size 8388608"), "{}", err);
+    check(serde_json::json!({ "block_producer": producer(DEFAULT_MAX_BLOCK_JSON_SIZE) })).unwrap();
+    check(serde_json::json!({
+        "block_producer": producer(3000000), "max_block_json_size": 3000000
+    })).unwrap();
+    check(serde_json::json!({ "raw_block_producer": producer(3000000) })).unwrap();
+    let err = check(serde_json::json!({ "raw_block_producer": producer(1000000) })).unwrap_err();
+    assert!(err.contains("max block size 2097152"), "{}", err);
+}
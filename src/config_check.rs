@@ -0,0 +1,148 @@
+use crate::{config::TonNodeConfig, network::node_network::NodeNetwork};
+use std::{fmt, path::Path, time::Duration};
+use ton_types::{error, fail, Result};
+
+pub const DEFAULT_MIN_FREE_SPACE_GB: u64 = 50;
+const DHT_PING_TIMEOUT_MS: u64 = 5000;
+const PROBE_FILE_NAME: &str = ".check_config";
+
+pub struct CheckParams {
+    pub min_free_space_gb: u64,
+    // Static DHT nodes to ping, 0 - no network check
+    pub dht_nodes: usize,
+}
+
+// PASS/FAIL line per check
+#[derive(Default)]
+pub struct CheckReport {
+    checks: Vec<(String, std::result::Result<String, String>)>,
+}
+
+impl CheckReport {
+
+    fn add(&mut self, name: impl ToString, result: Result<String>) {
+        self.checks.push((name.to_string(), result.map_err(|e| e.to_string())))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+impl fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, result) in self.checks.iter() {
+            match result {
+                Ok(details) => writeln!(f, "PASS {}: {}", name, details)?,
+                Err(e) => writeln!(f, "FAIL {}: {}", name, e)?
+            }
+        }
+        write!(f, "{}", if self.passed() { "PASS" } else { "FAIL" })
+    }
+}
+
+// Preflight of the node's config: the same loading and validation as on start, keys,
+// DB directories and optionally connectivity. Nothing is started or written except
+// probe files in DB directories.
+pub async fn check_config(configs_dir: &str, config_name: &str, params: &CheckParams) -> CheckReport {
+    let mut report = CheckReport::default();
+    let path = Path::new(configs_dir).join(config_name);
+    if !path.is_file() {
+        report.add("config", Err(error!("{} is not found", path.display())));
+        return report
+    }
+    let config = match TonNodeConfig::from_file(configs_dir, config_name, None, "", None) {
+        Ok(config) => {
+            report.add("config", Ok(format!("{} is valid", path.display())));
+            config
+        }
+        Err(e) => {
+            report.add("config", Err(e));
+            return report
+        }
+    };
+    report.add("keys", check_keys(&config));
+    for dir in db_dirs(&config) {
+        let result = check_db_dir(&dir, params.min_free_space_gb);
+        report.add(format!("db dir {}", dir), result);
+    }
+    if params.dht_nodes > 0 {
+        report.add("dht", check_dht(&config, params.dht_nodes).await);
+    }
+    report
+}
+
+fn check_keys(config: &TonNodeConfig) -> Result<String> {
+    config.adnl_node().map_err(|e| error!("ADNL node keys: {}", e))?;
+    let mut details = "ADNL node keys".to_string();
+    if config.control_server().map_err(|e| error!("control server: {}", e))?.is_some() {
+        details.push_str(", control server keys");
+    }
+    // Keys of validator key ring are decrypted and loaded by validation
+    Ok(format!("{} are loaded", details))
+}
+
+// Main DB directory and directories of moved parts of DB
+fn db_dirs(config: &TonNodeConfig) -> Vec<String> {
+    let paths = config.internal_db_paths();
+    let mut dirs = vec![config.internal_db_path().unwrap_or("node_db").to_string()];
+    let parts = [&paths.blocks, &paths.cells, &paths.archives, &paths.persistent_states];
+    for dir in parts.iter().filter_map(|dir| dir.as_ref()) {
+        if !dirs.contains(dir) {
+            dirs.push(dir.clone())
+        }
+    }
+    dirs
+}
+
+fn check_db_dir(dir: &str, min_free_space_gb: u64) -> Result<String> {
+    std::fs::create_dir_all(dir).map_err(|e| error!("can't create: {}", e))?;
+    let probe = Path::new(dir).join(PROBE_FILE_NAME);
+    std::fs::write(&probe, b"probe").map_err(|e| error!("is not writable: {}", e))?;
+    std::fs::remove_file(&probe)?;
+    let free_gb = fs2::available_space(dir)? >> 30;
+    if free_gb < min_free_space_gb {
+        fail!("{} GB free, at least {} GB required", free_gb, min_free_space_gb)
+    }
+    Ok(format!("writable, {} GB free", free_gb))
+}
+
+async fn check_dht(config: &TonNodeConfig, count: usize) -> Result<String> {
+    let timeout = Duration::from_millis(DHT_PING_TIMEOUT_MS);
+    let pinged = NodeNetwork::ping_dht_static_nodes(config, count, timeout).await?;
+    let answered = pinged.iter().filter(|(_, answered)| *answered).count();
+    if answered == 0 {
+        fail!("none of {} static DHT nodes answered", pinged.len())
+    }
+    Ok(format!("{} of {} static DHT nodes answered", answered, pinged.len()))
+}
+
+#[test]
+fn test_check_config_report() {
+    let dir = std::env::temp_dir().join(format!("test_check_config_{}", std::process::id()));
+    let db_dir = dir.join("node_db");
+    let db_dir = db_dir.to_str().unwrap();
+    assert!(check_db_dir(db_dir, 0).unwrap().starts_with("writable"));
+    assert!(!Path::new(db_dir).join(PROBE_FILE_NAME).exists());
+    let err = check_db_dir(db_dir, u64::MAX >> 30).unwrap_err();
+    assert!(err.to_string().contains("GB required"), "{}", err);
+
+    let mut report = CheckReport::default();
+    report.add("keys", Ok("ADNL node keys are loaded".to_string()));
+    assert!(report.passed());
+    assert_eq!(report.to_string(), "PASS keys: ADNL node keys are loaded\nPASS");
+    report.add("db dir node_db", Err(error!("is not writable")));
+    assert!(!report.passed());
+    assert_eq!(
+        report.to_string(),
+        "PASS keys: ADNL node keys are loaded\nFAIL db dir node_db: is not writable\nFAIL"
+    );
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let report = rt.block_on(check_config(
+        dir.to_str().unwrap(), "config.json", &CheckParams { min_free_space_gb: 0, dht_nodes: 0 }
+    ));
+    assert!(!report.passed());
+    assert!(report.to_string().starts_with("FAIL config: "));
+    std::fs::remove_dir_all(&dir).ok();
+}
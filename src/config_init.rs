@@ -0,0 +1,204 @@
+use crate::{config::TonNodeConfig, key_option_public_key};
+use adnl::common::{KeyOption, KeyOptionJson};
+use openssl::ssl::{SslConnector, SslMethod};
+use std::{
+    io::{Read, Write}, net::{IpAddr, SocketAddr, TcpStream}, path::Path, time::Duration
+};
+use ton_types::{error, fail, Result};
+
+pub const GLOBAL_CONFIG_NAME: &str = "ton-global.config.json";
+const DEFAULT_CONTROL_SERVER_PORT: u16 = 4925;
+const DOWNLOAD_TIMEOUT_MS: u64 = 30_000;
+
+pub struct InitParams {
+    pub ip: String,
+    pub port: u16,
+    // URL or path of global config of the network
+    pub network: String,
+    // Enables control server and generates console client key pair
+    pub validator: bool,
+    pub force: bool,
+}
+
+pub struct ConsoleClientKey {
+    pub private: KeyOptionJson,
+    pub public: String,
+}
+
+// Writes config.json with new ADNL keys and the network's global config into configs dir.
+// Nothing is overwritten without force.
+pub fn init_config(
+    configs_dir: &str,
+    config_name: &str,
+    params: &InitParams
+) -> Result<(TonNodeConfig, Option<ConsoleClientKey>)> {
+    let config_path = Path::new(configs_dir).join(config_name);
+    if config_path.exists() && !params.force {
+        fail!("{} already exists, use --force to overwrite it", config_path.display())
+    }
+    let ip = params.ip.parse::<IpAddr>()
+        .map_err(|e| error!("IP address {} is invalid: {}", params.ip, e))?;
+    if params.port == 0 {
+        fail!("port must not be zero")
+    }
+    std::fs::create_dir_all(configs_dir)?;
+
+    let global_config = fetch_global_config(&params.network)?;
+    serde_json::from_slice::<serde_json::Value>(&global_config)
+        .map_err(|e| error!("Global config {} is not json: {}", params.network, e))?;
+    std::fs::write(Path::new(configs_dir).join(GLOBAL_CONFIG_NAME), &global_config)?;
+
+    let console_client_key = if params.validator {
+        let (private, public) = KeyOption::with_type_id(KeyOption::KEY_ED25519)?;
+        let public = key_option_public_key!(base64::encode(&public.pub_key()?)).to_string();
+        Some(ConsoleClientKey { private, public })
+    } else {
+        None
+    };
+    let config = TonNodeConfig::generate_file(
+        configs_dir,
+        config_name,
+        &init_template(SocketAddr::new(ip, params.port), params.validator),
+        console_client_key.as_ref().map(|key| key.public.clone())
+    )?;
+    Ok((config, console_client_key))
+}
+
+fn init_template(address: SocketAddr, validator: bool) -> serde_json::Value {
+    let mut template = serde_json::json!({
+        "ton_global_config_name": GLOBAL_CONFIG_NAME,
+        "internal_db_path": "node_db",
+        "ip_address": address.to_string(),
+    });
+    // Validator is managed by console: elections, keys
+    if validator {
+        template["control_server_port"] = DEFAULT_CONTROL_SERVER_PORT.into();
+    }
+    template
+}
+
+fn fetch_global_config(network: &str) -> Result<Vec<u8>> {
+    if network.starts_with("http://") || network.starts_with("https://") {
+        download(network).map_err(|e| error!("Can't download global config {}: {}", network, e))
+    } else {
+        std::fs::read(network).map_err(|e| error!("Can't read global config {}: {}", network, e))
+    }
+}
+
+// Plain HTTP/1.0 GET: no chunked encoding, the body ends with the connection
+fn download(url: &str) -> Result<Vec<u8>> {
+    let (tls, host, address, path) = split_url(url)?;
+    let stream = TcpStream::connect(&address)?;
+    let timeout = Some(Duration::from_millis(DOWNLOAD_TIMEOUT_MS));
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: ton_node\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    let mut response = Vec::new();
+    if tls {
+        let connector = SslConnector::builder(SslMethod::tls())?.build();
+        let mut stream = connector.connect(&host, stream)
+            .map_err(|e| error!("TLS handshake with {} failed: {}", host, e))?;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut response)?;
+    } else {
+        let mut stream = stream;
+        stream.write_all(request.as_bytes())?;
+        stream.read_to_end(&mut response)?;
+    }
+    let header_end = response.windows(4).position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| error!("malformed HTTP response"))?;
+    let status_line = String::from_utf8_lossy(&response[..header_end]);
+    let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        fail!("HTTP status {}", status)
+    }
+    Ok(response.split_off(header_end + 4))
+}
+
+// TLS flag, host, host:port and path of the URL
+fn split_url(url: &str) -> Result<(bool, String, String, String)> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else {
+        fail!("{} is neither http nor https URL", url)
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, "/")
+    };
+    if authority.is_empty() {
+        fail!("{} has no host", url)
+    }
+    let (host, address) = match authority.rsplit_once(':') {
+        Some((host, _)) => (host, authority.to_string()),
+        None => (authority, format!("{}:{}", authority, if tls { 443 } else { 80 }))
+    };
+    Ok((tls, host.to_string(), address, path.to_string()))
+}
+
+#[test]
+fn test_split_url() {
+    assert_eq!(
+        split_url("https://example.com/configs/main.json").unwrap(),
+        (
+            true, "example.com".to_string(), "example.com:443".to_string(),
+            "/configs/main.json".to_string()
+        )
+    );
+    assert_eq!(
+        split_url("http://127.0.0.1:8080").unwrap(),
+        (false, "127.0.0.1".to_string(), "127.0.0.1:8080".to_string(), "/".to_string())
+    );
+    assert!(split_url("ftp://example.com/global.json").is_err());
+    assert!(split_url("https:///global.json").is_err());
+}
+
+#[test]
+fn test_init_config() {
+    let dir = std::env::temp_dir().join(format!("test_init_config_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let global_config = dir.join("downloaded.json");
+    std::fs::write(&global_config, serde_json::json!({ "validator": { "zero_state": {
+        "workchain": -1,
+        "shard": i64::MIN,
+        "seqno": 0,
+        "root_hash": base64::encode([1; 32]),
+        "file_hash": base64::encode([2; 32]),
+    }}}).to_string()).unwrap();
+    let configs_dir = dir.join("configs");
+    let configs_dir = configs_dir.to_str().unwrap();
+    let mut params = InitParams {
+        ip: "127.0.0.1".to_string(),
+        port: 30303,
+        network: global_config.to_str().unwrap().to_string(),
+        validator: true,
+        force: false,
+    };
+
+    let (config, console_client_key) = init_config(configs_dir, "config.json", &params).unwrap();
+    assert!(console_client_key.is_some());
+    assert!(config.control_server().unwrap().is_some());
+    assert!(Path::new(configs_dir).join("console_config.json").is_file());
+    assert!(Path::new(configs_dir).join(GLOBAL_CONFIG_NAME).is_file());
+    // the result passes validation when node starts
+    let loaded = TonNodeConfig::from_file(configs_dir, "config.json", None, "", None).unwrap();
+    assert!(loaded.adnl_node().is_ok());
+
+    // existing config is kept without force
+    let err = init_config(configs_dir, "config.json", &params).err().unwrap();
+    assert!(err.to_string().contains("already exists"), "{}", err);
+    params.force = true;
+    params.validator = false;
+    let (config, console_client_key) = init_config(configs_dir, "config.json", &params).unwrap();
+    assert!(console_client_key.is_none());
+    assert!(config.control_server().unwrap().is_none());
+
+    params.network = dir.join("absent.json").to_str().unwrap().to_string();
+    assert!(init_config(configs_dir, "config.json", &params).is_err());
+    std::fs::remove_dir_all(&dir).ok();
+}
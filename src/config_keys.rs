@@ -0,0 +1,324 @@
+use crate::{
+    config::{KeyringEncryptionConfig, TonNodeConfig}, keyring_cipher::KeyringCipher
+};
+use adnl::common::{KeyOption, KeyOptionJson};
+use std::{io::Write, path::Path};
+use ton_types::{error, fail, Result};
+
+const ARCHIVE_VERSION: u32 = 1;
+// Authenticated along with encrypted keys
+const ARCHIVE_AAD: &[u8] = b"ton-node keys archive";
+
+// Keys moving with the validator to another machine: validator key ring in plaintext
+// with validator_keys telling elections and roles of the keys, optionally ADNL node keys
+// and control server section with console keys. Sections are kept as in config.json.
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, PartialEq)]
+pub struct KeysBundle {
+    #[serde(default)]
+    pub validator_keys: Vec<serde_json::Value>,
+    #[serde(default)]
+    pub validator_key_ring: serde_json::Map<String, serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adnl_node: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_server: Option<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub console_keys: Vec<serde_json::Value>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct KeysArchive {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keys: Option<KeysBundle>,
+    // base64 of salt, iv, tag and ciphertext of keys json
+    #[serde(skip_serializing_if = "Option::is_none")]
+    encrypted: Option<String>,
+}
+
+pub struct ExportParams {
+    pub include_node_key: bool,
+    pub include_console_key: bool,
+    // Archive is encrypted with passphrase from this file
+    pub passphrase_file: Option<String>,
+}
+
+// Writes keys of the node's config into the archive, returns count of validator key ring
+// entries. The archive is readable by owner only.
+pub fn export_keys(
+    configs_dir: &str,
+    config_name: &str,
+    output: &str,
+    params: &ExportParams
+) -> Result<usize> {
+    let config = read_config_json(configs_dir, config_name)?;
+    let bundle = keys_bundle(&config, params.include_node_key, params.include_console_key)?;
+    if bundle.validator_key_ring.is_empty() && bundle.adnl_node.is_none()
+        && bundle.control_server.is_none()
+    {
+        fail!("{} has no keys to export", config_name)
+    }
+    let count = bundle.validator_key_ring.len();
+    let archive = match &params.passphrase_file {
+        Some(path) => {
+            let cipher = KeyringCipher::with_passphrase_file(path)?;
+            let data = cipher.encrypt_data(ARCHIVE_AAD, &serde_json::to_vec(&bundle)?)?;
+            KeysArchive {
+                version: ARCHIVE_VERSION,
+                keys: None,
+                encrypted: Some(base64::encode(&data))
+            }
+        }
+        None => KeysArchive { version: ARCHIVE_VERSION, keys: Some(bundle), encrypted: None }
+    };
+    write_private_file(output, serde_json::to_string_pretty(&archive)?.as_bytes())?;
+    Ok(count)
+}
+
+// Puts keys of the archive into the node's config, which is checked as on node start
+// before it is replaced. Existing keys are overwritten only with force.
+pub fn import_keys(
+    configs_dir: &str,
+    config_name: &str,
+    input: &str,
+    passphrase_file: Option<&str>,
+    force: bool
+) -> Result<usize> {
+    let bundle = read_archive(input, passphrase_file)?;
+    let mut config = read_config_json(configs_dir, config_name)?;
+    let config_map = config.as_object_mut()
+        .ok_or_else(|| error!("{} is not a json object", config_name))?;
+    let is_set = |field: &str| match config_map.get(field) {
+        None | Some(serde_json::Value::Null) => false,
+        Some(serde_json::Value::Array(array)) => !array.is_empty(),
+        Some(serde_json::Value::Object(object)) => !object.is_empty(),
+        Some(_) => true
+    };
+    let mut existing = Vec::new();
+    if is_set("validator_keys") || is_set("validator_key_ring") {
+        existing.push("validator keys")
+    }
+    if bundle.adnl_node.is_some() && is_set("adnl_node") {
+        existing.push("ADNL node keys")
+    }
+    if bundle.control_server.is_some() && is_set("control_server") {
+        existing.push("control server keys")
+    }
+    if !existing.is_empty() && !force {
+        fail!("{} already has {}, use --force to overwrite them", config_name, existing.join(", "))
+    }
+
+    // Keys are stored as the target node's keyring encryption requires
+    let encryption: KeyringEncryptionConfig = match config_map.get("keyring_encryption") {
+        Some(encryption) => serde_json::from_value(encryption.clone())?,
+        None => KeyringEncryptionConfig::default()
+    };
+    let cipher = KeyringCipher::with_config(&encryption)?;
+    let mut key_ring = serde_json::Map::new();
+    for (key_id, key) in bundle.validator_key_ring.iter() {
+        let key: KeyOptionJson = serde_json::from_value(key.clone())?;
+        let key = match &cipher {
+            Some(cipher) => cipher.encrypt(key_id, &key)?,
+            None => key
+        };
+        key_ring.insert(key_id.clone(), serde_json::to_value(key)?);
+    }
+    let count = key_ring.len();
+    config_map.insert("validator_keys".to_string(), bundle.validator_keys.into());
+    config_map.insert("validator_key_ring".to_string(), key_ring.into());
+    if let Some(adnl_node) = bundle.adnl_node {
+        config_map.insert("adnl_node".to_string(), adnl_node);
+    }
+    if let Some(control_server) = bundle.control_server {
+        config_map.insert("control_server".to_string(), control_server);
+        config_map.insert("console_keys".to_string(), bundle.console_keys.into());
+    }
+
+    // Checked copy replaces the config
+    let checked_name = format!("{}.import", config_name);
+    let checked_path = Path::new(configs_dir).join(&checked_name);
+    let checked_path_str = checked_path.to_str().ok_or_else(|| error!("path is not valid!"))?;
+    write_private_file(checked_path_str, &serde_json::to_vec_pretty(&config)?)?;
+    if let Err(e) = TonNodeConfig::from_file(configs_dir, &checked_name, None, "", None) {
+        std::fs::remove_file(&checked_path).ok();
+        fail!("config with imported keys is invalid, nothing is changed: {}", e)
+    }
+    std::fs::rename(&checked_path, Path::new(configs_dir).join(config_name))?;
+    Ok(count)
+}
+
+fn read_config_json(configs_dir: &str, config_name: &str) -> Result<serde_json::Value> {
+    let path = Path::new(configs_dir).join(config_name);
+    let data = std::fs::read_to_string(&path)
+        .map_err(|e| error!("Can't read {}: {}", path.display(), e))?;
+    serde_json::from_str(&data).map_err(|e| error!("Can't parse {}: {}", path.display(), e))
+}
+
+// Private keys of validator key ring are decrypted if keyring encryption is on
+fn keys_bundle(
+    config: &serde_json::Value,
+    include_node_key: bool,
+    include_console_key: bool
+) -> Result<KeysBundle> {
+    let mut bundle = KeysBundle::default();
+    if let Some(validator_keys) = config.get("validator_keys").and_then(|keys| keys.as_array()) {
+        bundle.validator_keys = validator_keys.clone();
+    }
+    let encryption: KeyringEncryptionConfig = match config.get("keyring_encryption") {
+        Some(encryption) => serde_json::from_value(encryption.clone())?,
+        None => KeyringEncryptionConfig::default()
+    };
+    let key_ring = config.get("validator_key_ring").and_then(|key_ring| key_ring.as_object());
+    if let Some(key_ring) = key_ring.filter(|key_ring| !key_ring.is_empty()) {
+        let cipher = KeyringCipher::with_config(&encryption)?;
+        for (key_id, key) in key_ring.iter() {
+            let key: KeyOptionJson = serde_json::from_value(key.clone())?;
+            let key = match (&cipher, KeyringCipher::is_encrypted(&key)?) {
+                (Some(cipher), true) => cipher.decrypt(key_id, &key)?,
+                (None, true) => fail!(
+                    "key {} is encrypted, but keyring_encryption is not enabled", key_id
+                ),
+                (_, false) => key
+            };
+            KeyOption::from_private_key(&key).map_err(|e| error!("key {}: {}", key_id, e))?;
+            bundle.validator_key_ring.insert(key_id.clone(), serde_json::to_value(key)?);
+        }
+    }
+    if include_node_key {
+        bundle.adnl_node = Some(
+            config.get("adnl_node").cloned().ok_or_else(|| error!("ADNL node is not configured"))?
+        );
+    }
+    if include_console_key {
+        bundle.control_server = Some(
+            config.get("control_server").cloned()
+                .ok_or_else(|| error!("control server is not configured"))?
+        );
+        if let Some(console_keys) = config.get("console_keys").and_then(|keys| keys.as_array()) {
+            bundle.console_keys = console_keys.clone();
+        }
+    }
+    Ok(bundle)
+}
+
+fn read_archive(input: &str, passphrase_file: Option<&str>) -> Result<KeysBundle> {
+    let data = std::fs::read_to_string(input)
+        .map_err(|e| error!("Can't read keys archive {}: {}", input, e))?;
+    let archive: KeysArchive = serde_json::from_str(&data)
+        .map_err(|e| error!("{} is not a keys archive: {}", input, e))?;
+    if archive.version != ARCHIVE_VERSION {
+        fail!("keys archive version {} is not supported", archive.version)
+    }
+    match (archive.keys, archive.encrypted, passphrase_file) {
+        (Some(keys), None, _) => Ok(keys),
+        (None, Some(encrypted), Some(passphrase_file)) => {
+            let data = base64::decode(&encrypted)
+                .map_err(|e| error!("bad encrypted keys archive: {}", e))?;
+            let cipher = KeyringCipher::with_passphrase_file(passphrase_file)?;
+            let keys = cipher.decrypt_data(ARCHIVE_AAD, &data)
+                .map_err(|e| error!("keys archive {}: {}", input, e))?;
+            Ok(serde_json::from_slice(&keys)?)
+        }
+        (None, Some(_), None) => {
+            fail!("keys archive {} is encrypted, passphrase is required", input)
+        }
+        _ => fail!("keys archive {} is malformed", input)
+    }
+}
+
+fn write_private_file(path: &str, data: &[u8]) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)
+        .map_err(|e| error!("Can't create {}: {}", path, e))?
+        .write_all(data)?;
+    Ok(())
+}
+
+#[test]
+fn test_keys_export_import() {
+    use crate::{
+        config_init::{init_config, InitParams}, validator::validator_utils::mine_key_for_workchain
+    };
+
+    let dir = std::env::temp_dir().join(format!("test_keys_archive_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let global_config = dir.join("global.json");
+    std::fs::write(&global_config, serde_json::json!({ "validator": { "zero_state": {
+        "workchain": -1,
+        "shard": i64::MIN,
+        "seqno": 0,
+        "root_hash": base64::encode([1; 32]),
+        "file_hash": base64::encode([2; 32]),
+    }}}).to_string()).unwrap();
+    let init = |name: &str| {
+        let configs_dir = dir.join(name).to_str().unwrap().to_string();
+        let params = InitParams {
+            ip: "127.0.0.1".to_string(),
+            port: 30303,
+            network: global_config.to_str().unwrap().to_string(),
+            validator: true,
+            force: false,
+        };
+        init_config(&configs_dir, "config.json", &params).unwrap();
+        configs_dir
+    };
+    let source = init("source");
+    let target = init("target");
+
+    // Validator keys of the source node
+    let mut config = read_config_json(&source, "config.json").unwrap();
+    let (private, public) = mine_key_for_workchain(None);
+    let key_id = base64::encode(public.id().data());
+    config["validator_key_ring"] = serde_json::json!({ &key_id: private });
+    config["validator_keys"] = serde_json::json!([{
+        "election_id": 1000,
+        "validator_key_id": &key_id,
+        "validator_adnl_key_id": &key_id
+    }]);
+    std::fs::write(Path::new(&source).join("config.json"), config.to_string()).unwrap();
+
+    let archive = dir.join("keys.json");
+    let archive = archive.to_str().unwrap();
+    let mut params = ExportParams {
+        include_node_key: false,
+        include_console_key: false,
+        passphrase_file: None,
+    };
+    assert_eq!(export_keys(&source, "config.json", archive, &params).unwrap(), 1);
+    let bundle = read_archive(archive, None).unwrap();
+    assert!(bundle.adnl_node.is_none());
+    assert!(bundle.control_server.is_none());
+
+    assert_eq!(import_keys(&target, "config.json", archive, None, false).unwrap(), 1);
+    let imported = read_config_json(&target, "config.json").unwrap();
+    assert_eq!(imported["validator_key_ring"], config["validator_key_ring"]);
+    assert_eq!(imported["validator_keys"], config["validator_keys"]);
+    assert_ne!(imported["adnl_node"], config["adnl_node"]);
+    assert!(!Path::new(&target).join("config.json.import").exists());
+    let err = import_keys(&target, "config.json", archive, None, false).unwrap_err();
+    assert!(err.to_string().contains("already has validator keys"), "{}", err);
+
+    // Encrypted archive with node and console keys
+    let passphrase = dir.join("passphrase");
+    std::fs::write(&passphrase, "correct horse\n").unwrap();
+    let wrong_passphrase = dir.join("wrong_passphrase");
+    std::fs::write(&wrong_passphrase, "battery staple\n").unwrap();
+    params.include_node_key = true;
+    params.include_console_key = true;
+    params.passphrase_file = Some(passphrase.to_str().unwrap().to_string());
+    assert_eq!(export_keys(&source, "config.json", archive, &params).unwrap(), 1);
+    assert!(!std::fs::read_to_string(archive).unwrap().contains(&key_id));
+    assert!(read_archive(archive, None).is_err());
+    assert!(read_archive(archive, wrong_passphrase.to_str()).is_err());
+    let err = import_keys(&target, "config.json", archive, passphrase.to_str(), false).unwrap_err();
+    assert!(err.to_string().contains("ADNL node keys, control server keys"), "{}", err);
+    import_keys(&target, "config.json", archive, passphrase.to_str(), true).unwrap();
+    let imported = read_config_json(&target, "config.json").unwrap();
+    for field in ["validator_key_ring", "adnl_node", "control_server"].iter() {
+        assert_eq!(imported[field], config[field], "{}", field);
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}
@@ -0,0 +1,482 @@
+use crate::{
+    config::{LogFileConfig, TonNodeConfig}, engine::STATSD, engine_traits::EngineOperations
+};
+use log::LevelFilter;
+use log4rs::{
+    append::{
+        Append, console::{ConsoleAppender, Target},
+        rolling_file::{
+            RollingFileAppender,
+            policy::compound::{
+                CompoundPolicy, roll::fixed_window::FixedWindowRoller, trigger::size::SizeTrigger
+            }
+        }
+    },
+    config::{Appender, Config, Logger, Root}
+};
+use std::{fmt, path::Path, sync::{Arc, Mutex}, time::{Duration, SystemTime}};
+use ton_types::{error, fail, Result};
+
+// Top-level fields of config.json applied to the running node on reload
+const HOT_RELOADABLE_FIELDS: [&str; 10] = [
+    "cells_gc_interval_ms", "cells_cache_size_mb", "states_gc_policy", "collator",
+    "bandwidth_limits", "log_config_name", "log_levels", "log_file", "metrics", "front_workchains"
+];
+const LOG_FIELDS: [&str; 3] = ["log_config_name", "log_levels", "log_file"];
+// Written into config.json by the node itself when keys are added or elections are won
+const NODE_MANAGED_FIELDS: [&str; 4] = [
+    "validator_keys", "validator_key_ring", "workchain", "console_keys"
+];
+
+// Log config file is checked for changes this often. The logger is initialized with
+// a handle to be reloaded, so `refresh_rate` of the file itself is not used.
+const LOG_CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    static ref LOG_HANDLE: Mutex<Option<log4rs::Handle>> = Mutex::new(None);
+    // Log config file the logger is configured with and its modification time
+    static ref LOG_CONFIG_FILE: Mutex<Option<(String, Option<SystemTime>)>> = Mutex::new(None);
+}
+
+// Logs with info level into stdout if there is no node config,
+// the handle is kept to reload the config
+pub fn init_logger(config: Option<&TonNodeConfig>) {
+    let log_config = match config.map(log_config) {
+        Some(Ok(log_config)) => log_config,
+        Some(Err(e)) => {
+            println!("Error while initializing log: {}", e);
+            default_log_config()
+        }
+        None => default_log_config()
+    };
+    match log4rs::init_config(log_config) {
+        Ok(handle) => *LOG_HANDLE.lock().unwrap() = Some(handle),
+        Err(e) => {
+            println!("Error init log: {}", e);
+            return
+        }
+    }
+    set_log_config_file(config.and_then(|config| config.log_config_path()));
+    let started = std::thread::Builder::new()
+        .name("log config watcher".to_string())
+        .spawn(|| loop {
+            std::thread::sleep(LOG_CONFIG_CHECK_INTERVAL);
+            if let Err(e) = reload_changed_log_config_file() {
+                log::error!("Can't reload log config: {}", e)
+            }
+        });
+    if let Err(e) = started {
+        println!("Can't watch log config file: {}", e)
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+fn set_log_config_file(path: Option<String>) {
+    *LOG_CONFIG_FILE.lock().unwrap() = path.map(|path| {
+        let modified = modified(&path);
+        (path, modified)
+    });
+}
+
+// Replaces polling by `refresh_rate` of log4rs
+fn reload_changed_log_config_file() -> Result<()> {
+    let mut file = LOG_CONFIG_FILE.lock().unwrap();
+    let (path, last_modified) = match file.as_mut() {
+        Some(file) => file,
+        None => return Ok(())
+    };
+    let modified = modified(path);
+    if modified == *last_modified {
+        return Ok(())
+    }
+    *last_modified = modified;
+    let log_config = log4rs::config::load_config_file(&path, Default::default())
+        .map_err(|e| error!("Can't load log config {}: {}", path, e))?;
+    if let Some(handle) = LOG_HANDLE.lock().unwrap().as_ref() {
+        handle.set_config(log_config);
+        log::info!("Log config {} is reloaded", path);
+    }
+    Ok(())
+}
+
+// Log config file has precedence over log_levels
+fn log_config(config: &TonNodeConfig) -> Result<Config> {
+    match config.log_config_path() {
+        Some(path) => log4rs::config::load_config_file(&path, Default::default())
+            .map_err(|e| error!("Can't load log config {}: {}", path, e)),
+        None => {
+            let (default, levels) = config.log_levels()?;
+            log_config_with_levels(default, &levels, config.log_file_config())
+        }
+    }
+}
+
+fn default_log_config() -> Config {
+    // Can't fail without file appender
+    log_config_with_levels(LevelFilter::Info, &[], None).unwrap()
+}
+
+// Programmatic config: a logger per target with its level, into stdout or rolling file
+pub fn log_config_with_levels(
+    default: LevelFilter,
+    levels: &[(String, LevelFilter)],
+    file: Option<&LogFileConfig>
+) -> Result<Config> {
+    let appender: Box<dyn Append> = match file {
+        Some(file) => {
+            let roller = FixedWindowRoller::builder()
+                .build(&format!("{}.{{}}", file.path), file.max_files)
+                .map_err(|e| error!("Can't set rotation of log file {}: {}", file.path, e))?;
+            let trigger = SizeTrigger::new(file.max_size_mb * 1024 * 1024);
+            let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
+            let appender = RollingFileAppender::builder()
+                .build(&file.path, Box::new(policy))
+                .map_err(|e| error!("Can't open log file {}: {}", file.path, e))?;
+            Box::new(appender)
+        }
+        None => Box::new(ConsoleAppender::builder().target(Target::Stdout).build())
+    };
+    let loggers = levels.iter().map(|(target, level)| Logger::builder().build(target, *level));
+    Config::builder()
+        .appender(Appender::builder().build("main", appender))
+        .loggers(loggers)
+        .build(Root::builder().appender("main").build(default))
+        .map_err(|e| error!("Can't build log config: {}", e))
+}
+
+fn reload_logger(config: &TonNodeConfig) -> Result<()> {
+    let log_config = log_config(config)?;
+    match LOG_HANDLE.lock().unwrap().as_ref() {
+        Some(handle) => handle.set_config(log_config),
+        None => fail!("Logger is not initialized")
+    }
+    set_log_config_file(config.log_config_path());
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    pub restart_required: Vec<String>,
+    pub ignored: Vec<String>,
+    pub failed: Vec<(String, String)>,
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "applied: {:?}, require restart: {:?}, managed by node (ignored): {:?}",
+            self.applied, self.restart_required, self.ignored
+        )?;
+        for (field, error) in self.failed.iter() {
+            write!(f, ", {} failed: {}", field, error)?;
+        }
+        Ok(())
+    }
+}
+
+// Top-level fields with different values, both configs are serialized the same way
+pub fn changed_fields(old: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let old = old.as_object().unwrap_or(&empty);
+    let new = new.as_object().unwrap_or(&empty);
+    let mut changed: Vec<String> = old.keys().chain(new.keys())
+        .filter(|key| old.get(*key) != new.get(*key))
+        .cloned()
+        .collect();
+    changed.sort();
+    changed.dedup();
+    changed
+}
+
+// Sorts changed fields by what can be done with them
+pub fn classify_changes(changed: Vec<String>) -> ReloadReport {
+    let mut report = ReloadReport::default();
+    for field in changed {
+        if HOT_RELOADABLE_FIELDS.contains(&field.as_str()) {
+            report.applied.push(field)
+        } else if NODE_MANAGED_FIELDS.contains(&field.as_str()) {
+            report.ignored.push(field)
+        } else {
+            report.restart_required.push(field)
+        }
+    }
+    report
+}
+
+// Re-reads config.json (on SIGHUP) and applies changed hot-reloadable values.
+// Values requiring restart stay as they were until the node is restarted.
+pub struct ConfigReloader {
+    configs_dir: String,
+    file_name: String,
+    // Serialized config the node runs with
+    running: Mutex<serde_json::Value>,
+}
+
+impl ConfigReloader {
+
+    pub fn new(config: &TonNodeConfig) -> Result<Self> {
+        let (configs_dir, file_name) = config.file_location();
+        Ok(Self {
+            configs_dir: configs_dir.to_string(),
+            file_name: file_name.to_string(),
+            running: Mutex::new(serde_json::to_value(config)?),
+        })
+    }
+
+    pub fn reload(&self, engine: &dyn EngineOperations) -> Result<ReloadReport> {
+        let path = Path::new(&self.configs_dir).join(&self.file_name);
+        if !path.is_file() {
+            fail!("Config file {} is not found", path.display())
+        }
+        // Fails on invalid values, then nothing is applied
+        let config = TonNodeConfig::from_file(&self.configs_dir, &self.file_name, None, "", None)?;
+        let new = serde_json::to_value(&config)?;
+        let mut running = self.running.lock().unwrap();
+        let mut report = classify_changes(changed_fields(&running, &new));
+        let mut applied = Vec::new();
+        for field in std::mem::take(&mut report.applied) {
+            match Self::apply(engine, &field, &config) {
+                Ok(()) => {
+                    if let Some(running) = running.as_object_mut() {
+                        match new.get(&field) {
+                            Some(value) => running.insert(field.clone(), value.clone()),
+                            None => running.remove(&field)
+                        };
+                    }
+                    applied.push(field)
+                }
+                Err(e) => report.failed.push((field, e.to_string()))
+            }
+        }
+        report.applied = applied;
+        // Log levels may be changed in the log config file itself
+        if config.log_config_path().is_some() &&
+            !report.applied.iter().any(|field| LOG_FIELDS.contains(&field.as_str()))
+        {
+            if let Err(e) = reload_logger(&config) {
+                report.failed.push(("log config".to_string(), e.to_string()))
+            }
+        }
+        Ok(report)
+    }
+
+    fn apply(engine: &dyn EngineOperations, field: &str, config: &TonNodeConfig) -> Result<()> {
+        match field {
+            "cells_gc_interval_ms" => {
+                engine.adjust_states_gc_interval(config.cells_gc_interval_ms())
+            }
+            "cells_cache_size_mb" => {
+                engine.adjust_cells_cache_size(config.cells_cache_size_mb())
+            }
+            "states_gc_policy" => engine.set_states_gc_policy(config.states_gc_policy()),
+            "collator" => engine.set_collator_settings(config.collator_settings().clone()),
+            "bandwidth_limits" => {
+                let limits = config.bandwidth_limits_config();
+                engine.set_bandwidth_limits(limits.download_kb_per_sec, limits.serve_kb_per_sec)
+            }
+            "log_config_name" | "log_levels" | "log_file" => reload_logger(config)?,
+            "metrics" => STATSD.configure(config.metrics_config()),
+            "front_workchains" => engine.set_front_workchain_ids(config.front_workchain_ids())?,
+            _ => fail!("{} can't be reloaded", field)
+        }
+        Ok(())
+    }
+
+    fn reload_and_log(&self, engine: &dyn EngineOperations) {
+        match self.reload(engine) {
+            Ok(report) => {
+                log::info!("Config reloaded: {}", report);
+                if !report.restart_required.is_empty() {
+                    log::warn!(
+                        "Changes of {:?} are not applied, they require restart",
+                        report.restart_required
+                    );
+                }
+            }
+            Err(e) => log::error!("Can't reload config, nothing is applied: {}", e)
+        }
+        match engine.reload_global_config() {
+            Ok(update) => log::info!("Global config reloaded, DHT static nodes: {}", update),
+            Err(e) => log::error!("Can't reload global config: {}", e)
+        }
+    }
+
+    // Reloads config and global config on every SIGHUP, files are read off the runtime
+    #[cfg(unix)]
+    pub fn listen_sighup(self: Arc<Self>, engine: Arc<dyn EngineOperations>) -> Result<()> {
+        let mut signals = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                log::info!("SIGHUP received, reloading config");
+                let reloader = self.clone();
+                let engine = engine.clone();
+                let reload = tokio::task::spawn_blocking(
+                    move || reloader.reload_and_log(engine.as_ref())
+                );
+                if let Err(e) = reload.await {
+                    log::error!("Config reload failed: {}", e)
+                }
+            }
+        });
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen_sighup(self: Arc<Self>, _engine: Arc<dyn EngineOperations>) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_config_reload_classify() {
+    let old = serde_json::json!({
+        "cells_gc_interval_ms": 900000,
+        "collator": { "want_split": false },
+        "internal_db_path": "node_db",
+        "validator_keys": null,
+        "metrics": {}
+    });
+    let new = serde_json::json!({
+        "cells_gc_interval_ms": 600000,
+        "collator": { "want_split": false },
+        "internal_db_path": "other_db",
+        "validator_keys": [],
+        "metrics": {},
+        "lite_server": { "address": "0.0.0.0:3000" }
+    });
+    let changed = changed_fields(&old, &new);
+    assert_eq!(
+        changed, vec!["cells_gc_interval_ms", "internal_db_path", "lite_server", "validator_keys"]
+    );
+    assert_eq!(
+        classify_changes(changed),
+        ReloadReport {
+            applied: vec!["cells_gc_interval_ms".to_string()],
+            restart_required: vec!["internal_db_path".to_string(), "lite_server".to_string()],
+            ignored: vec!["validator_keys".to_string()],
+            failed: Vec::new(),
+        }
+    );
+    assert!(changed_fields(&old, &old).is_empty());
+}
+
+#[test]
+fn test_log_config_with_levels() {
+    use log::{Level, Log, Record};
+
+    let dir = std::env::temp_dir().join(format!("test_log_levels_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("node.log");
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "log_levels": { "validator": "debug", "sync": "info", "default": "warn" },
+        "log_file": { "path": path.to_str().unwrap() }
+    })).unwrap();
+    let (default, levels) = config.log_levels().unwrap();
+    assert_eq!(default, LevelFilter::Warn);
+    let log_config = log_config_with_levels(default, &levels, config.log_file_config()).unwrap();
+
+    let logger = log4rs::Logger::new(log_config);
+    let records = [
+        ("validator", Level::Debug, "validator debug"),
+        ("validator::manager", Level::Debug, "manager debug"),
+        ("validator", Level::Trace, "validator trace"),
+        ("sync", Level::Info, "sync info"),
+        ("sync", Level::Debug, "sync debug"),
+        ("node", Level::Warn, "node warn"),
+        ("node", Level::Info, "node info"),
+    ];
+    for (target, level, text) in records.iter() {
+        logger.log(
+            &Record::builder().target(target).level(*level).args(format_args!("{}", text)).build()
+        );
+    }
+    logger.flush();
+    let written = std::fs::read_to_string(&path).unwrap();
+    for text in ["validator debug", "manager debug", "sync info", "node warn"].iter() {
+        assert!(written.contains(text), "{} is not logged", text);
+    }
+    for text in ["validator trace", "sync debug", "node info"].iter() {
+        assert!(!written.contains(text), "{} is logged", text);
+    }
+
+    let config: TonNodeConfig = serde_json::from_value(serde_json::json!({
+        "log_levels": { "sync": "verbose" }
+    })).unwrap();
+    assert!(config.log_levels().is_err());
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn test_config_reload_applies_changes() {
+    use adnl::{common::KeyOption, node::AdnlNodeConfig};
+    use crate::network::node_network::NodeNetwork;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    struct TestEngine {
+        gc_interval_ms: AtomicU32
+    }
+    impl EngineOperations for TestEngine {
+        fn adjust_states_gc_interval(&self, interval_ms: u32) {
+            self.gc_interval_ms.store(interval_ms, Ordering::Relaxed)
+        }
+    }
+
+    let dir = std::env::temp_dir().join(format!("test_config_reload_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (adnl_node, _) = AdnlNodeConfig::with_ip_address_and_key_type(
+        "127.0.0.1:30303",
+        KeyOption::KEY_ED25519,
+        vec![NodeNetwork::TAG_DHT_KEY, NodeNetwork::TAG_OVERLAY_KEY]
+    ).unwrap();
+    let global_config = serde_json::json!({ "validator": { "zero_state": {
+        "workchain": -1,
+        "shard": i64::MIN,
+        "seqno": 0,
+        "root_hash": base64::encode([1; 32]),
+        "file_hash": base64::encode([2; 32]),
+    }}});
+    std::fs::write(dir.join("global.json"), global_config.to_string()).unwrap();
+    let mut json = serde_json::json!({
+        "ton_global_config_name": "global.json",
+        "adnl_node": adnl_node,
+        "cells_gc_interval_ms": 900000,
+        "internal_db_path": "node_db",
+    });
+    let write = |json: &serde_json::Value| {
+        std::fs::write(dir.join("config.json"), json.to_string()).unwrap()
+    };
+    write(&json);
+    let config = TonNodeConfig::from_file(dir.to_str().unwrap(), "config.json", None, "", None).unwrap();
+    let reloader = ConfigReloader::new(&config).unwrap();
+    let engine = TestEngine { gc_interval_ms: AtomicU32::new(0) };
+
+    assert_eq!(reloader.reload(&engine).unwrap(), ReloadReport::default());
+
+    json["cells_gc_interval_ms"] = serde_json::json!(600000);
+    json["internal_db_path"] = serde_json::json!("other_db");
+    write(&json);
+    let report = reloader.reload(&engine).unwrap();
+    assert_eq!(report.applied, vec!["cells_gc_interval_ms".to_string()]);
+    assert_eq!(report.restart_required, vec!["internal_db_path".to_string()]);
+    assert!(report.failed.is_empty());
+    assert_eq!(engine.gc_interval_ms.load(Ordering::Relaxed), 600000);
+
+    // Applied value is not applied again, not applied one is reported until restart
+    engine.gc_interval_ms.store(0, Ordering::Relaxed);
+    let report = reloader.reload(&engine).unwrap();
+    assert!(report.applied.is_empty());
+    assert_eq!(report.restart_required, vec!["internal_db_path".to_string()]);
+    assert_eq!(engine.gc_interval_ms.load(Ordering::Relaxed), 0);
+
+    // Broken config is not applied at all
+    json["cells_gc_interval_ms"] = serde_json::json!("often");
+    write(&json);
+    assert!(reloader.reload(&engine).is_err());
+    assert_eq!(engine.gc_interval_ms.load(Ordering::Relaxed), 0);
+    std::fs::remove_dir_all(&dir).ok();
+}
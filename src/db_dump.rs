@@ -0,0 +1,157 @@
+use crate::{
+    block::BlockStuff, config::TonNodeConfig,
+    internal_db::{InternalDb, InternalDbConfig, InternalDbImpl}
+};
+use ton_block::{AccountIdPrefixFull, BlockIdExt, BlockProcessingStatus, ShardIdent};
+use ton_types::{cells_serialization::serialize_toc, error, fail, Result, UInt256};
+
+pub struct DumpParams {
+    // wc:shard:seqno or wc:shard:seqno:root_hash:file_hash, hex shard and hashes
+    pub block_id: String,
+    // File to write raw BOC into
+    pub boc: Option<String>,
+}
+
+// Opens internal DB of the node read-only. RocksDB instances are secondary ones then,
+// so it works while the node is running and never takes the node's DB lock.
+async fn open_db(configs_dir: &str, config_name: &str) -> Result<InternalDbImpl> {
+    let config = TonNodeConfig::from_file(configs_dir, config_name, None, "", None)?;
+    let rocksdb = config.rocksdb_config();
+    storage::db::rocksdb::set_memory_limits(
+        rocksdb.block_cache_mb.map(|mb| mb as usize * 1024 * 1024),
+        rocksdb.write_buffer_mb.map(|mb| mb as usize * 1024 * 1024)
+    )?;
+    let db_config = InternalDbConfig {
+        db_directory: config.internal_db_path().unwrap_or("node_db").to_string(),
+        cells_gc_interval_ms: config.cells_gc_interval_ms(),
+        cells_cache_size_bytes: config.cells_cache_size_mb() * 1024 * 1024,
+        block_handles_cache_size: config.block_handles_cache_size(),
+        write_behind: None,
+        paths: config.internal_db_paths().clone(),
+        migrate_layout: false,
+        index_transactions: config.index_transactions(),
+        read_only: true,
+    };
+    InternalDbImpl::new(db_config).await
+}
+
+// Block as ton_block_json renders it for external DB
+pub async fn dump_block(
+    configs_dir: &str,
+    config_name: &str,
+    params: &DumpParams
+) -> Result<serde_json::Value> {
+    let db = open_db(configs_dir, config_name).await?;
+    let id = resolve_block_id(&db, &params.block_id)?;
+    let handle = db.load_block_handle(&id)?
+        .ok_or_else(|| error!("block {} is not found in block_handle_db", id))?;
+    if !handle.has_data() {
+        fail!("block {} is in block_handle_db, but its data is not stored", id)
+    }
+    let data = db.load_block_data_raw(&handle).await
+        .map_err(|e| error!("data of block {} is not found in archives: {}", id, e))?;
+    let block = BlockStuff::deserialize(id.clone(), data)?;
+    if let Some(path) = &params.boc {
+        std::fs::write(path, block.data()).map_err(|e| error!("Can't write {}: {}", path, e))?;
+    }
+    let set = ton_block_json::BlockSerializationSet {
+        block: block.block().clone(),
+        id: id.root_hash.clone(),
+        status: BlockProcessingStatus::Finalized,
+        boc: block.data().to_vec(),
+    };
+    Ok(serde_json::Value::Object(ton_block_json::db_serialize_block("id", &set)?))
+}
+
+// Shard state after the block as ton_block_json renders it for external DB
+pub async fn dump_state(
+    configs_dir: &str,
+    config_name: &str,
+    params: &DumpParams
+) -> Result<serde_json::Value> {
+    let db = open_db(configs_dir, config_name).await?;
+    let id = resolve_block_id(&db, &params.block_id)?;
+    // Missing key is reported with shardstate_db, cells_db or cells_db1
+    let state = db.load_shard_state_dynamic(&id)
+        .map_err(|e| error!("state of block {} is not loaded: {}", id, e))?;
+    let boc = serialize_toc(state.root_cell())?;
+    if let Some(path) = &params.boc {
+        std::fs::write(path, &boc).map_err(|e| error!("Can't write {}: {}", path, e))?;
+    }
+    let set = ton_block_json::ShardStateSerializationSet {
+        state: state.state().clone(),
+        id: state.root_cell().repr_hash().to_hex_string(),
+        block_id: Some(id.root_hash.clone()),
+        workchain_id: id.shard().workchain_id(),
+        boc,
+    };
+    Ok(serde_json::Value::Object(ton_block_json::db_serialize_shard_state("id", &set)?))
+}
+
+// Block id without hashes is looked up by seqno in index_db
+fn resolve_block_id(db: &InternalDbImpl, block_id: &str) -> Result<BlockIdExt> {
+    let (shard, seqno, hashes) = parse_block_id(block_id)?;
+    if let Some((root_hash, file_hash)) = hashes {
+        return Ok(BlockIdExt::with_params(shard, seqno, root_hash, file_hash))
+    }
+    let acc_pfx = AccountIdPrefixFull {
+        workchain_id: shard.workchain_id(),
+        prefix: shard.shard_prefix_with_tag()
+    };
+    let handle = db.find_block_by_seq_no(&acc_pfx, seqno)
+        .map_err(|e| error!("block {}:{} is not found in index_db: {}", shard, seqno, e))?;
+    if (handle.id().shard() != &shard) || (handle.id().seq_no() != seqno) {
+        fail!("block {}:{} is not found in index_db, the nearest one is {}", shard, seqno, handle.id())
+    }
+    Ok(handle.id().clone())
+}
+
+fn parse_block_id(block_id: &str) -> Result<(ShardIdent, u32, Option<(UInt256, UInt256)>)> {
+    let parts = block_id.split(':').collect::<Vec<_>>();
+    if (parts.len() != 3) && (parts.len() != 5) {
+        fail!("block id {} is not wc:shard:seqno[:root_hash:file_hash]", block_id)
+    }
+    let workchain_id = parts[0].parse::<i32>()
+        .map_err(|e| error!("workchain of block id {} is invalid: {}", block_id, e))?;
+    let shard = u64::from_str_radix(parts[1], 16)
+        .map_err(|e| error!("shard of block id {} is invalid: {}", block_id, e))?;
+    let seqno = parts[2].parse::<u32>()
+        .map_err(|e| error!("seqno of block id {} is invalid: {}", block_id, e))?;
+    let shard = ShardIdent::with_tagged_prefix(workchain_id, shard)?;
+    let hashes = if parts.len() == 5 {
+        Some((parse_hash(parts[3])?, parse_hash(parts[4])?))
+    } else {
+        None
+    };
+    Ok((shard, seqno, hashes))
+}
+
+fn parse_hash(hash: &str) -> Result<UInt256> {
+    let bytes = hex::decode(hash).map_err(|e| error!("hash {} is invalid: {}", hash, e))?;
+    if bytes.len() != 32 {
+        fail!("hash {} is not 32 bytes long", hash)
+    }
+    Ok(UInt256::from_slice(&bytes))
+}
+
+#[test]
+fn test_parse_block_id() {
+    let (shard, seqno, hashes) = parse_block_id("-1:8000000000000000:100").unwrap();
+    assert_eq!(shard, ShardIdent::masterchain());
+    assert_eq!(seqno, 100);
+    assert!(hashes.is_none());
+
+    let root_hash = "01".repeat(32);
+    let file_hash = "AB".repeat(32);
+    let (shard, seqno, hashes) = parse_block_id(
+        &format!("0:4000000000000000:7:{}:{}", root_hash, file_hash)
+    ).unwrap();
+    assert_eq!(shard, ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000).unwrap());
+    assert_eq!(seqno, 7);
+    assert_eq!(hashes, Some((UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[0xab; 32]))));
+
+    assert!(parse_block_id("-1:8000000000000000").is_err());
+    assert!(parse_block_id("-1:8000000000000000:x").is_err());
+    assert!(parse_block_id("-1:8000000000000000:1:01:02").is_err());
+    assert!(parse_block_id(&format!("0:zz:1:{}:{}", root_hash, file_hash)).is_err());
+}
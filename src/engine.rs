@@ -1,43 +1,56 @@
 use crate::{
-    block::{BlockStuff, BlockIdExtExtention},
+    block::{BlockStuff, BlockIdExtExtention, convert_block_id_ext_api2blk},
     block_proof::BlockProofStuff,
-    config::{TonNodeConfig, KafkaConsumerConfig, CollatorTestBundlesGeneralConfig},
+    config::{
+        TonNodeConfig, KafkaConsumerConfig, CollatorTestBundlesGeneralConfig, 
+        ExternalDbHealthcheckConfig, BlocksRetentionConfig, PersistentStatesConfig,
+        ArchivesSyncConfig, ArchiveNodeConfig, TonNodeGlobalConfig, unprocessed_front_workchains
+    },
+    config_reload::ConfigReloader,
     engine_traits::{
         ExternalDb, EngineOperations,
         OverlayOperations, PrivateOverlayOperations,
         ValidatedBlockStat,
     },
+    error::{is_retriable, NetworkError, NodeError},
     full_node::{
-        apply_block::{self, apply_block},
+        apply_block::{self, apply_block}, apply_stages::{ApplyStage, ApplyStages},
+        broadcast_dedup::BroadcastDedup,
         shard_client::{
-            process_block_broadcast, start_masterchain_client, start_shards_client,
+            process_block_broadcast, run_masterchain_client, run_shards_client,
             SHARD_BROADCAST_WINDOW
         },
+        key_block_prefetch::KeyBlockPrefetcher, shard_lags::ShardLags, state_helper::StateDownloads,
+        tracked_shards::TrackedShards,
     },
     internal_db::{
         InternalDb, InternalDbConfig, InternalDbImpl, 
-        INITIAL_MC_BLOCK, LAST_APPLIED_MC_BLOCK, PSS_KEEPER_MC_BLOCK,
+        INITIAL_MC_BLOCK, LAST_APPLIED_MC_BLOCK, PSS_KEEPER_MC_BLOCK, ZERO_STATE_ID,
+        backup::DbBackup, compaction::DbCompactor, integrity, scrubber::DbScrubber,
         state_gc_resolver::AllowStateGcSmartResolver
     },
+    jaeger, metrics::MetricsClient,
     network::{
-        full_node_client::FullNodeOverlayClient, control::ControlServer,
-        full_node_service::FullNodeOverlayService
+        bandwidth::BandwidthLimits, full_node_client::FullNodeOverlayClient, peer_scores::PeerOutcome,
+        control::ControlServer, health::HealthServer, lite_server::LiteServer,
+        full_node_service::FullNodeOverlayService, node_network::DhtStaticNodesUpdate,
+        validators_overlay::ValidatorsOverlay
+    },
+    shard_state::ShardStateStuff, shutdown::SHUTDOWN, supervisor::Supervisor,
+    types::{awaiters_pool::AwaitersPool, states_cache::{StatesCache, StatesCacheStats}},
+    ext_messages::MessagesPool, external_db_ack::ExternalDbAck,
+    validator::{
+        CollatorSettings, validator_manager::run_validator_manager, candidate_db::LastRotationBlockDb,
+        timings::ShardTimings
     },
-    shard_state::ShardStateStuff,
-    types::{awaiters_pool::AwaitersPool, lockfree_cache::TimeBasedCache},
-    ext_messages::MessagesPool,
-    validator::{validator_manager::start_validator_manager, candidate_db::LastRotationBlockDb},
     shard_blocks::{
         ShardBlocksPool, resend_top_shard_blocks_worker, save_top_shard_blocks_worker, 
-        ShardBlockProcessingResult
+        ShardBlockProcessingResult, RevokedBlock
     },
-    boot,
+    boot, sync::SyncProgress,
 };
 #[cfg(feature = "slashing")]
-use crate::{
-    block::convert_block_id_ext_api2blk,
-    engine_traits::ValidatedBlockStatNode,
-};
+use crate::engine_traits::ValidatedBlockStatNode;
 #[cfg(feature = "local_test")]
 use crate::network::node_network_stub::NodeNetworkStub;
 #[cfg(not(feature = "local_test"))]
@@ -51,11 +64,10 @@ use crate::{
     network::telemetry::{FullNodeNetworkTelemetry, FullNodeNetworkTelemetryKind},
 };
 use overlay::QueriesConsumer;
-#[cfg(feature = "metrics")]
-use statsd::client;
 use std::{
-    convert::TryInto, ops::Deref, sync::{Arc, atomic::{AtomicBool, AtomicU32, Ordering, AtomicI32, AtomicU64}},
-    time::Duration, collections::HashMap,
+    convert::TryInto, ops::Deref,
+    sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering, AtomicI32}},
+    time::{Duration, Instant, SystemTime}, collections::HashMap,
 };
 #[cfg(feature = "slashing")]
 use std::collections::HashSet;
@@ -68,24 +80,34 @@ use crate::{
 use std::env;
 #[cfg(feature = "slashing")]
 use std::collections::HashSet;
-use storage::types::BlockHandle;
+use storage::{
+    block_handle_db::WriteBehindConfig, state_serializer::SerializationProgress, types::BlockHandle
+};
 use ton_block::{
-    self, ShardIdent, BlockIdExt, MASTERCHAIN_ID, SHARD_FULL,
+    self, AccountIdPrefixFull, ShardIdent, BlockIdExt, MASTERCHAIN_ID, SHARD_FULL,
 };
-use ton_types::{error, fail, Result};
-#[cfg(feature = "slashing")]
-use ton_types::UInt256;
+use ton_types::{error, fail, Result, UInt256};
 use ton_api::ton::ton_node::{
-    Broadcast, broadcast::{BlockBroadcast, ExternalMessageBroadcast, NewShardBlockBroadcast}
+    Broadcast, blockidext::BlockIdExt as BlockIdExtApi,
+    broadcast::{BlockBroadcast, ExternalMessageBroadcast, NewShardBlockBroadcast}
 };
 use adnl::{common::KeyId, server::AdnlServerConfig};
 use crossbeam_channel::{Sender, Receiver};
+use futures::future::Either;
 
 const MAX_VALIDATED_BLOCK_STATS_ENTRIES_COUNT: usize = 10000; //maximum number of validated block stats entries in engine's queue
+const MIN_KEPT_PERSISTENT_STATES: u32 = 2; // see Engine::blocks_pruning_boundary
+const READ_ONLY_CATCH_UP_INTERVAL_MS: u64 = 1000; // how often node in read-only mode follows DB changes
 
 pub struct Engine {
     db: Arc<dyn InternalDb>,
     ext_db: Vec<Arc<dyn ExternalDb>>,
+    ext_db_ack: ExternalDbAck,
+    ext_db_healthcheck: ExternalDbHealthcheckConfig,
+    ext_db_health: std::sync::Mutex<String>,
+    // Workchains written by external dbs, None - all
+    front_workchain_ids: std::sync::RwLock<Option<Vec<i32>>>,
+    front_workchains_strict: bool,
     overlay_operations: Arc<dyn OverlayOperations>,
     shard_states_awaiters: AwaitersPool<BlockIdExt, ShardStateStuff>,
     block_applying_awaiters: AwaitersPool<BlockIdExt, ()>,
@@ -94,25 +116,53 @@ pub struct Engine {
     external_messages: MessagesPool,
 
     zero_state_id: BlockIdExt,
+    global_config_path: String,
+    config_reloader: Arc<ConfigReloader>,
+    hardforks: Vec<BlockIdExt>,
     init_mc_block_id: BlockIdExt,
     initial_sync_disabled: bool,
     pub network: Arc<NodeNetwork>,
     archives_life_time: Option<u32>,
+    blocks_retention: BlocksRetentionConfig,
+    persistent_states: PersistentStatesConfig,
+    persistent_states_status: std::sync::Mutex<String>,
+    validation_history_status: std::sync::Mutex<String>,
+    runtime_workers: usize,
+    validator_runtime_workers: AtomicUsize,
+    resource_profile: String,
+    archives_sync: ArchivesSyncConfig,
+    archive_node: ArchiveNodeConfig,
     shard_blocks: ShardBlocksPool,
     last_known_mc_block_seqno: AtomicU32,
     last_known_keyblock_seqno: AtomicU32,
     will_validate: AtomicBool,
+    validator_adnl_unreachable: AtomicBool,
 
     test_bundles_config: CollatorTestBundlesGeneralConfig,
+    // Replaced by config reload, collators take it on start
+    collator_settings: std::sync::RwLock<CollatorSettings>,
  
-    shard_states_cache: TimeBasedCache<BlockIdExt, ShardStateStuff>,
-    loaded_from_ss_cache: AtomicU64,
-    loaded_ss_total: AtomicU64,
+    shard_states_cache: Arc<StatesCache>,
     pub workchain_id: AtomicI32,
 
     state_gc_resolver: Arc<AllowStateGcSmartResolver>,
-    validation_status: lockfree::map::Map<ShardIdent, u64>,
-    collation_status: lockfree::map::Map<ShardIdent, u64>,
+    db_compactor: Arc<DbCompactor>,
+    db_backup: Arc<DbBackup>,
+    db_scrubber: Arc<DbScrubber>,
+    state_downloads: StateDownloads,
+    bandwidth_limits: BandwidthLimits,
+    tracked_shards: TrackedShards,
+    shard_lags: ShardLags,
+    supervisor: Arc<Supervisor>,
+    broadcast_dedup: BroadcastDedup,
+    download_hedge_delay: Option<Duration>,
+    relay_untracked_broadcasts: bool,
+    max_serve_queries: usize,
+    sync_progress: SyncProgress,
+    key_block_prefetcher: KeyBlockPrefetcher,
+    apply_stages: ApplyStages,
+    validation_status: ShardTimings,
+    collation_status: ShardTimings,
     validated_block_stats_sender: Sender<ValidatedBlockStat>,
     validated_block_stats_receiver: Receiver<ValidatedBlockStat>,
 
@@ -129,14 +179,18 @@ pub struct Engine {
 }
 
 struct DownloadContext<'a, T> {
+    // Peers asked during current attempt, hedged download does not ask them again
+    active_peers: Arc<lockfree::set::Set<Arc<KeyId>>>,
     client: Arc<dyn FullNodeOverlayClient>,
     db: &'a dyn InternalDb,
     downloader: Arc<dyn Downloader<Item = T>>,
+    hedge_delay: Option<Duration>,
     id: &'a BlockIdExt,
     limit: Option<u32>,
     log_error_limit: u32,
     name: &'a str,
     timeout: Option<(u64, u64, u64)>, // (current, multiplier*10, max)
+    validators_overlays: Vec<Arc<ValidatorsOverlay>>, // asked before public overlay
     #[cfg(feature = "telemetry")]
     full_node_telemetry: &'a FullNodeTelemetry,
 }
@@ -146,14 +200,25 @@ impl <T> DownloadContext<'_, T> {
     async fn download(&mut self) -> Result<T> {
         let mut attempt = 1;
         loop {
-            match self.downloader.try_download(self).await {
+            self.active_peers = Arc::new(lockfree::set::Set::new());
+            let result = match self.hedge_delay {
+                Some(delay) if self.downloader.hedged() => {
+                    self.try_download_hedged(delay, attempt).await
+                },
+                _ => self.downloader.try_download(self).await
+            };
+            match result {
+                // Broken local data or wrong request stay the same with another attempt
+                Err(e) if !is_retriable(&e) => break Err(e),
                 Err(e) => self.log(format!("{}", e).as_str(), attempt),
                 Ok(ret) => break Ok(ret)
             }
             attempt += 1;
             if let Some(limit) = &self.limit {
                 if &attempt > limit {
-                    fail!("Downloader: out of attempts");
+                    fail!(NodeError::Network(
+                        NetworkError::OutOfAttempts, format!("Downloader: {} {}", self.name, self.id)
+                    ))
                 }
             }
             if let Some((current, mult, max)) = &mut self.timeout {
@@ -165,6 +230,31 @@ impl <T> DownloadContext<'_, T> {
         }
     }
 
+    // Slow peer must not stall the download: after the delay the same download is started
+    // once more with another neighbour, the first successful one wins, other is dropped
+    async fn try_download_hedged(&self, delay: Duration, attempt: u32) -> Result<T> {
+        let mut first = self.downloader.try_download(self);
+        if let Ok(ret) = tokio::time::timeout(delay, &mut first).await {
+            return ret
+        }
+        STATSD.incr(&format!("{}_hedges", self.name));
+        let second = self.downloader.try_download(self);
+        let (ret, won) = match futures::future::select(first, second).await {
+            Either::Left((Ok(ret), _)) => (ret, "first"),
+            Either::Right((Ok(ret), _)) => (ret, "second"),
+            Either::Left((Err(e), second)) => {
+                self.log(format!("{}", e).as_str(), attempt);
+                (second.await?, "second")
+            },
+            Either::Right((Err(e), first)) => {
+                self.log(format!("{}", e).as_str(), attempt);
+                (first.await?, "first")
+            }
+        };
+        STATSD.incr(&format!("{}_hedge_won_{}", self.name, won));
+        Ok(ret)
+    }
+
     fn log(&self, msg: &str, attempt: u32) {
        log::log!(
            if attempt > self.log_error_limit {
@@ -186,6 +276,10 @@ trait Downloader: Send + Sync {
         &self, 
         context: &DownloadContext<'_, Self::Item>,
     ) -> Result<Self::Item>;
+    // Whether a slow attempt is hedged by a parallel one
+    fn hedged(&self) -> bool {
+        false
+    }
 }
 
 struct BlockDownloader;
@@ -208,13 +302,27 @@ impl Downloader for BlockDownloader {
         }
         #[cfg(feature = "telemetry")]
         context.full_node_telemetry.new_downloading_block_attempt(context.id);
-        let ret = context.client.download_block_full(context.id).await;
+        for overlay in context.validators_overlays.iter() {
+            match overlay.download_block_full(context.id).await {
+                Ok(ret) => {
+                    #[cfg(feature = "telemetry")]
+                    context.full_node_telemetry.new_downloaded_block(context.id);
+                    return Ok(ret)
+                }
+                Err(e) => log::debug!("{}", e)
+            }
+        }
+        let ret = context.client.download_block_full(context.id, &context.active_peers).await;
         #[cfg(feature = "telemetry")]
         if ret.is_ok() { 
             context.full_node_telemetry.new_downloaded_block(context.id);
         }
         ret
     }
+
+    fn hedged(&self) -> bool {
+        true
+    }
 }
 
 struct BlockProofDownloader {
@@ -239,8 +347,13 @@ impl Downloader for BlockProofDownloader {
             context.id, 
             self.is_link, 
             self.key_block, 
+            &context.active_peers
         ).await        
     }
+
+    fn hedged(&self) -> bool {
+        true
+    }
 }              
 
 struct NextBlockDownloader;
@@ -266,8 +379,12 @@ impl Downloader for NextBlockDownloader {
                 }
             }
         }
-        context.client.download_next_block_full(context.id).await
+        context.client.download_next_block_full(context.id, &context.active_peers).await
     }    
+
+    fn hedged(&self) -> bool {
+        true
+    }
 }  
 
 struct ZeroStateDownloader;
@@ -293,23 +410,150 @@ impl Downloader for ZeroStateDownloader {
 
 impl Engine {
 
-    pub async fn new(general_config: TonNodeConfig, ext_db: Vec<Arc<dyn ExternalDb>>, initial_sync_disabled : bool) -> Result<Arc<Self>> {
+    pub async fn new(
+        general_config: TonNodeConfig,
+        ext_db: Vec<Arc<dyn ExternalDb>>,
+        initial_sync_disabled : bool,
+        migrate_db_layout: bool,
+        read_only: bool
+    ) -> Result<Arc<Self>> {
 
         log::info!("Creating engine...");
 
         let archives_life_time = general_config.gc_archives_life_time_hours();
+        let blocks_retention = general_config.blocks_retention_config();
+        let states_gc_policy = general_config.states_gc_policy().clone();
+        let persistent_states = general_config.persistent_states_config();
+        let archives_sync = general_config.archives_sync_config().clone();
+        let bandwidth_limits = BandwidthLimits::new(&general_config.bandwidth_limits_config());
+        let resource_profile = general_config.resource_profile_summary();
+        let global_config_path = general_config.global_config_path()?;
+        let config_reloader = Arc::new(ConfigReloader::new(&general_config)?);
+        let archive_node = general_config.archive_node_config().clone();
+        if archive_node.enabled {
+            log::info!("Archive node role: all blocks, archives and persistent states are kept");
+        }
+        let tracked_shards = TrackedShards::new(general_config.tracked_shards()?);
+        let shard_lags = ShardLags::new(general_config.shard_catch_up_config().clone());
+        let supervisor = Arc::new(Supervisor::new(general_config.supervisor_config().clone()));
+        let broadcast_dedup = BroadcastDedup::new(general_config.broadcast_dedup_config());
+        let download_hedge_delay = match general_config.network_config().download_hedge_delay_ms {
+            0 => None,
+            delay => Some(Duration::from_millis(delay))
+        };
+        let relay_untracked_broadcasts = general_config.network_config().relay_untracked_broadcasts;
+        let max_serve_queries = general_config.network_config().max_serve_queries;
         let db_directory = general_config.internal_db_path().unwrap_or_else(|| {"node_db"}).to_string();
         let cells_gc_interval_ms = general_config.cells_gc_interval_ms();
-        let last_rotation_block_db = LastRotationBlockDb::new(db_directory.clone());
-        let db_config = InternalDbConfig { db_directory, cells_gc_interval_ms };
+        let cells_cache_size_bytes = general_config.cells_cache_size_mb() * 1024 * 1024;
+        let states_cache_size_bytes = general_config.states_cache_size_mb() * 1024 * 1024;
+        let block_handles_cache_size = general_config.block_handles_cache_size();
+        let write_behind = general_config.db_write_behind_config();
+        let write_behind = if write_behind.enabled {
+            Some(WriteBehindConfig {
+                flush_interval: Duration::from_millis(write_behind.flush_interval_ms),
+                max_batch: write_behind.max_batch,
+            })
+        } else {
+            None
+        };
+        let last_rotation_block_db_dir = db_directory.clone();
+        let rocksdb = general_config.rocksdb_config();
+        storage::db::rocksdb::set_memory_limits(
+            rocksdb.block_cache_mb.map(|mb| mb as usize * 1024 * 1024),
+            rocksdb.write_buffer_mb.map(|mb| mb as usize * 1024 * 1024)
+        )?;
+        let db_config = InternalDbConfig {
+            db_directory, cells_gc_interval_ms, cells_cache_size_bytes, block_handles_cache_size,
+            write_behind,
+            paths: general_config.internal_db_paths().clone(),
+            migrate_layout: migrate_db_layout,
+            index_transactions: general_config.index_transactions(),
+            read_only,
+        };
         let db = Arc::new(InternalDbImpl::new(db_config).await?);
+        // Numbers of blocks in external db feeds continue after restart
+        for ext_db in ext_db.iter() {
+            ext_db.set_feed_storage(db.clone());
+        }
+        // Opened after internal DB to be a secondary instance in read-only mode
+        let last_rotation_block_db = LastRotationBlockDb::new(last_rotation_block_db_dir);
         let global_config = general_config.load_global_config()?;
         let test_bundles_config = general_config.test_bundles_config().clone();
+        let collator_settings = general_config.collator_settings().clone();
+        let ext_messages_config = general_config.ext_messages_config().clone();
+        let ext_db_ack = ExternalDbAck::new(general_config.external_db_ack_config());
+        let ext_db_healthcheck = general_config.external_db_healthcheck_config();
+        let front_workchain_ids = general_config.front_workchain_ids();
+        let front_workchains_strict = general_config.front_workchains_strict();
+        let db_compaction_windows = general_config.db_compaction_config().windows.clone();
+        let db_backup_config = general_config.db_backup_config().clone();
+        let db_scrubber_config = general_config.db_scrubber_config().clone();
         let zero_state_id = global_config.zero_state().expect("check zero state settings");
-        let mut init_mc_block_id = global_config.init_block()?.unwrap_or_else(|| zero_state_id.clone());
-        if let Ok(Some(block_id)) = db.load_node_state(INITIAL_MC_BLOCK) {
-            if block_id.seq_no > init_mc_block_id.seq_no {
+        let global_init_block = global_config.init_block()?;
+        let hardforks = global_config.hardforks()?;
+        if !hardforks.is_empty() {
+            log::info!(
+                "Hardforks: {}",
+                hardforks.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+            );
+        }
+        let trusted_init_block = general_config.init_block()?;
+        if let Some(init_block) = trusted_init_block.as_ref() {
+            crate::boot::check_trusted_init_block(init_block, &zero_state_id, global_init_block.as_ref())?;
+        }
+        // DB of another network can't be reused
+        match db.load_node_state(ZERO_STATE_ID)? {
+            Some(db_zero_state_id) => if *db_zero_state_id != zero_state_id {
+                fail!(
+                    "Internal DB is created for network with zerostate {}, but global config \
+                    has zerostate {}. Use another DB directory for this network",
+                    db_zero_state_id, zero_state_id
+                )
+            },
+            None => {
+                // Zerostate is not pinned yet: non-empty DB is expected to have blocks of this network
+                if db.load_node_state(LAST_APPLIED_MC_BLOCK)?.is_some() {
+                    let db_init_block = db.load_node_state(INITIAL_MC_BLOCK)?;
+                    let known_blocks = global_init_block.iter()
+                        .chain(trusted_init_block.iter())
+                        .chain(hardforks.iter())
+                        .collect::<Vec<_>>();
+                    let known = is_network_db(
+                        db_init_block.as_deref(),
+                        &known_blocks,
+                        db.load_block_handle(&zero_state_id)?.is_some(),
+                        |id| Ok(
+                            db.load_block_handle(id)?
+                                .map(|handle| handle.has_proof() && handle.is_key_block().unwrap_or(false))
+                                .unwrap_or(false)
+                        )
+                    )?;
+                    if !known {
+                        log::warn!(
+                            "Internal DB has neither zerostate {} nor known init block {}, \
+                            it may belong to another network. Zerostate of global config is pinned",
+                            zero_state_id,
+                            db_init_block.map(|id| id.to_string()).unwrap_or_default()
+                        )
+                    }
+                }
+                if !read_only {
+                    db.save_node_state(ZERO_STATE_ID, &zero_state_id)?
+                }
+            }
+        }
+        let mut init_mc_block_id = global_init_block.unwrap_or_else(|| zero_state_id.clone());
+        match db.load_node_state(INITIAL_MC_BLOCK) {
+            Ok(Some(block_id)) => if block_id.seq_no > init_mc_block_id.seq_no {
                 init_mc_block_id = block_id.deref().clone()
+            },
+            // Trusted init block is used only to boot with empty DB
+            _ => if let Some(init_block) = trusted_init_block {
+                if db.load_node_state(LAST_APPLIED_MC_BLOCK)?.is_none() {
+                    log::info!("Booting from trusted init block {}", init_block);
+                    init_mc_block_id = init_block
+                }
             }
         }
         let workchain_id = general_config.workchain_id().unwrap_or(ton_block::INVALID_WORKCHAIN_ID);
@@ -324,7 +568,10 @@ impl Engine {
         };
         #[cfg(not(feature = "local_test"))]
         let network = NodeNetwork::new(general_config).await?;
-        network.clone().start().await?;
+        // Node in read-only mode doesn't talk to other nodes
+        if !read_only {
+            network.clone().start().await?;
+        }
 
         let shard_blocks = match db.load_all_top_shard_blocks() {
             Ok(tsbs) => tsbs,
@@ -340,38 +587,91 @@ impl Engine {
         let (shard_blocks_pool, shard_blocks_receiver) = 
             ShardBlocksPool::new(shard_blocks, last_mc_seqno, false);
 
-        let state_gc_resolver = Arc::new(AllowStateGcSmartResolver::new());
-        db.start_states_gc(state_gc_resolver.clone());
+        let state_gc_resolver = Arc::new(AllowStateGcSmartResolver::new(&states_gc_policy));
+        let db_compactor = Arc::new(DbCompactor::new(db.clone()));
+        let db_backup = Arc::new(DbBackup::new(db.clone(), zero_state_id.clone()));
+        let db_scrubber = Arc::new(DbScrubber::new(db.clone()));
+        if !read_only {
+            let gc_db = db.clone();
+            let gc_resolver = state_gc_resolver.clone();
+            supervisor.spawn("states gc", move || {
+                let gc = gc_db.start_states_gc(gc_resolver.clone());
+                Box::pin(async move { gc.await.map_err(|e| error!("{}", e)) })
+            });
+            db_compactor.clone().start_scheduler(db_compaction_windows);
+            db_backup.clone().start_scheduler(db_backup_config);
+        }
+
+        let ext_messages_storage = if ext_messages_config.persist && !read_only {
+            Some(db.clone() as Arc<dyn InternalDb>)
+        } else {
+            None
+        };
 
         log::info!("Engine is created.");
 
         let (validated_block_stats_sender, validated_block_stats_receiver) = crossbeam_channel::bounded(MAX_VALIDATED_BLOCK_STATS_ENTRIES_COUNT);
+        let external_messages = MessagesPool::new(ext_messages_config, ext_messages_storage)?;
+        #[cfg(feature = "external_db")]
+        let external_messages = external_messages.with_status_feed(&ext_db);
         let engine = Arc::new(Engine {
             db,
             ext_db,
+            ext_db_ack,
+            ext_db_healthcheck,
+            ext_db_health: std::sync::Mutex::new("not checked".to_string()),
+            front_workchain_ids: std::sync::RwLock::new(front_workchain_ids),
+            front_workchains_strict,
             overlay_operations: network.clone(),
             shard_states_awaiters: AwaitersPool::new("shard_states_awaiters"),
             block_applying_awaiters: AwaitersPool::new("block_applying_awaiters"),
             next_block_applying_awaiters: AwaitersPool::new("next_block_applying_awaiters"),
             download_block_awaiters: AwaitersPool::new("download_block_awaiters"),
-            external_messages: MessagesPool::new(),
+            external_messages,
             zero_state_id,
+            global_config_path,
+            config_reloader,
+            hardforks,
             init_mc_block_id,
             initial_sync_disabled,
             archives_life_time,
+            blocks_retention,
+            persistent_states,
+            persistent_states_status: std::sync::Mutex::new("no states generated".to_string()),
+            validation_history_status: std::sync::Mutex::new("no validations".to_string()),
+            runtime_workers: tokio::runtime::Handle::current().metrics().num_workers(),
+            validator_runtime_workers: AtomicUsize::new(0),
+            resource_profile,
+            archives_sync,
+            archive_node,
             network: network.clone(),
             shard_blocks: shard_blocks_pool,
             last_known_mc_block_seqno: AtomicU32::new(0),
             last_known_keyblock_seqno: AtomicU32::new(0),
             will_validate: AtomicBool::new(false),
+            validator_adnl_unreachable: AtomicBool::new(false),
             test_bundles_config,
-            shard_states_cache: TimeBasedCache::new(120, "shard_states_cache".to_string()),
-            loaded_from_ss_cache: AtomicU64::new(0),
-            loaded_ss_total: AtomicU64::new(0),
+            collator_settings: std::sync::RwLock::new(collator_settings),
+            shard_states_cache: Arc::new(StatesCache::new(states_cache_size_bytes)),
             workchain_id,
             state_gc_resolver,
-            validation_status: lockfree::map::Map::new(),
-            collation_status: lockfree::map::Map::new(),
+            db_compactor,
+            db_backup,
+            db_scrubber,
+            state_downloads: StateDownloads::default(),
+            bandwidth_limits,
+            tracked_shards,
+            shard_lags,
+            supervisor,
+            broadcast_dedup,
+            download_hedge_delay,
+            relay_untracked_broadcasts,
+            max_serve_queries,
+            sync_progress: SyncProgress::default(),
+            key_block_prefetcher: KeyBlockPrefetcher::default(),
+            apply_stages: ApplyStages::default(),
+            validation_status: ShardTimings::new("validation"),
+            collation_status: ShardTimings::new("collation"),
             validated_block_stats_sender,
             validated_block_stats_receiver,
             #[cfg(feature = "telemetry")]
@@ -386,37 +686,196 @@ impl Engine {
         });
 
         save_top_shard_blocks_worker(engine.clone(), shard_blocks_receiver);
+        // Node in read-only mode only verifies proofs, corrupted ones can't be replaced
+        let scrubber_engine = if read_only {
+            None
+        } else {
+            Some(engine.clone() as Arc<dyn EngineOperations>)
+        };
+        engine.db_scrubber.clone().start(db_scrubber_config, scrubber_engine);
 
         Ok(engine)
     }
 
     pub fn db(&self) -> &Arc<dyn InternalDb> { &self.db }
 
+    pub fn db_compactor(&self) -> &DbCompactor { &self.db_compactor }
+
+    pub fn db_backup(&self) -> &DbBackup { &self.db_backup }
+
+    pub fn db_scrubber(&self) -> &DbScrubber { &self.db_scrubber }
+
+    pub fn state_downloads(&self) -> &StateDownloads { &self.state_downloads }
+
+    pub fn bandwidth_limits(&self) -> &BandwidthLimits { &self.bandwidth_limits }
+
+    pub fn tracked_shards(&self) -> &TrackedShards { &self.tracked_shards }
+
+    pub fn shard_lags(&self) -> &ShardLags { &self.shard_lags }
+    pub fn supervisor(&self) -> &Arc<Supervisor> { &self.supervisor }
+    pub fn broadcast_dedup(&self) -> &BroadcastDedup { &self.broadcast_dedup }
+    pub fn relay_untracked_broadcasts(&self) -> bool { self.relay_untracked_broadcasts }
+    pub fn max_serve_queries(&self) -> usize { self.max_serve_queries }
+
+    pub fn sync_progress(&self) -> &SyncProgress { &self.sync_progress }
+
+    pub fn key_block_prefetcher(&self) -> &KeyBlockPrefetcher { &self.key_block_prefetcher }
+
+    pub fn apply_stages(&self) -> &ApplyStages { &self.apply_stages }
+
+    pub fn state_gc_resolver(&self) -> &AllowStateGcSmartResolver { &self.state_gc_resolver }
+
     pub fn validator_network(&self) -> Arc<dyn PrivateOverlayOperations> { self.network.clone() }
 
     pub fn network(&self) -> &NodeNetwork { &self.network }
 
     pub fn ext_db(&self) -> &Vec<Arc<dyn ExternalDb>> { &self.ext_db }
 
+    pub fn ext_db_ack(&self) -> &ExternalDbAck { &self.ext_db_ack }
+
+    pub fn external_db_health(&self) -> String {
+        self.ext_db_health.lock().unwrap().clone()
+    }
+
+    pub async fn check_external_db_health(&self) -> Result<()> {
+        let timeout = Duration::from_millis(self.ext_db_healthcheck.healthcheck_timeout_ms);
+        let mut result = Ok(());
+        for db in self.ext_db() {
+            result = db.healthcheck(timeout).await;
+            if result.is_err() {
+                break
+            }
+        }
+        let health = match &result {
+            Ok(_) => format!("healthy, checked at {}", self.now()),
+            Err(e) => format!("unhealthy, checked at {}: {}", self.now(), e)
+        };
+        STATSD.gauge("external_db_healthy", if result.is_ok() { 1.0 } else { 0.0 });
+        *self.ext_db_health.lock().unwrap() = health;
+        result
+    }
+
+    // Front workchains the node doesn't process are an error in strict mode, otherwise a warning
+    fn check_front_workchains(
+        &self,
+        front_workchain_ids: Option<&[i32]>,
+        workchain_id: i32
+    ) -> Result<()> {
+        let unprocessed = unprocessed_front_workchains(front_workchain_ids, workchain_id);
+        if !unprocessed.is_empty() {
+            if self.front_workchains_strict {
+                fail!(
+                    "front workchains {:?} are not processed, node processes masterchain and \
+                    workchain {}", unprocessed, workchain_id
+                )
+            }
+            log::warn!(
+                "Nothing will be written into external db for front workchains {:?}, \
+                node processes masterchain and workchain {}", unprocessed, workchain_id
+            );
+        }
+        Ok(())
+    }
+
+    pub fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>) -> Result<()> {
+        // Processed workchain is not known before boot, it is checked on start then
+        let workchain_id = match self.workchain_id.load(Ordering::Relaxed) {
+            ton_block::INVALID_WORKCHAIN_ID => None,
+            // Masterchain node processes base workchain as well
+            ton_block::MASTERCHAIN_ID => Some(ton_block::BASE_WORKCHAIN_ID),
+            workchain_id => Some(workchain_id)
+        };
+        if let Some(workchain_id) = workchain_id {
+            self.check_front_workchains(front_workchain_ids.as_deref(), workchain_id)?
+        }
+        for db in self.ext_db() {
+            db.set_front_workchain_ids(front_workchain_ids.clone());
+        }
+        *self.front_workchain_ids.write().unwrap() = front_workchain_ids;
+        Ok(())
+    }
+
+    pub fn set_validator_runtime_workers(&self, workers: usize) {
+        self.validator_runtime_workers.store(workers, Ordering::Relaxed)
+    }
+
+    pub fn runtime_status(&self) -> String {
+        format!(
+            "node workers: {}, validator groups workers: {}",
+            self.runtime_workers,
+            self.validator_runtime_workers.load(Ordering::Relaxed)
+        )
+    }
+
+    pub fn config_reloader(&self) -> &Arc<ConfigReloader> {
+        &self.config_reloader
+    }
+
+    // Re-reads global config and brings DHT static nodes in line with it
+    pub fn reload_global_config(&self) -> Result<DhtStaticNodesUpdate> {
+        let global_config = TonNodeGlobalConfig::from_json_file(&self.global_config_path)?;
+        // Engine's zero state is the one of internal DB, it is checked at start
+        let zero_state_id = global_config.zero_state()?;
+        if zero_state_id != self.zero_state_id {
+            fail!(
+                "Global config {} is for network with zerostate {}, but internal DB has \
+                zerostate {}", self.global_config_path, zero_state_id, self.zero_state_id
+            )
+        }
+        self.network.update_dht_static_nodes(&global_config.dht_nodes()?)
+    }
+
+    pub fn resource_profile(&self) -> &str {
+        &self.resource_profile
+    }
+
+    pub fn persistent_states_status(&self) -> String {
+        self.persistent_states_status.lock().unwrap().clone()
+    }
+
+    pub fn archives_sync_config(&self) -> &ArchivesSyncConfig {
+        &self.archives_sync
+    }
+
+    pub fn archive_node_config(&self) -> &ArchiveNodeConfig {
+        &self.archive_node
+    }
+
+    fn set_persistent_states_status(&self, status: String) {
+        *self.persistent_states_status.lock().unwrap() = status;
+    }
+
+    pub fn validation_history_status(&self) -> String {
+        self.validation_history_status.lock().unwrap().clone()
+    }
+
+    pub fn set_validation_history_status(&self, status: String) {
+        *self.validation_history_status.lock().unwrap() = status;
+    }
+
     pub fn zero_state_id(&self) -> &BlockIdExt { &self.zero_state_id }
 
     pub fn init_mc_block_id(&self) -> &BlockIdExt {&self.init_mc_block_id}
 
     pub fn initial_sync_disabled(&self) -> bool {self.initial_sync_disabled}
 
-    pub fn shard_states_cache(&self) -> &TimeBasedCache<BlockIdExt, ShardStateStuff> {
+    pub fn hardforks(&self) -> &[BlockIdExt] { &self.hardforks }
+
+    pub fn shard_states_cache(&self) -> &Arc<StatesCache> {
         &self.shard_states_cache
     }
 
-    pub fn update_shard_states_cache_stat(&self, loaded_from_cache: bool) {
-        let loaded_ss_total = self.loaded_ss_total.fetch_add(1, Ordering::Relaxed) + 1;
-        let loaded_from_ss_cache = if loaded_from_cache {
-            self.loaded_from_ss_cache.fetch_add(1, Ordering::Relaxed) + 1
+    pub fn shard_states_cache_stats(&self) -> StatesCacheStats {
+        self.shard_states_cache.stats()
+    }
+
+    pub async fn load_state_cached(&self, block_id: &BlockIdExt) -> Result<Arc<ShardStateStuff>> {
+        if let Some(state) = self.shard_states_cache.get(block_id) {
+            Ok(state)
         } else {
-            self.loaded_from_ss_cache.load(Ordering::Relaxed)
-        };
-        log::trace!("shard_states_cache  total loaded: {}  from cache: {}  use cache: {}%",
-            loaded_ss_total, loaded_from_ss_cache, (100 * loaded_from_ss_cache) / loaded_ss_total);
+            let state = self.db().load_shard_state_dynamic(block_id)?;
+            Ok(self.shard_states_cache.insert(Arc::new(state)))
+        }
     }
 
 /*
@@ -466,10 +925,22 @@ impl Engine {
         self.will_validate.load(Ordering::SeqCst)
     }
 
+    pub fn set_validator_adnl_unreachable(&self, unreachable: bool) {
+        self.validator_adnl_unreachable.store(unreachable, Ordering::Relaxed);
+    }
+
+    pub fn validator_adnl_unreachable(&self) -> bool {
+        self.validator_adnl_unreachable.load(Ordering::Relaxed)
+    }
+
     pub fn update_last_known_mc_block_seqno(&self, seqno: u32) -> bool {
         self.last_known_mc_block_seqno.fetch_max(seqno, Ordering::SeqCst) < seqno
     }
 
+    pub fn last_known_mc_block_seqno(&self) -> u32 {
+        self.last_known_mc_block_seqno.load(Ordering::Relaxed)
+    }
+
     pub fn update_last_known_keyblock_seqno(&self, seqno: u32) -> bool {
         self.last_known_keyblock_seqno.fetch_max(seqno, Ordering::SeqCst) < seqno
     }
@@ -478,6 +949,14 @@ impl Engine {
         &self.test_bundles_config
     }
 
+    pub fn collator_settings(&self) -> CollatorSettings {
+        self.collator_settings.read().unwrap().clone()
+    }
+
+    pub fn set_collator_settings(&self, settings: CollatorSettings) {
+        *self.collator_settings.write().unwrap() = settings;
+    }
+
     #[cfg(feature = "telemetry")]
     pub fn full_node_telemetry(&self) -> &FullNodeTelemetry {
         &self.full_node_telemetry
@@ -498,11 +977,11 @@ impl Engine {
         &self.full_node_service_telemetry
     }
 
-    pub fn validation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    pub fn validation_status(&self) -> &ShardTimings {
         &self.validation_status
     }
 
-    pub fn collation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    pub fn collation_status(&self) -> &ShardTimings {
         &self.collation_status
     }
 
@@ -569,14 +1048,27 @@ impl Engine {
                 self.download_block_worker(id, attempts, timeout)
             ).await? {
 
-                if self.load_block_handle(id)?.is_some() {
+                // Handle without data may be created by prefetched proof of key block
+                let handle = self.load_block_handle(id)?;
+                if handle.as_ref().map(|handle| handle.has_data()).unwrap_or(false) {
                     continue;
                 }
+                let prefetched = handle.map(|handle| handle.has_proof()).unwrap_or(false);
 
                 let downloading_time = now.elapsed().as_millis();
 
                 let now = std::time::Instant::now();
-                proof.check_proof(self.deref()).await?;
+                let check_started = SystemTime::now();
+                let key_block = proof.virtualize_block()?.0.read_info()?.key_block();
+                // Prefetched proof is checked already
+                if !prefetched {
+                    proof.check_proof(self.deref()).await?;
+                    self.apply_stages.report(id.shard(), ApplyStage::ProofCheck, now.elapsed());
+                    jaeger::block_stage(id, key_block, "proof checked", check_started);
+                }
+                if id.shard().is_masterchain() && key_block {
+                    self.key_block_prefetcher.report_fetch(prefetched);
+                }
                 let handle = self.store_block(&block).await?;
                 let handle = if let Some(handle) = handle.as_non_created() {
                     handle
@@ -636,9 +1128,44 @@ impl Engine {
                 self.save_last_applied_mc_block_id(block.id())?;
                 STATSD.gauge("last_applied_mc_block", block.id().seq_no() as f64);
                 STATSD.gauge("timediff", ago as f64);
-                self.shard_blocks().update_shard_blocks(&self.load_state(block.id()).await?)?;
+                let cache_stats = self.db().cells_cache_stats();
+                STATSD.gauge("cells_cache_hits", cache_stats.hits as f64);
+                STATSD.gauge("cells_cache_misses", cache_stats.misses as f64);
+                STATSD.gauge("cells_cache_evictions", cache_stats.evictions as f64);
+                STATSD.gauge("cells_cache_bytes", cache_stats.used_bytes as f64);
+                let states_stats = self.shard_states_cache_stats();
+                STATSD.gauge("states_cache_bytes", states_stats.used_bytes as f64);
+                STATSD.gauge("states_cache_entries", states_stats.entries as f64);
+                STATSD.gauge("states_cache_pinned", states_stats.pinned as f64);
+                STATSD.gauge("states_cache_evictions", states_stats.evictions as f64);
+                let handles_stats = self.db().block_handles_cache_stats();
+                let requests = handles_stats.hits + handles_stats.misses;
+                if requests > 0 {
+                    STATSD.gauge(
+                        "block_handles_cache_hit_rate",
+                        handles_stats.hits as f64 * 100.0 / requests as f64
+                    );
+                }
+                STATSD.gauge("block_handles_cache_size", handles_stats.size as f64);
+                STATSD.gauge("block_handles_cache_evictions", handles_stats.evictions as f64);
+                let revoked = self.shard_blocks().update_shard_blocks(&self.load_state(block.id()).await?)?;
+                for revoked in revoked {
+                    let reason = match self.check_block_revoked(&revoked) {
+                        Some(reason) => reason,
+                        None => continue
+                    };
+                    log::warn!("Shard block {} is revoked: {}", revoked.id, reason);
+                    if let Err(e) = self.process_block_revoked_in_ext_db(&revoked.id, &reason).await {
+                        log::error!("Can't write revoked block {} into external db: {}", revoked.id, e);
+                    }
+                }
+                self.process_applied_ext_messages(block);
+                self.index_block_transactions(handle, block)?;
 
-                if self.set_applied(handle, mc_seq_no).await? {
+                let now = std::time::Instant::now();
+                let applied = self.set_applied(handle, mc_seq_no).await?;
+                self.apply_stages.report(block.id().shard(), ApplyStage::HandleUpdate, now.elapsed());
+                if applied {
                     #[cfg(feature = "telemetry")]
                     self.full_node_telemetry().submit_transactions(gen_utime as u64, block.calculate_tr_count()?);
                 }
@@ -677,7 +1204,7 @@ impl Engine {
                     last_rotation_block_id_str,
                     min_id.seq_no()
                 );
-                self.state_gc_resolver.advance(min_id, self.deref()).await?;
+                self.state_gc_resolver.advance(min_id, &pss_keeper, self.deref()).await?;
             }
 
             log::info!(
@@ -688,7 +1215,12 @@ impl Engine {
             );
         } else {
             if !pre_apply {
-                if self.set_applied(handle, mc_seq_no).await? {
+                self.process_applied_ext_messages(block);
+                self.index_block_transactions(handle, block)?;
+                let now = std::time::Instant::now();
+                let applied = self.set_applied(handle, mc_seq_no).await?;
+                self.apply_stages.report(block.id().shard(), ApplyStage::HandleUpdate, now.elapsed());
+                if applied {
                     #[cfg(feature = "telemetry")]
                     self.full_node_telemetry().submit_transactions(gen_utime as u64, block.calculate_tr_count()?);
                 }
@@ -714,31 +1246,89 @@ impl Engine {
             loop {
                 match client.wait_broadcast().await {
                     Err(e) => log::error!("Error while wait_broadcast for shard {}: {}", shard_ident, e),
-                    Ok((brodcast, src)) => {
-                        match brodcast {
-                            Broadcast::TonNode_BlockBroadcast(broadcast) => {
-                                self.clone().process_block_broadcast(broadcast, src);
-                            }
-                            Broadcast::TonNode_ExternalMessageBroadcast(broadcast) => {
-                                self.process_ext_msg_broadcast(broadcast, src);
-                            }
-                            Broadcast::TonNode_IhrMessageBroadcast(broadcast) => {
-                                log::trace!("TonNode_IhrMessageBroadcast from {}: {:?}", src, broadcast);
-                            }
-                            Broadcast::TonNode_NewShardBlockBroadcast(broadcast) => {
-                                self.clone().process_new_shard_block_broadcast(broadcast, src);
-                            }
-                            Broadcast::TonNode_ConnectivityCheckBroadcast(broadcast) => {
-                                self.network.clone().process_connectivity_broadcast(broadcast);
-                            }
-                        }
-                    }
+                    Ok((brodcast, src)) => self.process_broadcast(brodcast, src)
                 }
             }
         });
         Ok(())
     }
 
+    // Duplicates are dropped before any deserialization
+    fn process_broadcast(self: &Arc<Self>, broadcast: Broadcast, src: Arc<KeyId>) {
+        match broadcast {
+            Broadcast::TonNode_BlockBroadcast(broadcast) => {
+                if self.is_new_block_broadcast(&broadcast.id, false) {
+                    self.clone().process_block_broadcast(broadcast, src);
+                }
+            }
+            Broadcast::TonNode_ExternalMessageBroadcast(broadcast) => {
+                if self.broadcast_dedup.check_ext_message(&broadcast.message.data.0) {
+                    self.process_ext_msg_broadcast(broadcast, src);
+                }
+            }
+            Broadcast::TonNode_IhrMessageBroadcast(broadcast) => {
+                log::trace!("TonNode_IhrMessageBroadcast from {}: {:?}", src, broadcast);
+            }
+            Broadcast::TonNode_NewShardBlockBroadcast(broadcast) => {
+                if self.is_new_block_broadcast(&broadcast.block.block, true) {
+                    self.clone().process_new_shard_block_broadcast(broadcast, src);
+                }
+            }
+            Broadcast::TonNode_ConnectivityCheckBroadcast(broadcast) => {
+                self.network.clone().process_connectivity_broadcast(broadcast);
+            }
+        }
+    }
+
+    pub fn start_validators_overlay(self: Arc<Self>, validator_list_id: &UInt256) -> Result<()> {
+        let consumer = Arc::new(FullNodeOverlayService::new(self.clone() as Arc<dyn EngineOperations>));
+        let overlay = match self.network.start_validators_overlay(validator_list_id, consumer)? {
+            Some(overlay) => overlay,
+            None => return Ok(())
+        };
+        tokio::spawn(async move {
+            loop {
+                match overlay.wait_broadcast().await {
+                    Err(e) => log::error!(
+                        "Error while wait_broadcast in validators overlay of list {:x}: {}",
+                        overlay.validator_list_id(), e
+                    ),
+                    Ok(Some((broadcast, src))) => self.process_broadcast(broadcast, src),
+                    Ok(None) => break
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn stop_validators_overlay(&self, validator_list_id: &UInt256) {
+        self.network.stop_validators_overlay(validator_list_id);
+    }
+
+    // Validators get own blocks and top shard block descriptions without public overlay hops
+    pub async fn mirror_to_validators_overlays(&self, broadcast: Broadcast) {
+        if !self.will_validate() {
+            return
+        }
+        for overlay in self.network.validators_overlays() {
+            if let Err(e) = overlay.send_broadcast(broadcast.clone()).await {
+                log::warn!(
+                    "Can't mirror broadcast into validators overlay of list {:x}: {}",
+                    overlay.validator_list_id(), e
+                );
+            }
+        }
+    }
+
+    // Broadcast with malformed id is not checked here, it fails while processing
+    fn is_new_block_broadcast(&self, id: &BlockIdExtApi, shard_block: bool) -> bool {
+        match convert_block_id_ext_api2blk(id) {
+            Ok(id) if shard_block => self.broadcast_dedup.check_shard_block(&id),
+            Ok(id) => self.broadcast_dedup.check_block(&id),
+            Err(_) => true
+        }
+    }
+
     pub fn validated_block_stats_sender(&self) -> &Sender<ValidatedBlockStat> { &self.validated_block_stats_sender }
     pub fn validated_block_stats_receiver(&self) -> &Receiver<ValidatedBlockStat> { &self.validated_block_stats_receiver }
 
@@ -807,6 +1397,10 @@ impl Engine {
         tokio::spawn(async move {
             if let Err(e) = process_block_broadcast(&engine, &broadcast).await {
                 log::error!("Error while processing block broadcast {} from {}: {}", broadcast.id, src, e);
+                // Copies from other neighbours may be correct
+                if let Ok(block_id) = convert_block_id_ext_api2blk(&broadcast.id) {
+                    self.broadcast_dedup.forget_block(&block_id);
+                }
             } else {
                 log::trace!("Processed block broadcast {} from {}", broadcast.id, src);
 
@@ -836,7 +1430,7 @@ impl Engine {
                 broadcast.message.data.0.len(), src);
         } else {
             log::trace!("Processing ext message broadcast {}bytes from {}", broadcast.message.data.0.len(), src);
-            match self.external_messages().new_message_raw(&broadcast.message.data.0, self.now()) {
+            match self.external_messages().new_message_raw(&broadcast.message.data.0, Some(src.clone()), self.now()) {
                 Err(e) => log::debug!("Error while processing ext message broadcast {}bytes from {}: {}",
                     broadcast.message.data.0.len(), src, e),
                 Ok(id) => log::trace!("Processed ext message broadcast {:x} {}bytes from {}",
@@ -845,6 +1439,82 @@ impl Engine {
         }
     }
 
+    // Drains the node when shutdown is requested. Block clients and validator manager
+    // stop taking new work themselves, the rest is done here stage by stage.
+    async fn shutdown(self: Arc<Self>) {
+        SHUTDOWN.wait().await;
+        let started = Instant::now();
+        SHUTDOWN.run_stage("stop validator manager", async {
+            SHUTDOWN.wait_components().await;
+            Ok(())
+        }).await;
+        SHUTDOWN.run_stage("flush external db", async {
+            let results = futures::future::join_all(self.ext_db().iter().map(|db| db.flush())).await;
+            results.into_iter().collect::<Result<Vec<_>>>().map(|_| ())
+        }).await;
+        SHUTDOWN.run_stage("flush internal db", self.db().flush()).await;
+        SHUTDOWN.run_stage("stop network", async {
+            self.network().stop().await;
+            Ok(())
+        }).await;
+        log::info!("Shutdown finished in {} ms", started.elapsed().as_millis());
+        SHUTDOWN.finish();
+    }
+
+    fn start_ext_messages_gc(engine: Arc<Engine>) {
+        let supervisor = Arc::clone(engine.supervisor());
+        supervisor.spawn("ext messages gc", move || {
+            Box::pin(Self::ext_messages_gc(Arc::clone(&engine)))
+        });
+    }
+
+    async fn ext_messages_gc(engine: Arc<Engine>) -> Result<()> {
+        let interval = Duration::from_secs(engine.external_messages().config().gc_interval_sec);
+        loop {
+            tokio::time::sleep(interval).await;
+            let purged = engine.external_messages().purge_expired(engine.now());
+            if purged > 0 {
+                log::debug!("Purged {} expired external messages", purged);
+                STATSD.count("ext_msg_expired", purged as f64);
+            }
+            STATSD.gauge("ext_msg_pool_bytes", engine.external_messages().stats().bytes as f64);
+        }
+    }
+
+    fn start_ext_messages_rebroadcast(engine: Arc<Engine>) {
+        const CHECK_INTERVAL_MS: u64 = 1000;
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_millis(CHECK_INTERVAL_MS)).await;
+                for (id, dst, data) in engine.external_messages().local_messages_to_rebroadcast(engine.now()) {
+                    match engine.broadcast_to_public_overlay(&dst, &data).await {
+                        Ok(_) => log::debug!("External message {:x} is re-broadcast", id),
+                        Err(e) => log::warn!("Error while re-broadcasting external message {:x}: {}", id, e)
+                    }
+                }
+            }
+        });
+    }
+
+    fn process_applied_ext_messages(&self, block: &BlockStuff) {
+        match block.ext_in_messages() {
+            Ok(messages) => {
+                self.external_messages().messages_committed(block.id(), &messages, self.now());
+            }
+            Err(e) => log::warn!("Can't read inbound external messages of block {}: {}", block.id(), e)
+        }
+    }
+
+    // Index is written before block is marked as applied, so it is rewritten if the node
+    // stops in between
+    fn index_block_transactions(&self, handle: &Arc<BlockHandle>, block: &BlockStuff) -> Result<()> {
+        if self.db().transactions_index_enabled() && !handle.is_applied() {
+            let count = self.db().index_block_transactions(block)?;
+            STATSD.count("indexed_transactions", count as f64);
+        }
+        Ok(())
+    }
+
     fn process_new_shard_block_broadcast(self: Arc<Self>, broadcast: Box<NewShardBlockBroadcast>, src: Arc<KeyId>) {
         let id = broadcast.block.block.clone();
         if self.is_validator() {
@@ -854,6 +1524,9 @@ impl Engine {
                     match self.clone().process_new_shard_block(broadcast).await {
                         Err(e) => {
                             log::error!("Error while processing new shard block broadcast {} from {}: {}", id, src, e);
+                            if let Ok(block_id) = convert_block_id_ext_api2blk(&id) {
+                                self.broadcast_dedup.forget_shard_block(&block_id);
+                            }
                             #[cfg(feature = "telemetry")]
                             self.full_node_telemetry().bad_top_block_broadcast();
                         }
@@ -932,7 +1605,7 @@ impl Engine {
             tokio::spawn(async move {
                 let mut result = true;
                 // wait for state only for processed workchains
-                if workchain_id == id.shard().workchain_id() {
+                if workchain_id == id.shard().workchain_id() && self.is_shard_tracked(id.shard()) {
                     if let Err(e) = self.clone().wait_state(&id, Some(10_000), false).await {
                         log::error!("Error in wait_state after top-block-broadcast false {}: {}", id, e);
                         if let Err(e) = self.clone().wait_state(&id, Some(10_000), true).await {
@@ -963,17 +1636,24 @@ impl Engine {
          timeout: Option<(u64, u64, u64)>
     ) -> Result<DownloadContext<'a, T>> {
         let ret = DownloadContext {
+            active_peers: Arc::new(lockfree::set::Set::new()),
             client: self.get_full_node_overlay(
                 id.shard().workchain_id(),
                 id.shard().shard_prefix_with_tag()
             ).await?,
             db: self.db.deref(),
             downloader,
+            hedge_delay: self.download_hedge_delay,
             id,
             limit,
             log_error_limit,
             name,
             timeout,
+            validators_overlays: if self.will_validate() {
+                self.network.validators_overlays()
+            } else {
+                Vec::new()
+            },
             #[cfg(feature = "telemetry")]
             full_node_telemetry: self.full_node_telemetry(),
         };
@@ -1036,6 +1716,103 @@ impl Engine {
         ).await?.download().await
     }
 
+    // Block which masterchain went beyond is revoked only if another block with its seqno
+    // is committed; if committed chain is unknown locally, the block is not reported.
+    fn check_block_revoked(&self, revoked: &RevokedBlock) -> Option<String> {
+        if revoked.confirmed {
+            return Some(revoked.reason.clone())
+        }
+        let shard = revoked.id.shard();
+        let acc_pfx = AccountIdPrefixFull {
+            workchain_id: shard.workchain_id(),
+            prefix: shard.shard_prefix_with_tag()
+        };
+        match self.db.find_block_by_seq_no(&acc_pfx, revoked.id.seq_no()) {
+            Ok(handle) if handle.id().shard() == shard && handle.id().seq_no() == revoked.id.seq_no() => {
+                if handle.id() != &revoked.id {
+                    Some(format!("superseded by {}", handle.id()))
+                } else {
+                    None
+                }
+            }
+            _ => None
+        }
+    }
+
+    // Local DB is looked up first, ids answered by neighbours are used only if their proofs
+    // link them to our chain. Returns None if neither DB nor neighbours have the block.
+    pub async fn find_block_by_seqno(
+        &self,
+        shard: &ShardIdent,
+        seqno: u32
+    ) -> Result<Option<BlockIdExt>> {
+        let acc_pfx = AccountIdPrefixFull {
+            workchain_id: shard.workchain_id(),
+            prefix: shard.shard_prefix_with_tag()
+        };
+        if let Ok(handle) = self.db.find_block_by_seq_no(&acc_pfx, seqno) {
+            if (handle.id().shard() == shard) && (handle.id().seq_no() == seqno) {
+                return Ok(Some(handle.id().clone()))
+            }
+        }
+        let client = self.get_full_node_overlay(
+            shard.workchain_id(),
+            shard.shard_prefix_with_tag()
+        ).await?;
+        let answers = client.lookup_block_by_seqno(shard, seqno).await?;
+        if answers.is_empty() {
+            log::info!("Block {}:{} is not found by neighbours", shard, seqno);
+            return Ok(None)
+        }
+        let mut verified: Vec<BlockIdExt> = Vec::new();
+        for (id, peer) in answers {
+            if verified.contains(&id) {
+                continue
+            }
+            let result = if (id.shard() != shard) || (id.seq_no() != seqno) {
+                Err(error!("block {} is not asked one", id))
+            } else {
+                self.check_looked_up_block(&id).await
+            };
+            match result {
+                Ok(()) => verified.push(id),
+                Err(e) => {
+                    log::warn!("Block {}:{} answered by {} is rejected: {}", shard, seqno, peer, e);
+                    client.report_peer(&peer, PeerOutcome::BadData);
+                }
+            }
+        }
+        match verified.len() {
+            0 => fail!("No block {}:{} answered by neighbours is linked to our chain", shard, seqno),
+            1 => Ok(verified.pop()),
+            _ => fail!(
+                "Ambiguous lookup of block {}:{}: {}",
+                shard, seqno, verified.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+            )
+        }
+    }
+
+    // Masterchain block proof is checked by validator signatures, shard block proof link
+    // has to refer a masterchain block known to us
+    async fn check_looked_up_block(&self, id: &BlockIdExt) -> Result<()> {
+        if id.shard().is_masterchain() {
+            let proof = self.download_block_proof_worker(id, false, false, Some(3)).await?;
+            return proof.check_proof(self).await
+        }
+        let proof = self.download_block_proof_worker(id, true, false, Some(3)).await?;
+        proof.check_proof_link()?;
+        let (virt_block, _) = proof.virtualize_block()?;
+        let master = virt_block.read_info()?.read_master_ref()?
+            .ok_or_else(|| error!("Shard block {} has no master ref", id))?
+            .master;
+        let handle = self.db.find_block_by_seq_no(&AccountIdPrefixFull::any_masterchain(), master.seq_no)
+            .map_err(|e| error!("Can't check master ref {} of block {}: {}", master.seq_no, id, e))?;
+        if (handle.id().root_hash != master.root_hash) || (handle.id().file_hash != master.file_hash) {
+            fail!("Master ref of block {} is not our masterchain block {}", id, handle.id())
+        }
+        Ok(())
+    }
+
     pub async fn download_zerostate_worker(
         &self,
         id: &BlockIdExt,
@@ -1123,17 +1900,25 @@ impl Engine {
         self.db().save_node_state(PSS_KEEPER_MC_BLOCK, id)
     }
 
-    pub fn start_persistent_states_keeper(
-        engine: Arc<Engine>,
-        pss_keeper_block: BlockIdExt
-    ) -> Result<tokio::task::JoinHandle<()>> {
+    pub fn start_persistent_states_keeper(engine: Arc<Engine>) {
         log::info!("start_persistent_states_keeper");
-        let join_handle = tokio::spawn(async move {
-            if let Err(e) = Self::persistent_states_keeper(engine, pss_keeper_block).await {
-                log::error!("FATAL!!! Unexpected error in persistent states keeper: {:?}", e);
-            }
+        let supervisor = Arc::clone(engine.supervisor());
+        supervisor.spawn("persistent states keeper", move || {
+            let engine = Arc::clone(&engine);
+            Box::pin(async move {
+                // Restarted keeper continues from the block it has saved
+                let pss_keeper_block = engine.load_pss_keeper_mc_block_id()?.ok_or_else(
+                    || error!("INTERNAL ERROR: No PSS keeper MC block")
+                )?;
+                let result = Self::persistent_states_keeper(
+                    Arc::clone(&engine), pss_keeper_block.deref().clone()
+                ).await;
+                if let Err(e) = &result {
+                    log::error!("FATAL!!! Unexpected error in persistent states keeper: {:?}", e);
+                }
+                result
+            })
         });
-        Ok(join_handle)
     }
 
     pub async fn persistent_states_keeper(
@@ -1171,6 +1956,8 @@ impl Engine {
                 };
                 if !is_persistent_state {
                     log::trace!("persistent_states_keeper: skip keyblock (is not persistent) {}", handle.id());
+                } else if !engine.persistent_states.generate {
+                    log::trace!("persistent_states_keeper: skip keyblock (generation is disabled) {}", handle.id());
                 } else {
                     log::trace!("persistent_states_keeper: saving {}", handle.id());
                     let now = std::time::Instant::now();
@@ -1181,7 +1968,8 @@ impl Engine {
                     let mut shard_blocks = vec!();
                     let processed_workchain = engine.processed_workchain().await?.1;
                     mc_state.shards()?.iterate_shards(|ident, descr| {
-                        if ident.is_masterchain() || ident.workchain_id() == processed_workchain {
+                        let processed = ident.is_masterchain() || ident.workchain_id() == processed_workchain;
+                        if processed && engine.is_shard_tracked(&ident) {
                             shard_blocks.push(BlockIdExt {
                                 shard_id: ident,
                                 seq_no: descr.seq_no,
@@ -1204,6 +1992,16 @@ impl Engine {
                         log::trace!("persistent_states_keeper: saved {} TIME {}ms",
                             handle.id(), now.elapsed().as_millis());
                     };
+                    engine.set_persistent_states_status(format!(
+                        "generated states of key block {} in {}ms, finished at {}",
+                        handle.id().seq_no(), now.elapsed().as_millis(), engine.now()
+                    ));
+                    STATSD.gauge("persistent_state_key_block", handle.id().seq_no() as f64);
+                    if let Some(keep_states) = engine.persistent_states.keep_states {
+                        if let Err(e) = Self::remove_old_persistent_states(&engine, &handle, &mc_state, keep_states).await {
+                            log::warn!("Error while removing old persistent states: {:?}", e);
+                        }
+                    }
                 }
                 if let Err(e) = Self::check_gc_for_archives(&engine, &handle, &mc_state).await {
                     log::warn!("Error (archive_manager gc): {:?}", e);
@@ -1224,6 +2022,9 @@ impl Engine {
         let mut handle = curr_block_handle.clone();
         let mut check_date = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
 
+        if engine.blocks_retention.enabled() {
+            return Ok(()) // archives are removed by blocks pruning
+        }
         match &engine.archives_life_time {
             None => return Ok(()),
             Some(life_time) => {
@@ -1282,7 +2083,8 @@ impl Engine {
 
             if let Some(gc_marked_block) = &prev_prev_pss_block {
                 log::info!("start gc for archives..");
-                engine.db.archive_manager().gc(&gc_marked_block.id()).await;
+                let (removed_bytes, _) = engine.db.archive_manager().gc(&gc_marked_block.id()).await;
+                STATSD.count("pruned_archive_bytes", removed_bytes as f64);
                 log::info!("finish gc for archives.");
                 break;
             }
@@ -1290,9 +2092,194 @@ impl Engine {
         Ok(())
     }
 
+    pub fn start_blocks_pruning(engine: Arc<Engine>) {
+        if !engine.blocks_retention.enabled() {
+            return
+        }
+        let supervisor = Arc::clone(engine.supervisor());
+        supervisor.spawn("blocks pruning", move || {
+            let engine = Arc::clone(&engine);
+            Box::pin(async move {
+                let interval = engine.blocks_retention.pruning_interval_sec;
+                loop {
+                    tokio::time::sleep(Duration::from_secs(interval)).await;
+                    if let Err(e) = Self::prune_blocks(&engine).await {
+                        log::warn!("Error while pruning blocks: {:?}", e);
+                    }
+                }
+            })
+        });
+    }
+
+    async fn prune_blocks(engine: &Arc<Engine>) -> Result<()> {
+        // Only persistent states which are already saved are taken into account
+        let pss_keeper_block = match engine.load_pss_keeper_mc_block_id()? {
+            Some(id) => id,
+            None => return Ok(())
+        };
+        let mc_state = engine.load_state(&pss_keeper_block).await?;
+        let boundary = match Self::blocks_pruning_boundary(engine, &pss_keeper_block, &mc_state)? {
+            Some(handle) => handle,
+            None => {
+                log::trace!("blocks pruning: no blocks to prune");
+                return Ok(())
+            }
+        };
+        log::info!("blocks pruning: pruning blocks before {}", boundary.id());
+        STATSD.gauge("blocks_pruning_boundary", boundary.id().seq_no() as f64);
+        let (removed_bytes, pruned_below) = engine.db.archive_manager().gc(boundary.id()).await;
+        STATSD.count("pruned_archive_bytes", removed_bytes as f64);
+        if pruned_below == 0 {
+            return Ok(())
+        }
+        // Masterchain blocks are pruned by time of the first kept one, shard ones by reference
+        let first_kept_id = match mc_state.shard_state_extra()?.prev_blocks.get(&pruned_below)? {
+            Some(block_ref) => block_ref.master_block_id().1,
+            None => fail!("blocks pruning: can't find masterchain block {}", pruned_below)
+        };
+        let first_kept = engine.load_block_handle(&first_kept_id)?.ok_or_else(
+            || error!("Cannot load handle for blocks pruning boundary {}", first_kept_id)
+        )?;
+        let gen_utime = first_kept.gen_utime()?;
+        let db = engine.db.clone();
+        let pruned = tokio::task::spawn_blocking(
+            move || db.prune_block_handles(pruned_below, gen_utime)
+        ).await??;
+        STATSD.count("pruned_block_handles", pruned as f64);
+        if engine.db.transactions_index_enabled() {
+            let db = engine.db.clone();
+            let pruned_transactions = tokio::task::spawn_blocking(
+                move || db.prune_transactions_index(gen_utime)
+            ).await??;
+            STATSD.count("pruned_indexed_transactions", pruned_transactions as f64);
+        }
+        log::info!(
+            "blocks pruning: removed {} bytes of archives, pruned {} block handles before {}",
+            removed_bytes, pruned, first_kept_id
+        );
+        Ok(())
+    }
+
+    // Finds the newest persistent state key block satisfying all retention limits.
+    // The latest persistent state and the previous one are never pruned: shard blocks
+    // of the latest state, needed by nodes booting from it, may be committed before it.
+    fn blocks_pruning_boundary(
+        engine: &Arc<Engine>,
+        pss_keeper_block: &BlockIdExt,
+        mc_state: &ShardStateStuff
+    ) -> Result<Option<Arc<BlockHandle>>> {
+        let min_pss_count = engine.blocks_retention.keep_persistent_states.unwrap_or(0)
+            .max(MIN_KEPT_PERSISTENT_STATES);
+        let max_gen_utime = engine.blocks_retention.keep_days
+            .map(|days| engine.now().saturating_sub(days.saturating_mul(24 * 3600)));
+        let prev_blocks = &mc_state.shard_state_extra()?.prev_blocks;
+        let init_seq_no = engine.init_mc_block_id().seq_no();
+        find_pruning_boundary(
+            pss_keeper_block.seq_no(),
+            min_pss_count,
+            max_gen_utime,
+            |seq_no| {
+                let prev_key_block_id = match prev_blocks.get_prev_key_block(seq_no - 1)? {
+                    Some(id) => id,
+                    None => return Ok(None)
+                };
+                // Blocks before the init one are not in the database
+                if prev_key_block_id.seq_no < init_seq_no {
+                    return Ok(None)
+                }
+                let block_id = BlockIdExt {
+                    shard_id: ShardIdent::masterchain(),
+                    seq_no: prev_key_block_id.seq_no,
+                    root_hash: prev_key_block_id.root_hash,
+                    file_hash: prev_key_block_id.file_hash
+                };
+                let handle = engine.load_block_handle(&block_id)?.ok_or_else(
+                    || error!("Cannot load handle for blocks pruning key block {}", block_id)
+                )?;
+                let gen_utime = handle.gen_utime()?;
+                Ok(Some((handle, block_id.seq_no, gen_utime)))
+            },
+            |gen_utime, prev_gen_utime| {
+                engine.is_persistent_state(gen_utime, prev_gen_utime, boot::PSS_PERIOD_BITS)
+            }
+        )
+    }
+
+    // Removes persistent states older than `keep_states` last ones, starting from the newest
+    // generated one. Walking stops at the first persistent state key block without state.
+    async fn remove_old_persistent_states(
+        engine: &Arc<Engine>,
+        pss_block: &Arc<BlockHandle>,
+        mc_state: &ShardStateStuff,
+        keep_states: u32
+    ) -> Result<()> {
+        let prev_blocks = &mc_state.shard_state_extra()?.prev_blocks;
+        let mut pss_count = 0;
+        let mut newer_key_block = pss_block.clone();
+        let mut seq_no = pss_block.id().seq_no();
+        while seq_no > 0 {
+            let prev_key_block_id = match prev_blocks.get_prev_key_block(seq_no - 1)? {
+                Some(id) => id,
+                None => break
+            };
+            let block_id = BlockIdExt {
+                shard_id: ShardIdent::masterchain(),
+                seq_no: prev_key_block_id.seq_no,
+                root_hash: prev_key_block_id.root_hash,
+                file_hash: prev_key_block_id.file_hash
+            };
+            let prev_handle = engine.load_block_handle(&block_id)?.ok_or_else(
+                || error!("Cannot load handle for persistent states removing key block {}", block_id)
+            )?;
+            let gen_utime = newer_key_block.gen_utime()?;
+            if engine.is_persistent_state(gen_utime, prev_handle.gen_utime()?, boot::PSS_PERIOD_BITS) {
+                pss_count += 1;
+                if pss_count > keep_states {
+                    if !newer_key_block.has_persistent_state() {
+                        break
+                    }
+                    Self::remove_persistent_states_of(engine, &newer_key_block).await?;
+                }
+            }
+            seq_no = block_id.seq_no;
+            newer_key_block = prev_handle;
+        }
+        Ok(())
+    }
+
+    async fn remove_persistent_states_of(engine: &Arc<Engine>, key_block: &Arc<BlockHandle>) -> Result<()> {
+        let block = engine.load_block(key_block).await?;
+        for (_, block_id) in block.shards_blocks()? {
+            if let Some(handle) = engine.load_block_handle(&block_id)? {
+                engine.db.delete_shard_state_persistent(&handle).await?;
+            }
+        }
+        engine.db.delete_shard_state_persistent(key_block).await?;
+        STATSD.incr("persistent_states_removed");
+        log::info!("persistent states of key block {} are removed", key_block.id());
+        Ok(())
+    }
+
     pub async fn store_persistent_state_attempts(&self, handle: &Arc<BlockHandle>, ss: &ShardStateStuff) {
+        let bytes_per_sec = self.persistent_states.write_speed_limit();
+        let progress = |progress: SerializationProgress| {
+            let stage = match progress {
+                SerializationProgress::Traversal { cells, estimate } => {
+                    format!("traversal {} cells of ~{}", cells, estimate)
+                }
+                SerializationProgress::Writing { cells, total } => {
+                    let percent = if total > 0 { cells * 100 / total } else { 100 };
+                    STATSD.gauge("persistent_state_progress", percent as f64);
+                    format!("writing {}%", percent)
+                }
+            };
+            self.set_persistent_states_status(format!(
+                "generating state of {} (block {}): {}",
+                ss.block_id().shard(), ss.block_id().seq_no(), stage
+            ));
+        };
         let mut attempts = 1;
-        while let Err(e) = self.db.store_shard_state_persistent(handle, ss, None).await {
+        while let Err(e) = self.db.store_shard_state_persistent(handle, ss, bytes_per_sec, &progress, None).await {
             log::error!("CRITICAL Error saving persistent state (attempt: {}): {:?}", attempts, e);
             attempts += 1;
             futures_timer::Delay::new(Duration::from_millis(5000)).await;
@@ -1316,7 +2303,9 @@ pub(crate) async fn load_zero_state(engine: &Arc<Engine>, path: &str) -> Result<
         let path = format!("{}/{:x}.boc", path, zero_id.file_hash());
         let bytes = tokio::fs::read(&path).await
             .map_err(|err| error!("Cannot read mc zerostate {}: {}", path, err))?;
-        (ShardStateStuff::deserialize_zerostate(zero_id.clone(), &bytes)?, bytes)
+        let zs = ShardStateStuff::deserialize_zerostate(zero_id.clone(), &bytes)
+            .map_err(|err| error!("Zerostate {} doesn't match global config: {}", path, err))?;
+        (zs, bytes)
     };
 
     let workchains = mc_zero_state.workchains()?;
@@ -1336,7 +2325,8 @@ pub(crate) async fn load_zero_state(engine: &Arc<Engine>, path: &str) -> Result<
         let path = format!("{}/{:x}.boc", path, id.file_hash());
         let bytes = tokio::fs::read(&path).await
             .map_err(|err| error!("Cannot read zerostate {}: {}", path, err))?;
-        let zs = ShardStateStuff::deserialize_zerostate(id.clone(), &bytes)?;
+        let zs = ShardStateStuff::deserialize_zerostate(id.clone(), &bytes)
+            .map_err(|err| error!("Zerostate {} doesn't match masterchain zerostate: {}", path, err))?;
         let handle = engine.store_zerostate(&id, &zs, &bytes).await?;
         engine.set_applied(&handle, id.seq_no()).await?;
     }
@@ -1417,24 +2407,87 @@ async fn run_control_server(engine: Arc<Engine>, config: AdnlServerConfig) -> Re
     ).await
 }
 
-pub async fn run(node_config: TonNodeConfig, zerostate_path: Option<&str>, ext_db: Vec<Arc<dyn ExternalDb>>, initial_sync_disabled : bool) -> Result<()> {
+pub async fn run(
+    node_config: TonNodeConfig,
+    zerostate_path: Option<&str>,
+    ext_db: Vec<Arc<dyn ExternalDb>>,
+    initial_sync_disabled : bool,
+    check_db: bool,
+    migrate_db_layout: bool,
+    read_only: bool,
+    import_archives: Option<&str>
+) -> Result<()> {
     log::info!("Engine::run");
 
     let consumer_config = node_config.kafka_consumer_config();
     let control_server_config = node_config.control_server()?;
+    let health_server_config = node_config.health_server_config().clone();
+    let lite_server_config = node_config.lite_server()?;
+    let validator_manager_config = node_config.validator_manager_config();
+    let db_integrity_check = node_config.db_integrity_check_config().clone();
 
     // Create engine
-    let engine = Engine::new(node_config, ext_db, initial_sync_disabled).await?;
+    let engine = Engine::new(
+        node_config, ext_db, initial_sync_disabled, migrate_db_layout, read_only
+    ).await?;
+
+    if read_only {
+        return run_read_only(engine, control_server_config).await
+    }
+
+    // Shutdown is drained by the engine from now on
+    SHUTDOWN.set_handled();
+    tokio::spawn(Arc::clone(&engine).shutdown());
+
+    // Internal DB integrity check, before anything uses the last applied blocks
+    if check_db || db_integrity_check.light_check_on_start {
+        let workchain_id = match engine.workchain_id.load(Ordering::Relaxed) {
+            ton_block::INVALID_WORKCHAIN_ID => None,
+            ton_block::MASTERCHAIN_ID => Some(ton_block::BASE_WORKCHAIN_ID),
+            workchain_id => Some(workchain_id)
+        };
+        let report = integrity::check_and_truncate(
+            engine.db().deref(), db_integrity_check.depth, check_db, workchain_id
+        ).await?;
+        log::info!(
+            "DB integrity {} check: {} masterchain and {} shard blocks checked, {} broken, {}",
+            if check_db { "full" } else { "light" },
+            report.checked_mc_blocks, report.checked_shard_blocks, report.broken_blocks,
+            if report.truncated { "truncated" } else { "nothing truncated" }
+        );
+    }
 
     #[cfg(feature = "telemetry")]
     telemetry_logger(engine.clone());
 
+    // External DB healthcheck
+    if !engine.ext_db().is_empty() {
+        if let Err(e) = engine.check_external_db_health().await {
+            if engine.ext_db_healthcheck.require_healthy_on_start {
+                fail!("External db is unhealthy: {}", e)
+            }
+            log::error!("External db is unhealthy: {}", e);
+        }
+        start_external_db_healthcheck(engine.clone());
+    }
+
+    // Pending external messages saved before restart
+    match engine.external_messages().restore(engine.now()) {
+        Ok(restored) => log::info!("Restored {} pending external messages", restored),
+        Err(e) => log::error!("Can't restore pending external messages: {}", e)
+    }
+
     // Console service - run firt to allow console to connect to generate new keys while node is looking for net
     if let Some(config) = control_server_config {
         let control_server = run_control_server(engine.clone(), config).await?;
         // Asking the compiler not to drop `control_server`, despite we don't have any link to it.
         std::mem::forget(control_server);
     };
+    HealthServer::listen(
+        &health_server_config, Arc::clone(&engine) as Arc<dyn EngineOperations>
+    ).await?;
+    Arc::clone(engine.config_reloader())
+        .listen_sighup(Arc::clone(&engine) as Arc<dyn EngineOperations>)?;
 
     // Messages from external DB (usually kafka)
     start_external_broadcast_process(engine.clone(), &consumer_config)?;
@@ -1448,12 +2501,50 @@ pub async fn run(node_config: TonNodeConfig, zerostate_path: Option<&str>, ext_d
     network.add_consumer(&overlay_id, full_node_service.clone())?;
 
     // Boot
-    let (mut last_applied_mc_block, mut shard_client_mc_block, pss_keeper_block) = boot(&engine, zerostate_path).await?;
+    // Block clients and persistent states keeper take their blocks saved by boot from DB
+    let (mut last_applied_mc_block, _, _) = boot(&engine, zerostate_path).await?;
+
+    // Archive packages given by operator, sync continues from where the import ended
+    if let Some(dir) = import_archives {
+        let report = crate::sync::import_archives(Arc::clone(&engine) as Arc<dyn EngineOperations>, dir).await?;
+        log::info!(
+            "Archives import from {}: {} packages imported, {} already applied, {} skipped, \
+            last synced MC seq_no {}",
+            dir, report.imported.len(), report.already_applied.len(), report.skipped.len(),
+            report.last_mc_seq_no
+        );
+        last_applied_mc_block = engine.load_last_applied_mc_block_id()?.ok_or_else(
+            || error!("INTERNAL ERROR: No last applied MC block after archives import")
+        )?.deref().clone();
+    }
+
+    // Top shard blocks restored from DB could be committed into masterchain while node was down
+    let last_mc_state = engine.load_state(&last_applied_mc_block).await?;
+    let restored = engine.shard_blocks().drop_outdated(&last_mc_state)?;
+    log::info!("{} top shard blocks are restored from DB", restored);
+
+    // Lite server answers from applied states, so it is started after boot
+    if let Some(config) = lite_server_config {
+        let lite_server = LiteServer::with_config(
+            config, Arc::clone(&engine) as Arc<dyn EngineOperations>
+        ).await?;
+        std::mem::forget(lite_server);
+    }
 
     let (masterchain, workchain_id) = engine.processed_workchain().await?;
     log::info!("processed masterchain: {} workchain: {}", masterchain, workchain_id);
+    engine.tracked_shards().check_workchain(workchain_id)?;
+    if !engine.ext_db().is_empty() {
+        let front_workchain_ids = engine.front_workchain_ids.read().unwrap().clone();
+        engine.check_front_workchains(front_workchain_ids.as_deref(), workchain_id)?;
+    }
+    if engine.tracked_shards().is_partial() {
+        log::info!("partial mode, tracked shards: {}", engine.tracked_shards().status());
+    }
 
     // Broadcasts (blocks, external messages etc.)
+    Engine::start_ext_messages_gc(engine.clone());
+    Engine::start_ext_messages_rebroadcast(engine.clone());
     Arc::clone(&engine).listen_broadcasts(ShardIdent::masterchain()).await?;
 
     Arc::clone(&engine).listen_broadcasts(ShardIdent::with_tagged_prefix(workchain_id, SHARD_FULL)?).await?;
@@ -1462,10 +2553,15 @@ pub async fn run(node_config: TonNodeConfig, zerostate_path: Option<&str>, ext_d
     engine.get_full_node_overlay(workchain_id, SHARD_FULL).await?;
 
     // Saving of persistent states (for sync)
-    let _ = Engine::start_persistent_states_keeper(engine.clone(), pss_keeper_block)?;
+    Engine::start_persistent_states_keeper(engine.clone());
+    Engine::start_blocks_pruning(engine.clone());
 
-    // Start validator manager, which will start validator sessions when necessary
-    start_validator_manager(Arc::clone(&engine) as Arc<dyn EngineOperations>);
+    // Start validator manager, which will start validator sessions when necessary.
+    // Sessions and pinned MC state of failed manager stay alive, so it isn't restarted.
+    let validator_engine = Arc::clone(&engine) as Arc<dyn EngineOperations>;
+    engine.supervisor().spawn_no_restart("validator manager", move || Box::pin(run_validator_manager(
+        Arc::clone(&validator_engine), validator_manager_config.clone()
+    )));
 
     // Sync by archives
     if !engine.check_sync().await? {
@@ -1476,24 +2572,76 @@ pub async fn run(node_config: TonNodeConfig, zerostate_path: Option<&str>, ext_d
             async fn check(&self, _engine: &Arc<dyn EngineOperations>) -> bool { true }
         }
         crate::sync::start_sync(Arc::clone(&engine) as Arc<dyn EngineOperations>, Some(&FakeSync)).await?;
-        last_applied_mc_block = engine.load_last_applied_mc_block_id()?.ok_or_else(
-            || error!("INTERNAL ERROR: No last applied MC block after boot")
-        )?.deref().clone();
-        shard_client_mc_block = engine.load_shard_client_mc_block_id()?.ok_or_else(
-            || error!("INTERNAL ERROR: No shard client MC block after boot")
-        )?.deref().clone();
     }
 
     // top shard blocks
     resend_top_shard_blocks_worker(engine.clone());
 
-    // blocks download clients
-    let _ = start_shards_client(engine.clone(), shard_client_mc_block)?;
-    let _ = start_masterchain_client(engine, last_applied_mc_block)?.await;   
+    // blocks download clients, they continue from blocks saved in DB after restart
+    let client_engine = Arc::clone(&engine) as Arc<dyn EngineOperations>;
+    engine.supervisor().spawn("shards client", move || {
+        Box::pin(run_shards_client(Arc::clone(&client_engine)))
+    });
+    let client_engine = Arc::clone(&engine) as Arc<dyn EngineOperations>;
+    engine.supervisor().spawn("masterchain client", move || {
+        Box::pin(run_masterchain_client(Arc::clone(&client_engine)))
+    });
+
+    // The process exits when shutdown is drained
+    SHUTDOWN.wait_finished().await;
     Ok(())
 
 }
 
+// Only control server is run, data written by another node is followed
+async fn run_read_only(
+    engine: Arc<Engine>,
+    control_server_config: Option<AdnlServerConfig>
+) -> Result<()> {
+    log::info!("Engine is running in read-only mode");
+    if let Some(config) = control_server_config {
+        let control_server = run_control_server(engine.clone(), config).await?;
+        std::mem::forget(control_server);
+    }
+    loop {
+        tokio::time::sleep(Duration::from_millis(READ_ONLY_CATCH_UP_INTERVAL_MS)).await;
+        if let Err(e) = engine.db().catch_up_with_primary().await {
+            log::warn!("Can't catch up with node writing to DB: {}", e);
+        }
+    }
+}
+
+// Walks key blocks down from `seq_no`, `prev_key_block` gives the previous key block with its
+// seqno and generation time. Returns the newest persistent state key block having at least
+// `min_pss_count` persistent states after it (itself included), older than `max_gen_utime`.
+fn find_pruning_boundary<T>(
+    mut seq_no: u32,
+    min_pss_count: u32,
+    max_gen_utime: Option<u32>,
+    mut prev_key_block: impl FnMut(u32) -> Result<Option<(T, u32, u32)>>,
+    is_persistent_state: impl Fn(u32, u32) -> bool
+) -> Result<Option<T>> {
+    let mut pss_count = 0;
+    let mut newer_key_block: Option<(T, u32)> = None;
+    while seq_no > 0 {
+        let (prev_block, prev_seq_no, prev_gen_utime) = match prev_key_block(seq_no)? {
+            Some(prev) => prev,
+            None => break
+        };
+        if let Some((block, gen_utime)) = newer_key_block.take() {
+            if is_persistent_state(gen_utime, prev_gen_utime) {
+                pss_count += 1;
+                if pss_count >= min_pss_count && max_gen_utime.map(|t| gen_utime < t).unwrap_or(true) {
+                    return Ok(Some(block))
+                }
+            }
+        }
+        seq_no = prev_seq_no;
+        newer_key_block = Some((prev_block, prev_gen_utime));
+    }
+    Ok(None)
+}
+
 #[cfg(feature = "telemetry")]
 fn telemetry_logger(engine: Arc<Engine>) {
     const TELEMETRY_TIMEOUT: u64 = 30;
@@ -1530,6 +2678,21 @@ fn telemetry_logger(engine: Arc<Engine>) {
 }
 
 
+fn start_external_db_healthcheck(engine: Arc<Engine>) {
+    let interval = engine.ext_db_healthcheck.healthcheck_interval_sec;
+    if interval == 0 {
+        return
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(Duration::from_secs(interval)).await;
+            if let Err(e) = engine.check_external_db_health().await {
+                log::error!("External db is unhealthy: {}", e);
+            }
+        }
+    });
+}
+
 #[cfg(not(feature = "external_db"))]
 pub fn start_external_broadcast_process(
     _engine: Arc<dyn EngineOperations>, 
@@ -1564,36 +2727,288 @@ pub fn start_external_broadcast_process(
 
 #[cfg(feature = "metrics")]
 lazy_static::lazy_static! {
-    pub static ref STATSD: client::Client = {
+    pub static ref STATSD: MetricsClient = {
         let mut statsd_endp = env::var("STATSD_DOMAIN").expect("STATSD_DOMAIN env variable not found");
         let statsd_port = env::var("STATSD_PORT").expect("STATSD_PORT env variable not found");
         statsd_endp.push_str(&statsd_port);
-        let statsd_client = match client::Client::new(statsd_endp, "rnode"){
+        match MetricsClient::with_address(&statsd_endp, "rnode") {
             Ok(client) => client,
             Err(e) => {
                 panic!("Can't init statsd client: {:?}", e);
             }
-        };
-        statsd_client
+        }
     };
 }
 
 #[cfg(not(feature = "metrics"))]
-pub struct StatsdClient {}
+lazy_static::lazy_static! {
+    pub static ref STATSD: MetricsClient = MetricsClient::disabled();
+}
 
-#[cfg(not(feature = "metrics"))]
-#[allow(dead_code)]
-impl StatsdClient {
-    pub fn new() -> StatsdClient { StatsdClient{} }
-    pub fn gauge(&self, _metric_name: &str, _value: f64) {}
-    pub fn incr(&self, _metric_name: &str) {}
-    #[allow(dead_code)]
-    pub fn histogram(&self, _metric_name: &str, _value: f64) {}
-    #[cfg(feature = "external_db")]
-    pub fn timer(&self, _metric_name: &str, _value: f64) {}
+// DB is of the network of global config if it has the zerostate or it is booted from
+// init block of config, from hardfork or from key block whose proof it keeps. The proof
+// was checked with the chain of config when it was stored, so DB booted from key block
+// which is not in global config anymore is known too.
+fn is_network_db(
+    db_init_block: Option<&BlockIdExt>,
+    known_blocks: &[&BlockIdExt],
+    has_zero_state: bool,
+    is_proven_key_block: impl Fn(&BlockIdExt) -> Result<bool>
+) -> Result<bool> {
+    if has_zero_state {
+        return Ok(true)
+    }
+    match db_init_block {
+        Some(id) => Ok(known_blocks.contains(&id) || is_proven_key_block(id)?),
+        None => Ok(false)
+    }
 }
 
-#[cfg(not(feature = "metrics"))]
-lazy_static::lazy_static! {
-    pub static ref STATSD: StatsdClient = StatsdClient::new();
+#[cfg(test)]
+const TEST_PSS_PERIOD_BITS: u32 = 10;
+
+// Key blocks are (seqno, gen_utime), the oldest first. Returns seqno of the boundary.
+#[cfg(test)]
+fn test_pruning_boundary(
+    key_blocks: &[(u32, u32)],
+    from_seq_no: u32,
+    min_pss_count: u32,
+    max_gen_utime: Option<u32>
+) -> Option<u32> {
+    find_pruning_boundary(
+        from_seq_no,
+        min_pss_count,
+        max_gen_utime,
+        |seq_no| Ok(
+            key_blocks.iter().rev()
+                .find(|(key_seq_no, _)| *key_seq_no < seq_no)
+                .map(|(key_seq_no, gen_utime)| (*key_seq_no, *key_seq_no, *gen_utime))
+        ),
+        |gen_utime, prev_gen_utime| {
+            gen_utime >> TEST_PSS_PERIOD_BITS != prev_gen_utime >> TEST_PSS_PERIOD_BITS
+        }
+    ).unwrap()
+}
+
+#[test]
+fn test_pruning_boundary_keeps_persistent_states() {
+    // Every key block starts new persistent state period
+    let key_blocks = (0..10).map(|i| (i * 10, i << TEST_PSS_PERIOD_BITS)).collect::<Vec<_>>();
+    // The latest persistent state and the previous one are kept
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, MIN_KEPT_PERSISTENT_STATES, None), Some(80));
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 5, None), Some(50));
+    // Keeper block itself is a key block
+    assert_eq!(test_pruning_boundary(&key_blocks, 90, MIN_KEPT_PERSISTENT_STATES, None), Some(70));
+    // Not enough persistent states
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 9, None), Some(10));
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 10, None), None);
+    assert_eq!(test_pruning_boundary(&key_blocks, 15, MIN_KEPT_PERSISTENT_STATES, None), None);
+}
+
+#[test]
+fn test_pruning_boundary_skips_non_pss_key_blocks() {
+    // Key blocks 10, 30, 50 are in the same persistent state period as the previous ones
+    let key_blocks = [
+        (0, 0), (10, 100), (20, 1 << TEST_PSS_PERIOD_BITS), (30, (1 << TEST_PSS_PERIOD_BITS) + 100),
+        (40, 2 << TEST_PSS_PERIOD_BITS), (50, (2 << TEST_PSS_PERIOD_BITS) + 100), (60, 3 << TEST_PSS_PERIOD_BITS)
+    ];
+    assert_eq!(test_pruning_boundary(&key_blocks, 65, MIN_KEPT_PERSISTENT_STATES, None), Some(40));
+    assert_eq!(test_pruning_boundary(&key_blocks, 65, 3, None), Some(20));
+    assert_eq!(test_pruning_boundary(&key_blocks, 65, 4, None), None);
+}
+
+#[test]
+fn test_pruning_boundary_keep_days() {
+    let key_blocks = (0..10).map(|i| (i * 10, i << TEST_PSS_PERIOD_BITS)).collect::<Vec<_>>();
+    // Newer blocks are kept by time even if there are enough persistent states
+    let max_gen_utime = Some(5 << TEST_PSS_PERIOD_BITS);
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, MIN_KEPT_PERSISTENT_STATES, max_gen_utime), Some(40));
+    // And by persistent states count if they are older
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 7, max_gen_utime), Some(30));
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, MIN_KEPT_PERSISTENT_STATES, Some(0)), None);
+}
+
+#[test]
+fn test_pruning_boundary_stops_at_init_block() {
+    // Node is booted from key block 50, older key blocks are not in the database
+    let key_blocks = (5..10).map(|i| (i * 10, i << TEST_PSS_PERIOD_BITS)).collect::<Vec<_>>();
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, MIN_KEPT_PERSISTENT_STATES, None), Some(80));
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 4, None), Some(60));
+    assert_eq!(test_pruning_boundary(&key_blocks, 95, 5, None), None);
+}
+
+#[test]
+fn test_is_network_db() {
+    let id = |seq_no: u32, hash: u8| BlockIdExt::with_params(
+        ShardIdent::masterchain(), seq_no, UInt256::from_slice(&[hash; 32]), UInt256::from_slice(&[hash; 32])
+    );
+    let global_init_block = id(100, 1);
+    let key_blocks = vec!(id(50, 2));
+    let is_proven_key_block = |block_id: &BlockIdExt| Ok(key_blocks.contains(block_id));
+    let known = [&global_init_block];
+
+    assert!(is_network_db(None, &known, true, is_proven_key_block).unwrap());
+    assert!(is_network_db(Some(&id(100, 1)), &known, false, is_proven_key_block).unwrap());
+    // Booted from key block which is not in global config anymore
+    assert!(is_network_db(Some(&id(50, 2)), &known, false, is_proven_key_block).unwrap());
+    // Unknown block, or key block of the same seqno without proof
+    assert!(!is_network_db(Some(&id(50, 3)), &known, false, is_proven_key_block).unwrap());
+    assert!(!is_network_db(None, &known, false, is_proven_key_block).unwrap());
+}
+
+#[cfg(test)]
+struct HedgeTestClient {
+    peers: Vec<Arc<KeyId>>,
+    asked: std::sync::Mutex<Vec<Arc<KeyId>>>
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl FullNodeOverlayClient for HedgeTestClient {
+    async fn broadcast_external_message(&self, _msg: &[u8]) -> Result<overlay::BroadcastSendInfo> {
+        unimplemented!()
+    }
+    async fn send_block_broadcast(
+        &self,
+        _broadcast: ton_api::ton::ton_node::broadcast::BlockBroadcast
+    ) -> Result<()> {
+        unimplemented!()
+    }
+    async fn send_top_shard_block_description(
+        &self,
+        _tbd: &crate::types::top_block_descr::TopBlockDescrStuff
+    ) -> Result<()> {
+        unimplemented!()
+    }
+    async fn download_block_proof(
+        &self,
+        _block_id: &BlockIdExt,
+        _is_link: bool,
+        _key_block: bool,
+        _active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<BlockProofStuff> {
+        unimplemented!()
+    }
+    async fn download_block_full(
+        &self,
+        _id: &BlockIdExt,
+        _active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<(BlockStuff, BlockProofStuff)> {
+        unimplemented!()
+    }
+    async fn check_persistent_state(
+        &self,
+        _block_id: &BlockIdExt,
+        _masterchain_block_id: &BlockIdExt,
+        _active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<Option<Arc<crate::network::neighbours::Neighbour>>> {
+        unimplemented!()
+    }
+    async fn download_persistent_state_part(
+        &self,
+        _block_id: &BlockIdExt,
+        _masterchain_block_id: &BlockIdExt,
+        _offset: usize,
+        _max_size: usize,
+        _peer: Arc<crate::network::neighbours::Neighbour>,
+        _attempt: u32,
+    ) -> Result<Vec<u8>> {
+        unimplemented!()
+    }
+    async fn download_zero_state(&self, _id: &BlockIdExt) -> Result<(ShardStateStuff, Vec<u8>)> {
+        unimplemented!()
+    }
+    async fn download_next_key_blocks_ids(
+        &self,
+        _block_id: &BlockIdExt,
+        _max_size: i32
+    ) -> Result<Vec<BlockIdExt>> {
+        unimplemented!()
+    }
+    // The first peer never answers, others answer at once
+    async fn download_next_block_full(
+        &self,
+        _prev_id: &BlockIdExt,
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<(BlockStuff, BlockProofStuff)> {
+        let peer = self.peers.iter().find(|peer| !active_peers.contains(*peer))
+            .ok_or_else(|| error!("neighbour is not found!"))?;
+        active_peers.insert(peer.clone()).ok();
+        self.asked.lock().unwrap().push(peer.clone());
+        if peer == &self.peers[0] {
+            futures::future::pending::<()>().await;
+        }
+        Ok((BlockStuff::default(), BlockProofStuff::default()))
+    }
+    async fn lookup_block_by_seqno(
+        &self,
+        _shard: &ShardIdent,
+        _seqno: u32
+    ) -> Result<Vec<(BlockIdExt, Arc<KeyId>)>> {
+        unimplemented!()
+    }
+    async fn download_archive(
+        &self,
+        _mc_seq_no: u32,
+        _min_speed: u64,
+        _limiter: &crate::network::bandwidth::BandwidthLimiter,
+        _active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<Option<Vec<u8>>> {
+        unimplemented!()
+    }
+    async fn wait_broadcast(&self) -> Result<(ton_api::ton::ton_node::Broadcast, Arc<KeyId>)> {
+        unimplemented!()
+    }
+    fn report_peer(&self, _peer: &Arc<KeyId>, _outcome: PeerOutcome) {
+    }
+}
+
+#[test]
+fn test_hedged_download_asks_another_peer() {
+    let db_directory = std::env::temp_dir().join(format!("hedged_download_{}", std::process::id()));
+    std::fs::remove_dir_all(&db_directory).ok();
+    let db_config = InternalDbConfig {
+        db_directory: db_directory.to_str().unwrap().to_string(),
+        cells_gc_interval_ms: 0,
+        cells_cache_size_bytes: 0,
+        block_handles_cache_size: 0,
+        write_behind: None,
+        paths: Default::default(),
+        migrate_layout: false,
+        index_transactions: false,
+        read_only: false,
+    };
+    let peers = vec!(KeyId::from_data([1; 32]), KeyId::from_data([2; 32]));
+    let client = Arc::new(HedgeTestClient {
+        peers: peers.clone(),
+        asked: std::sync::Mutex::new(Vec::new())
+    });
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let db = InternalDbImpl::new(db_config).await.unwrap();
+        #[cfg(feature = "telemetry")]
+        let full_node_telemetry = FullNodeTelemetry::new();
+        let id = BlockIdExt::with_params(ShardIdent::masterchain(), 1, UInt256::default(), UInt256::default());
+        let mut context = DownloadContext {
+            active_peers: Arc::new(lockfree::set::Set::new()),
+            client: client.clone(),
+            db: &db,
+            downloader: Arc::new(NextBlockDownloader),
+            hedge_delay: Some(Duration::from_millis(50)),
+            id: &id,
+            limit: Some(1),
+            log_error_limit: 1,
+            name: "test_hedged_download",
+            timeout: None,
+            validators_overlays: Vec::new(),
+            #[cfg(feature = "telemetry")]
+            full_node_telemetry: &full_node_telemetry,
+        };
+        tokio::time::timeout(Duration::from_secs(10), context.download()).await
+            .expect("hedged download must not wait for the slow peer")
+            .unwrap();
+    });
+    // The slow peer is not asked twice
+    assert_eq!(*client.asked.lock().unwrap(), peers);
+    std::fs::remove_dir_all(&db_directory).ok();
 }
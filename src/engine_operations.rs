@@ -1,12 +1,23 @@
 use crate::{
     block::BlockStuff, block_proof::BlockProofStuff, 
-    config::CollatorTestBundlesGeneralConfig,
-    engine::{Engine, STATSD},
+    config::{
+        ArchiveNodeConfig, ArchivesSyncConfig, CollatorTestBundlesGeneralConfig, StatesGcPolicy
+    },
+    config_reload::ReloadReport, engine::{Engine, STATSD},
     engine_traits::{ChainRange, EngineOperations, PrivateOverlayOperations, ValidatedBlockStat},
-    error::NodeError,
-    internal_db::{INITIAL_MC_BLOCK, LAST_APPLIED_MC_BLOCK, SHARD_CLIENT_MC_BLOCK, BlockResult},
-    shard_state::ShardStateStuff,
-    types::top_block_descr::{TopBlockDescrStuff, TopBlockDescrId}
+    error::{DbError, NodeError},
+    ext_messages::{ExtMessageStatus, MessagesPoolStats, precheck_ext_message},
+    full_node::{apply_stages::ApplyStage, key_block_prefetch::KeyBlockPrefetcher},
+    internal_db::{
+        INITIAL_MC_BLOCK, LAST_APPLIED_MC_BLOCK, SHARD_CLIENT_MC_BLOCK, BlockResult,
+        scrubber::QuarantinedProof, sync_cursor::SyncCursor
+    },
+    network::{node_network::DhtStaticNodesUpdate, peer_scores::PeerScore},
+    shard_state::{ChangeKind, ShardStateStuff},
+    types::{states_cache::StatePin, top_block_descr::{TopBlockDescrStuff, TopBlockDescrId}},
+    validator::{
+        CollatorSettings, timings::ShardTimings, validator_group::ValidatorGroupStatusEvent
+    },
 };
 use adnl::common::{KeyId, KeyOption};
 use catchain::{
@@ -14,14 +25,14 @@ use catchain::{
 };
 use overlay::{BroadcastSendInfo, PrivateOverlayShortId};
 use rand::Rng;
-use std::{sync::{atomic::Ordering, Arc}, ops::Deref};
-use storage::types::BlockHandle;
-use ton_api::ton::ton_node::broadcast::BlockBroadcast;
+use std::{sync::{atomic::Ordering, Arc}, ops::Deref, path::Path, time::Duration};
+use storage::{transactions_index_db::IndexedTransaction, types::BlockHandle};
+use ton_api::{IntoBoxed, ton::ton_node::broadcast::{BlockBroadcast, NewShardBlockBroadcast}};
 use ton_block::{
     MASTERCHAIN_ID, INVALID_WORKCHAIN_ID, BASE_WORKCHAIN_ID, SHARD_FULL,
     BlockIdExt, AccountIdPrefixFull, ShardIdent, Message,
 };
-use ton_types::{fail, error, Result, UInt256};
+use ton_types::{fail, error, AccountId, Result, UInt256};
 #[cfg(feature = "telemetry")]
 use crate::{
     full_node::telemetry::FullNodeTelemetry,
@@ -42,7 +53,7 @@ impl EngineOperations for Engine {
                         1 => workchains[0],
                         count => {
                             //TODO: check gen_range here must be [0..count]
-                            match rand::thread_rng().gen_range(0, count as usize) {
+                            match crate::rng::stream("workchain").gen_range(0, count as usize) {
                                 0 => {
                                     self.workchain_id.store(MASTERCHAIN_ID, Ordering::Relaxed);
                                     self.network().config_handler().store_workchain(MASTERCHAIN_ID);
@@ -85,11 +96,11 @@ impl EngineOperations for Engine {
         self.network().activate_validator_list(validator_list_id)
     }
 
-    fn validation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    fn validation_status(&self) -> &ShardTimings {
         self.validation_status()
     }
 
-    fn collation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    fn collation_status(&self) -> &ShardTimings {
         self.collation_status()
     }
 
@@ -118,6 +129,14 @@ impl EngineOperations for Engine {
         self.validator_network().stop_catchain_client(overlay_short_id)
     }
 
+    fn start_validators_overlay(self: Arc<Self>, validator_list_id: &UInt256) -> Result<()> {
+        Engine::start_validators_overlay(self, validator_list_id)
+    }
+
+    fn stop_validators_overlay(&self, validator_list_id: &UInt256) {
+        Engine::stop_validators_overlay(self, validator_list_id)
+    }
+
     fn load_block_handle(&self, id: &BlockIdExt) -> Result<Option<Arc<BlockHandle>>> {
         self.db().load_block_handle(id)
     }
@@ -127,9 +146,9 @@ impl EngineOperations for Engine {
         if handle.is_applied() {
             self.load_block(handle).await
         } else if handle.has_data() {
-            fail!("Block is not applied yet")
+            fail!(NodeError::Db(DbError::NotFound, format!("Block {} is not applied yet", handle.id())))
         } else {
-            fail!("No block")
+            fail!(NodeError::Db(DbError::NotFound, format!("No block {}", handle.id())))
         }
     }
 
@@ -194,6 +213,10 @@ impl EngineOperations for Engine {
         self.db().find_block_by_unix_time(acc_pfx, utime)
     }
 
+    async fn find_block_by_seqno(&self, shard: &ShardIdent, seqno: u32) -> Result<Option<BlockIdExt>> {
+        Engine::find_block_by_seqno(self, shard, seqno).await
+    }
+
     async fn find_block_by_lt(&self, acc_pfx: &AccountIdPrefixFull, lt: u64) -> Result<Arc<BlockHandle>> {
         self.db().find_block_by_lt(acc_pfx, lt)
     }
@@ -202,11 +225,11 @@ impl EngineOperations for Engine {
         let block_id = if let Some(id) = self.load_last_applied_mc_block_id()? {
             id
         } else {
-            fail!("INTERNAL ERROR: No last applied MC block set")
+            fail!(NodeError::Db(DbError::NotFound, "No last applied MC block set".to_string()))
         };
-        let handle = self.load_block_handle(&block_id)?.ok_or_else(
-            || error!("Cannot load handle for last applied master block {}", block_id)
-        )?;
+        let handle = self.load_block_handle(&block_id)?.ok_or_else(|| NodeError::Db(
+            DbError::NotFound, format!("Cannot load handle for last applied master block {}", block_id)
+        ))?;
         self.load_applied_block(&handle).await
     }
 
@@ -214,7 +237,7 @@ impl EngineOperations for Engine {
         let block_id = if let Some(id) = self.load_last_applied_mc_block_id()? {
             id
         } else {
-            fail!("INTERNAL ERROR: No last applied MC block set")
+            fail!(NodeError::Db(DbError::NotFound, "No last applied MC block set".to_string()))
         };
         self.load_state(&block_id).await
     }
@@ -236,6 +259,18 @@ impl EngineOperations for Engine {
         self.db().save_node_state(SHARD_CLIENT_MC_BLOCK, id)
     }
 
+    fn load_sync_cursor(&self) -> Result<Option<SyncCursor>> {
+        self.db().load_sync_cursor()
+    }
+
+    fn save_sync_cursor(&self, cursor: &SyncCursor) -> Result<()> {
+        self.db().save_sync_cursor(cursor)
+    }
+
+    fn clear_sync_cursor(&self) -> Result<()> {
+        self.db().clear_sync_cursor()
+    }
+
     async fn apply_block_internal(
         self: Arc<Self>, 
         handle: &Arc<BlockHandle>, 
@@ -310,8 +345,11 @@ impl EngineOperations for Engine {
             block_id.shard().workchain_id(), 
             block_id.shard().shard_prefix_with_tag()
         ).await?;
+        let staging_dir = Path::new(self.db_root_dir()?)
+            .join(crate::full_node::state_helper::STATE_DOWNLOAD_DIR_NAME);
         crate::full_node::state_helper::download_persistent_state(
-            block_id, master_id, overlay.deref(), active_peers
+            block_id, master_id, overlay.deref(), active_peers, &staging_dir, self.state_downloads(),
+            &self.bandwidth_limits().download
         ).await
     }
 
@@ -362,15 +400,25 @@ impl EngineOperations for Engine {
     }
 
     async fn load_state(&self, block_id: &BlockIdExt) -> Result<ShardStateStuff> {
-        if let Some(state) = self.shard_states_cache().get(block_id) {
-            self.update_shard_states_cache_stat(true);
-            Ok(state)
-        } else {
-            let state = self.db().load_shard_state_dynamic(block_id)?;
-            self.shard_states_cache().set(block_id.clone(), |_| Some(state.clone()))?;
-            self.update_shard_states_cache_stat(false);
-            Ok(state)
-        }
+        Ok(Engine::load_state_cached(self, block_id).await?.deref().clone())
+    }
+
+    async fn load_state_cached(&self, block_id: &BlockIdExt) -> Result<Arc<ShardStateStuff>> {
+        Engine::load_state_cached(self, block_id).await
+    }
+
+    async fn diff_accounts(
+        &self,
+        from: &BlockIdExt,
+        to: &BlockIdExt
+    ) -> Result<Vec<(AccountId, ChangeKind)>> {
+        let from_state = Engine::load_state_cached(self, from).await?;
+        let to_state = Engine::load_state_cached(self, to).await?;
+        from_state.diff_accounts(&to_state)
+    }
+
+    fn pin_state(&self, block_id: &BlockIdExt) -> Option<StatePin> {
+        Some(self.shard_states_cache().pin(block_id))
     }
 
     async fn load_persistent_state_size(&self, block_id: &BlockIdExt) -> Result<u64> {
@@ -420,9 +468,7 @@ impl EngineOperations for Engine {
         handle: &Arc<BlockHandle>, 
         state: &ShardStateStuff
     ) -> Result<()> {
-        if self.shard_states_cache().get(handle.id()).is_none() {
-            self.shard_states_cache().set(handle.id().clone(), |_| Some(state.clone()))?;
-        }
+        self.shard_states_cache().insert(Arc::new(state.clone()));
         if self.db().store_shard_state_dynamic(handle, state, None)? {
             #[cfg(feature = "telemetry")]
             self.full_node_telemetry().new_pre_applied_block(handle.got_by_broadcast());
@@ -497,34 +543,47 @@ impl EngineOperations for Engine {
             if proof.is_some() && !handle.id().shard().is_masterchain() {
                 fail!("Non master blocks should be processed without proof")
             }
-            if proof.is_none() && handle.id().shard().is_masterchain() {
-                let proof = self.load_block_proof(handle, false).await?;
-                for db in self.ext_db() {
-                    db.process_block(block, Some(&proof), state).await?;
-                }
+            let loaded_proof = if proof.is_none() && handle.id().shard().is_masterchain() {
+                Some(self.load_block_proof(handle, false).await?)
             } else {
-                for db in self.ext_db() {
-                    db.process_block(block, proof, state).await?;
-                }
-            }
+                None
+            };
+            let proof = loaded_proof.as_ref().or(proof);
+            // Sinks are processed concurrently, error of one doesn't stop others
+            join_ext_db_results(
+                futures::future::join_all(
+                    self.ext_db().iter().map(|db| db.process_block(block, proof, state))
+                ).await
+            )?;
+            // Block must not be marked as applied until its records are delivered
+            self.ext_db_ack().wait(self.ext_db(), handle.id()).await?;
         }
 //        self.db().store_block_processed_in_ext_db(handle)?;
         Ok(())
     }
 
+    async fn process_block_revoked_in_ext_db(&self, id: &BlockIdExt, reason: &str) -> Result<()> {
+        join_ext_db_results(
+            futures::future::join_all(self.ext_db().iter().map(|db| db.process_block_revoked(id, reason))).await
+        )
+    }
+
     async fn process_chain_range_in_ext_db(&self, chain_range: &ChainRange) -> Result<()> {
-        for db in self.ext_db() {
-            db.process_chain_range(chain_range).await?;
-        }
+        join_ext_db_results(
+            futures::future::join_all(self.ext_db().iter().map(|db| db.process_chain_range(chain_range))).await
+        )
+    }
 
-        Ok(())
+    async fn process_validator_group_status_in_ext_db(&self, event: &ValidatorGroupStatusEvent) -> Result<()> {
+        join_ext_db_results(
+            futures::future::join_all(self.ext_db().iter().map(|db| db.process_validator_group_status(event))).await
+        )
     }
 
     async fn process_full_state_in_ext_db(&self, state: &ShardStateStuff)-> Result<()> {
-        for db in self.ext_db() {
-            db.process_full_state(state).await?;
-        }
-        Ok(())
+        join_ext_db_results(
+            futures::future::join_all(self.ext_db().iter().map(|db| db.process_full_state(state))).await
+        )
     }
 
     async fn download_next_key_blocks_ids(
@@ -558,10 +617,16 @@ impl EngineOperations for Engine {
 
     fn initial_sync_disabled(&self) -> bool { Engine::initial_sync_disabled(self) }
 
+    fn get_hardforks(&self) -> &[BlockIdExt] { Engine::hardforks(self) }
+
     fn init_mc_block_id(&self) -> &BlockIdExt { 
         (self as &Engine).init_mc_block_id() 
     }
 
+    fn zero_state_id(&self) -> &BlockIdExt {
+        Engine::zero_state_id(self)
+    }
+
     fn save_init_mc_block_id(&self, id: &BlockIdExt) -> Result<()> {
         self.db().save_node_state(INITIAL_MC_BLOCK, id)
     }
@@ -589,7 +654,18 @@ impl EngineOperations for Engine {
         active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<Option<Vec<u8>>> {
         let client = self.get_masterchain_overlay().await?;
-        client.download_archive(masterchain_seqno, active_peers).await
+        let min_speed = self.archives_sync_config().min_download_speed_kb * 1024;
+        client.download_archive(
+            masterchain_seqno, min_speed, &self.bandwidth_limits().download, active_peers
+        ).await
+    }
+
+    fn archives_sync_config(&self) -> ArchivesSyncConfig {
+        Engine::archives_sync_config(self).clone()
+    }
+
+    fn archive_node_config(&self) -> ArchiveNodeConfig {
+        Engine::archive_node_config(self).clone()
     }
 
     async fn send_block_broadcast(&self, broadcast: BlockBroadcast) -> Result<()> {
@@ -597,12 +673,19 @@ impl EngineOperations for Engine {
             broadcast.id.workchain,
             SHARD_FULL, //broadcast.id.shard as u64
         ).await?;
+        let mirrored = broadcast.clone().into_boxed();
         overlay.send_block_broadcast(broadcast).await?;
+        self.mirror_to_validators_overlays(mirrored).await;
         #[cfg(feature = "telemetry")]
         self.full_node_telemetry().sent_block_broadcast();
         Ok(())
     }
 
+    async fn relay_block_broadcast(&self, broadcast: BlockBroadcast) -> Result<()> {
+        let overlay = self.get_full_node_overlay(broadcast.id.workchain, SHARD_FULL).await?;
+        overlay.send_block_broadcast(broadcast).await
+    }
+
     async fn send_top_shard_block_description(
         &self,
         tbd: Arc<TopBlockDescrStuff>,
@@ -623,6 +706,8 @@ impl EngineOperations for Engine {
         }
         
         overlay.send_top_shard_block_description(&tbd).await?;
+        let mirrored = NewShardBlockBroadcast { block: tbd.new_shard_block()? }.into_boxed();
+        self.mirror_to_validators_overlays(mirrored).await;
         #[cfg(feature = "telemetry")]
         self.full_node_telemetry().sent_top_block_broadcast();
         Ok(())
@@ -640,11 +725,19 @@ impl EngineOperations for Engine {
         self.will_validate()
     }
 
+    fn set_validator_adnl_unreachable(&self, unreachable: bool) {
+        Engine::set_validator_adnl_unreachable(self, unreachable);
+    }
+
+    fn validator_adnl_unreachable(&self) -> bool {
+        Engine::validator_adnl_unreachable(self)
+    }
+
     fn new_external_message(&self, id: UInt256, message: Arc<Message>) -> Result<()> {
         if !self.is_validator() {
             return Ok(());
         }
-        self.external_messages().new_message(id, message, self.now())
+        self.external_messages().new_message(id, message, None, self.now())
     }
 
     fn get_external_messages(&self, shard: &ShardIdent) -> Result<Vec<(Arc<Message>, UInt256)>> {
@@ -655,6 +748,77 @@ impl EngineOperations for Engine {
         self.external_messages().complete_messages(to_delay, to_delete, self.now())
     }
 
+    async fn precheck_external_message(&self, message: &Message) -> Result<()> {
+        if !self.external_messages().config().precheck {
+            return Ok(())
+        }
+        let dst = message.dst_ref().ok_or_else(|| error!("External message has no destination"))?;
+        let mc_state = self.load_last_applied_mc_state().await?;
+        let prefix = AccountIdPrefixFull::prefix(dst)?;
+        let shard_state = if prefix.is_masterchain() {
+            mc_state.clone()
+        } else {
+            let shard = match mc_state.shards()?.find_shard_by_prefix(&prefix)? {
+                Some(shard) => shard,
+                None => fail!("Can't find shard for external message destination {}", dst)
+            };
+            match self.load_state(shard.block_id()).await {
+                Ok(state) => state,
+                Err(e) => {
+                    // node doesn't track this shard, so the message can't be checked
+                    log::trace!("Skipped precheck of external message to {}: {}", dst, e);
+                    return Ok(())
+                }
+            }
+        };
+        // Execution is bounded by gas credit, but it is CPU work anyway
+        let message = message.clone();
+        let now = self.now();
+        tokio::task::spawn_blocking(
+            move || precheck_ext_message(&message, &shard_state, &mc_state, now)
+        ).await?
+    }
+
+    fn new_local_external_message(&self, id: UInt256, dst: AccountIdPrefixFull, data: &[u8]) {
+        self.external_messages().new_local_message(id, dst, data, self.now())
+    }
+
+    fn external_messages_stats(&self) -> MessagesPoolStats {
+        self.external_messages().stats()
+    }
+
+    fn external_messages_queue_head(&self, limit: usize) -> Vec<(UInt256, u128)> {
+        self.external_messages().queue_head(limit, self.now())
+    }
+
+    fn set_external_message_status(&self, id: &UInt256, status: ExtMessageStatus) {
+        self.external_messages().set_status(id, status, self.now())
+    }
+
+    fn external_message_status(&self, id: &UInt256) -> Option<(ExtMessageStatus, u32)> {
+        self.external_messages().status(id)
+    }
+
+    fn external_messages_recent_statuses(&self, limit: usize) -> Vec<(UInt256, ExtMessageStatus, u32)> {
+        self.external_messages().recent_statuses(limit)
+    }
+
+    fn external_db_stats(&self) -> Vec<String> {
+        self.ext_db().iter().flat_map(|db| db.stats()).collect()
+    }
+
+    fn external_db_filtered_records(&self) -> u64 {
+        self.ext_db().iter().map(|db| db.filtered_records()).sum()
+    }
+
+    fn external_db_health(&self) -> String {
+        Engine::external_db_health(self)
+    }
+
+    fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>) -> Result<()> {
+        Engine::set_front_workchain_ids(self, front_workchain_ids)
+    }
+
     // Get current list of new shard blocks with respect to last mc block.
     // If given mc_seq_no is not equal to last mc seq_no - function fails.
     fn get_shard_blocks(&self, mc_seq_no: u32) -> Result<Vec<Arc<TopBlockDescrStuff>>> {
@@ -678,6 +842,14 @@ impl EngineOperations for Engine {
         Engine::test_bundles_config(self)
     }
 
+    fn collator_settings(&self) -> CollatorSettings {
+        Engine::collator_settings(self)
+    }
+
+    fn set_collator_settings(&self, settings: CollatorSettings) {
+        Engine::set_collator_settings(self, settings)
+    }
+
     fn db_root_dir(&self) -> Result<&str> {
         self.db().db_root_dir()
     }
@@ -740,4 +912,225 @@ impl EngineOperations for Engine {
     fn adjust_states_gc_interval(&self, interval_ms: u32) {
         self.db().adjust_states_gc_interval(interval_ms)
     }
+
+    fn compact_db(&self, db: Option<String>) -> Result<bool> {
+        self.db_compactor().compact(db)
+    }
+
+    fn db_compaction_status(&self) -> String {
+        self.db_compactor().status()
+    }
+
+    fn create_backup(&self, path: String) -> Result<bool> {
+        self.db_backup().create(path)
+    }
+
+    fn db_backup_status(&self) -> String {
+        self.db_backup().status()
+    }
+
+    fn db_scrubber_status(&self) -> String {
+        self.db_scrubber().status()
+    }
+
+    fn db_scrubber_quarantine(&self) -> Vec<QuarantinedProof> {
+        self.db_scrubber().quarantine()
+    }
+
+    fn state_downloads_status(&self) -> String {
+        self.state_downloads().status()
+    }
+
+    fn set_bandwidth_limits(&self, download_kb_per_sec: u64, serve_kb_per_sec: u64) {
+        self.bandwidth_limits().set(download_kb_per_sec, serve_kb_per_sec)
+    }
+
+    fn bandwidth_limits_status(&self) -> String {
+        self.bandwidth_limits().status()
+    }
+
+    async fn consume_serve_bandwidth(&self, bytes: usize) {
+        self.bandwidth_limits().serve.consume(bytes).await
+    }
+
+    fn peer_scores(&self) -> Vec<(Arc<KeyId>, PeerScore)> {
+        self.network().peer_scores().scores()
+    }
+
+    fn is_shard_tracked(&self, shard: &ShardIdent) -> bool {
+        self.tracked_shards().is_tracked(shard)
+    }
+
+    fn relay_untracked_broadcasts(&self) -> bool {
+        Engine::relay_untracked_broadcasts(self)
+    }
+
+    fn max_serve_queries(&self) -> usize {
+        Engine::max_serve_queries(self)
+    }
+
+    fn is_account_tracked(&self, prefix: &AccountIdPrefixFull) -> bool {
+        self.tracked_shards().is_account_tracked(prefix)
+    }
+
+    fn update_tracked_shards(&self, top_shards: &[ShardIdent]) {
+        self.tracked_shards().update(top_shards);
+    }
+
+    fn tracked_shards_status(&self) -> String {
+        self.tracked_shards().status()
+    }
+
+    fn update_shard_lags(&self, lags: &[(ShardIdent, u32, u32)]) -> Vec<ShardIdent> {
+        self.shard_lags().update(lags)
+    }
+
+    fn shard_lags_status(&self) -> String {
+        self.shard_lags().status()
+    }
+
+    fn tasks_status(&self) -> String {
+        self.supervisor().status()
+    }
+
+    fn update_sync_progress(&self, mc_seq_no: u32, gen_utime: u32) {
+        self.sync_progress().update(mc_seq_no, gen_utime, self.last_known_mc_block_seqno(), self.now())
+    }
+
+    fn sync_progress_status(&self) -> String {
+        self.sync_progress().status()
+    }
+
+    fn key_block_prefetcher(&self) -> &KeyBlockPrefetcher {
+        Engine::key_block_prefetcher(self)
+    }
+
+    fn report_apply_stage(&self, shard: &ShardIdent, stage: ApplyStage, elapsed: Duration) {
+        self.apply_stages().report(shard, stage, elapsed)
+    }
+
+    fn apply_stages_status(&self) -> String {
+        self.apply_stages().status()
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.db().is_read_only()
+    }
+
+    fn check_db_writable(&self) -> Result<()> {
+        self.db().check_writable()
+    }
+
+    fn set_validator_runtime_workers(&self, workers: usize) {
+        Engine::set_validator_runtime_workers(self, workers)
+    }
+
+    fn runtime_status(&self) -> String {
+        Engine::runtime_status(self)
+    }
+
+    fn resource_profile(&self) -> String {
+        Engine::resource_profile(self).to_string()
+    }
+
+    fn reload_config(&self) -> Result<ReloadReport> {
+        self.config_reloader().reload(self)
+    }
+
+    fn reload_global_config(&self) -> Result<DhtStaticNodesUpdate> {
+        Engine::reload_global_config(self)
+    }
+
+    fn set_states_gc_policy(&self, policy: &StatesGcPolicy) {
+        self.state_gc_resolver().set_policy(policy)
+    }
+
+    fn states_gc_status(&self) -> String {
+        self.state_gc_resolver().status()
+    }
+
+    fn adjust_cells_cache_size(&self, size_mb: u64) {
+        self.db().adjust_cells_cache_size(size_mb * 1024 * 1024)
+    }
+
+    fn cells_cache_status(&self) -> String {
+        let stats = self.db().cells_cache_stats();
+        let requests = stats.hits + stats.misses;
+        format!(
+            "used {} of {} bytes, hits {}, misses {}, hit rate {:.2}%, evictions {}",
+            stats.used_bytes, stats.budget_bytes, stats.hits, stats.misses,
+            if requests > 0 { stats.hits as f64 * 100.0 / requests as f64 } else { 0.0 },
+            stats.evictions
+        )
+    }
+
+    fn states_cache_status(&self) -> String {
+        let stats = self.shard_states_cache_stats();
+        let requests = stats.hits + stats.misses;
+        format!(
+            "{} states ({} pinned), used {} of {} bytes, hits {}, misses {}, hit rate {:.2}%, \
+            evictions {}",
+            stats.entries, stats.pinned, stats.used_bytes, stats.budget_bytes, stats.hits,
+            stats.misses, if requests > 0 { stats.hits as f64 * 100.0 / requests as f64 } else { 0.0 },
+            stats.evictions
+        )
+    }
+
+    fn persistent_states_status(&self) -> String {
+        Engine::persistent_states_status(self)
+    }
+
+    fn set_validation_history_status(&self, status: String) {
+        Engine::set_validation_history_status(self, status)
+    }
+
+    fn validation_history_status(&self) -> String {
+        Engine::validation_history_status(self)
+    }
+
+    async fn load_account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: usize,
+        with_boc: bool
+    ) -> Result<Vec<(IndexedTransaction, Option<Vec<u8>>)>> {
+        let transactions = self.db().load_account_transactions(workchain_id, account, from_lt, limit)?;
+        let mut result = Vec::with_capacity(transactions.len());
+        let mut block: Option<BlockStuff> = None;
+        for tr in transactions {
+            if !with_boc {
+                result.push((tr, None));
+                continue
+            }
+            // Transactions of the same block usually go in a row
+            if block.as_ref().map(|block| block.id() != &tr.block_id).unwrap_or(true) {
+                let handle = self.load_block_handle(&tr.block_id)?.ok_or_else(|| NodeError::Db(
+                    DbError::NotFound,
+                    format!("Cannot load handle for block {} of transaction", tr.block_id)
+                ))?;
+                block = Some(self.load_block(&handle).await?);
+            }
+            let cell = match &block {
+                Some(block) => block.find_transaction(account, tr.lt)?,
+                None => None
+            }.ok_or_else(|| error!(
+                "Transaction {:x} of account {}:{:x} is not found in block {}",
+                tr.hash, workchain_id, account, tr.block_id
+            ))?;
+            let boc = ton_types::serialize_toc(&cell)?;
+            result.push((tr, Some(boc)));
+        }
+        Ok(result)
+    }
+}
+
+// Every sink gets the record regardless of others' failures, all errors are reported together
+fn join_ext_db_results(results: Vec<Result<()>>) -> Result<()> {
+    let errors: Vec<String> = results.into_iter().filter_map(|r| r.err().map(|e| e.to_string())).collect();
+    if !errors.is_empty() {
+        fail!("{}", errors.join("; "))
+    }
+    Ok(())
 }
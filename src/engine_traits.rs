@@ -1,11 +1,23 @@
 use crate::{
-    block::{BlockStuff}, config::CollatorTestBundlesGeneralConfig, internal_db::BlockResult,
-    shard_state::ShardStateStuff,
-    network::{full_node_client::FullNodeOverlayClient},
+    block::{BlockStuff},
+    config_reload::ReloadReport,
+    config::{
+        ArchiveNodeConfig, ArchivesSyncConfig, CollatorTestBundlesGeneralConfig, StatesGcPolicy
+    },
+    full_node::{apply_stages::ApplyStage, key_block_prefetch::KeyBlockPrefetcher},
+    internal_db::{BlockResult, InternalDb, scrubber::QuarantinedProof, sync_cursor::SyncCursor},
+    shard_state::{ChangeKind, ShardStateStuff},
+    network::{
+        full_node_client::FullNodeOverlayClient, node_network::DhtStaticNodesUpdate,
+        peer_scores::PeerScore, validators_overlay::ValidatorsOverlay
+    },
     block_proof::BlockProofStuff,
-    types::top_block_descr::{TopBlockDescrStuff, TopBlockDescrId},
-    ext_messages::create_ext_message,
+    types::{states_cache::StatePin, top_block_descr::{TopBlockDescrStuff, TopBlockDescrId}},
+    ext_messages::{create_ext_message, ExtMessageStatus, MessagesPoolStats},
     jaeger,
+    validator::{
+        CollatorSettings, timings::ShardTimings, validator_group::ValidatorGroupStatusEvent
+    },
 };
 use adnl::common::{KeyId, KeyOption};
 use catchain::{
@@ -15,11 +27,11 @@ use catchain::{
 use overlay::{
     BroadcastSendInfo, OverlayId, OverlayShortId, QueriesConsumer, PrivateOverlayShortId
 };
-use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
-use storage::types::BlockHandle;
+use std::{collections::HashMap, sync::Arc, time::{Duration, SystemTime, UNIX_EPOCH}};
+use storage::{transactions_index_db::IndexedTransaction, types::BlockHandle};
 use ton_api::ton::ton_node::broadcast::BlockBroadcast;
 use ton_block::{AccountIdPrefixFull, BlockIdExt, Message, ShardIdent, signature::SigPubKey};
-use ton_types::{fail, Result, UInt256};
+use ton_types::{error, fail, AccountId, Result, UInt256};
 #[cfg(feature = "telemetry")]
 use crate::{
     full_node::telemetry::FullNodeTelemetry,
@@ -71,6 +83,21 @@ pub trait PrivateOverlayOperations: Sync + Send {
     ) -> Result<Arc<dyn CatchainOverlay + Send>>;
 
     fn stop_catchain_client(&self, overlay_short_id: &Arc<PrivateOverlayShortId>);
+
+    /// Starts private overlay of the validator list set before, returns None if it is started
+    fn start_validators_overlay(
+        &self,
+        validator_list_id: &UInt256,
+        consumer: Arc<dyn QueriesConsumer>
+    ) -> Result<Option<Arc<ValidatorsOverlay>>>;
+
+    fn stop_validators_overlay(&self, validator_list_id: &UInt256) -> bool;
+
+    fn validators_overlays(&self) -> Vec<Arc<ValidatorsOverlay>>;
+
+    /// Checks that given ADNL key is served by local ADNL node and is resolvable 
+    /// to the local address via DHT
+    async fn check_local_adnl_key(&self, adnl_id: &Arc<KeyId>) -> Result<()>;
 }
 
 // TODO make separate traits for read and write operations (may be critical and not etc.)
@@ -80,17 +107,85 @@ pub trait EngineOperations : Sync + Send {
 
     async fn processed_workchain(&self) -> Result<(bool, i32)> { Ok((true, 0)) }
 
+    // Partial mode: only tracked shards of processed workchain are applied
+    fn is_shard_tracked(&self, shard: &ShardIdent) -> bool { true }
+    fn relay_untracked_broadcasts(&self) -> bool {
+        unimplemented!()
+    }
+    // Archive and persistent state slices served at once, unless node is in archive node role
+    fn max_serve_queries(&self) -> usize {
+        unimplemented!()
+    }
+
+    fn check_shard_tracked(&self, shard: &ShardIdent) -> Result<()> {
+        if !self.is_shard_tracked(shard) {
+            fail!("Shard {} is not tracked by this node", shard)
+        }
+        Ok(())
+    }
+
+    fn is_account_tracked(&self, prefix: &AccountIdPrefixFull) -> bool { true }
+
+    // Takes top shards of processed workchain at each masterchain block
+    fn update_tracked_shards(&self, top_shards: &[ShardIdent]) {
+        unimplemented!()
+    }
+
+    fn tracked_shards_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Takes (shard, seqno lag, time lag) of shard client, returns shards to catch up
+    fn update_shard_lags(&self, lags: &[(ShardIdent, u32, u32)]) -> Vec<ShardIdent> {
+        unimplemented!()
+    }
+
+    fn shard_lags_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Critical tasks with their states and restarts
+    fn tasks_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Takes masterchain blocks applied by sync to estimate its ETA
+    fn update_sync_progress(&self, mc_seq_no: u32, gen_utime: u32) {}
+
+    fn sync_progress_status(&self) -> String {
+        unimplemented!()
+    }
+
+    fn key_block_prefetcher(&self) -> &KeyBlockPrefetcher {
+        unimplemented!()
+    }
+
+    fn report_apply_stage(&self, shard: &ShardIdent, stage: ApplyStage, elapsed: Duration) {}
+
+    fn apply_stages_status(&self) -> String {
+        unimplemented!()
+    }
+
     fn get_validator_status(&self) -> bool { unimplemented!() }
 
     fn validator_network(&self) -> Arc<dyn PrivateOverlayOperations> {
         unimplemented!()
     }
 
-    fn validation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    // Durations of validations of others' candidates and time of the last one
+    fn validation_status(&self) -> &ShardTimings {
+        unimplemented!()
+    }
+
+    // Durations of our own collations and time of the last one
+    fn collation_status(&self) -> &ShardTimings {
         unimplemented!()
     }
 
-    fn collation_status(&self) -> &lockfree::map::Map<ShardIdent, u64> {
+    // Recent validations of others' candidates by active validator groups
+    fn set_validation_history_status(&self, status: String) {}
+
+    fn validation_history_status(&self) -> String {
         unimplemented!()
     }
 
@@ -126,6 +221,15 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    // Private overlay of validator list for blocks propagation
+    fn start_validators_overlay(self: Arc<Self>, validator_list_id: &UInt256) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn stop_validators_overlay(&self, validator_list_id: &UInt256) {
+        unimplemented!()
+    }
+
     // Block related operations
 
     fn load_block_handle(&self, id: &BlockIdExt) -> Result<Option<Arc<BlockHandle>>> {
@@ -155,6 +259,18 @@ pub trait EngineOperations : Sync + Send {
     fn save_last_applied_mc_block_id(&self, last_mc_block: &BlockIdExt) -> Result<()> {
         unimplemented!()
     }
+    // Last applied masterchain block with its generation time, None before boot
+    fn load_last_applied_mc_block_time(&self) -> Result<Option<(Arc<BlockIdExt>, u32)>> {
+        let id = match self.load_last_applied_mc_block_id()? {
+            Some(id) => id,
+            None => return Ok(None)
+        };
+        let handle = self.load_block_handle(&id)?.ok_or_else(
+            || error!("Cannot load handle for block {}", id)
+        )?;
+        let gen_utime = handle.gen_utime()?;
+        Ok(Some((id, gen_utime)))
+    }
     async fn load_last_applied_mc_state_or_zerostate(&self) -> Result<ShardStateStuff> {
         match self.load_last_applied_mc_block_id()? {
             Some(block_id) => self.load_state(&block_id).await,
@@ -170,12 +286,30 @@ pub trait EngineOperations : Sync + Send {
     fn save_shard_client_mc_block_id(&self, id: &BlockIdExt) -> Result<()> {
         unimplemented!()
     }
+    fn load_sync_cursor(&self) -> Result<Option<SyncCursor>> {
+        unimplemented!()
+    }
+    fn save_sync_cursor(&self, cursor: &SyncCursor) -> Result<()> {
+        unimplemented!()
+    }
+    fn clear_sync_cursor(&self) -> Result<()> {
+        unimplemented!()
+    }
     async fn find_block_by_seq_no(&self, acc_pfx: &AccountIdPrefixFull, seqno: u32) -> Result<Arc<BlockHandle>> {
         unimplemented!()
     }
     async fn find_block_by_unix_time(&self, acc_pfx: &AccountIdPrefixFull, utime: u32) -> Result<Arc<BlockHandle>> {
         unimplemented!()
     }
+    // Top shard blocks of stored masterchain block
+    async fn load_mc_shard_blocks(&self, mc_seq_no: u32) -> Result<HashMap<ShardIdent, BlockIdExt>> {
+        let handle = self.find_block_by_seq_no(&AccountIdPrefixFull::any_masterchain(), mc_seq_no).await?;
+        self.load_block(&handle).await?.shards_blocks()
+    }
+    // Unlike find_block_by_seq_no asks neighbours if the block is not in DB
+    async fn find_block_by_seqno(&self, shard: &ShardIdent, seqno: u32) -> Result<Option<BlockIdExt>> {
+        unimplemented!()
+    }
     async fn find_block_by_lt(&self, acc_pfx: &AccountIdPrefixFull, lt: u64) -> Result<Arc<BlockHandle>> {
         unimplemented!()
     }
@@ -257,6 +391,10 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    async fn process_block_revoked_in_ext_db(&self, id: &BlockIdExt, reason: &str) -> Result<()> {
+        unimplemented!()
+    }
+
     async fn process_chain_range_in_ext_db(
         &self,
         chain_range: &ChainRange)
@@ -264,6 +402,13 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    async fn process_validator_group_status_in_ext_db(
+        &self,
+        event: &ValidatorGroupStatusEvent)
+    -> Result<()> {
+        unimplemented!()
+    }
+
     // State related operations
 
     async fn download_state(
@@ -283,6 +428,22 @@ pub trait EngineOperations : Sync + Send {
     async fn load_state(&self, block_id: &BlockIdExt) -> Result<ShardStateStuff> {
         unimplemented!()
     }
+    // Shared with other holders through the states cache, without copying
+    async fn load_state_cached(&self, block_id: &BlockIdExt) -> Result<Arc<ShardStateStuff>> {
+        unimplemented!()
+    }
+    // Accounts changed between states of two blocks of the same shard
+    async fn diff_accounts(
+        &self,
+        from: &BlockIdExt,
+        to: &BlockIdExt
+    ) -> Result<Vec<(AccountId, ChangeKind)>> {
+        unimplemented!()
+    }
+    // Pinned state is not evicted from the states cache until the pin is dropped
+    fn pin_state(&self, block_id: &BlockIdExt) -> Option<StatePin> {
+        None
+    }
     async fn load_persistent_state_size(&self, block_id: &BlockIdExt) -> Result<u64> {
         unimplemented!()
     }
@@ -359,6 +520,12 @@ pub trait EngineOperations : Sync + Send {
     fn is_validator(&self) -> bool {
         unimplemented!()
     }
+    fn set_validator_adnl_unreachable(&self, unreachable: bool) {
+        unimplemented!()
+    }
+    fn validator_adnl_unreachable(&self) -> bool {
+        unimplemented!()
+    }
 
     fn get_last_rotation_block_id(&self) -> Result<Option<BlockIdExt>> {
         unimplemented!()
@@ -404,6 +571,48 @@ pub trait EngineOperations : Sync + Send {
     fn complete_external_messages(&self, to_delay: Vec<UInt256>, to_delete: Vec<UInt256>) -> Result<()> {
         unimplemented!()
     }
+    async fn precheck_external_message(&self, message: &Message) -> Result<()> {
+        unimplemented!()
+    }
+    fn new_local_external_message(&self, id: UInt256, dst: AccountIdPrefixFull, data: &[u8]) {
+        unimplemented!()
+    }
+    fn external_messages_stats(&self) -> MessagesPoolStats {
+        unimplemented!()
+    }
+    // Ids and importance scores of messages which will be given to collator first
+    fn external_messages_queue_head(&self, limit: usize) -> Vec<(UInt256, u128)> {
+        unimplemented!()
+    }
+    fn set_external_message_status(&self, id: &UInt256, status: ExtMessageStatus) {
+        unimplemented!()
+    }
+    // Last known status of message and time it was set at
+    fn external_message_status(&self, id: &UInt256) -> Option<(ExtMessageStatus, u32)> {
+        unimplemented!()
+    }
+    fn external_messages_recent_statuses(&self, limit: usize) -> Vec<(UInt256, ExtMessageStatus, u32)> {
+        unimplemented!()
+    }
+
+    // External DB
+
+    fn external_db_stats(&self) -> Vec<String> {
+        unimplemented!()
+    }
+
+    fn external_db_filtered_records(&self) -> u64 {
+        unimplemented!()
+    }
+
+    fn external_db_health(&self) -> String {
+        unimplemented!()
+    }
+
+    // Changes workchains written by external dbs without restart
+    fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>) -> Result<()> {
+        unimplemented!()
+    }
 
     // Utils
 
@@ -427,9 +636,16 @@ pub trait EngineOperations : Sync + Send {
 
     // Options
 
-    fn get_last_fork_masterchain_seqno(&self) -> u32 { 0 }
+    // Masterchain hardfork blocks of global config in seqno order
+    fn get_hardforks(&self) -> &[BlockIdExt] { &[] }
 
-    fn get_hardforks(&self) { todo!("WTF") }
+    fn get_last_fork_masterchain_seqno(&self) -> u32 {
+        self.get_hardforks().last().map(|id| id.seq_no()).unwrap_or(0)
+    }
+
+    fn is_hardfork(&self, id: &BlockIdExt) -> bool {
+        self.get_hardforks().contains(id)
+    }
 
     // True to allow sync from initial block, but it fail if it is not key block
     fn initial_sync_disabled(&self) -> bool { false } 
@@ -460,6 +676,10 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    fn zero_state_id(&self) -> &BlockIdExt {
+        unimplemented!()
+    }
+
     fn save_init_mc_block_id(&self, _init_block_id: &BlockIdExt) -> Result<()> {
         unimplemented!()
     }
@@ -468,6 +688,14 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    fn collator_settings(&self) -> CollatorSettings {
+        CollatorSettings::default()
+    }
+
+    fn set_collator_settings(&self, settings: CollatorSettings) {
+        unimplemented!()
+    }
+
     fn db_root_dir(&self) -> Result<&str> {
         Ok("node_db")
     }
@@ -480,6 +708,127 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    // Starts full compaction of given internal database (or all ones) in background.
+    // Returns false if it is skipped because another compaction is in flight.
+    fn compact_db(&self, db: Option<String>) -> Result<bool> {
+        unimplemented!()
+    }
+
+    fn db_compaction_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Starts online backup of internal DB into given new directory.
+    // Returns false if it is skipped because another backup is in flight.
+    fn create_backup(&self, path: String) -> Result<bool> {
+        unimplemented!()
+    }
+
+    fn db_backup_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Background verification of stored proofs and proofs found corrupted by it
+    fn db_scrubber_status(&self) -> String {
+        unimplemented!()
+    }
+
+    fn db_scrubber_quarantine(&self) -> Vec<QuarantinedProof> {
+        unimplemented!()
+    }
+
+    // Persistent states being downloaded by boot
+    fn state_downloads_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // KB/s, 0 - unlimited
+    fn set_bandwidth_limits(&self, download_kb_per_sec: u64, serve_kb_per_sec: u64) {
+        unimplemented!()
+    }
+
+    fn bandwidth_limits_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Waits if data served to other nodes exceeds the limit
+    async fn consume_serve_bandwidth(&self, bytes: usize) {
+        unimplemented!()
+    }
+
+    // Download scores and bans of peers, the worst first
+    fn peer_scores(&self) -> Vec<(Arc<KeyId>, PeerScore)> {
+        unimplemented!()
+    }
+
+    // Node follows DB of another node and changes nothing
+    fn is_read_only(&self) -> bool {
+        unimplemented!()
+    }
+
+    fn check_db_writable(&self) -> Result<()> {
+        unimplemented!()
+    }
+
+    fn set_validator_runtime_workers(&self, workers: usize) {}
+
+    // Worker threads of node's and validator groups' runtimes
+    fn runtime_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Resource profile and the settings it resolves to
+    fn resource_profile(&self) -> String {
+        unimplemented!()
+    }
+
+    // Re-reads config.json and applies changed values which can be changed at runtime
+    fn reload_config(&self) -> Result<ReloadReport> {
+        unimplemented!()
+    }
+
+    // Re-reads global config, DHT static nodes are added and removed according to it
+    fn reload_global_config(&self) -> Result<DhtStaticNodesUpdate> {
+        unimplemented!()
+    }
+
+    fn set_states_gc_policy(&self, policy: &StatesGcPolicy) {
+        unimplemented!()
+    }
+
+    fn states_gc_status(&self) -> String {
+        unimplemented!()
+    }
+
+    fn adjust_cells_cache_size(&self, size_mb: u64) {
+        unimplemented!()
+    }
+
+    fn cells_cache_status(&self) -> String {
+        unimplemented!()
+    }
+
+    fn states_cache_status(&self) -> String {
+        unimplemented!()
+    }
+
+    fn persistent_states_status(&self) -> String {
+        unimplemented!()
+    }
+
+    // Up to `limit` transactions of account with lt not greater than `from_lt` (the newest
+    // first) from transactions index, with BOCs if `with_boc` is set
+    async fn load_account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: usize,
+        with_boc: bool
+    ) -> Result<Vec<(IndexedTransaction, Option<Vec<u8>>)>> {
+        unimplemented!()
+    }
+
     // I/O
 
     async fn broadcast_to_public_overlay(
@@ -494,6 +843,11 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    // Broadcast of other node's block, it isn't mirrored into validators overlays
+    async fn relay_block_broadcast(&self, broadcast: BlockBroadcast) -> Result<()> {
+        unimplemented!()
+    }
+
     async fn send_top_shard_block_description(
         &self,
         tbd: Arc<TopBlockDescrStuff>,
@@ -503,15 +857,28 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
-    async fn redirect_external_message(&self, message_data: &[u8]) -> Result<BroadcastSendInfo> {
+    // Precheck may be skipped by tools which intentionally send messages valid in future only
+    async fn redirect_external_message(
+        &self,
+        message_data: &[u8],
+        skip_precheck: bool
+    ) -> Result<BroadcastSendInfo> {
         let (id, message) = create_ext_message(message_data)?;
+        if !skip_precheck {
+            if let Err(e) = self.precheck_external_message(&message).await {
+                self.set_external_message_status(&id, ExtMessageStatus::Rejected(e.to_string()));
+                return Err(e)
+            }
+        }
         let message = Arc::new(message);
         self.new_external_message(id.clone(), message.clone())?;
         if let Some(header) = message.ext_in_header() {
-            let res = self.broadcast_to_public_overlay(
-                &AccountIdPrefixFull::checked_prefix(&header.dst)?,
-                message_data
-            ).await;
+            let dst = AccountIdPrefixFull::checked_prefix(&header.dst)?;
+            let res = self.broadcast_to_public_overlay(&dst, message_data).await;
+            if res.is_ok() {
+                self.set_external_message_status(&id, ExtMessageStatus::Broadcast);
+            }
+            self.new_local_external_message(id.clone(), dst, message_data);
             #[cfg(feature = "telemetry")]
             self.full_node_telemetry().sent_ext_msg_broadcast();
             jaeger::broadcast_sended(id.to_hex_string());
@@ -547,6 +914,14 @@ pub trait EngineOperations : Sync + Send {
         unimplemented!()
     }
 
+    fn archives_sync_config(&self) -> ArchivesSyncConfig {
+        unimplemented!()
+    }
+
+    fn archive_node_config(&self) -> ArchiveNodeConfig {
+        unimplemented!()
+    }
+
     #[cfg(feature = "telemetry")]
     fn full_node_telemetry(&self) -> &FullNodeTelemetry {
         unimplemented!()
@@ -578,6 +953,7 @@ pub trait EngineOperations : Sync + Send {
     }
 }
 
+#[derive(Clone)]
 pub struct ChainRange {
     pub master_block: BlockIdExt,
     pub shard_blocks: Vec<BlockIdExt>
@@ -595,4 +971,22 @@ pub trait ExternalDb : Sync + Send {
     async fn process_full_state(&self, state: &ShardStateStuff) -> Result<()>;
     fn process_chain_range_enabled(&self) -> bool;
     async fn process_chain_range(&self, range: &ChainRange) -> Result<()>;
+    async fn process_validator_group_status(&self, event: &ValidatorGroupStatusEvent) -> Result<()>;
+    async fn process_block_revoked(&self, id: &BlockIdExt, reason: &str) -> Result<()>;
+    fn process_ext_msg_status_enabled(&self) -> bool;
+    async fn process_ext_msg_status(&self, id: &UInt256, status: &ExtMessageStatus, updated_at: u32) -> Result<()>;
+    // Checks that records can be delivered
+    async fn healthcheck(&self, timeout: std::time::Duration) -> Result<()>;
+    // Resolves when all records of processed block are delivered
+    async fn flush_block(&self, block_id: &BlockIdExt) -> Result<()>;
+    // Resolves when all written records are delivered, called on shutdown
+    async fn flush(&self) -> Result<()>;
+    // Producers' statistics: retry queues, retries and dead-lettered records
+    fn stats(&self) -> Vec<String>;
+    // Count of messages and transactions skipped by filters since start
+    fn filtered_records(&self) -> u64;
+    // Workchains to write, None - all
+    fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>);
+    // Storage of feeds' counters, set by engine when internal DB is opened
+    fn set_feed_storage(&self, storage: Arc<dyn InternalDb>);
 }
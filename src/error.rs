@@ -1,3 +1,72 @@
+use std::fmt;
+use storage::StorageError;
+
+// Code of error which is not NodeError
+pub const ERROR_CODE_UNKNOWN: u32 = 0;
+
+// Kind of error of the subsystem with its stable code. Codes are given to control server
+// clients and metrics, so they are never renumbered or reused for another kind.
+macro_rules! error_kind {
+    ($name:ident { $($kind:ident = $code:expr, $text:expr;)* }) => {
+        #[derive(Clone, Copy, Debug, PartialEq)]
+        pub enum $name {
+            $($kind),*
+        }
+        impl $name {
+            pub fn code(&self) -> u32 {
+                match self {
+                    $($name::$kind => $code),*
+                }
+            }
+        }
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(match self {
+                    $($name::$kind => $text),*
+                })
+            }
+        }
+    }
+}
+
+error_kind!(DbError {
+    NotFound = 101, "DB data not found";
+    Corrupted = 102, "DB data corrupted";
+    ReadOnly = 103, "DB is read-only";
+    Failure = 104, "DB failure";
+});
+
+error_kind!(NetworkError {
+    NoNeighbours = 201, "no neighbours";
+    NotFound = 202, "not found by peer";
+    BadData = 203, "bad data from peer";
+    OutOfAttempts = 204, "out of attempts";
+});
+
+error_kind!(ValidatorError {
+    WrongValidatorSet = 301, "wrong validator set";
+    UnregisteredChain = 302, "unregistered chain";
+    NotActualMcState = 303, "masterchain state is not actual";
+});
+
+error_kind!(BootError {
+    WrongInitBlock = 401, "wrong init block";
+    Hardfork = 402, "hardfork";
+    NoState = 403, "no state to boot from";
+});
+
+error_kind!(ConfigError {
+    Invalid = 501, "invalid config";
+});
+
+error_kind!(ControlError {
+    BadQuery = 601, "bad control query";
+    NotAllowed = 602, "control query is not allowed";
+    Unsupported = 603, "unsupported control query";
+    NotReady = 604, "node is not ready";
+    InProgress = 605, "operation is already in progress";
+});
+
 #[derive(Debug, failure::Fail)]
 pub enum NodeError {
     #[fail(display = "Invalid argument: {}", 0)]
@@ -10,4 +79,131 @@ pub enum NodeError {
     ValidatorReject(String),
     #[fail(display = "{}", 0)]
     ValidatorSoftReject(String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Db(DbError, String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Network(NetworkError, String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Validator(ValidatorError, String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Boot(BootError, String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Config(ConfigError, String),
+    #[fail(display = "{}: {}", 0, 1)]
+    Control(ControlError, String),
+}
+
+impl NodeError {
+
+    pub fn code(&self) -> u32 {
+        match self {
+            NodeError::InvalidArg(_) => 1,
+            NodeError::InvalidData(_) => 2,
+            NodeError::InvalidOperation(_) => 3,
+            NodeError::ValidatorReject(_) => 4,
+            NodeError::ValidatorSoftReject(_) => 5,
+            NodeError::Db(kind, _) => kind.code(),
+            NodeError::Network(kind, _) => kind.code(),
+            NodeError::Validator(kind, _) => kind.code(),
+            NodeError::Boot(kind, _) => kind.code(),
+            NodeError::Config(kind, _) => kind.code(),
+            NodeError::Control(kind, _) => kind.code(),
+        }
+    }
+
+    pub fn from_error(err: &failure::Error) -> Option<&Self> {
+        err.downcast_ref::<Self>()
+    }
+
+    // Another attempt can't help with wrong request or broken local data
+    pub fn is_retriable(&self) -> bool {
+        !matches!(
+            self,
+            NodeError::InvalidArg(_) | NodeError::Config(..) |
+            NodeError::Db(DbError::Corrupted, _) | NodeError::Db(DbError::ReadOnly, _)
+        )
+    }
+}
+
+// Storage errors which don't get through internal DB are classified too
+pub fn code_of(err: &failure::Error) -> u32 {
+    if let Some(err) = NodeError::from_error(err) {
+        err.code()
+    } else if let Some(StorageError::KeyNotFound(..)) = err.downcast_ref::<StorageError>() {
+        DbError::NotFound.code()
+    } else {
+        ERROR_CODE_UNKNOWN
+    }
+}
+
+// Errors of other kinds are retried as before
+pub fn is_retriable(err: &failure::Error) -> bool {
+    NodeError::from_error(err).map(|err| err.is_retriable()).unwrap_or(true)
+}
+
+// Error of storage given out by internal DB, NodeError raised by the node itself is kept.
+// Text of storage error stays in the context ("Key not found" is looked for by telemetry).
+pub fn db_error(err: failure::Error, context: impl fmt::Display) -> failure::Error {
+    if NodeError::from_error(&err).is_some() {
+        return err
+    }
+    let kind = match err.downcast_ref::<StorageError>() {
+        Some(StorageError::KeyNotFound(..)) => DbError::NotFound,
+        _ => DbError::Failure
+    };
+    NodeError::Db(kind, format!("{}: {}", context, err)).into()
+}
+
+#[test]
+fn test_error_codes_stability() {
+    let s = String::new;
+    let codes = vec![
+        (NodeError::InvalidArg(s()), 1),
+        (NodeError::InvalidData(s()), 2),
+        (NodeError::InvalidOperation(s()), 3),
+        (NodeError::ValidatorReject(s()), 4),
+        (NodeError::ValidatorSoftReject(s()), 5),
+        (NodeError::Db(DbError::NotFound, s()), 101),
+        (NodeError::Db(DbError::Corrupted, s()), 102),
+        (NodeError::Db(DbError::ReadOnly, s()), 103),
+        (NodeError::Db(DbError::Failure, s()), 104),
+        (NodeError::Network(NetworkError::NoNeighbours, s()), 201),
+        (NodeError::Network(NetworkError::NotFound, s()), 202),
+        (NodeError::Network(NetworkError::BadData, s()), 203),
+        (NodeError::Network(NetworkError::OutOfAttempts, s()), 204),
+        (NodeError::Validator(ValidatorError::WrongValidatorSet, s()), 301),
+        (NodeError::Validator(ValidatorError::UnregisteredChain, s()), 302),
+        (NodeError::Validator(ValidatorError::NotActualMcState, s()), 303),
+        (NodeError::Boot(BootError::WrongInitBlock, s()), 401),
+        (NodeError::Boot(BootError::Hardfork, s()), 402),
+        (NodeError::Boot(BootError::NoState, s()), 403),
+        (NodeError::Config(ConfigError::Invalid, s()), 501),
+        (NodeError::Control(ControlError::BadQuery, s()), 601),
+        (NodeError::Control(ControlError::NotAllowed, s()), 602),
+        (NodeError::Control(ControlError::Unsupported, s()), 603),
+        (NodeError::Control(ControlError::NotReady, s()), 604),
+        (NodeError::Control(ControlError::InProgress, s()), 605),
+    ];
+    for (err, code) in codes {
+        let text = err.to_string();
+        let err: failure::Error = err.into();
+        assert_eq!(code_of(&err), code, "{}", text);
+    }
+    assert_eq!(code_of(&failure::err_msg("some error")), ERROR_CODE_UNKNOWN);
+}
+
+#[test]
+fn test_db_error() {
+    let not_found = StorageError::KeyNotFound("BlockIdExt", "-1:8000".to_string());
+    let err = db_error(not_found.into(), "prev1_block_db");
+    assert_eq!(code_of(&err), 101);
+    assert_eq!(err.to_string(), "DB data not found: prev1_block_db: Key not found: BlockIdExt(-1:8000)");
+    assert!(is_retriable(&err));
+
+    let err = db_error(NodeError::Db(DbError::Corrupted, "block".to_string()).into(), "archives");
+    assert_eq!(err.to_string(), "DB data corrupted: block");
+    assert!(!is_retriable(&err));
+
+    assert_eq!(code_of(&db_error(StorageError::DbIsDropped.into(), "cells_db")), 104);
+    assert!(is_retriable(&failure::err_msg("timeout")));
 }
@@ -1,18 +1,37 @@
+use crate::{
+    config::{ExtMessagesConfig, ExtMessagesOrdering}, engine::STATSD, error::NodeError, 
+    internal_db::InternalDb, shard_state::ShardStateStuff, types::lru_cache::LruCache
+};
+#[cfg(feature = "external_db")]
+use crate::engine_traits::ExternalDb;
 use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt,
     io::Cursor,
-    sync::{Arc, atomic::{AtomicU64, Ordering}}
+    sync::{Arc, Mutex, atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering}}
+};
+use adnl::common::KeyId;
+use ton_block::{Deserializable, Serializable, BlockIdExt, ShardIdent, Message, AccountIdPrefixFull};
+use ton_executor::{
+    BlockchainConfig, ExecuteParams, ExecutorError, OrdinaryTransactionExecutor, TransactionExecutor
 };
-use ton_block::{Deserializable, ShardIdent, Message, AccountIdPrefixFull};
-use ton_types::{Result, types::UInt256, deserialize_tree_of_cells, fail};
+use ton_types::{Result, types::UInt256, deserialize_tree_of_cells, error, fail};
 
 
-const MESSAGE_LIFETIME: u32 = 600; // seconds
 const MESSAGE_MAX_GENERATIONS: u8 = 2;
 const MAX_EXTERNAL_MESSAGE_DEPTH: u16 = 512;
 const MAX_EXTERNAL_MESSAGE_SIZE: usize = 65535;
+// messages given to collator are not evicted during this time
+const COLLATION_LEASE_SEC: u32 = 10;
 
 struct MessageKeeper {
     message: Arc<Message>,
+    dst: String,
+    source: Option<Arc<KeyId>>,
+    score: u128,
+    seqno: u64, // order of arrival
+    size: usize, // serialized size
 
     // active: bool,            0x1_00_00000000
     // generation: u8,          0x0_ff_00000000
@@ -20,18 +39,35 @@ struct MessageKeeper {
     atomic_storage: AtomicU64,
 
     delete_at: u32,
+    persisted: AtomicBool,
+    leased_until: AtomicU32, // time message is given to collator until
 }
 
 impl MessageKeeper {
 
-    pub fn new(message: Arc<Message>, now: u32) -> Self {
+    pub fn new(
+        message: Arc<Message>,
+        dst: String,
+        source: Option<Arc<KeyId>>,
+        score: u128,
+        seqno: u64,
+        size: usize,
+        delete_at: u32
+    ) -> Self {
         let mut atomic_storage = 0;
         Self::set_active(&mut atomic_storage, true);
         
         Self {
             message,
+            dst,
+            source,
+            score,
+            seqno,
+            size,
             atomic_storage: AtomicU64::new(atomic_storage),
-            delete_at: now + MESSAGE_LIFETIME
+            delete_at,
+            persisted: AtomicBool::new(false),
+            leased_until: AtomicU32::new(0),
         }
     }
 
@@ -80,6 +116,14 @@ impl MessageKeeper {
         self.delete_at <= now
     }
 
+    pub fn lease(&self, until: u32) {
+        self.leased_until.store(until, Ordering::Relaxed);
+    }
+
+    pub fn leased(&self, now: u32) -> bool {
+        self.leased_until.load(Ordering::Relaxed) > now
+    }
+
     fn fetch_active(atomic_storage: u64) -> bool { 
         atomic_storage & 0x1_00_00000000 != 0
     }
@@ -108,28 +152,256 @@ impl MessageKeeper {
     }
 }
 
+#[derive(Default)]
+struct MessagesPoolCounters {
+    received: AtomicU64,
+    duplicated: AtomicU64,
+    rejected: AtomicU64,
+    expired: AtomicU64,
+    evicted: AtomicU64,
+}
+
+pub struct MessagesPoolStats {
+    pub pending: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+    pub received: u64,
+    pub duplicated: u64,
+    pub rejected: u64,
+    pub expired: u64,
+    pub evicted: u64,
+}
+
+impl fmt::Display for MessagesPoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "pending: {}, bytes: {} of {}, received: {}, duplicated: {}, rejected: {}, \
+                expired: {}, evicted: {}",
+            self.pending, self.bytes, self.max_bytes, self.received, self.duplicated, 
+            self.rejected, self.expired, self.evicted
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExtMessageStatus {
+    Received,
+    Broadcast,
+    Collated { shard: ShardIdent, seq_no: u32 },
+    Committed { block_id: BlockIdExt, tx_hash: UInt256 },
+    Expired,
+    Rejected(String),
+}
+
+impl fmt::Display for ExtMessageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtMessageStatus::Received => write!(f, "received"),
+            ExtMessageStatus::Broadcast => write!(f, "broadcast"),
+            ExtMessageStatus::Collated { shard, seq_no } => 
+                write!(f, "collated into candidate {}, seqno {}", shard, seq_no),
+            ExtMessageStatus::Committed { block_id, tx_hash } => 
+                write!(f, "committed in block {}, transaction {:x}", block_id, tx_hash),
+            ExtMessageStatus::Expired => write!(f, "expired"),
+            ExtMessageStatus::Rejected(reason) => write!(f, "rejected: {}", reason),
+        }
+    }
+}
+
+impl ExtMessageStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ExtMessageStatus::Received => "received",
+            ExtMessageStatus::Broadcast => "broadcast",
+            ExtMessageStatus::Collated { .. } => "collated",
+            ExtMessageStatus::Committed { .. } => "committed",
+            ExtMessageStatus::Expired => "expired",
+            ExtMessageStatus::Rejected(_) => "rejected",
+        }
+    }
+}
+
+// Message accepted by this node (not received from network), 
+// it is re-broadcast until it is committed
+struct LocalMessage {
+    dst: AccountIdPrefixFull,
+    data: Arc<Vec<u8>>,
+    attempts: AtomicU32,
+    next_broadcast_at: AtomicU32,
+}
+
+#[derive(Default)]
+struct PendingSlots {
+    per_dst: HashMap<String, usize>,
+    per_src: HashMap<Arc<KeyId>, usize>,
+}
+
 pub struct MessagesPool {
-    messages: lockfree::map::Map<UInt256, MessageKeeper>
+    messages: lockfree::map::Map<UInt256, MessageKeeper>,
+    // hashes of recently received messages (including already collated ones) 
+    // with time they were received at
+    seen: LruCache<UInt256, u32>,
+    // numbers of pending messages per destination account and per source peer
+    pending: Mutex<PendingSlots>,
+    local: lockfree::map::Map<UInt256, LocalMessage>,
+    // last known status of recent messages with time it was set at
+    statuses: LruCache<UInt256, (ExtMessageStatus, u32)>,
+    counters: MessagesPoolCounters,
+    next_seqno: AtomicU64,
+    priority_weights: HashMap<String, u32>,
+    // pending messages are saved here if persistence is enabled
+    storage: Option<Arc<dyn InternalDb>>,
+    persisted: AtomicUsize,
+    // total serialized size of pending messages
+    bytes: AtomicUsize,
+    config: ExtMessagesConfig,
+    // status transitions are sent to external db in order they happened
+    #[cfg(feature = "external_db")]
+    status_feed: Option<tokio::sync::mpsc::UnboundedSender<(UInt256, ExtMessageStatus, u32)>>,
 }
 
 impl MessagesPool {
 
-    pub fn new() -> Self {
-        Self{ messages: lockfree::map::Map::new() }
+    pub fn new(config: ExtMessagesConfig, storage: Option<Arc<dyn InternalDb>>) -> Result<Self> {
+        Ok(Self {
+            messages: lockfree::map::Map::new(),
+            seen: LruCache::new(config.dedup_capacity),
+            pending: Mutex::new(PendingSlots::default()),
+            local: lockfree::map::Map::new(),
+            statuses: LruCache::new(config.status_capacity),
+            counters: MessagesPoolCounters::default(),
+            next_seqno: AtomicU64::new(0),
+            priority_weights: config.priority_weights()?,
+            storage,
+            persisted: AtomicUsize::new(0),
+            bytes: AtomicUsize::new(0),
+            config,
+            #[cfg(feature = "external_db")]
+            status_feed: None,
+        })
+    }
+
+    /// Starts publishing of status transitions into external databases which support it
+    #[cfg(feature = "external_db")]
+    pub fn with_status_feed(mut self, ext_db: &[Arc<dyn ExternalDb>]) -> Self {
+        let ext_db: Vec<_> = ext_db.iter()
+            .filter(|db| db.process_ext_msg_status_enabled())
+            .cloned()
+            .collect();
+        if ext_db.is_empty() {
+            return self
+        }
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            // Single worker keeps order of transitions of each message
+            while let Some((id, status, updated_at)) = receiver.recv().await {
+                for db in ext_db.iter() {
+                    if let Err(e) = db.process_ext_msg_status(&id, &status, updated_at).await {
+                        log::warn!("Can't write status of external message {:x} into external db: {}", id, e);
+                    }
+                }
+            }
+        });
+        self.status_feed = Some(sender);
+        self
     }
 
+    /// Loads messages saved before restart, expired and broken ones are deleted from db.
+    /// Returns number of restored messages.
+    pub fn restore(&self, now: u32) -> Result<usize> {
+        let storage = match self.storage.as_ref() {
+            Some(storage) => storage,
+            None => return Ok(0)
+        };
+        let mut restored = 0;
+        for (id, data) in storage.load_all_ext_messages()? {
+            match self.restore_message(&id, &data, now) {
+                Ok(true) => {
+                    restored += 1;
+                    continue
+                }
+                Ok(false) => log::trace!("Saved external message {:x} is expired", id),
+                Err(e) => log::warn!("Can't restore saved external message {:x}: {}", id, e)
+            }
+            storage.remove_ext_message(&id)?;
+        }
+        self.persisted.store(restored, Ordering::Relaxed);
+        Ok(restored)
+    }
 
-    pub fn new_message_raw(&self, data: &[u8], now: u32) -> Result<UInt256> {
+    pub fn stats(&self) -> MessagesPoolStats {
+        MessagesPoolStats {
+            pending: self.messages.iter().count(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            max_bytes: self.config.max_total_bytes,
+            received: self.counters.received.load(Ordering::Relaxed),
+            duplicated: self.counters.duplicated.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            expired: self.counters.expired.load(Ordering::Relaxed),
+            evicted: self.counters.evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn config(&self) -> &ExtMessagesConfig {
+        &self.config
+    }
+
+
+    pub fn new_message_raw(&self, data: &[u8], source: Option<Arc<KeyId>>, now: u32) -> Result<UInt256> {
         let (id, message) = create_ext_message(data)?;
         let message = Arc::new(message);
 
-        self.new_message(id.clone(), message, now)?;
+        self.new_message(id.clone(), message, source, now)?;
         Ok(id)
     }
 
 
-    pub fn new_message(&self, id: UInt256, message: Arc<Message>, now: u32) -> Result<()> {
-        self.messages.insert_with(id, |_key, prev_gen_val, updated_pair | {
+    pub fn new_message(
+        &self,
+        id: UInt256,
+        message: Arc<Message>,
+        source: Option<Arc<KeyId>>,
+        now: u32
+    ) -> Result<()> {
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+        if self.is_duplicate(&id, now) {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+            log::trace!("Duplicated external message {:x} dropped", id);
+            return Ok(())
+        }
+        let boc = message.write_to_bytes()?;
+        let dst = message.dst_ref().map(|dst| dst.to_string()).unwrap_or_default();
+        if let Err(e) = self.acquire_slot(&dst, source.as_ref()) {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+            log::debug!("External message {:x} rejected: {}", id, e);
+            self.set_status(&id, ExtMessageStatus::Rejected(e.to_string()), now);
+            return Err(e)
+        }
+        let delete_at = self.delete_at(&message, now);
+        if self.insert_message(&id, &message, &dst, source.as_ref(), boc.len(), delete_at) {
+            self.seen.insert(id.clone(), now);
+            self.set_status(&id, ExtMessageStatus::Received, now);
+            self.persist_message(&id, &boc, delete_at);
+            self.enforce_memory_budget(now);
+        } else {
+            // other thread has added the same message
+            self.release_slot(&dst, source.as_ref());
+        }
+
+        Ok(())
+    }
+
+    // Returns true if message was inserted, false if it is already in the pool
+    fn insert_message(
+        &self,
+        id: &UInt256,
+        message: &Arc<Message>,
+        dst: &str,
+        source: Option<&Arc<KeyId>>,
+        size: usize,
+        delete_at: u32
+    ) -> bool {
+        let insertion = self.messages.insert_with(id.clone(), |_key, prev_gen_val, updated_pair | {
             if updated_pair.is_some() {
                 // someone already added the value into map
                 // so discard this insertion attempt
@@ -140,13 +412,72 @@ impl MessagesPool {
             } else {
                 // there is not the value in the map - try to add.
                 // If other thread adding value the same time - the closure will be recalled
-                lockfree::map::Preview::New(MessageKeeper::new(Arc::clone(&message), now))
+                let score = self.score(message);
+                let seqno = self.next_seqno.fetch_add(1, Ordering::Relaxed);
+                lockfree::map::Preview::New(MessageKeeper::new(
+                    Arc::clone(message), dst.to_string(), source.cloned(), score, seqno, size, delete_at
+                ))
             }
         });
+        if let lockfree::map::Insertion::Created = insertion {
+            self.bytes.fetch_add(size, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
 
-        Ok(())
+    fn restore_message(&self, id: &UInt256, data: &[u8], now: u32) -> Result<bool> {
+        if data.len() < 4 {
+            fail!("saved data is too short")
+        }
+        let delete_at = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        if delete_at <= now {
+            return Ok(false)
+        }
+        let (_, message) = create_ext_message(&data[4..])?;
+        let message = Arc::new(message);
+        let dst = message.dst_ref().map(|dst| dst.to_string()).unwrap_or_default();
+        self.acquire_slot(&dst, None)?;
+        if self.insert_message(id, &message, &dst, None, data.len() - 4, delete_at) {
+            if let Some(guard) = self.messages.get(id) {
+                guard.val().persisted.store(true, Ordering::Relaxed);
+            }
+            self.seen.insert(id.clone(), now);
+            self.set_status(id, ExtMessageStatus::Received, now);
+            self.enforce_memory_budget(now);
+        } else {
+            self.release_slot(&dst, None);
+        }
+        Ok(true)
+    }
+
+    fn persist_message(&self, id: &UInt256, boc: &[u8], delete_at: u32) {
+        let storage = match self.storage.as_ref() {
+            Some(storage) => storage,
+            None => return
+        };
+        if self.persisted.load(Ordering::Relaxed) >= self.config.persist_max_count {
+            log::trace!("External message {:x} is not saved: persistence limit is reached", id);
+            return
+        }
+        let mut data = delete_at.to_le_bytes().to_vec();
+        data.extend_from_slice(boc);
+        match storage.save_ext_message(id, &data) {
+            Ok(_) => {
+                if let Some(guard) = self.messages.get(id) {
+                    guard.val().persisted.store(true, Ordering::Relaxed);
+                    self.persisted.fetch_add(1, Ordering::Relaxed);
+                } else if let Err(e) = storage.remove_ext_message(id) {
+                    // message was removed from pool while it was being saved
+                    log::warn!("Can't delete saved external message {:x}: {}", id, e);
+                }
+            }
+            Err(e) => log::warn!("Can't save external message {:x}: {}", id, e)
+        }
     }
 
+    /// Returns active messages for given shard ordered according to configured policy
     pub fn get_messages(&self, shard: &ShardIdent, now: u32) -> Result<Vec<(Arc<Message>, UInt256)>> {
         let mut result = vec!();
         for guard in self.messages.iter() {
@@ -154,34 +485,285 @@ impl MessagesPool {
                 if let Ok(prefix) = AccountIdPrefixFull::prefix(dst) {
                     if shard.contains_full_prefix(&prefix) {
                         if guard.val().expired(now) {
-                            self.messages.remove(guard.key());
+                            if self.remove_message(guard.key()) {
+                                self.counters.expired.fetch_add(1, Ordering::Relaxed);
+                                self.set_status(guard.key(), ExtMessageStatus::Expired, now);
+                            }
                         } else if guard.val().check_active(now) {
-                            result.push((guard.val().clone_message(), guard.key().clone()));
+                            guard.val().lease(now + COLLATION_LEASE_SEC);
+                            result.push((
+                                (Reverse(guard.val().score), guard.val().seqno),
+                                guard.val().clone_message(),
+                                guard.key().clone()
+                            ));
                         }
                     }
                 }
             }
         }
-        Ok(result)
+        result.sort_by_key(|(order, _, _)| *order);
+        Ok(result.into_iter().map(|(_, message, id)| (message, id)).collect())
+    }
+
+    /// Returns ids and scores of first `limit` messages in the order they are given to collator
+    pub fn queue_head(&self, limit: usize, now: u32) -> Vec<(UInt256, u128)> {
+        let mut result: Vec<_> = self.messages.iter()
+            .filter(|guard| !guard.val().expired(now))
+            .map(|guard| ((Reverse(guard.val().score), guard.val().seqno), guard.key().clone()))
+            .collect();
+        result.sort_by_key(|(order, _)| *order);
+        result.into_iter().take(limit).map(|((score, _), id)| (id, score.0)).collect()
     }
 
     pub fn complete_messages(&self, to_delay: Vec<UInt256>, to_delete: Vec<UInt256>, now: u32) -> Result<()> {
         for id in to_delete.iter() {
-            self.messages.remove(id);
+            self.remove_message(id);
         }
         for id in to_delay.iter() {
             if let Some(guard) = self.messages.get(id) {
                 if guard.val().can_postpone() {
                     guard.val().postpone(now);
-                } else {
-                    self.messages.remove(id);
+                } else if self.remove_message(id) {
+                    self.set_status(
+                        id, 
+                        ExtMessageStatus::Rejected("not processed after all postponements".to_string()),
+                        now
+                    );
                 }
             }
         }
         Ok(())
     }
 
+    /// Removes expired messages, returns number of removed ones
+    pub fn purge_expired(&self, now: u32) -> usize {
+        let mut purged = 0;
+        for guard in self.messages.iter() {
+            if guard.val().expired(now) && self.remove_message(guard.key()) {
+                self.set_status(guard.key(), ExtMessageStatus::Expired, now);
+                purged += 1;
+            }
+        }
+        self.counters.expired.fetch_add(purged as u64, Ordering::Relaxed);
+        purged
+    }
+
+    /// Remembers locally accepted message to re-broadcast it if it is not committed in time
+    pub fn new_local_message(&self, id: UInt256, dst: AccountIdPrefixFull, data: &[u8], now: u32) {
+        self.local.insert(id, LocalMessage {
+            dst,
+            data: Arc::new(data.to_vec()),
+            attempts: AtomicU32::new(0),
+            next_broadcast_at: AtomicU32::new(now + self.config.rebroadcast_interval_sec),
+        });
+    }
 
+    /// Returns local messages which should be broadcast again
+    pub fn local_messages_to_rebroadcast(&self, now: u32) -> Vec<(UInt256, AccountIdPrefixFull, Arc<Vec<u8>>)> {
+        let mut result = Vec::new();
+        for guard in self.local.iter() {
+            let local = guard.val();
+            if local.next_broadcast_at.load(Ordering::Relaxed) > now {
+                continue
+            }
+            if local.attempts.fetch_add(1, Ordering::Relaxed) >= self.config.rebroadcast_max_attempts {
+                log::debug!("External message {:x} is not committed after all re-broadcasts", guard.key());
+                self.local.remove(guard.key());
+                continue
+            }
+            local.next_broadcast_at.store(now + self.config.rebroadcast_interval_sec, Ordering::Relaxed);
+            result.push((guard.key().clone(), local.dst.clone(), local.data.clone()));
+        }
+        result
+    }
+
+    /// Processes messages committed in applied block: they are not needed anymore.
+    /// `messages` contains pairs of message hash and transaction hash.
+    pub fn messages_committed(&self, block_id: &BlockIdExt, messages: &[(UInt256, UInt256)], now: u32) {
+        for (id, tx_hash) in messages {
+            self.local.remove(id);
+            self.remove_message(id);
+            if self.statuses.contains_key(id) {
+                let status = ExtMessageStatus::Committed { 
+                    block_id: block_id.clone(), 
+                    tx_hash: tx_hash.clone() 
+                };
+                self.set_status(id, status, now);
+            }
+        }
+    }
+
+    /// Updates status of message. Committed message doesn't change status anymore.
+    pub fn set_status(&self, id: &UInt256, status: ExtMessageStatus, now: u32) {
+        if let Some((ExtMessageStatus::Committed { .. }, _)) = self.statuses.peek(id) {
+            return
+        }
+        log::trace!("External message {:x} status: {}", id, status);
+        #[cfg(feature = "external_db")]
+        if let Some(status_feed) = self.status_feed.as_ref() {
+            let _ = status_feed.send((id.clone(), status.clone(), now));
+        }
+        self.statuses.insert(id.clone(), (status, now));
+    }
+
+    /// Returns last known status of message and time it was set at
+    pub fn status(&self, id: &UInt256) -> Option<(ExtMessageStatus, u32)> {
+        self.statuses.peek(id)
+    }
+
+    /// Returns most recently updated statuses
+    pub fn recent_statuses(&self, limit: usize) -> Vec<(UInt256, ExtMessageStatus, u32)> {
+        self.statuses.recent(limit).into_iter()
+            .map(|(id, (status, updated_at))| (id, status, updated_at))
+            .collect()
+    }
+
+    fn remove_message(&self, id: &UInt256) -> bool {
+        match self.messages.remove(id) {
+            Some(removed) => {
+                self.release_slot(&removed.val().dst, removed.val().source.as_ref());
+                self.bytes.fetch_sub(removed.val().size, Ordering::Relaxed);
+                if removed.val().persisted.load(Ordering::Relaxed) {
+                    self.persisted.fetch_sub(1, Ordering::Relaxed);
+                    if let Some(storage) = self.storage.as_ref() {
+                        if let Err(e) = storage.remove_ext_message(id) {
+                            log::warn!("Can't delete saved external message {:x}: {}", id, e);
+                        }
+                    }
+                }
+                true
+            }
+            None => false
+        }
+    }
+
+    // Evicts lowest priority (and then oldest) messages while total size exceeds the budget.
+    // Messages given to collator are never evicted.
+    fn enforce_memory_budget(&self, now: u32) {
+        if self.bytes.load(Ordering::Relaxed) <= self.config.max_total_bytes {
+            return
+        }
+        let mut candidates: Vec<_> = self.messages.iter()
+            .filter(|guard| !guard.val().leased(now))
+            .map(|guard| ((guard.val().score, guard.val().seqno), guard.key().clone()))
+            .collect();
+        candidates.sort_by_key(|(order, _)| *order);
+        let mut evicted = 0;
+        for (_, id) in candidates {
+            if self.bytes.load(Ordering::Relaxed) <= self.config.max_total_bytes {
+                break
+            }
+            if self.remove_message(&id) {
+                self.local.remove(&id);
+                self.set_status(
+                    &id, 
+                    ExtMessageStatus::Rejected("evicted due to memory budget".to_string()), 
+                    now
+                );
+                evicted += 1;
+            }
+        }
+        if evicted > 0 {
+            log::debug!(
+                "Evicted {} external messages, pool size is {} bytes", 
+                evicted, self.bytes.load(Ordering::Relaxed)
+            );
+            self.counters.evicted.fetch_add(evicted, Ordering::Relaxed);
+            STATSD.count("ext_msg_evicted", evicted as f64);
+        }
+    }
+
+    fn acquire_slot(&self, dst: &str, source: Option<&Arc<KeyId>>) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        let dst_count = pending.per_dst.get(dst).cloned().unwrap_or_default();
+        if dst_count >= self.config.max_pending_per_dst {
+            fail!(
+                "too many pending external messages to account {} (limit {})",
+                dst, self.config.max_pending_per_dst
+            )
+        }
+        if let Some(source) = source {
+            let src_count = pending.per_src.get(source).cloned().unwrap_or_default();
+            if src_count >= self.config.max_pending_per_src {
+                fail!(
+                    "too many pending external messages from peer {} (limit {})",
+                    source, self.config.max_pending_per_src
+                )
+            }
+            *pending.per_src.entry(source.clone()).or_default() += 1;
+        }
+        *pending.per_dst.entry(dst.to_string()).or_default() += 1;
+        Ok(())
+    }
+
+    fn release_slot(&self, dst: &str, source: Option<&Arc<KeyId>>) {
+        let mut pending = self.pending.lock().unwrap();
+        if let Some(count) = pending.per_dst.get_mut(dst) {
+            *count -= 1;
+            if *count == 0 {
+                pending.per_dst.remove(dst);
+            }
+        }
+        if let Some(source) = source {
+            if let Some(count) = pending.per_src.get_mut(source) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.per_src.remove(source);
+                }
+            }
+        }
+    }
+
+    // Checks if message was already received recently
+    fn is_duplicate(&self, id: &UInt256, now: u32) -> bool {
+        if self.messages.get(id).is_some() {
+            return true
+        }
+        match self.seen.get(id) {
+            Some(seen_at) => seen_at + self.config.dedup_period_sec > now,
+            None => false
+        }
+    }
+
+    // Importance score: import fee multiplied by destination weight
+    fn score(&self, message: &Message) -> u128 {
+        if self.config.ordering == ExtMessagesOrdering::Arrival {
+            return 0
+        }
+        match message.ext_in_header() {
+            Some(header) => {
+                let weight = self.priority_weights.get(&header.dst.to_string()).cloned().unwrap_or(1);
+                (header.import_fee.0 + 1).saturating_mul(weight as u128)
+            }
+            None => 0
+        }
+    }
+
+    fn delete_at(&self, message: &Message, now: u32) -> u32 {
+        let delete_at = now + self.config.ttl_sec;
+        match declared_expire_at(message, now, self.config.ttl_sec) {
+            Some(expire_at) => expire_at.min(delete_at),
+            None => delete_at
+        }
+    }
+}
+
+// Best-effort lookup of expiration time declared in ABI-encoded message body
+// with default headers: maybe(signature: bits512) time: uint64 expire: uint32.
+// Values are trusted only if they are consistent with current time.
+fn declared_expire_at(message: &Message, now: u32, ttl: u32) -> Option<u32> {
+    let mut body = message.body()?;
+    if body.get_next_bit().ok()? {
+        body.get_next_bits(512).ok()?;
+    }
+    let time = (body.get_next_u64().ok()? / 1000) as u32;
+    let expire_at = body.get_next_u32().ok()?;
+    let time_diff = if time > now { time - now } else { now - time };
+    if time_diff > ttl || expire_at <= time {
+        None
+    } else {
+        Some(expire_at)
+    }
 }
 
 pub fn create_ext_message(data: &[u8]) -> Result<(UInt256, Message)> {
@@ -203,3 +785,182 @@ pub fn create_ext_message(data: &[u8]) -> Result<(UInt256, Message)> {
         fail!("External inbound message {} doesn't have proper header", root.repr_hash())
     }
 }
+
+/// Dry-runs external message against destination account from given shard state.
+/// Execution before the message is accepted is bounded by gas credit for external messages,
+/// so deterministically failing messages are detected cheaply.
+pub fn precheck_ext_message(
+    message: &Message,
+    shard_state: &ShardStateStuff,
+    mc_state: &ShardStateStuff,
+    now: u32
+) -> Result<()> {
+    let dst = message.dst_ref().ok_or_else(|| error!("External message has no destination"))?;
+    let (_, account_id) = dst.extract_std_address(true)?;
+    let shard_acc = match shard_state.state().read_accounts()?.account(&account_id)? {
+        Some(shard_acc) => shard_acc,
+        None => fail!(NodeError::InvalidData(format!("Destination account {} doesn't exist", dst)))
+    };
+    let mut account_root = shard_acc.account_cell();
+    let config = BlockchainConfig::with_config(mc_state.config_params()?.clone())?;
+    let params = ExecuteParams {
+        state_libs: mc_state.state().libraries().clone().inner(),
+        block_unixtime: now,
+        block_lt: shard_acc.last_trans_lt() + 1,
+        last_tr_lt: Arc::new(AtomicU64::new(shard_acc.last_trans_lt())),
+        ..ExecuteParams::default()
+    };
+    let executor = OrdinaryTransactionExecutor::new(config);
+    if let Err(e) = executor.execute_with_libs_and_params(Some(message), &mut account_root, params) {
+        match e.downcast_ref::<ExecutorError>() {
+            Some(ExecutorError::NoAcceptError(exit_code, _)) => fail!(NodeError::InvalidData(
+                format!("External message is not accepted by account {}, exit code {}", dst, exit_code)
+            )),
+            _ => fail!(NodeError::InvalidData(
+                format!("External message can't be executed by account {}: {}", dst, e)
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn create_test_message(dst: u8, body: u8) -> (UInt256, Arc<Message>) {
+    use ton_block::{ExternalInboundMessageHeader, MsgAddressInt, Serializable};
+    use ton_types::{AccountId, SliceData};
+
+    let header = ExternalInboundMessageHeader {
+        dst: MsgAddressInt::with_standart(None, 0, AccountId::from([dst; 32])).unwrap(),
+        ..Default::default()
+    };
+    let mut message = Message::with_ext_in_header(header);
+    message.set_body(SliceData::from_raw(vec![body], 8));
+    (message.serialize().unwrap().repr_hash(), Arc::new(message))
+}
+
+#[test]
+fn test_pending_limits() {
+    let mut config = ExtMessagesConfig::default();
+    config.ttl_sec = 10;
+    config.max_pending_per_dst = 2;
+    config.max_pending_per_src = 3;
+    let pool = MessagesPool::new(config, None).unwrap();
+    let source = KeyId::from_data([7; 32]);
+
+    let (id1, m1) = create_test_message(1, 1);
+    let (id2, m2) = create_test_message(1, 2);
+    let (id3, m3) = create_test_message(1, 3);
+    pool.new_message(id1.clone(), m1, None, 0).unwrap();
+    pool.new_message(id2, m2, None, 0).unwrap();
+    assert!(pool.new_message(id3.clone(), m3.clone(), None, 0).is_err());
+
+    // slot is freed when message is completed
+    pool.complete_messages(vec![], vec![id1], 0).unwrap();
+    pool.new_message(id3, m3, None, 0).unwrap();
+
+    // limit per source peer
+    for i in 0..3 {
+        let (id, m) = create_test_message(2 + i, 0);
+        pool.new_message(id, m, Some(source.clone()), 0).unwrap();
+    }
+    let (id, m) = create_test_message(5, 0);
+    assert!(pool.new_message(id.clone(), m.clone(), Some(source.clone()), 0).is_err());
+    assert_eq!(pool.stats().rejected, 2);
+
+    // slots are freed when messages expire
+    assert_eq!(pool.purge_expired(10), 5);
+    pool.new_message(id, m, Some(source), 10).unwrap();
+    let (id, m) = create_test_message(1, 4);
+    pool.new_message(id, m, None, 10).unwrap();
+    assert_eq!(pool.stats().pending, 2);
+}
+
+#[cfg(test)]
+fn create_test_message_expiring(dst: u8, time: u32, expire_at: u32) -> (UInt256, Arc<Message>) {
+    use ton_block::{ExternalInboundMessageHeader, MsgAddressInt};
+    use ton_types::{AccountId, BuilderData, SliceData};
+
+    let header = ExternalInboundMessageHeader {
+        dst: MsgAddressInt::with_standart(None, 0, AccountId::from([dst; 32])).unwrap(),
+        ..Default::default()
+    };
+    // ABI body without signature: time in milliseconds and expire time
+    let mut body = BuilderData::new();
+    body.append_bit_zero().unwrap();
+    body.append_u64(time as u64 * 1000).unwrap();
+    body.append_u32(expire_at).unwrap();
+    let mut message = Message::with_ext_in_header(header);
+    message.set_body(SliceData::from(body.into_cell().unwrap()));
+    (message.serialize().unwrap().repr_hash(), Arc::new(message))
+}
+
+#[test]
+fn test_messages_expiration() {
+    let mut config = ExtMessagesConfig::default();
+    config.ttl_sec = 100;
+    let pool = MessagesPool::new(config, None).unwrap();
+    let shard = ShardIdent::full(0);
+
+    // declared expiration is earlier than ttl
+    let (id, m) = create_test_message_expiring(2, 1000, 1030);
+    pool.new_message(id.clone(), m, None, 1000).unwrap();
+    let messages = vec![
+        create_test_message(1, 1),
+        // declared expiration is later than ttl
+        create_test_message_expiring(3, 1000, 2000),
+        // declared time is too far from now, so expiration is not trusted
+        create_test_message_expiring(4, 500, 1010),
+        // declared expiration is not later than declared time
+        create_test_message_expiring(5, 1000, 1000),
+    ];
+    for (id, m) in messages {
+        pool.new_message(id, m, None, 1000).unwrap();
+    }
+    assert_eq!(pool.get_messages(&shard, 1029).unwrap().len(), 5);
+    let rest = pool.get_messages(&shard, 1030).unwrap();
+    assert_eq!(rest.len(), 4);
+    assert!(rest.iter().all(|(_, rest_id)| *rest_id != id));
+    assert_eq!(pool.stats().expired, 1);
+
+    assert_eq!(pool.purge_expired(1099), 0);
+    assert_eq!(pool.purge_expired(1100), 4);
+    let stats = pool.stats();
+    assert_eq!(stats.expired, 5);
+    assert_eq!(stats.pending, 0);
+}
+
+#[test]
+fn test_messages_deduplication() {
+    let mut config = ExtMessagesConfig::default();
+    config.dedup_period_sec = 100;
+    config.dedup_capacity = 2;
+    let pool = MessagesPool::new(config, None).unwrap();
+
+    // duplicate of pending message
+    let (id1, m1) = create_test_message(1, 1);
+    pool.new_message(id1.clone(), m1.clone(), None, 0).unwrap();
+    pool.new_message(id1.clone(), m1.clone(), None, 1).unwrap();
+    assert_eq!(pool.stats().pending, 1);
+
+    // duplicate of already collated message within dedup period
+    pool.complete_messages(vec![], vec![id1.clone()], 2).unwrap();
+    pool.new_message(id1.clone(), m1.clone(), None, 99).unwrap();
+    assert_eq!(pool.stats().pending, 0);
+
+    // the same message is accepted again after dedup period
+    pool.new_message(id1.clone(), m1.clone(), None, 100).unwrap();
+    assert_eq!(pool.stats().pending, 1);
+
+    // hash of the collated message is evicted by newer ones, so it is accepted again
+    for i in 2..4 {
+        let (id, m) = create_test_message(i, 1);
+        pool.new_message(id, m, None, 100).unwrap();
+    }
+    pool.complete_messages(vec![], vec![id1.clone()], 101).unwrap();
+    pool.new_message(id1, m1, None, 101).unwrap();
+
+    let stats = pool.stats();
+    assert_eq!(stats.pending, 3);
+    assert_eq!(stats.received, 6);
+    assert_eq!(stats.duplicated, 2);
+}
@@ -0,0 +1,311 @@
+use crate::{
+    block::BlockStuff, block_proof::BlockProofStuff, engine::STATSD,
+    engine_traits::{ChainRange, ExternalDb}, ext_messages::ExtMessageStatus, internal_db::InternalDb,
+    shard_state::ShardStateStuff, validator::validator_group::ValidatorGroupStatusEvent,
+};
+use std::{sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Duration};
+use tokio::sync::mpsc;
+use ton_block::BlockIdExt;
+use ton_types::{error, fail, Result, UInt256};
+
+const FLUSH_CHECK_INTERVAL_MS: u64 = 10;
+const MAX_UNREPORTED_ERRORS: usize = 100;
+
+enum BufferedRecord {
+    Block {
+        block: BlockStuff,
+        proof: Option<BlockProofStuff>,
+        state: ShardStateStuff,
+    },
+    FullState(ShardStateStuff),
+    ChainRange(ChainRange),
+    ValidatorGroupStatus(ValidatorGroupStatusEvent),
+    BlockRevoked(BlockIdExt, String),
+    ExtMsgStatus(UInt256, ExtMessageStatus, u32),
+}
+
+// State of sink shared with its background worker
+#[derive(Default)]
+struct SinkState {
+    processed: AtomicU64,
+    failed: AtomicU64,
+    // Failures not yet returned to caller, block failures are reported by flush_block as well
+    unreported: Mutex<Vec<(Option<BlockIdExt>, String)>>,
+}
+
+impl SinkState {
+    fn add_error(&self, name: &str, block_id: Option<BlockIdExt>, error: String) {
+        log::error!("External db {}: {}", name, error);
+        self.failed.fetch_add(1, Ordering::Relaxed);
+        STATSD.incr(&format!("external_db_sink_errors_{}", name));
+        let mut unreported = self.unreported.lock().unwrap();
+        if unreported.len() >= MAX_UNREPORTED_ERRORS {
+            unreported.remove(0);
+        }
+        unreported.push((block_id, error));
+    }
+
+    fn take_errors(&self) -> Vec<String> {
+        self.unreported.lock().unwrap().drain(..).map(|(_, error)| error).collect()
+    }
+
+    fn take_block_errors(&self, block_id: &BlockIdExt) -> Vec<String> {
+        let mut unreported = self.unreported.lock().unwrap();
+        let mut errors = Vec::new();
+        unreported.retain(|(id, error)| {
+            if id.as_ref() == Some(block_id) {
+                errors.push(error.clone());
+                false
+            } else {
+                true
+            }
+        });
+        errors
+    }
+}
+
+// Sink which processes all its records in background, so failing or lagging sink 
+// doesn't affect other ones until its buffer is full. Failures are kept in sink's state:
+// flush_block reports failure of the block, and any call reports failures of previous records.
+pub(super) struct BufferedExternalDb {
+    name: String,
+    inner: Arc<dyn ExternalDb>,
+    sender: mpsc::Sender<BufferedRecord>,
+    queued: AtomicU64,
+    state: Arc<SinkState>,
+}
+
+impl BufferedExternalDb {
+
+    pub fn new(name: String, inner: Arc<dyn ExternalDb>, buffer_size: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<BufferedRecord>(buffer_size);
+        let state = Arc::new(SinkState::default());
+        let worker_inner = inner.clone();
+        let worker_state = state.clone();
+        let worker_name = name.clone();
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                let (block_id, result) = match record {
+                    BufferedRecord::Block { block, proof, state } => (
+                        Some(block.id().clone()),
+                        worker_inner.process_block(&block, proof.as_ref(), &state).await
+                            .map_err(|e| error!("can't process block {}: {}", block.id(), e))
+                    ),
+                    BufferedRecord::FullState(state) => (
+                        None,
+                        worker_inner.process_full_state(&state).await
+                            .map_err(|e| error!("can't process full state {}: {}", state.block_id(), e))
+                    ),
+                    BufferedRecord::ChainRange(range) => (
+                        None,
+                        worker_inner.process_chain_range(&range).await
+                            .map_err(|e| error!("can't process chain range {}: {}", range.master_block, e))
+                    ),
+                    BufferedRecord::ValidatorGroupStatus(event) => (
+                        None,
+                        worker_inner.process_validator_group_status(&event).await
+                            .map_err(|e| error!("can't process validator group status: {}", e))
+                    ),
+                    BufferedRecord::BlockRevoked(id, reason) => (
+                        None,
+                        worker_inner.process_block_revoked(&id, &reason).await
+                            .map_err(|e| error!("can't process revoked block {}: {}", id, e))
+                    ),
+                    BufferedRecord::ExtMsgStatus(id, status, updated_at) => (
+                        None,
+                        worker_inner.process_ext_msg_status(&id, &status, updated_at).await
+                            .map_err(|e| error!("can't process status of external message {}: {}", id.to_hex_string(), e))
+                    ),
+                };
+                if let Err(e) = result {
+                    worker_state.add_error(&worker_name, block_id, e.to_string());
+                }
+                worker_state.processed.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+        Self { name, inner, sender, queued: AtomicU64::new(0), state }
+    }
+
+    fn buffered(&self) -> u64 {
+        self.queued.load(Ordering::Relaxed).saturating_sub(self.state.processed.load(Ordering::Relaxed))
+    }
+
+    fn check_errors(&self, errors: Vec<String>) -> Result<()> {
+        if !errors.is_empty() {
+            fail!("External db {}: {}", self.name, errors.join("; "))
+        }
+        Ok(())
+    }
+
+    // Reports failures of previous records, then puts the record into buffer
+    async fn send(&self, record: BufferedRecord) -> Result<()> {
+        self.check_errors(self.state.take_errors())?;
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        self.sender.send(record).await.map_err(|_| {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            error!("External db {}: buffer is closed", self.name)
+        })
+    }
+
+    // Waits for all records queued before
+    async fn wait_processed(&self) {
+        let queued = self.queued.load(Ordering::Relaxed);
+        while self.state.processed.load(Ordering::Relaxed) < queued {
+            futures_timer::Delay::new(Duration::from_millis(FLUSH_CHECK_INTERVAL_MS)).await;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ExternalDb for BufferedExternalDb {
+
+    async fn process_block(
+        &self,
+        block: &BlockStuff,
+        proof: Option<&BlockProofStuff>,
+        state: &ShardStateStuff
+    ) -> Result<()> {
+        self.send(BufferedRecord::Block { block: block.clone(), proof: proof.cloned(), state: state.clone() }).await
+    }
+
+    async fn process_full_state(&self, state: &ShardStateStuff) -> Result<()> {
+        self.send(BufferedRecord::FullState(state.clone())).await
+    }
+
+    fn process_chain_range_enabled(&self) -> bool {
+        self.inner.process_chain_range_enabled()
+    }
+
+    async fn process_chain_range(&self, range: &ChainRange) -> Result<()> {
+        self.send(BufferedRecord::ChainRange(range.clone())).await
+    }
+
+    async fn process_validator_group_status(&self, event: &ValidatorGroupStatusEvent) -> Result<()> {
+        self.send(BufferedRecord::ValidatorGroupStatus(event.clone())).await
+    }
+
+    async fn process_block_revoked(&self, id: &BlockIdExt, reason: &str) -> Result<()> {
+        self.send(BufferedRecord::BlockRevoked(id.clone(), reason.to_string())).await
+    }
+
+    fn process_ext_msg_status_enabled(&self) -> bool {
+        self.inner.process_ext_msg_status_enabled()
+    }
+
+    async fn process_ext_msg_status(&self, id: &UInt256, status: &ExtMessageStatus, updated_at: u32) -> Result<()> {
+        self.send(BufferedRecord::ExtMsgStatus(id.clone(), status.clone(), updated_at)).await
+    }
+
+    async fn healthcheck(&self, timeout: Duration) -> Result<()> {
+        self.inner.healthcheck(timeout).await
+    }
+
+    async fn flush_block(&self, block_id: &BlockIdExt) -> Result<()> {
+        self.wait_processed().await;
+        self.check_errors(self.state.take_block_errors(block_id))?;
+        self.inner.flush_block(block_id).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.wait_processed().await;
+        self.check_errors(self.state.take_errors())?;
+        self.inner.flush().await
+    }
+
+    fn stats(&self) -> Vec<String> {
+        let mut stats = vec!(format!(
+            "sink: {}, buffered records: {}, failed records: {}",
+            self.name, self.buffered(), self.state.failed.load(Ordering::Relaxed)
+        ));
+        stats.extend(self.inner.stats());
+        stats
+    }
+
+    fn filtered_records(&self) -> u64 {
+        self.inner.filtered_records()
+    }
+
+    fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>) {
+        self.inner.set_front_workchain_ids(front_workchain_ids)
+    }
+
+    fn set_feed_storage(&self, storage: Arc<dyn InternalDb>) {
+        self.inner.set_feed_storage(storage)
+    }
+}
+
+#[cfg(test)]
+struct FailingRevokedDb;
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl ExternalDb for FailingRevokedDb {
+    async fn process_block(
+        &self,
+        _block: &BlockStuff,
+        _proof: Option<&BlockProofStuff>,
+        _state: &ShardStateStuff
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn process_full_state(&self, _state: &ShardStateStuff) -> Result<()> {
+        Ok(())
+    }
+    fn process_chain_range_enabled(&self) -> bool {
+        false
+    }
+    async fn process_chain_range(&self, _range: &ChainRange) -> Result<()> {
+        Ok(())
+    }
+    async fn process_validator_group_status(&self, _event: &ValidatorGroupStatusEvent) -> Result<()> {
+        Ok(())
+    }
+    async fn process_block_revoked(&self, id: &BlockIdExt, _reason: &str) -> Result<()> {
+        fail!("kafka is down, block {}", id.seq_no())
+    }
+    fn process_ext_msg_status_enabled(&self) -> bool {
+        false
+    }
+    async fn process_ext_msg_status(&self, _id: &UInt256, _status: &ExtMessageStatus, _updated_at: u32) -> Result<()> {
+        Ok(())
+    }
+    async fn healthcheck(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+    async fn flush_block(&self, _block_id: &BlockIdExt) -> Result<()> {
+        Ok(())
+    }
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    fn stats(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn filtered_records(&self) -> u64 {
+        0
+    }
+    fn set_front_workchain_ids(&self, _front_workchain_ids: Option<Vec<i32>>) {}
+    fn set_feed_storage(&self, _storage: Arc<dyn InternalDb>) {}
+}
+
+#[test]
+fn test_buffered_sink_reports_background_errors() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let db = BufferedExternalDb::new("test".to_string(), Arc::new(FailingRevokedDb), 4);
+        let id = BlockIdExt::with_params(
+            ton_block::ShardIdent::masterchain(), 7, UInt256::from([1; 32]), UInt256::default()
+        );
+        // Record is buffered, so its failure doesn't stop the caller
+        db.process_block_revoked(&id, "test").await.unwrap();
+        db.wait_processed().await;
+        assert_eq!(db.state.failed.load(Ordering::Relaxed), 1);
+        // and is reported by the next call only once
+        let error = db.process_block_revoked(&id, "test").await.unwrap_err();
+        assert!(error.to_string().contains("block 7"));
+        db.wait_processed().await;
+        assert!(db.flush().await.is_err());
+        db.flush().await.unwrap();
+        assert!(db.stats()[0].contains("failed records: 2"));
+    });
+}
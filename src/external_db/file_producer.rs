@@ -0,0 +1,289 @@
+use std::{
+    fs::{File, OpenOptions}, io::Write, path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}
+};
+use crate::{external_db::{BlockDelivery, WriteData}, config::{FilesDbConfig, FsyncPolicy, KafkaProducerConfig}};
+use ton_types::{Result, fail};
+
+const FILE_EXTENSION: &str = "ndjson";
+
+struct CurrentFile {
+    file: File,
+    index: u64,
+    size: u64,
+}
+
+// Writes records into size-rotated newline-delimited json files in <directory>/<topic>/
+pub(super) struct FileProducer {
+    files: Arc<TopicFiles>,
+}
+
+// Files are written and synced on blocking threads, never on runtime ones
+struct TopicFiles {
+    enabled: bool,
+    topic: String,
+    dir: PathBuf,
+    config: FilesDbConfig,
+    current: Mutex<Option<CurrentFile>>,
+    // Index of current file plus one, zero if there is no file yet
+    current_index: AtomicU64,
+    written: AtomicU64,
+}
+
+impl FileProducer {
+    pub fn new(files_config: &FilesDbConfig, config: KafkaProducerConfig) -> Result<Self> {
+        let dir = Path::new(&files_config.directory).join(&config.topic);
+        if config.enabled {
+            log::trace!("Creating file producer (topic: {}) in {}", config.topic, dir.display());
+            std::fs::create_dir_all(&dir)?;
+        } else {
+            log::trace!("File producer (topic: {}) is DISABLED", config.topic);
+        }
+        let files = TopicFiles {
+            enabled: config.enabled,
+            topic: config.topic,
+            dir,
+            config: files_config.clone(),
+            current: Mutex::new(None),
+            current_index: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+        };
+        Ok(Self { files: Arc::new(files) })
+    }
+
+    async fn run_blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&TopicFiles) -> Result<T> + Send + 'static
+    ) -> Result<T> {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || f(&files)).await?
+    }
+
+    async fn write_line(&self, line: String) -> Result<()> {
+        if !self.files.enabled {
+            fail!("Producer is disabled");
+        }
+        self.run_blocking(move |files| files.write_line(line.as_bytes())).await
+    }
+}
+
+impl TopicFiles {
+
+    fn file_path(&self, index: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", index, FILE_EXTENSION))
+    }
+
+    // Returns existing files of topic sorted by index
+    fn list_files(&self) -> Result<Vec<(u64, PathBuf, u64)>> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(FILE_EXTENSION) {
+                continue
+            }
+            let index = match path.file_stem().and_then(|stem| stem.to_str()).map(|stem| stem.parse()) {
+                Some(Ok(index)) => index,
+                _ => continue
+            };
+            let size = std::fs::metadata(&path)?.len();
+            files.push((index, path, size));
+        }
+        files.sort_by_key(|(index, _, _)| *index);
+        Ok(files)
+    }
+
+    fn open_next(&self, index: u64) -> Result<CurrentFile> {
+        let path = self.file_path(index);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        log::trace!("File producer (topic: {}) opened {}", self.topic, path.display());
+        Ok(CurrentFile { file, index, size: 0 })
+    }
+
+    fn apply_retention(&self, current_index: u64) -> Result<()> {
+        if self.config.max_files == 0 && self.config.max_total_bytes == 0 {
+            return Ok(())
+        }
+        let files = self.list_files()?;
+        let mut count = files.len();
+        let mut total: u64 = files.iter().map(|(_, _, size)| size).sum();
+        for (index, path, size) in files {
+            let exceeded = (self.config.max_files > 0 && count > self.config.max_files) ||
+                (self.config.max_total_bytes > 0 && total > self.config.max_total_bytes);
+            if !exceeded || index == current_index {
+                break
+            }
+            std::fs::remove_file(&path)?;
+            log::trace!("File producer (topic: {}) removed {}", self.topic, path.display());
+            count -= 1;
+            total -= size;
+        }
+        Ok(())
+    }
+
+    fn write_line(&self, line: &[u8]) -> Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let rotate = match current.as_ref() {
+            Some(file) => file.size > 0 && file.size + line.len() as u64 > self.config.max_file_size,
+            None => true
+        };
+        if rotate {
+            let index = match current.take() {
+                Some(file) => {
+                    if self.config.fsync == FsyncPolicy::OnRotate {
+                        file.file.sync_all()?;
+                    }
+                    file.index + 1
+                }
+                // Never append to files of previous run
+                None => self.list_files()?.last().map(|(index, _, _)| index + 1).unwrap_or(0)
+            };
+            *current = Some(self.open_next(index)?);
+            self.current_index.store(index + 1, Ordering::Relaxed);
+            self.apply_retention(index)?;
+        }
+        let file = current.as_mut().unwrap();
+        file.file.write_all(line)?;
+        if self.config.fsync == FsyncPolicy::EveryWrite {
+            file.file.sync_data()?;
+        }
+        file.size += line.len() as u64;
+        self.written.fetch_add(line.len() as u64, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl WriteData for FileProducer {
+
+    fn enabled(&self) -> bool { self.files.enabled }
+
+    // Records are written before the call returns, so block delivery has nothing pending
+    async fn write_data(
+        &self,
+        key: String,
+        data: String,
+        _delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        // Documents are pretty printed by processor, so they are compacted to fit into one line
+        let mut line = if data.contains('\n') {
+            serde_json::from_str::<serde_json::Value>(&data)?.to_string()
+        } else {
+            data
+        };
+        line.push('\n');
+        log::trace!("Writing record, topic: {}, key: {}", self.files.topic, key);
+        self.write_line(line).await
+    }
+
+    async fn write_raw_data(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        self.write_raw_data_with_headers(key, data, Vec::new(), delivery).await
+    }
+
+    async fn write_raw_data_with_headers(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+        _delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        let mut record = serde_json::json!({
+            "id": hex::encode(&key),
+            "boc": base64::encode(&data),
+        });
+        if !headers.is_empty() {
+            let headers: serde_json::Map<String, serde_json::Value> = headers.into_iter()
+                .map(|(name, value)| (name.to_string(), hex::encode(value).into()))
+                .collect();
+            record["headers"] = headers.into();
+        }
+        let mut line = record.to_string();
+        line.push('\n');
+        self.write_line(line).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.run_blocking(|files| {
+            if let Some(file) = files.current.lock().unwrap().as_ref() {
+                file.file.sync_data()?;
+            }
+            Ok(())
+        }).await
+    }
+
+    async fn healthcheck(&self, _timeout: std::time::Duration) -> Result<()> {
+        self.run_blocking(|files| {
+            if !std::fs::metadata(&files.dir)?.is_dir() {
+                fail!("{} is not a directory", files.dir.display());
+            }
+            Ok(())
+        }).await
+    }
+
+    // Doesn't wait for file lock held while writing
+    fn stats(&self) -> Option<String> {
+        if !self.files.enabled {
+            return None
+        }
+        let index = self.files.current_index.load(Ordering::Relaxed).checked_sub(1);
+        Some(format!(
+            "topic: {}, file: {:?}, written: {} bytes",
+            self.files.topic, index, self.files.written.load(Ordering::Relaxed)
+        ))
+    }
+}
+
+#[test]
+fn test_file_producer_writes_off_runtime() {
+    const WRITERS: usize = 8;
+    const RECORDS: usize = 50;
+    let dir = std::env::temp_dir().join(format!("test_file_producer_{}", std::process::id()));
+    std::fs::remove_dir_all(&dir).ok();
+    let files_config = FilesDbConfig {
+        directory: dir.to_str().unwrap().to_string(),
+        max_file_size: 1000,
+        fsync: FsyncPolicy::EveryWrite,
+        ..Default::default()
+    };
+    let config = KafkaProducerConfig { enabled: true, topic: "blocks".to_string(), ..Default::default() };
+    // Writers share single runtime thread, files are written by blocking pool
+    let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+    let producer = Arc::new(FileProducer::new(&files_config, config).unwrap());
+    runtime.block_on(async {
+        let writers = (0..WRITERS).map(|writer| {
+            let producer = producer.clone();
+            tokio::spawn(async move {
+                for record in 0..RECORDS {
+                    let data = format!("{{\n  \"writer\": {},\n  \"record\": {}\n}}", writer, record);
+                    producer.write_data(format!("{}", record), data, None).await.unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+        for writer in writers {
+            writer.await.unwrap();
+        }
+        producer.flush().await.unwrap();
+        producer.healthcheck(std::time::Duration::from_secs(1)).await.unwrap();
+    });
+    // Every record is a whole line, records of each writer keep their order
+    let mut next_record = vec![0; WRITERS];
+    let files = producer.files.list_files().unwrap();
+    assert!(files.len() > 1);
+    for (_, path, size) in files.iter() {
+        assert!(*size <= files_config.max_file_size);
+        for line in std::fs::read_to_string(path).unwrap().lines() {
+            let record: serde_json::Value = serde_json::from_str(line).unwrap();
+            let writer = record["writer"].as_u64().unwrap() as usize;
+            assert_eq!(record["record"].as_u64().unwrap() as usize, next_record[writer]);
+            next_record[writer] += 1;
+        }
+    }
+    assert_eq!(next_record, vec![RECORDS; WRITERS]);
+    let stats = producer.stats().unwrap();
+    assert!(stats.contains(&format!("file: Some({})", files.last().unwrap().0)), "{}", stats);
+    std::fs::remove_dir_all(&dir).ok();
+}
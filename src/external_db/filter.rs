@@ -0,0 +1,75 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use ton_block::{CommonMsgInfo, Message, MsgAddressInt};
+use ton_types::{AccountId, Result, fail};
+
+use crate::config::{ExternalDbFilterConfig, MessageTypeSelector};
+
+// Filters messages and transactions before serialization.
+// Empty lists mean no filtering by corresponding criteria.
+pub(super) struct RecordFilter {
+    account_prefixes: Vec<(i32, String)>, // workchain and lowercase hex prefix of account id
+    message_types: Vec<MessageTypeSelector>,
+    filtered: AtomicU64,
+}
+
+impl RecordFilter {
+
+    pub fn with_config(config: &ExternalDbFilterConfig) -> Result<Self> {
+        let mut account_prefixes = Vec::new();
+        for prefix in &config.account_prefixes {
+            let mut parts = prefix.splitn(2, ':');
+            let workchain_id = match parts.next().map(|wc| wc.parse::<i32>()) {
+                Some(Ok(workchain_id)) => workchain_id,
+                _ => fail!("Invalid workchain in account prefix {}", prefix)
+            };
+            let hex = parts.next().unwrap_or_default().to_lowercase();
+            if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                fail!("Invalid hex in account prefix {}", prefix);
+            }
+            account_prefixes.push((workchain_id, hex));
+        }
+        Ok(Self {
+            account_prefixes,
+            message_types: config.message_types.clone(),
+            filtered: AtomicU64::new(0),
+        })
+    }
+
+    pub fn account_passes(&self, workchain_id: i32, account_id: &AccountId) -> bool {
+        if self.account_prefixes.is_empty() {
+            return true
+        }
+        let address = account_id.to_hex_string().to_lowercase();
+        self.account_prefixes.iter().any(|(wc, prefix)| *wc == workchain_id && address.starts_with(prefix))
+    }
+
+    fn address_passes(&self, address: Option<&MsgAddressInt>) -> bool {
+        match address {
+            Some(address) => self.account_passes(address.workchain_id(), &address.address()),
+            None => false
+        }
+    }
+
+    pub fn message_passes(&self, msg: &Message) -> bool {
+        if !self.message_types.is_empty() {
+            let message_type = match msg.header() {
+                CommonMsgInfo::IntMsgInfo(_) => MessageTypeSelector::Int,
+                CommonMsgInfo::ExtInMsgInfo(_) => MessageTypeSelector::ExtIn,
+                CommonMsgInfo::ExtOutMsgInfo(_) => MessageTypeSelector::ExtOut,
+            };
+            if !self.message_types.contains(&message_type) {
+                return false
+            }
+        }
+        self.account_prefixes.is_empty() ||
+            self.address_passes(msg.src_ref()) || self.address_passes(msg.dst_ref())
+    }
+
+    pub fn skip(&self, count: u64) {
+        self.filtered.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn filtered(&self) -> u64 {
+        self.filtered.load(Ordering::Relaxed)
+    }
+}
@@ -56,7 +56,7 @@ impl KafkaConsumer {
             let now = std::time::Instant::now();
             if let Some(payload) = rdkafka::Message::payload(&borrowed_message) {
                 log::trace!("Processing record, {:?}", payload);
-                match self.engine.redirect_external_message(&payload).await {
+                match self.engine.redirect_external_message(&payload, false).await {
                     Ok(info) => log::trace!("count number of nodes to broadcast to: {}", info.send_to),
                     Err(e) => log::error!(
                         "error while processing external message (topic: {}, partition: {}, offset: {}): {:?}",
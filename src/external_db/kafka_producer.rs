@@ -1,29 +1,96 @@
-use std::time;
-use crate::{external_db::WriteData, config::KafkaProducerConfig};
-use rdkafka::message::OwnedHeaders;
+use std::{collections::HashMap, sync::{Arc, Mutex, atomic::{AtomicU64, AtomicUsize, Ordering}}, time};
+use crate::{external_db::{BlockDelivery, WriteData}, config::KafkaProducerConfig, engine::STATSD};
+use rdkafka::{message::OwnedHeaders, producer::Producer};
+use tokio::sync::mpsc;
 use ton_types::{Result, fail};
 use chrono::Utc;
 
 const EXTERNAL_MESSAGE_DATA_HEADER_KEY: &str = "external-message-ref";
 const PATTERN_TO_REPLACE: &str = "{message_filename}";
+const DEAD_LETTER_ERROR_HEADER_KEY: &str = "error";
+const DEAD_LETTER_TOPIC_HEADER_KEY: &str = "source-topic";
+const FLUSH_CHECK_INTERVAL_MS: u64 = 10;
 
-pub(super) struct KafkaProducer {
+struct RetryRecord {
+    key: Vec<u8>,
+    key_str: String,
+    data: Vec<u8>,
+    ts: Option<i64>,
+    headers: Vec<(&'static str, Vec<u8>)>,
+    attempt: u32,
+    error: String,
+    delivery: Option<Arc<BlockDelivery>>,
+}
+
+#[derive(Default)]
+struct ProducerCounters {
+    queued: AtomicUsize,
+    retries: AtomicU64,
+    dead_lettered: AtomicU64,
+    last_produced_at: AtomicU64, // unix time of last successful produce
+}
+
+struct ProducerCore {
     config: KafkaProducerConfig,
     producer: Option<rdkafka::producer::FutureProducer>,
+    counters: ProducerCounters,
+    queued_keys: QueuedKeys,
+}
+
+// Keys of records in retry queue with count of records. Records of the same key are produced
+// in order they are written: while the key is queued, its new records go to retry queue as well.
+#[derive(Default)]
+struct QueuedKeys(Mutex<HashMap<Vec<u8>, usize>>);
+
+impl QueuedKeys {
+    fn add(&self, key: &[u8]) {
+        *self.0.lock().unwrap().entry(key.to_vec()).or_insert(0) += 1;
+    }
+    fn remove(&self, key: &[u8]) {
+        let mut keys = self.0.lock().unwrap();
+        if let Some(count) = keys.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                keys.remove(key);
+            }
+        }
+    }
+    fn contains(&self, key: &[u8]) -> bool {
+        self.0.lock().unwrap().contains_key(key)
+    }
+}
+
+pub(super) struct KafkaProducer {
+    core: Arc<ProducerCore>,
+    retry_queue: Option<mpsc::Sender<RetryRecord>>,
 }
 
 impl KafkaProducer {
     pub fn new(config: KafkaProducerConfig) -> Result<Self> {
         if !config.enabled {
             log::trace!("Kafka producer (topic: {}) is DISABLED", config.topic);
-            Ok(Self { config, producer: None } )
+            let core = ProducerCore {
+                config, producer: None, counters: ProducerCounters::default(), queued_keys: QueuedKeys::default()
+            };
+            Ok(Self { core: Arc::new(core), retry_queue: None } )
         } else {
             log::trace!("Creating kafka producer (topic: {})...", config.topic);
-            let producer = rdkafka::config::ClientConfig::new()
+            let mut client_config = rdkafka::config::ClientConfig::new();
+            client_config
                 .set("bootstrap.servers", &config.brokers)
                 .set("message.timeout.ms", &config.message_timeout_ms.to_string())
                 .set("message.max.bytes", &config.message_max_size.to_string())
-                .create()?;
+                .set("compression.codec", config.compression_codec.as_str());
+            if let Some(linger_ms) = config.linger_ms {
+                client_config.set("linger.ms", &linger_ms.to_string());
+            }
+            if let Some(batch_size) = config.batch_size {
+                client_config.set("batch.size", &batch_size.to_string());
+            }
+            if let Some(max_in_flight) = config.max_in_flight {
+                client_config.set("max.in.flight.requests.per.connection", &max_in_flight.to_string());
+            }
+            let producer = client_config.create()?;
 
             if let Some(pattern) = &config.external_message_ref_address_pattern {
                 if pattern.find(PATTERN_TO_REPLACE).is_none() {
@@ -31,8 +98,114 @@ impl KafkaProducer {
                 }
             }
 
-            Ok(Self { config, producer: Some(producer) } )
+            let retry_queue_size = config.retry_queue_size;
+            let core = Arc::new(
+                ProducerCore {
+                    config,
+                    producer: Some(producer),
+                    counters: ProducerCounters::default(),
+                    queued_keys: QueuedKeys::default()
+                }
+            );
+            let retry_queue = if retry_queue_size > 0 {
+                let (sender, receiver) = mpsc::channel(retry_queue_size);
+                tokio::spawn(ProducerCore::retry_worker(core.clone(), receiver));
+                Some(sender)
+            } else {
+                None
+            };
+            Ok(Self { core, retry_queue } )
+        }
+    }
+
+    async fn write_internal(
+        &self,
+        key: Vec<u8>,
+        key_str: String,
+        data: Vec<u8>,
+        ts: Option<i64>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        if !self.enabled() {
+            fail!("Producer is disabled");
+        }
+        else if self.core.producer.is_none() {
+            fail!("Internal error: producer is enabled but kafka producer instance is None");
+        }
+
+        let mut record = RetryRecord {
+            key, key_str, data, ts, headers, attempt: 0, error: String::new(), delivery
+        };
+        // Record of key which has records in retry queue goes after them to keep their order
+        let mut produce = !self.core.is_key_queued(&record.key);
+        loop {
+            if produce {
+                match self.core.produce(&record).await {
+                    Ok(_) => break,
+                    Err(e) => {
+                        record.attempt += 1;
+                        record.error = e.to_string();
+                    }
+                }
+                if self.core.attempts_exhausted(record.attempt) {
+                    self.core.dead_letter(&record).await;
+                    break;
+                }
+            }
+            if let Some(retry_queue) = &self.retry_queue {
+                // Counters are increased before sending to keep them consistent with worker's decrement
+                self.core.counters.queued.fetch_add(1, Ordering::Relaxed);
+                self.core.add_queued_key(&record.key);
+                if let Some(delivery) = &record.delivery {
+                    delivery.add_pending();
+                }
+                match retry_queue.try_send(record) {
+                    Ok(_) => {
+                        STATSD.gauge(
+                            &format!("kafka_retry_queue_{}", self.core.config.topic), 
+                            self.core.counters.queued.load(Ordering::Relaxed) as f64
+                        );
+                        break
+                    }
+                    Err(mpsc::error::TrySendError::Full(rejected)) |
+                    Err(mpsc::error::TrySendError::Closed(rejected)) => {
+                        self.core.counters.queued.fetch_sub(1, Ordering::Relaxed);
+                        self.core.remove_queued_key(&rejected.key);
+                        if let Some(delivery) = &rejected.delivery {
+                            delivery.remove_pending();
+                        }
+                        record = rejected;
+                    }
+                }
+                if self.core.config.drop_on_full_retry_queue {
+                    self.core.dead_letter(&record).await;
+                    break;
+                }
+                // back-pressure: retry in place
+            }
+            if produce {
+                self.core.counters.retries.fetch_add(1, Ordering::Relaxed);
+            }
+            self.core.backoff(record.attempt).await;
+            produce = !self.core.is_key_queued(&record.key);
         }
+        Ok(())
+    }
+}
+
+impl ProducerCore {
+
+    fn is_key_queued(&self, key: &[u8]) -> bool {
+        self.queued_keys.contains(key)
+    }
+
+    fn add_queued_key(&self, key: &[u8]) {
+        self.queued_keys.add(key)
+    }
+
+    fn remove_queued_key(&self, key: &[u8]) {
+        self.queued_keys.remove(key)
     }
 
     fn store_oversized(&self, key: &str, data: &[u8]) -> Result<()> {
@@ -92,66 +265,245 @@ impl KafkaProducer {
         Ok(())
     }
 
-    async fn write_internal(&self, key: Vec<u8>, key_str: String, data: Vec<u8>, ts: Option<i64>) -> Result<()> {
-        if !self.enabled() {
-            fail!("Producer is disabled");
+    // Single attempt to produce record
+    async fn produce(&self, record: &RetryRecord) -> Result<()> {
+        log::trace!("Producing record, topic: {}, key: {}", self.config.topic, record.key_str);
+        let now = std::time::Instant::now();
+        let (header_name, header_value) = if let Some(timestamp) = record.ts {
+                ("raw_block_timestamp", timestamp.to_be_bytes())
+            } else {
+                ("raw_block_timestamp", [0u8;8])
+            };
+        let mut headers = OwnedHeaders::new_with_capacity(record.headers.len() + 1)
+            .add(header_name, &header_value);
+        for (name, value) in record.headers.iter() {
+            headers = headers.add(name, value.as_slice());
         }
-        else if self.producer.is_none() {
-            fail!("Internal error: producer is enabled but kafka producer instance is None");
+        let produce_future = self.producer.as_ref().unwrap().send(
+            rdkafka::producer::FutureRecord::to(&self.config.topic)
+                .key(&record.key)
+                .payload(&record.data)
+                .headers(headers),
+            0,
+        );
+        match produce_future.await {
+            Ok(Ok(_)) => {
+                log::trace!("Produced record, topic: {}, key: {}, time: {} mcs", self.config.topic, record.key_str, now.elapsed().as_micros());
+                self.counters.last_produced_at.store(Utc::now().timestamp() as u64, Ordering::Relaxed);
+                Ok(())
+            },
+            Ok(Err((e, _))) => {
+                match e {
+                    rdkafka::error::KafkaError::MessageProduction(rdkafka::error::RDKafkaError::MessageSizeTooLarge) => {
+                        self.process_oversized(&record.key_str, &record.data).await
+                    }
+                    _ => {
+                        log::warn!("Error while producing into kafka, topic: {}, key: {}, error: {}", self.config.topic, record.key_str, e);
+                        fail!("{}", e)
+                    }
+                }
+            },
+            Err(e) => {
+                log::warn!("Internal error while producing into kafka, topic: {}, key: {}, error: {}", self.config.topic, record.key_str, e);
+                fail!("{}", e)
+            }
         }
+    }
 
-        loop {
-            log::trace!("Producing record, topic: {}, key: {}", self.config.topic, key_str);
-            let now = std::time::Instant::now();
-            let (header_name, header_value) = if let Some(timestamp) = ts {
-                    ("raw_block_timestamp", timestamp.to_be_bytes())
-                } else {
-                    ("raw_block_timestamp", [0u8;8])
-                };
-            let produce_future = self.producer.as_ref().unwrap().send(
-                rdkafka::producer::FutureRecord::to(&self.config.topic)
-                    .key(&key)
-                    .payload(&data)
-                    .headers(OwnedHeaders::new().add(header_name, &header_value)),
-                0,
-            );
-            match produce_future.await {
-                Ok(Ok(_)) => {
-                    log::trace!("Produced record, topic: {}, key: {}, time: {} mcs", self.config.topic, key_str, now.elapsed().as_micros());
-                    break;
-                },
-                Ok(Err((e, _))) => {
-                    match e {
-                        rdkafka::error::KafkaError::MessageProduction(rdkafka::error::RDKafkaError::MessageSizeTooLarge) => {
-                            self.process_oversized(&key_str, &data).await?;
-                            break;
-                        }
-                        _ => log::warn!("Error while producing into kafka, topic: {}, key: {}, error: {}", self.config.topic, key_str, e),
+    fn attempts_exhausted(&self, attempt: u32) -> bool {
+        self.config.max_attempts > 0 && attempt >= self.config.max_attempts
+    }
+
+    async fn backoff(&self, attempt: u32) {
+        let base = self.config.attempt_timeout_ms as u64;
+        let max = base.max(self.config.max_backoff_ms as u64);
+        let delay = (base << attempt.saturating_sub(1).min(16)).min(max);
+        futures_timer::Delay::new(time::Duration::from_millis(delay)).await;
+    }
+
+    async fn retry_worker(core: Arc<ProducerCore>, mut receiver: mpsc::Receiver<RetryRecord>) {
+        while let Some(mut record) = receiver.recv().await {
+            loop {
+                core.counters.retries.fetch_add(1, Ordering::Relaxed);
+                core.backoff(record.attempt).await;
+                match core.produce(&record).await {
+                    Ok(_) => break,
+                    Err(e) => {
+                        record.attempt += 1;
+                        record.error = e.to_string();
                     }
-                },
-                Err(e) => log::warn!("Internal error while producing into kafka, topic: {}, key: {}, error: {}", self.config.topic, key_str, e),
+                }
+                if core.attempts_exhausted(record.attempt) {
+                    core.dead_letter(&record).await;
+                    break;
+                }
             }
-            futures_timer::Delay::new(
-                time::Duration::from_millis(
-                    self.config.attempt_timeout_ms as u64
-                )
+            // Record is counted until it is delivered or dead-lettered, flush relies on it
+            if let Some(delivery) = &record.delivery {
+                delivery.remove_pending();
+            }
+            core.remove_queued_key(&record.key);
+            let queued = core.counters.queued.fetch_sub(1, Ordering::Relaxed) - 1;
+            STATSD.gauge(&format!("kafka_retry_queue_{}", core.config.topic), queued as f64);
+        }
+    }
+
+    // Writes record which can't be produced into dead-letter topic, or into local storage.
+    // Block of the record is not acknowledged anyway.
+    async fn dead_letter(&self, record: &RetryRecord) {
+        if let Some(delivery) = &record.delivery {
+            delivery.add_dead_lettered();
+        }
+        self.counters.dead_lettered.fetch_add(1, Ordering::Relaxed);
+        STATSD.incr(&format!("kafka_dead_lettered_{}", self.config.topic));
+        log::error!(
+            "Record can't be produced, topic: {}, key: {}, attempts: {}, error: {}",
+            self.config.topic, record.key_str, record.attempt, record.error
+        );
+        if let Some(topic) = &self.config.dead_letter_topic {
+            let headers = OwnedHeaders::new_with_capacity(2)
+                .add(DEAD_LETTER_ERROR_HEADER_KEY, &record.error)
+                .add(DEAD_LETTER_TOPIC_HEADER_KEY, &self.config.topic);
+            let result = self.producer.as_ref().unwrap().send(
+                rdkafka::producer::FutureRecord::to(topic)
+                    .key(&record.key)
+                    .payload(&record.data)
+                    .headers(headers),
+                0,
             ).await;
+            match result {
+                Ok(Ok(_)) => return,
+                Ok(Err((e, _))) => log::warn!(
+                    "Error while producing into dead-letter topic {}, key: {}, error: {}", topic, record.key_str, e
+                ),
+                Err(e) => log::warn!(
+                    "Internal error while producing into dead-letter topic {}, key: {}, error: {}", topic, record.key_str, e
+                )
+            }
+        }
+        if let Some(storage) = &self.config.dead_letter_storage {
+            let dir = std::path::Path::new(storage).join(std::path::Path::new(&self.config.topic));
+            // Records of the same key are dead-lettered into different files
+            let name = format!("{}_{}_{}", self.config.topic, record.key_str, Utc::now().timestamp_nanos());
+            let data = record.data.clone();
+            let error = record.error.clone();
+            let write_dir = dir.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                std::fs::create_dir_all(&write_dir)?;
+                std::fs::write(write_dir.join(&name), &data)?;
+                std::fs::write(write_dir.join(format!("{}.error", name)), &error)
+            }).await;
+            match result {
+                Ok(Ok(_)) => log::error!(
+                    "Record (topic: {}, key: {}) saved into {}",
+                    self.config.topic, record.key_str, dir.to_str().unwrap_or_default()
+                ),
+                Ok(Err(e)) => log::error!(
+                    "Record (topic: {}, key: {}) is lost, error while saving into {}: {}",
+                    self.config.topic, record.key_str, dir.to_str().unwrap_or_default(), e
+                ),
+                Err(e) => log::error!(
+                    "Record (topic: {}, key: {}) is lost, internal error while saving into {}: {}",
+                    self.config.topic, record.key_str, dir.to_str().unwrap_or_default(), e
+                )
+            }
+        } else {
+            log::error!("Record (topic: {}, key: {}) is lost", self.config.topic, record.key_str);
         }
-        Ok(())
     }
 }
 
 #[async_trait::async_trait]
 impl WriteData for KafkaProducer {
 
-    fn enabled(&self) -> bool { self.config.enabled }
+    fn enabled(&self) -> bool { self.core.config.enabled }
 
-    async fn write_raw_data(&self, key: Vec<u8>, data: Vec<u8>) -> Result<()> {
+    async fn write_raw_data(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
         let key_str = format!("{}", hex::encode(&key));
-        self.write_internal(key, key_str, data, Some(Utc::now().timestamp())).await
+        self.write_internal(key, key_str, data, Some(Utc::now().timestamp()), Vec::new(), delivery).await
     }
 
-    async fn write_data(&self, key: String, data: String) -> Result<()> {
-        self.write_internal(key.clone().into_bytes(), key, data.into_bytes(), None).await
+    async fn write_raw_data_with_headers(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        let key_str = format!("{}", hex::encode(&key));
+        self.write_internal(key, key_str, data, Some(Utc::now().timestamp()), headers, delivery).await
     }
+
+    async fn write_data(
+        &self,
+        key: String,
+        data: String,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        self.write_internal(key.clone().into_bytes(), key, data.into_bytes(), None, Vec::new(), delivery).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        while self.core.counters.queued.load(Ordering::Relaxed) > 0 {
+            futures_timer::Delay::new(time::Duration::from_millis(FLUSH_CHECK_INTERVAL_MS)).await;
+        }
+        Ok(())
+    }
+
+    async fn healthcheck(&self, timeout: time::Duration) -> Result<()> {
+        let producer = match self.core.producer.as_ref() {
+            Some(producer) => producer.clone(),
+            None => return Ok(())
+        };
+        let topic = self.core.config.topic.clone();
+        // Metadata request is blocking
+        let metadata = tokio::task::spawn_blocking(
+            move || producer.client().fetch_metadata(Some(&topic), timeout)
+        ).await??;
+        match metadata.topics().iter().find(|t| t.name() == self.core.config.topic) {
+            Some(topic) => match topic.error() {
+                Some(e) => fail!("Kafka topic {} is unavailable: {:?}", self.core.config.topic, e),
+                None => Ok(())
+            }
+            None => fail!("Kafka topic {} is not found", self.core.config.topic)
+        }
+    }
+
+    fn stats(&self) -> Option<String> {
+        if !self.enabled() {
+            return None
+        }
+        let counters = &self.core.counters;
+        Some(format!(
+            "topic: {}, retry queue: {}, retries: {}, dead-lettered: {}, last produced at: {}",
+            self.core.config.topic,
+            counters.queued.load(Ordering::Relaxed),
+            counters.retries.load(Ordering::Relaxed),
+            counters.dead_lettered.load(Ordering::Relaxed),
+            counters.last_produced_at.load(Ordering::Relaxed)
+        ))
+    }
+}
+
+#[test]
+fn test_queued_keys_keep_order_until_drained() {
+    let keys = QueuedKeys::default();
+    assert!(!keys.contains(b"a"));
+    // two records of the key are in retry queue, new records must follow them
+    keys.add(b"a");
+    keys.add(b"a");
+    keys.add(b"b");
+    keys.remove(b"a");
+    assert!(keys.contains(b"a"));
+    keys.remove(b"a");
+    assert!(!keys.contains(b"a"));
+    assert!(keys.contains(b"b"));
+    // unknown key is ignored
+    keys.remove(b"c");
+    assert!(keys.contains(b"b"));
 }
@@ -1,31 +1,105 @@
 use crate::{engine_traits::ExternalDb, config::ExternalDbConfig};
+use filter::RecordFilter;
 use processor::Processor;
 
-use std::sync::Arc;
+use std::sync::{Arc, atomic::{AtomicUsize, Ordering}};
 use ton_types::Result;
 
+mod buffered;
+mod filter;
 mod processor;
 #[cfg(feature = "external_db")]
 mod kafka_producer;
 #[cfg(feature = "external_db")]
 pub mod kafka_consumer;
-#[cfg(not(feature = "external_db"))]
+#[cfg(feature = "external_db")]
+mod file_producer;
+#[cfg(any(test, not(feature = "external_db")))]
 mod stub_producer;
 
 
+// Delivery state of records written for one block. Producers count records put into
+// retry queues as pending and mark dead-lettered ones, so block's ack can fail.
+#[derive(Default)]
+pub struct BlockDelivery {
+    pending: AtomicUsize,
+    dead_lettered: AtomicUsize,
+}
+
+impl BlockDelivery {
+    pub fn add_pending(&self) {
+        self.pending.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn remove_pending(&self) {
+        self.pending.fetch_sub(1, Ordering::Relaxed);
+    }
+    pub fn add_dead_lettered(&self) {
+        self.dead_lettered.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn pending(&self) -> usize {
+        self.pending.load(Ordering::Relaxed)
+    }
+    pub fn dead_lettered(&self) -> usize {
+        self.dead_lettered.load(Ordering::Relaxed)
+    }
+}
+
 #[async_trait::async_trait]
 pub trait WriteData : Sync + Send {
     fn enabled(&self) -> bool;
-    async fn write_data(&self, key: String, data: String) -> Result<()>;
-    async fn write_raw_data(&self, key: Vec<u8>, data: Vec<u8>) -> Result<()>;
+    // Records of blocks are written with delivery tracker when external db ack is awaited
+    async fn write_data(
+        &self,
+        key: String,
+        data: String,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()>;
+    async fn write_raw_data(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()>;
+    async fn write_raw_data_with_headers(
+        &self,
+        key: Vec<u8>,
+        data: Vec<u8>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()>;
+    // Resolves when all previously written records are delivered
+    async fn flush(&self) -> Result<()>;
+    async fn healthcheck(&self, timeout: std::time::Duration) -> Result<()>;
+    fn stats(&self) -> Option<String>;
+}
+
+pub const DEFAULT_SINK_BUFFER_SIZE: usize = 64;
+
+// Makes sink process blocks in background, independently of other sinks
+pub fn create_buffered_external_db(
+    name: String,
+    external_db: Arc<dyn ExternalDb>,
+    buffer_size: usize
+) -> Arc<dyn ExternalDb> {
+    Arc::new(buffered::BufferedExternalDb::new(name, external_db, buffer_size))
 }
 
 #[allow(dead_code)]
 #[cfg(not(feature = "external_db"))]
-pub fn create_external_db(config: ExternalDbConfig, front_workchain_ids: Vec<i32>) -> Result<Arc<dyn ExternalDb>> {
+pub fn create_external_db(
+    config: ExternalDbConfig,
+    front_workchain_ids: Option<Vec<i32>>,
+    track_delivery: bool
+) -> Result<Arc<dyn ExternalDb>> {
     Ok(
         Arc::new(
             Processor::new(
+                stub_producer::StubProducer{enabled: true},
+                stub_producer::StubProducer{enabled: true},
+                stub_producer::StubProducer{enabled: true},
+                stub_producer::StubProducer{enabled: true},
+                stub_producer::StubProducer{enabled: true},
+                stub_producer::StubProducer{enabled: true},
                 stub_producer::StubProducer{enabled: true},
                 stub_producer::StubProducer{enabled: true},
                 stub_producer::StubProducer{enabled: true},
@@ -34,7 +108,11 @@ pub fn create_external_db(config: ExternalDbConfig, front_workchain_ids: Vec<i32
                 stub_producer::StubProducer{enabled: true},
                 stub_producer::StubProducer{enabled: true},
                 config.bad_blocks_storage,
+                config.name.unwrap_or_default(),
                 front_workchain_ids,
+                RecordFilter::with_config(&config.filter)?,
+                config.legacy_transaction_format,
+                track_delivery,
             )
         )
     )
@@ -42,19 +120,70 @@ pub fn create_external_db(config: ExternalDbConfig, front_workchain_ids: Vec<i32
 
 #[allow(dead_code)]
 #[cfg(feature = "external_db")]
-pub fn create_external_db(config: ExternalDbConfig, front_workchain_ids: Vec<i32>) -> Result<Arc<dyn ExternalDb>> {
+pub fn create_external_db(
+    config: ExternalDbConfig,
+    front_workchain_ids: Option<Vec<i32>>,
+    track_delivery: bool
+) -> Result<Arc<dyn ExternalDb>> {
+    config.check()?;
     Ok(
         Arc::new(
             Processor::new(
                 kafka_producer::KafkaProducer::new(config.block_producer)?,
                 kafka_producer::KafkaProducer::new(config.raw_block_producer)?,
+                kafka_producer::KafkaProducer::new(config.raw_block_proof_producer)?,
                 kafka_producer::KafkaProducer::new(config.message_producer)?,
                 kafka_producer::KafkaProducer::new(config.transaction_producer)?,
                 kafka_producer::KafkaProducer::new(config.account_producer)?,
                 kafka_producer::KafkaProducer::new(config.block_proof_producer)?,
                 kafka_producer::KafkaProducer::new(config.chain_range_producer)?,
+                kafka_producer::KafkaProducer::new(config.validator_status_producer)?,
+                kafka_producer::KafkaProducer::new(config.account_state_producer)?,
+                kafka_producer::KafkaProducer::new(config.key_block_producer)?,
+                kafka_producer::KafkaProducer::new(config.ext_msg_status_producer)?,
+                kafka_producer::KafkaProducer::new(config.block_revoked_producer)?,
+                config.bad_blocks_storage,
+                config.name.unwrap_or_default(),
+                front_workchain_ids,
+                RecordFilter::with_config(&config.filter)?,
+                config.legacy_transaction_format,
+                track_delivery,
+            )
+        )
+    )
+}
+
+#[allow(dead_code)]
+#[cfg(feature = "external_db")]
+pub fn create_files_external_db(
+    config: ExternalDbConfig,
+    front_workchain_ids: Option<Vec<i32>>,
+    track_delivery: bool
+) -> Result<Arc<dyn ExternalDb>> {
+    config.check()?;
+    let files = &config.files;
+    Ok(
+        Arc::new(
+            Processor::new(
+                file_producer::FileProducer::new(files, config.block_producer)?,
+                file_producer::FileProducer::new(files, config.raw_block_producer)?,
+                file_producer::FileProducer::new(files, config.raw_block_proof_producer)?,
+                file_producer::FileProducer::new(files, config.message_producer)?,
+                file_producer::FileProducer::new(files, config.transaction_producer)?,
+                file_producer::FileProducer::new(files, config.account_producer)?,
+                file_producer::FileProducer::new(files, config.block_proof_producer)?,
+                file_producer::FileProducer::new(files, config.chain_range_producer)?,
+                file_producer::FileProducer::new(files, config.validator_status_producer)?,
+                file_producer::FileProducer::new(files, config.account_state_producer)?,
+                file_producer::FileProducer::new(files, config.key_block_producer)?,
+                file_producer::FileProducer::new(files, config.ext_msg_status_producer)?,
+                file_producer::FileProducer::new(files, config.block_revoked_producer)?,
                 config.bad_blocks_storage,
+                config.name.unwrap_or_default(),
                 front_workchain_ids,
+                RecordFilter::with_config(&config.filter)?,
+                config.legacy_transaction_format,
+                track_delivery,
             )
         )
     )
@@ -1,20 +1,21 @@
-use std::{collections::hash_set::HashSet};
+use std::{collections::{HashMap, hash_set::HashSet}, sync::{Arc, Mutex, RwLock}};
 use ton_block::{
     Account, InMsg, OutMsg, Deserializable, Serializable, MessageProcessingStatus, Transaction,
     TransactionProcessingStatus, BlockProcessingStatus, Block, BlockProof, HashmapAugType,
-    AccountBlock, ShardAccount,
+    AccountBlock, BlockIdExt, Message, ShardAccount, ShardAccounts, ShardIdent,
 };
 use ton_types::{
     cells_serialization::serialize_toc,
     types::UInt256,
-    AccountId, Cell, Result, SliceData, HashmapType
+    error, fail, AccountId, Cell, Result, SliceData, HashmapType
 };
 use serde::Serialize;
 
 use crate::{
     block::BlockStuff, block_proof::BlockProofStuff, engine::STATSD,
-    engine_traits::{ChainRange, ExternalDb}, error::NodeError, external_db::WriteData,
-    shard_state::ShardStateStuff
+    engine_traits::{ChainRange, ExternalDb}, error::NodeError, ext_messages::ExtMessageStatus,
+    external_db::{BlockDelivery, WriteData, filter::RecordFilter}, internal_db::InternalDb,
+    shard_state::ShardStateStuff, validator::validator_group::ValidatorGroupStatusEvent,
 };
 
 lazy_static::lazy_static!(
@@ -26,11 +27,22 @@ enum DbRecord {
     Message(String, String),
     Transaction(String, String),
     Account(String, String),
+    AccountState(String, String),
     BlockProof(String, String),
-    Block(String, String),
-    RawBlock(Vec<u8>, Vec<u8>)
+    Block(serde_json::Map<String, serde_json::Value>), // numbered when other records are written
+    RawBlock(Vec<u8>, Vec<u8>),
+    RawBlockProof(Vec<u8>, Vec<u8>, Vec<(&'static str, Vec<u8>)>),
+    KeyBlockProof(Vec<u8>, Vec<u8>, Vec<(&'static str, Vec<u8>)>),
 }
 
+// Headers of block proof records to follow key blocks chain without parsing proofs
+const IS_KEY_BLOCK_HEADER: &str = "is_key_block";
+const PREV_KEY_BLOCK_SEQNO_HEADER: &str = "prev_key_block_seqno";
+
+// Version of transaction records enriched with account state hash and balances.
+// Records of legacy format have no version field.
+const TRANSACTION_SCHEMA_VERSION: u32 = 2;
+
 #[derive(Clone, Debug, Serialize)]
 struct ChainRangeMasterBlock {
     pub id: String,
@@ -43,52 +55,198 @@ struct ChainRangeData {
     pub shard_blocks_ids: Vec<String>,
 }
 
+#[derive(Clone, Debug, Serialize)]
+struct AccountStateData {
+    pub address: String,
+    pub block_id: String,
+    pub block_seq_no: u32,
+    pub deleted: bool,
+    pub last_trans_lt: u64,
+    pub last_trans_hash: String,
+    pub boc: Option<String>, // base64
+}
+
+/// Record of `block_revoked` feed: block was dropped without being included into masterchain,
+/// so data of the block which consumer might have got must be discarded.
+/// ```json
+/// {"id": "<block root hash hex>", "workchain_id": 0, "shard": "8000000000000000", "seq_no": 1234, 
+///  "reason": "superseded by ..."}
+/// ```
+#[derive(Clone, Debug, Serialize)]
+struct BlockRevokedData {
+    pub id: String,
+    pub workchain_id: i32,
+    pub shard: String,
+    pub seq_no: u32,
+    pub reason: String,
+}
+
+impl BlockRevokedData {
+    fn new(block_id: &BlockIdExt, reason: &str) -> Self {
+        Self {
+            id: block_id.root_hash().to_hex_string(),
+            workchain_id: block_id.shard().workchain_id(),
+            shard: format!("{:016x}", block_id.shard().shard_prefix_with_tag()),
+            seq_no: block_id.seq_no(),
+            reason: reason.to_string(),
+        }
+    }
+}
+
+// Field added into block records to let consumers detect gaps: number of block in shard's feed
+// is increased by one for every written block. Numbers are stored in internal DB, so they
+// continue after restart.
+const SHARD_FEED_SEQ_NO_FIELD: &str = "shard_feed_seq_no";
+
+#[derive(Clone, Debug, Serialize)]
+struct ExtMessageStatusData {
+    pub id: String,
+    pub status: String,
+    pub updated_at: u32,
+    pub shard: Option<String>,
+    pub seq_no: Option<u32>,
+    pub block_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct ValidatorGroupStatusData {
+    pub session_id: String,
+    pub shard: String,
+    pub old_status: String,
+    pub new_status: String,
+    pub timestamp: u64,
+}
+
 pub(super) struct Processor<T: WriteData> {
     write_block: T,
     write_raw_block: T,
+    write_raw_block_proof: T,
     write_message: T,
     write_transaction: T,
     write_account: T,
     write_block_proof: T,
     write_chain_range: T,
+    write_validator_status: T,
+    write_account_state: T,
+    write_key_block: T,
+    write_ext_msg_status: T,
+    write_block_revoked: T,
     bad_blocks_storage: String,
-    front_workchain_ids: Vec<i32>, // write only this workchain, or write all if None
+    // write only these workchains, or write all if None; replaced by config reload
+    front_workchain_ids: RwLock<Option<Vec<i32>>>,
+    name: String, // feeds of sinks are numbered independently
+    feed_storage: RwLock<Option<Arc<dyn InternalDb>>>,
+    // last numbers of shards' feeds, locked while block record is written
+    shard_feeds: tokio::sync::Mutex<HashMap<ShardIdent, u64>>,
+    filter: Arc<RecordFilter>, // applied to messages and transactions of processed workchains
+    legacy_transaction_format: bool, // transactions without schema version, state hash and balances
+    // delivery of block's records awaited by flush_block, None if ack is not used
+    deliveries: Option<Mutex<HashMap<BlockIdExt, Arc<BlockDelivery>>>>,
 }
 
+const FLUSH_CHECK_INTERVAL_MS: u64 = 10;
+
 impl<T: WriteData> Processor<T> {
 
     pub fn new(
         write_block: T,
         write_raw_block: T,
+        write_raw_block_proof: T,
         write_message: T,
         write_transaction: T,
         write_account: T,
         write_block_proof: T,
         write_chain_range: T,
+        write_validator_status: T,
+        write_account_state: T,
+        write_key_block: T,
+        write_ext_msg_status: T,
+        write_block_revoked: T,
         bad_blocks_storage: String,
-        front_workchain_ids: Vec<i32>,
+        name: String,
+        front_workchain_ids: Option<Vec<i32>>,
+        filter: RecordFilter,
+        legacy_transaction_format: bool,
+        track_delivery: bool,
     ) 
     -> Self {
         log::trace!("Processor::new workchains {:?}", front_workchain_ids);
         Processor {
             write_block,
             write_raw_block,
+            write_raw_block_proof,
             write_message,
             write_transaction,
             write_account,
             write_block_proof,
             write_chain_range,
+            write_validator_status,
+            write_account_state,
+            write_key_block,
+            write_ext_msg_status,
+            write_block_revoked,
             bad_blocks_storage,
-            front_workchain_ids,
+            front_workchain_ids: RwLock::new(front_workchain_ids),
+            name,
+            feed_storage: RwLock::new(None),
+            shard_feeds: tokio::sync::Mutex::new(HashMap::new()),
+            filter: Arc::new(filter),
+            legacy_transaction_format,
+            deliveries: if track_delivery { Some(Mutex::new(HashMap::new())) } else { None },
         }
     }
 
+    fn writers(&self) -> [&T; 13] {
+        [
+            &self.write_block, &self.write_raw_block, &self.write_raw_block_proof, 
+            &self.write_message, &self.write_transaction, &self.write_account, 
+            &self.write_block_proof, &self.write_chain_range, &self.write_validator_status,
+            &self.write_account_state, &self.write_key_block, &self.write_ext_msg_status,
+            &self.write_block_revoked
+        ]
+    }
+
+    fn feed_name(&self, shard: &ShardIdent) -> String {
+        format!("{}/{}", self.name, shard)
+    }
+
+    // Block record is numbered after all other records of the block are written,
+    // and the number is stored only if the record is written too. So failed blocks
+    // leave no gaps, and numbers go in order of writing.
+    async fn write_block_record(
+        &self,
+        shard: &ShardIdent,
+        mut doc: serde_json::Map<String, serde_json::Value>,
+        delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        let storage = self.feed_storage.read().unwrap().clone();
+        let mut feeds = self.shard_feeds.lock().await;
+        let last = match (feeds.get(shard), &storage) {
+            (Some(seq_no), _) => *seq_no,
+            (None, Some(storage)) => storage.load_ext_db_feed_seq_no(&self.feed_name(shard))?,
+            (None, None) => 0
+        };
+        let seq_no = last + 1;
+        doc.insert(SHARD_FEED_SEQ_NO_FIELD.to_string(), seq_no.into());
+        self.write_block.write_data(
+            doc["id"].to_string(),
+            format!("{:#}", serde_json::json!(doc)),
+            delivery
+        ).await?;
+        if let Some(storage) = &storage {
+            storage.save_ext_db_feed_seq_no(&self.feed_name(shard), seq_no)?;
+        }
+        feeds.insert(shard.clone(), seq_no);
+        Ok(())
+    }
+
     fn process_workchain(&self, workchain_id: i32) -> bool {
-        self
-            .front_workchain_ids
-            .iter()
-            .position(|id| id == &workchain_id)
-            .is_some()
+        match &*self.front_workchain_ids.read().unwrap() {
+            Some(ids) => ids.contains(&workchain_id),
+            None => true
+        }
     }
 
     fn prepare_in_message_record(
@@ -96,10 +254,15 @@ impl<T: WriteData> Processor<T> {
         block_root: &Cell, 
         block_id: UInt256,
         add_proof: bool,
+        filter: &RecordFilter,
     ) -> Result<DbRecord> {
+        let msg = in_msg.read_message()?;
+        if !filter.message_passes(&msg) {
+            filter.skip(1);
+            return Ok(DbRecord::Empty)
+        }
         let transaction_id = in_msg.transaction_cell().map(|cell| cell.repr_hash());
         let transaction_now = in_msg.read_transaction()?.map(|t| t.now);
-        let msg = in_msg.read_message()?;
         let cell = in_msg.message_cell()?;
         let boc = serialize_toc(&cell)?;
         let proof = if add_proof {
@@ -129,9 +292,14 @@ impl<T: WriteData> Processor<T> {
         block_root: &Cell, 
         block_id: UInt256,
         add_proof: bool,
+        filter: &RecordFilter,
     ) -> Result<DbRecord> {
         let transaction_id = out_msg.transaction_cell().map(|cell| cell.repr_hash());
         if let (Some(msg), Some(cell)) = (out_msg.read_message()?, out_msg.message_cell()?) {
+            if !filter.message_passes(&msg) {
+                filter.skip(1);
+                return Ok(DbRecord::Empty)
+            }
             let boc = serialize_toc(&cell)?;
             let proof = if add_proof {
                 Some(serialize_toc(&msg.prepare_proof(false, &block_root)?)?)
@@ -164,7 +332,9 @@ impl<T: WriteData> Processor<T> {
         block_id: UInt256,
         workchain_id: i32,
         add_proof: bool,
-    ) -> Result<DbRecord> {
+        legacy_format: bool,
+        balance_post: Option<u128>,
+    ) -> Result<(DbRecord, Option<u128>)> {
         let cell = transaction_slice.reference(0)?.clone();
         let boc = serialize_toc(&cell).unwrap();
         let transaction: Transaction = Transaction::construct_from(&mut cell.clone().into())?;
@@ -173,6 +343,14 @@ impl<T: WriteData> Processor<T> {
         } else {
             None
         };
+        let enrichment = if legacy_format {
+            None
+        } else {
+            let state_update = transaction.read_state_update()?;
+            let delta = Self::transaction_balance_delta(&transaction)?;
+            let balance_pre = balance_post.and_then(|post| u128::try_from(post as i128 - delta).ok());
+            Some((state_update.new_hash, balance_pre))
+        };
         let set = ton_block_json::TransactionSerializationSet {
             transaction,
             id: cell.repr_hash(),
@@ -182,13 +360,52 @@ impl<T: WriteData> Processor<T> {
             boc,
             proof,
         };
-        let doc = ton_block_json::db_serialize_transaction("id", &set)?;
-        Ok(DbRecord::Transaction(
-            doc["id"].to_string(),
-            format!("{:#}", serde_json::json!(doc))
+        let mut doc = ton_block_json::db_serialize_transaction("id", &set)?;
+        let balance_pre = match enrichment {
+            Some((account_state_hash, balance_pre)) => {
+                doc.insert("schema_version".to_owned(), TRANSACTION_SCHEMA_VERSION.into());
+                doc.insert("account_state_hash".to_owned(), account_state_hash.to_hex_string().into());
+                doc.insert("balance_pre".to_owned(), balance_pre.map(|b| b.to_string()).into());
+                doc.insert("balance_post".to_owned(), balance_post.map(|b| b.to_string()).into());
+                balance_pre
+            }
+            None => None
+        };
+        Ok((
+            DbRecord::Transaction(
+                doc["id"].to_string(),
+                format!("{:#}", serde_json::json!(doc))
+            ),
+            balance_pre
         ))
     }
 
+    // Change of account balance made by transaction, it is computed the same way validator
+    // checks it: imported value - exported value with forwarding fees - total fees
+    fn transaction_balance_delta(transaction: &Transaction) -> Result<i128> {
+        let mut delta = 0;
+        if let Some(header) = transaction.read_in_msg()?.as_ref().and_then(|msg| msg.int_header()) {
+            delta += header.value.grams.0 as i128;
+        }
+        transaction.out_msgs.iterate_slices(|slice| {
+            let msg = Message::construct_from_cell(slice.reference(0)?)?;
+            if let Some(header) = msg.int_header() {
+                delta -= (header.value.grams.0 + header.ihr_fee.0 + header.fwd_fee().0) as i128;
+            }
+            Ok(true)
+        })?;
+        delta -= transaction.total_fees().grams.0 as i128;
+        Ok(delta)
+    }
+
+    fn account_balance(shard_accounts: &ShardAccounts, account_id: &AccountId) -> Result<u128> {
+        let balance = match shard_accounts.account(account_id)? {
+            Some(shard_acc) => shard_acc.read_account()?.balance().map(|b| b.grams.0).unwrap_or_default(),
+            None => 0 // deleted
+        };
+        Ok(balance)
+    }
+
     fn prepare_account_record(account: Account) -> Result<DbRecord> {
         let boc = serialize_toc(&account.serialize()?.into())?;
         let set = ton_block_json::AccountSerializationSet {
@@ -204,6 +421,36 @@ impl<T: WriteData> Processor<T> {
         ))
     }
 
+    fn prepare_account_state_record(
+        workchain_id: i32,
+        account_id: &AccountId,
+        shard_account: Option<ShardAccount>,
+        block_id: &BlockIdExt,
+    ) -> Result<DbRecord> {
+        let address = format!("{}:{}", workchain_id, account_id.to_hex_string());
+        let data = match shard_account {
+            Some(shard_account) => AccountStateData {
+                address: address.clone(),
+                block_id: block_id.root_hash().to_hex_string(),
+                block_seq_no: block_id.seq_no(),
+                deleted: false,
+                last_trans_lt: shard_account.last_trans_lt(),
+                last_trans_hash: shard_account.last_trans_hash().to_hex_string(),
+                boc: Some(base64::encode(&serialize_toc(&shard_account.account_cell())?)),
+            },
+            None => AccountStateData {
+                address: address.clone(),
+                block_id: block_id.root_hash().to_hex_string(),
+                block_seq_no: block_id.seq_no(),
+                deleted: true,
+                last_trans_lt: 0,
+                last_trans_hash: UInt256::default().to_hex_string(),
+                boc: None,
+            }
+        };
+        Ok(DbRecord::AccountState(address, serde_json::to_string(&data)?))
+    }
+
     fn prepare_deleted_account_record(account_id: AccountId, workchain_id: i32) -> Result<DbRecord> {
         let set = ton_block_json::DeletedAccountSerializationSet {
             account_id,
@@ -228,11 +475,7 @@ impl<T: WriteData> Processor<T> {
             status: BlockProcessingStatus::Finalized,
             boc: block_boc,
         };
-        let doc = ton_block_json::db_serialize_block("id", &set)?;
-        Ok(DbRecord::Block(
-            doc["id"].to_string(),
-            format!("{:#}", serde_json::json!(doc))
-        ))
+        Ok(DbRecord::Block(ton_block_json::db_serialize_block("id", &set)?))
     }
 
     fn prepare_raw_block_record(
@@ -245,6 +488,30 @@ impl<T: WriteData> Processor<T> {
         ))
     }
 
+    fn prepare_raw_block_proof_record(
+        block_root: &Cell,
+        proof_boc: Vec<u8>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+    ) -> Result<DbRecord> {
+        Ok(DbRecord::RawBlockProof(
+            block_root.repr_hash().as_slice().to_vec(),
+            proof_boc,
+            headers
+        ))
+    }
+
+    fn prepare_key_block_proof_record(
+        block_root: &Cell,
+        proof_boc: Vec<u8>,
+        headers: Vec<(&'static str, Vec<u8>)>,
+    ) -> Result<DbRecord> {
+        Ok(DbRecord::KeyBlockProof(
+            block_root.repr_hash().as_slice().to_vec(),
+            proof_boc,
+            headers
+        ))
+    }
+
     fn prepare_block_proof_record(proof: &BlockProof) -> Result<DbRecord> {
         let doc = ton_block_json::db_serialize_block_proof("id", proof)?;
         Ok(DbRecord::BlockProof(
@@ -291,7 +558,9 @@ impl<T: WriteData> Processor<T> {
         let process_message = self.write_message.enabled();
         let process_transaction = self.write_transaction.enabled();
         let process_account = self.write_account.enabled();
+        let process_account_state = self.write_account_state.enabled();
         let process_block_proof = self.write_block_proof.enabled();
+        let process_raw_block_proof = self.write_raw_block_proof.enabled();
         let block_id = block_stuff.id().clone();
         let block = block_stuff.block().clone();
         let proof = block_proof.map(|p| p.proof().clone());
@@ -299,7 +568,27 @@ impl<T: WriteData> Processor<T> {
         let block_extra = block.read_extra()?;
         let block_boc1 = if process_block { Some(block_stuff.data().to_vec()) } else { None };
         let block_boc2 = if process_raw_block { Some(block_stuff.data().to_vec()) } else { None };
+        let proof_boc = if process_raw_block_proof { 
+            block_proof.map(|p| p.data().to_vec())
+        } else { 
+            None 
+        };
+        let block_info = block.read_info()?;
+        let proof_headers = vec!(
+            (IS_KEY_BLOCK_HEADER, vec!(block_info.key_block() as u8)),
+            (PREV_KEY_BLOCK_SEQNO_HEADER, block_info.prev_key_block_seqno().to_be_bytes().to_vec()),
+        );
+        // Key blocks are written into dedicated topic even if full proofs feed is disabled
+        let key_block_proof_boc = if self.write_key_block.enabled() 
+            && block_id.shard().is_masterchain() && block_info.key_block() 
+        {
+            block_proof.map(|p| p.data().to_vec())
+        } else {
+            None
+        };
         let shard_accounts = state.map(|s| s.state().read_accounts()).transpose()?;
+        let filter = self.filter.clone();
+        let legacy_transaction_format = self.legacy_transaction_format;
 
         let now = std::time::Instant::now();
 
@@ -312,17 +601,20 @@ impl<T: WriteData> Processor<T> {
                 let now = std::time::Instant::now();
                 let mut msg_count = 0;
                 block_extra.read_in_msg_descr()?.iterate_objects(|msg| {
-                    msg_count += 1;
-                    db_records.push(
-                        Self::prepare_in_message_record(msg, &block_root, block_root.repr_hash(), add_proof)?
-                    );
+                    match Self::prepare_in_message_record(msg, &block_root, block_root.repr_hash(), add_proof, &filter)? {
+                        DbRecord::Empty => (),
+                        r => {
+                            msg_count += 1;
+                            db_records.push(r);
+                        }
+                    }
                     Ok(true)
                 })?;
                 log::trace!("TIME: in messages {} {}ms;   {}", msg_count, now.elapsed().as_millis(), block_id);
                 let now = std::time::Instant::now();
                 let mut msg_count = 0;
                 block_extra.read_out_msg_descr()?.iterate_objects(|msg| {
-                    match Self::prepare_out_message_record(msg, &block_root, block_root.repr_hash(), add_proof)? {
+                    match Self::prepare_out_message_record(msg, &block_root, block_root.repr_hash(), add_proof, &filter)? {
                         DbRecord::Empty => (),
                         r => {
                             msg_count += 1;
@@ -336,35 +628,74 @@ impl<T: WriteData> Processor<T> {
             // Transactions
             let mut changed_acc = HashSet::new();
             let mut deleted_acc = HashSet::new();
-            if process_transaction || process_account{
+            if process_transaction || process_account || process_account_state {
                 let now = std::time::Instant::now();
                 let mut tr_count = 0;
                 let workchain_id = block.read_info()?.shard().workchain_id();
 
                 block_extra.read_account_blocks()?.iterate_objects(|account_block: AccountBlock| {
                     let state_upd = account_block.read_state_update()?;
-                    if process_account && state_upd.old_hash != state_upd.new_hash {
+                    if (process_account || process_account_state) && state_upd.old_hash != state_upd.new_hash {
                         if state_upd.new_hash == *ACCOUNT_NONE_HASH {
                             deleted_acc.insert(account_block.account_id().clone());
                         } else {
                             changed_acc.insert(account_block.account_id().clone());
                         }
                     }
-                    if process_transaction {
+                    if process_transaction && !filter.account_passes(workchain_id, account_block.account_id()) {
+                        filter.skip(account_block.transactions().len()? as u64);
+                    } else if process_transaction {
+                        // Balance after the last transaction is taken from the new state,
+                        // the previous ones are restored backwards by transactions' deltas
+                        let mut balance = match shard_accounts.as_ref() {
+                            Some(accounts) if !legacy_transaction_format =>
+                                Some(Self::account_balance(accounts, account_block.account_id())?),
+                            _ => None
+                        };
+                        let mut transactions = Vec::new();
                         account_block.transactions().iterate_slices(|_, transaction_slice| {
-                            tr_count += 1;
-                            db_records.push(
-                                Self::prepare_transaction_record(
-                                    transaction_slice, &block_root, block_root.repr_hash(), workchain_id, add_proof
-                                )?
-                            );
+                            transactions.push(transaction_slice);
                             Ok(true)
                         })?;
+                        let first = db_records.len();
+                        for transaction_slice in transactions.into_iter().rev() {
+                            tr_count += 1;
+                            let (record, balance_pre) = Self::prepare_transaction_record(
+                                transaction_slice, &block_root, block_root.repr_hash(), workchain_id, add_proof,
+                                legacy_transaction_format, balance
+                            )?;
+                            db_records.push(record);
+                            balance = balance_pre;
+                        }
+                        db_records[first..].reverse();
                     }
                     Ok(true)
                 })?;
                 log::trace!("TIME: transactions {} {}ms;   {}", tr_count, now.elapsed().as_millis(), block_id);
 
+                // Account states feed
+                if process_account_state {
+                    if let Some(shard_accounts) = shard_accounts.as_ref() {
+                        for acc_addr in changed_acc.iter() {
+                            let shard_acc = shard_accounts.account(acc_addr)?
+                                .ok_or_else(|| 
+                                    NodeError::InvalidData(
+                                        "Block and shard state mismatch: \
+                                        state doesn't contain changed account".to_string()
+                                    )
+                                )?;
+                            db_records.push(Self::prepare_account_state_record(
+                                workchain_id, acc_addr, Some(shard_acc), &block_id
+                            )?);
+                        }
+                    }
+                    for acc_addr in deleted_acc.iter() {
+                        db_records.push(Self::prepare_account_state_record(
+                            workchain_id, acc_addr, None, &block_id
+                        )?);
+                    }
+                }
+
                 // Accounts (changed only)
                 if process_account {
                     let now = std::time::Instant::now();
@@ -420,6 +751,19 @@ impl<T: WriteData> Processor<T> {
                 log::trace!("TIME: raw block {}ms;   {}", now.elapsed().as_millis(), block_id);
             }
 
+            // raw block proof
+            if let Some(proof_boc) = proof_boc {
+                db_records.push(
+                    Self::prepare_raw_block_proof_record(&block_root, proof_boc, proof_headers.clone())?
+                );
+            }
+
+            if let Some(proof_boc) = key_block_proof_boc {
+                db_records.push(
+                    Self::prepare_key_block_proof_record(&block_root, proof_boc, proof_headers)?
+                );
+            }
+
             Ok(db_records)
         }).await;
 
@@ -439,18 +783,40 @@ impl<T: WriteData> Processor<T> {
             Ok(Ok(db_records)) => db_records
         };
 
+        let delivery = self.start_delivery(block_stuff.id());
+        let mut block_doc = None;
         let mut send_tasks = vec!();
         for record in db_records {
             if let Some(send_task) = match record {
-                DbRecord::Message(key, value) => Some(self.write_message.write_data(key, value)),
-                DbRecord::Transaction(key, value) => Some(self.write_transaction.write_data(key, value)),
-                DbRecord::Account(key, value) => Some(self.write_account.write_data(key, value)),
-                DbRecord::Block(key, value) => Some(self.write_block.write_data(key, value)),
+                DbRecord::Message(key, value) => {
+                    Some(self.write_message.write_data(key, value, delivery.clone()))
+                }
+                DbRecord::Transaction(key, value) => {
+                    Some(self.write_transaction.write_data(key, value, delivery.clone()))
+                }
+                DbRecord::Account(key, value) => {
+                    Some(self.write_account.write_data(key, value, delivery.clone()))
+                }
+                DbRecord::AccountState(key, value) => {
+                    Some(self.write_account_state.write_data(key, value, delivery.clone()))
+                }
+                DbRecord::Block(doc) => {
+                    block_doc = Some(doc);
+                    None
+                }
                 DbRecord::RawBlock(key, value) => {
                     STATSD.histogram("raw_block_size", value.len() as f64);
-                    Some(self.write_raw_block.write_raw_data(key, value))
+                    Some(self.write_raw_block.write_raw_data(key, value, delivery.clone()))
+                }
+                DbRecord::RawBlockProof(key, value, headers) => {
+                    Some(self.write_raw_block_proof.write_raw_data_with_headers(key, value, headers, delivery.clone()))
+                }
+                DbRecord::KeyBlockProof(key, value, headers) => {
+                    Some(self.write_key_block.write_raw_data_with_headers(key, value, headers, delivery.clone()))
+                }
+                DbRecord::BlockProof(key, value) => {
+                    Some(self.write_block_proof.write_data(key, value, delivery.clone()))
                 }
-                DbRecord::BlockProof(key, value) => Some(self.write_block_proof.write_data(key, value)),
                 DbRecord::Empty => None
             } {
                 send_tasks.push(send_task);
@@ -462,11 +828,19 @@ impl<T: WriteData> Processor<T> {
         let now = std::time::Instant::now();
 
         // Await while all the block stuff been written
-        futures::future::join_all(send_tasks)
+        let result = futures::future::join_all(send_tasks)
             .await
             .into_iter()
             .find(|r| r.is_err())
-            .unwrap_or(Ok(()))?;
+            .unwrap_or(Ok(()));
+        let result = match (result, block_doc) {
+            (Ok(()), Some(doc)) => self.write_block_record(block_stuff.id().shard(), doc, delivery.clone()).await,
+            (result, _) => result
+        };
+        if let Some(delivery) = delivery {
+            delivery.remove_pending();
+        }
+        result?;
 
         log::trace!("TIME: sent to kafka {}ms;   {}", now.elapsed().as_millis(), block_stuff.id());
 
@@ -506,7 +880,7 @@ impl<T: WriteData> ExternalDb for Processor<T> {
             futures::future::join_all(
                 accounts.into_iter().map(|r| {
                     match r {
-                        DbRecord::Account(key, value) => self.write_account.write_data(key, value),
+                        DbRecord::Account(key, value) => self.write_account.write_data(key, value, None),
                         _ => unreachable!(),
                     }
                 })
@@ -524,6 +898,50 @@ impl<T: WriteData> ExternalDb for Processor<T> {
         Ok(())
     }
 
+    async fn process_block_revoked(&self, id: &BlockIdExt, reason: &str) -> Result<()> {
+        if self.write_block_revoked.enabled() && self.process_workchain(id.shard().workchain_id()) {
+            let data = BlockRevokedData::new(id, reason);
+            self.write_block_revoked.write_data(data.id.clone(), serde_json::to_string(&data)?, None).await?;
+        }
+        Ok(())
+    }
+
+    fn process_ext_msg_status_enabled(&self) -> bool {
+        self.write_ext_msg_status.enabled()
+    }
+
+    async fn process_ext_msg_status(&self, id: &UInt256, status: &ExtMessageStatus, updated_at: u32) -> Result<()> {
+        if self.write_ext_msg_status.enabled() {
+            let mut data = ExtMessageStatusData {
+                id: id.to_hex_string(),
+                status: status.name().to_string(),
+                updated_at,
+                shard: None,
+                seq_no: None,
+                block_id: None,
+                tx_hash: None,
+                reason: None,
+            };
+            match status {
+                ExtMessageStatus::Collated { shard, seq_no } => {
+                    data.shard = Some(shard.to_string());
+                    data.seq_no = Some(*seq_no);
+                }
+                ExtMessageStatus::Committed { block_id, tx_hash } => {
+                    data.shard = Some(block_id.shard().to_string());
+                    data.seq_no = Some(block_id.seq_no());
+                    data.block_id = Some(block_id.root_hash().to_hex_string());
+                    data.tx_hash = Some(tx_hash.to_hex_string());
+                }
+                ExtMessageStatus::Rejected(reason) => data.reason = Some(reason.clone()),
+                _ => ()
+            }
+            // Key is message hash, so all transitions of message go to the same partition
+            self.write_ext_msg_status.write_data(data.id.clone(), serde_json::to_string(&data)?, None).await?;
+        }
+        Ok(())
+    }
+
     fn process_chain_range_enabled(&self) -> bool {
         self.write_chain_range.enabled()
     }
@@ -543,9 +961,201 @@ impl<T: WriteData> ExternalDb for Processor<T> {
                 data.shard_blocks_ids.push(block.root_hash().to_hex_string());
             }
 
-            self.write_chain_range.write_data(master_block_id, serde_json::to_string(&data)?).await?;
+            self.write_chain_range.write_data(master_block_id, serde_json::to_string(&data)?, None).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn healthcheck(&self, timeout: std::time::Duration) -> Result<()> {
+        let errors: Vec<String> = futures::future::join_all(
+            self.writers().iter().filter(|writer| writer.enabled()).map(|writer| writer.healthcheck(timeout))
+        )
+        .await
+        .into_iter()
+        .filter_map(|r| r.err().map(|e| e.to_string()))
+        .collect();
+        if !errors.is_empty() {
+            return Err(error!("{}", errors.join("; ")))
+        }
+        Ok(())
+    }
+
+    async fn flush_block(&self, block_id: &BlockIdExt) -> Result<()> {
+        // process_block awaits writing of all block's records, 
+        // so only records put into producers' retry queues are waited here.
+        // The wait is bounded by external db ack timeout.
+        let delivery = match &self.deliveries {
+            Some(deliveries) => deliveries.lock().unwrap().remove(block_id),
+            None => None
+        };
+        if let Some(delivery) = delivery {
+            while delivery.pending() > 0 {
+                futures_timer::Delay::new(
+                    std::time::Duration::from_millis(FLUSH_CHECK_INTERVAL_MS)
+                ).await;
+            }
+            if delivery.dead_lettered() > 0 {
+                fail!("{} records of block {} are dead-lettered", delivery.dead_lettered(), block_id)
+            }
         }
+        Ok(())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        futures::future::join_all(
+            self.writers().iter().filter(|writer| writer.enabled()).map(|writer| writer.flush())
+        )
+        .await
+        .into_iter()
+        .find(|r| r.is_err())
+        .unwrap_or(Ok(()))
+    }
+
+    fn filtered_records(&self) -> u64 {
+        self.filter.filtered()
+    }
+
+    fn set_front_workchain_ids(&self, front_workchain_ids: Option<Vec<i32>>) {
+        log::info!("External db workchains: {:?}", front_workchain_ids);
+        *self.front_workchain_ids.write().unwrap() = front_workchain_ids;
+    }
 
+    fn set_feed_storage(&self, storage: Arc<dyn InternalDb>) {
+        *self.feed_storage.write().unwrap() = Some(storage);
+    }
+
+    fn stats(&self) -> Vec<String> {
+        self.writers().iter().filter_map(|writer| writer.stats()).collect()
+    }
+
+    async fn process_validator_group_status(&self, event: &ValidatorGroupStatusEvent) -> Result<()> {
+        if self.write_validator_status.enabled() {
+            let data = ValidatorGroupStatusData {
+                session_id: event.session_id.to_hex_string(),
+                shard: event.shard.to_string(),
+                old_status: event.old_status.name().to_string(),
+                new_status: event.new_status.name().to_string(),
+                timestamp: event.timestamp,
+            };
+            self.write_validator_status.write_data(
+                format!("{}_{}", data.session_id, event.timestamp),
+                serde_json::to_string(&data)?,
+                None
+            ).await?;
+        }
         Ok(())
     }
 }
+
+#[test]
+fn test_block_revoked_record() {
+    let id = BlockIdExt::with_params(
+        ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap(),
+        1234,
+        UInt256::from([1; 32]),
+        UInt256::from([2; 32]),
+    );
+    let data = BlockRevokedData::new(&id, "superseded");
+    let json: serde_json::Value = serde_json::from_str(&serde_json::to_string(&data).unwrap()).unwrap();
+    assert_eq!(json["id"], id.root_hash().to_hex_string());
+    assert_eq!(json["workchain_id"], 0);
+    assert_eq!(json["shard"], "8000000000000000");
+    assert_eq!(json["seq_no"], 1234);
+    assert_eq!(json["reason"], "superseded");
+    assert_eq!(json.as_object().unwrap().len(), 5);
+}
+
+#[test]
+fn test_front_workchains_switch() {
+    use crate::{config::ExternalDbFilterConfig, external_db::stub_producer::StubProducer};
+
+    let stub = || StubProducer { enabled: true };
+    let processor = Processor::new(
+        stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(),
+        stub(), stub(), String::new(), String::new(), Some(vec![-1, 0]),
+        RecordFilter::with_config(&ExternalDbFilterConfig::default()).unwrap(), false, false
+    );
+    assert!(processor.process_workchain(-1));
+    assert!(processor.process_workchain(0));
+    assert!(!processor.process_workchain(1));
+
+    // config reload switches workchains without restart
+    processor.set_front_workchain_ids(Some(vec![1]));
+    assert!(!processor.process_workchain(0));
+    assert!(processor.process_workchain(1));
+    processor.set_front_workchain_ids(None);
+    assert!(processor.process_workchain(0));
+    assert!(processor.process_workchain(7));
+}
+
+#[cfg(test)]
+fn create_tracking_processor() -> Processor<crate::external_db::stub_producer::StubProducer> {
+    use crate::{config::ExternalDbFilterConfig, external_db::stub_producer::StubProducer};
+
+    let stub = || StubProducer { enabled: true };
+    Processor::new(
+        stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(), stub(),
+        stub(), stub(), String::new(), String::new(), None,
+        RecordFilter::with_config(&ExternalDbFilterConfig::default()).unwrap(), false, true
+    )
+}
+
+#[test]
+fn test_flush_block_waits_own_records() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let processor = create_tracking_processor();
+    let block1 = BlockIdExt::with_params(ShardIdent::masterchain(), 1, UInt256::from([1; 32]), UInt256::default());
+    let block2 = BlockIdExt::with_params(ShardIdent::masterchain(), 2, UInt256::from([2; 32]), UInt256::default());
+    let delivery1 = processor.start_delivery(&block1).unwrap();
+    let delivery2 = processor.start_delivery(&block2).unwrap();
+    // block 1 is written, one record is in retry queue; block 2 stays stuck
+    delivery1.add_pending();
+    delivery1.remove_pending();
+    let worker = delivery1.clone();
+    rt.spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(30)).await;
+        worker.remove_pending();
+    });
+    rt.block_on(processor.flush_block(&block1)).unwrap();
+    assert_eq!(delivery1.pending(), 0);
+    assert_eq!(delivery2.pending(), 1);
+    // block which is unknown (not tracked or already settled) has nothing to wait
+    rt.block_on(processor.flush_block(&block1)).unwrap();
+}
+
+#[test]
+fn test_flush_block_fails_on_dead_lettered() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let processor = create_tracking_processor();
+    let block1 = BlockIdExt::with_params(ShardIdent::masterchain(), 1, UInt256::from([1; 32]), UInt256::default());
+    let block2 = BlockIdExt::with_params(ShardIdent::masterchain(), 2, UInt256::from([2; 32]), UInt256::default());
+    let delivery1 = processor.start_delivery(&block1).unwrap();
+    delivery1.add_dead_lettered();
+    delivery1.remove_pending();
+    // failed block is not pruned when the next one starts
+    let delivery2 = processor.start_delivery(&block2).unwrap();
+    delivery2.remove_pending();
+    assert!(rt.block_on(processor.flush_block(&block1)).is_err());
+    rt.block_on(processor.flush_block(&block2)).unwrap();
+}
+
+#[test]
+fn test_shard_feed_numbering() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let processor = create_tracking_processor();
+    let doc = |id: &str| {
+        let mut doc = serde_json::Map::new();
+        doc.insert("id".to_string(), id.into());
+        doc
+    };
+    let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    rt.block_on(async {
+        processor.write_block_record(&shard, doc("a"), None).await.unwrap();
+        processor.write_block_record(&shard, doc("b"), None).await.unwrap();
+        processor.write_block_record(&ShardIdent::masterchain(), doc("c"), None).await.unwrap();
+        let feeds = processor.shard_feeds.lock().await;
+        assert_eq!(feeds.get(&shard), Some(&2));
+        assert_eq!(feeds.get(&ShardIdent::masterchain()), Some(&1));
+    });
+}
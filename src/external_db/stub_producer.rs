@@ -1,5 +1,6 @@
-use crate::external_db::WriteData;
+use crate::external_db::{BlockDelivery, WriteData};
 
+use std::sync::Arc;
 use ton_types::Result;
 
 #[derive(Clone)]
@@ -10,12 +11,41 @@ pub struct StubProducer {
 #[async_trait::async_trait]
 impl WriteData for StubProducer {
     fn enabled(&self) -> bool { self.enabled }
-    async fn write_data(&self, _key: String, _data: String) -> Result<()> {
+    async fn write_data(
+        &self,
+        _key: String,
+        _data: String,
+        _delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
         futures_timer::Delay::new(std::time::Duration::from_millis(3)).await;
         Ok(())
     }
-    async fn write_raw_data(&self, _key: Vec<u8>, _data: Vec<u8>) -> Result<()> {
+    async fn write_raw_data(
+        &self,
+        _key: Vec<u8>,
+        _data: Vec<u8>,
+        _delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
         futures_timer::Delay::new(std::time::Duration::from_millis(3)).await;
         Ok(())
     }
+    async fn write_raw_data_with_headers(
+        &self,
+        _key: Vec<u8>,
+        _data: Vec<u8>,
+        _headers: Vec<(&'static str, Vec<u8>)>,
+        _delivery: Option<Arc<BlockDelivery>>
+    ) -> Result<()> {
+        futures_timer::Delay::new(std::time::Duration::from_millis(3)).await;
+        Ok(())
+    }
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    async fn healthcheck(&self, _timeout: std::time::Duration) -> Result<()> {
+        Ok(())
+    }
+    fn stats(&self) -> Option<String> {
+        None
+    }
 }
@@ -0,0 +1,177 @@
+use crate::{
+    config::{ExternalDbAckConfig, ExternalDbAckTimeoutPolicy}, engine::STATSD,
+    engine_traits::ExternalDb
+};
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, time::Duration};
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+/// Awaits delivery confirmation of block's records from external databases
+/// before the block is marked as applied
+pub struct ExternalDbAck {
+    config: ExternalDbAckConfig,
+    degraded: AtomicBool,
+}
+
+impl ExternalDbAck {
+
+    pub fn new(config: ExternalDbAckConfig) -> Self {
+        Self {
+            config,
+            degraded: AtomicBool::new(false),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.wait_for_external_db_ack && !self.degraded.load(Ordering::Relaxed)
+    }
+
+    pub async fn wait(&self, ext_db: &[Arc<dyn ExternalDb>], block_id: &BlockIdExt) -> Result<()> {
+        if !self.enabled() || ext_db.is_empty() {
+            return Ok(())
+        }
+        let flush = futures::future::join_all(ext_db.iter().map(|db| db.flush_block(block_id)));
+        // Zero timeout is rejected by config check
+        let timeout = Duration::from_millis(self.config.external_db_ack_timeout_ms);
+        let results = match tokio::time::timeout(timeout, flush).await {
+            Ok(results) => results,
+            Err(_) => return self.on_timeout(block_id)
+        };
+        results.into_iter().find(|r| r.is_err()).unwrap_or(Ok(()))
+    }
+
+    fn on_timeout(&self, block_id: &BlockIdExt) -> Result<()> {
+        match self.config.external_db_ack_timeout_policy {
+            ExternalDbAckTimeoutPolicy::Fail => fail!(
+                "Timeout {}ms expired while waiting external db ack for block {}",
+                self.config.external_db_ack_timeout_ms, block_id
+            ),
+            ExternalDbAckTimeoutPolicy::Degrade => {
+                if !self.degraded.swap(true, Ordering::Relaxed) {
+                    log::warn!(
+                        "Timeout {}ms expired while waiting external db ack for block {}, \
+                        switched to async mode",
+                        self.config.external_db_ack_timeout_ms, block_id
+                    );
+                    STATSD.incr("external_db_ack_degraded");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+struct ExternalDbMock {
+    flush_delay_ms: u64,
+    flushed: std::sync::atomic::AtomicU32,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl ExternalDb for ExternalDbMock {
+    async fn process_block(
+        &self,
+        _block: &crate::block::BlockStuff,
+        _proof: Option<&crate::block_proof::BlockProofStuff>,
+        _state: &crate::shard_state::ShardStateStuff
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn process_full_state(&self, _state: &crate::shard_state::ShardStateStuff) -> Result<()> {
+        Ok(())
+    }
+    fn process_chain_range_enabled(&self) -> bool {
+        false
+    }
+    async fn process_chain_range(&self, _range: &crate::engine_traits::ChainRange) -> Result<()> {
+        Ok(())
+    }
+    async fn process_validator_group_status(
+        &self,
+        _event: &crate::validator::validator_group::ValidatorGroupStatusEvent
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn process_block_revoked(&self, _id: &BlockIdExt, _reason: &str) -> Result<()> {
+        Ok(())
+    }
+    fn process_ext_msg_status_enabled(&self) -> bool {
+        false
+    }
+    async fn process_ext_msg_status(
+        &self,
+        _id: &ton_types::UInt256,
+        _status: &crate::ext_messages::ExtMessageStatus,
+        _updated_at: u32
+    ) -> Result<()> {
+        Ok(())
+    }
+    async fn healthcheck(&self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+    async fn flush_block(&self, _block_id: &BlockIdExt) -> Result<()> {
+        tokio::time::sleep(Duration::from_millis(self.flush_delay_ms)).await;
+        self.flushed.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+    fn stats(&self) -> Vec<String> {
+        Vec::new()
+    }
+    fn filtered_records(&self) -> u64 {
+        0
+    }
+    fn set_front_workchain_ids(&self, _front_workchain_ids: Option<Vec<i32>>) {}
+    fn set_feed_storage(&self, _storage: Arc<dyn crate::internal_db::InternalDb>) {}
+}
+
+#[cfg(test)]
+fn create_ack_test(
+    flush_delay_ms: u64,
+    policy: ExternalDbAckTimeoutPolicy
+) -> (ExternalDbAck, Arc<ExternalDbMock>) {
+    let config = ExternalDbAckConfig {
+        wait_for_external_db_ack: true,
+        external_db_ack_timeout_ms: 50,
+        external_db_ack_timeout_policy: policy,
+    };
+    let db = Arc::new(ExternalDbMock { flush_delay_ms, flushed: Default::default() });
+    (ExternalDbAck::new(config), db)
+}
+
+#[test]
+fn test_external_db_ack_delivered() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (ack, db) = create_ack_test(1, ExternalDbAckTimeoutPolicy::Fail);
+    let ext_db = vec!(db.clone() as Arc<dyn ExternalDb>);
+    rt.block_on(ack.wait(&ext_db, &BlockIdExt::default())).unwrap();
+    assert_eq!(db.flushed.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_external_db_ack_timeout_fails() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (ack, db) = create_ack_test(1000, ExternalDbAckTimeoutPolicy::Fail);
+    let ext_db = vec!(db as Arc<dyn ExternalDb>);
+    assert!(rt.block_on(ack.wait(&ext_db, &BlockIdExt::default())).is_err());
+    // Next blocks keep waiting
+    assert!(ack.enabled());
+    assert!(rt.block_on(ack.wait(&ext_db, &BlockIdExt::default())).is_err());
+}
+
+#[test]
+fn test_external_db_ack_timeout_degrades() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (ack, db) = create_ack_test(1000, ExternalDbAckTimeoutPolicy::Degrade);
+    let ext_db = vec!(db.clone() as Arc<dyn ExternalDb>);
+    rt.block_on(ack.wait(&ext_db, &BlockIdExt::default())).unwrap();
+    assert!(!ack.enabled());
+    // Async mode: flush is not awaited anymore
+    let now = std::time::Instant::now();
+    rt.block_on(ack.wait(&ext_db, &BlockIdExt::default())).unwrap();
+    assert!(now.elapsed() < Duration::from_millis(50));
+    assert_eq!(db.flushed.load(Ordering::Relaxed), 0);
+}
@@ -1,10 +1,11 @@
 use crate::{
-    block::BlockStuff, engine_traits::EngineOperations, shard_state::ShardStateStuff
+    block::BlockStuff, boot, engine_traits::EngineOperations, full_node::apply_stages::ApplyStage,
+    jaeger, shard_state::ShardStateStuff
 };
-use std::{ops::Deref, sync::Arc};
+use std::{collections::HashMap, ops::Deref, sync::Arc, time::{Instant, SystemTime}};
 use storage::types::BlockHandle;
 use ton_types::{error, fail, Result};
-use ton_block::BlockIdExt;
+use ton_block::{BlockIdExt, ShardIdent};
 
 pub const MAX_RECURSION_DEPTH: u32 = 16;
 
@@ -20,15 +21,28 @@ pub async fn apply_block(
         fail!("Block id mismatch in apply block: {} vs {}", handle.id(), block.id())
     }
     let prev_ids = block.construct_prev_id()?;    
+    if block.id().shard().is_masterchain() {
+        crate::boot::check_block_hardforks(engine.get_hardforks(), block.id(), &prev_ids.0)?;
+    }
+    let now = Instant::now();
     check_prev_blocks(&prev_ids, engine, mc_seq_no, pre_apply, recursion_depth).await?;
+    engine.report_apply_stage(block.id().shard(), ApplyStage::Queued, now.elapsed());
+    let key_block = block.block().read_info()?.key_block();
     let shard_state = if handle.has_state() {
         engine.load_state(handle.id()).await?
     } else {
-        calc_shard_state(handle, block, &prev_ids, engine).await?
+        let started = SystemTime::now();
+        let state = calc_shard_state(handle, block, &prev_ids, engine).await?;
+        jaeger::block_stage(block.id(), key_block, "state applied", started);
+        state
     };
     if !pre_apply {
+        let now = Instant::now();
         set_next_prev_ids(&handle, &prev_ids, engine.deref())?;
+        engine.report_apply_stage(block.id().shard(), ApplyStage::HandleUpdate, now.elapsed());
+        let started = SystemTime::now();
         engine.process_block_in_ext_db(handle, &block, None, &shard_state).await?;
+        jaeger::block_stage(block.id(), key_block, "external db produced", started);
     }
     Ok(())
 }
@@ -44,12 +58,19 @@ async fn check_prev_blocks(
     match prev_ids {
         (prev1_id, Some(prev2_id)) => {
             let mut apply_prev_futures = Vec::with_capacity(2);
-            apply_prev_futures.push(
-                engine.clone().download_and_apply_block_internal(&prev1_id, mc_seq_no, pre_apply, recursion_depth + 1)
-            );
-            apply_prev_futures.push(
-                engine.clone().download_and_apply_block_internal(&prev2_id, mc_seq_no, pre_apply, recursion_depth + 1)
-            );
+            for prev_id in vec!(prev1_id, prev2_id) {
+                // Merge with not tracked shard: only its state is needed
+                let tracked = engine.is_shard_tracked(prev_id.shard());
+                if !tracked && !has_state(engine.deref(), prev_id)? {
+                    let key_block_id = find_persistent_key_block(engine, mc_seq_no).await?;
+                    restore_untracked_state(engine, prev_id, &key_block_id, mc_seq_no).await?;
+                }
+                apply_prev_futures.push(
+                    engine.clone().download_and_apply_block_internal(
+                        prev_id, mc_seq_no, pre_apply || !tracked, recursion_depth + 1
+                    )
+                );
+            }
             futures::future::join_all(apply_prev_futures)
                 .await
                 .into_iter()
@@ -63,6 +84,96 @@ async fn check_prev_blocks(
     Ok(())
 }
 
+fn has_state(engine: &dyn EngineOperations, id: &BlockIdExt) -> Result<bool> {
+    Ok(engine.load_block_handle(id)?.map(|handle| handle.has_state()).unwrap_or(false))
+}
+
+// The last key block before masterchain block `mc_seq_no` with persistent states which
+// are still kept by peers
+async fn find_persistent_key_block(
+    engine: &Arc<dyn EngineOperations>,
+    mc_seq_no: u32
+) -> Result<BlockIdExt> {
+    let mc_state = engine.load_last_applied_mc_state().await?;
+    let prev_blocks = &mc_state.shard_state_extra()?.prev_blocks;
+    let gen_utime = |seq_no| -> Result<u32> {
+        match prev_blocks.get_prev_key_block(seq_no)? {
+            Some(block_ref) => {
+                let id = block_ref.master_block_id().1;
+                engine.load_block_handle(&id)?.ok_or_else(
+                    || error!("Cannot load handle for key block {}", id)
+                )?.gen_utime()
+            }
+            None => Ok(0)
+        }
+    };
+    let mut seq_no = mc_seq_no;
+    while seq_no > 1 {
+        let key_block_id = match prev_blocks.get_prev_key_block(seq_no - 1)? {
+            Some(block_ref) if block_ref.seq_no > 0 => block_ref.master_block_id().1,
+            _ => break
+        };
+        let block_time = gen_utime(key_block_id.seq_no())?;
+        let prev_time = gen_utime(key_block_id.seq_no() - 1)?;
+        if engine.is_persistent_state(block_time, prev_time, boot::PSS_PERIOD_BITS) &&
+            engine.persistent_state_ttl(block_time, boot::PSS_PERIOD_BITS) > engine.now() {
+            return Ok(key_block_id)
+        }
+        seq_no = key_block_id.seq_no();
+    }
+    fail!("No persistent states before masterchain block {}", mc_seq_no)
+}
+
+fn untracked_blocks(
+    engine: &dyn EngineOperations,
+    shard_blocks: HashMap<ShardIdent, BlockIdExt>,
+    shard: &ShardIdent
+) -> Vec<BlockIdExt> {
+    shard_blocks.into_iter()
+        .map(|(_, id)| id)
+        .filter(|id| id.shard().intersect_with(shard) && !engine.is_shard_tracked(id.shard()))
+        .collect()
+}
+
+// Partial mode: block after merge of tracked shard with not tracked one needs state of the
+// latter. States of not tracked shards are downloaded as persistent ones of the key block,
+// then their blocks listed in next masterchain blocks are pre-applied: states are
+// calculated, but blocks are not marked as applied.
+pub(crate) async fn restore_untracked_state(
+    engine: &Arc<dyn EngineOperations>,
+    id: &BlockIdExt,
+    key_block_id: &BlockIdExt,
+    mc_seq_no: u32
+) -> Result<()> {
+    log::info!("Restoring state of not tracked block {} from key block {}", id, key_block_id);
+    let active_peers = Arc::new(lockfree::set::Set::new());
+    let shard_blocks = engine.load_mc_shard_blocks(key_block_id.seq_no()).await?;
+    for block_id in untracked_blocks(engine.deref(), shard_blocks, id.shard()) {
+        let (handle, block) = boot::download_block(engine.deref(), &block_id).await?;
+        if handle.has_state() {
+            continue
+        }
+        let state = engine.download_state(&block_id, key_block_id, &active_peers).await?;
+        if block.block().read_state_update()?.new_hash != state.root_cell().repr_hash() {
+            fail!("Root hash of downloaded state {} is wrong", block_id)
+        }
+        engine.store_state(&handle, &state).await?;
+    }
+    for seq_no in key_block_id.seq_no() + 1..mc_seq_no {
+        if has_state(engine.deref(), id)? {
+            break
+        }
+        let shard_blocks = engine.load_mc_shard_blocks(seq_no).await?;
+        for block_id in untracked_blocks(engine.deref(), shard_blocks, id.shard()) {
+            engine.clone().download_and_apply_block_internal(&block_id, seq_no, true, 0).await?;
+        }
+    }
+    // Block may be not committed into masterchain before merge
+    engine.clone().download_and_apply_block_internal(id, mc_seq_no, true, 0).await?;
+    log::info!("Restored state of not tracked block {}", id);
+    Ok(())
+}
+
 // Gets prev block(s) state and applies merkle update from block to calculate new state
 pub async fn calc_shard_state(
     handle: &Arc<BlockHandle>,
@@ -73,6 +184,7 @@ pub async fn calc_shard_state(
 
     log::trace!("calc_shard_state: block: {}", block.id());
 
+    let now = Instant::now();
     let prev_ss_root = match prev_ids {
         (prev1, Some(prev2)) => {
             let ss1 = engine.clone().wait_state(prev1, None, true).await?.root_cell().clone();
@@ -84,23 +196,29 @@ pub async fn calc_shard_state(
         }
     };
 
+    let shard = block.id().shard();
+    engine.report_apply_stage(shard, ApplyStage::PrevStateLoad, now.elapsed());
+
     let merkle_update = block
         .block()
         .read_state_update()?;
     let block_id = block.id().clone();
 
+    let now = Instant::now();
     let ss = tokio::task::spawn_blocking(move || -> Result<ShardStateStuff> {
-        let now = std::time::Instant::now();
+        let now = Instant::now();
         let ss_root = merkle_update.apply_for(&prev_ss_root)?;
         log::trace!("TIME: calc_shard_state: applied Merkle update {}ms   {}",
             now.elapsed().as_millis(), block_id);
         ShardStateStuff::new(block_id.clone(), ss_root)
     }).await??;
+    engine.report_apply_stage(shard, ApplyStage::MerkleUpdate, now.elapsed());
 
-    let now = std::time::Instant::now();
+    let now = Instant::now();
     engine.store_state(handle, &ss).await?;
     log::trace!("TIME: calc_shard_state: store_state {}ms   {}",
             now.elapsed().as_millis(), handle.id());
+    engine.report_apply_stage(shard, ApplyStage::StateStore, now.elapsed());
     Ok(ss)
 
 }
@@ -142,3 +260,145 @@ pub fn set_next_prev_ids(
     }
     Ok(())
 }
+
+#[test]
+fn test_merge_with_untracked_shard() {
+    use std::{collections::HashSet, sync::{Mutex, Weak}};
+    use ton_block::{
+        Block, BlockExtra, BlockInfo, MerkleUpdate, Serializable, ShardStateUnsplit, ValueFlow
+    };
+    use ton_types::UInt256;
+
+    let shard = |prefix| ShardIdent::with_tagged_prefix(0, prefix).unwrap();
+    let left = shard(0x4000_0000_0000_0000);
+    let right = shard(0xc000_0000_0000_0000);
+    let id = |shard: &ShardIdent, seq_no: u32| BlockIdExt::with_params(
+        shard.clone(), seq_no, UInt256::from([seq_no as u8; 32]), UInt256::default()
+    );
+
+    struct TestEngine {
+        tracked: ShardIdent,
+        // Top shard blocks of masterchain blocks
+        mc_blocks: HashMap<u32, HashMap<ShardIdent, BlockIdExt>>,
+        // Block and state of not tracked shard at key block
+        base: (BlockStuff, ShardStateStuff),
+        handle_cache: Arc<lockfree::map::Map<BlockIdExt, Weak<BlockHandle>>>,
+        states: Mutex<HashSet<BlockIdExt>>,
+        applied: Mutex<Vec<(BlockIdExt, bool)>>
+    }
+    #[async_trait::async_trait]
+    impl EngineOperations for TestEngine {
+        fn is_shard_tracked(&self, shard: &ShardIdent) -> bool {
+            self.tracked.intersect_with(shard)
+        }
+        fn load_block_handle(&self, id: &BlockIdExt) -> Result<Option<Arc<BlockHandle>>> {
+            let handle = BlockHandle::new(id.clone(), self.handle_cache.clone());
+            if id == self.base.0.id() {
+                handle.set_data();
+                handle.set_proof_link();
+            }
+            if self.states.lock().unwrap().contains(id) {
+                handle.set_state();
+            }
+            Ok(Some(Arc::new(handle)))
+        }
+        async fn load_block(&self, handle: &BlockHandle) -> Result<BlockStuff> {
+            assert_eq!(handle.id(), self.base.0.id());
+            Ok(self.base.0.clone())
+        }
+        async fn load_mc_shard_blocks(
+            &self,
+            mc_seq_no: u32
+        ) -> Result<HashMap<ShardIdent, BlockIdExt>> {
+            self.mc_blocks.get(&mc_seq_no).cloned().ok_or_else(
+                || error!("No masterchain block {}", mc_seq_no)
+            )
+        }
+        async fn download_state(
+            &self,
+            block_id: &BlockIdExt,
+            master_id: &BlockIdExt,
+            _active_peers: &Arc<lockfree::set::Set<Arc<adnl::common::KeyId>>>
+        ) -> Result<ShardStateStuff> {
+            assert_eq!(block_id, self.base.0.id());
+            assert_eq!(master_id.seq_no(), 10);
+            Ok(self.base.1.clone())
+        }
+        async fn store_state(
+            &self,
+            handle: &Arc<BlockHandle>,
+            _state: &ShardStateStuff
+        ) -> Result<()> {
+            self.states.lock().unwrap().insert(handle.id().clone());
+            Ok(())
+        }
+        async fn download_and_apply_block_internal(
+            self: Arc<Self>,
+            id: &BlockIdExt,
+            _mc_seq_no: u32,
+            pre_apply: bool,
+            _recursion_depth: u32
+        ) -> Result<()> {
+            self.applied.lock().unwrap().push((id.clone(), pre_apply));
+            self.states.lock().unwrap().insert(id.clone());
+            Ok(())
+        }
+    }
+
+    // Not tracked right shard is taken from key block 10, its block 8 isn't in masterchain
+    let base_state = ShardStateStuff::with_state(
+        id(&right, 5), ShardStateUnsplit::default()
+    ).unwrap();
+    let mut info = BlockInfo::default();
+    info.set_shard(right.clone());
+    info.set_seq_no(5).unwrap();
+    let state_update = MerkleUpdate {
+        new_hash: base_state.root_cell().repr_hash(),
+        ..MerkleUpdate::default()
+    };
+    let block = Block::with_params(
+        0, info, ValueFlow::default(), state_update, BlockExtra::default()
+    ).unwrap();
+    let root = block.serialize().unwrap();
+    let data = ton_types::serialize_toc(&root).unwrap();
+    let base_id = BlockIdExt::with_params(
+        right.clone(), 5, root.repr_hash(), UInt256::calc_file_hash(&data)
+    );
+    let base_block = BlockStuff::deserialize(base_id.clone(), data).unwrap();
+    let mut mc_blocks = HashMap::new();
+    for (mc_seq_no, seq_no) in &[(10, 5), (11, 6), (12, 7)] {
+        let mut shard_blocks = HashMap::new();
+        shard_blocks.insert(left.clone(), id(&left, *seq_no));
+        let right_id = if *seq_no == 5 { base_id.clone() } else { id(&right, *seq_no) };
+        shard_blocks.insert(right.clone(), right_id);
+        mc_blocks.insert(*mc_seq_no, shard_blocks);
+    }
+    let engine = Arc::new(TestEngine {
+        tracked: left.clone(),
+        mc_blocks,
+        base: (base_block, base_state),
+        handle_cache: Arc::new(lockfree::map::Map::new()),
+        states: Mutex::new(HashSet::new()),
+        applied: Mutex::new(Vec::new())
+    });
+    let engine_ops: Arc<dyn EngineOperations> = engine.clone();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(
+        restore_untracked_state(&engine_ops, &id(&right, 8), &id(&ShardIdent::masterchain(), 10), 13)
+    ).unwrap();
+    assert!(engine.states.lock().unwrap().contains(&base_id));
+    assert_eq!(
+        *engine.applied.lock().unwrap(),
+        vec!((id(&right, 6), true), (id(&right, 7), true), (id(&right, 8), true))
+    );
+
+    // Tracked prev block of merge is applied, not tracked one is pre-applied only
+    engine.applied.lock().unwrap().clear();
+    rt.block_on(check_prev_blocks(
+        &(id(&left, 8), Some(id(&right, 8))), &engine_ops, 13, false, 0
+    )).unwrap();
+    assert_eq!(
+        *engine.applied.lock().unwrap(),
+        vec!((id(&left, 8), false), (id(&right, 8), true))
+    );
+}
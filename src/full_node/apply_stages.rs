@@ -0,0 +1,151 @@
+use crate::engine::STATSD;
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::{Duration, Instant}};
+use ton_block::ShardIdent;
+
+// Timings are pre-aggregated and emitted at most once in this period
+const EMIT_PERIOD: Duration = Duration::from_secs(10);
+// Worst stage is reported over this period
+const WORST_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum ApplyStage {
+    // Waiting for previous blocks to be applied
+    Queued,
+    ProofCheck,
+    PrevStateLoad,
+    MerkleUpdate,
+    StateStore,
+    HandleUpdate,
+}
+
+impl ApplyStage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ApplyStage::Queued => "queued",
+            ApplyStage::ProofCheck => "proof_check",
+            ApplyStage::PrevStateLoad => "prev_state_load",
+            ApplyStage::MerkleUpdate => "merkle_update",
+            ApplyStage::StateStore => "state_store",
+            ApplyStage::HandleUpdate => "handle_update",
+        }
+    }
+}
+
+#[derive(Default)]
+struct StageStat {
+    count: u32,
+    total: Duration,
+    max: Duration,
+}
+
+struct ApplyStagesInner {
+    pending: HashMap<(ShardIdent, ApplyStage), StageStat>,
+    emitted_at: Instant,
+    // Candidates to the worst stage within the window, durations are decreasing
+    worst: VecDeque<(Instant, ShardIdent, ApplyStage, Duration)>,
+}
+
+// Per-shard timings of block application stages, to see which one makes node lag
+pub struct ApplyStages {
+    inner: Mutex<ApplyStagesInner>,
+}
+
+impl Default for ApplyStages {
+    fn default() -> Self {
+        Self::with_start(Instant::now())
+    }
+}
+
+impl ApplyStages {
+
+    fn with_start(now: Instant) -> Self {
+        Self {
+            inner: Mutex::new(ApplyStagesInner {
+                pending: HashMap::new(),
+                emitted_at: now,
+                worst: VecDeque::new(),
+            })
+        }
+    }
+
+    pub fn report(&self, shard: &ShardIdent, stage: ApplyStage, elapsed: Duration) {
+        self.report_at(shard, stage, elapsed, Instant::now())
+    }
+
+    fn report_at(&self, shard: &ShardIdent, stage: ApplyStage, elapsed: Duration, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        let stat = inner.pending.entry((shard.clone(), stage)).or_default();
+        stat.count += 1;
+        stat.total += elapsed;
+        stat.max = stat.max.max(elapsed);
+
+        while inner.worst.back().map(|(_, _, _, worst)| *worst <= elapsed).unwrap_or(false) {
+            inner.worst.pop_back();
+        }
+        inner.worst.push_back((now, shard.clone(), stage, elapsed));
+        Self::expire_worst(&mut inner.worst, now);
+
+        if now.duration_since(inner.emitted_at) >= EMIT_PERIOD {
+            inner.emitted_at = now;
+            for ((shard, stage), stat) in inner.pending.drain() {
+                let name = format!("apply_stage_{}", stage.name());
+                let avg = stat.total / stat.count;
+                let metrics = STATSD.shard(&shard);
+                metrics.timer(&name, avg.as_micros() as f64 / 1000f64);
+                metrics.timer(&format!("{}_max", name), stat.max.as_micros() as f64 / 1000f64);
+            }
+        }
+    }
+
+    fn expire_worst(worst: &mut VecDeque<(Instant, ShardIdent, ApplyStage, Duration)>, now: Instant) {
+        let expired = |at: &Instant| now.duration_since(*at) > WORST_WINDOW;
+        while worst.front().map(|(at, _, _, _)| expired(at)).unwrap_or(false) {
+            worst.pop_front();
+        }
+    }
+
+    pub fn status(&self) -> String {
+        self.status_at(Instant::now())
+    }
+
+    fn status_at(&self, now: Instant) -> String {
+        let mut inner = self.inner.lock().unwrap();
+        Self::expire_worst(&mut inner.worst, now);
+        match inner.worst.front() {
+            Some((_, shard, stage, elapsed)) => format!(
+                "worst in last {} sec: {} of {} block, {} ms",
+                WORST_WINDOW.as_secs(), stage.name(), shard, elapsed.as_millis()
+            ),
+            None => "no blocks applied in last minute".to_string()
+        }
+    }
+}
+
+#[test]
+fn test_apply_stages_worst() {
+    let start = Instant::now();
+    let stages = ApplyStages::with_start(start);
+    let mc = ShardIdent::masterchain();
+    let wc = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    let at = |sec| start + Duration::from_secs(sec);
+    assert_eq!(stages.status_at(at(0)), "no blocks applied in last minute");
+
+    stages.report_at(&mc, ApplyStage::MerkleUpdate, Duration::from_millis(300), at(0));
+    stages.report_at(&wc, ApplyStage::StateStore, Duration::from_millis(100), at(30));
+    stages.report_at(&wc, ApplyStage::ProofCheck, Duration::from_millis(50), at(40));
+    assert_eq!(
+        stages.status_at(at(45)),
+        format!("worst in last 60 sec: merkle_update of {} block, 300 ms", mc)
+    );
+    // The slowest one is out of window, the next one is taken
+    assert_eq!(
+        stages.status_at(at(70)),
+        format!("worst in last 60 sec: state_store of {} block, 100 ms", wc)
+    );
+    stages.report_at(&wc, ApplyStage::Queued, Duration::from_millis(200), at(80));
+    assert_eq!(
+        stages.status_at(at(85)),
+        format!("worst in last 60 sec: queued of {} block, 200 ms", wc)
+    );
+    assert_eq!(stages.status_at(at(200)), "no blocks applied in last minute");
+}
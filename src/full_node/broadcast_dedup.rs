@@ -0,0 +1,87 @@
+use crate::{config::BroadcastDedupConfig, engine::STATSD, types::lru_cache::LruCache};
+use std::hash::Hash;
+use ton_block::BlockIdExt;
+use ton_types::UInt256;
+
+// The same broadcast usually comes from several neighbours. Ids of recently seen ones are
+// kept to drop copies before deserialization. Block ids include root and file hashes, so
+// the id of broadcast which failed to be processed is forgotten to accept further copies.
+pub struct BroadcastDedup {
+    blocks: LruCache<BlockIdExt, ()>,
+    shard_blocks: LruCache<BlockIdExt, ()>,
+    ext_messages: LruCache<UInt256, ()>,
+}
+
+impl BroadcastDedup {
+
+    pub fn new(config: &BroadcastDedupConfig) -> Self {
+        Self {
+            blocks: LruCache::new(config.block_capacity),
+            shard_blocks: LruCache::new(config.shard_block_capacity),
+            ext_messages: LruCache::new(config.ext_msg_capacity),
+        }
+    }
+
+    // Returns false if block broadcast is a duplicate
+    pub fn check_block(&self, id: &BlockIdExt) -> bool {
+        Self::check(&self.blocks, id, "dup_block_broadcasts")
+    }
+
+    pub fn forget_block(&self, id: &BlockIdExt) {
+        self.blocks.remove(id);
+    }
+
+    // Returns false if top shard block description broadcast is a duplicate
+    pub fn check_shard_block(&self, id: &BlockIdExt) -> bool {
+        Self::check(&self.shard_blocks, id, "dup_shard_block_broadcasts")
+    }
+
+    pub fn forget_shard_block(&self, id: &BlockIdExt) {
+        self.shard_blocks.remove(id);
+    }
+
+    // Returns false if external message broadcast is a duplicate
+    pub fn check_ext_message(&self, data: &[u8]) -> bool {
+        Self::check(&self.ext_messages, &UInt256::calc_file_hash(data), "dup_ext_msg_broadcasts")
+    }
+
+    fn check<K: Hash + Eq + Clone>(cache: &LruCache<K, ()>, key: &K, stat: &str) -> bool {
+        if cache.get(key).is_some() {
+            STATSD.incr(stat);
+            false
+        } else {
+            cache.insert(key.clone(), ());
+            true
+        }
+    }
+}
+
+#[test]
+fn test_broadcast_dedup() {
+    let dedup = BroadcastDedup::new(&BroadcastDedupConfig {
+        block_capacity: 2,
+        shard_block_capacity: 2,
+        ext_msg_capacity: 2,
+    });
+    let id = |seq_no| BlockIdExt {
+        seq_no,
+        root_hash: UInt256::from([seq_no as u8; 32]),
+        ..Default::default()
+    };
+    assert!(dedup.check_block(&id(1)));
+    assert!(!dedup.check_block(&id(1)));
+    // Same broadcast is processed independently as block and as top shard block description
+    assert!(dedup.check_shard_block(&id(1)));
+    assert!(dedup.check_block(&id(2)));
+    assert!(dedup.check_block(&id(3)));
+    // The oldest one is evicted
+    assert!(dedup.check_block(&id(1)));
+    assert!(!dedup.check_block(&id(3)));
+
+    dedup.forget_block(&id(3));
+    assert!(dedup.check_block(&id(3)));
+
+    assert!(dedup.check_ext_message(&[1, 2, 3]));
+    assert!(!dedup.check_ext_message(&[1, 2, 3]));
+    assert!(dedup.check_ext_message(&[1, 2, 4]));
+}
@@ -0,0 +1,134 @@
+use crate::{boot, engine::STATSD, engine_traits::EngineOperations};
+use std::sync::{Arc, atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering}};
+use ton_types::{error, Result};
+
+// Proofs of key blocks announced by newer masterchain blocks (as their previous key block)
+// are downloaded, checked and stored in advance, so they are at hand when the key block
+// itself is applied
+#[derive(Default)]
+pub struct KeyBlockPrefetcher {
+    running: AtomicBool,
+    // Key blocks up to this seq_no have prefetched proofs
+    prefetched_seq_no: AtomicU32,
+    prefetched: AtomicU64,
+    // Key blocks applied with prefetched proof and ones fetched with proof on demand
+    hits: AtomicU64,
+    on_demand: AtomicU64,
+}
+
+impl KeyBlockPrefetcher {
+
+    pub fn report_fetch(&self, prefetched: bool) {
+        if prefetched {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            STATSD.incr("key_block_prefetch_hits");
+        } else {
+            self.on_demand.fetch_add(1, Ordering::Relaxed);
+            STATSD.incr("key_block_on_demand_fetches");
+        }
+    }
+
+    pub fn status(&self) -> String {
+        format!(
+            "prefetched {} proofs up to seq_no {}, hits {}, on-demand fetches {}{}",
+            self.prefetched.load(Ordering::Relaxed),
+            self.prefetched_seq_no.load(Ordering::Relaxed),
+            self.hits.load(Ordering::Relaxed),
+            self.on_demand.load(Ordering::Relaxed),
+            if self.running.load(Ordering::Relaxed) { ", running" } else { "" }
+        )
+    }
+}
+
+// Starts prefetch of key block proofs up to `seq_no`, unless they are prefetched already
+// or another prefetch is running
+pub fn prefetch_key_blocks(engine: &Arc<dyn EngineOperations>, seq_no: u32) {
+    let prefetcher = engine.key_block_prefetcher();
+    if seq_no <= prefetcher.prefetched_seq_no.load(Ordering::Relaxed) ||
+        prefetcher.running.swap(true, Ordering::SeqCst)
+    {
+        return
+    }
+    let engine = Arc::clone(engine);
+    tokio::spawn(async move {
+        let prefetcher = engine.key_block_prefetcher();
+        match prefetch(&engine, seq_no).await {
+            Ok(count) => {
+                log::info!("Prefetched {} key block proofs up to seq_no {}", count, seq_no);
+                prefetcher.prefetched_seq_no.fetch_max(seq_no, Ordering::Relaxed);
+                prefetcher.prefetched.fetch_add(count as u64, Ordering::Relaxed);
+                STATSD.count("key_block_proofs_prefetched", count as f64);
+            },
+            Err(e) => log::warn!("Can't prefetch key block proofs up to seq_no {}: {}", seq_no, e)
+        }
+        prefetcher.running.store(false, Ordering::SeqCst);
+    });
+}
+
+async fn prefetch(engine: &Arc<dyn EngineOperations>, seq_no: u32) -> Result<usize> {
+    let last_mc_block_id = engine.load_last_applied_mc_block_id()?.ok_or_else(
+        || error!("INTERNAL ERROR: no last applied MC block")
+    )?;
+    let handle = engine.load_block_handle(&last_mc_block_id)?.ok_or_else(
+        || error!("Cannot load handle for last masterchain block {}", last_mc_block_id)
+    )?;
+    let key_block_id = if handle.is_key_block()? {
+        (*last_mc_block_id).clone()
+    } else {
+        engine.load_state(&last_mc_block_id).await?.shard_state_extra()?.last_key_block.clone()
+            .ok_or_else(|| error!(
+                "Masterchain state for {} doesn't contain info about previous key block",
+                last_mc_block_id
+            ))?
+            .master_block_id().1
+    };
+
+    // Chain of proofs starts from the last applied key block
+    let (zero_state, prev_proof) = if key_block_id.seq_no() == 0 {
+        (Some(engine.load_mc_zero_state().await?), None)
+    } else {
+        let handle = engine.load_block_handle(&key_block_id)?.ok_or_else(
+            || error!("Cannot load handle for key block {}", key_block_id)
+        )?;
+        (None, Some(engine.load_block_proof(&handle, false).await?))
+    };
+    let ids = engine.download_next_key_blocks_ids(&key_block_id, 10).await?;
+    let mut next_ids = Vec::new();
+    let mut last_id = &key_block_id;
+    for id in &ids {
+        if id.seq_no() > seq_no || id.seq_no() <= last_id.seq_no() {
+            break
+        }
+        boot::check_key_block_hardforks(engine.get_hardforks(), last_id, id)?;
+        next_ids.push(id.clone());
+        last_id = id;
+    }
+    let proofs = boot::download_key_block_proofs(
+        engine.as_ref(), &next_ids, zero_state.as_ref(), prev_proof.as_ref()
+    ).await?;
+    Ok(proofs.len())
+}
+
+#[test]
+fn test_key_block_prefetcher() {
+    struct TestEngine(KeyBlockPrefetcher);
+    impl EngineOperations for TestEngine {
+        fn key_block_prefetcher(&self) -> &KeyBlockPrefetcher { &self.0 }
+    }
+    let engine: Arc<dyn EngineOperations> = Arc::new(TestEngine(KeyBlockPrefetcher::default()));
+    let prefetcher = engine.key_block_prefetcher();
+    prefetcher.prefetched_seq_no.store(100, Ordering::Relaxed);
+    // Nothing is spawned (there is no runtime) for prefetched key blocks or while prefetch runs
+    prefetch_key_blocks(&engine, 100);
+    assert!(!prefetcher.running.load(Ordering::Relaxed));
+    prefetcher.running.store(true, Ordering::Relaxed);
+    prefetch_key_blocks(&engine, 200);
+
+    prefetcher.report_fetch(true);
+    prefetcher.report_fetch(false);
+    prefetcher.report_fetch(false);
+    assert_eq!(
+        prefetcher.status(),
+        "prefetched 0 proofs up to seq_no 100, hits 1, on-demand fetches 2, running"
+    );
+}
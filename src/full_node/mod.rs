@@ -1,5 +1,10 @@
 pub mod state_helper;
 pub mod apply_block;
+pub mod apply_stages;
+pub mod broadcast_dedup;
+pub mod key_block_prefetch;
 pub mod shard_client;
+pub mod shard_lags;
+pub mod tracked_shards;
 #[cfg(feature = "telemetry")]
 pub mod telemetry;
@@ -1,35 +1,35 @@
 use crate::{
     block::{BlockStuff, convert_block_id_ext_api2blk}, block_proof::BlockProofStuff, 
-    engine_traits::{ChainRange, EngineOperations},
-    error::NodeError,
+    engine::STATSD, engine_traits::{ChainRange, EngineOperations},
+    error::NodeError, full_node::{apply_stages::ApplyStage, key_block_prefetch::prefetch_key_blocks},
+    jaeger, shutdown::SHUTDOWN,
     validator::validator_utils::{calc_subset_for_workchain, check_crypto_signatures},
 };
 
-use std::{sync::Arc, mem::drop};
-use tokio::task::JoinHandle;
+use futures::StreamExt;
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, mem::drop, ops::Deref, time::SystemTime};
+use storage::archives::ARCHIVE_PACKAGE_SIZE;
 use ton_block::{
     BlockIdExt, BlockSignaturesPure, CryptoSignaturePair, CryptoSignature,
-    AccountIdPrefixFull, ValidatorSet, CatchainConfig, ConfigParams,
+    AccountIdPrefixFull, ValidatorSet, CatchainConfig, ConfigParams, ShardIdent,
 };
 use ton_types::{Result, fail, error, UInt256};
 use ton_api::ton::ton_node::broadcast::BlockBroadcast;
 
-pub fn start_masterchain_client(engine: Arc<dyn EngineOperations>, last_got_block_id: BlockIdExt) -> Result<JoinHandle<()>> {
-    let join_handle = tokio::spawn(async move {
-        if let Err(e) = load_master_blocks_cycle(engine, last_got_block_id).await {
-            log::error!("FATAL!!! Unexpected error in master blocks loading cycle: {:?}", e);
-        }
-    });
-    Ok(join_handle)
+// Runs until shutdown, starts from the last applied masterchain block saved in DB
+pub async fn run_masterchain_client(engine: Arc<dyn EngineOperations>) -> Result<()> {
+    let last_got_block_id = engine.load_last_applied_mc_block_id()?.ok_or_else(
+        || error!("INTERNAL ERROR: No last applied MC block")
+    )?;
+    load_master_blocks_cycle(engine, last_got_block_id.deref().clone()).await
 }
 
-pub fn start_shards_client(engine: Arc<dyn EngineOperations>, shards_mc_block_id: BlockIdExt) -> Result<JoinHandle<()>> {
-    let join_handle = tokio::spawn(async move {
-        if let Err(e) = load_shard_blocks_cycle(engine, shards_mc_block_id).await {
-            log::error!("FATAL!!! Unexpected error in shards client: {:?}", e);
-        }
-    });
-    Ok(join_handle)
+// Runs until shutdown, starts from the shard client masterchain block saved in DB
+pub async fn run_shards_client(engine: Arc<dyn EngineOperations>) -> Result<()> {
+    let shards_mc_block_id = engine.load_shard_client_mc_block_id()?.ok_or_else(
+        || error!("INTERNAL ERROR: No shard client MC block")
+    )?;
+    load_shard_blocks_cycle(engine, shards_mc_block_id.deref().clone()).await
 }
 
 async fn load_master_blocks_cycle(
@@ -38,6 +38,10 @@ async fn load_master_blocks_cycle(
 ) -> Result<()> {
     let mut attempt = 0;
     loop {
+        if SHUTDOWN.is_requested() {
+            log::info!("Master blocks loading cycle is stopped at {}", last_got_block_id);
+            return Ok(())
+        }
         last_got_block_id = match load_next_master_block(&engine, &last_got_block_id).await {
             Ok(id) => {
                 attempt = 0;
@@ -80,9 +84,15 @@ async fn load_next_master_block(
     if block.id().seq_no != prev_id.seq_no + 1 {
         fail!("Invalid next master block got: {}, prev: {}", block.id(), prev_id);
     }
+    crate::boot::check_block_hardforks(engine.get_hardforks(), block.id(), prev_id)?;
 
-    let prev_state = engine.clone().wait_state(&prev_id, None, true).await?;
-    proof.check_with_master_state(&prev_state)?;
+    if engine.is_hardfork(block.id()) {
+        // Hardfork block isn't signed by validators of previous state
+        proof.check_hardfork_proof()?;
+    } else {
+        let prev_state = engine.clone().wait_state(&prev_id, None, true).await?;
+        proof.check_with_master_state(&prev_state)?;
+    }
     let mut next_handle = loop {
         if let Some(next_handle) = engine.load_block_handle(block.id())? {
             if !next_handle.has_data() {
@@ -123,12 +133,35 @@ async fn load_shard_blocks_cycle(
         || error!("Cannot load handle for shard master block {}", shards_mc_block_id)
     )?;
     let (_masterchain, workchain_id) = engine.processed_workchain().await?;
+    let catching_up = Arc::new(AtomicBool::new(false));
     loop {
         log::trace!("load_shard_blocks_cycle: mc block: {}", mc_handle.id());
         let r = engine.wait_next_applied_mc_block(&mc_handle, None).await?;
+        if SHUTDOWN.is_requested() {
+            log::info!("Shards client is stopped at mc block {}", mc_handle.id());
+            return Ok(())
+        }
         mc_handle = r.0;
         let mc_block = r.1;
         let shard_ids = mc_block.shard_hashes()?.top_blocks(&[workchain_id])?;
+        engine.update_tracked_shards(
+            &shard_ids.iter().map(|id| id.shard().clone()).collect::<Vec<_>>()
+        );
+        let shard_ids = shard_ids.into_iter()
+            .filter(|id| engine.is_shard_tracked(id.shard()))
+            .collect::<Vec<_>>();
+
+        match update_shard_lags(&engine, &mc_block, &shard_ids).await {
+            Ok((lagging_shards, last_mc_seq_no)) => {
+                if !lagging_shards.is_empty() && !catching_up.swap(true, Ordering::Relaxed) {
+                    start_shards_catch_up(
+                        Arc::clone(&engine), lagging_shards, mc_block.id().seq_no() + 1,
+                        last_mc_seq_no, Arc::clone(&catching_up)
+                    );
+                }
+            },
+            Err(e) => log::warn!("Can't update shard lags at mc block {}: {}", mc_block.id(), e)
+        }
 
         log::trace!("load_shard_blocks_cycle: waiting semaphore: {}", mc_block.id());
         let semaphore_permit = Arc::clone(&semaphore).acquire_owned().await?;
@@ -150,6 +183,93 @@ async fn load_shard_blocks_cycle(
     }
 }
 
+// Compares shard top blocks of masterchain block processed by shard client with those of
+// the last applied one. Returns shards to catch up and seqno of the last applied block.
+async fn update_shard_lags(
+    engine: &Arc<dyn EngineOperations>,
+    mc_block: &BlockStuff,
+    shard_ids: &[BlockIdExt]
+) -> Result<(Vec<ShardIdent>, u32)> {
+    let last_mc_block_id = engine.load_last_applied_mc_block_id()?.ok_or_else(
+        || error!("INTERNAL ERROR: no last applied MC block in shard client")
+    )?;
+    let (last_shard_ids, lag_sec) = if last_mc_block_id.seq_no() > mc_block.id().seq_no() {
+        let handle = engine.load_block_handle(&last_mc_block_id)?.ok_or_else(
+            || error!("Cannot load handle for last applied MC block {}", last_mc_block_id)
+        )?;
+        let last_mc_block = engine.load_block(&handle).await?;
+        let lag_sec = last_mc_block.gen_utime()?.saturating_sub(mc_block.gen_utime()?);
+        (last_mc_block.shards_blocks()?.values().cloned().collect::<Vec<_>>(), lag_sec)
+    } else {
+        (shard_ids.to_vec(), 0)
+    };
+    let lags = shard_ids.iter()
+        .map(|id| {
+            // Shard may be split or merged since then
+            let last_seq_no = last_shard_ids.iter()
+                .filter(|last_id| last_id.shard().intersect_with(id.shard()))
+                .map(|last_id| last_id.seq_no())
+                .max()
+                .unwrap_or(id.seq_no());
+            (id.shard().clone(), last_seq_no.saturating_sub(id.seq_no()), lag_sec)
+        })
+        .collect::<Vec<_>>();
+    Ok((engine.update_shard_lags(&lags), last_mc_block_id.seq_no()))
+}
+
+// Downloads archives of masterchain blocks `from`..=`to` in parallel and stores blocks of
+// lagging shards. Shard client applies them from DB then, missing ones are still downloaded
+// one by one.
+fn start_shards_catch_up(
+    engine: Arc<dyn EngineOperations>,
+    shards: Vec<ShardIdent>,
+    from: u32,
+    to: u32,
+    running: Arc<AtomicBool>
+) {
+    tokio::spawn(async move {
+        log::info!("Shards catch-up: downloading archives for MC blocks {}..{}", from, to);
+        let window = engine.archives_sync_config().download_window;
+        let active_peers = Arc::new(lockfree::set::Set::new());
+        let stored = futures::stream::iter((from..=to).step_by(ARCHIVE_PACKAGE_SIZE as usize))
+            .map(|mc_seq_no| {
+                let engine = Arc::clone(&engine);
+                let shards = &shards;
+                let active_peers = &active_peers;
+                async move {
+                    let data = match engine.download_archive(mc_seq_no, active_peers).await {
+                        Ok(Some(data)) => data,
+                        // Archive is not finished yet
+                        Ok(None) => return Ok(0),
+                        Err(e) => fail!("Can't download archive for MC seq_no {}: {}", mc_seq_no, e)
+                    };
+                    crate::sync::store_shard_blocks(&engine, &data, shards).await.map_err(
+                        |e| error!("Can't store blocks of archive for MC seq_no {}: {}", mc_seq_no, e)
+                    )
+                }
+            })
+            .buffer_unordered(window)
+            .fold(0, |stored, result| async move {
+                match result {
+                    Ok(count) => stored + count,
+                    Err(e) => {
+                        log::warn!("Shards catch-up: {}", e);
+                        stored
+                    }
+                }
+            })
+            .await;
+        log::info!(
+            "Shards catch-up: {} blocks of {} stored for MC blocks {}..{}",
+            stored,
+            shards.iter().map(|shard| shard.to_string()).collect::<Vec<_>>().join(", "),
+            from,
+            to
+        );
+        running.store(false, Ordering::Relaxed);
+    });
+}
+
 pub async fn produce_chain_range(
     engine: Arc<dyn EngineOperations>,
     mc_block: &BlockStuff
@@ -165,7 +285,10 @@ pub async fn produce_chain_range(
     let prev_master = engine.load_block(&prev_master).await?;
     let prev_master_shards = prev_master.shards_blocks()?;
 
-    let mut blocks: Vec<BlockIdExt> = mc_block.shards_blocks()?.values().cloned().collect();
+    let mut blocks: Vec<BlockIdExt> = mc_block.shards_blocks()?.values()
+        .filter(|id| engine.is_shard_tracked(id.shard()))
+        .cloned()
+        .collect();
 
     while let Some(block_id) = blocks.pop() {
         let handle = engine.load_block_handle(&block_id)?
@@ -251,7 +374,14 @@ pub async fn process_block_broadcast(
 ) -> Result<()> {
 
     log::trace!("process_block_broadcast: {}", broadcast.id);
+    let received_at = SystemTime::now();
     let block_id = convert_block_id_ext_api2blk(&broadcast.id)?;
+    // Block of not tracked shard is checked and relayed to neighbours, but not stored
+    let relay_only = !engine.is_shard_tracked(block_id.shard());
+    if relay_only && !engine.relay_untracked_broadcasts() {
+        log::trace!("Skipped block broadcast {} of not tracked shard", block_id);
+        return Ok(());
+    }
     if let Some(handle) = engine.load_block_handle(&block_id)? {
         if handle.has_data() {
             #[cfg(feature = "telemetry")] {
@@ -266,14 +396,24 @@ pub async fn process_block_broadcast(
     engine.full_node_telemetry().new_block_broadcast(&block_id, false, false);
 
     let is_master = block_id.shard().is_masterchain();
+    let pre_fork = engine.get_hardforks().iter()
+        .any(|id| id.seq_no() == block_id.seq_no() && *id != block_id);
+    if is_master && pre_fork {
+        log::debug!("Skipped block broadcast {} of pre-fork branch", block_id);
+        return Ok(());
+    }
     let proof = BlockProofStuff::deserialize(&block_id, broadcast.proof.0.clone(), !is_master)?;
     let (virt_block, _) = proof.virtualize_block()?;
     let block_info = virt_block.read_info()?;
+    let key_block = block_info.key_block();
+    jaeger::block_stage(&block_id, key_block, "broadcast received", received_at);
     let prev_key_block_seqno = block_info.prev_key_block_seqno();
     let last_applied_mc_block_id = engine.load_last_applied_mc_block_id()?.ok_or_else(
         || error!("INTERNAL ERROR: no last applied MC block after sync")
     )?;
     if prev_key_block_seqno > last_applied_mc_block_id.seq_no() {
+        // Key block is needed soon, its proof is fetched in advance
+        prefetch_key_blocks(engine, prev_key_block_seqno);
         log::debug!(
             "Skipped block broadcast {} because it refers too new key block: {}, but last processed mc block is {})",
             block_id, prev_key_block_seqno, last_applied_mc_block_id.seq_no()
@@ -303,6 +443,8 @@ pub async fn process_block_broadcast(
         vs
     };
 
+    let now = std::time::Instant::now();
+    let check_started = SystemTime::now();
     validate_brodcast(broadcast, &config_params, &block_id, &validator_set, &cc_config)?;
 
     // Build and save block and proof
@@ -315,6 +457,17 @@ pub async fn process_block_broadcast(
     } else {
         proof.check_proof_link()?;
     }
+    engine.report_apply_stage(block_id.shard(), ApplyStage::ProofCheck, now.elapsed());
+    jaeger::block_stage(&block_id, key_block, "proof checked", check_started);
+    if relay_only {
+        if UInt256::calc_file_hash(&broadcast.data.0) != block_id.file_hash {
+            fail!("Block broadcast {} has wrong file hash", block_id)
+        }
+        engine.relay_block_broadcast(broadcast.clone()).await?;
+        STATSD.incr("relayed_block_broadcasts");
+        log::trace!("Block broadcast {} of not tracked shard is relayed", block_id);
+        return Ok(());
+    }
     let block = BlockStuff::deserialize_checked(block_id, broadcast.data.0.clone())?;
     let mut handle = if let Some(handle) = engine.store_block(&block).await?.as_updated() {
         handle
@@ -345,6 +498,7 @@ pub async fn process_block_broadcast(
     if block.id().shard().is_masterchain() {
         if block.id().seq_no() == last_applied_mc_block_id.seq_no() + 1 {
             engine.clone().apply_block(&handle, &block, block.id().seq_no(), false).await?;
+            STATSD.incr("applied_block_broadcasts");
         } else {
             log::debug!(
                 "Skipped apply for block broadcast {} because it is too new (last master block: {})",
@@ -364,6 +518,7 @@ pub async fn process_block_broadcast(
         )?;
         if shard_client_mc_block_id.seq_no() + SHARD_BROADCAST_WINDOW >= master_ref.master.seq_no {
             engine.clone().apply_block(&handle, &block, shard_client_mc_block_id.seq_no(), true).await?;
+            STATSD.incr("applied_block_broadcasts");
         } else {
             log::debug!(
                 "Skipped pre-apply for block broadcast {} because it refers to master block {}, but shard client is on {}",
@@ -434,3 +589,111 @@ fn validate_brodcast(
 
     Ok(())
 }
+
+#[cfg(test)]
+fn test_master_block(seq_no: u32) -> (BlockStuff, BlockProofStuff) {
+    use ton_block::{
+        Block, BlockExtra, BlockInfo, BlockProof, MerkleProof, MerkleUpdate, Serializable,
+        ValueFlow
+    };
+    let mut info = BlockInfo::default();
+    info.set_shard(ShardIdent::masterchain());
+    info.set_seq_no(seq_no).unwrap();
+    let block = Block::with_params(
+        0, info, ValueFlow::default(), MerkleUpdate::default(), BlockExtra::default()
+    ).unwrap();
+    let root = block.serialize().unwrap();
+    let data = ton_types::serialize_toc(&root).unwrap();
+    let id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), seq_no, root.repr_hash(), UInt256::calc_file_hash(&data)
+    );
+    let proof = BlockProof {
+        proof_for: id.clone(),
+        root: MerkleProof::create(&root, |_| true).unwrap().serialize().unwrap(),
+        signatures: None
+    };
+    (
+        BlockStuff::deserialize(id, data).unwrap(),
+        BlockProofStuff::new(proof, false).unwrap()
+    )
+}
+
+#[test]
+fn test_load_next_master_block_hardfork() {
+    use std::sync::Weak;
+    use storage::types::BlockHandle;
+    use crate::shard_state::ShardStateStuff;
+
+    struct TestEngine {
+        prev_id: BlockIdExt,
+        next: (BlockStuff, BlockProofStuff),
+        hardforks: Vec<BlockIdExt>,
+        handle_cache: Arc<lockfree::map::Map<BlockIdExt, Weak<BlockHandle>>>,
+        applied: std::sync::Mutex<Vec<BlockIdExt>>
+    }
+    #[async_trait::async_trait]
+    impl EngineOperations for TestEngine {
+        fn get_hardforks(&self) -> &[BlockIdExt] { &self.hardforks }
+        fn load_block_handle(&self, id: &BlockIdExt) -> Result<Option<Arc<BlockHandle>>> {
+            let handle = BlockHandle::new(id.clone(), self.handle_cache.clone());
+            if id == self.next.0.id() {
+                handle.set_data();
+                handle.set_proof();
+            }
+            Ok(Some(Arc::new(handle)))
+        }
+        async fn download_next_block(
+            &self,
+            prev_id: &BlockIdExt
+        ) -> Result<(BlockStuff, BlockProofStuff)> {
+            assert_eq!(prev_id, &self.prev_id);
+            Ok(self.next.clone())
+        }
+        async fn wait_state(
+            self: Arc<Self>,
+            id: &BlockIdExt,
+            _timeout_ms: Option<u64>,
+            _allow_block_downloading: bool
+        ) -> Result<ShardStateStuff> {
+            fail!("No signatures of block next to {} in test", id)
+        }
+        async fn apply_block(
+            self: Arc<Self>,
+            handle: &Arc<BlockHandle>,
+            _block: &BlockStuff,
+            _mc_seq_no: u32,
+            _pre_apply: bool
+        ) -> Result<()> {
+            self.applied.lock().unwrap().push(handle.id().clone());
+            Ok(())
+        }
+    }
+
+    let (prev, _) = test_master_block(99);
+    let next = test_master_block(100);
+    let next_id = next.0.id().clone();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    // Unsigned block is refused unless it is the hardfork of global config
+    let engine = Arc::new(TestEngine {
+        prev_id: prev.id().clone(),
+        next: next.clone(),
+        hardforks: vec!(),
+        handle_cache: Arc::new(lockfree::map::Map::new()),
+        applied: std::sync::Mutex::new(Vec::new())
+    });
+    let engine_ops: Arc<dyn EngineOperations> = engine.clone();
+    assert!(rt.block_on(load_next_master_block(&engine_ops, prev.id())).is_err());
+    assert!(engine.applied.lock().unwrap().is_empty());
+
+    let engine = Arc::new(TestEngine {
+        prev_id: prev.id().clone(),
+        next,
+        hardforks: vec!(next_id.clone()),
+        handle_cache: Arc::new(lockfree::map::Map::new()),
+        applied: std::sync::Mutex::new(Vec::new())
+    });
+    let engine_ops: Arc<dyn EngineOperations> = engine.clone();
+    assert_eq!(rt.block_on(load_next_master_block(&engine_ops, prev.id())).unwrap(), next_id);
+    assert_eq!(*engine.applied.lock().unwrap(), vec!(next_id));
+}
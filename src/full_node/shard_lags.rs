@@ -0,0 +1,98 @@
+use crate::{config::ShardCatchUpConfig, engine::STATSD};
+use std::{collections::HashMap, sync::Mutex};
+use ton_block::ShardIdent;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ShardLag {
+    // Seqno gap between shard top blocks of last applied masterchain block and of
+    // the masterchain block processed by shard client
+    pub seqno: u32,
+    // Gap between generation times of these masterchain blocks
+    pub sec: u32,
+    pub catching_up: bool,
+}
+
+// Lags of shard client behind masterchain client. Shard lagging more than allowed by config
+// is caught up by archives instead of next-block queries, until it is within the gap again.
+pub struct ShardLags {
+    config: ShardCatchUpConfig,
+    lags: Mutex<HashMap<ShardIdent, ShardLag>>,
+}
+
+impl ShardLags {
+
+    pub fn new(config: ShardCatchUpConfig) -> Self {
+        Self { config, lags: Mutex::new(HashMap::new()) }
+    }
+
+    // Takes (shard, seqno lag, time lag) of all top shards. Returns shards in catch-up mode.
+    pub fn update(&self, lags: &[(ShardIdent, u32, u32)]) -> Vec<ShardIdent> {
+        let mut current = self.lags.lock().unwrap();
+        let mut updated = HashMap::new();
+        for (shard, seqno, sec) in lags {
+            let was_catching_up = current.get(shard).map(|lag| lag.catching_up).unwrap_or(false);
+            let catching_up = *seqno > self.config.max_lag_seqno || *sec > self.config.max_lag_sec;
+            if catching_up && !was_catching_up {
+                log::warn!(
+                    "Shard {} is behind masterchain by {} blocks, {} sec: catching up by archives",
+                    shard, seqno, sec
+                );
+                STATSD.incr("shard_catch_up_started");
+            } else if !catching_up && was_catching_up {
+                log::info!(
+                    "Shard {} is caught up: behind masterchain by {} blocks, {} sec",
+                    shard, seqno, sec
+                );
+            }
+            STATSD.shard(shard).gauge("shard_lag_seqno", *seqno as f64);
+            STATSD.shard(shard).gauge("shard_lag_sec", *sec as f64);
+            updated.insert(shard.clone(), ShardLag { seqno: *seqno, sec: *sec, catching_up });
+        }
+        *current = updated;
+        let mut catching_up = current.iter()
+            .filter(|(_, lag)| lag.catching_up)
+            .map(|(shard, _)| shard.clone())
+            .collect::<Vec<_>>();
+        catching_up.sort_by_key(|shard| (shard.workchain_id(), shard.shard_prefix_with_tag()));
+        catching_up
+    }
+
+    pub fn status(&self) -> String {
+        let lags = self.lags.lock().unwrap();
+        let mut shards = lags.keys().collect::<Vec<_>>();
+        shards.sort_by_key(|shard| (shard.workchain_id(), shard.shard_prefix_with_tag()));
+        shards.iter()
+            .map(|shard| {
+                let lag = &lags[*shard];
+                format!(
+                    "{}: {} blocks, {} sec{}",
+                    shard, lag.seqno, lag.sec, if lag.catching_up { ", catching up" } else { "" }
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+#[test]
+fn test_shard_lags() {
+    let lags = ShardLags::new(ShardCatchUpConfig { max_lag_seqno: 10, max_lag_sec: 60 });
+    let left = ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000).unwrap();
+    let right = ShardIdent::with_tagged_prefix(0, 0xc000_0000_0000_0000).unwrap();
+
+    assert!(lags.update(&[(left.clone(), 10, 60), (right.clone(), 0, 60)]).is_empty());
+    // Too many blocks or too much time behind
+    assert_eq!(lags.update(&[(left.clone(), 11, 60), (right.clone(), 0, 60)]), vec!(left.clone()));
+    assert_eq!(
+        lags.update(&[(left.clone(), 11, 61), (right.clone(), 0, 61)]),
+        vec!(left.clone(), right.clone())
+    );
+    assert_eq!(
+        lags.status(),
+        format!("{}: 11 blocks, 61 sec, catching up; {}: 0 blocks, 61 sec, catching up", left, right)
+    );
+    // Back to normal within the gap, merged shards are forgotten
+    let merged = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    assert!(lags.update(&[(merged.clone(), 1, 5)]).is_empty());
+    assert_eq!(lags.status(), format!("{}: 1 blocks, 5 sec", merged));
+}
@@ -5,12 +5,75 @@ use crate::{
 
 use adnl::common::KeyId;
 #[cfg(not(feature = "local_test"))]
-use std::{sync::{Arc, atomic::{AtomicUsize, Ordering}}};
+use crate::{
+    error::{NetworkError, NodeError},
+    network::{bandwidth::BandwidthLimiter, neighbours::Neighbour, peer_scores::PeerOutcome}
+};
+#[cfg(not(feature = "local_test"))]
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, sync::Mutex};
+#[cfg(not(feature = "local_test"))]
+use std::{
+    collections::BTreeSet, fs::{File, OpenOptions}, io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf}, sync::{Arc, atomic::{AtomicU64, AtomicUsize, Ordering}}
+};
 use ton_block::{BlockIdExt};
 #[cfg(not(feature = "local_test"))]
 use ton_types::{error, fail};
 use ton_types::Result;
 
+// Progress of persistent state download
+#[derive(Clone, Debug, Default)]
+pub struct StateDownloadProgress {
+    pub bytes: u64,
+    // Is known when the last chunk is got
+    pub total_bytes: Option<u64>,
+    pub peer: String,
+    pub resumed_bytes: u64,
+    pub refetched_chunks: u64,
+}
+
+// Persistent state downloads in progress, shown in GetStats
+#[derive(Default)]
+pub struct StateDownloads {
+    downloads: Mutex<BTreeMap<String, StateDownloadProgress>>,
+}
+
+impl StateDownloads {
+
+    pub fn status(&self) -> String {
+        let downloads = self.downloads.lock().unwrap();
+        if downloads.is_empty() {
+            return "no downloads".to_string()
+        }
+        let mut status = String::new();
+        for (id, progress) in downloads.iter() {
+            let percent = match progress.total_bytes {
+                Some(total) if total > 0 => format!("{}%", progress.bytes * 100 / total),
+                _ => "?%".to_string()
+            };
+            status.push_str(&format!(
+                "{}: {} bytes ({}), resumed {} bytes, refetched chunks {}, peer {}\n",
+                id, progress.bytes, percent, progress.resumed_bytes, progress.refetched_chunks,
+                progress.peer
+            ));
+        }
+        status
+    }
+
+    pub fn progress(&self, id: &BlockIdExt) -> Option<StateDownloadProgress> {
+        self.downloads.lock().unwrap().get(&id.to_string()).cloned()
+    }
+
+    fn update(&self, id: &BlockIdExt, update: impl FnOnce(&mut StateDownloadProgress)) {
+        update(self.downloads.lock().unwrap().entry(id.to_string()).or_default())
+    }
+
+    fn finish(&self, id: &BlockIdExt) {
+        self.downloads.lock().unwrap().remove(&id.to_string());
+    }
+}
+
 #[cfg(feature = "local_test")]
 pub async fn download_persistent_state(
     id: &BlockIdExt,
@@ -21,16 +84,44 @@ pub async fn download_persistent_state(
     ShardStateStuff::deserialize(id.clone(), &bytes)
 }
 
+// Partially downloaded states are kept there, relative to DB root
+pub const STATE_DOWNLOAD_DIR_NAME: &str = "state_download";
+
+#[cfg(not(feature = "local_test"))]
+const CHUNK_SIZE: usize = 1 << 20;
+// Chunks downloaded at once
+#[cfg(not(feature = "local_test"))]
+const DOWNLOAD_THREADS: usize = 3;
+// Failed attempts of chunk before another peer is taken
+#[cfg(not(feature = "local_test"))]
+const PEER_ATTEMPTS: u32 = 3;
+#[cfg(not(feature = "local_test"))]
+const CHUNK_ATTEMPTS: u32 = 10;
+
+// Downloads persistent state by fixed-size chunks into `staging_dir`. Chunks got before
+// restart are verified by their hashes and not downloaded again. Failed or corrupted chunk
+// is fetched from another peer. Sizes of chunks are checked with the size of state given
+// by its BOC header. The whole state is verified with its root hash, chunks of suspected
+// peer are fetched again if it mismatches. Each chunk is passed through `limiter`.
 #[cfg(not(feature = "local_test"))]
 pub async fn download_persistent_state(
     id: &BlockIdExt,
     master_id: &BlockIdExt,
     overlay: &dyn FullNodeOverlayClient,
-    active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>,
+    staging_dir: &Path,
+    downloads: &StateDownloads,
+    limiter: &BandwidthLimiter
 ) -> Result<ShardStateStuff> {
+    if id.seq_no == 0 {
+        fail!("zerostate is not supported");
+    }
     let mut result = None;
+    let mut mismatched = false;
     for _ in 0..10 {
-        match download_persistent_state_iter(id, master_id, overlay, active_peers).await {
+        match download_persistent_state_iter(
+            id, master_id, overlay, active_peers, staging_dir, downloads, limiter, &mut mismatched
+        ).await {
             Err(e) => {
                 log::warn!("download_persistent_state_iter err: {}", e);
                 result = Some(Err(e));
@@ -38,127 +129,616 @@ pub async fn download_persistent_state(
                 continue;
             },
             Ok(res) => { 
+                downloads.finish(id);
                 return Ok(res); 
             }
         }
     }
+    downloads.finish(id);
     result.ok_or_else(|| error!("internal error!"))?
 }
 
 #[cfg(not(feature = "local_test"))]
-async fn download_persistent_state_iter(
+async fn find_state_peer(
     id: &BlockIdExt,
     master_id: &BlockIdExt,
     overlay: &dyn FullNodeOverlayClient,
     active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
-) -> Result<ShardStateStuff> {
-
-    if id.seq_no == 0 {
-        fail!("zerostate is not supported");
-    }
-
-    // Check
-    let mut peer = None;
+) -> Result<Arc<Neighbour>> {
     for _ in 0..10 {
         match overlay.check_persistent_state(id, master_id, active_peers).await {
             Err(e) => 
                 log::trace!("check_persistent_state {}: {}", id.shard(), e),
             Ok(None) => 
                 log::trace!("download_persistent_state {}: state not found!", id.shard()),
-            Ok(p) => 
-                peer = p,
+            Ok(Some(peer)) =>
+                return Ok(peer)
         }
         futures_timer::Delay::new(std::time::Duration::from_millis(100)).await;
     };
-    let peer = peer.ok_or_else(|| error!("Can't find peer to load persistent state"))?;
+    fail!("Can't find peer to load persistent state")
+}
+
+#[cfg(not(feature = "local_test"))]
+async fn download_persistent_state_iter(
+    id: &BlockIdExt,
+    master_id: &BlockIdExt,
+    overlay: &dyn FullNodeOverlayClient,
+    active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>,
+    staging_dir: &Path,
+    downloads: &StateDownloads,
+    limiter: &BandwidthLimiter,
+    mismatched: &mut bool
+) -> Result<ShardStateStuff> {
+
+    let staged = {
+        let staging_dir = staging_dir.to_path_buf();
+        let id = id.clone();
+        Arc::new(tokio::task::spawn_blocking(move || StagedState::open(&staging_dir, &id)).await??)
+    };
+    let resumed_bytes = staged.chunks.lock().unwrap().values()
+        .map(|chunk| chunk.size as u64)
+        .sum::<u64>();
+    if resumed_bytes > 0 {
+        log::info!(
+            "download_persistent_state {}: resuming, {} bytes are downloaded before",
+            id, resumed_bytes
+        );
+    }
+    let peer = find_state_peer(id, master_id, overlay, active_peers).await?;
+    downloads.update(id, |progress| {
+        progress.bytes = resumed_bytes;
+        progress.resumed_bytes = resumed_bytes;
+        progress.total_bytes = staged.total_size().map(|size| size as u64);
+        progress.peer = peer.id().to_string();
+        progress.refetched_chunks += staged.corrupted as u64;
+    });
+    let download = StateDownload {
+        id,
+        master_id,
+        overlay,
+        active_peers,
+        downloads,
+        limiter,
+        total_size: AtomicUsize::new(staged.total_size().unwrap_or(usize::MAX)),
+        staged,
+        peer: Mutex::new(peer),
+        next_chunk: AtomicUsize::new(0),
+        bytes: AtomicU64::new(resumed_bytes),
+        errors: AtomicUsize::new(0),
+    };
 
     // Download
     log::trace!("download_persistent_state: start: id: {}, master_id: {}", id, master_id);
     let now = std::time::Instant::now();
+    let res = futures::future::join_all((0..DOWNLOAD_THREADS).map(|_| download.download_chunks()))
+        .await
+        .into_iter()
+        .find(|r| r.is_err());
+    active_peers.remove(download.peer.lock().unwrap().id());
+    res.unwrap_or(Ok(()))?;
 
-    let mut offset = 0;
-    let parts = Arc::new(lockfree::map::Map::new());
-    let max_size = 1 << 20; // part max size
-    let mut download_futures = vec!();
-    let total_size = Arc::new(AtomicUsize::new(usize::max_value()));
-    let errors = Arc::new(AtomicUsize::new(0));
-    let threads = 3;
-    let peer_drop = peer.clone();
-
-    for _ in 0..threads {
-        let parts = parts.clone();
-        let total_size = total_size.clone();
-        let errors = errors.clone();
-        let peer = peer.clone();
-        download_futures.push(async move {
-            let mut peer_attempt = 0;
-            let mut part_attempt = 0;
-            loop {
-
-                if offset >= total_size.load(Ordering::Relaxed) {
-                    return Ok(());
-                }
+    log::trace!(
+        "download_persistent_state: DOWNLOADED {}ms, id: {}, master_id: {} ", 
+        now.elapsed().as_millis(), id, master_id
+    );
+
+    let staged = download.staged;
+    let total_size = download.total_size.load(Ordering::Relaxed);
+    let state_bytes = {
+        let staged = Arc::clone(&staged);
+        tokio::task::spawn_blocking(move || staged.read(total_size)).await??
+    };
+    match ShardStateStuff::deserialize(id.clone(), &state_bytes) {
+        Ok(state) => {
+            tokio::task::spawn_blocking(move || staged.remove()).await?;
+            Ok(state)
+        },
+        Err(e) => {
+            // Only chunks of suspected peers are fetched again. The whole state is fetched
+            // again if it is still broken after that.
+            let refetch_all = *mismatched;
+            *mismatched = true;
+            let dropped = tokio::task::spawn_blocking(move || staged.drop_chunks(refetch_all)).await??;
+            log::warn!(
+                "download_persistent_state {}: broken state, {} chunks are fetched again: {}",
+                id, dropped, e
+            );
+            Err(e)
+        }
+    }
+}
+
+#[cfg(not(feature = "local_test"))]
+struct StateDownload<'a> {
+    id: &'a BlockIdExt,
+    master_id: &'a BlockIdExt,
+    overlay: &'a dyn FullNodeOverlayClient,
+    active_peers: &'a Arc<lockfree::set::Set<Arc<KeyId>>>,
+    downloads: &'a StateDownloads,
+    limiter: &'a BandwidthLimiter,
+    staged: Arc<StagedState>,
+    // Is known when the last chunk is got
+    total_size: AtomicUsize,
+    peer: Mutex<Arc<Neighbour>>,
+    next_chunk: AtomicUsize,
+    bytes: AtomicU64,
+    errors: AtomicUsize,
+}
+
+#[cfg(not(feature = "local_test"))]
+impl StateDownload<'_> {
+
+    // Takes next chunks not downloaded yet until the end of state
+    async fn download_chunks(&self) -> Result<()> {
+        loop {
+            let index = self.next_chunk.fetch_add(1, Ordering::Relaxed);
+            if index * CHUNK_SIZE >= self.total_size.load(Ordering::Relaxed) {
+                return Ok(())
+            }
+            if !self.staged.chunks.lock().unwrap().contains_key(&index) {
+                self.download_chunk(index).await?;
+            }
+        }
+    }
 
-                match overlay.download_persistent_state_part(
-                    id, master_id, offset, max_size, peer.clone(), peer_attempt
-                ).await {
-                    Ok(next_bytes) => {
-                        part_attempt = 0;
-                        let len = next_bytes.len();
-                        parts.insert(offset, next_bytes);
-                        //if (offset / max_size) % 10 == 0 {
-                            log::trace!("download_persistent_state {}: got part offset: {}", id.shard(), offset);
-                        //}
-                        if len < max_size {
-                            total_size.store(offset + len, Ordering::Relaxed);
-                            return Ok(());
+    async fn download_chunk(&self, index: usize) -> Result<()> {
+        let id = self.id;
+        let mut part_attempt = 0;
+        let mut peer_attempt = 0;
+        loop {
+            // End of state may be found by another chunk meanwhile
+            if index * CHUNK_SIZE >= self.total_size.load(Ordering::Relaxed) {
+                return Ok(())
+            }
+            let peer = self.peer.lock().unwrap().clone();
+            let result = match self.overlay.download_persistent_state_part(
+                id, self.master_id, index * CHUNK_SIZE, CHUNK_SIZE, peer.clone(), peer_attempt
+            ).await {
+                Ok(data) => self.staged.check_chunk(index, &data, &self.total_size)
+                    .map(|total_size| (data, total_size)),
+                Err(e) => Err(e)
+            };
+            match result {
+                Ok((data, total_size)) => {
+                    if index * CHUNK_SIZE >= self.total_size.load(Ordering::Relaxed) {
+                        return Ok(())
+                    }
+                    let size = data.len();
+                    self.limiter.consume(size).await;
+                    let staged = Arc::clone(&self.staged);
+                    let peer_id = peer.id().to_string();
+                    tokio::task::spawn_blocking(
+                        move || staged.write_chunk(index, &data, &peer_id)
+                    ).await??;
+                    if let Some(total_size) = total_size {
+                        self.total_size.store(total_size, Ordering::Relaxed);
+                    }
+                    let total_size = total_size.map(|total_size| total_size as u64);
+                    let bytes = self.bytes.fetch_add(size as u64, Ordering::Relaxed) + size as u64;
+                    self.downloads.update(id, |progress| {
+                        progress.bytes = bytes;
+                        if total_size.is_some() {
+                            progress.total_bytes = total_size;
                         }
-                        offset += max_size * threads;
-                    },
-                    Err(e) => {
-                        errors.fetch_add(1, Ordering::SeqCst);
-                        part_attempt += 1;
-                        peer_attempt += 1;
-                        log::error!(
-                            "download_persistent_state_part {}: {}, attempt: {}, total errors: {}",
-                            id.shard(), e, part_attempt, errors.load(Ordering::Relaxed)
-                        );
-                        if part_attempt > 10 {
-                            fail!(
-                                "Error download_persistent_state_part after {} attempts: {}", 
-                                part_attempt, e
-                            )
+                    });
+                    log::trace!(
+                        "download_persistent_state {}: got part offset: {}",
+                        id.shard(), index * CHUNK_SIZE
+                    );
+                    return Ok(())
+                },
+                Err(e) => {
+                    let bad_data = matches!(
+                        NodeError::from_error(&e), Some(NodeError::Network(NetworkError::BadData, _))
+                    );
+                    if bad_data {
+                        // Corrupted chunk is fetched from another peer at once
+                        self.overlay.report_peer(peer.id(), PeerOutcome::BadData);
+                        peer_attempt = PEER_ATTEMPTS - 1;
+                    }
+                    let errors = self.errors.fetch_add(1, Ordering::SeqCst) + 1;
+                    part_attempt += 1;
+                    peer_attempt += 1;
+                    log::error!(
+                        "download_persistent_state_part {}: {}, attempt: {}, total errors: {}",
+                        id.shard(), e, part_attempt, errors
+                    );
+                    self.downloads.update(id, |progress| progress.refetched_chunks += 1);
+                    if part_attempt > CHUNK_ATTEMPTS {
+                        fail!(
+                            "Error download_persistent_state_part after {} attempts: {}",
+                            part_attempt, e
+                        )
+                    }
+                    if peer_attempt >= PEER_ATTEMPTS {
+                        // Bad peer stays in active peers not to be taken again
+                        let new_peer = find_state_peer(
+                            id, self.master_id, self.overlay, self.active_peers
+                        ).await;
+                        if let Ok(new_peer) = new_peer {
+                            log::warn!(
+                                "download_persistent_state {}: switching from peer {} to {}",
+                                id.shard(), peer.id(), new_peer.id()
+                            );
+                            self.downloads.update(id, |progress| progress.peer = new_peer.id().to_string());
+                            *self.peer.lock().unwrap() = new_peer;
+                            peer_attempt = 0;
                         }
-                        futures_timer::Delay::new(std::time::Duration::from_millis(100)).await;
-                    },
+                    }
+                    futures_timer::Delay::new(std::time::Duration::from_millis(100)).await;
+                },
+            }
+        }
+    }
+}
+
+// Partially downloaded state file with journal of written chunks: index, size, sha256 and
+// peer of each one. Chunks are checked with the journal when download is resumed.
+// Files are accessed synchronously, so methods are called in blocking tasks.
+#[cfg(not(feature = "local_test"))]
+struct StagedState {
+    data_path: PathBuf,
+    journal_path: PathBuf,
+    data: Mutex<File>,
+    journal: Mutex<File>,
+    chunks: Mutex<BTreeMap<usize, StagedChunk>>,
+    // Size of state given by BOC header of the first chunk
+    boc_size: Mutex<Option<usize>>,
+    // Chunks found corrupted when opened
+    corrupted: usize,
+}
+
+#[cfg(not(feature = "local_test"))]
+#[derive(Clone, Debug, PartialEq)]
+struct StagedChunk {
+    size: usize,
+    hash: String,
+    peer: String,
+}
+
+#[cfg(not(feature = "local_test"))]
+impl StagedState {
+
+    fn open(staging_dir: &Path, id: &BlockIdExt) -> Result<Self> {
+        std::fs::create_dir_all(staging_dir)?;
+        let name = format!("state_{:x}", id.root_hash());
+        let data_path = staging_dir.join(format!("{}.part", name));
+        let journal_path = staging_dir.join(format!("{}.chunks", name));
+        let mut data = OpenOptions::new().read(true).write(true).create(true).open(&data_path)
+            .map_err(|e| error!("Can't open {}: {}", data_path.display(), e))?;
+        let journal = std::fs::read_to_string(&journal_path).unwrap_or_default();
+        let mut chunks = BTreeMap::new();
+        let mut corrupted = BTreeSet::new();
+        let mut boc_size = None;
+        for line in journal.lines() {
+            let mut fields = line.split(' ');
+            let entry = match (fields.next(), fields.next(), fields.next()) {
+                (Some(index), Some(size), Some(hash)) => match (index.parse::<usize>(), size.parse::<usize>()) {
+                    (Ok(index), Ok(size)) if size <= CHUNK_SIZE => Some((index, size, hash)),
+                    _ => None
+                },
+                _ => None
+            };
+            // Last line may be torn by restart
+            let (index, size, hash) = match entry {
+                Some(entry) => entry,
+                None => continue
+            };
+            // Peer is absent in journals of older versions
+            let peer = fields.next().unwrap_or_default().to_string();
+            let mut chunk = vec!(0; size);
+            data.seek(SeekFrom::Start((index * CHUNK_SIZE) as u64))?;
+            if data.read_exact(&mut chunk).is_ok() && hex::encode(Sha256::digest(&chunk)) == hash {
+                if index == 0 {
+                    boc_size = state_boc_size(&chunk);
                 }
+                chunks.insert(index, StagedChunk { size, hash: hash.to_string(), peer });
+            } else {
+                corrupted.insert(index);
+            }
+        }
+        let corrupted = corrupted.into_iter()
+            .filter(|index| !chunks.contains_key(index))
+            .collect::<Vec<_>>();
+        for index in corrupted.iter() {
+            log::warn!("Staged chunk {} of state {} is corrupted, it is fetched again", index, id);
+        }
+        // Journal is rewritten with valid chunks only
+        let journal = Self::write_journal(&journal_path, &chunks)?;
+        Ok(Self {
+            data_path,
+            journal_path,
+            data: Mutex::new(data),
+            journal: Mutex::new(journal),
+            chunks: Mutex::new(chunks),
+            boc_size: Mutex::new(boc_size),
+            corrupted: corrupted.len(),
+        })
+    }
+
+    fn write_journal(journal_path: &Path, chunks: &BTreeMap<usize, StagedChunk>) -> Result<File> {
+        let mut journal = File::create(journal_path)
+            .map_err(|e| error!("Can't create {}: {}", journal_path.display(), e))?;
+        let mut entries = String::new();
+        for (index, chunk) in chunks.iter() {
+            entries.push_str(&format!("{} {} {} {}\n", index, chunk.size, chunk.hash, chunk.peer));
+        }
+        journal.write_all(entries.as_bytes())?;
+        journal.sync_data()?;
+        Ok(journal)
+    }
 
+    // Size of state if its first or last chunk is downloaded
+    fn total_size(&self) -> Option<usize> {
+        self.boc_size.lock().unwrap().or_else(|| {
+            self.chunks.lock().unwrap().iter()
+                .find(|(_, chunk)| chunk.size < CHUNK_SIZE)
+                .map(|(index, chunk)| index * CHUNK_SIZE + chunk.size)
+        })
+    }
+
+    // Chunk must be full unless it is the last one. The first chunk gives size of state
+    // by its BOC header, so sizes of the rest are checked with it as soon as it is got. Returns size of state
+    // if it is found by the chunk.
+    fn check_chunk(&self, index: usize, data: &[u8], total_size: &AtomicUsize) -> Result<Option<usize>> {
+        let total_size = total_size.load(Ordering::Relaxed);
+        let expected = if total_size == usize::MAX || index * CHUNK_SIZE >= total_size {
+            None
+        } else if (index + 1) * CHUNK_SIZE <= total_size {
+            Some(CHUNK_SIZE)
+        } else {
+            Some(total_size - index * CHUNK_SIZE)
+        };
+        let chunks = self.chunks.lock().unwrap();
+        if data.len() > CHUNK_SIZE || (data.len() < CHUNK_SIZE && chunks.range(index + 1..).next().is_some()) {
+            fail!(NodeError::Network(
+                NetworkError::BadData, format!("chunk {} has wrong size {}", index, data.len())
+            ))
+        }
+        if let Some(expected) = expected {
+            if expected != data.len() {
+                fail!(NodeError::Network(
+                    NetworkError::BadData,
+                    format!("chunk {} has size {}, expected {}", index, data.len(), expected)
+                ))
+            }
+        }
+        let found_size = if index == 0 {
+            match state_boc_size(data) {
+                Some(size) => {
+                    let consistent = (total_size == usize::MAX || total_size == size) &&
+                        if data.len() < CHUNK_SIZE { size == data.len() } else { size >= CHUNK_SIZE };
+                    if !consistent {
+                        fail!(NodeError::Network(
+                            NetworkError::BadData,
+                            format!("state size {} in BOC header doesn't match chunk size {}", size, data.len())
+                        ))
+                    }
+                    *self.boc_size.lock().unwrap() = Some(size);
+                    Some(size)
+                },
+                // State of unknown format is checked as a whole only
+                None => None
             }
-        });
-        offset += max_size;
+        } else if data.len() < CHUNK_SIZE {
+            Some(index * CHUNK_SIZE + data.len())
+        } else {
+            None
+        };
+        Ok(found_size)
     }
 
-    let res = futures::future::join_all(download_futures)
-        .await
-        .into_iter()
-        .find(|r| r.is_err());
-    active_peers.remove(peer_drop.id());
-    res.unwrap_or(Ok(()))?;
+    fn write_chunk(&self, index: usize, chunk: &[u8], peer: &str) -> Result<()> {
+        {
+            let mut data = self.data.lock().unwrap();
+            data.seek(SeekFrom::Start((index * CHUNK_SIZE) as u64))?;
+            data.write_all(chunk)?;
+            data.sync_data()?;
+        }
+        let chunk = StagedChunk {
+            size: chunk.len(),
+            hash: hex::encode(Sha256::digest(chunk)),
+            peer: peer.to_string(),
+        };
+        let mut journal = self.journal.lock().unwrap();
+        journal.write_all(format!("{} {} {} {}\n", index, chunk.size, chunk.hash, chunk.peer).as_bytes())?;
+        self.chunks.lock().unwrap().insert(index, chunk);
+        Ok(())
+    }
 
-    log::trace!(
-        "download_persistent_state: DOWNLOADED {}ms, id: {}, master_id: {} ", 
-        now.elapsed().as_millis(), id, master_id
+    fn read(&self, total_size: usize) -> Result<Vec<u8>> {
+        let mut data = self.data.lock().unwrap();
+        let mut bytes = vec!(0; total_size);
+        data.seek(SeekFrom::Start(0))?;
+        data.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // Drops chunks of broken state to fetch them again. Chunks of the peer which has given
+    // the most of them are kept unless `all` is set or there is no other peer. Returns count
+    // of dropped chunks.
+    fn drop_chunks(&self, all: bool) -> Result<usize> {
+        let mut journal = self.journal.lock().unwrap();
+        let mut chunks = self.chunks.lock().unwrap();
+        let mut peers = BTreeMap::new();
+        for chunk in chunks.values() {
+            *peers.entry(chunk.peer.clone()).or_insert(0) += 1;
+        }
+        let keep = if all || peers.len() < 2 {
+            None
+        } else {
+            peers.into_iter().max_by_key(|(_, count)| *count).map(|(peer, _)| peer)
+        };
+        let count = chunks.len();
+        chunks.retain(|_, chunk| Some(&chunk.peer) == keep.as_ref());
+        if !chunks.contains_key(&0) {
+            *self.boc_size.lock().unwrap() = None;
+        }
+        *journal = Self::write_journal(&self.journal_path, &chunks)?;
+        Ok(count - chunks.len())
+    }
+
+    fn remove(&self) {
+        for path in [&self.data_path, &self.journal_path].iter() {
+            if let Err(e) = std::fs::remove_file(path) {
+                log::warn!("Can't remove staged state file {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Size of serialized state given by BOC header, `None` if header is broken or
+// has unknown format
+#[cfg(not(feature = "local_test"))]
+fn state_boc_size(data: &[u8]) -> Option<usize> {
+    const BOC_GENERIC_MAGIC: u32 = 0xb5ee_9c72;
+    let read = |pos: usize, size: usize| data.get(pos..pos + size)
+        .map(|bytes| bytes.iter().fold(0u64, |value, byte| (value << 8) | *byte as u64));
+    if read(0, 4)? != BOC_GENERIC_MAGIC as u64 {
+        return None
+    }
+    let flags = read(4, 1)?;
+    let has_index = flags & 0x80 != 0;
+    let has_crc = flags & 0x40 != 0;
+    let ref_size = (flags & 0x07) as usize;
+    let offset_size = read(5, 1)? as usize;
+    if ref_size == 0 || ref_size > 4 || offset_size == 0 || offset_size > 8 {
+        return None
+    }
+    let cells = read(6, ref_size)?;
+    let roots = read(6 + ref_size, ref_size)?;
+    let cells_size = read(6 + 3 * ref_size, offset_size)?;
+    let mut size = (6 + 3 * ref_size + offset_size) as u64;
+    size = size.checked_add(roots.checked_mul(ref_size as u64)?)?;
+    if has_index {
+        size = size.checked_add(cells.checked_mul(offset_size as u64)?)?;
+    }
+    size = size.checked_add(cells_size)?;
+    if has_crc {
+        size = size.checked_add(4)?;
+    }
+    if size > usize::MAX as u64 {
+        return None
+    }
+    Some(size as usize)
+}
+
+#[cfg(not(feature = "local_test"))]
+#[test]
+fn test_staged_state_resume() {
+    use ton_block::ShardIdent;
+    use ton_types::UInt256;
+
+    let staging_dir = std::env::temp_dir().join(format!("state_download_{}", std::process::id()));
+    std::fs::remove_dir_all(&staging_dir).ok();
+    let id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), 100, UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[2; 32])
+    );
+    let total_size = AtomicUsize::new(usize::MAX);
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    staged.write_chunk(0, &vec!(1; CHUNK_SIZE), "peer_a").unwrap();
+    staged.write_chunk(2, &vec!(3; 100), "peer_b").unwrap();
+    staged.write_chunk(1, &vec!(2; CHUNK_SIZE), "peer_a").unwrap();
+    // Short chunk before the last one is wrong
+    assert!(staged.check_chunk(1, &vec!(2; 10), &total_size).is_err());
+    drop(staged);
+
+    // Restart: all chunks are resumed
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    assert_eq!(staged.chunks.lock().unwrap().len(), 3);
+    assert_eq!(staged.chunks.lock().unwrap()[&2].peer, "peer_b");
+    assert_eq!(staged.total_size(), Some(2 * CHUNK_SIZE + 100));
+    let bytes = staged.read(2 * CHUNK_SIZE + 100).unwrap();
+    assert_eq!(bytes[CHUNK_SIZE], 2);
+    assert_eq!(bytes[2 * CHUNK_SIZE + 99], 3);
+    let total_size = AtomicUsize::new(2 * CHUNK_SIZE + 100);
+    assert!(staged.check_chunk(1, &vec!(2; CHUNK_SIZE), &total_size).is_ok());
+    assert!(staged.check_chunk(2, &vec!(3; 99), &total_size).is_err());
+    drop(staged);
+
+    // Corrupted chunk is dropped, others stay
+    {
+        let mut data = OpenOptions::new().write(true)
+            .open(staging_dir.join(format!("state_{:x}.part", id.root_hash()))).unwrap();
+        data.seek(SeekFrom::Start(CHUNK_SIZE as u64 + 10)).unwrap();
+        data.write_all(&[0; 5]).unwrap();
+    }
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    assert_eq!(staged.corrupted, 1);
+    assert_eq!(staged.chunks.lock().unwrap().keys().cloned().collect::<Vec<_>>(), vec!(0, 2));
+    staged.remove();
+    assert_eq!(std::fs::read_dir(&staging_dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&staging_dir).ok();
+}
+
+#[cfg(not(feature = "local_test"))]
+#[test]
+fn test_staged_state_refetch() {
+    use ton_block::ShardIdent;
+    use ton_types::UInt256;
+
+    let staging_dir = std::env::temp_dir().join(format!("state_refetch_{}", std::process::id()));
+    std::fs::remove_dir_all(&staging_dir).ok();
+    let id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), 100, UInt256::from_slice(&[3; 32]), UInt256::from_slice(&[4; 32])
     );
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    staged.write_chunk(0, &vec!(1; CHUNK_SIZE), "peer_a").unwrap();
+    staged.write_chunk(1, &vec!(2; CHUNK_SIZE), "peer_a").unwrap();
+    staged.write_chunk(2, &vec!(3; CHUNK_SIZE), "peer_b").unwrap();
+    staged.write_chunk(3, &vec!(4; 100), "peer_a").unwrap();
 
-    // TODO try to walk map by iterator and fill preallocated vector to speed up this process
-    let total_size = total_size.load(Ordering::Relaxed);
-    let mut state_bytes = Vec::with_capacity(total_size);
-    let mut i = 0;
-    while let Some(part) = parts.get(&i) {
-        state_bytes.extend_from_slice(&part.1);
-        i += max_size;
+    // Broken state: chunks of the minor peer only are fetched again, also after restart
+    assert_eq!(staged.drop_chunks(false).unwrap(), 1);
+    drop(staged);
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    assert_eq!(staged.chunks.lock().unwrap().keys().cloned().collect::<Vec<_>>(), vec!(0, 1, 3));
+    assert_eq!(staged.total_size(), Some(3 * CHUNK_SIZE + 100));
+
+    // Still broken: the whole state is fetched again
+    staged.write_chunk(2, &vec!(3; CHUNK_SIZE), "peer_c").unwrap();
+    assert_eq!(staged.drop_chunks(true).unwrap(), 4);
+    drop(staged);
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    assert!(staged.chunks.lock().unwrap().is_empty());
+    assert_eq!(staged.total_size(), None);
+    staged.remove();
+    std::fs::remove_dir_all(&staging_dir).ok();
+}
+
+#[cfg(not(feature = "local_test"))]
+#[test]
+fn test_state_boc_size() {
+    use ton_types::{BuilderData, IBitstring};
+
+    let mut root = BuilderData::new();
+    for i in 0..4u8 {
+        let mut child = BuilderData::new();
+        child.append_u32(i as u32).unwrap();
+        root.checked_append_reference(child.into_cell().unwrap()).unwrap();
     }
-    assert_eq!(total_size, state_bytes.len());
+    root.append_u64(0x1234_5678).unwrap();
+    let data = ton_types::serialize_toc(&root.into_cell().unwrap()).unwrap();
+    assert_eq!(state_boc_size(&data), Some(data.len()));
+    assert_eq!(state_boc_size(&data[..3]), None);
+    assert_eq!(state_boc_size(&[0; 32]), None);
 
-    ShardStateStuff::deserialize(id.clone(), &state_bytes)
+    // Size of state is known by the first chunk, wrong sizes of chunks are bad data
+    let staging_dir = std::env::temp_dir().join(format!("state_boc_{}", std::process::id()));
+    std::fs::remove_dir_all(&staging_dir).ok();
+    let id = BlockIdExt::with_params(
+        ton_block::ShardIdent::masterchain(), 100,
+        ton_types::UInt256::from_slice(&[5; 32]), ton_types::UInt256::from_slice(&[6; 32])
+    );
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    let total_size = AtomicUsize::new(usize::MAX);
+    assert_eq!(staged.check_chunk(0, &data, &total_size).unwrap(), Some(data.len()));
+    assert!(staged.check_chunk(0, &data[..data.len() - 1], &total_size).is_err());
+    staged.write_chunk(0, &data, "peer_a").unwrap();
+    drop(staged);
+    let staged = StagedState::open(&staging_dir, &id).unwrap();
+    assert_eq!(staged.total_size(), Some(data.len()));
+    staged.remove();
+    std::fs::remove_dir_all(&staging_dir).ok();
 }
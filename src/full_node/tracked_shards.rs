@@ -0,0 +1,98 @@
+use std::sync::Mutex;
+use ton_block::{AccountIdPrefixFull, ShardIdent};
+use ton_types::{fail, Result};
+
+// Shards of processed workchain applied by node in partial mode. Shard is tracked if it
+// intersects with one of configured ones: children of tracked shard are tracked after
+// split, and their parent is tracked after merge. Masterchain is always tracked.
+pub struct TrackedShards {
+    // Empty if whole workchain is tracked
+    configured: Vec<ShardIdent>,
+    // Tracked top shards of the last processed masterchain block
+    current: Mutex<Vec<ShardIdent>>,
+}
+
+impl TrackedShards {
+
+    pub fn new(configured: Vec<ShardIdent>) -> Self {
+        Self { configured, current: Mutex::new(Vec::new()) }
+    }
+
+    pub fn is_partial(&self) -> bool {
+        !self.configured.is_empty()
+    }
+
+    pub fn check_workchain(&self, workchain_id: i32) -> Result<()> {
+        if let Some(shard) = self.configured.iter().find(|shard| shard.workchain_id() != workchain_id) {
+            fail!("Tracked shard {} is not in processed workchain {}", shard, workchain_id)
+        }
+        Ok(())
+    }
+
+    pub fn is_tracked(&self, shard: &ShardIdent) -> bool {
+        shard.is_masterchain() || self.configured.is_empty() ||
+            self.configured.iter().any(|tracked| tracked.intersect_with(shard))
+    }
+
+    pub fn is_account_tracked(&self, prefix: &AccountIdPrefixFull) -> bool {
+        self.configured.is_empty() || ShardIdent::masterchain().contains_full_prefix(prefix) ||
+            self.configured.iter().any(|tracked| tracked.contains_full_prefix(prefix))
+    }
+
+    // Takes top shards of the next masterchain block. Returns true if tracked ones are
+    // changed by split or merge.
+    pub fn update(&self, top_shards: &[ShardIdent]) -> bool {
+        let tracked = top_shards.iter()
+            .filter(|shard| self.is_tracked(shard))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut current = self.current.lock().unwrap();
+        if *current == tracked {
+            return false
+        }
+        log::info!(
+            "Tracked shards: {}",
+            tracked.iter().map(|shard| shard.to_string()).collect::<Vec<_>>().join(", ")
+        );
+        *current = tracked;
+        true
+    }
+
+    pub fn status(&self) -> String {
+        if !self.is_partial() {
+            return "all shards".to_string()
+        }
+        format!(
+            "configured: {}; current: {}",
+            self.configured.iter().map(|shard| shard.to_string()).collect::<Vec<_>>().join(", "),
+            self.current.lock().unwrap().iter().map(|shard| shard.to_string()).collect::<Vec<_>>().join(", ")
+        )
+    }
+}
+
+#[test]
+fn test_tracked_shards() {
+    let shard = |prefix| ShardIdent::with_tagged_prefix(0, prefix).unwrap();
+    let all = TrackedShards::new(Vec::new());
+    assert!(all.is_tracked(&shard(0x4000_0000_0000_0000)));
+
+    let tracked = TrackedShards::new(vec!(shard(0x4000_0000_0000_0000)));
+    assert!(tracked.is_tracked(&ShardIdent::masterchain()));
+    assert!(tracked.is_tracked(&shard(0x8000_0000_0000_0000)));
+    assert!(!tracked.is_tracked(&shard(0xc000_0000_0000_0000)));
+    assert!(!tracked.is_tracked(&ShardIdent::with_tagged_prefix(1, 0x4000_0000_0000_0000).unwrap()));
+    assert!(tracked.check_workchain(1).is_err());
+
+    // Whole workchain, then split into halves, then split of the tracked half
+    assert!(tracked.update(&[shard(0x8000_0000_0000_0000)]));
+    assert!(!tracked.update(&[shard(0x8000_0000_0000_0000)]));
+    assert!(tracked.update(&[shard(0x4000_0000_0000_0000), shard(0xc000_0000_0000_0000)]));
+    assert_eq!(*tracked.current.lock().unwrap(), vec!(shard(0x4000_0000_0000_0000)));
+    assert!(tracked.update(&[
+        shard(0x2000_0000_0000_0000), shard(0x6000_0000_0000_0000), shard(0xc000_0000_0000_0000)
+    ]));
+    assert_eq!(
+        *tracked.current.lock().unwrap(),
+        vec!(shard(0x2000_0000_0000_0000), shard(0x6000_0000_0000_0000))
+    );
+}
@@ -0,0 +1,233 @@
+use crate::{
+    config::{DbBackupConfig, TonNodeConfig}, engine::STATSD,
+    internal_db::{InternalDb, layout::{copy_dir, DbLayout, DATA_CLASSES}}
+};
+use std::{
+    fs, path::Path,
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+use ton_block::BlockIdExt;
+use ton_types::{error, fail, Result};
+
+const META_FILE_NAME: &str = "backup_meta.json";
+const SCHEDULED_BACKUP_PREFIX: &str = "backup_";
+const SCHEDULER_CHECK_INTERVAL_SEC: u64 = 60;
+
+// Stored alongside backup data, backup without it is incomplete
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+pub struct BackupMeta {
+    pub zero_state_root_hash: String,
+    pub zero_state_file_hash: String,
+    pub last_applied_mc_block: String,
+    pub last_applied_mc_seq_no: u32,
+    pub created_at: u64,
+}
+
+impl BackupMeta {
+    pub fn load(backup_path: &str) -> Result<Self> {
+        let path = Path::new(backup_path).join(META_FILE_NAME);
+        let data = fs::read_to_string(&path)
+            .map_err(|e| error!("Can't read backup metadata {}: {}", path.display(), e))?;
+        Ok(serde_json::from_str(&data)?)
+    }
+
+    fn store(&self, backup_path: &str) -> Result<()> {
+        let path = Path::new(backup_path).join(META_FILE_NAME);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| error!("Can't write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+}
+
+// Makes online backups of internal DB in background, one at a time
+pub struct DbBackup {
+    db: Arc<dyn InternalDb>,
+    zero_state_id: BlockIdExt,
+    in_flight: Arc<AtomicBool>,
+    status: Arc<Mutex<String>>,
+}
+
+impl DbBackup {
+
+    pub fn new(db: Arc<dyn InternalDb>, zero_state_id: BlockIdExt) -> Self {
+        Self {
+            db,
+            zero_state_id,
+            in_flight: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(Mutex::new("never run".to_string())),
+        }
+    }
+
+    pub fn status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Starts backup into given new directory.
+    // Returns false if backup is skipped because another one is in flight.
+    pub fn create(&self, path: String) -> Result<bool> {
+        self.create_and_then(path, |_| ())
+    }
+
+    fn create_and_then(
+        &self,
+        path: String,
+        on_success: impl FnOnce(&str) + Send + 'static
+    ) -> Result<bool> {
+        if Path::new(&path).exists() {
+            fail!("Backup directory {} already exists", path)
+        }
+        if self.in_flight.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            log::warn!("DB backup to {} is skipped: another one is in flight", path);
+            return Ok(false)
+        }
+        let db = self.db.clone();
+        let zero_state_id = self.zero_state_id.clone();
+        let in_flight = self.in_flight.clone();
+        let status = self.status.clone();
+        tokio::spawn(async move {
+            log::info!("DB backup to {} started", path);
+            *status.lock().unwrap() = format!("backing up to {}", path);
+            STATSD.gauge("db_backup_in_flight", 1.0);
+            let now = Instant::now();
+            match Self::backup(db.as_ref(), &zero_state_id, &path).await {
+                Ok(meta) => {
+                    log::info!(
+                        "DB backup to {} finished, last applied mc block {}, TIME: {}ms",
+                        path, meta.last_applied_mc_block, now.elapsed().as_millis()
+                    );
+                    STATSD.timer("db_backup_time", now.elapsed().as_millis() as f64);
+                    STATSD.gauge("db_backup_mc_seq_no", meta.last_applied_mc_seq_no as f64);
+                    *status.lock().unwrap() = format!(
+                        "backed up to {} in {}ms, last applied mc block {}, finished at {}",
+                        path, now.elapsed().as_millis(), meta.last_applied_mc_seq_no, meta.created_at
+                    );
+                    on_success(&path);
+                },
+                Err(e) => {
+                    log::error!("DB backup to {} failed: {}", path, e);
+                    STATSD.incr("db_backup_failures");
+                    *status.lock().unwrap() = format!("backup to {} failed at {}: {}", path, unix_time(), e);
+                    // Incomplete backup is useless
+                    if let Err(e) = fs::remove_dir_all(&path) {
+                        log::warn!("Can't remove incomplete backup {}: {}", path, e);
+                    }
+                }
+            }
+            STATSD.gauge("db_backup_in_flight", 0.0);
+            in_flight.store(false, Ordering::Relaxed);
+        });
+        Ok(true)
+    }
+
+    async fn backup(db: &dyn InternalDb, zero_state_id: &BlockIdExt, path: &str) -> Result<BackupMeta> {
+        let last_applied = db.create_backup(path).await?
+            .ok_or_else(|| error!("There is no applied masterchain block to back up"))?;
+        let meta = BackupMeta {
+            zero_state_root_hash: format!("{:x}", zero_state_id.root_hash()),
+            zero_state_file_hash: format!("{:x}", zero_state_id.file_hash()),
+            last_applied_mc_block: format!("{}", last_applied),
+            last_applied_mc_seq_no: last_applied.seq_no(),
+            created_at: unix_time(),
+        };
+        meta.store(path)?;
+        Ok(meta)
+    }
+
+    // Checks config every minute and makes backup when its hour comes
+    pub fn start_scheduler(self: Arc<Self>, config: DbBackupConfig) {
+        let root = match config.path {
+            Some(ref root) => root.clone(),
+            None => return
+        };
+        tokio::spawn(async move {
+            let mut last_run = None;
+            loop {
+                let hour = unix_time() / 3600;
+                let period = config.period_hours as u64;
+                if hour % period == config.start_hour as u64 % period && last_run != Some(hour) {
+                    last_run = Some(hour);
+                    let path = format!("{}/{}{}", root, SCHEDULED_BACKUP_PREFIX, unix_time());
+                    let keep = config.keep_backups as usize;
+                    let root = root.clone();
+                    let started = self.create_and_then(path, move |_| {
+                        if let Err(e) = remove_old_backups(&root, keep) {
+                            log::warn!("Can't remove old backups in {}: {}", root, e);
+                        }
+                    });
+                    match started {
+                        Ok(true) => log::info!("Scheduled DB backup started"),
+                        Ok(false) => (),
+                        Err(e) => log::error!("Can't start scheduled DB backup: {}", e)
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(SCHEDULER_CHECK_INTERVAL_SEC)).await;
+            }
+        });
+    }
+}
+
+// Removes scheduled backups except `keep` latest ones
+fn remove_old_backups(root: &str, keep: usize) -> Result<()> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(time) = name.strip_prefix(SCHEDULED_BACKUP_PREFIX) {
+            if let Ok(time) = time.parse::<u64>() {
+                backups.push((time, entry.path()));
+            }
+        }
+    }
+    backups.sort();
+    let count = backups.len().saturating_sub(keep);
+    for (_, path) in backups.into_iter().take(count) {
+        log::info!("Removing old DB backup {}", path.display());
+        fs::remove_dir_all(&path)?;
+    }
+    Ok(())
+}
+
+// Copies backup to internal DB directories set by config. Backup must be made for the
+// network of the config, and internal DB must be empty.
+pub fn restore_backup(backup_path: &str, config: &TonNodeConfig) -> Result<BackupMeta> {
+    let meta = BackupMeta::load(backup_path)?;
+    let zero_state_id = config.load_global_config()?.zero_state()?;
+    if meta.zero_state_root_hash != format!("{:x}", zero_state_id.root_hash()) ||
+        meta.zero_state_file_hash != format!("{:x}", zero_state_id.file_hash())
+    {
+        fail!(
+            "Backup {} is made for another network: zero state root hash {}, file hash {}, \
+            but config has {}",
+            backup_path, meta.zero_state_root_hash, meta.zero_state_file_hash, zero_state_id
+        )
+    }
+    let db_directory = config.internal_db_path().unwrap_or("node_db");
+    let layout = DbLayout::new(db_directory, config.internal_db_paths());
+    for class in DATA_CLASSES.iter() {
+        for entry in class.entries() {
+            let target = Path::new(layout.dir(*class)).join(entry);
+            if target.exists() {
+                fail!("Internal DB already has data in {}, remove it before restoring", target.display())
+            }
+        }
+    }
+    for class in DATA_CLASSES.iter() {
+        for entry in class.entries() {
+            let source = Path::new(backup_path).join(entry);
+            if source.exists() {
+                log::info!("Restoring {} from backup {}", entry, backup_path);
+                copy_dir(&source, &Path::new(layout.dir(*class)).join(entry))?;
+            }
+        }
+    }
+    log::info!(
+        "Internal DB is restored from backup {}, last applied mc block {}",
+        backup_path, meta.last_applied_mc_block
+    );
+    Ok(meta)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,114 @@
+use crate::{
+    config::DbCompactionWindow, engine::STATSD, internal_db::{InternalDb, COMPACTABLE_DBS}
+};
+use std::{
+    sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
+};
+use ton_types::{fail, Result};
+
+const SCHEDULER_CHECK_INTERVAL_SEC: u64 = 60;
+
+// Runs full compactions of internal databases in background thread, one at a time
+pub struct DbCompactor {
+    db: Arc<dyn InternalDb>,
+    in_flight: Arc<AtomicBool>,
+    status: Arc<Mutex<String>>,
+}
+
+impl DbCompactor {
+
+    pub fn new(db: Arc<dyn InternalDb>) -> Self {
+        Self {
+            db,
+            in_flight: Arc::new(AtomicBool::new(false)),
+            status: Arc::new(Mutex::new("never run".to_string())),
+        }
+    }
+
+    pub fn status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+
+    // Starts compaction of given database or all ones if not set.
+    // Returns false if compaction is skipped because another one is in flight.
+    pub fn compact(&self, db_name: Option<String>) -> Result<bool> {
+        let names = match db_name {
+            Some(name) => {
+                if !COMPACTABLE_DBS.contains(&name.as_str()) {
+                    fail!("Unknown database {}, supported ones: {:?}", name, COMPACTABLE_DBS)
+                }
+                vec!(name)
+            },
+            None => COMPACTABLE_DBS.iter().map(|name| name.to_string()).collect()
+        };
+        if self.in_flight.compare_exchange(false, true, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+            log::warn!("DB compaction of {:?} is skipped: another one is in flight", names);
+            return Ok(false)
+        }
+        let db = self.db.clone();
+        let in_flight = self.in_flight.clone();
+        let status = self.status.clone();
+        let spawned = std::thread::Builder::new().name("DB compaction".to_string()).spawn(move || {
+            log::info!("DB compaction of {:?} started", names);
+            STATSD.gauge("db_compaction_in_flight", 1.0);
+            let now = Instant::now();
+            let mut failed = 0;
+            for (i, name) in names.iter().enumerate() {
+                *status.lock().unwrap() = format!("compacting {} ({} of {})", name, i + 1, names.len());
+                let started = Instant::now();
+                match db.compact_db(name) {
+                    Ok(()) => log::info!("DB compaction of {} finished, TIME: {}ms", name, started.elapsed().as_millis()),
+                    Err(e) => {
+                        failed += 1;
+                        log::error!("DB compaction of {} failed: {}", name, e);
+                    }
+                }
+                STATSD.timer(&format!("db_compaction_time.{}", name), started.elapsed().as_millis() as f64);
+                STATSD.gauge("db_compaction_progress", ((i + 1) * 100 / names.len()) as f64);
+            }
+            STATSD.timer("db_compaction_time", now.elapsed().as_millis() as f64);
+            STATSD.gauge("db_compaction_in_flight", 0.0);
+            *status.lock().unwrap() = format!(
+                "compacted {:?} in {}ms, failed: {}, finished at {}",
+                names, now.elapsed().as_millis(), failed, unix_time()
+            );
+            in_flight.store(false, Ordering::Relaxed);
+        });
+        if let Err(e) = spawned {
+            self.in_flight.store(false, Ordering::Relaxed);
+            fail!("Can't start DB compaction thread: {}", e)
+        }
+        Ok(true)
+    }
+
+    // Checks windows every minute and starts compaction when window's hour comes
+    pub fn start_scheduler(self: Arc<Self>, windows: Vec<DbCompactionWindow>) {
+        if windows.is_empty() {
+            return
+        }
+        tokio::spawn(async move {
+            let mut last_runs = vec!(None; windows.len());
+            loop {
+                let hour = unix_time() / 3600;
+                for (window, last_run) in windows.iter().zip(last_runs.iter_mut()) {
+                    let period = window.period_hours as u64;
+                    if hour % period != window.start_hour as u64 % period || *last_run == Some(hour) {
+                        continue
+                    }
+                    *last_run = Some(hour);
+                    match self.compact(window.db.clone()) {
+                        Ok(true) => log::info!("Scheduled DB compaction of {:?} started", window.db),
+                        Ok(false) => (),
+                        Err(e) => log::error!("Can't start scheduled DB compaction: {}", e)
+                    }
+                }
+                tokio::time::sleep(Duration::from_secs(SCHEDULER_CHECK_INTERVAL_SEC)).await;
+            }
+        });
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
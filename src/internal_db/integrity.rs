@@ -0,0 +1,444 @@
+use crate::{
+    block::BlockStuff,
+    internal_db::{InternalDb, LAST_APPLIED_MC_BLOCK, PSS_KEEPER_MC_BLOCK, SHARD_CLIENT_MC_BLOCK},
+};
+use std::collections::HashMap;
+use ton_block::BlockIdExt;
+#[cfg(test)]
+use ton_block::{ShardIdent, SHARD_FULL};
+use ton_types::{error, fail, Result};
+#[cfg(test)]
+use ton_types::UInt256;
+
+// Part of internal DB needed to check the last applied blocks after restart
+#[async_trait::async_trait]
+pub trait CheckedBlocksDb: Sync + Send {
+    fn load_pointer(&self, key: &'static str) -> Result<Option<BlockIdExt>>;
+    fn save_pointer(&self, key: &'static str, id: &BlockIdExt) -> Result<()>;
+    fn load_prev_mc_block(&self, id: &BlockIdExt) -> Result<BlockIdExt>;
+    // Checks that block is applied and its data, proof and state are present (full check
+    // also verifies their hashes). Returns masterchain block's top blocks of the workchain.
+    async fn check_block(
+        &self,
+        id: &BlockIdExt,
+        full: bool,
+        workchain_id: Option<i32>
+    ) -> Result<Vec<BlockIdExt>>;
+    fn reset_applied(&self, id: &BlockIdExt) -> Result<bool>;
+}
+
+#[async_trait::async_trait]
+impl<T: InternalDb + ?Sized> CheckedBlocksDb for T {
+    fn load_pointer(&self, key: &'static str) -> Result<Option<BlockIdExt>> {
+        Ok(self.load_node_state(key)?.map(|id| id.as_ref().clone()))
+    }
+
+    fn save_pointer(&self, key: &'static str, id: &BlockIdExt) -> Result<()> {
+        self.save_node_state(key, id)
+    }
+
+    fn load_prev_mc_block(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
+        self.load_block_prev1(id)
+    }
+
+    async fn check_block(
+        &self,
+        id: &BlockIdExt,
+        full: bool,
+        workchain_id: Option<i32>
+    ) -> Result<Vec<BlockIdExt>> {
+        let handle = self.load_block_handle(id)?.ok_or_else(
+            || error!("there is no handle")
+        )?;
+        if !handle.has_state() {
+            fail!("state is not stored")
+        }
+        let state = self.load_shard_state_dynamic(id)?;
+        // Zero state has neither data nor proof
+        if id.seq_no() != 0 {
+            if !handle.is_applied() {
+                fail!("block is not applied")
+            }
+            let mut is_link = false;
+            if !handle.has_data() {
+                fail!("data is not stored")
+            }
+            if !handle.has_proof_or_link(&mut is_link) {
+                fail!("proof is not stored")
+            }
+            if full {
+                let block = BlockStuff::deserialize_checked(id.clone(), self.load_block_data_raw(&handle).await?)?;
+                self.load_block_proof(&handle, is_link).await?;
+                let state_hash = block.block().read_state_update()?.new_hash;
+                if state.root_cell().repr_hash() != state_hash {
+                    fail!("state root hash {} differs from the block's one {}", state.root_cell().repr_hash(), state_hash)
+                }
+            } else {
+                self.load_block_data_raw(&handle).await?;
+                self.load_block_proof_raw(&handle, is_link).await?;
+            }
+        }
+        match workchain_id {
+            Some(workchain_id) if id.shard().is_masterchain() => {
+                state.shard_hashes()?.top_blocks(&[workchain_id])
+            }
+            _ => Ok(vec!())
+        }
+    }
+
+    fn reset_applied(&self, id: &BlockIdExt) -> Result<bool> {
+        self.reset_block_applied(id)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct IntegrityCheckReport {
+    pub checked_mc_blocks: u32,
+    pub checked_shard_blocks: u32,
+    pub broken_blocks: u32,
+    pub truncated: bool,
+}
+
+// Checks the last `depth` masterchain blocks and top shard blocks they refer and moves
+// last applied, shard client and PSS keeper pointers back to the newest consistent blocks.
+// Blocks after them are marked not applied to be applied again.
+pub async fn check_and_truncate<D: CheckedBlocksDb + ?Sized>(
+    db: &D,
+    depth: u32,
+    full: bool,
+    workchain_id: Option<i32>
+) -> Result<IntegrityCheckReport> {
+    let mut report = IntegrityCheckReport::default();
+    let last_applied = match db.load_pointer(LAST_APPLIED_MC_BLOCK)? {
+        Some(id) => id,
+        // Node is not booted yet
+        None => return Ok(report)
+    };
+    let shard_client = db.load_pointer(SHARD_CLIENT_MC_BLOCK)?;
+
+    // Masterchain blocks from the last applied one
+    let mut chain = vec!(last_applied.clone());
+    while chain.len() < depth as usize {
+        let id = chain.last().unwrap();
+        if id.seq_no() == 0 {
+            break
+        }
+        match db.load_prev_mc_block(id) {
+            Ok(prev) => chain.push(prev),
+            Err(e) => {
+                log::warn!("DB integrity: can't load previous block for {}: {}", id, e);
+                break
+            }
+        }
+    }
+
+    let mut new_last_applied = None;
+    let mut new_shard_client = None;
+    let mut broken_shard_blocks = vec!();
+    let mut checked_shard_blocks = HashMap::new();
+    let shard_client_seqno = shard_client.as_ref().map(|id| id.seq_no()).unwrap_or(0);
+    for id in chain.iter() {
+        report.checked_mc_blocks += 1;
+        let top_blocks = match db.check_block(id, full, workchain_id).await {
+            Ok(top_blocks) => top_blocks,
+            Err(e) => {
+                log::warn!("DB integrity: masterchain block {} is broken: {}", id, e);
+                report.broken_blocks += 1;
+                continue
+            }
+        };
+        if new_last_applied.is_none() {
+            new_last_applied = Some(id.clone());
+        }
+        // Shard blocks are applied up to the shard client's masterchain block only
+        if new_shard_client.is_none() && id.seq_no() <= shard_client_seqno {
+            let mut consistent = true;
+            for shard_block in top_blocks {
+                if let Some(ok) = checked_shard_blocks.get(&shard_block) {
+                    consistent &= *ok;
+                    continue
+                }
+                report.checked_shard_blocks += 1;
+                let ok = match db.check_block(&shard_block, full, None).await {
+                    Ok(_) => true,
+                    Err(e) => {
+                        log::warn!("DB integrity: shard block {} is broken: {}", shard_block, e);
+                        report.broken_blocks += 1;
+                        broken_shard_blocks.push(shard_block.clone());
+                        false
+                    }
+                };
+                checked_shard_blocks.insert(shard_block, ok);
+                consistent &= ok;
+            }
+            if consistent {
+                new_shard_client = Some(id.clone());
+            }
+        }
+        if new_last_applied.is_some() && (new_shard_client.is_some() || shard_client.is_none()) {
+            break
+        }
+    }
+
+    let new_last_applied = new_last_applied.ok_or_else(|| error!(
+        "DB integrity: there is no consistent masterchain block among {} ones before {}, \
+        node has to be synced from scratch",
+        chain.len(), last_applied
+    ))?;
+    let oldest_checked = chain.last().unwrap().seq_no();
+    let new_shard_client = match shard_client {
+        Some(shard_client) => match new_shard_client {
+            Some(id) => Some((shard_client, id)),
+            None if shard_client.seq_no() >= oldest_checked => fail!(
+                "DB integrity: there is no consistent shard client block among {} ones before {}, \
+                node has to be synced from scratch",
+                chain.len(), shard_client
+            ),
+            // Shard client is too far behind to be checked
+            None => None
+        },
+        None => None
+    };
+
+    if new_last_applied != last_applied {
+        log::warn!("DB integrity: last applied block is truncated {} -> {}", last_applied, new_last_applied);
+        db.save_pointer(LAST_APPLIED_MC_BLOCK, &new_last_applied)?;
+        for id in chain.iter().take_while(|id| id.seq_no() > new_last_applied.seq_no()) {
+            db.reset_applied(id)?;
+        }
+        report.truncated = true;
+    }
+    if let Some((shard_client, new_shard_client)) = new_shard_client {
+        if new_shard_client != shard_client {
+            log::warn!("DB integrity: shard client block is truncated {} -> {}", shard_client, new_shard_client);
+            db.save_pointer(SHARD_CLIENT_MC_BLOCK, &new_shard_client)?;
+            report.truncated = true;
+        }
+    }
+    for id in broken_shard_blocks.iter() {
+        db.reset_applied(id)?;
+    }
+    if let Some(pss_keeper) = db.load_pointer(PSS_KEEPER_MC_BLOCK)? {
+        if pss_keeper.seq_no() > new_last_applied.seq_no() {
+            log::warn!("DB integrity: PSS keeper block is truncated {} -> {}", pss_keeper, new_last_applied);
+            db.save_pointer(PSS_KEEPER_MC_BLOCK, &new_last_applied)?;
+            report.truncated = true;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+#[derive(Clone)]
+struct FixtureBlock {
+    prev: Option<BlockIdExt>,
+    applied: bool,
+    data: bool,
+    proof: bool,
+    state: bool,
+    top_blocks: Vec<BlockIdExt>,
+}
+
+// Masterchain blocks 0..=last_seqno, each one refers to shard block with the same seq_no
+#[cfg(test)]
+struct FixtureDb {
+    blocks: std::sync::Mutex<HashMap<BlockIdExt, FixtureBlock>>,
+    pointers: std::sync::Mutex<HashMap<&'static str, BlockIdExt>>,
+}
+
+#[cfg(test)]
+impl FixtureDb {
+    fn new(last_seqno: u32, shard_client_seqno: u32) -> Self {
+        let mut blocks = HashMap::new();
+        for seq_no in 0..=last_seqno {
+            let prev = if seq_no == 0 {
+                None
+            } else {
+                Some(fixture_mc_id(seq_no - 1))
+            };
+            let block = FixtureBlock {
+                prev,
+                applied: true,
+                data: true,
+                proof: true,
+                state: true,
+                top_blocks: vec!(fixture_shard_id(seq_no)),
+            };
+            blocks.insert(fixture_shard_id(seq_no), FixtureBlock { top_blocks: vec!(), ..block.clone() });
+            blocks.insert(fixture_mc_id(seq_no), block);
+        }
+        let mut pointers = HashMap::new();
+        pointers.insert(LAST_APPLIED_MC_BLOCK, fixture_mc_id(last_seqno));
+        pointers.insert(SHARD_CLIENT_MC_BLOCK, fixture_mc_id(shard_client_seqno));
+        pointers.insert(PSS_KEEPER_MC_BLOCK, fixture_mc_id(last_seqno));
+        Self {
+            blocks: std::sync::Mutex::new(blocks),
+            pointers: std::sync::Mutex::new(pointers),
+        }
+    }
+
+    fn damage(&self, id: &BlockIdExt, damage: impl FnOnce(&mut FixtureBlock)) {
+        damage(self.blocks.lock().unwrap().get_mut(id).unwrap())
+    }
+
+    fn pointer(&self, key: &'static str) -> u32 {
+        self.pointers.lock().unwrap().get(key).unwrap().seq_no()
+    }
+
+    fn is_applied(&self, id: &BlockIdExt) -> bool {
+        self.blocks.lock().unwrap().get(id).unwrap().applied
+    }
+
+    fn check(&self, depth: u32) -> Result<IntegrityCheckReport> {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(check_and_truncate(self, depth, true, Some(0)))
+    }
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl CheckedBlocksDb for FixtureDb {
+    fn load_pointer(&self, key: &'static str) -> Result<Option<BlockIdExt>> {
+        Ok(self.pointers.lock().unwrap().get(key).cloned())
+    }
+
+    fn save_pointer(&self, key: &'static str, id: &BlockIdExt) -> Result<()> {
+        self.pointers.lock().unwrap().insert(key, id.clone());
+        Ok(())
+    }
+
+    fn load_prev_mc_block(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
+        self.blocks.lock().unwrap().get(id).and_then(|block| block.prev.clone()).ok_or_else(
+            || error!("there is no prev block for {}", id)
+        )
+    }
+
+    async fn check_block(
+        &self,
+        id: &BlockIdExt,
+        _full: bool,
+        workchain_id: Option<i32>
+    ) -> Result<Vec<BlockIdExt>> {
+        let blocks = self.blocks.lock().unwrap();
+        let block = blocks.get(id).ok_or_else(|| error!("there is no handle"))?;
+        if !block.state {
+            fail!("state is not stored")
+        }
+        if id.seq_no() != 0 && !(block.applied && block.data && block.proof) {
+            fail!("block is not applied or its data is not stored")
+        }
+        Ok(if workchain_id.is_some() { block.top_blocks.clone() } else { vec!() })
+    }
+
+    fn reset_applied(&self, id: &BlockIdExt) -> Result<bool> {
+        let mut blocks = self.blocks.lock().unwrap();
+        let block = blocks.get_mut(id).ok_or_else(|| error!("there is no handle"))?;
+        let was_applied = block.applied;
+        block.applied = false;
+        Ok(was_applied)
+    }
+}
+
+#[cfg(test)]
+fn fixture_mc_id(seq_no: u32) -> BlockIdExt {
+    BlockIdExt {
+        shard_id: ShardIdent::masterchain(),
+        seq_no,
+        root_hash: UInt256::from([seq_no as u8; 32]),
+        file_hash: UInt256::default(),
+    }
+}
+
+#[cfg(test)]
+fn fixture_shard_id(seq_no: u32) -> BlockIdExt {
+    BlockIdExt {
+        shard_id: ShardIdent::with_tagged_prefix(0, SHARD_FULL).unwrap(),
+        seq_no,
+        root_hash: UInt256::from([seq_no as u8; 32]),
+        file_hash: UInt256::default(),
+    }
+}
+
+#[test]
+fn test_db_integrity_consistent() {
+    let db = FixtureDb::new(10, 10);
+    let report = db.check(16).unwrap();
+    assert!(!report.truncated);
+    assert_eq!(report.checked_mc_blocks, 1);
+    assert_eq!(report.checked_shard_blocks, 1);
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 10);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 10);
+}
+
+#[test]
+fn test_db_integrity_truncates_missing_state_and_data() {
+    let db = FixtureDb::new(10, 10);
+    db.damage(&fixture_mc_id(10), |block| block.state = false);
+    db.damage(&fixture_mc_id(9), |block| block.data = false);
+    let report = db.check(16).unwrap();
+    assert!(report.truncated);
+    assert_eq!(report.broken_blocks, 2);
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 8);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 8);
+    assert_eq!(db.pointer(PSS_KEEPER_MC_BLOCK), 8);
+    assert!(!db.is_applied(&fixture_mc_id(10)));
+    assert!(!db.is_applied(&fixture_mc_id(9)));
+    assert!(db.is_applied(&fixture_mc_id(8)));
+}
+
+#[test]
+fn test_db_integrity_truncates_shard_client() {
+    let db = FixtureDb::new(10, 10);
+    db.damage(&fixture_shard_id(10), |block| block.state = false);
+    let report = db.check(16).unwrap();
+    assert!(report.truncated);
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 10);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 9);
+    assert_eq!(db.pointer(PSS_KEEPER_MC_BLOCK), 10);
+    assert!(db.is_applied(&fixture_mc_id(10)));
+    assert!(!db.is_applied(&fixture_shard_id(10)));
+}
+
+#[test]
+fn test_db_integrity_keeps_shard_client_behind() {
+    let db = FixtureDb::new(10, 5);
+    db.damage(&fixture_mc_id(10), |block| block.proof = false);
+    let report = db.check(16).unwrap();
+    assert!(report.truncated);
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 9);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 5);
+    assert_eq!(report.checked_mc_blocks, 6);
+}
+
+#[test]
+fn test_db_integrity_ignores_older_broken_blocks() {
+    let db = FixtureDb::new(10, 10);
+    db.damage(&fixture_mc_id(8), |block| block.state = false);
+    let report = db.check(16).unwrap();
+    assert!(!report.truncated);
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 10);
+}
+
+#[test]
+fn test_db_integrity_fails_without_consistent_block() {
+    let db = FixtureDb::new(10, 10);
+    for seq_no in 8..=10 {
+        db.damage(&fixture_mc_id(seq_no), |block| block.applied = false);
+    }
+    assert!(db.check(3).is_err());
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 10);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 10);
+    // Deeper check finds the consistent block
+    db.check(4).unwrap();
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 7);
+}
+
+#[test]
+fn test_db_integrity_reaches_zerostate() {
+    let db = FixtureDb::new(2, 2);
+    db.damage(&fixture_mc_id(2), |block| block.data = false);
+    db.damage(&fixture_mc_id(1), |block| block.data = false);
+    db.check(16).unwrap();
+    assert_eq!(db.pointer(LAST_APPLIED_MC_BLOCK), 0);
+    assert_eq!(db.pointer(SHARD_CLIENT_MC_BLOCK), 0);
+}
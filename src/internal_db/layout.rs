@@ -0,0 +1,252 @@
+use crate::config::DbPathsConfig;
+use std::{fs, path::{Path, PathBuf}};
+use ton_types::{error, fail, Result};
+
+// Layout of the last start is stored in main DB directory to detect data left in old places
+const LAYOUT_FILE_NAME: &str = "db_layout.json";
+
+// Data classes of internal DB. Each one can be placed in its own directory:
+// - blocks: block handles, node state, indexes, prev/next links, top blocks, ext messages
+// - cells: shard states and cells
+// - archives: archive packages (block data and proofs) and their file maps
+// - persistent states: serialized persistent states offered to booting nodes
+// Other data (catchains, candidates, etc.) is always in main DB directory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DbDataClass {
+    Blocks,
+    Cells,
+    Archives,
+    PersistentStates,
+}
+
+pub const DATA_CLASSES: [DbDataClass; 4] = [
+    DbDataClass::Blocks, DbDataClass::Cells, DbDataClass::Archives, DbDataClass::PersistentStates
+];
+
+impl DbDataClass {
+    // Directories of the class, relative to its root
+    pub fn entries(&self) -> &'static [&'static str] {
+        match self {
+            DbDataClass::Blocks => &[
+                "block_handle_db", "node_state_db", "index_db", "prev1_block_db", "prev2_block_db",
+                "next1_block_db", "next2_block_db", "shard_top_blocks_db", "ext_messages_db",
+                "transactions_index_db", "block_db", "block_proof_db", "block_proof_link_db"
+            ],
+            DbDataClass::Cells => &["shardstate_db", "cells_db", "cells_db1"],
+            DbDataClass::Archives => &["archive", "file_maps"],
+            DbDataClass::PersistentStates => &["shard_state_persistent_db"],
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct DbLayout {
+    blocks: String,
+    cells: String,
+    archives: String,
+    persistent_states: String,
+}
+
+impl DbLayout {
+
+    // Not configured classes are placed in main DB directory
+    pub fn new(db_directory: &str, paths: &DbPathsConfig) -> Self {
+        let path = |path: &Option<String>| path.clone().unwrap_or_else(|| db_directory.to_string());
+        Self {
+            blocks: path(&paths.blocks),
+            cells: path(&paths.cells),
+            archives: path(&paths.archives),
+            persistent_states: path(&paths.persistent_states),
+        }
+    }
+
+    pub fn dir(&self, class: DbDataClass) -> &str {
+        match class {
+            DbDataClass::Blocks => &self.blocks,
+            DbDataClass::Cells => &self.cells,
+            DbDataClass::Archives => &self.archives,
+            DbDataClass::PersistentStates => &self.persistent_states,
+        }
+    }
+
+    pub fn path(&self, class: DbDataClass, name: &str) -> String {
+        format!("{}/{}", self.dir(class), name)
+    }
+
+    // Compares layout with the one of the last start (all classes in main directory
+    // if there is no stored one). Data found in old places is moved if `migrate` is set,
+    // otherwise it is an error. The layout is stored on success.
+    pub fn check_and_migrate(&self, db_directory: &str, migrate: bool) -> Result<()> {
+        let prev = Self::load(db_directory)?
+            .unwrap_or_else(|| Self::new(db_directory, &DbPathsConfig::default()));
+        let moves = self.pending_moves(&prev)?;
+        if !moves.is_empty() {
+            if !migrate {
+                fail!(
+                    "Internal DB layout is changed, data must be moved: {}. \
+                    Run node with --migrate-db-layout to move it",
+                    moves.iter()
+                        .map(|(from, to)| format!("{} -> {}", from.display(), to.display()))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+            for (from, to) in moves {
+                log::info!("Moving internal DB data {} -> {}", from.display(), to.display());
+                move_dir(&from, &to)?;
+            }
+        }
+        if prev != *self || !Path::new(db_directory).join(LAYOUT_FILE_NAME).exists() {
+            self.store(db_directory)?;
+        }
+        Ok(())
+    }
+
+    // Read-only check that layout is the same as the one of the last start
+    pub fn check(&self, db_directory: &str) -> Result<()> {
+        let prev = Self::load(db_directory)?
+            .unwrap_or_else(|| Self::new(db_directory, &DbPathsConfig::default()));
+        if prev != *self {
+            fail!("Internal DB layout {:?} differs from the stored one {:?}", self, prev)
+        }
+        Ok(())
+    }
+
+    // Directories which exist in previous place of their class
+    fn pending_moves(&self, prev: &DbLayout) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut moves = Vec::new();
+        for class in DATA_CLASSES.iter() {
+            if Path::new(prev.dir(*class)) == Path::new(self.dir(*class)) {
+                continue
+            }
+            for entry in class.entries() {
+                let from = Path::new(prev.dir(*class)).join(entry);
+                if !from.exists() {
+                    continue
+                }
+                let to = Path::new(self.dir(*class)).join(entry);
+                if to.exists() {
+                    fail!(
+                        "Internal DB data is found in both {} and {}, remove one of them",
+                        from.display(), to.display()
+                    )
+                }
+                moves.push((from, to));
+            }
+        }
+        Ok(moves)
+    }
+
+    fn load(db_directory: &str) -> Result<Option<Self>> {
+        let path = Path::new(db_directory).join(LAYOUT_FILE_NAME);
+        if !path.exists() {
+            return Ok(None)
+        }
+        let data = fs::read_to_string(&path)
+            .map_err(|e| error!("Can't read {}: {}", path.display(), e))?;
+        Ok(Some(serde_json::from_str(&data)?))
+    }
+
+    fn store(&self, db_directory: &str) -> Result<()> {
+        fs::create_dir_all(db_directory)?;
+        let path = Path::new(db_directory).join(LAYOUT_FILE_NAME);
+        fs::write(&path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| error!("Can't write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+}
+
+// Renames directory, or copies and removes it if directories are on different devices
+fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(())
+    }
+    copy_dir(from, to)?;
+    fs::remove_dir_all(from)?;
+    Ok(())
+}
+
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("db_layout_{}_{}", name, std::process::id()));
+    fs::remove_dir_all(&path).ok();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn test_layout_paths() {
+    let layout = DbLayout::new("node_db", &DbPathsConfig::default());
+    for class in DATA_CLASSES.iter() {
+        assert_eq!(layout.dir(*class), "node_db");
+    }
+    assert_eq!(layout.path(DbDataClass::Cells, "cells_db"), "node_db/cells_db");
+
+    let paths = DbPathsConfig {
+        archives: Some("/hdd/archives".to_string()),
+        persistent_states: Some("/hdd/states".to_string()),
+        ..Default::default()
+    };
+    let layout = DbLayout::new("node_db", &paths);
+    assert_eq!(layout.path(DbDataClass::Blocks, "block_handle_db"), "node_db/block_handle_db");
+    assert_eq!(layout.path(DbDataClass::Cells, "cells_db"), "node_db/cells_db");
+    assert_eq!(layout.dir(DbDataClass::Archives), "/hdd/archives");
+    assert_eq!(
+        layout.path(DbDataClass::PersistentStates, "shard_state_persistent_db"),
+        "/hdd/states/shard_state_persistent_db"
+    );
+}
+
+#[test]
+fn test_layout_migration() {
+    let db_directory = test_dir("main");
+    let hdd = test_dir("hdd");
+    fs::create_dir_all(format!("{}/cells_db", db_directory)).unwrap();
+    fs::create_dir_all(format!("{}/archive/packages", db_directory)).unwrap();
+    fs::write(format!("{}/archive/packages/archive.00000.pack", db_directory), b"pack").unwrap();
+
+    // Single directory layout is stored on the first start
+    let single = DbLayout::new(&db_directory, &DbPathsConfig::default());
+    single.check_and_migrate(&db_directory, false).unwrap();
+    assert_eq!(DbLayout::load(&db_directory).unwrap(), Some(single.clone()));
+
+    // Archives are moved only with migrate flag
+    let paths = DbPathsConfig { archives: Some(hdd.clone()), ..Default::default() };
+    let split = DbLayout::new(&db_directory, &paths);
+    assert!(split.check_and_migrate(&db_directory, false).is_err());
+    assert!(Path::new(&format!("{}/archive", db_directory)).exists());
+    split.check_and_migrate(&db_directory, true).unwrap();
+    assert!(!Path::new(&format!("{}/archive", db_directory)).exists());
+    assert_eq!(fs::read(format!("{}/archive/packages/archive.00000.pack", hdd)).unwrap(), b"pack");
+    assert!(Path::new(&format!("{}/cells_db", db_directory)).exists());
+    assert_eq!(DbLayout::load(&db_directory).unwrap(), Some(split.clone()));
+    split.check_and_migrate(&db_directory, false).unwrap();
+
+    // Moving back is detected by stored layout
+    assert!(single.check_and_migrate(&db_directory, false).is_err());
+    single.check_and_migrate(&db_directory, true).unwrap();
+    assert!(Path::new(&format!("{}/archive/packages", db_directory)).exists());
+
+    // Data in both places is never merged
+    fs::create_dir_all(format!("{}/archive", hdd)).unwrap();
+    assert!(split.check_and_migrate(&db_directory, true).is_err());
+
+    fs::remove_dir_all(&db_directory).ok();
+    fs::remove_dir_all(&hdd).ok();
+}
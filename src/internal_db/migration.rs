@@ -0,0 +1,256 @@
+use crate::internal_db::{InternalDbImpl, INITIAL_MC_BLOCK, LAST_APPLIED_MC_BLOCK};
+use std::{convert::TryInto, io::Cursor};
+use ton_types::{deserialize_tree_of_cells, error, fail, Result, UInt256};
+
+/// Node state key of DB schema version (u32 LE)
+pub(crate) const DB_SCHEMA_VERSION: &str = "DbSchemaVersion";
+
+// Databases created before versioning have no version record
+const UNVERSIONED_SCHEMA_VERSION: u32 = 1;
+
+// Brings DB schema from `version() - 1` to `version()`. Version is stored only after
+// successful apply, so steps must be idempotent: interrupted one is applied again.
+pub trait Migration {
+    fn version(&self) -> u32;
+    fn description(&self) -> &'static str;
+    fn apply(&self, db: &mut InternalDbImpl) -> Result<()>;
+}
+
+// Registered steps, ordered by version
+fn migrations() -> Vec<Box<dyn Migration>> {
+    vec!(
+        Box::new(ExtMessagesPersistence),
+    )
+}
+
+pub fn current_version() -> u32 {
+    migrations().last().map(|m| m.version()).unwrap_or(UNVERSIONED_SCHEMA_VERSION)
+}
+
+pub(crate) fn load_version(db: &InternalDbImpl) -> Result<Option<u32>> {
+    match db.node_state_db.try_get(&DB_SCHEMA_VERSION)? {
+        Some(slice) => {
+            let bytes: [u8; 4] = slice.as_ref().try_into()
+                .map_err(|_| error!("Invalid DB schema version record length {}", slice.as_ref().len()))?;
+            Ok(Some(u32::from_le_bytes(bytes)))
+        },
+        None => Ok(None)
+    }
+}
+
+fn store_version(db: &InternalDbImpl, version: u32) -> Result<()> {
+    db.node_state_db.put(&DB_SCHEMA_VERSION, &version.to_le_bytes())
+}
+
+// Checks schema version and runs pending migrations. New DB gets current version.
+// DB opened read-only is only checked. Returns version of DB.
+pub fn migrate(db: &mut InternalDbImpl) -> Result<u32> {
+    let current = current_version();
+    let version = match load_version(db)? {
+        Some(version) => version,
+        None => {
+            let is_new = db.block_handle_storage.load_state(INITIAL_MC_BLOCK)?.is_none() &&
+                db.block_handle_storage.load_state(LAST_APPLIED_MC_BLOCK)?.is_none();
+            if is_new {
+                if !db.config.read_only {
+                    store_version(db, current)?;
+                }
+                return Ok(current)
+            }
+            UNVERSIONED_SCHEMA_VERSION
+        }
+    };
+    if version > current {
+        fail!(
+            "Internal DB schema version {} is newer than {} supported by this node build, \
+            use newer build or another DB",
+            version, current
+        )
+    }
+    if version == current {
+        return Ok(version)
+    }
+    if db.config.read_only {
+        fail!(
+            "Internal DB schema version {} is older than {}, it must be migrated by node \
+            running with the DB",
+            version, current
+        )
+    }
+    for migration in migrations().iter().filter(|m| m.version() > version) {
+        log::info!(
+            "Migrating internal DB to schema version {}: {}",
+            migration.version(), migration.description()
+        );
+        let now = std::time::Instant::now();
+        migration.apply(db)?;
+        store_version(db, migration.version())?;
+        log::info!(
+            "Internal DB is migrated to schema version {}, TIME: {}ms",
+            migration.version(), now.elapsed().as_millis()
+        );
+    }
+    Ok(current)
+}
+
+// Version 2: pending external messages are persisted keyed by message hash, value is
+// expiration time (4 bytes LE) followed by message BOC. Entries of other format can't
+// be restored, so they are removed.
+struct ExtMessagesPersistence;
+
+impl ExtMessagesPersistence {
+    fn is_valid(key: &[u8], data: &[u8]) -> bool {
+        if key.len() != 32 || data.len() <= 4 {
+            return false
+        }
+        match deserialize_tree_of_cells(&mut Cursor::new(&data[4..])) {
+            Ok(root) => root.repr_hash().as_slice() == key,
+            Err(_) => false
+        }
+    }
+}
+
+impl Migration for ExtMessagesPersistence {
+    fn version(&self) -> u32 {
+        2
+    }
+
+    fn description(&self) -> &'static str {
+        "persistent external messages"
+    }
+
+    fn apply(&self, db: &mut InternalDbImpl) -> Result<()> {
+        let mut invalid = Vec::new();
+        db.ext_messages_db.for_each(&mut |key, data| {
+            if !Self::is_valid(key, data) {
+                invalid.push(key.to_vec());
+            }
+            Ok(true)
+        })?;
+        for key in invalid.iter() {
+            let id: [u8; 32] = match key.as_slice().try_into() {
+                Ok(id) => id,
+                Err(_) => {
+                    // Can't be deleted with typed key
+                    log::warn!("Skipping external message with invalid key {}", hex::encode(key));
+                    continue
+                }
+            };
+            db.ext_messages_db.delete(&UInt256::from(id))?;
+        }
+        log::info!("Removed {} invalid persisted external messages", invalid.len());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn fixture_dir(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("db_migration_{}_{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    path.to_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+fn open_db(db_directory: &str) -> Result<InternalDbImpl> {
+    let config = crate::internal_db::InternalDbConfig {
+        db_directory: db_directory.to_string(),
+        cells_gc_interval_ms: 0,
+        cells_cache_size_bytes: 0,
+        block_handles_cache_size: 0,
+        write_behind: None,
+        paths: Default::default(),
+        migrate_layout: false,
+        index_transactions: false,
+        read_only: false,
+    };
+    // DB is closed with runtime, background tasks hold its parts
+    tokio::runtime::Runtime::new().unwrap().block_on(InternalDbImpl::new(config))
+}
+
+// Unversioned DB of a node which applied blocks and persisted some external messages
+#[cfg(test)]
+fn make_fixture(db_directory: &str, ext_messages: &[(Vec<u8>, Vec<u8>)]) {
+    use storage::{ext_messages_db::ExtMessagesDb, node_state_db::NodeStateDb, traits::Serializable};
+    use ton_block::{BlockIdExt, ShardIdent};
+
+    std::fs::create_dir_all(db_directory).unwrap();
+    let node_state_db = NodeStateDb::with_path(format!("{}/node_state_db", db_directory));
+    let block_id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), 100, UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[2; 32])
+    );
+    let mut data = Vec::new();
+    block_id.serialize(&mut data).unwrap();
+    node_state_db.put(&LAST_APPLIED_MC_BLOCK, &data).unwrap();
+    let ext_messages_db = ExtMessagesDb::with_path(format!("{}/ext_messages_db", db_directory));
+    for (key, data) in ext_messages {
+        ext_messages_db.put(&UInt256::from_slice(key), data).unwrap();
+    }
+}
+
+#[cfg(test)]
+fn ext_message_entry(body: u8) -> (Vec<u8>, Vec<u8>) {
+    use ton_types::{BuilderData, cells_serialization::serialize_toc};
+    let mut builder = BuilderData::new();
+    builder.append_u8(body).unwrap();
+    let cell = builder.into_cell().unwrap();
+    let mut data = 1000u32.to_le_bytes().to_vec();
+    data.extend_from_slice(&serialize_toc(&cell).unwrap());
+    (cell.repr_hash().as_slice().to_vec(), data)
+}
+
+#[test]
+fn test_migrate_unversioned_db() {
+    let db_directory = fixture_dir("unversioned");
+    let valid = ext_message_entry(1);
+    let mut wrong_key = ext_message_entry(2);
+    wrong_key.0 = vec!(7; 32);
+    let mut no_header = ext_message_entry(3);
+    no_header.1 = no_header.1[4..].to_vec();
+    make_fixture(&db_directory, &[valid.clone(), wrong_key, no_header, (vec!(8; 32), vec!(1, 2))]);
+
+    let mut db = open_db(&db_directory).unwrap();
+    assert_eq!(load_version(&db).unwrap(), Some(current_version()));
+    let mut keys = Vec::new();
+    db.ext_messages_db.for_each(&mut |key, _| {
+        keys.push(key.to_vec());
+        Ok(true)
+    }).unwrap();
+    assert_eq!(keys, vec!(valid.0));
+
+    // Migration is idempotent
+    ExtMessagesPersistence.apply(&mut db).unwrap();
+    assert_eq!(migrate(&mut db).unwrap(), current_version());
+    drop(db);
+    std::fs::remove_dir_all(&db_directory).ok();
+}
+
+#[test]
+fn test_new_and_too_new_db() {
+    let db_directory = fixture_dir("new");
+    let db = open_db(&db_directory).unwrap();
+    assert_eq!(load_version(&db).unwrap(), Some(current_version()));
+    store_version(&db, current_version() + 1).unwrap();
+    drop(db);
+
+    let err = open_db(&db_directory).err().expect("too new DB must not be opened");
+    assert!(err.to_string().contains("is newer than"));
+    std::fs::remove_dir_all(&db_directory).ok();
+}
+
+#[test]
+fn test_read_only_db_is_not_migrated() {
+    let db_directory = fixture_dir("read_only");
+    let mut db = open_db(&db_directory).unwrap();
+    store_version(&db, UNVERSIONED_SCHEMA_VERSION).unwrap();
+
+    db.config.read_only = true;
+    let err = migrate(&mut db).unwrap_err();
+    assert!(err.to_string().contains("must be migrated by node running with the DB"), "{}", err);
+    assert_eq!(load_version(&db).unwrap(), Some(UNVERSIONED_SCHEMA_VERSION));
+
+    db.config.read_only = false;
+    assert_eq!(migrate(&mut db).unwrap(), current_version());
+    assert_eq!(load_version(&db).unwrap(), Some(current_version()));
+    drop(db);
+    std::fs::remove_dir_all(&db_directory).ok();
+}
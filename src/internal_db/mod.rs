@@ -1,20 +1,30 @@
 use crate::{
     block::{convert_block_id_ext_blk2api, convert_block_id_ext_api2blk, BlockStuff},
-    block_proof::BlockProofStuff, error::NodeError, shard_state::ShardStateStuff,
+    block_proof::BlockProofStuff, config::DbPathsConfig,
+    error::{db_error, DbError, NodeError},
+    internal_db::{layout::{DbDataClass, DbLayout}, sync_cursor::{SyncCursor, SYNC_CURSOR}},
+    shard_state::ShardStateStuff,
     types::top_block_descr::{TopBlockDescrId, TopBlockDescrStuff},
 };
 use std::{
-    path::PathBuf, sync::Arc, cmp::min, collections::HashMap,
-    sync::atomic::{AtomicU32, Ordering}
+    path::{Path, PathBuf}, sync::{Arc, Mutex}, cmp::min, collections::HashMap, convert::TryInto,
+    sync::atomic::{AtomicU32, Ordering}, time::{Duration, SystemTime, UNIX_EPOCH}
 };
+use rand::Rng;
 use storage::{
     TimeChecker,
+    cell_cache::{CellCache, CellCacheStats},
     archives::{archive_manager::ArchiveManager, package_entry_id::PackageEntryId},
-    block_handle_db::{BlockHandleDb, BlockHandleStorage, Callback}, 
+    block_handle_db::{
+        BlockHandleCacheStats, BlockHandleDb, BlockHandleStorage, Callback, WriteBehindConfig
+    },
     block_index_db::BlockIndexDb, block_info_db::BlockInfoDb, node_state_db::NodeStateDb, 
+    db::rocksdb::enable_secondary_mode,
     shardstate_db::{AllowStateGcResolver, ShardStateDb}, 
-    shardstate_persistent_db::ShardStatePersistentDb, 
+    shardstate_persistent_db::ShardStatePersistentDb, state_serializer::SerializationProgress,
     types::{BlockHandle, BlockMeta}, shard_top_blocks_db::ShardTopBlocksDb,
+    ext_messages_db::ExtMessagesDb,
+    transactions_index_db::{IndexedTransaction, TransactionsIndexDb}, traits::Serializable,
 };
 #[cfg(feature = "read_old_db")]
 use storage::block_db::BlockDb;
@@ -27,6 +37,17 @@ pub(crate) const INITIAL_MC_BLOCK: &str      = "InitMcBlockId";
 pub(crate) const LAST_APPLIED_MC_BLOCK: &str = "LastMcBlockId";
 pub(crate) const PSS_KEEPER_MC_BLOCK: &str   = "PssKeeperBlockId";
 pub(crate) const SHARD_CLIENT_MC_BLOCK: &str = "ShardsClientMcBlockId";
+// Zero state of the network DB is created for
+pub(crate) const ZERO_STATE_ID: &str         = "ZeroStateId";
+const HEALTH_PROBE: &str                     = "HealthProbe";
+// Last numbers of blocks written into external db feeds
+const EXT_DB_FEEDS: &str                     = "ExtDbShardFeeds";
+
+/// Databases which can be compacted, named by their directories
+pub const COMPACTABLE_DBS: [&str; 10] = [
+    "block_handle_db", "node_state_db", "index_db", "prev1_block_db", "prev2_block_db",
+    "next1_block_db", "next2_block_db", "shardstate_db", "shard_top_blocks_db", "ext_messages_db"
+];
 
 #[derive(Clone, Debug)]
 pub enum DataStatus {
@@ -98,7 +119,14 @@ impl BlockResult {
 
 }
 
+pub mod backup;
+pub mod compaction;
+pub mod integrity;
+pub mod layout;
+pub mod migration;
+pub mod scrubber;
 pub mod state_gc_resolver;
+pub mod sync_cursor;
 
 #[async_trait::async_trait]
 pub trait InternalDb : Sync + Send {
@@ -142,10 +170,14 @@ pub trait InternalDb : Sync + Send {
     fn load_shard_state_dynamic(&self, id: &BlockIdExt) -> Result<ShardStateStuff>;
    // fn gc_shard_state_dynamic_db(&self) -> Result<usize>;
 
+    // State is written with given speed (bytes per second, 0 - unlimited),
+    // `progress` is called with count of written bytes and total size
     async fn store_shard_state_persistent(
         &self, 
         handle: &Arc<BlockHandle>, 
         state: &ShardStateStuff,
+        bytes_per_sec: u64,
+        progress: &(dyn Fn(SerializationProgress) + Send + Sync),
         callback: Option<Arc<dyn Callback>>
     ) -> Result<()>;
     async fn store_shard_state_persistent_raw(
@@ -156,6 +188,8 @@ pub trait InternalDb : Sync + Send {
     ) -> Result<()>;
     async fn load_shard_state_persistent_slice(&self, id: &BlockIdExt, offset: u64, length: u64) -> Result<Vec<u8>>;
     async fn load_shard_state_persistent_size(&self, id: &BlockIdExt) -> Result<u64>;
+    // Removes persistent state, so it is not offered to peers anymore
+    async fn delete_shard_state_persistent(&self, handle: &Arc<BlockHandle>) -> Result<bool>;
 
     fn store_block_prev1(
         &self, 
@@ -225,30 +259,108 @@ pub trait InternalDb : Sync + Send {
     fn load_all_top_shard_blocks_raw(&self) -> Result<HashMap<TopBlockDescrId, Vec<u8>>>;
     fn remove_top_shard_block(&self, id: &TopBlockDescrId) -> Result<()>;
 
+    fn save_ext_message(&self, id: &UInt256, data: &[u8]) -> Result<()>;
+    fn load_all_ext_messages(&self) -> Result<Vec<(UInt256, Vec<u8>)>>;
+    fn remove_ext_message(&self, id: &UInt256) -> Result<()>;
+
     fn db_root_dir(&self) -> Result<&str>;
 
     fn adjust_states_gc_interval(&self, interval_ms: u32);
+
+    // Full compaction of one of COMPACTABLE_DBS, blocks until it is finished
+    fn compact_db(&self, name: &str) -> Result<()>;
+
+    // Resets data and proof flags of blocks whose archive packages are removed
+    fn prune_block_handles(&self, mc_seq_no: u32, mc_gen_utime: u32) -> Result<u64>;
+
+    // Resets applied and state flags, so the block is applied again
+    fn reset_block_applied(&self, id: &BlockIdExt) -> Result<bool>;
+
+    // Changes memory budget of cells cache, zero disables it
+    fn adjust_cells_cache_size(&self, size_bytes: u64);
+    fn cells_cache_stats(&self) -> CellCacheStats;
+    fn block_handles_cache_stats(&self) -> BlockHandleCacheStats;
+
+    // Account transactions index, all methods fail if it is disabled.
+    // Transactions of block are added with one batch, before block is marked as applied.
+    fn transactions_index_enabled(&self) -> bool;
+    fn index_block_transactions(&self, block: &BlockStuff) -> Result<usize>;
+    fn load_account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: usize
+    ) -> Result<Vec<IndexedTransaction>>;
+    // Removes transactions of blocks generated before given time
+    fn prune_transactions_index(&self, gen_utime: u32) -> Result<u64>;
+
+    // Makes consistent copy of all data classes in given new directory without stopping
+    // the node. Returns last applied masterchain block of the copy.
+    async fn create_backup(&self, path: &str) -> Result<Option<BlockIdExt>>;
+
+    // Read-only mode: catches up with node writing to DB, fails in normal mode
+    fn is_read_only(&self) -> bool;
+    async fn catch_up_with_primary(&self) -> Result<()>;
+    // Writes probe record, fails if DB doesn't accept writes
+    fn check_writable(&self) -> Result<()>;
+    // Commits batched block handles and flushes memtables, called on shutdown
+    async fn flush(&self) -> Result<()>;
+
+    // Ids of applied blocks in order of block index following given index key (from the
+    // beginning if it is empty), each one with its key to continue the walk
+    fn load_indexed_block_ids(&self, after: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, BlockIdExt)>>;
+    // Overwrites stored proof of the block, fails if it is already in archive package
+    async fn replace_block_proof(&self, handle: &Arc<BlockHandle>, proof: &BlockProofStuff) -> Result<()>;
+
+    // Position of interrupted archive sync, removed when sync is complete
+    fn load_sync_cursor(&self) -> Result<Option<SyncCursor>>;
+    fn save_sync_cursor(&self, cursor: &SyncCursor) -> Result<()>;
+    fn clear_sync_cursor(&self) -> Result<()>;
+
+    // Last number of block written into external db feed (sink and shard), zero if none
+    fn load_ext_db_feed_seq_no(&self, feed: &str) -> Result<u64>;
+    fn save_ext_db_feed_seq_no(&self, feed: &str, seq_no: u64) -> Result<()>;
 }
 
 #[derive(serde::Deserialize)]
 pub struct InternalDbConfig {
     pub db_directory: String,
     pub cells_gc_interval_ms: u32,
+    pub cells_cache_size_bytes: u64,
+    #[serde(default)]
+    pub block_handles_cache_size: usize,
+    #[serde(skip)]
+    pub write_behind: Option<WriteBehindConfig>,
+    #[serde(default)]
+    pub paths: DbPathsConfig,
+    // Move data to directories set by `paths` instead of refusing to start
+    #[serde(skip)]
+    pub migrate_layout: bool,
+    #[serde(default)]
+    pub index_transactions: bool,
+    // Follow data of node running with this DB, nothing is written
+    #[serde(skip)]
+    pub read_only: bool,
 }
 
 pub struct InternalDbImpl {
     block_handle_storage: Arc<BlockHandleStorage>,
+    block_handle_db: Arc<BlockHandleDb>,
+    node_state_db: Arc<NodeStateDb>,
     block_index_db: Arc<BlockIndexDb>,
     prev_block_db: BlockInfoDb,
     prev2_block_db: BlockInfoDb,
     next_block_db: BlockInfoDb,
     next2_block_db: BlockInfoDb,
-    shard_state_persistent_db: ShardStatePersistentDb,
+    shard_state_persistent_db: Arc<ShardStatePersistentDb>,
     shard_state_dynamic_db: Arc<ShardStateDb>,
     //ss_test_map: lockfree::map::Map<BlockIdExt, ShardStateStuff>,
     //shardstate_db_gc: GC,
     archive_manager: Arc<ArchiveManager>,
     shard_top_blocks_db: ShardTopBlocksDb,
+    ext_messages_db: ExtMessagesDb,
+    transactions_index_db: Option<TransactionsIndexDb>,
 
     #[cfg(feature = "read_old_db")]
     old_block_db: BlockDb,
@@ -259,66 +371,116 @@ pub struct InternalDbImpl {
 
     config: InternalDbConfig,
     cells_gc_interval: Arc<AtomicU32>,
+    cell_cache: Arc<CellCache>,
+    ext_db_feeds: Mutex<()>, // all feeds are stored under one key
 }
 
 impl InternalDbImpl {
 
     pub async fn new(config: InternalDbConfig) -> Result<Self> {
+        let layout = DbLayout::new(&config.db_directory, &config.paths);
+        if config.read_only {
+            layout.check(&config.db_directory)?;
+            // RocksDB instances are opened as secondary ones, they need own directory for logs
+            let secondary_root = std::env::temp_dir()
+                .join(format!("ton_node_read_only_{}", std::process::id()));
+            std::fs::remove_dir_all(&secondary_root).ok();
+            std::fs::create_dir_all(&secondary_root)?;
+            enable_secondary_mode(&secondary_root);
+        } else {
+            layout.check_and_migrate(&config.db_directory, config.migrate_layout)?;
+        }
         let block_index_db = Arc::new(BlockIndexDb::with_paths(
-            &Self::build_name(&config.db_directory, "index_db/lt_desc_db"),
-            &Self::build_name(&config.db_directory, "index_db/lt_db"),
+            &layout.path(DbDataClass::Blocks, "index_db/lt_desc_db"),
+            &layout.path(DbDataClass::Blocks, "index_db/lt_db"),
         ));
         let block_handle_db = Arc::new(
             BlockHandleDb::with_path(
-                &Self::build_name(&config.db_directory, "block_handle_db"),
+                &layout.path(DbDataClass::Blocks, "block_handle_db"),
             )
         );
         let node_state_db = Arc::new(
             NodeStateDb::with_path(
-                &Self::build_name(&config.db_directory, "node_state_db")
+                &layout.path(DbDataClass::Blocks, "node_state_db")
             )
         );
         let block_handle_storage = Arc::new(
-            BlockHandleStorage::with_dbs(block_handle_db.clone(), node_state_db)
+            BlockHandleStorage::with_dbs(
+                block_handle_db.clone(), node_state_db.clone(), config.write_behind.clone(),
+                config.block_handles_cache_size
+            )
         );
+        let cell_cache = Arc::new(CellCache::new(config.cells_cache_size_bytes));
         let shard_state_dynamic_db = ShardStateDb::with_paths(
-            &Self::build_name(&config.db_directory, "shardstate_db"),
-            &Self::build_name(&config.db_directory, "cells_db"),
-            &Self::build_name(&config.db_directory, "cells_db1"),
+            &layout.path(DbDataClass::Cells, "shardstate_db"),
+            &layout.path(DbDataClass::Cells, "cells_db"),
+            &layout.path(DbDataClass::Cells, "cells_db1"),
+            cell_cache.clone(),
         )?;
         //let shardstate_db_gc = GC::new(&shard_state_dynamic_db, Arc::clone(&block_handle_db))?;
-        let archive_manager = Arc::new(ArchiveManager::with_data(Arc::new(PathBuf::from(&config.db_directory))).await?);
-        let db = Self {
+        let archive_manager = Arc::new(ArchiveManager::with_data(
+            Arc::new(PathBuf::from(layout.dir(DbDataClass::Archives)))
+        ).await?);
+        let mut db = Self {
             block_handle_storage,
+            block_handle_db,
+            node_state_db,
             block_index_db,
-            prev_block_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "prev1_block_db")),
-            prev2_block_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "prev2_block_db")),
-            next_block_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "next1_block_db")),
-            next2_block_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "next2_block_db")),
-            shard_state_persistent_db: ShardStatePersistentDb::with_path(
-                &Self::build_name(&config.db_directory, "shard_state_persistent_db")),
+            prev_block_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "prev1_block_db")),
+            prev2_block_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "prev2_block_db")),
+            next_block_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "next1_block_db")),
+            next2_block_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "next2_block_db")),
+            shard_state_persistent_db: Arc::new(ShardStatePersistentDb::with_path(
+                &layout.path(DbDataClass::PersistentStates, "shard_state_persistent_db"))),
             shard_state_dynamic_db,
             //ss_test_map: lockfree::map::Map::new(),
             //shardstate_db_gc,
             archive_manager,
-            shard_top_blocks_db: ShardTopBlocksDb::with_path(&Self::build_name(&config.db_directory, "shard_top_blocks_db")),
+            shard_top_blocks_db: ShardTopBlocksDb::with_path(&layout.path(DbDataClass::Blocks, "shard_top_blocks_db")),
+            ext_messages_db: ExtMessagesDb::with_path(&layout.path(DbDataClass::Blocks, "ext_messages_db")),
+            transactions_index_db: if config.index_transactions {
+                Some(TransactionsIndexDb::with_path(&layout.path(DbDataClass::Blocks, "transactions_index_db")))
+            } else {
+                None
+            },
 
             #[cfg(feature = "read_old_db")]
-            old_block_db: BlockDb::with_path(&Self::build_name(&config.db_directory, "block_db")),
+            old_block_db: BlockDb::with_path(&layout.path(DbDataClass::Blocks, "block_db")),
             #[cfg(feature = "read_old_db")]
-            old_block_proof_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "block_proof_db")),
+            old_block_proof_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "block_proof_db")),
             #[cfg(feature = "read_old_db")]
-            old_block_proof_link_db: BlockInfoDb::with_path(&Self::build_name(&config.db_directory, "block_proof_link_db")),
+            old_block_proof_link_db: BlockInfoDb::with_path(&layout.path(DbDataClass::Blocks, "block_proof_link_db")),
 
             cells_gc_interval: Arc::new(AtomicU32::new(config.cells_gc_interval_ms)),
+            cell_cache,
             config,
+            ext_db_feeds: Mutex::new(()),
         };
+        let version = migration::migrate(&mut db)?;
+        log::info!("Internal DB schema version {}", version);
 
         Ok(db)
     }
 
-    pub fn start_states_gc(&self, resolver: Arc<dyn AllowStateGcResolver>) {
-        self.shard_state_dynamic_db.clone().start_gc(resolver, self.cells_gc_interval.clone())
+    fn load_ext_db_feeds(&self) -> Result<HashMap<String, u64>> {
+        match self.node_state_db.try_get(&EXT_DB_FEEDS)? {
+            Some(slice) => Ok(serde_json::from_slice(slice.as_ref())?),
+            None => Ok(HashMap::new())
+        }
+    }
+
+    pub fn start_states_gc(
+        &self,
+        resolver: Arc<dyn AllowStateGcResolver>
+    ) -> tokio::task::JoinHandle<()> {
+        // When test-blockchains deploys all validator-nodes start approx at one time,
+        // so all GC start at one time all nodes. It can reduce whole blockchain's performans,
+        // so let's mix start time next way
+        let run_gc_interval = self.cells_gc_interval.load(Ordering::Relaxed) as u64;
+        let initial_delay = crate::rng::stream("states gc").gen_range(0, run_gc_interval);
+        self.shard_state_dynamic_db.clone().start_gc(
+            resolver, self.cells_gc_interval.clone(), Duration::from_millis(initial_delay)
+        )
     }
 
     pub fn build_name(dir: &str, name: &str) -> String {
@@ -333,6 +495,12 @@ impl InternalDbImpl {
         self.block_handle_storage.store_handle(handle, callback)
     }
 
+    fn transactions_index(&self) -> Result<&TransactionsIndexDb> {
+        self.transactions_index_db.as_ref().ok_or_else(
+            || error!("Transactions index is disabled, set index_transactions in config")
+        )
+    }
+
 }
 
 
@@ -372,7 +540,7 @@ impl InternalDb for InternalDbImpl {
 
     fn load_block_handle(&self, id: &BlockIdExt) -> Result<Option<Arc<BlockHandle>>> {
         let _tc = TimeChecker::new(format!("load_block_handle {}", id), 10);
-        self.block_handle_storage.load_handle(id)
+        self.block_handle_storage.load_handle(id).map_err(|e| db_error(e, "block_handle_db"))
     }
 
     async fn store_block_data(
@@ -404,45 +572,53 @@ impl InternalDb for InternalDbImpl {
     async fn load_block_data(&self, handle: &BlockHandle) -> Result<BlockStuff> {
         let _tc = TimeChecker::new(format!("load_block_data {}", handle.id()), 100);
         let raw_block = self.load_block_data_raw(handle).await?;
-        BlockStuff::deserialize(handle.id().clone(), raw_block)
+        BlockStuff::deserialize(handle.id().clone(), raw_block).map_err(
+            |e| NodeError::Db(DbError::Corrupted, format!("block {}: {}", handle.id(), e)).into()
+        )
     }
 
     async fn load_block_data_raw(&self, handle: &BlockHandle) -> Result<Vec<u8>> {
         let _tc = TimeChecker::new(format!("load_block_data_raw {}", handle.id()), 100);
         if !handle.has_data() {
-            fail!("This block is not stored yet: {:?}", handle);
+            fail!(NodeError::Db(
+                DbError::NotFound, format!("This block is not stored yet: {:?}", handle)
+            ))
         }
         #[cfg(feature = "read_old_db")] {
-            let raw_block = self.old_block_db.get(&handle.id().into())?;
+            let raw_block = self.old_block_db.get(&handle.id().into())
+                .map_err(|e| db_error(e, "block_db"))?;
             return Ok(raw_block.to_vec());
         }
         #[cfg(not(feature = "read_old_db"))] {
             let entry_id = PackageEntryId::<_, UInt256, PublicKey>::Block(handle.id());
-            self.archive_manager.get_file(handle, &entry_id).await
+            self.archive_manager.get_file(handle, &entry_id).await.map_err(|e| db_error(e, "archives"))
         }
     }
 
     fn find_block_by_seq_no(&self, acc_pfx: &AccountIdPrefixFull, seq_no: u32) -> Result<Arc<BlockHandle>> {
         let _tc = TimeChecker::new(format!("find_block_by_seq_no {} {}", acc_pfx, seq_no), 100);
-        let id = self.block_index_db.get_block_by_seq_no(acc_pfx, seq_no)?;
+        let id = self.block_index_db.get_block_by_seq_no(acc_pfx, seq_no)
+            .map_err(|e| db_error(e, "index_db"))?;
         self.load_block_handle(&id)?.ok_or_else(
-            || error!("Cannot find handle for block {}", id) 
+            || NodeError::Db(DbError::NotFound, format!("Cannot find handle for block {}", id)).into()
         )
     }
 
     fn find_block_by_unix_time(&self, acc_pfx: &AccountIdPrefixFull, utime: u32) -> Result<Arc<BlockHandle>> {
         let _tc = TimeChecker::new(format!("find_block_by_unix_time {} {}", acc_pfx, utime), 100);
-        let id = self.block_index_db.get_block_by_ut(acc_pfx, UnixTime32(utime))?;
+        let id = self.block_index_db.get_block_by_ut(acc_pfx, UnixTime32(utime))
+            .map_err(|e| db_error(e, "index_db"))?;
         self.load_block_handle(&id)?.ok_or_else(
-            || error!("Cannot find handle for block {}", id) 
+            || NodeError::Db(DbError::NotFound, format!("Cannot find handle for block {}", id)).into()
         )
     }
 
     fn find_block_by_lt(&self, acc_pfx: &AccountIdPrefixFull, lt: u64) -> Result<Arc<BlockHandle>> {
         let _tc = TimeChecker::new(format!("find_block_by_lt {} {}", acc_pfx, lt), 100);
-        let id = self.block_index_db.get_block_by_lt(acc_pfx, lt)?;
+        let id = self.block_index_db.get_block_by_lt(acc_pfx, lt)
+            .map_err(|e| db_error(e, "index_db"))?;
         self.load_block_handle(&id)?.ok_or_else(
-            || error!("Cannot find handle for block {}", id) 
+            || NodeError::Db(DbError::NotFound, format!("Cannot find handle for block {}", id)).into()
         )
     }
 
@@ -528,16 +704,19 @@ impl InternalDb for InternalDbImpl {
     async fn load_block_proof(&self, handle: &BlockHandle, is_link: bool) -> Result<BlockProofStuff> {
         let _tc = TimeChecker::new(format!("load_block_proof {} {}", if is_link {"link"} else {""}, handle.id()), 100);
         let raw_proof = self.load_block_proof_raw(handle, is_link).await?;
-        BlockProofStuff::deserialize(handle.id(), raw_proof, is_link)
+        BlockProofStuff::deserialize(handle.id(), raw_proof, is_link).map_err(|e| NodeError::Db(
+            DbError::Corrupted, format!("proof of block {}: {}", handle.id(), e)
+        ).into())
     }
 
     #[cfg(feature = "read_old_db")]
     async fn load_block_proof_raw(&self, handle: &BlockHandle, is_link: bool) -> Result<Vec<u8>> {
         log::trace!("load_block_proof_raw {} {}", if is_link {"link"} else {""}, handle.id());
         let raw_proof = if is_link {
-            self.old_block_proof_link_db.get(&handle.id().into())?
+            self.old_block_proof_link_db.get(&handle.id().into())
+                .map_err(|e| db_error(e, "block_proof_link_db"))?
         } else {
-            self.old_block_proof_db.get(&handle.id().into())?
+            self.old_block_proof_db.get(&handle.id().into()).map_err(|e| db_error(e, "block_proof_db"))?
         };
         Ok(raw_proof.to_vec())
     }
@@ -551,9 +730,12 @@ impl InternalDb for InternalDbImpl {
             (PackageEntryId::<_, UInt256, PublicKey>::Proof(handle.id()), handle.has_proof())
         };
         if !inited {
-            fail!("This proof{} is not in the archive: {:?}", if is_link { "link" } else { "" }, handle);
+            let proof = if is_link { "proof link" } else { "proof" };
+            fail!(NodeError::Db(
+                DbError::NotFound, format!("This {} is not in the archive: {:?}", proof, handle)
+            ))
         }
-        self.archive_manager.get_file(handle, &entry_id).await
+        self.archive_manager.get_file(handle, &entry_id).await.map_err(|e| db_error(e, "archives"))
     }
 
     fn store_shard_state_dynamic(
@@ -579,7 +761,11 @@ impl InternalDb for InternalDbImpl {
 
     fn load_shard_state_dynamic(&self, id: &BlockIdExt) -> Result<ShardStateStuff> {
         let _tc = TimeChecker::new(format!("load_shard_state_dynamic {}", id), 10);        
-        Ok(ShardStateStuff::new(id.clone(), self.shard_state_dynamic_db.get(id)?)?)
+        let root = self.shard_state_dynamic_db.get(id)
+            .map_err(|e| db_error(e, self.shard_state_dynamic_db.storage_name(id)))?;
+        ShardStateStuff::new(id.clone(), root).map_err(
+            |e| NodeError::Db(DbError::Corrupted, format!("state {}: {}", id, e)).into()
+        )
     }
 
     /*
@@ -591,6 +777,8 @@ impl InternalDb for InternalDbImpl {
         &self, 
         handle: &Arc<BlockHandle>, 
         state: &ShardStateStuff,
+        bytes_per_sec: u64,
+        progress: &(dyn Fn(SerializationProgress) + Send + Sync),
         callback: Option<Arc<dyn Callback>>
     ) -> Result<()> {
         let _tc = TimeChecker::new(format!("store_shard_state_persistent {}", state.block_id()), 10_000);
@@ -598,7 +786,24 @@ impl InternalDb for InternalDbImpl {
             fail!(NodeError::InvalidArg("`state` and `handle` mismatch".to_string()))
         }
         if !handle.has_persistent_state() {
-            self.shard_state_persistent_db.put(state.block_id(), &state.serialize()?).await?;
+            // State is serialized right from the cells storage, so the whole BOC
+            // is never kept in memory
+            let (root, source) = self.shard_state_dynamic_db.cells_source(state.block_id())?;
+            if root != state.root_cell().repr_hash() {
+                fail!("State {} in cells storage differs from given one", state.block_id())
+            }
+            let estimate = state.root_cell().tree_cell_count();
+            // Serialization is blocking, its progress is passed back through the channel
+            let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+            let persistent_db = self.shard_state_persistent_db.clone();
+            let id = state.block_id().clone();
+            let serialization = tokio::task::spawn_blocking(move || persistent_db.put_serialized(
+                &id, &source, &root, estimate, bytes_per_sec, &|stage| { sender.send(stage).ok(); }
+            ));
+            while let Some(stage) = receiver.recv().await {
+                progress(stage)
+            }
+            serialization.await??;
             if handle.set_persistent_state() {
                 self.store_block_handle(handle, callback)?;
             }
@@ -635,7 +840,8 @@ impl InternalDb for InternalDbImpl {
             Ok(vec![])
         } else {
             let length = min(length, full_lenth - offset);
-            let db_slice = self.shard_state_persistent_db.get_slice(id, offset, length).await?;
+            let db_slice = self.shard_state_persistent_db.get_slice(id, offset, length).await
+                .map_err(|e| db_error(e, "shardstate_persistent_db"))?;
             Ok(db_slice.to_vec())
         }
     }
@@ -643,6 +849,17 @@ impl InternalDb for InternalDbImpl {
     async fn load_shard_state_persistent_size(&self, id: &BlockIdExt) -> Result<u64> {
         let _tc = TimeChecker::new(format!("load_shard_state_persistent_size {}", id), 50);
         self.shard_state_persistent_db.get_size(id).await
+            .map_err(|e| db_error(e, "shardstate_persistent_db"))
+    }
+
+    async fn delete_shard_state_persistent(&self, handle: &Arc<BlockHandle>) -> Result<bool> {
+        let _tc = TimeChecker::new(format!("delete_shard_state_persistent {}", handle.id()), 1000);
+        if !handle.reset_persistent_state() {
+            return Ok(false)
+        }
+        self.store_block_handle(handle, None)?;
+        self.shard_state_persistent_db.delete(handle.id()).await?;
+        Ok(true)
     }
 
     fn store_block_prev1(
@@ -664,8 +881,9 @@ impl InternalDb for InternalDbImpl {
 
     fn load_block_prev1(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
         let _tc = TimeChecker::new(format!("load_block_prev {}", id), 10);
-        let bytes = self.prev_block_db.get(id)?;
-        let prev = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)?;
+        let bytes = self.prev_block_db.get(id).map_err(|e| db_error(e, "prev1_block_db"))?;
+        let prev = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)
+            .map_err(|e| NodeError::Db(DbError::Corrupted, format!("prev of {}: {}", id, e)))?;
         convert_block_id_ext_api2blk(&prev)
     }
 
@@ -688,8 +906,9 @@ impl InternalDb for InternalDbImpl {
 
     fn load_block_prev2(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
         let _tc = TimeChecker::new(format!("load_block_prev2 {}", id), 10);
-        let bytes = self.prev2_block_db.get(id)?;
-        let prev2 = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)?;
+        let bytes = self.prev2_block_db.get(id).map_err(|e| db_error(e, "prev2_block_db"))?;
+        let prev2 = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)
+            .map_err(|e| NodeError::Db(DbError::Corrupted, format!("prev2 of {}: {}", id, e)))?;
         convert_block_id_ext_api2blk(&prev2)
     }
 
@@ -712,8 +931,9 @@ impl InternalDb for InternalDbImpl {
 
     fn load_block_next1(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
         let _tc = TimeChecker::new(format!("load_block_next1 {}", id), 10);
-        let bytes = self.next_block_db.get(id)?;
-        let next = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)?;
+        let bytes = self.next_block_db.get(id).map_err(|e| db_error(e, "next1_block_db"))?;
+        let next = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)
+            .map_err(|e| NodeError::Db(DbError::Corrupted, format!("next1 of {}: {}", id, e)))?;
         convert_block_id_ext_api2blk(&next)
     }
 
@@ -736,8 +956,9 @@ impl InternalDb for InternalDbImpl {
 
     fn load_block_next2(&self, id: &BlockIdExt) -> Result<BlockIdExt> {
         let _tc = TimeChecker::new(format!("load_block_next2 {}", id), 10);
-        let bytes = self.next2_block_db.get(id)?;
-        let next2 = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)?;
+        let bytes = self.next2_block_db.get(id).map_err(|e| db_error(e, "next2_block_db"))?;
+        let next2 = bincode::deserialize::<ton_api::ton::ton_node::blockidext::BlockIdExt>(&bytes)
+            .map_err(|e| NodeError::Db(DbError::Corrupted, format!("next2 of {}: {}", id, e)))?;
         convert_block_id_ext_api2blk(&next2)
     }
 
@@ -772,7 +993,7 @@ impl InternalDb for InternalDbImpl {
     ) -> Result<()> {
         let _tc = TimeChecker::new(format!("archive_block {}", id), 200);
         let handle = self.load_block_handle(id)?.ok_or_else(
-            || error!("Cannot load handle for archiving block {}", id)
+            || NodeError::Db(DbError::NotFound, format!("Cannot load handle for archiving block {}", id))
         )?;
         if handle.is_archived() {
             return Ok(());
@@ -798,7 +1019,7 @@ impl InternalDb for InternalDbImpl {
 
     fn load_node_state(&self, key: &'static str) -> Result<Option<Arc<BlockIdExt>>> {
         let _tc = TimeChecker::new(format!("load_node_state {}", key), 10);
-        self.block_handle_storage.load_state(key)
+        self.block_handle_storage.load_state(key).map_err(|e| db_error(e, "node_state_db"))
     }
 
     fn save_node_state(&self, key: &'static str, block_id: &BlockIdExt) -> Result<()> {
@@ -844,12 +1065,26 @@ impl InternalDb for InternalDbImpl {
     fn load_all_top_shard_blocks(&self) -> Result<HashMap<TopBlockDescrId, TopBlockDescrStuff>> {
         let _tc = TimeChecker::new(format!("load_all_top_shard_blocks"), 100);
         let mut result = HashMap::<TopBlockDescrId, TopBlockDescrStuff>::new();
+        let mut broken = Vec::new();
         self.shard_top_blocks_db.for_each(&mut |id_bytes, tsb_bytes| {
-            let id = TopBlockDescrId::from_bytes(&id_bytes)?;
-            let tsb = TopBlockDescrStuff::from_bytes(tsb_bytes, false)?;
-            result.insert(id, tsb);
+            // One broken record must not prevent others from loading
+            let loaded = TopBlockDescrId::from_bytes(&id_bytes).and_then(
+                |id| Ok((id, TopBlockDescrStuff::from_bytes(tsb_bytes, false)?))
+            );
+            match loaded {
+                Ok((id, tsb)) => {
+                    result.insert(id, tsb);
+                }
+                Err(e) => {
+                    log::warn!("Broken top shard block record is removed: {}", e);
+                    broken.push(id_bytes.to_vec());
+                }
+            }
             Ok(true)
         })?;
+        for id_bytes in broken {
+            self.shard_top_blocks_db.delete(&id_bytes)?;
+        }
         Ok(result)
     }
 
@@ -869,6 +1104,28 @@ impl InternalDb for InternalDbImpl {
         self.shard_top_blocks_db.delete(&id.to_bytes()?)
     }
 
+    fn save_ext_message(&self, id: &UInt256, data: &[u8]) -> Result<()> {
+        let _tc = TimeChecker::new(format!("save_ext_message {:x}", id), 50);
+        self.ext_messages_db.put(id, data)
+    }
+
+    fn load_all_ext_messages(&self) -> Result<Vec<(UInt256, Vec<u8>)>> {
+        let _tc = TimeChecker::new(format!("load_all_ext_messages"), 100);
+        let mut result = Vec::new();
+        self.ext_messages_db.for_each(&mut |id_bytes, data| {
+            let id: [u8; 32] = id_bytes.try_into()
+                .map_err(|_| error!("Invalid external message id length {}", id_bytes.len()))?;
+            result.push((UInt256::from(id), data.to_vec()));
+            Ok(true)
+        })?;
+        Ok(result)
+    }
+
+    fn remove_ext_message(&self, id: &UInt256) -> Result<()> {
+        let _tc = TimeChecker::new(format!("remove_ext_message {:x}", id), 50);
+        self.ext_messages_db.delete(id)
+    }
+
     fn db_root_dir(&self) -> Result<&str> {
         Ok(&self.config.db_directory)
     }
@@ -877,5 +1134,209 @@ impl InternalDb for InternalDbImpl {
         let prev = self.cells_gc_interval.swap(interval_ms, Ordering::Relaxed);
         log::info!("Adjusted states gc interval {} -> {}", prev, interval_ms);
     }
+
+    fn compact_db(&self, name: &str) -> Result<()> {
+        match name {
+            "block_handle_db" => self.block_handle_db.compact(),
+            "node_state_db" => self.node_state_db.compact(),
+            "index_db" => self.block_index_db.compact(),
+            "prev1_block_db" => self.prev_block_db.compact(),
+            "prev2_block_db" => self.prev2_block_db.compact(),
+            "next1_block_db" => self.next_block_db.compact(),
+            "next2_block_db" => self.next2_block_db.compact(),
+            "shardstate_db" => self.shard_state_dynamic_db.compact(),
+            "shard_top_blocks_db" => self.shard_top_blocks_db.compact(),
+            "ext_messages_db" => self.ext_messages_db.compact(),
+            _ => fail!("Unknown database {}", name)
+        }
+    }
+
+    fn prune_block_handles(&self, mc_seq_no: u32, mc_gen_utime: u32) -> Result<u64> {
+        self.block_handle_storage.prune_handles(mc_seq_no, mc_gen_utime)
+    }
+
+    fn reset_block_applied(&self, id: &BlockIdExt) -> Result<bool> {
+        let handle = match self.load_block_handle(id)? {
+            Some(handle) => handle,
+            None => return Ok(false)
+        };
+        if !handle.reset_applied_and_state() {
+            return Ok(false)
+        }
+        self.store_block_handle(&handle, None)?;
+        Ok(true)
+    }
+
+    fn adjust_cells_cache_size(&self, size_bytes: u64) {
+        let prev = self.cell_cache.set_budget(size_bytes);
+        log::info!("Adjusted cells cache size {} -> {} bytes", prev, size_bytes);
+    }
+
+    fn cells_cache_stats(&self) -> CellCacheStats {
+        self.cell_cache.stats()
+    }
+
+    fn block_handles_cache_stats(&self) -> BlockHandleCacheStats {
+        self.block_handle_storage.cache_stats()
+    }
+
+    fn transactions_index_enabled(&self) -> bool {
+        self.transactions_index_db.is_some()
+    }
+
+    fn index_block_transactions(&self, block: &BlockStuff) -> Result<usize> {
+        let _tc = TimeChecker::new(format!("index_block_transactions {}", block.id()), 50);
+        let transactions = block.transactions_ids()?;
+        self.transactions_index()?.add_block_transactions(block.id(), block.gen_utime()?, &transactions)?;
+        Ok(transactions.len())
+    }
+
+    fn load_account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: usize
+    ) -> Result<Vec<IndexedTransaction>> {
+        self.transactions_index()?.account_transactions(workchain_id, account, from_lt, limit)
+    }
+
+    fn prune_transactions_index(&self, gen_utime: u32) -> Result<u64> {
+        self.transactions_index()?.prune(gen_utime)
+    }
+
+    async fn create_backup(&self, path: &str) -> Result<Option<BlockIdExt>> {
+        let root = Path::new(path);
+        if root.exists() {
+            fail!("Backup directory {} already exists", path)
+        }
+        std::fs::create_dir_all(root)?;
+        let now = std::time::Instant::now();
+
+        // Each database is copied after the ones referring to it: node state pointers,
+        // then handles, then other indexes, cells, persistent states and archives
+        let node_state_path = root.join("node_state_db");
+        self.node_state_db.checkpoint(&node_state_path)?;
+        self.block_handle_db.checkpoint(&root.join("block_handle_db"))?;
+        std::fs::create_dir_all(root.join("index_db"))?;
+        self.block_index_db.checkpoint(&root.join("index_db/lt_desc_db"), &root.join("index_db/lt_db"))?;
+        self.prev_block_db.checkpoint(&root.join("prev1_block_db"))?;
+        self.prev2_block_db.checkpoint(&root.join("prev2_block_db"))?;
+        self.next_block_db.checkpoint(&root.join("next1_block_db"))?;
+        self.next2_block_db.checkpoint(&root.join("next2_block_db"))?;
+        self.shard_top_blocks_db.checkpoint(&root.join("shard_top_blocks_db"))?;
+        self.ext_messages_db.checkpoint(&root.join("ext_messages_db"))?;
+        if let Some(transactions_index_db) = &self.transactions_index_db {
+            transactions_index_db.checkpoint(&root.join("transactions_index_db"))?;
+        }
+        #[cfg(feature = "read_old_db")]
+        self.old_block_db.checkpoint(&root.join("block_db"))?;
+        #[cfg(feature = "read_old_db")]
+        self.old_block_proof_db.checkpoint(&root.join("block_proof_db"))?;
+        #[cfg(feature = "read_old_db")]
+        self.old_block_proof_link_db.checkpoint(&root.join("block_proof_link_db"))?;
+        self.shard_state_dynamic_db.checkpoint(
+            &root.join("shardstate_db"), &root.join("cells_db"), &root.join("cells_db1")
+        ).await?;
+        let states = self.shard_state_persistent_db.backup(&root.join("shard_state_persistent_db")).await?;
+        self.archive_manager.backup(root).await?;
+
+        // Pointer is read from the copy, the node may have applied more blocks meanwhile
+        let last_applied = match NodeStateDb::with_path(&node_state_path).try_get(&LAST_APPLIED_MC_BLOCK)? {
+            Some(slice) => Some(
+                <BlockIdExt as storage::traits::Serializable>::deserialize(&mut std::io::Cursor::new(slice.as_ref()))?
+            ),
+            None => None
+        };
+        log::info!(
+            "Backup of internal DB is created in {}, last applied mc block {:?}, persistent states: {}, TIME: {}ms",
+            path, last_applied, states, now.elapsed().as_millis()
+        );
+        Ok(last_applied)
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.config.read_only
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.config.read_only {
+            fail!(NodeError::Db(DbError::ReadOnly, "Internal DB is in read-only mode".to_string()))
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        self.node_state_db.put(&HEALTH_PROBE, &now.to_le_bytes())
+    }
+
+    async fn flush(&self) -> Result<()> {
+        if self.config.read_only {
+            return Ok(())
+        }
+        self.block_handle_storage.flush().await?;
+        let instances = storage::db::rocksdb::flush_all()?;
+        log::info!("Internal DB is flushed: {} databases", instances);
+        Ok(())
+    }
+
+    async fn catch_up_with_primary(&self) -> Result<()> {
+        if !self.config.read_only {
+            fail!("Internal DB is not in read-only mode")
+        }
+        let _tc = TimeChecker::new("catch_up_with_primary".to_string(), 1000);
+        let instances = storage::db::rocksdb::catch_up_with_primary()?;
+        self.block_handle_storage.drop_caches();
+        let slices = self.archive_manager.catch_up_with_primary().await?;
+        log::trace!("Caught up with primary: {} databases, {} archive slices reopened", instances, slices);
+        Ok(())
+    }
+
+    fn load_indexed_block_ids(&self, after: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, BlockIdExt)>> {
+        self.block_index_db.block_ids_after(after, limit)
+    }
+
+    async fn replace_block_proof(&self, handle: &Arc<BlockHandle>, proof: &BlockProofStuff) -> Result<()> {
+        let _tc = TimeChecker::new(format!("replace_block_proof {}", proof.id()), 200);
+        if handle.id() != proof.id() {
+            fail!(NodeError::InvalidArg("`proof` and `handle` mismatch".to_string()))
+        }
+        let _lock = handle.proof_file_lock().write().await;
+        if handle.is_archived() {
+            fail!("Proof of block {} is in archive package and can't be replaced", handle.id())
+        }
+        if proof.is_link() {
+            let entry_id = PackageEntryId::<_, UInt256, PublicKey>::ProofLink(handle.id());
+            self.archive_manager.add_file(&entry_id, proof.data().to_vec()).await
+        } else {
+            let entry_id = PackageEntryId::<_, UInt256, PublicKey>::Proof(handle.id());
+            self.archive_manager.add_file(&entry_id, proof.data().to_vec()).await
+        }
+    }
+
+    fn load_sync_cursor(&self) -> Result<Option<SyncCursor>> {
+        match self.node_state_db.try_get(&SYNC_CURSOR)? {
+            Some(slice) => Ok(Some(SyncCursor::from_slice(slice.as_ref())?)),
+            None => Ok(None)
+        }
+    }
+
+    fn save_sync_cursor(&self, cursor: &SyncCursor) -> Result<()> {
+        let _tc = TimeChecker::new(format!("save_sync_cursor {}", cursor.mc_block_id), 10);
+        self.node_state_db.put(&SYNC_CURSOR, &cursor.to_vec()?)
+    }
+
+    fn clear_sync_cursor(&self) -> Result<()> {
+        self.node_state_db.delete(&SYNC_CURSOR)
+    }
+
+    fn load_ext_db_feed_seq_no(&self, feed: &str) -> Result<u64> {
+        let _lock = self.ext_db_feeds.lock().unwrap();
+        Ok(self.load_ext_db_feeds()?.get(feed).cloned().unwrap_or_default())
+    }
+
+    fn save_ext_db_feed_seq_no(&self, feed: &str, seq_no: u64) -> Result<()> {
+        let _lock = self.ext_db_feeds.lock().unwrap();
+        let mut feeds = self.load_ext_db_feeds()?;
+        feeds.insert(feed.to_string(), seq_no);
+        self.node_state_db.put(&EXT_DB_FEEDS, &serde_json::to_vec(&feeds)?)
+    }
 }
 
@@ -0,0 +1,191 @@
+use crate::{
+    config::DbScrubberConfig, engine::STATSD, engine_traits::EngineOperations,
+    internal_db::InternalDb
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+use storage::types::BlockHandle;
+use ton_block::BlockIdExt;
+use ton_types::Result;
+
+const BATCH_SIZE: usize = 100;
+const PASS_PAUSE_SEC: u64 = 3600;
+const MAX_QUARANTINE_SIZE: usize = 1000;
+
+#[derive(Clone, Debug)]
+pub struct QuarantinedProof {
+    pub block_id: BlockIdExt,
+    pub is_link: bool,
+    pub error: String,
+    pub found_at: u64,
+}
+
+// Verifies stored proofs and proof links of indexed blocks in background at limited rate.
+// Corrupted ones are quarantined and, if engine is given, replaced with downloaded ones.
+pub struct DbScrubber {
+    db: Arc<dyn InternalDb>,
+    quarantine: Mutex<Vec<QuarantinedProof>>,
+    status: Mutex<String>,
+}
+
+impl DbScrubber {
+
+    pub fn new(db: Arc<dyn InternalDb>) -> Self {
+        Self {
+            db,
+            quarantine: Mutex::new(Vec::new()),
+            status: Mutex::new("never run".to_string()),
+        }
+    }
+
+    pub fn status(&self) -> String {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn quarantine(&self) -> Vec<QuarantinedProof> {
+        self.quarantine.lock().unwrap().clone()
+    }
+
+    // Walks all indexed blocks at `blocks_per_sec` rate, then pauses and starts again
+    pub fn start(self: Arc<Self>, config: DbScrubberConfig, engine: Option<Arc<dyn EngineOperations>>) {
+        if !config.enabled {
+            return
+        }
+        let engine = if config.redownload { engine } else { None };
+        let delay = Duration::from_micros(1_000_000 / config.blocks_per_sec as u64);
+        tokio::spawn(async move {
+            let mut pass = 0;
+            loop {
+                pass += 1;
+                let mut after = Vec::new();
+                let mut checked = 0;
+                let mut corrupted = 0;
+                log::info!("DB scrubber pass {} started", pass);
+                loop {
+                    let ids = match self.db.load_indexed_block_ids(&after, BATCH_SIZE) {
+                        Ok(ids) => ids,
+                        Err(e) => {
+                            log::error!("DB scrubber can't load block ids: {}", e);
+                            break
+                        }
+                    };
+                    after = match ids.last() {
+                        Some((key, _)) => key.clone(),
+                        None => break
+                    };
+                    for (_, id) in ids.iter() {
+                        match self.check_block(id, engine.as_deref()).await {
+                            Ok(count) => corrupted += count,
+                            Err(e) => log::warn!("DB scrubber can't check block {}: {}", id, e)
+                        }
+                        checked += 1;
+                        tokio::time::sleep(delay).await;
+                    }
+                    STATSD.count("db_scrubber_checked_blocks", ids.len() as f64);
+                    *self.status.lock().unwrap() = format!(
+                        "pass {}: checked {} blocks, corrupted proofs: {}", pass, checked, corrupted
+                    );
+                }
+                log::info!(
+                    "DB scrubber pass {} finished: checked {} blocks, corrupted proofs: {}",
+                    pass, checked, corrupted
+                );
+                *self.status.lock().unwrap() = format!(
+                    "pass {} finished at {}: checked {} blocks, corrupted proofs: {}, quarantined: {}",
+                    pass, unix_time(), checked, corrupted, self.quarantine.lock().unwrap().len()
+                );
+                tokio::time::sleep(Duration::from_secs(PASS_PAUSE_SEC)).await;
+            }
+        });
+    }
+
+    // Returns count of corrupted proofs of the block
+    async fn check_block(&self, id: &BlockIdExt, engine: Option<&dyn EngineOperations>) -> Result<u32> {
+        let handle = match self.db.load_block_handle(id)? {
+            Some(handle) => handle,
+            None => return Ok(0)
+        };
+        let mut corrupted = 0;
+        for is_link in [false, true].iter().cloned() {
+            if !Self::has_proof(&handle, is_link) {
+                continue
+            }
+            if let Err(e) = self.verify_proof(&handle, is_link).await {
+                // Proof may be removed with its archive meanwhile
+                if !Self::has_proof(&handle, is_link) {
+                    continue
+                }
+                corrupted += 1;
+                self.quarantine_proof(id, is_link, e.to_string());
+                if let Some(engine) = engine {
+                    self.redownload_proof(engine, &handle, is_link).await;
+                }
+            }
+        }
+        Ok(corrupted)
+    }
+
+    fn has_proof(handle: &BlockHandle, is_link: bool) -> bool {
+        if is_link {
+            handle.has_proof_link()
+        } else {
+            handle.has_proof()
+        }
+    }
+
+    async fn verify_proof(&self, handle: &BlockHandle, is_link: bool) -> Result<()> {
+        // Proof must be for the block, and its Merkle proof must have root hash of the block
+        let proof = self.db.load_block_proof(handle, is_link).await?;
+        proof.virtualize_block()?;
+        Ok(())
+    }
+
+    fn quarantine_proof(&self, id: &BlockIdExt, is_link: bool, error: String) {
+        log::error!("Stored proof{} of block {} is corrupted: {}", if is_link { " link" } else { "" }, id, error);
+        STATSD.incr("db_scrubber_corrupted_proofs");
+        let mut quarantine = self.quarantine.lock().unwrap();
+        quarantine.retain(|entry| entry.block_id != *id || entry.is_link != is_link);
+        if quarantine.len() >= MAX_QUARANTINE_SIZE {
+            quarantine.remove(0);
+        }
+        quarantine.push(QuarantinedProof { block_id: id.clone(), is_link, error, found_at: unix_time() });
+        STATSD.gauge("db_scrubber_quarantine", quarantine.len() as f64);
+    }
+
+    async fn redownload_proof(&self, engine: &dyn EngineOperations, handle: &Arc<BlockHandle>, is_link: bool) {
+        let id = handle.id();
+        match self.download_and_replace_proof(engine, handle, is_link).await {
+            Ok(()) => {
+                log::info!(
+                    "Corrupted proof{} of block {} is replaced with downloaded one",
+                    if is_link { " link" } else { "" }, id
+                );
+                STATSD.incr("db_scrubber_redownloaded_proofs");
+                let mut quarantine = self.quarantine.lock().unwrap();
+                quarantine.retain(|entry| entry.block_id != *id || entry.is_link != is_link);
+                STATSD.gauge("db_scrubber_quarantine", quarantine.len() as f64);
+            },
+            Err(e) => log::warn!(
+                "Can't replace corrupted proof{} of block {}: {}",
+                if is_link { " link" } else { "" }, id, e
+            )
+        }
+    }
+
+    async fn download_and_replace_proof(
+        &self,
+        engine: &dyn EngineOperations,
+        handle: &Arc<BlockHandle>,
+        is_link: bool
+    ) -> Result<()> {
+        let proof = engine.download_block_proof(handle.id(), is_link, handle.is_key_block()?).await?;
+        proof.virtualize_block()?;
+        self.db.replace_block_proof(handle, &proof).await
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -1,29 +1,75 @@
-use storage::shardstate_db::AllowStateGcResolver;
+use storage::shardstate_db::{AllowStateGcResolver, GcStatistic};
 use ton_block::{BlockIdExt, UnixTime32, ShardIdent, AccountIdPrefixFull};
-use ton_types::Result;
+use ton_types::{error, Result};
 use adnl::common::add_object_to_map_with_update;
-use crate::engine_traits::EngineOperations;
+use crate::{boot, config::StatesGcPolicy, engine::STATSD, engine_traits::EngineOperations};
 use std::{
-    sync::atomic::{AtomicU32, Ordering},
+    sync::{Mutex, RwLock, atomic::{AtomicU32, Ordering}},
     collections::HashSet,
 };
 
+// Roots kept by policy and the point they are computed for
+#[derive(Default)]
+struct PolicyRoots {
+    computed: bool,
+    key_block_seqno: u32,
+    pss_keeper_seqno: u32,
+    roots: HashSet<BlockIdExt>,
+}
+
 pub struct AllowStateGcSmartResolver {
     last_processed_block: AtomicU32,
     min_ref_mc_block: AtomicU32,
     min_actual_ss: lockfree::map::Map<ShardIdent, AtomicU32>,
+    keep_key_blocks: AtomicU32,
+    policy_roots: RwLock<PolicyRoots>,
+    retained_by_policy: AtomicU32,
+    last_gc: Mutex<String>,
 }
 
 impl AllowStateGcSmartResolver {
-    pub fn new() -> Self {
+    pub fn new(policy: &StatesGcPolicy) -> Self {
         Self {
             last_processed_block: AtomicU32::new(0),
             min_ref_mc_block: AtomicU32::new(0),
             min_actual_ss: lockfree::map::Map::new(),
+            keep_key_blocks: AtomicU32::new(policy.keep_key_blocks),
+            policy_roots: RwLock::new(PolicyRoots::default()),
+            retained_by_policy: AtomicU32::new(0),
+            last_gc: Mutex::new("never run".to_string()),
+        }
+    }
+
+    pub fn policy(&self) -> StatesGcPolicy {
+        StatesGcPolicy {
+            keep_key_blocks: self.keep_key_blocks.load(Ordering::Relaxed),
         }
     }
 
-    pub async fn advance(&self, mc_block_id: &BlockIdExt, engine: &dyn EngineOperations) -> Result<()> {
+    // New policy is applied to the roots set at the next advance
+    pub fn set_policy(&self, policy: &StatesGcPolicy) {
+        let prev = self.keep_key_blocks.swap(policy.keep_key_blocks, Ordering::Relaxed);
+        self.policy_roots.write().unwrap().computed = false;
+        log::info!("States GC policy: keep_key_blocks {} -> {}", prev, policy.keep_key_blocks);
+    }
+
+    pub fn status(&self) -> String {
+        format!(
+            "keep_key_blocks: {}, roots kept by policy: {}, last gc: {}",
+            self.keep_key_blocks.load(Ordering::Relaxed),
+            self.policy_roots.read().unwrap().roots.len(),
+            self.last_gc.lock().unwrap()
+        )
+    }
+
+    pub async fn advance(
+        &self,
+        mc_block_id: &BlockIdExt,
+        pss_keeper_block_id: &BlockIdExt,
+        engine: &dyn EngineOperations
+    ) -> Result<()> {
+        self.update_policy_roots(pss_keeper_block_id, engine).await?;
+
         let seqno = mc_block_id.seq_no();
         if seqno <= self.last_processed_block.fetch_max(seqno, Ordering::Relaxed) {
             return Ok(())
@@ -90,10 +136,118 @@ impl AllowStateGcSmartResolver {
 
         Ok(())
     }
+
+    // Collects states of the last keep_key_blocks key blocks and of persistent state
+    // candidates which are not processed by PSS keeper yet. Key blocks are walked back
+    // from the last applied masterchain state through their handles.
+    async fn update_policy_roots(
+        &self,
+        pss_keeper_block_id: &BlockIdExt,
+        engine: &dyn EngineOperations
+    ) -> Result<()> {
+        let last_mc_id = match engine.load_last_applied_mc_block_id()? {
+            Some(id) => id,
+            None => return Ok(())
+        };
+        let last_mc_state = engine.load_state(&last_mc_id).await?;
+        let prev_blocks = &last_mc_state.shard_state_extra()?.prev_blocks;
+        let key_block_seqno = match prev_blocks.get_prev_key_block(last_mc_id.seq_no())? {
+            Some(id) => id.seq_no,
+            None => 0
+        };
+        {
+            let policy_roots = self.policy_roots.read().unwrap();
+            if policy_roots.computed &&
+                policy_roots.key_block_seqno == key_block_seqno &&
+                policy_roots.pss_keeper_seqno == pss_keeper_block_id.seq_no() {
+                return Ok(())
+            }
+        }
+
+        let keep_key_blocks = self.keep_key_blocks.load(Ordering::Relaxed);
+        let mut roots = HashSet::new();
+        let mut kept_key_blocks = 0;
+        let mut candidates = 0;
+        let mut newer_key_block: Option<(BlockIdExt, u32)> = None;
+        let mut seq_no = last_mc_id.seq_no() + 1;
+        while seq_no > 0 {
+            let prev_key_block_id = match prev_blocks.get_prev_key_block(seq_no - 1)? {
+                Some(id) => id,
+                None => break
+            };
+            let block_id = BlockIdExt {
+                shard_id: ShardIdent::masterchain(),
+                seq_no: prev_key_block_id.seq_no,
+                root_hash: prev_key_block_id.root_hash,
+                file_hash: prev_key_block_id.file_hash
+            };
+            let handle = engine.load_block_handle(&block_id)?.ok_or_else(
+                || error!("Cannot load handle for states GC key block {}", block_id)
+            )?;
+            let gen_utime = handle.gen_utime()?;
+            if let Some((newer_id, newer_gen_utime)) = newer_key_block.take() {
+                if newer_id.seq_no() >= pss_keeper_block_id.seq_no() &&
+                    engine.is_persistent_state(newer_gen_utime, gen_utime, boot::PSS_PERIOD_BITS) &&
+                    !roots.contains(&newer_id) {
+                    Self::collect_roots(&newer_id, &mut roots, engine).await?;
+                    candidates += 1;
+                }
+            }
+            if kept_key_blocks < keep_key_blocks {
+                if handle.has_state() {
+                    Self::collect_roots(&block_id, &mut roots, engine).await?;
+                }
+                kept_key_blocks += 1;
+            } else if block_id.seq_no() < pss_keeper_block_id.seq_no() {
+                break
+            }
+            seq_no = block_id.seq_no();
+            newer_key_block = Some((block_id, gen_utime));
+        }
+
+        log::info!(
+            "AllowStateGcSmartResolver: {} roots kept by policy for {} key blocks and {} persistent state candidates, \
+            last key block {}, PSS keeper block {}",
+            roots.len(), kept_key_blocks, candidates, key_block_seqno, pss_keeper_block_id.seq_no()
+        );
+        STATSD.gauge("states_gc_policy_roots", roots.len() as f64);
+        *self.policy_roots.write().unwrap() = PolicyRoots {
+            computed: true,
+            key_block_seqno,
+            pss_keeper_seqno: pss_keeper_block_id.seq_no(),
+            roots,
+        };
+        Ok(())
+    }
+
+    // Adds masterchain block and top blocks of its shards
+    async fn collect_roots(
+        mc_block_id: &BlockIdExt,
+        roots: &mut HashSet<BlockIdExt>,
+        engine: &dyn EngineOperations
+    ) -> Result<()> {
+        let mc_state = match engine.load_state(mc_block_id).await {
+            Ok(state) => state,
+            Err(e) => {
+                log::warn!("AllowStateGcSmartResolver: can't load state {} kept by policy: {}", mc_block_id, e);
+                return Ok(())
+            }
+        };
+        let (_masterchain, workchain_id) = engine.processed_workchain().await?;
+        for id in mc_state.shard_hashes()?.top_blocks(&[workchain_id])? {
+            roots.insert(id);
+        }
+        roots.insert(mc_block_id.clone());
+        Ok(())
+    }
 }
 
 impl AllowStateGcResolver for AllowStateGcSmartResolver {
     fn allow_state_gc(&self, block_id: &BlockIdExt, _gc_utime: UnixTime32) -> Result<bool> {
+        if self.policy_roots.read().unwrap().roots.contains(block_id) {
+            self.retained_by_policy.fetch_add(1, Ordering::Relaxed);
+            return Ok(false)
+        }
         if block_id.shard().is_masterchain() {
             if block_id.seq_no() != 0 { // we need zerostate
                 let min_ref_mc_block = self.min_ref_mc_block.load(Ordering::Relaxed);
@@ -114,4 +268,19 @@ impl AllowStateGcResolver for AllowStateGcSmartResolver {
         }
         Ok(false)
     }
+
+    fn gc_finished(&self, gc_stat: &GcStatistic) {
+        let retained_by_policy = self.retained_by_policy.swap(0, Ordering::Relaxed);
+        log::info!(
+            "States GC retained {} roots ({} by policy), deleted {} roots",
+            gc_stat.marked_roots, retained_by_policy, gc_stat.roots_to_sweep
+        );
+        STATSD.gauge("states_gc_retained_roots", gc_stat.marked_roots as f64);
+        STATSD.gauge("states_gc_retained_roots_by_policy", retained_by_policy as f64);
+        STATSD.gauge("states_gc_deleted_roots", gc_stat.roots_to_sweep as f64);
+        *self.last_gc.lock().unwrap() = format!(
+            "retained {} roots ({} by policy), deleted {} roots",
+            gc_stat.marked_roots, retained_by_policy, gc_stat.roots_to_sweep
+        );
+    }
 }
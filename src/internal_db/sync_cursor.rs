@@ -0,0 +1,66 @@
+use std::io::{Read, Write};
+use storage::traits::Serializable;
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+/// Node state key of archive sync position
+pub(crate) const SYNC_CURSOR: &str = "SyncCursor";
+
+// Archive sync position, stored after each imported masterchain block of a package
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncCursor {
+    // Masterchain block applied together with its shard blocks
+    pub mc_block_id: BlockIdExt,
+    // Shard blocks of `mc_block_id`, all applied
+    pub shard_block_ids: Vec<BlockIdExt>,
+    // Archive being imported (masterchain seq_no it is requested by). Its blocks up to
+    // `mc_block_id` are skipped as applied when the archive is imported again after restart
+    pub archive_seq_no: u32,
+}
+
+impl Serializable for SyncCursor {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<()> {
+        self.mc_block_id.serialize(writer)?;
+        (self.shard_block_ids.len() as u32).serialize(writer)?;
+        for id in self.shard_block_ids.iter() {
+            id.serialize(writer)?;
+        }
+        self.archive_seq_no.serialize(writer)
+    }
+
+    fn deserialize<R: Read>(reader: &mut R) -> Result<Self> {
+        let mc_block_id = BlockIdExt::deserialize(reader)?;
+        let count = u32::deserialize(reader)?;
+        if count > u16::MAX as u32 {
+            fail!("Invalid sync cursor: {} shard blocks", count)
+        }
+        let mut shard_block_ids = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            shard_block_ids.push(BlockIdExt::deserialize(reader)?);
+        }
+        let archive_seq_no = u32::deserialize(reader)?;
+        Ok(Self { mc_block_id, shard_block_ids, archive_seq_no })
+    }
+}
+
+#[test]
+fn test_sync_cursor_serialization() {
+    use ton_block::ShardIdent;
+    use ton_types::UInt256;
+
+    let cursor = SyncCursor {
+        mc_block_id: BlockIdExt::with_params(
+            ShardIdent::masterchain(), 200, UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[2; 32])
+        ),
+        shard_block_ids: vec!(
+            BlockIdExt::with_params(
+                ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap(), 300,
+                UInt256::from_slice(&[3; 32]), UInt256::from_slice(&[4; 32])
+            )
+        ),
+        archive_seq_no: 101,
+    };
+    let data = cursor.to_vec().unwrap();
+    assert_eq!(SyncCursor::from_slice(&data).unwrap(), cursor);
+    assert!(SyncCursor::from_slice(&data[..data.len() - 1]).is_err());
+}
@@ -1,16 +1,18 @@
-use rustracing::sampler::AllSampler;
+use crate::{config::TracingConfig, types::lru_cache::LruCache};
+use rustracing::{sampler::AllSampler, tag::Tag};
 use rustracing_jaeger::{
     reporter::JaegerCompactReporter,
-    span::SpanContext,
-    span::SpanReceiver,
+    span::{Span, SpanContext, SpanReceiver},
     Tracer,
 };
 use std::{
     collections::HashMap,
     env, 
     net::ToSocketAddrs,
-    sync::Mutex,
+    sync::{atomic::{AtomicU64, Ordering}, Mutex},
+    time::SystemTime,
 };
+use ton_block::BlockIdExt;
 
 use ton_types::types::*;
 use ton_types::fail;
@@ -31,11 +33,105 @@ lazy_static::lazy_static! {
     static ref JAEGER: Mutex<JaegerHelper> = Mutex::new(JaegerHelper::new("r-node"));
 }
 
-pub fn init_jaeger(){
+// f64 bits of the share of traced blocks
+static BLOCKS_SAMPLING_RATE: AtomicU64 = AtomicU64::new(0);
+
+pub fn init_jaeger(config: &TracingConfig) {
+    BLOCKS_SAMPLING_RATE.store(config.blocks_sampling_rate.to_bits(), Ordering::Relaxed);
     lazy_static::initialize(&JAEGER);
     log::trace!("Jaeger lazy init");
 }
 
+// Decision depends on the block only, so all stages of the block get into one trace
+fn block_sampled(block_id: &BlockIdExt, key_block: bool, rate: f64) -> bool {
+    if key_block || rate >= 1.0 {
+        return true
+    }
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&block_id.root_hash().as_slice()[0..8]);
+    (u64::from_be_bytes(bytes) as f64) < rate * u64::MAX as f64
+}
+
+// Contexts of the last traced stage of recent blocks, the next stage is its child
+const BLOCK_SPANS_CAPACITY: usize = 1000;
+
+lazy_static::lazy_static! {
+    static ref BLOCK_SPANS: LruCache<BlockIdExt, SpanContext> = LruCache::new(BLOCK_SPANS_CAPACITY);
+}
+
+// Stage of block processing (broadcast received, proof checked, state applied, ...)
+// which started at `started` and is finished now. Stages of the block make a chain: each
+// one is a child of the previous one, the first is a child of root context of the block.
+pub fn block_stage(block_id: &BlockIdExt, key_block: bool, stage: &'static str, started: SystemTime) {
+    let rate = f64::from_bits(BLOCKS_SAMPLING_RATE.load(Ordering::Relaxed));
+    if !block_sampled(block_id, key_block, rate) {
+        return
+    }
+    let parent = match BLOCK_SPANS.get(block_id) {
+        Some(parent) => parent,
+        None => match create_root_context(block_id.root_hash().to_hex_string()) {
+            Ok(root) => root,
+            Err(e) => {
+                log::error!(target: "jaeger", "Error: {}", e);
+                return
+            }
+        }
+    };
+    match JAEGER.lock() {
+        Ok(helper) => {
+            let span = start_block_span(
+                &helper.tracer, &parent, stage, started, block_id.to_string(), key_block
+            );
+            if let Some(context) = span.context() {
+                BLOCK_SPANS.insert(block_id.clone(), context.clone());
+            }
+        }
+        Err(e) => {
+            log::error!(target: "jaeger", "Mutex locking error: {}", e);
+            return
+        }
+    }
+    // Span is finished already, it is sent to agent off the runtime
+    tokio::task::spawn_blocking(move || {
+        match JAEGER.lock() {
+            Ok(mut helper) => helper.report_span(),
+            Err(e) => { log::error!(target: "jaeger", "Mutex locking error: {}", e); }
+        }
+    });
+}
+
+fn create_root_context(msg_id: String) -> Result<SpanContext> {
+    let mut carrier = HashMap::new();
+    let span_ctx = format!("{}:{}:0:1", &msg_id[0..16], &msg_id[16..32]);
+    carrier.insert(
+        "uber-trace-id".to_string(),
+        span_ctx.to_string());
+    if let Ok(Some(ctx)) = SpanContext::extract_from_text_map(&carrier) {
+        Ok(ctx)
+    } else {
+        fail!("Can't extract root span context from textmap")
+    }
+}
+
+fn start_block_span(
+    tracer: &Tracer,
+    parent: &SpanContext,
+    stage: &'static str,
+    started: SystemTime,
+    block_id: String,
+    key_block: bool
+) -> Span {
+    let span = tracer
+        .span(stage)
+        .child_of(parent)
+        .start_time(started)
+        .tag(Tag::new("block_id", block_id))
+        .tag(Tag::new("key_block", key_block))
+        .start();
+    log::trace!(target: "jaeger", "Block span started");
+    span
+}
+
 #[cfg(feature = "external_db")]
 pub fn message_from_kafka_received(kf_key: &[u8]) {
     let msg_id_bytes = kf_key[0..32].to_vec();
@@ -95,7 +191,7 @@ impl JaegerHelper {
     }
     
     pub fn send_span(&mut self, msg_id: String, span_name: String){
-        match self.create_root_span(msg_id) {
+        match create_root_context(msg_id) {
             Ok(span_root) => {
                 self.start_span(span_root, span_name);
                 self.report_span();
@@ -104,19 +200,6 @@ impl JaegerHelper {
         }
     }
     
-    fn create_root_span(&mut self, msg_id: String) -> Result<SpanContext> {
-        let mut carrier = HashMap::new();
-        let span_ctx = format!("{}:{}:0:1", &msg_id[0..16], &msg_id[16..32]);
-        carrier.insert(
-            "uber-trace-id".to_string(),
-            span_ctx.to_string());
-        if let Ok(Some(ctx)) = SpanContext::extract_from_text_map(&carrier) {
-            Ok(ctx)
-        } else {
-            fail!("Can't extract root span context from textmap")
-        }
-    }
-    
     fn start_span(&mut self, ctx: SpanContext, name: String) {
         let _span = self.tracer
             .span(name)
@@ -124,7 +207,7 @@ impl JaegerHelper {
             .start();
         log::trace!(target: "jaeger", "Span started");
     }
-   
+
     fn report_span(&mut self){
         if let Err(e) = self.reporter.report(&(self.span_rx).try_iter().collect::<Vec<_>>()){
             log::error!(target: "jaeger", "Internal rustracing_jaeger crate error in reporter. Desc: {}", e); 
@@ -132,3 +215,42 @@ impl JaegerHelper {
     }
 }
 
+
+#[test]
+fn test_block_sampling() {
+    let block_id = |first: u8| {
+        let mut hash = [0xff; 32];
+        hash[0] = first;
+        BlockIdExt::with_params(
+            ton_block::ShardIdent::masterchain(), 1, UInt256::from(&hash), UInt256::default()
+        )
+    };
+    assert!(!block_sampled(&block_id(0x80), false, 0.01));
+    assert!(block_sampled(&block_id(0x80), true, 0.01));
+    assert!(block_sampled(&block_id(0x01), false, 0.01));
+    assert!(!block_sampled(&block_id(0x00), false, 0.0));
+    assert!(block_sampled(&block_id(0xff), false, 1.0));
+}
+
+#[test]
+fn test_block_stages_chain() {
+    use rustracing_jaeger::span::FinishedSpan;
+    let (span_tx, span_rx) = crossbeam_channel::bounded(10);
+    let tracer = Tracer::with_sender(AllSampler, span_tx);
+    let root = create_root_context(UInt256::from([1; 32]).to_hex_string()).unwrap();
+    let first = start_block_span(
+        &tracer, &root, "broadcast received", SystemTime::now(), "block".to_string(), false
+    );
+    let first_context = first.context().unwrap().clone();
+    drop(first);
+    let second = start_block_span(
+        &tracer, &first_context, "proof checked", SystemTime::now(), "block".to_string(), false
+    );
+    drop(second);
+    let spans = span_rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(spans.len(), 2);
+    let parent_of = |span: &FinishedSpan| span.references()[0].span().span_id();
+    assert_eq!(parent_of(&spans[0]), root.state().span_id());
+    assert_eq!(parent_of(&spans[1]), spans[0].context().state().span_id());
+    assert_eq!(spans[1].context().state().trace_id(), root.state().trace_id());
+}
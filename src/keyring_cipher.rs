@@ -0,0 +1,152 @@
+use crate::config::KeyringEncryptionConfig;
+use adnl::common::KeyOptionJson;
+use openssl::{
+    hash::MessageDigest, pkcs5::pbkdf2_hmac, rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher}
+};
+use ton_types::{error, fail, Result};
+
+// Marks encrypted pvt_key, the rest is base64 of salt, iv, tag and ciphertext
+const ENCRYPTED_PREFIX: &str = "encrypted:";
+const SALT_LEN: usize = 16;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+const PBKDF2_ITERATIONS: usize = 100_000;
+
+// AES-256-GCM encryption of private keys of validator key ring with a key derived from
+// the passphrase. Every key has its own salt; key id is authenticated along with the key,
+// so encrypted entries can't be swapped in config.json.
+pub struct KeyringCipher {
+    passphrase: String,
+}
+
+impl KeyringCipher {
+
+    pub fn with_passphrase(passphrase: &str) -> Result<Self> {
+        if passphrase.is_empty() {
+            fail!("Passphrase is empty")
+        }
+        Ok(Self { passphrase: passphrase.to_string() })
+    }
+
+    // None if encryption is off. Passphrase from the environment variable is preferred.
+    pub fn with_config(config: &KeyringEncryptionConfig) -> Result<Option<Self>> {
+        if !config.enabled {
+            return Ok(None)
+        }
+        match (std::env::var(&config.passphrase_env), &config.passphrase_file) {
+            (Ok(passphrase), _) => Self::with_passphrase(&passphrase).map(Some),
+            (Err(_), Some(path)) => Self::with_passphrase_file(path).map(Some),
+            (Err(_), None) => fail!(
+                "Keyring encryption is enabled, but passphrase is set neither in {} \
+                nor in passphrase_file", config.passphrase_env
+            )
+        }
+    }
+
+    // Content of the file without trailing line breaks
+    pub fn with_passphrase_file(path: &str) -> Result<Self> {
+        let passphrase = std::fs::read_to_string(path)
+            .map_err(|e| error!("Can't read passphrase file {}: {}", path, e))?;
+        Self::with_passphrase(passphrase.trim_end_matches(|c| c == '\r' || c == '\n'))
+    }
+
+    pub fn is_encrypted(key: &KeyOptionJson) -> Result<bool> {
+        let (_, pvt_key) = split_pvt_key(key)?;
+        Ok(pvt_key.starts_with(ENCRYPTED_PREFIX))
+    }
+
+    pub fn encrypt(&self, key_id: &str, key: &KeyOptionJson) -> Result<KeyOptionJson> {
+        let (mut json, pvt_key) = split_pvt_key(key)?;
+        if pvt_key.starts_with(ENCRYPTED_PREFIX) {
+            fail!("key {} is encrypted already", key_id)
+        }
+        let data = self.encrypt_data(key_id.as_bytes(), pvt_key.as_bytes())?;
+        json["pvt_key"] = format!("{}{}", ENCRYPTED_PREFIX, base64::encode(&data)).into();
+        Ok(serde_json::from_value(json)?)
+    }
+
+    pub fn decrypt(&self, key_id: &str, key: &KeyOptionJson) -> Result<KeyOptionJson> {
+        let (mut json, pvt_key) = split_pvt_key(key)?;
+        let data = match pvt_key.strip_prefix(ENCRYPTED_PREFIX) {
+            Some(data) => base64::decode(data)
+                .map_err(|e| error!("key {}: bad encrypted data: {}", key_id, e))?,
+            None => fail!("key {} is not encrypted", key_id)
+        };
+        if data.len() < SALT_LEN + IV_LEN + TAG_LEN {
+            fail!("key {}: encrypted data is too short", key_id)
+        }
+        let plain = self.decrypt_data(key_id.as_bytes(), &data)
+            .map_err(|_| error!("key {} can't be decrypted, keyring passphrase is wrong", key_id))?;
+        json["pvt_key"] = String::from_utf8(plain)?.into();
+        Ok(serde_json::from_value(json)?)
+    }
+
+    // Salt, iv, tag and ciphertext; additional data is authenticated along with the data
+    pub fn encrypt_data(&self, aad: &[u8], plain: &[u8]) -> Result<Vec<u8>> {
+        let mut data = vec![0; SALT_LEN + IV_LEN + TAG_LEN];
+        rand_bytes(&mut data[..SALT_LEN + IV_LEN])?;
+        let (salt, rest) = data.split_at_mut(SALT_LEN);
+        let (iv, tag) = rest.split_at_mut(IV_LEN);
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(), &self.derive_key(salt)?, Some(&*iv), aad, plain, tag
+        )?;
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt_data(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < SALT_LEN + IV_LEN + TAG_LEN {
+            fail!("encrypted data is too short")
+        }
+        let (salt, rest) = data.split_at(SALT_LEN);
+        let (iv, rest) = rest.split_at(IV_LEN);
+        let (tag, ciphertext) = rest.split_at(TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(), &self.derive_key(salt)?, Some(iv), aad, ciphertext, tag
+        ).map_err(|_| error!("data can't be decrypted, passphrase is wrong"))
+    }
+
+    fn derive_key(&self, salt: &[u8]) -> Result<[u8; 32]> {
+        let mut key = [0; 32];
+        pbkdf2_hmac(
+            self.passphrase.as_bytes(), salt, PBKDF2_ITERATIONS, MessageDigest::sha256(), &mut key
+        )?;
+        Ok(key)
+    }
+}
+
+fn split_pvt_key(key: &KeyOptionJson) -> Result<(serde_json::Value, String)> {
+    let json = serde_json::to_value(key)?;
+    let pvt_key = json.get("pvt_key")
+        .and_then(|pvt_key| pvt_key.as_str())
+        .ok_or_else(|| error!("private key is not set"))?
+        .to_string();
+    Ok((json, pvt_key))
+}
+
+#[test]
+fn test_keyring_cipher() {
+    use crate::validator::validator_utils::mine_key_for_workchain;
+    use adnl::common::KeyOption;
+
+    let (private, public) = mine_key_for_workchain(None);
+    let key_id = base64::encode(public.id().data());
+    let cipher = KeyringCipher::with_passphrase("correct horse").unwrap();
+    assert!(!KeyringCipher::is_encrypted(&private).unwrap());
+
+    let encrypted = cipher.encrypt(&key_id, &private).unwrap();
+    assert!(KeyringCipher::is_encrypted(&encrypted).unwrap());
+    assert!(KeyOption::from_private_key(&encrypted).is_err());
+    assert!(cipher.encrypt(&key_id, &encrypted).is_err());
+    let decrypted = KeyOption::from_private_key(&cipher.decrypt(&key_id, &encrypted).unwrap());
+    assert_eq!(decrypted.unwrap().id().data(), public.id().data());
+
+    // wrong passphrase and entry moved to another key id
+    let wrong = KeyringCipher::with_passphrase("battery staple").unwrap();
+    let err = wrong.decrypt(&key_id, &encrypted).unwrap_err();
+    assert!(err.to_string().contains("keyring passphrase is wrong"));
+    assert!(cipher.decrypt("other", &encrypted).is_err());
+    assert!(cipher.decrypt(&key_id, &private).is_err());
+    assert!(KeyringCipher::with_passphrase("").is_err());
+}
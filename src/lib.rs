@@ -1,20 +1,32 @@
 pub mod block;
 pub mod block_proof;
 pub mod boot;
+pub mod bundle_replay;
 pub mod collator_test_bundle;
 pub mod config;
+pub mod config_check;
+pub mod config_init;
+pub mod config_keys;
+pub mod config_reload;
+pub mod db_dump;
 pub mod error;
 pub mod engine;
 pub mod engine_traits;
 pub mod engine_operations;
 pub mod ext_messages;
+pub mod external_db_ack;
 pub mod full_node;
 pub mod internal_db;
+pub mod keyring_cipher;
 pub mod macros;
+pub mod metrics;
 pub mod network;
+pub mod node_status;
 pub mod rng;
 pub mod shard_blocks;
 pub mod shard_state;
+pub mod shutdown;
+pub mod supervisor;
 pub mod sync;
 pub mod types;
 pub mod validating_utils;
@@ -25,10 +37,16 @@ pub mod jaeger;
 
 #[cfg(not(feature = "tracing"))]
 pub mod jaeger {
-    pub fn init_jaeger(){}
+    pub fn init_jaeger(_config: &crate::config::TracingConfig) {}
     #[cfg(feature = "external_db")]
     pub fn message_from_kafka_received(_kf_key: &[u8]) {}
     pub fn broadcast_sended(_msg_id: String) {}
+    pub fn block_stage(
+        _block_id: &ton_block::BlockIdExt,
+        _key_block: bool,
+        _stage: &'static str,
+        _started: std::time::SystemTime
+    ) {}
 }
 
 #[cfg(feature = "external_db")]
@@ -1,19 +1,31 @@
 mod block;
 mod block_proof;
 mod boot;
+mod bundle_replay;
 mod collator_test_bundle;
 pub mod config;
+mod config_check;
+mod config_init;
+mod config_keys;
+mod config_reload;
+mod db_dump;
 mod engine;
 mod engine_traits;
 mod engine_operations;
 mod error;
+mod external_db_ack;
 mod full_node;
 mod internal_db;
+mod keyring_cipher;
 mod macros;
+mod metrics;
 pub mod network;
+mod node_status;
 mod rng;
 mod shard_blocks;
 mod shard_state;
+mod shutdown;
+mod supervisor;
 mod sync;
 mod types;
 mod validating_utils;
@@ -24,10 +36,16 @@ mod jaeger;
 
 #[cfg(not(feature = "tracing"))]
 mod jaeger {
-    pub fn init_jaeger(){}
+    pub fn init_jaeger(_config: &crate::config::TracingConfig) {}
     #[cfg(feature = "external_db")]
     pub fn message_from_kafka_received(_kf_key: &[u8]) {}
     pub fn broadcast_sended(_msg_id: String) {}
+    pub fn block_stage(
+        _block_id: &ton_block::BlockIdExt,
+        _key_block: bool,
+        _stage: &'static str,
+        _started: std::time::SystemTime
+    ) {}
 }
 
 //extern crate lazy_static;
@@ -40,44 +58,12 @@ use crate::{config::TonNodeConfig, engine_traits::ExternalDb, engine::STATSD, ja
 use clap;
 
 #[cfg(feature = "external_db")]
-use ton_types::error;
+use crate::config::ExternalDbType;
+#[cfg(feature = "external_db")]
+use ton_types::fail;
 use ton_types::Result;
-use std::sync::Arc;
-
-
-fn init_logger(log_config_path: Option<String>) {
-
-    if let Some(path) = log_config_path {
-        if let Err(err) = log4rs::init_file(path, Default::default()) {
-            println!("Error while initializing log by {}: {}", err, err);
-        } else {
-            return;
-        }
-    }
+use std::{sync::Arc, time::Duration};
 
-    let level = log::LevelFilter::Info; 
-    let stdout = log4rs::append::console::ConsoleAppender::builder()
-        .target(log4rs::append::console::Target::Stdout)
-        .build();
-
-    let config = log4rs::config::Config::builder()
-        .appender(
-            log4rs::config::Appender::builder()
-                .filter(Box::new(log4rs::filter::threshold::ThresholdFilter::new(level)))
-                .build("stdout", Box::new(stdout)),
-        )
-        .build(
-            log4rs::config::Root::builder()
-                .appender("stdout")
-                .build(log::LevelFilter::Info),
-        )
-        .unwrap();
-
-    let result = log4rs::init_config(config);
-    if let Err(e) = result {
-        println!("Error init log: {}", e);
-    }
-}
 
 fn log_version() {
     log::info!(
@@ -129,12 +115,42 @@ fn print_build_info() -> String {
 
 #[cfg(feature = "external_db")]
 fn start_external_db(config: &TonNodeConfig) -> Result<Vec<Arc<dyn ExternalDb>>> {
-    Ok(vec!(
-        external_db::create_external_db(
-            config.external_db_config().ok_or_else(|| error!("Can't load external database config!"))?,
-            config.front_workchain_ids()
-        )?
-    ))
+    let external_db_configs = config.external_db_config();
+    if external_db_configs.is_empty() {
+        fail!("Can't load external database config!")
+    }
+    let multiple = external_db_configs.len() > 1;
+    // Sinks track delivery of block's records only if engine awaits it
+    let track_delivery = config.external_db_ack_config().wait_for_external_db_ack;
+    let mut external_dbs = Vec::new();
+    for (i, mut external_db_config) in external_db_configs.into_iter().enumerate() {
+        let name = external_db_config.name.clone().unwrap_or_else(|| i.to_string());
+        // Name identifies sink's feeds in internal DB
+        external_db_config.name = Some(name.clone());
+        let buffer_size = match external_db_config.buffer_size {
+            Some(buffer_size) => buffer_size,
+            None if multiple => external_db::DEFAULT_SINK_BUFFER_SIZE,
+            None => 0
+        };
+        let external_db = match external_db_config.db_type {
+            ExternalDbType::Kafka => external_db::create_external_db(
+                external_db_config,
+                config.front_workchain_ids(),
+                track_delivery
+            )?,
+            ExternalDbType::Files => external_db::create_files_external_db(
+                external_db_config,
+                config.front_workchain_ids(),
+                track_delivery
+            )?,
+        };
+        if buffer_size > 0 {
+            external_dbs.push(external_db::create_buffered_external_db(name, external_db, buffer_size));
+        } else {
+            external_dbs.push(external_db);
+        }
+    }
+    Ok(external_dbs)
 }
 
 #[cfg(not(feature = "external_db"))]
@@ -142,12 +158,225 @@ fn start_external_db(_config: &TonNodeConfig) -> Result<Vec<Arc<dyn ExternalDb>>
     Ok(vec!())
 }
 
-async fn start_engine(config: TonNodeConfig, zerostate_path: Option<&str>, initial_sync_disabled: bool) -> Result<()> {
-    let external_db = start_external_db(&config)?;
-    crate::engine::run(config, zerostate_path, external_db, initial_sync_disabled).await?;
+async fn start_engine(
+    config: TonNodeConfig,
+    zerostate_path: Option<&str>,
+    initial_sync_disabled: bool,
+    check_db: bool,
+    migrate_db_layout: bool,
+    read_only: bool,
+    import_archives: Option<&str>
+) -> Result<()> {
+    // Node in read-only mode produces nothing to external DB
+    let external_db = if read_only {
+        Vec::new()
+    } else {
+        start_external_db(&config)?
+    };
+    crate::engine::run(
+        config, zerostate_path, external_db, initial_sync_disabled, check_db, migrate_db_layout,
+        read_only, import_archives
+    ).await?;
     Ok(())
 }
 
+fn replay_bundle(matches: &clap::ArgMatches) -> i32 {
+    let path = matches.value_of("path").unwrap_or_default();
+    let collate = !matches.is_present("validate_only");
+    let validate = !matches.is_present("collate_only");
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_stack_size(8 * 1024 * 1024)
+        .build()
+        .expect("Can't create tokio runtime");
+    match runtime.block_on(crate::bundle_replay::replay_bundle(path, collate, validate)) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(e) => {
+            println!("Can't replay bundle {}: {}", path, e);
+            2
+        }
+    }
+}
+
+fn init(config_dir: &str, matches: &clap::ArgMatches) -> i32 {
+    let port = match matches.value_of("port").unwrap_or_default().parse::<u16>() {
+        Ok(port) => port,
+        Err(e) => {
+            println!("Invalid port: {}", e);
+            return 1
+        }
+    };
+    let params = config_init::InitParams {
+        ip: matches.value_of("ip").unwrap_or_default().to_string(),
+        port,
+        network: matches.value_of("network").unwrap_or_default().to_string(),
+        validator: matches.is_present("validator"),
+        force: matches.is_present("force"),
+    };
+    match config_init::init_config(config_dir, CONFIG_NAME, &params) {
+        Ok((_, console_client_key)) => {
+            println!("{} is written to {}", CONFIG_NAME, config_dir);
+            if let Some(key) = console_client_key {
+                println!("Console client public key (admin): {}", key.public);
+                match serde_json::to_string_pretty(&key.private) {
+                    Ok(private) => println!(
+                        "Console client private key, keep it secret and put into console \
+                        config:\n{}", private
+                    ),
+                    Err(e) => println!("Can't print console client private key: {}", e)
+                }
+            }
+            0
+        }
+        Err(e) => {
+            println!("Can't init node config: {}", e);
+            1
+        }
+    }
+}
+
+fn check_config(config_dir: &str, matches: &clap::ArgMatches) -> i32 {
+    let parse = |name: &str, default: u64| match matches.value_of(name) {
+        Some(value) => value.parse::<u64>().map_err(|e| format!("Invalid {}: {}", name, e)),
+        None => Ok(default)
+    };
+    let min_free_space_gb = parse("min_free_space_gb", config_check::DEFAULT_MIN_FREE_SPACE_GB);
+    let params = match (min_free_space_gb, parse("ping_dht", 0)) {
+        (Ok(min_free_space_gb), Ok(dht_nodes)) => config_check::CheckParams {
+            min_free_space_gb,
+            dht_nodes: dht_nodes as usize
+        },
+        (Err(e), _) | (_, Err(e)) => {
+            println!("{}", e);
+            return 1
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Can't create tokio runtime");
+    let report = runtime.block_on(config_check::check_config(config_dir, CONFIG_NAME, &params));
+    println!("{}", report);
+    if report.passed() { 0 } else { 1 }
+}
+
+fn keys(config_dir: &str, matches: &clap::ArgMatches) -> i32 {
+    let result = match matches.subcommand() {
+        ("export", Some(matches)) => {
+            let output = matches.value_of("output").unwrap_or_default();
+            let params = config_keys::ExportParams {
+                include_node_key: matches.is_present("include_node_key"),
+                include_console_key: matches.is_present("include_console_key"),
+                passphrase_file: matches.value_of("passphrase_file").map(|path| path.to_string()),
+            };
+            config_keys::export_keys(config_dir, CONFIG_NAME, output, &params)
+                .map(|count| format!("{} validator key(s) are exported to {}", count, output))
+        }
+        ("import", Some(matches)) => {
+            let input = matches.value_of("input").unwrap_or_default();
+            config_keys::import_keys(
+                config_dir, CONFIG_NAME, input, matches.value_of("passphrase_file"),
+                matches.is_present("force")
+            ).map(|count| format!("{} validator key(s) are imported from {}", count, input))
+        }
+        _ => Err(ton_types::error!("use keys export or keys import"))
+    };
+    match result {
+        Ok(message) => {
+            println!("{}", message);
+            0
+        }
+        Err(e) => {
+            println!("Can't move keys: {}", e);
+            1
+        }
+    }
+}
+
+fn status(config_dir: &str, matches: &clap::ArgMatches) -> i32 {
+    let max_timediff = match matches.value_of("max_timediff").map(|value| value.parse::<i64>()) {
+        Some(Ok(max_timediff)) => Some(max_timediff),
+        Some(Err(e)) => {
+            println!("Invalid max_timediff: {}", e);
+            return 1
+        }
+        None => None
+    };
+    let params = node_status::StatusParams {
+        ctl_addr: matches.value_of("ctl_addr").map(|addr| addr.to_string()),
+        ctl_key: matches.value_of("ctl_key").map(|key| key.to_string()),
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Can't create tokio runtime");
+    let status = match runtime.block_on(node_status::query_status(config_dir, &params)) {
+        Ok(status) => status,
+        Err(e) => {
+            println!("Can't get node status: {}", e);
+            return 1
+        }
+    };
+    if matches.is_present("json") {
+        println!("{:#}", status.to_json());
+    } else {
+        print!("{}", status);
+    }
+    if let Some(max_timediff) = max_timediff {
+        if let Err(e) = status.check_timediff(max_timediff) {
+            println!("Node is out of sync: {}", e);
+            return 2
+        }
+    }
+    0
+}
+
+fn dump_subcommand<'a, 'b>(name: &'b str, about: &'b str) -> clap::App<'a, 'b> {
+    clap::SubCommand::with_name(name)
+        .about(about)
+        .arg(clap::Arg::with_name("block_id")
+            .required(true)
+            .value_name("block id")
+            .help("wc:shard:seqno or wc:shard:seqno:root_hash:file_hash, all hex but wc and seqno"))
+        .arg(clap::Arg::with_name("boc")
+            .long("boc")
+            .value_name("path")
+            .help("also write raw bytes into the file"))
+}
+
+fn dump(config_dir: &str, matches: &clap::ArgMatches) -> i32 {
+    let (what, matches) = match matches.subcommand() {
+        (what, Some(matches)) => (what, matches),
+        _ => {
+            println!("use dump block or dump state");
+            return 1
+        }
+    };
+    let params = db_dump::DumpParams {
+        block_id: matches.value_of("block_id").unwrap_or_default().to_string(),
+        boc: matches.value_of("boc").map(|path| path.to_string()),
+    };
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Can't create tokio runtime");
+    let result = match what {
+        "block" => runtime.block_on(db_dump::dump_block(config_dir, CONFIG_NAME, &params)),
+        _ => runtime.block_on(db_dump::dump_state(config_dir, CONFIG_NAME, &params))
+    };
+    match result {
+        Ok(json) => {
+            println!("{:#}", json);
+            0
+        }
+        Err(e) => {
+            println!("Can't dump {}: {}", what, e);
+            1
+        }
+    }
+}
+
 const CONFIG_NAME: &str = "config.json";
 const DEFAULT_CONFIG_NAME: &str = "default_config.json";
 
@@ -173,11 +402,176 @@ fn main() {
             .short("i")
             .long("initial-sync-disabled")
             .value_name("initial sync disable flag")
-            .help("use this flag to sync from zero_state"));
+            .help("use this flag to sync from zero_state"))
+        .arg(clap::Arg::with_name("check_db")
+            .long("check-db")
+            .help("check the last applied blocks in internal db and truncate them to the last consistent one"))
+        .arg(clap::Arg::with_name("migrate_db_layout")
+            .long("migrate-db-layout")
+            .help("move internal db data to directories set by internal_db_paths config"))
+        .arg(clap::Arg::with_name("restore_from_backup")
+            .long("restore-from-backup")
+            .value_name("backup path")
+            .help("restore empty internal db from backup made for the network of global config"))
+        .arg(clap::Arg::with_name("read_only")
+            .long("read-only")
+            .conflicts_with_all(&["check_db", "migrate_db_layout", "restore_from_backup"])
+            .help("follow internal db of another node running with it, nothing is written; \
+                only read queries are served by control server"))
+        .arg(clap::Arg::with_name("import_archives")
+            .long("import-archives")
+            .value_name("archives dir")
+            .conflicts_with("read_only")
+            .help("apply blocks from archive packages in directory before sync, \
+                malformed and out of order packages are skipped"))
+        .arg(clap::Arg::with_name("encrypt_keys")
+            .long("encrypt-keys")
+            .help("encrypt private keys of validator key ring in config.json with passphrase \
+                set by keyring_encryption config, enable the encryption and exit"))
+        .arg(clap::Arg::with_name("public_ip")
+            .long("public-ip")
+            .value_name("ip[:port]")
+            .help("advertise this ip instead of ADNL ip of config.json, the port must be \
+                ADNL port, the file is not changed"))
+        .arg(clap::Arg::with_name("allow_private_ip")
+            .long("allow-private-ip")
+            .requires("public_ip")
+            .help("allow private and loopback address in --public-ip, for test networks"))
+        .subcommand(clap::SubCommand::with_name("init")
+            .about("write config.json with new keys and global config into configs dir")
+            .arg(clap::Arg::with_name("ip")
+                .long("ip")
+                .required(true)
+                .value_name("address")
+                .help("IP address of the node"))
+            .arg(clap::Arg::with_name("port")
+                .long("port")
+                .required(true)
+                .value_name("port")
+                .help("ADNL UDP port"))
+            .arg(clap::Arg::with_name("network")
+                .long("network")
+                .required(true)
+                .value_name("global config")
+                .help("URL or path of global config of the network"))
+            .arg(clap::Arg::with_name("validator")
+                .long("validator")
+                .help("enable control server and generate console client key pair"))
+            .arg(clap::Arg::with_name("force")
+                .long("force")
+                .help("overwrite existing config.json")))
+        .subcommand(clap::SubCommand::with_name("check-config")
+            .about("check config, keys, internal db directories and optionally connectivity \
+                without starting the node, exit code is nonzero on failure")
+            .arg(clap::Arg::with_name("min_free_space_gb")
+                .long("min-free-space-gb")
+                .value_name("GB")
+                .help("free space required in internal db directories, 50 by default"))
+            .arg(clap::Arg::with_name("ping_dht")
+                .long("ping-dht")
+                .value_name("count")
+                .help("ping this number of static DHT nodes of global config, \
+                    ADNL port must be free")))
+        .subcommand(clap::SubCommand::with_name("keys")
+            .about("move validator keys between nodes")
+            .subcommand(clap::SubCommand::with_name("export")
+                .about("write validator key ring with roles of keys into archive")
+                .arg(clap::Arg::with_name("output")
+                    .long("output")
+                    .required(true)
+                    .value_name("file"))
+                .arg(clap::Arg::with_name("include_node_key")
+                    .long("include-node-key")
+                    .help("export ADNL node keys as well"))
+                .arg(clap::Arg::with_name("include_console_key")
+                    .long("include-console-key")
+                    .help("export control server key and console keys as well"))
+                .arg(clap::Arg::with_name("passphrase_file")
+                    .long("passphrase-file")
+                    .value_name("file")
+                    .help("encrypt archive with passphrase from the file")))
+            .subcommand(clap::SubCommand::with_name("import")
+                .about("put keys from archive into config.json made by init")
+                .arg(clap::Arg::with_name("input")
+                    .long("input")
+                    .required(true)
+                    .value_name("file"))
+                .arg(clap::Arg::with_name("passphrase_file")
+                    .long("passphrase-file")
+                    .value_name("file")
+                    .help("passphrase of encrypted archive"))
+                .arg(clap::Arg::with_name("force")
+                    .long("force")
+                    .help("overwrite existing keys"))))
+        .subcommand(clap::SubCommand::with_name("status")
+            .about("print stats of running node got from its control server, exit code is 2 \
+                if timediff exceeds --max-timediff")
+            .arg(clap::Arg::with_name("ctl_addr")
+                .long("ctl-addr")
+                .value_name("address")
+                .help("control server address, the one of console_config.json by default"))
+            .arg(clap::Arg::with_name("ctl_key")
+                .long("ctl-key")
+                .value_name("file")
+                .help("console client private key, the one of console_config.json by default"))
+            .arg(clap::Arg::with_name("json")
+                .long("json")
+                .help("print JSON instead of table"))
+            .arg(clap::Arg::with_name("max_timediff")
+                .long("max-timediff")
+                .value_name("sec")
+                .help("max seconds since the last applied masterchain block")))
+        .subcommand(clap::SubCommand::with_name("dump")
+            .about("print block or shard state of internal DB as JSON, the DB is opened \
+                read-only and may be used by running node")
+            .subcommand(dump_subcommand("block", "print block"))
+            .subcommand(dump_subcommand("state", "print shard state after the block")))
+        .subcommand(clap::SubCommand::with_name("replay-bundle")
+            .about("collate and validate block from collator test bundle offline, \
+                exit code is nonzero if the result differs from the bundle's block")
+            .arg(clap::Arg::with_name("path")
+                .required(true)
+                .value_name("bundle dir"))
+            .arg(clap::Arg::with_name("collate_only")
+                .long("collate-only")
+                .conflicts_with("validate_only"))
+            .arg(clap::Arg::with_name("validate_only")
+                .long("validate-only")));
 
     let matches = app.get_matches();
 
+    if let Some(matches) = matches.subcommand_matches("replay-bundle") {
+        config_reload::init_logger(None);
+        std::process::exit(replay_bundle(matches));
+    }
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        let config_dir = matches.value_of("config").unwrap_or("./");
+        std::process::exit(init(config_dir, init_matches));
+    }
+    if let Some(keys_matches) = matches.subcommand_matches("keys") {
+        let config_dir = matches.value_of("config").unwrap_or("./");
+        std::process::exit(keys(config_dir, keys_matches));
+    }
+    if let Some(dump_matches) = matches.subcommand_matches("dump") {
+        let config_dir = matches.value_of("config").unwrap_or("./");
+        std::process::exit(dump(config_dir, dump_matches));
+    }
+    if let Some(status_matches) = matches.subcommand_matches("status") {
+        let config_dir = matches.value_of("config").unwrap_or("./");
+        std::process::exit(status(config_dir, status_matches));
+    }
+    if let Some(check_matches) = matches.subcommand_matches("check-config") {
+        let config_dir = matches.value_of("config").unwrap_or("./");
+        std::process::exit(check_config(config_dir, check_matches));
+    }
+
     let initial_sync_disabled = matches.is_present("initial_sync_disabled");
+    let restore_from_backup = matches.value_of("restore_from_backup");
+    // Restored database is checked as with --check-db
+    let check_db = matches.is_present("check_db") || restore_from_backup.is_some();
+    let migrate_db_layout = matches.is_present("migrate_db_layout");
+    let read_only = matches.is_present("read_only");
+    let import_archives = matches.value_of("import_archives");
 
     let config_dir_path = match matches.value_of("config") {
         Some(config) => {
@@ -189,10 +583,21 @@ fn main() {
         }
     };
 
+    if matches.is_present("encrypt_keys") {
+        match TonNodeConfig::encrypt_keys_in_file(config_dir_path, CONFIG_NAME) {
+            Ok(count) => println!("{} key(s) of validator key ring are encrypted", count),
+            Err(e) => {
+                println!("Can't encrypt keys: {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     let console_key = matches.value_of("console_key").map(|console_key| console_key.to_string());
 
     let zerostate_path = matches.value_of("zerostate");
-    let config = match TonNodeConfig::from_file(
+    let mut config = match TonNodeConfig::from_file(
         config_dir_path, 
         CONFIG_NAME, 
         None,
@@ -200,28 +605,69 @@ fn main() {
         console_key
     ) {
         Err(e) => {
-            println!("Can't load config: {:?}", e);
-            return;
+            println!("Can't load config: {}", e);
+            std::process::exit(1);
         },
         Ok(c) => c
     };
+    if let Some(public_ip) = matches.value_of("public_ip") {
+        let allow_private_ip = matches.is_present("allow_private_ip");
+        if let Err(e) = config.set_public_address(public_ip, allow_private_ip) {
+            println!("Invalid --public-ip: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-    init_logger(config.log_config_path());
+    config_reload::init_logger(Some(&config));
     log_version();
+    for env_override in config.env_overrides() {
+        log::info!("Config field is overridden by environment: {}", env_override);
+    }
+    if let Some(seed) = config.test_rng_seed() {
+        log::warn!("Subsystems use deterministic randomness with seed {}, for test runs only", seed);
+    }
+    rng::init(config.test_rng_seed());
+    match config.advertised_address() {
+        Ok(address) if matches.is_present("public_ip") => {
+            log::info!("Advertised ADNL address: {} (set by --public-ip)", address)
+        }
+        Ok(address) => log::info!("Advertised ADNL address: {}", address),
+        Err(e) => log::warn!("Can't determine advertised ADNL address: {}", e)
+    }
+
+    if let Some(backup_path) = restore_from_backup {
+        if let Err(e) = crate::internal_db::backup::restore_backup(backup_path, &config) {
+            log::error!("Can't restore internal db from backup {}: {:?}", backup_path, e);
+            return;
+        }
+    }
     
     lazy_static::initialize(&STATSD);
+    STATSD.configure(config.metrics_config());
     
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .thread_stack_size(8 * 1024 * 1024)
-        .build()
-        .expect("Can't create tokio runtime");
+    log::info!("Resource profile: {}", config.resource_profile_summary());
+    log::info!("Node runtime: {}", config.runtime_config());
+    let runtime = config.runtime_config().build().expect("Can't create tokio runtime");
 
-    init_jaeger();
+    init_jaeger(config.tracing_config());
     
+    let shutdown_timeout = Duration::from_secs(config.shutdown_config().timeout_sec as u64);
     runtime.block_on(async move {
-        if let Err(e) = start_engine(config, zerostate_path, initial_sync_disabled).await {
+        if let Err(e) = shutdown::listen_signals(shutdown_timeout) {
+            log::error!("Can't listen for shutdown signals: {}", e);
+        }
+        if let Err(e) = start_engine(
+            config, zerostate_path, initial_sync_disabled, check_db, migrate_db_layout, read_only,
+            import_archives
+        ).await {
             log::error!("Can't start node's Engine: {:?}", e);
+            // Whatever is started already is drained, the node doesn't stay half-alive
+            let reason = format!("engine failed: {}", e);
+            if shutdown::SHUTDOWN.request_with_exit_code(&reason, 1) && shutdown::SHUTDOWN.is_handled() {
+                shutdown::SHUTDOWN.wait_finished().await;
+            }
+            log::logger().flush();
+            std::process::exit(1);
         }
     });
 }
@@ -0,0 +1,251 @@
+use crate::config::{MetricsConfig, MetricsTagsFormat};
+use std::{net::UdpSocket, sync::RwLock};
+use ton_block::ShardIdent;
+use ton_types::Result;
+
+struct MetricsSettings {
+    format: MetricsTagsFormat,
+    global_tags: Vec<(&'static str, String)>,
+}
+
+// Statsd client. Tags are sent with DogStatsD extension ("name:1|c|#tag:value"),
+// or put into metric name for plain statsd: global ones as prefix, per-metric ones
+// as suffix, with characters not allowed in names replaced.
+pub struct MetricsClient {
+    socket: Option<UdpSocket>,
+    prefix: String,
+    settings: RwLock<MetricsSettings>,
+}
+
+impl MetricsClient {
+
+    pub fn with_address(address: &str, prefix: &str) -> Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::with_socket(Some(socket), prefix))
+    }
+
+    pub fn disabled() -> Self {
+        Self::with_socket(None, "")
+    }
+
+    fn with_socket(socket: Option<UdpSocket>, prefix: &str) -> Self {
+        Self {
+            socket,
+            prefix: prefix.to_string(),
+            settings: RwLock::new(MetricsSettings {
+                format: MetricsTagsFormat::default(),
+                global_tags: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn configure(&self, config: &MetricsConfig) {
+        let mut global_tags = Vec::new();
+        if let Some(node_name) = &config.node_name {
+            global_tags.push(("node", node_name.clone()));
+        }
+        if let Some(network_id) = &config.network_id {
+            global_tags.push(("network", network_id.clone()));
+        }
+        *self.settings.write().unwrap() = MetricsSettings {
+            format: config.tags_format,
+            global_tags,
+        };
+    }
+
+    pub fn tag(&self, key: &'static str, value: impl ToString) -> TaggedMetrics {
+        TaggedMetrics { client: self, tags: Vec::new() }.tag(key, value)
+    }
+
+    // Workchain and shard tags, "{workchain}_{shard:016x}" suffix in plain mode
+    pub fn shard(&self, shard: &ShardIdent) -> TaggedMetrics {
+        TaggedMetrics { client: self, tags: Vec::new() }.shard(shard)
+    }
+
+    pub fn pipeline(&self) -> MetricsPipeline {
+        MetricsPipeline { client: self, tags: Vec::new(), lines: Vec::new() }
+    }
+
+    pub fn incr(&self, name: &str) {
+        self.send(&self.format(name, "1", "c", &[]))
+    }
+
+    pub fn decr(&self, name: &str) {
+        self.send(&self.format(name, "-1", "c", &[]))
+    }
+
+    pub fn count(&self, name: &str, value: f64) {
+        self.send(&self.format(name, &value.to_string(), "c", &[]))
+    }
+
+    pub fn gauge(&self, name: &str, value: f64) {
+        self.send(&self.format(name, &value.to_string(), "g", &[]))
+    }
+
+    pub fn timer(&self, name: &str, value: f64) {
+        self.send(&self.format(name, &value.to_string(), "ms", &[]))
+    }
+
+    pub fn histogram(&self, name: &str, value: f64) {
+        self.send(&self.format(name, &value.to_string(), "h", &[]))
+    }
+
+    fn format(&self, name: &str, value: &str, kind: &str, tags: &[(&'static str, String)]) -> String {
+        let settings = self.settings.read().unwrap();
+        let mut line = self.prefix.clone();
+        match settings.format {
+            MetricsTagsFormat::Datadog => {
+                line.push('.');
+                line.push_str(name);
+                line.push_str(&format!(":{}|{}", value, kind));
+                let mut all_tags = settings.global_tags.iter().chain(tags.iter());
+                if let Some((key, value)) = all_tags.next() {
+                    line.push_str(&format!("|#{}:{}", key, value));
+                    for (key, value) in all_tags {
+                        line.push_str(&format!(",{}:{}", key, value));
+                    }
+                }
+            }
+            MetricsTagsFormat::Plain => {
+                for (_, value) in settings.global_tags.iter() {
+                    line.push('.');
+                    line.push_str(&plain_tag_value(value));
+                }
+                line.push('.');
+                line.push_str(name);
+                for (_, value) in tags {
+                    line.push('_');
+                    line.push_str(&plain_tag_value(value));
+                }
+                line.push_str(&format!(":{}|{}", value, kind));
+            }
+        }
+        line
+    }
+
+    fn send(&self, data: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.send(data.as_bytes()) {
+                log::trace!("Can't send metrics: {}", e);
+            }
+        }
+    }
+}
+
+// Separators of statsd line and of name's hierarchy must not come from tag value
+fn plain_tag_value(value: &str) -> String {
+    value.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+pub struct TaggedMetrics<'a> {
+    client: &'a MetricsClient,
+    tags: Vec<(&'static str, String)>,
+}
+
+impl<'a> TaggedMetrics<'a> {
+
+    pub fn tag(mut self, key: &'static str, value: impl ToString) -> Self {
+        self.tags.push((key, value.to_string()));
+        self
+    }
+
+    pub fn shard(self, shard: &ShardIdent) -> Self {
+        self.tag("workchain", shard.workchain_id())
+            .tag("shard", format!("{:016x}", shard.shard_prefix_with_tag()))
+    }
+
+    pub fn pipeline(self) -> MetricsPipeline<'a> {
+        MetricsPipeline { client: self.client, tags: self.tags, lines: Vec::new() }
+    }
+
+    pub fn incr(&self, name: &str) {
+        self.client.send(&self.client.format(name, "1", "c", &self.tags))
+    }
+
+    pub fn decr(&self, name: &str) {
+        self.client.send(&self.client.format(name, "-1", "c", &self.tags))
+    }
+
+    pub fn gauge(&self, name: &str, value: f64) {
+        self.client.send(&self.client.format(name, &value.to_string(), "g", &self.tags))
+    }
+
+    pub fn timer(&self, name: &str, value: f64) {
+        self.client.send(&self.client.format(name, &value.to_string(), "ms", &self.tags))
+    }
+}
+
+// Several metrics sent in one packet
+pub struct MetricsPipeline<'a> {
+    client: &'a MetricsClient,
+    tags: Vec<(&'static str, String)>,
+    lines: Vec<String>,
+}
+
+impl MetricsPipeline<'_> {
+
+    pub fn incr(&mut self, name: &str) {
+        self.lines.push(self.client.format(name, "1", "c", &self.tags))
+    }
+
+    pub fn gauge(&mut self, name: &str, value: f64) {
+        self.lines.push(self.client.format(name, &value.to_string(), "g", &self.tags))
+    }
+
+    pub fn timer(&mut self, name: &str, value: f64) {
+        self.lines.push(self.client.format(name, &value.to_string(), "ms", &self.tags))
+    }
+
+    pub fn send(self) {
+        if !self.lines.is_empty() {
+            self.client.send(&self.lines.join("\n"))
+        }
+    }
+}
+
+#[test]
+fn test_metrics_tags_format() {
+    let client = MetricsClient::with_socket(None, "rnode");
+    let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    let tags = client.shard(&shard).tag("reason", "bad_proof").tags;
+    assert_eq!(client.format("gas_used", "10", "g", &[]), "rnode.gas_used:10|g");
+    assert_eq!(
+        client.format("rejected", "1", "c", &tags),
+        "rnode.rejected_0_8000000000000000_bad_proof:1|c"
+    );
+
+    let mut config = MetricsConfig {
+        node_name: Some("node1".to_string()),
+        network_id: Some("main".to_string()),
+        tags_format: MetricsTagsFormat::Datadog,
+    };
+    client.configure(&config);
+    assert_eq!(
+        client.format("gas_used", "10", "g", &[]), "rnode.gas_used:10|g|#node:node1,network:main"
+    );
+    assert_eq!(
+        client.format("rejected", "1", "c", &tags),
+        "rnode.rejected:1|c|#node:node1,network:main,workchain:0,shard:8000000000000000,reason:bad_proof"
+    );
+
+    config.tags_format = MetricsTagsFormat::Plain;
+    client.configure(&config);
+    assert_eq!(client.format("gas_used", "10", "g", &[]), "rnode.node1.main.gas_used:10|g");
+    assert_eq!(
+        client.format("rejected", "1", "c", &tags),
+        "rnode.node1.main.rejected_0_8000000000000000_bad_proof:1|c"
+    );
+
+    // Tag values can't break name's hierarchy or statsd line
+    config.node_name = Some("dc1.node:1".to_string());
+    client.configure(&config);
+    let tags = client.tag("status", "ok|#x y").tags;
+    assert_eq!(
+        client.format("validator_group_status", "1", "c", &tags),
+        "rnode.dc1_node_1.main.validator_group_status_ok__x_y:1|c"
+    );
+}
@@ -0,0 +1,166 @@
+use crate::{config::BandwidthLimitsConfig, engine::STATSD};
+use std::{
+    sync::{Mutex, atomic::{AtomicU64, Ordering}},
+    time::{Duration, Instant}
+};
+
+// Period of throughput measurement
+const THROUGHPUT_WINDOW_MS: u64 = 1000;
+
+struct Bucket {
+    // Bytes allowed to pass now, negative if taken in advance
+    tokens: f64,
+    refilled_at: Instant,
+    window_bytes: u64,
+    window_started: Instant,
+}
+
+// Token bucket limiter of data flow. The bucket holds up to one second of traffic.
+// Portion bigger than available tokens passes at once, and the caller waits until
+// the debt is refilled, so following portions are delayed too.
+pub struct BandwidthLimiter {
+    name: &'static str,
+    // Bytes per second, 0 - unlimited
+    rate: AtomicU64,
+    bucket: Mutex<Bucket>,
+    throughput: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl BandwidthLimiter {
+
+    pub fn new(name: &'static str, rate: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            name,
+            rate: AtomicU64::new(rate),
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled_at: now,
+                window_bytes: 0,
+                window_started: now,
+            }),
+            throughput: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    pub fn set_rate(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+    }
+
+    // Bytes per second measured in the last full window
+    pub fn throughput(&self) -> u64 {
+        self.throughput.load(Ordering::Relaxed)
+    }
+
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    // Accounts `bytes` and waits if the limit is exceeded
+    pub async fn consume(&self, bytes: usize) {
+        if let Some(delay) = self.take(bytes as u64, Instant::now()) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            STATSD.incr(&format!("bandwidth_{}_throttled", self.name));
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // Returns time to wait until taken tokens are refilled
+    fn take(&self, bytes: u64, now: Instant) -> Option<Duration> {
+        let rate = self.rate();
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.window_bytes += bytes;
+        let window = now.saturating_duration_since(bucket.window_started).as_millis() as u64;
+        if window >= THROUGHPUT_WINDOW_MS {
+            let throughput = bucket.window_bytes * 1000 / window;
+            self.throughput.store(throughput, Ordering::Relaxed);
+            STATSD.gauge(&format!("bandwidth_{}_rate", self.name), throughput as f64);
+            bucket.window_bytes = 0;
+            bucket.window_started = now;
+        }
+        let elapsed = now.saturating_duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.refilled_at = now;
+        if rate == 0 {
+            bucket.tokens = 0.0;
+            return None
+        }
+        bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64) - bytes as f64;
+        if bucket.tokens >= 0.0 {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-bucket.tokens / rate as f64))
+        }
+    }
+
+    fn status(&self) -> String {
+        let rate = match self.rate() {
+            0 => "unlimited".to_string(),
+            rate => format!("{} KB/s", rate / 1024)
+        };
+        format!(
+            "{}: limit {}, throughput {} KB/s, throttled {} times",
+            self.name, rate, self.throughput() / 1024, self.throttled()
+        )
+    }
+}
+
+// Limits of archive sync and persistent state downloads, and of archive and persistent
+// state slices served to other nodes
+pub struct BandwidthLimits {
+    pub download: BandwidthLimiter,
+    pub serve: BandwidthLimiter,
+}
+
+impl BandwidthLimits {
+
+    pub fn new(config: &BandwidthLimitsConfig) -> Self {
+        Self {
+            download: BandwidthLimiter::new("download", config.download_kb_per_sec * 1024),
+            serve: BandwidthLimiter::new("serve", config.serve_kb_per_sec * 1024),
+        }
+    }
+
+    // KB/s, 0 - unlimited
+    pub fn set(&self, download_kb_per_sec: u64, serve_kb_per_sec: u64) {
+        self.download.set_rate(download_kb_per_sec * 1024);
+        self.serve.set_rate(serve_kb_per_sec * 1024);
+        log::info!(
+            "Bandwidth limits are set: download {} KB/s, serve {} KB/s (0 - unlimited)",
+            download_kb_per_sec, serve_kb_per_sec
+        );
+    }
+
+    pub fn status(&self) -> String {
+        format!("{}; {}", self.download.status(), self.serve.status())
+    }
+}
+
+#[test]
+fn test_bandwidth_limiter() {
+    let limiter = BandwidthLimiter::new("test", 1000);
+    let start = limiter.bucket.lock().unwrap().refilled_at;
+
+    // Full bucket passes at once, the debt is waited for
+    assert_eq!(limiter.take(1000, start), None);
+    assert_eq!(limiter.take(500, start), Some(Duration::from_millis(500)));
+    // Refill covers the debt only
+    let now = start + Duration::from_millis(500);
+    assert_eq!(limiter.take(0, now), None);
+    assert_eq!(limiter.take(2000, now), Some(Duration::from_secs(2)));
+    // Bucket doesn't grow above one second of traffic
+    let now = now + Duration::from_secs(10);
+    assert_eq!(limiter.take(1500, now), Some(Duration::from_millis(500)));
+    assert_eq!(limiter.throughput(), 5000 * 1000 / 10500);
+
+    limiter.set_rate(0);
+    assert_eq!(limiter.take(1 << 30, now), None);
+    limiter.set_rate(1000);
+    assert_eq!(limiter.take(100, now + Duration::from_millis(100)), None);
+    assert_eq!(limiter.take(100, now + Duration::from_millis(100)), Some(Duration::from_millis(100)));
+}
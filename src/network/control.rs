@@ -1,27 +1,38 @@
 use crate::{
-    block::convert_block_id_ext_api2blk,
+    block::{convert_block_id_ext_api2blk, convert_block_id_ext_blk2api},
     collator_test_bundle::CollatorTestBundle,
-    config::{KeyRing, NodeConfigHandler},
-    engine_traits::EngineOperations,
+    config::{
+        BandwidthLimitsConfig, ConsoleKeyJson, ConsoleRole, KeyRing, NodeConfigHandler,
+        StatesGcPolicy
+    },
+    engine_traits::EngineOperations, error::{code_of, ControlError, NodeError},
+    ext_messages::{create_ext_message, ExtMessageStatus},
+    network::control_ext::{ControlAnswerExt, ControlQueryExt}, shutdown::SHUTDOWN,
     validator::validator_utils::validatordescr_to_catchain_node
 };
 use adnl::{
-    common::{deserialize, QueryResult, Subscriber, AdnlPeers},
+    common::{deserialize, Answer, QueryResult, Subscriber, AdnlPeers, TaggedByteVec},
     server::{AdnlServer, AdnlServerConfig}
 };
 use std::{ops::Deref, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 use ton_api::ton::{
     self, PublicKey, TLObject,
     engine::validator::{
-        keyhash::KeyHash, onestat::OneStat, signature::Signature, stats::Stats, Success
+        controlqueryerror, keyhash::KeyHash, onestat::OneStat, signature::Signature, stats::Stats,
+        ControlQueryError, Success
     },
+    lite_server::{BlockHeader, blockheader},
     rpc::engine::validator::{
         AddAdnlId, AddValidatorAdnlAddress, AddValidatorPermanentKey, AddValidatorTempKey, 
         ControlQuery, ExportPublicKey, GenerateKeyPair, Sign, GetBundle, GetFutureBundle,
     }
 };
-use ton_types::{fail, error, Result};
-use ton_block::BlockIdExt;
+use ton_types::{fail, error, Result, UInt256};
+use ton_block::{AccountIdPrefixFull, BlockIdExt, ShardIdent};
+
+const EXT_MESSAGES_QUEUE_HEAD_LEN: usize = 16;
+const EXT_MESSAGES_RECENT_STATUSES_LEN: usize = 32;
+const MAX_ACCOUNT_TRANSACTIONS: u32 = 256;
 
 pub struct ControlServer {
     adnl: AdnlServer
@@ -47,6 +58,13 @@ impl ControlServer {
     }
 }
 
+fn save_bundle(bundle: CollatorTestBundle, level: i32) {
+    match bundle.save("target/bundles", Some(level)) {
+        Ok(size) => log::info!("Test bundle saved, {} bytes", size),
+        Err(e) => log::warn!("Error while test bundle saving: {}", e)
+    }
+}
+
 struct ControlQuerySubscriber {
     engine: Option<Arc<dyn EngineOperations>>,
     key_ring: Arc<dyn KeyRing>, 
@@ -75,8 +93,9 @@ impl ControlQuerySubscriber {
         if let Some(engine) = self.engine.as_ref() {
             let mut stats: ton::vector<ton::Bare, OneStat> = ton::vector::default();
 
-            let mc_block_id = if let Some(id) = engine.load_last_applied_mc_block_id()? {
-                id
+            let last_mc_block = engine.load_last_applied_mc_block_time()?;
+            let (mc_block_id, mc_block_time) = if let Some(last) = last_mc_block {
+                last
             } else {
                 stats.0.push(OneStat {
                     key: "masterchainblock".to_string(),
@@ -86,12 +105,9 @@ impl ControlQuerySubscriber {
             };
 
             // masterchainblocktime
-            let mc_block_handle = engine.load_block_handle(&mc_block_id)?.ok_or_else(
-                || error!("Cannot load handle for block {}", &mc_block_id)
-            )?;
             stats.0.push(OneStat {
                 key: "masterchainblocktime".to_string(),
-                value: mc_block_handle.gen_utime()?.to_string()
+                value: mc_block_time.to_string()
             });
 
             // masterchainblocknumber
@@ -102,7 +118,7 @@ impl ControlQuerySubscriber {
 
             // timediff
             let diff = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i32 - 
-                mc_block_handle.gen_utime()? as i32;
+                mc_block_time as i32;
 
             stats.0.push(OneStat {
                 key: "timediff".to_string(),
@@ -111,7 +127,7 @@ impl ControlQuerySubscriber {
 
             // in_current_vset_p34
             let adnl_ids = self.config.get_actual_validator_adnl_ids()?;
-            let mc_state = engine.load_last_applied_mc_state().await?;
+            let mc_state = engine.load_state_cached(&mc_block_id).await?;
             let current = mc_state.config_params()?.validator_set()?.list().iter().any(|val| {
                 match validatordescr_to_catchain_node(val) {
                     Ok(catchain_node) => adnl_ids.contains(&catchain_node.adnl_id),
@@ -135,6 +151,140 @@ impl ControlQuerySubscriber {
                 value: next.to_string()
             });
 
+            // validator_adnl_unreachable
+            stats.0.push(OneStat {
+                key: "validator_adnl_unreachable".to_string(),
+                value: engine.validator_adnl_unreachable().to_string()
+            });
+
+            // ext_messages_stats
+            stats.0.push(OneStat {
+                key: "ext_messages_stats".to_string(),
+                value: engine.external_messages_stats().to_string()
+            });
+
+            // ext_messages_queue_head
+            let mut head = String::new();
+            for (id, score) in engine.external_messages_queue_head(EXT_MESSAGES_QUEUE_HEAD_LEN) {
+                head.push_str(&format!("{:x} score: {}\n", id, score));
+            }
+            stats.0.push(OneStat {
+                key: "ext_messages_queue_head".to_string(),
+                value: head
+            });
+
+            // ext_messages_statuses
+            let mut statuses = String::new();
+            for (id, status, updated_at) in engine.external_messages_recent_statuses(EXT_MESSAGES_RECENT_STATUSES_LEN) {
+                statuses.push_str(&format!("{:x} {} (at {})\n", id, status, updated_at));
+            }
+            stats.0.push(OneStat {
+                key: "ext_messages_statuses".to_string(),
+                value: statuses
+            });
+
+            // external_db_stats
+            stats.0.push(OneStat {
+                key: "external_db_stats".to_string(),
+                value: engine.external_db_stats().join("\n")
+            });
+
+            // external_db_health
+            stats.0.push(OneStat {
+                key: "external_db_health".to_string(),
+                value: engine.external_db_health()
+            });
+
+            // external_db_filtered_records
+            stats.0.push(OneStat {
+                key: "external_db_filtered_records".to_string(),
+                value: engine.external_db_filtered_records().to_string()
+            });
+
+            // db_compaction
+            stats.0.push(OneStat {
+                key: "db_compaction".to_string(),
+                value: engine.db_compaction_status()
+            });
+
+            // db_backup
+            stats.0.push(OneStat {
+                key: "db_backup".to_string(),
+                value: engine.db_backup_status()
+            });
+
+            // persistent_state_downloads
+            stats.0.push(OneStat {
+                key: "persistent_state_downloads".to_string(),
+                value: engine.state_downloads_status()
+            });
+
+            // bandwidth_limits
+            stats.0.push(OneStat {
+                key: "bandwidth_limits".to_string(),
+                value: engine.bandwidth_limits_status()
+            });
+
+            // tracked_shards
+            stats.0.push(OneStat {
+                key: "tracked_shards".to_string(),
+                value: engine.tracked_shards_status()
+            });
+
+            // shard_lags
+            stats.0.push(OneStat {
+                key: "shard_lags".to_string(),
+                value: engine.shard_lags_status()
+            });
+
+            // tasks
+            stats.0.push(OneStat {
+                key: "tasks".to_string(),
+                value: engine.tasks_status()
+            });
+
+            // sync_progress
+            stats.0.push(OneStat {
+                key: "sync_progress".to_string(),
+                value: engine.sync_progress_status()
+            });
+
+            // key_block_prefetch
+            stats.0.push(OneStat {
+                key: "key_block_prefetch".to_string(),
+                value: engine.key_block_prefetcher().status()
+            });
+
+            // apply_stages
+            stats.0.push(OneStat {
+                key: "apply_stages".to_string(),
+                value: engine.apply_stages_status()
+            });
+
+            // states_gc
+            stats.0.push(OneStat {
+                key: "states_gc".to_string(),
+                value: engine.states_gc_status()
+            });
+
+            // states_cache
+            stats.0.push(OneStat {
+                key: "states_cache".to_string(),
+                value: engine.states_cache_status()
+            });
+
+            // runtime
+            stats.0.push(OneStat {
+                key: "runtime".to_string(),
+                value: engine.runtime_status()
+            });
+
+            // persistent_states
+            stats.0.push(OneStat {
+                key: "persistent_states".to_string(),
+                value: engine.persistent_states_status()
+            });
+
             let value = match engine.load_last_applied_mc_state_or_zerostate().await {
                 Ok(mc_state) => mc_state.block_id().to_string(),
                 Err(err) => err.to_string()
@@ -151,48 +301,22 @@ impl ControlQuerySubscriber {
             stats.0.push(OneStat { key, value });
 
             // validation_stats
-            let validation_stats = engine.validation_status();
-
-            let mut stat = String::new();
-            let ago = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?.as_secs();
-
-            for item in validation_stats.iter() {
-                stat.push_str("shard: ");
-                stat.push_str(&item.key().to_string()); 
-                stat.push_str(" - ");
-                if item.val() == &0 {
-                    stat.push_str("never");
-                } else {
-                    stat.push_str(&(ago - item.val()).to_string());
-                    stat.push_str(" sec ago");
-                }
-                stat.push_str("\n");
-            }
-
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
             stats.0.push(OneStat {
                 key: "validation_stats".to_string(), 
-                value: stat.to_string()
+                value: engine.validation_status().status(now)
             });
 
             // collation_stats
-            let mut stat = String::new();
-            let collation_stats = engine.collation_status();
-            for item in collation_stats.iter() {
-                stat.push_str("shard: ");
-                stat.push_str(&item.key().to_string()); 
-                stat.push_str(" - ");
-                if item.val() == &0 {
-                    stat.push_str("never");
-                } else {
-                    stat.push_str(&(ago - item.val()).to_string());
-                    stat.push_str(" sec ago");
-                }
-                stat.push_str("\n");
-            }
-
             stats.0.push(OneStat {
                 key: "collation_stats".to_string(), 
-                value: stat.to_string()
+                value: engine.collation_status().status(now)
+            });
+
+            // validation_history
+            stats.0.push(OneStat {
+                key: "validation_history".to_string(),
+                value: engine.validation_history_status()
             });
 
             Ok(Stats {stats})
@@ -227,53 +351,407 @@ impl ControlQuerySubscriber {
     fn add_adnl_address(&self, _key_hash: &[u8; 32], _category: ton::int) -> Result<Success> {
         Ok(Success::Engine_Validator_Success)
     }
+    // New key can connect after restart, the last admin key can't be removed
+    async fn add_console_key(&self, key: &[u8; 32], role: ConsoleRole) -> Result<Success> {
+        self.config.add_console_key(ConsoleKeyJson { pub_key: base64::encode(key), role }).await?;
+        Ok(Success::Engine_Validator_Success)
+    }
+    async fn remove_console_key(&self, key_hash: &[u8; 32]) -> Result<Success> {
+        self.config.remove_console_key(key_hash).await?;
+        Ok(Success::Engine_Validator_Success)
+    }
     async fn prepare_bundle(&self, block_id: BlockIdExt) -> Result<Success> {
         if let Some(engine) = self.engine.as_ref() {
+            engine.check_shard_tracked(block_id.shard())?;
             let bundle = CollatorTestBundle::build_with_ethalon(&block_id, engine.deref()).await?;
-            tokio::task::spawn_blocking(move || {
-                bundle.save("target/bundles").ok();
-            });
+            let level = engine.test_bundles_config().collator.compression_level();
+            tokio::task::spawn_blocking(move || save_bundle(bundle, level));
         }
         Ok(Success::Engine_Validator_Success)
     }
     async fn prepare_future_bundle(&self, prev_block_ids: Vec<BlockIdExt>) -> Result<Success> {
         if let Some(engine) = self.engine.as_ref() {
+            for id in prev_block_ids.iter() {
+                engine.check_shard_tracked(id.shard())?;
+            }
             let bundle = CollatorTestBundle::build_for_collating_block(prev_block_ids, engine.deref()).await?;
-            tokio::task::spawn_blocking(move || {
-                bundle.save("target/bundles").ok();
-            });
+            let level = engine.test_bundles_config().collator.compression_level();
+            tokio::task::spawn_blocking(move || save_bundle(bundle, level));
         }
         Ok(Success::Engine_Validator_Success)
     }
-    async fn redirect_external_message(&self, message_data: &[u8]) -> Result<Success> {
+    async fn redirect_external_message(
+        &self,
+        message_data: &[u8],
+        skip_precheck: bool
+    ) -> Result<Success> {
         if let Some(engine) = self.engine.as_ref() {
-            engine.redirect_external_message(&message_data).await?;
+            let (_, message) = create_ext_message(message_data)?;
+            if let Some(dst) = message.ext_in_header().map(|header| &header.dst) {
+                let prefix = AccountIdPrefixFull::checked_prefix(dst)?;
+                if !engine.is_account_tracked(&prefix) {
+                    fail!("Shard of account {} is not tracked by this node", dst)
+                }
+            }
+            engine.redirect_external_message(&message_data, skip_precheck).await?;
             Ok(Success::Engine_Validator_Success)
         } else {
-            fail!("`engine is not set`")
+            fail!(NodeError::Control(ControlError::NotReady, "engine is not set".to_string()))
         }
     }
 
+    // Only lookup by seqno is supported, the block is looked up in neighbours if not in DB
+    async fn lookup_block(&self, query: ton::rpc::lite_server::LookupBlock) -> Result<BlockHeader> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        if query.mode & 1 == 0 {
+            fail!("Only lookup by seqno is supported")
+        }
+        let shard = ShardIdent::with_tagged_prefix(query.id.workchain, query.id.shard as u64)?;
+        let seqno = query.id.seqno as u32;
+        let id = engine.find_block_by_seqno(&shard, seqno).await?
+            .ok_or_else(|| error!("Block {}:{} is not found", shard, seqno))?;
+        Ok(BlockHeader::LiteServer_BlockHeader(Box::new(blockheader::BlockHeader {
+            id: convert_block_id_ext_blk2api(&id),
+            mode: query.mode,
+            header_proof: ton::bytes(Vec::new())
+        })))
+    }
+
+    // Queries which change nothing, the only ones of read-only node and read-only console key
+    fn filter_read_queries(query: TLObject) -> std::result::Result<TLObject, TLObject> {
+        let query = match query.downcast::<ton::rpc::engine::validator::GetStats>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        let query = match query.downcast::<ExportPublicKey>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        let query = match query.downcast::<ton::rpc::lite_server::LookupBlock>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        Err(query)
+    }
+
+    // Operator console key can do everything except keys management
+    fn filter_operator_queries(query: TLObject) -> std::result::Result<TLObject, TLObject> {
+        let query = match query.downcast::<GetBundle>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        let query = match query.downcast::<GetFutureBundle>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        let query = match query.downcast::<ton::rpc::lite_server::SendMessage>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        let query = match query.downcast::<ton::rpc::engine::validator::SetStatesGcInterval>() {
+            Ok(query) => return Ok(TLObject::new(query)),
+            Err(query) => query
+        };
+        Self::filter_read_queries(query)
+    }
+
     fn set_states_gc_interval(&self, interval_ms: u32) -> Result<Success> {
         if let Some(engine) = self.engine.as_ref() {
             engine.adjust_states_gc_interval(interval_ms);
             self.config.store_states_gc_interval(interval_ms);
             Ok(Success::Engine_Validator_Success)
         } else {
-            fail!("`engine is not set`")
+            fail!(NodeError::Control(ControlError::NotReady, "engine is not set".to_string()))
         }
     }
-}
 
-#[async_trait::async_trait]
-impl Subscriber for ControlQuerySubscriber {
-    async fn try_consume_query(&self, object: TLObject, _peers: &AdnlPeers) -> Result<QueryResult> {
-        log::info!("recieve object (control server): {:?}", object);
-        let query = match object.downcast::<ControlQuery>() {
-            Ok(query) => deserialize(&query.data[..])?,
-            Err(object) => return Ok(QueryResult::Rejected(object))
+    fn external_message_status(&self, hash: &UInt256) -> Result<ControlAnswerExt> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        let (status, updated_at) = engine.external_message_status(hash)
+            .ok_or_else(|| error!("Status of message {:x} is unknown", hash))?;
+        let committed = match &status {
+            ExtMessageStatus::Committed { block_id, tx_hash } => {
+                Some((block_id.clone(), tx_hash.clone()))
+            }
+            _ => None
         };
+        Ok(ControlAnswerExt::MessageStatus {
+            status: status.name().to_string(),
+            description: status.to_string(),
+            updated_at,
+            committed
+        })
+    }
+
+    fn compact_db(&self, db: Option<String>) -> Result<Success> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        if !engine.compact_db(db)? {
+            fail!(NodeError::Control(
+                ControlError::InProgress, "another compaction is in flight".to_string()
+            ))
+        }
+        Ok(Success::Engine_Validator_Success)
+    }
+
+    fn create_backup(&self, path: String) -> Result<Success> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        if !engine.create_backup(path)? {
+            fail!(NodeError::Control(
+                ControlError::InProgress, "another backup is in flight".to_string()
+            ))
+        }
+        Ok(Success::Engine_Validator_Success)
+    }
+
+    fn get_db_stats(&self) -> Result<Stats> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        let mut stats: ton::vector<ton::Bare, OneStat> = ton::vector::default();
+
+        // cells_cache
+        stats.0.push(OneStat {
+            key: "cells_cache".to_string(),
+            value: engine.cells_cache_status()
+        });
+
+        // db_scrubber
+        stats.0.push(OneStat {
+            key: "db_scrubber".to_string(),
+            value: engine.db_scrubber_status()
+        });
+
+        // db_scrubber_quarantine
+        let mut quarantine = String::new();
+        for entry in engine.db_scrubber_quarantine() {
+            quarantine.push_str(&format!(
+                "{} {} (at {}): {}\n",
+                entry.block_id, if entry.is_link { "proof link" } else { "proof" },
+                entry.found_at, entry.error
+            ));
+        }
+        stats.0.push(OneStat {
+            key: "db_scrubber_quarantine".to_string(),
+            value: quarantine
+        });
+
+        Ok(Stats {stats})
+    }
+
+    // What the node runs with
+    fn get_node_info(&self) -> Result<Stats> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        let mut stats: ton::vector<ton::Bare, OneStat> = ton::vector::default();
+
+        // version
+        stats.0.push(OneStat {
+            key: "version".to_string(),
+            value: std::option_env!("CARGO_PKG_VERSION").unwrap_or("Not set").to_string()
+        });
+
+        // commit
+        stats.0.push(OneStat {
+            key: "commit".to_string(),
+            value: std::option_env!("BUILD_GIT_COMMIT").unwrap_or("Not set").to_string()
+        });
+
+        // resource_profile
+        stats.0.push(OneStat {
+            key: "resource_profile".to_string(),
+            value: engine.resource_profile()
+        });
+
+        Ok(Stats {stats})
+    }
+
+    // Budget is applied at once and saved into config
+    fn set_cells_cache_size(&self, size_mb: u64) -> Result<Success> {
+        if let Some(engine) = self.engine.as_ref() {
+            engine.adjust_cells_cache_size(size_mb);
+            self.config.store_cells_cache_size(size_mb);
+            Ok(Success::Engine_Validator_Success)
+        } else {
+            fail!(NodeError::Control(ControlError::NotReady, "engine is not set".to_string()))
+        }
+    }
+
+    async fn account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: u32,
+        with_boc: bool
+    ) -> Result<ControlAnswerExt> {
+        let engine = self.engine.as_ref().ok_or_else(
+            || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+        )?;
+        if limit > MAX_ACCOUNT_TRANSACTIONS {
+            fail!(NodeError::Control(
+                ControlError::BadQuery,
+                format!("Limit of transactions must not exceed {}", MAX_ACCOUNT_TRANSACTIONS)
+            ))
+        }
+        let transactions = engine.load_account_transactions(
+            workchain_id, account, from_lt, limit as usize, with_boc
+        ).await?;
+        Ok(ControlAnswerExt::Transactions(transactions))
+    }
+
+    // Limits are applied at once and saved into config
+    fn set_bandwidth_limits(&self, limits: BandwidthLimitsConfig) -> Result<Success> {
+        if let Some(engine) = self.engine.as_ref() {
+            engine.set_bandwidth_limits(limits.download_kb_per_sec, limits.serve_kb_per_sec);
+            self.config.store_bandwidth_limits(limits);
+            Ok(Success::Engine_Validator_Success)
+        } else {
+            fail!(NodeError::Control(ControlError::NotReady, "engine is not set".to_string()))
+        }
+    }
+
+    // Policy is applied at once and saved into config
+    fn set_states_gc_policy(&self, policy: StatesGcPolicy) -> Result<Success> {
+        if let Some(engine) = self.engine.as_ref() {
+            engine.set_states_gc_policy(&policy);
+            self.config.store_states_gc_policy(policy);
+            Ok(Success::Engine_Validator_Success)
+        } else {
+            fail!(NodeError::Control(ControlError::NotReady, "engine is not set".to_string()))
+        }
+    }
+
+    async fn process_query_ext(&self, query: ControlQueryExt) -> Result<QueryResult> {
+        match query {
+            ControlQueryExt::SendMessage { body, skip_precheck } => QueryResult::consume_boxed(
+                self.redirect_external_message(&body, skip_precheck).await?,
+                None
+            ),
+            ControlQueryExt::GetMessageStatus { hash } => {
+                raw_answer(self.external_message_status(&hash)?)
+            }
+            ControlQueryExt::CompactDb { db } => {
+                QueryResult::consume_boxed(self.compact_db(db)?, None)
+            }
+            ControlQueryExt::SetStatesGcPolicy { policy } => {
+                QueryResult::consume_boxed(self.set_states_gc_policy(policy)?, None)
+            }
+            ControlQueryExt::GetDbStats => QueryResult::consume_boxed(
+                ton_api::ton::engine::validator::Stats::Engine_Validator_Stats(
+                    Box::new(self.get_db_stats()?)
+                ),
+                None
+            ),
+            ControlQueryExt::GetNodeInfo => QueryResult::consume_boxed(
+                ton_api::ton::engine::validator::Stats::Engine_Validator_Stats(
+                    Box::new(self.get_node_info()?)
+                ),
+                None
+            ),
+            ControlQueryExt::SetCellsCacheSize { size_mb } => {
+                QueryResult::consume_boxed(self.set_cells_cache_size(size_mb)?, None)
+            }
+            ControlQueryExt::GetAccountTransactions {
+                workchain_id, account, from_lt, limit, with_boc
+            } => raw_answer(
+                self.account_transactions(workchain_id, &account, from_lt, limit, with_boc).await?
+            ),
+            ControlQueryExt::CreateBackup { path } => {
+                QueryResult::consume_boxed(self.create_backup(path)?, None)
+            }
+            ControlQueryExt::SetBandwidthLimits { limits } => {
+                QueryResult::consume_boxed(self.set_bandwidth_limits(limits)?, None)
+            }
+            ControlQueryExt::GetPeerScores => {
+                let engine = self.engine.as_ref().ok_or_else(
+                    || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+                )?;
+                let scores = engine.peer_scores().into_iter()
+                    .map(|(peer, score)| (*peer.data(), score))
+                    .collect();
+                raw_answer(ControlAnswerExt::PeerScores(scores))
+            }
+            ControlQueryExt::ReloadConfig => {
+                let engine = self.engine.as_ref().ok_or_else(
+                    || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+                )?;
+                // Config files are read off the runtime
+                let engine = engine.clone();
+                let report = tokio::task::spawn_blocking(move || engine.reload_config()).await??;
+                log::info!("Config reloaded by control query: {}", report);
+                raw_answer(ControlAnswerExt::ConfigReloaded(report))
+            }
+            ControlQueryExt::ReloadGlobalConfig => {
+                let engine = self.engine.as_ref().ok_or_else(
+                    || NodeError::Control(ControlError::NotReady, "engine is not set".to_string())
+                )?;
+                let engine = engine.clone();
+                let update = tokio::task::spawn_blocking(move || engine.reload_global_config()).await??;
+                log::info!("Global config reloaded by control query, DHT static nodes: {}", update);
+                raw_answer(ControlAnswerExt::DhtStaticNodesUpdated(update))
+            }
+            ControlQueryExt::Shutdown => {
+                if !SHUTDOWN.request("shutdown control query") {
+                    fail!(NodeError::Control(
+                        ControlError::InProgress,
+                        format!("shutdown is in progress: {}", SHUTDOWN.stage())
+                    ))
+                }
+                QueryResult::consume_boxed(Success::Engine_Validator_Success, None)
+            }
+            ControlQueryExt::AddConsoleKey { key, role } => {
+                QueryResult::consume_boxed(self.add_console_key(&key, role).await?, None)
+            }
+            ControlQueryExt::RemoveConsoleKey { key_hash } => {
+                QueryResult::consume_boxed(self.remove_console_key(&key_hash).await?, None)
+            }
+        }
+    }
+
+    async fn process_query(&self, data: &[u8], peers: &AdnlPeers) -> Result<QueryResult> {
+        let not_allowed = |reason: String| NodeError::Control(ControlError::NotAllowed, reason);
+        let role = self.config.console_role(peers.other().data())
+            .ok_or_else(|| not_allowed(format!("Console key {} is not allowed", peers.other())))?;
+        let read_only = self.engine.as_ref().map(|engine| engine.is_read_only()).unwrap_or(false);
+        let query = ControlQueryExt::deserialize(data)
+            .map_err(|e| NodeError::Control(ControlError::BadQuery, e.to_string()))?;
+        if let Some(query) = query {
+            log::info!("query (control server): {:?}", query);
+            if read_only && !query.is_allowed(ConsoleRole::ReadOnly) {
+                fail!(not_allowed(format!("ControlQuery {:?} is not allowed in read-only mode", query)))
+            }
+            if !query.is_allowed(role) {
+                fail!(not_allowed(format!("ControlQuery {:?} is not allowed for {:?} key", query, role)))
+            }
+            return self.process_query_ext(query).await
+        }
+        let query = deserialize(data)
+            .map_err(|e| NodeError::Control(ControlError::BadQuery, e.to_string()))?;
         log::info!("query (control server): {:?}", query);
+        let query = if read_only {
+            Self::filter_read_queries(query).map_err(
+                |query| not_allowed(format!("ControlQuery {:?} is not allowed in read-only mode", query))
+            )?
+        } else {
+            match role {
+                ConsoleRole::Admin => query,
+                ConsoleRole::Operator => Self::filter_operator_queries(query).map_err(|query| {
+                    not_allowed(format!("ControlQuery {:?} is not allowed for operator key", query))
+                })?,
+                ConsoleRole::ReadOnly => Self::filter_read_queries(query).map_err(|query| not_allowed(
+                    format!("ControlQuery {:?} is not allowed for read-only key", query)
+                ))?
+            }
+        };
         let query = match query.downcast::<GenerateKeyPair>() {
             Ok(_) => return QueryResult::consume(self.process_generate_keypair().await?, None),
             Err(query) => query
@@ -349,7 +827,7 @@ impl Subscriber for ControlQuerySubscriber {
             Ok(query) => {
                 let message_data = query.body.0;
                 return QueryResult::consume_boxed(
-                    self.redirect_external_message(&message_data).await?,
+                    self.redirect_external_message(&message_data, false).await?,
                     None
                 )
             }
@@ -366,6 +844,10 @@ impl Subscriber for ControlQuerySubscriber {
             },
             Err(query) => query
         };
+        let query = match query.downcast::<ton::rpc::lite_server::LookupBlock>() {
+            Ok(query) => return QueryResult::consume_boxed(self.lookup_block(query).await?, None),
+            Err(query) => query
+        };
         let query = match query.downcast::<ton::rpc::engine::validator::SetStatesGcInterval>() {
             Ok(query) => return QueryResult::consume_boxed(
                 self.set_states_gc_interval(query.interval_ms as u32)?,
@@ -373,8 +855,199 @@ impl Subscriber for ControlQuerySubscriber {
             ),
             Err(query) => query
         };
-        log::warn!("Unsupported ControlQuery (control server): {:?}", query);
-        fail!("Unsupported ControlQuery {:?}", query)
+        fail!(NodeError::Control(ControlError::Unsupported, format!("ControlQuery {:?}", query)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscriber for ControlQuerySubscriber {
+    async fn try_consume_query(&self, object: TLObject, peers: &AdnlPeers) -> Result<QueryResult> {
+        log::info!("recieve object (control server): {:?}", object);
+        let query = match object.downcast::<ControlQuery>() {
+            Ok(query) => query,
+            Err(object) => return Ok(QueryResult::Rejected(object))
+        };
+        match self.process_query(&query.data[..], peers).await {
+            Ok(answer) => Ok(answer),
+            Err(e) => {
+                // Faults of the console are not errors of the node
+                if let Some(NodeError::Control(..)) = NodeError::from_error(&e) {
+                    log::warn!("control server query is refused: {}", e)
+                } else {
+                    log::error!("control server query error: {}", e)
+                }
+                QueryResult::consume_boxed(error_answer(&e), None)
+            }
+        }
     }
 }
 
+// Answers of control queries extension which are not ton_api objects
+fn raw_answer(answer: ControlAnswerExt) -> Result<QueryResult> {
+    let object = answer.serialize()?;
+    #[cfg(feature = "telemetry")]
+    let tag = u32::from_le_bytes([object[0], object[1], object[2], object[3]]);
+    Ok(QueryResult::Consumed(Some(Answer::Raw(TaggedByteVec {
+        object,
+        #[cfg(feature = "telemetry")]
+        tag
+    }))))
+}
+
+// Client branches on the code, the message is for humans
+fn error_answer(e: &failure::Error) -> ControlQueryError {
+    ControlQueryError::Engine_Validator_ControlQueryError(Box::new(
+        controlqueryerror::ControlQueryError { code: code_of(e) as i32, message: e.to_string() }
+    ))
+}
+
+
+#[cfg(test)]
+#[derive(Default)]
+struct TestEngine {
+    transactions: Vec<(storage::transactions_index_db::IndexedTransaction, Vec<u8>)>,
+    backup_in_flight: std::sync::atomic::AtomicBool,
+    quarantine: Vec<crate::internal_db::scrubber::QuarantinedProof>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl EngineOperations for TestEngine {
+    async fn load_account_transactions(
+        &self,
+        _workchain_id: i32,
+        _account: &UInt256,
+        from_lt: u64,
+        limit: usize,
+        with_boc: bool
+    ) -> Result<Vec<(storage::transactions_index_db::IndexedTransaction, Option<Vec<u8>>)>> {
+        Ok(self.transactions.iter().rev()
+            .filter(|(tr, _)| tr.lt <= from_lt)
+            .take(limit)
+            .map(|(tr, boc)| (tr.clone(), if with_boc { Some(boc.clone()) } else { None }))
+            .collect())
+    }
+    fn create_backup(&self, _path: String) -> Result<bool> {
+        Ok(!self.backup_in_flight.swap(true, std::sync::atomic::Ordering::Relaxed))
+    }
+    fn cells_cache_status(&self) -> String {
+        "cells cache".to_string()
+    }
+    fn db_scrubber_status(&self) -> String {
+        "scrubber".to_string()
+    }
+    fn db_scrubber_quarantine(&self) -> Vec<crate::internal_db::scrubber::QuarantinedProof> {
+        self.quarantine.clone()
+    }
+}
+
+#[cfg(test)]
+fn create_test_subscriber(
+    engine: TestEngine,
+    rt: &tokio::runtime::Runtime
+) -> ControlQuerySubscriber {
+    let config = serde_json::from_value(serde_json::json!({})).unwrap();
+    let (handler, _context) = NodeConfigHandler::create(config, rt.handle().clone()).unwrap();
+    ControlQuerySubscriber::new(Some(Arc::new(engine)), handler.clone(), handler)
+}
+
+#[test]
+fn test_account_transactions() {
+    use storage::transactions_index_db::IndexedTransaction;
+    let transaction = |lt: u64| IndexedTransaction {
+        lt,
+        hash: UInt256::from([lt as u8; 32]),
+        block_id: BlockIdExt::with_params(
+            ShardIdent::masterchain(), lt as u32, UInt256::default(), UInt256::default()
+        ),
+        gen_utime: lt as u32
+    };
+    let engine = TestEngine {
+        transactions: (1..=5).map(|lt| (transaction(lt * 10), vec![lt as u8])).collect(),
+        ..Default::default()
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let subscriber = create_test_subscriber(engine, &rt);
+    let account = UInt256::from([1; 32]);
+
+    // The newest first, not later than from_lt
+    let answer = rt.block_on(subscriber.account_transactions(0, &account, 35, 2, true)).unwrap();
+    assert_eq!(
+        answer,
+        ControlAnswerExt::Transactions(vec![
+            (transaction(30), Some(vec![3])),
+            (transaction(20), Some(vec![2]))
+        ])
+    );
+    let answer = rt.block_on(subscriber.account_transactions(0, &account, 10, 256, false)).unwrap();
+    assert_eq!(answer, ControlAnswerExt::Transactions(vec![(transaction(10), None)]));
+
+    let err = rt.block_on(subscriber.account_transactions(0, &account, 50, 257, true)).unwrap_err();
+    assert!(err.to_string().contains("must not exceed 256"), "{}", err);
+    assert_eq!(code_of(&err), 601);
+}
+
+#[test]
+fn test_create_backup_in_flight() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let subscriber = create_test_subscriber(TestEngine::default(), &rt);
+    subscriber.create_backup("backup1".to_string()).unwrap();
+    let err = subscriber.create_backup("backup2".to_string()).unwrap_err();
+    assert!(err.to_string().contains("another backup is in flight"), "{}", err);
+    assert_eq!(code_of(&err), 605);
+}
+
+#[test]
+fn test_filter_read_queries() {
+    use ton_api::ton::rpc::{
+        engine::validator::{GetStats, SetStatesGcInterval}, lite_server::LookupBlock
+    };
+    let allowed = vec![
+        TLObject::new(GetStats),
+        TLObject::new(ExportPublicKey { key_hash: ton::int256([1; 32]) }),
+        TLObject::new(LookupBlock::default()),
+    ];
+    for query in allowed {
+        assert!(ControlQuerySubscriber::filter_read_queries(query).is_ok());
+    }
+    let refused = vec![
+        TLObject::new(GenerateKeyPair),
+        TLObject::new(Sign { key_hash: ton::int256([1; 32]), data: ton::bytes(vec![1]) }),
+        TLObject::new(SetStatesGcInterval { interval_ms: 1000 }),
+    ];
+    for query in refused {
+        assert!(ControlQuerySubscriber::filter_read_queries(query).is_err());
+    }
+}
+
+#[test]
+fn test_db_stats_quarantine() {
+    use crate::internal_db::scrubber::QuarantinedProof;
+    let block_id = |seq_no: u32| BlockIdExt::with_params(
+        ShardIdent::masterchain(), seq_no, UInt256::default(), UInt256::default()
+    );
+    let engine = TestEngine {
+        quarantine: vec![
+            QuarantinedProof {
+                block_id: block_id(1), is_link: true, error: "bad root".to_string(), found_at: 100
+            },
+            QuarantinedProof {
+                block_id: block_id(2), is_link: false, error: "bad boc".to_string(), found_at: 200
+            },
+        ],
+        ..Default::default()
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let subscriber = create_test_subscriber(engine, &rt);
+    let stats = subscriber.get_db_stats().unwrap();
+    let stat = |key: &str| stats.stats.0.iter().find(|stat| stat.key == key).unwrap().value.clone();
+    assert_eq!(stat("cells_cache"), "cells cache");
+    assert_eq!(stat("db_scrubber"), "scrubber");
+    assert_eq!(
+        stat("db_scrubber_quarantine"),
+        format!(
+            "{} proof link (at 100): bad root\n{} proof (at 200): bad boc\n",
+            block_id(1), block_id(2)
+        )
+    );
+}
@@ -0,0 +1,636 @@
+use crate::{
+    block::{convert_block_id_ext_api2blk, convert_block_id_ext_blk2api},
+    config::{BandwidthLimitsConfig, ConsoleRole, StatesGcPolicy}, config_reload::ReloadReport,
+    network::{node_network::DhtStaticNodesUpdate, peer_scores::PeerScore}
+};
+use std::{convert::TryInto, io::Cursor};
+use storage::transactions_index_db::IndexedTransaction;
+use ton_api::{ton, Deserializer, Serializer};
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result, UInt256};
+
+// Control queries of this node which are absent in engine.validator schema of ton_api.
+// They are sent inside engine.validator.controlQuery like the ones of ton_api and are
+// recognized by constructor id (CRC32 of the schema line) before ton_api deserialization.
+//
+// Answers of engine.validator types are ton_api objects, the others are sent raw.
+//
+// engine.validator.ext.sendMessage flags:# body:bytes = engine.validator.Success;
+//     flags.0 - skip precheck, for tools which send messages valid in future only
+// engine.validator.ext.getMessageStatus hash:int256 = engine.validator.ext.MessageStatus;
+// engine.validator.ext.compactDb db:string = engine.validator.Success;
+//     empty db - all internal databases
+// engine.validator.ext.setStatesGcPolicy keep_key_blocks:int = engine.validator.Success;
+// engine.validator.ext.getDbStats = engine.validator.Stats;
+// engine.validator.ext.setCellsCacheSize size_mb:long = engine.validator.Success;
+// engine.validator.ext.getAccountTransactions flags:# workchain:int account:int256 from_lt:long
+//     limit:int = engine.validator.ext.Transactions;
+//     flags.0 - with BOCs of transactions
+// engine.validator.ext.createBackup path:string = engine.validator.Success;
+//     path - new directory on the node's host
+// engine.validator.ext.setBandwidthLimits download_kb_per_sec:long serve_kb_per_sec:long
+//     = engine.validator.Success;
+//     KB/s, 0 - unlimited
+// engine.validator.ext.getPeerScores = engine.validator.ext.PeerScores;
+// engine.validator.ext.reloadConfig = engine.validator.ext.ConfigReloaded;
+// engine.validator.ext.addConsoleKey key:int256 role:int = engine.validator.Success;
+//     key - ed25519 public key, role - 0 admin, 1 operator, 2 read-only
+// engine.validator.ext.removeConsoleKey key_hash:int256 = engine.validator.Success;
+//     key_hash - ADNL id of the key
+// engine.validator.ext.getNodeInfo = engine.validator.Stats;
+// engine.validator.ext.reloadGlobalConfig = engine.validator.ext.DhtStaticNodesUpdated;
+// engine.validator.ext.shutdown = engine.validator.Success;
+//     answered before the node drains and exits
+//
+// engine.validator.ext.messageStatus flags:# status:string description:string updated_at:int
+//     block:flags.0?tonNode.blockIdExt transaction:flags.0?int256
+//     = engine.validator.ext.MessageStatus;
+//     flags.0 - message is committed in the block by the transaction
+// engine.validator.ext.transaction flags:# lt:long hash:int256 block:tonNode.blockIdExt
+//     gen_utime:int boc:flags.0?bytes = engine.validator.ext.Transaction;
+// engine.validator.ext.transactions transactions:(vector engine.validator.ext.transaction)
+//     = engine.validator.ext.Transactions;
+//     newest transactions first
+// engine.validator.ext.peerScore peer:int256 score:int bans:int banned_until:long
+//     = engine.validator.ext.PeerScore;
+// engine.validator.ext.peerScores scores:(vector engine.validator.ext.peerScore)
+//     = engine.validator.ext.PeerScores;
+//     the worst peers first, banned_until is unix time
+// engine.validator.ext.reloadFailure field:string error:string = engine.validator.ext.ReloadFailure;
+// engine.validator.ext.configReloaded applied:(vector string) restart_required:(vector string)
+//     ignored:(vector string) failed:(vector engine.validator.ext.reloadFailure)
+//     = engine.validator.ext.ConfigReloaded;
+//     ignored - fields written by the node itself
+// engine.validator.ext.dhtStaticNodesUpdated added:int removed:int kept:int
+//     = engine.validator.ext.DhtStaticNodesUpdated;
+
+const SEND_MESSAGE: u32 = 0x52e117f2;
+const GET_MESSAGE_STATUS: u32 = 0x98efd05e;
+const COMPACT_DB: u32 = 0xf27288e4;
+const SET_STATES_GC_POLICY: u32 = 0xc6742da2;
+const GET_DB_STATS: u32 = 0x553ab31b;
+const SET_CELLS_CACHE_SIZE: u32 = 0xc4bcb1bf;
+const GET_ACCOUNT_TRANSACTIONS: u32 = 0x2db91839;
+const CREATE_BACKUP: u32 = 0x384c35ac;
+const SET_BANDWIDTH_LIMITS: u32 = 0x9e07e8f1;
+const GET_PEER_SCORES: u32 = 0x65ac2859;
+const RELOAD_CONFIG: u32 = 0x867dbe14;
+const ADD_CONSOLE_KEY: u32 = 0x0eff8312;
+const REMOVE_CONSOLE_KEY: u32 = 0x6838cb59;
+const GET_NODE_INFO: u32 = 0xbd32f10d;
+const RELOAD_GLOBAL_CONFIG: u32 = 0x90506a4e;
+const SHUTDOWN: u32 = 0x0831008a;
+
+const MESSAGE_STATUS: u32 = 0x7ebb3ac3;
+const TRANSACTIONS: u32 = 0x9965c2c3;
+const PEER_SCORES: u32 = 0xf9a24eb1;
+const CONFIG_RELOADED: u32 = 0xf35975d3;
+const DHT_STATIC_NODES_UPDATED: u32 = 0x2c251013;
+
+const SEND_MESSAGE_SKIP_PRECHECK: i32 = 1;
+const MESSAGE_STATUS_COMMITTED: i32 = 1;
+const WITH_BOC: i32 = 1;
+
+const CONSOLE_ROLES: [ConsoleRole; 3] =
+    [ConsoleRole::Admin, ConsoleRole::Operator, ConsoleRole::ReadOnly];
+
+fn split_constructor(data: &[u8]) -> Result<Option<(u32, &[u8])>> {
+    match data.get(..4) {
+        Some(id) => Ok(Some((u32::from_le_bytes(id.try_into()?), &data[4..]))),
+        None => Ok(None)
+    }
+}
+
+fn read_strings(de: &mut Deserializer) -> Result<Vec<String>> {
+    let count = de.read_bare::<ton::int>()?;
+    let mut strings = Vec::new();
+    for _ in 0..count {
+        strings.push(de.read_bare::<ton::string>()?);
+    }
+    Ok(strings)
+}
+
+fn write_strings(ser: &mut Serializer, strings: &[String]) -> Result<()> {
+    ser.write_bare::<ton::int>(&(strings.len() as i32))?;
+    for string in strings {
+        ser.write_bare::<ton::string>(string)?;
+    }
+    Ok(())
+}
+
+fn read_block_id(de: &mut Deserializer) -> Result<BlockIdExt> {
+    convert_block_id_ext_api2blk(&de.read_bare()?)
+}
+
+fn write_block_id(ser: &mut Serializer, id: &BlockIdExt) -> Result<()> {
+    ser.write_bare(&convert_block_id_ext_blk2api(id))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ControlQueryExt {
+    SendMessage { body: Vec<u8>, skip_precheck: bool },
+    GetMessageStatus { hash: UInt256 },
+    // None - all databases
+    CompactDb { db: Option<String> },
+    SetStatesGcPolicy { policy: StatesGcPolicy },
+    GetDbStats,
+    // Zero disables the cache
+    SetCellsCacheSize { size_mb: u64 },
+    // Up to `limit` transactions with lt not greater than `from_lt`
+    GetAccountTransactions {
+        workchain_id: i32,
+        account: UInt256,
+        from_lt: u64,
+        limit: u32,
+        with_boc: bool
+    },
+    CreateBackup { path: String },
+    SetBandwidthLimits { limits: BandwidthLimitsConfig },
+    GetPeerScores,
+    ReloadConfig,
+    AddConsoleKey { key: [u8; 32], role: ConsoleRole },
+    RemoveConsoleKey { key_hash: [u8; 32] },
+    GetNodeInfo,
+    ReloadGlobalConfig,
+    Shutdown,
+}
+
+impl ControlQueryExt {
+
+    // None if data is not a query of this schema
+    pub fn deserialize(data: &[u8]) -> Result<Option<Self>> {
+        let (id, data) = match split_constructor(data)? {
+            Some(split) => split,
+            None => return Ok(None)
+        };
+        let mut cursor = Cursor::new(data);
+        let mut de = Deserializer::new(&mut cursor);
+        let query = match id {
+            SEND_MESSAGE => {
+                let flags = de.read_bare::<ton::int>()?;
+                let body = de.read_bare::<ton::bytes>()?.0;
+                Self::SendMessage { body, skip_precheck: flags & SEND_MESSAGE_SKIP_PRECHECK != 0 }
+            }
+            GET_MESSAGE_STATUS => {
+                Self::GetMessageStatus { hash: UInt256::from(de.read_bare::<ton::int256>()?.0) }
+            }
+            COMPACT_DB => {
+                let db = de.read_bare::<ton::string>()?;
+                Self::CompactDb { db: Some(db).filter(|db| !db.is_empty()) }
+            }
+            SET_STATES_GC_POLICY => {
+                let keep_key_blocks = de.read_bare::<ton::int>()?;
+                if keep_key_blocks < 0 {
+                    fail!("Negative keep_key_blocks {}", keep_key_blocks)
+                }
+                let policy = StatesGcPolicy { keep_key_blocks: keep_key_blocks as u32 };
+                Self::SetStatesGcPolicy { policy }
+            }
+            GET_DB_STATS => Self::GetDbStats,
+            SET_CELLS_CACHE_SIZE => {
+                let size_mb = de.read_bare::<ton::long>()?;
+                if size_mb < 0 {
+                    fail!("Negative cells cache size {}", size_mb)
+                }
+                Self::SetCellsCacheSize { size_mb: size_mb as u64 }
+            }
+            GET_ACCOUNT_TRANSACTIONS => {
+                let flags = de.read_bare::<ton::int>()?;
+                let workchain_id = de.read_bare::<ton::int>()?;
+                let account = UInt256::from(de.read_bare::<ton::int256>()?.0);
+                let from_lt = de.read_bare::<ton::long>()? as u64;
+                let limit = de.read_bare::<ton::int>()?;
+                if limit <= 0 {
+                    fail!("Limit of transactions must be positive, not {}", limit)
+                }
+                Self::GetAccountTransactions {
+                    workchain_id,
+                    account,
+                    from_lt,
+                    limit: limit as u32,
+                    with_boc: flags & WITH_BOC != 0
+                }
+            }
+            CREATE_BACKUP => Self::CreateBackup { path: de.read_bare::<ton::string>()? },
+            SET_BANDWIDTH_LIMITS => {
+                let download_kb_per_sec = de.read_bare::<ton::long>()?;
+                let serve_kb_per_sec = de.read_bare::<ton::long>()?;
+                if download_kb_per_sec < 0 || serve_kb_per_sec < 0 {
+                    fail!("Negative bandwidth limit")
+                }
+                let limits = BandwidthLimitsConfig {
+                    download_kb_per_sec: download_kb_per_sec as u64,
+                    serve_kb_per_sec: serve_kb_per_sec as u64
+                };
+                Self::SetBandwidthLimits { limits }
+            }
+            GET_PEER_SCORES => Self::GetPeerScores,
+            RELOAD_CONFIG => Self::ReloadConfig,
+            ADD_CONSOLE_KEY => {
+                let key = de.read_bare::<ton::int256>()?.0;
+                let role = de.read_bare::<ton::int>()?;
+                match CONSOLE_ROLES.get(role as usize) {
+                    Some(role) => Self::AddConsoleKey { key, role: *role },
+                    None => fail!("Unknown console key role {}", role)
+                }
+            }
+            REMOVE_CONSOLE_KEY => {
+                Self::RemoveConsoleKey { key_hash: de.read_bare::<ton::int256>()?.0 }
+            }
+            GET_NODE_INFO => Self::GetNodeInfo,
+            RELOAD_GLOBAL_CONFIG => Self::ReloadGlobalConfig,
+            SHUTDOWN => Self::Shutdown,
+            _ => return Ok(None)
+        };
+        Ok(Some(query))
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self {
+            Self::SendMessage { body, skip_precheck } => {
+                data.extend_from_slice(&SEND_MESSAGE.to_le_bytes());
+                let flags = if *skip_precheck { SEND_MESSAGE_SKIP_PRECHECK } else { 0 };
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&flags)?;
+                ser.write_bare::<ton::bytes>(&ton::bytes(body.clone()))?;
+            }
+            Self::GetMessageStatus { hash } => {
+                data.extend_from_slice(&GET_MESSAGE_STATUS.to_le_bytes());
+                Serializer::new(&mut data).write_bare(&ton::int256(*hash.as_slice()))?;
+            }
+            Self::CompactDb { db } => {
+                data.extend_from_slice(&COMPACT_DB.to_le_bytes());
+                Serializer::new(&mut data)
+                    .write_bare::<ton::string>(&db.clone().unwrap_or_default())?;
+            }
+            Self::SetStatesGcPolicy { policy } => {
+                data.extend_from_slice(&SET_STATES_GC_POLICY.to_le_bytes());
+                Serializer::new(&mut data)
+                    .write_bare::<ton::int>(&(policy.keep_key_blocks as i32))?;
+            }
+            Self::GetDbStats => data.extend_from_slice(&GET_DB_STATS.to_le_bytes()),
+            Self::SetCellsCacheSize { size_mb } => {
+                data.extend_from_slice(&SET_CELLS_CACHE_SIZE.to_le_bytes());
+                Serializer::new(&mut data).write_bare::<ton::long>(&(*size_mb as i64))?;
+            }
+            Self::GetAccountTransactions { workchain_id, account, from_lt, limit, with_boc } => {
+                data.extend_from_slice(&GET_ACCOUNT_TRANSACTIONS.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&if *with_boc { WITH_BOC } else { 0 })?;
+                ser.write_bare::<ton::int>(workchain_id)?;
+                ser.write_bare(&ton::int256(*account.as_slice()))?;
+                ser.write_bare::<ton::long>(&(*from_lt as i64))?;
+                ser.write_bare::<ton::int>(&(*limit as i32))?;
+            }
+            Self::CreateBackup { path } => {
+                data.extend_from_slice(&CREATE_BACKUP.to_le_bytes());
+                Serializer::new(&mut data).write_bare::<ton::string>(path)?;
+            }
+            Self::SetBandwidthLimits { limits } => {
+                data.extend_from_slice(&SET_BANDWIDTH_LIMITS.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::long>(&(limits.download_kb_per_sec as i64))?;
+                ser.write_bare::<ton::long>(&(limits.serve_kb_per_sec as i64))?;
+            }
+            Self::GetPeerScores => data.extend_from_slice(&GET_PEER_SCORES.to_le_bytes()),
+            Self::ReloadConfig => data.extend_from_slice(&RELOAD_CONFIG.to_le_bytes()),
+            Self::AddConsoleKey { key, role } => {
+                data.extend_from_slice(&ADD_CONSOLE_KEY.to_le_bytes());
+                let role = CONSOLE_ROLES.iter().position(|other| other == role).unwrap_or_default();
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare(&ton::int256(*key))?;
+                ser.write_bare::<ton::int>(&(role as i32))?;
+            }
+            Self::RemoveConsoleKey { key_hash } => {
+                data.extend_from_slice(&REMOVE_CONSOLE_KEY.to_le_bytes());
+                Serializer::new(&mut data).write_bare(&ton::int256(*key_hash))?;
+            }
+            Self::GetNodeInfo => data.extend_from_slice(&GET_NODE_INFO.to_le_bytes()),
+            Self::ReloadGlobalConfig => {
+                data.extend_from_slice(&RELOAD_GLOBAL_CONFIG.to_le_bytes())
+            }
+            Self::Shutdown => data.extend_from_slice(&SHUTDOWN.to_le_bytes()),
+        }
+        Ok(data)
+    }
+
+    // Read-only node serves only queries allowed for read-only keys
+    pub fn is_allowed(&self, role: ConsoleRole) -> bool {
+        match self.required_role() {
+            ConsoleRole::ReadOnly => true,
+            ConsoleRole::Operator => role != ConsoleRole::ReadOnly,
+            ConsoleRole::Admin => role == ConsoleRole::Admin
+        }
+    }
+
+    fn required_role(&self) -> ConsoleRole {
+        match self {
+            Self::SendMessage { .. } => ConsoleRole::Operator,
+            Self::GetMessageStatus { .. } => ConsoleRole::ReadOnly,
+            Self::CompactDb { .. } => ConsoleRole::Operator,
+            Self::SetStatesGcPolicy { .. } => ConsoleRole::Operator,
+            Self::GetDbStats => ConsoleRole::ReadOnly,
+            Self::SetCellsCacheSize { .. } => ConsoleRole::Operator,
+            Self::GetAccountTransactions { .. } => ConsoleRole::ReadOnly,
+            Self::CreateBackup { .. } => ConsoleRole::Operator,
+            Self::SetBandwidthLimits { .. } => ConsoleRole::Operator,
+            Self::GetPeerScores => ConsoleRole::ReadOnly,
+            Self::ReloadConfig => ConsoleRole::Operator,
+            Self::AddConsoleKey { .. } | Self::RemoveConsoleKey { .. } => ConsoleRole::Admin,
+            Self::GetNodeInfo => ConsoleRole::ReadOnly,
+            Self::ReloadGlobalConfig => ConsoleRole::Operator,
+            Self::Shutdown => ConsoleRole::Admin,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ControlAnswerExt {
+    MessageStatus {
+        // Short name of the status, e.g. "committed"
+        status: String,
+        description: String,
+        updated_at: u32,
+        // Block and transaction hash
+        committed: Option<(BlockIdExt, UInt256)>
+    },
+    Transactions(Vec<(IndexedTransaction, Option<Vec<u8>>)>),
+    // Peer ADNL id and its score
+    PeerScores(Vec<([u8; 32], PeerScore)>),
+    ConfigReloaded(ReloadReport),
+    DhtStaticNodesUpdated(DhtStaticNodesUpdate),
+}
+
+impl ControlAnswerExt {
+
+    pub fn deserialize(data: &[u8]) -> Result<Self> {
+        let (id, data) = match split_constructor(data)? {
+            Some(split) => split,
+            None => fail!("Answer is too short")
+        };
+        let mut cursor = Cursor::new(data);
+        let mut de = Deserializer::new(&mut cursor);
+        let answer = match id {
+            MESSAGE_STATUS => {
+                let flags = de.read_bare::<ton::int>()?;
+                let status = de.read_bare::<ton::string>()?;
+                let description = de.read_bare::<ton::string>()?;
+                let updated_at = de.read_bare::<ton::int>()? as u32;
+                let committed = if flags & MESSAGE_STATUS_COMMITTED != 0 {
+                    let block_id = read_block_id(&mut de)?;
+                    Some((block_id, UInt256::from(de.read_bare::<ton::int256>()?.0)))
+                } else {
+                    None
+                };
+                Self::MessageStatus { status, description, updated_at, committed }
+            }
+            TRANSACTIONS => {
+                let count = de.read_bare::<ton::int>()?;
+                let mut transactions = Vec::new();
+                for _ in 0..count {
+                    let flags = de.read_bare::<ton::int>()?;
+                    let lt = de.read_bare::<ton::long>()? as u64;
+                    let hash = UInt256::from(de.read_bare::<ton::int256>()?.0);
+                    let block_id = read_block_id(&mut de)?;
+                    let gen_utime = de.read_bare::<ton::int>()? as u32;
+                    let boc = if flags & WITH_BOC != 0 {
+                        Some(de.read_bare::<ton::bytes>()?.0)
+                    } else {
+                        None
+                    };
+                    let tr = IndexedTransaction { lt, hash, block_id, gen_utime };
+                    transactions.push((tr, boc));
+                }
+                Self::Transactions(transactions)
+            }
+            PEER_SCORES => {
+                let count = de.read_bare::<ton::int>()?;
+                let mut scores = Vec::new();
+                for _ in 0..count {
+                    let peer = de.read_bare::<ton::int256>()?.0;
+                    let score = PeerScore {
+                        score: de.read_bare::<ton::int>()?,
+                        bans: de.read_bare::<ton::int>()? as u32,
+                        banned_until: de.read_bare::<ton::long>()? as u64
+                    };
+                    scores.push((peer, score));
+                }
+                Self::PeerScores(scores)
+            }
+            CONFIG_RELOADED => {
+                let applied = read_strings(&mut de)?;
+                let restart_required = read_strings(&mut de)?;
+                let ignored = read_strings(&mut de)?;
+                let count = de.read_bare::<ton::int>()?;
+                let mut failed = Vec::new();
+                for _ in 0..count {
+                    let field = de.read_bare::<ton::string>()?;
+                    failed.push((field, de.read_bare::<ton::string>()?));
+                }
+                Self::ConfigReloaded(ReloadReport { applied, restart_required, ignored, failed })
+            }
+            DHT_STATIC_NODES_UPDATED => {
+                Self::DhtStaticNodesUpdated(DhtStaticNodesUpdate {
+                    added: de.read_bare::<ton::int>()? as usize,
+                    removed: de.read_bare::<ton::int>()? as usize,
+                    kept: de.read_bare::<ton::int>()? as usize,
+                    learned: de.read_bare::<ton::int>()? as usize
+                })
+            }
+            id => fail!("Unknown answer constructor {:08x}", id)
+        };
+        Ok(answer)
+    }
+
+    pub fn serialize(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match self {
+            Self::MessageStatus { status, description, updated_at, committed } => {
+                data.extend_from_slice(&MESSAGE_STATUS.to_le_bytes());
+                let flags = if committed.is_some() { MESSAGE_STATUS_COMMITTED } else { 0 };
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&flags)?;
+                ser.write_bare::<ton::string>(status)?;
+                ser.write_bare::<ton::string>(description)?;
+                ser.write_bare::<ton::int>(&(*updated_at as i32))?;
+                if let Some((block_id, tx_hash)) = committed {
+                    write_block_id(&mut ser, block_id)?;
+                    ser.write_bare(&ton::int256(*tx_hash.as_slice()))?;
+                }
+            }
+            Self::Transactions(transactions) => {
+                data.extend_from_slice(&TRANSACTIONS.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&(transactions.len() as i32))?;
+                for (tr, boc) in transactions {
+                    ser.write_bare::<ton::int>(&if boc.is_some() { WITH_BOC } else { 0 })?;
+                    ser.write_bare::<ton::long>(&(tr.lt as i64))?;
+                    ser.write_bare(&ton::int256(*tr.hash.as_slice()))?;
+                    write_block_id(&mut ser, &tr.block_id)?;
+                    ser.write_bare::<ton::int>(&(tr.gen_utime as i32))?;
+                    if let Some(boc) = boc {
+                        ser.write_bare::<ton::bytes>(&ton::bytes(boc.clone()))?;
+                    }
+                }
+            }
+            Self::PeerScores(scores) => {
+                data.extend_from_slice(&PEER_SCORES.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&(scores.len() as i32))?;
+                for (peer, score) in scores {
+                    ser.write_bare(&ton::int256(*peer))?;
+                    ser.write_bare::<ton::int>(&score.score)?;
+                    ser.write_bare::<ton::int>(&(score.bans as i32))?;
+                    ser.write_bare::<ton::long>(&(score.banned_until as i64))?;
+                }
+            }
+            Self::ConfigReloaded(report) => {
+                data.extend_from_slice(&CONFIG_RELOADED.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                write_strings(&mut ser, &report.applied)?;
+                write_strings(&mut ser, &report.restart_required)?;
+                write_strings(&mut ser, &report.ignored)?;
+                ser.write_bare::<ton::int>(&(report.failed.len() as i32))?;
+                for (field, error) in report.failed.iter() {
+                    ser.write_bare::<ton::string>(field)?;
+                    ser.write_bare::<ton::string>(error)?;
+                }
+            }
+            Self::DhtStaticNodesUpdated(update) => {
+                data.extend_from_slice(&DHT_STATIC_NODES_UPDATED.to_le_bytes());
+                let mut ser = Serializer::new(&mut data);
+                ser.write_bare::<ton::int>(&(update.added as i32))?;
+                ser.write_bare::<ton::int>(&(update.removed as i32))?;
+                ser.write_bare::<ton::int>(&(update.kept as i32))?;
+                ser.write_bare::<ton::int>(&(update.learned as i32))?;
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[test]
+fn test_control_query_ext_serialization() {
+    let queries = vec![
+        ControlQueryExt::SendMessage { body: vec![1, 2, 3], skip_precheck: true },
+        ControlQueryExt::SendMessage { body: vec![0; 300], skip_precheck: false },
+        ControlQueryExt::GetMessageStatus { hash: UInt256::from([7; 32]) },
+        ControlQueryExt::CompactDb { db: Some("shardstate_db".to_string()) },
+        ControlQueryExt::CompactDb { db: None },
+        ControlQueryExt::SetStatesGcPolicy { policy: StatesGcPolicy { keep_key_blocks: 3 } },
+        ControlQueryExt::GetDbStats,
+        ControlQueryExt::SetCellsCacheSize { size_mb: 1024 },
+        ControlQueryExt::GetAccountTransactions {
+            workchain_id: -1,
+            account: UInt256::from([5; 32]),
+            from_lt: u64::MAX >> 1,
+            limit: 10,
+            with_boc: true
+        },
+        ControlQueryExt::CreateBackup { path: "/var/backups/node_db".to_string() },
+        ControlQueryExt::SetBandwidthLimits {
+            limits: BandwidthLimitsConfig { download_kb_per_sec: 10240, serve_kb_per_sec: 0 }
+        },
+        ControlQueryExt::GetPeerScores,
+        ControlQueryExt::ReloadConfig,
+        ControlQueryExt::AddConsoleKey { key: [8; 32], role: ConsoleRole::Admin },
+        ControlQueryExt::AddConsoleKey { key: [9; 32], role: ConsoleRole::ReadOnly },
+        ControlQueryExt::RemoveConsoleKey { key_hash: [10; 32] },
+        ControlQueryExt::GetNodeInfo,
+        ControlQueryExt::ReloadGlobalConfig,
+        ControlQueryExt::Shutdown,
+    ];
+    for query in queries {
+        let data = query.serialize().unwrap();
+        assert_eq!(ControlQueryExt::deserialize(&data).unwrap(), Some(query));
+    }
+
+    // Queries of ton_api are left to it
+    let data = adnl::common::serialize(
+        &ton::TLObject::new(ton::rpc::engine::validator::GetStats)
+    ).unwrap();
+    assert_eq!(ControlQueryExt::deserialize(&data).unwrap(), None);
+    assert_eq!(ControlQueryExt::deserialize(&[1, 2]).unwrap(), None);
+    // Truncated query of this schema
+    let data = ControlQueryExt::SendMessage { body: vec![1; 10], skip_precheck: false }
+        .serialize().unwrap();
+    assert!(ControlQueryExt::deserialize(&data[..8]).is_err());
+
+    let block_id = BlockIdExt::with_params(
+        ton_block::ShardIdent::masterchain(), 100, UInt256::from([1; 32]), UInt256::from([2; 32])
+    );
+    let answers = vec![
+        ControlAnswerExt::MessageStatus {
+            status: "committed".to_string(),
+            description: "committed in block".to_string(),
+            updated_at: 1700000000,
+            committed: Some((block_id.clone(), UInt256::from([3; 32])))
+        },
+        ControlAnswerExt::MessageStatus {
+            status: "rejected".to_string(),
+            description: "rejected: exit code 33".to_string(),
+            updated_at: 1700000001,
+            committed: None
+        },
+        ControlAnswerExt::Transactions(vec![
+            (
+                IndexedTransaction {
+                    lt: 2000,
+                    hash: UInt256::from([4; 32]),
+                    block_id: block_id.clone(),
+                    gen_utime: 20
+                },
+                Some(vec![0xb5, 0xee, 0x9c, 0x72])
+            ),
+            (
+                IndexedTransaction {
+                    lt: 1000,
+                    hash: UInt256::from([5; 32]),
+                    block_id: block_id.clone(),
+                    gen_utime: 10
+                },
+                None
+            ),
+        ]),
+        ControlAnswerExt::Transactions(Vec::new()),
+        ControlAnswerExt::PeerScores(vec![
+            ([1; 32], PeerScore { score: -50, bans: 2, banned_until: 1700000120 }),
+            ([2; 32], PeerScore { score: 40, bans: 0, banned_until: 0 }),
+        ]),
+        ControlAnswerExt::ConfigReloaded(ReloadReport {
+            applied: vec!["cells_gc_interval_ms".to_string(), "metrics".to_string()],
+            restart_required: vec!["internal_db_path".to_string()],
+            ignored: Vec::new(),
+            failed: vec![("log_file".to_string(), "Can't open log file".to_string())]
+        }),
+        ControlAnswerExt::DhtStaticNodesUpdated(
+            DhtStaticNodesUpdate { added: 2, removed: 1, kept: 10, learned: 1 }
+        ),
+    ];
+    for answer in answers {
+        let data = answer.serialize().unwrap();
+        assert_eq!(ControlAnswerExt::deserialize(&data).unwrap(), answer);
+    }
+}
+
+#[test]
+fn test_control_query_ext_roles() {
+    let query = ControlQueryExt::SendMessage { body: Vec::new(), skip_precheck: true };
+    assert!(query.is_allowed(ConsoleRole::Admin));
+    assert!(query.is_allowed(ConsoleRole::Operator));
+    assert!(!query.is_allowed(ConsoleRole::ReadOnly));
+    let query = ControlQueryExt::GetMessageStatus { hash: UInt256::default() };
+    assert!(query.is_allowed(ConsoleRole::ReadOnly));
+    let query = ControlQueryExt::RemoveConsoleKey { key_hash: [0; 32] };
+    assert!(query.is_allowed(ConsoleRole::Admin));
+    assert!(!query.is_allowed(ConsoleRole::Operator));
+    assert!(!query.is_allowed(ConsoleRole::ReadOnly));
+
+    // Unknown role
+    let mut data = ControlQueryExt::AddConsoleKey { key: [0; 32], role: ConsoleRole::Operator }
+        .serialize().unwrap();
+    let len = data.len();
+    data[len - 4..].copy_from_slice(&3i32.to_le_bytes());
+    assert!(ControlQueryExt::deserialize(&data).is_err());
+}
@@ -2,8 +2,12 @@ use crate::{
     block::{
         compare_block_ids, convert_block_id_ext_api2blk, convert_block_id_ext_blk2api, BlockStuff
     },
-    block_proof::BlockProofStuff, shard_state::ShardStateStuff,
-    network::neighbours::{Neighbours, Neighbour},
+    block_proof::BlockProofStuff, config::ExtMessagesConfig, engine::STATSD,
+    error::{NetworkError, NodeError}, shard_state::ShardStateStuff,
+    network::{
+        bandwidth::BandwidthLimiter, lite_server::ERROR_CODE_NOTREADY,
+        neighbours::{Neighbours, Neighbour}, peer_scores::PeerOutcome
+    },
     types::top_block_descr::TopBlockDescrStuff,
 };
 
@@ -16,11 +20,14 @@ use adnl::{
 };
 use overlay::{BroadcastSendInfo, OverlayShortId, OverlayNode};
 use rldp::RldpNode;
-use std::{io::Cursor, time::Instant, sync::Arc, time::Duration};
+use std::{collections::HashSet, io::Cursor, time::Instant, sync::Arc, time::Duration};
+use storage::archives::package::read_package_from;
 use ton_api::{BoxedSerialize, BoxedDeserialize, Deserializer, IntoBoxed};
 use ton_api::ton::{
     self, TLObject,
+    lite_server::{BlockHeader, Error as LiteError},
     rpc::{
+        lite_server::LookupBlock,
         ton_node::{
             DownloadNextBlockFull, DownloadPersistentStateSlice, DownloadZeroState,
             PreparePersistentState, DownloadBlockProof, DownloadBlockProofLink,
@@ -33,10 +40,10 @@ use ton_api::ton::{
         ArchiveInfo, Broadcast, 
         DataFull, KeyBlocks, Prepared, PreparedProof, PreparedState, 
         broadcast::{BlockBroadcast, ExternalMessageBroadcast, NewShardBlockBroadcast}, 
-        externalmessage::ExternalMessage, 
+        blockid::BlockId, externalmessage::ExternalMessage,
     }
 };
-use ton_block::BlockIdExt;
+use ton_block::{BlockIdExt, ShardIdent};
 use ton_types::{fail, error, Result};
 #[cfg(feature = "telemetry")]
 use crate::network::telemetry::FullNodeNetworkTelemetry;
@@ -46,8 +53,19 @@ pub trait FullNodeOverlayClient : Sync + Send {
     async fn broadcast_external_message(&self, msg: &[u8]) -> Result<BroadcastSendInfo>;
     async fn send_block_broadcast(&self, broadcast: BlockBroadcast) -> Result<()>;
     async fn send_top_shard_block_description(&self, tbd: &TopBlockDescrStuff) -> Result<()>;
-    async fn download_block_proof(&self, block_id: &BlockIdExt, is_link: bool, key_block: bool) -> Result<BlockProofStuff>;
-    async fn download_block_full(&self, id: &BlockIdExt) -> Result<(BlockStuff, BlockProofStuff)>;
+    // Peers in `active_peers` are not asked, the asked one is added there
+    async fn download_block_proof(
+        &self,
+        block_id: &BlockIdExt,
+        is_link: bool,
+        key_block: bool,
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<BlockProofStuff>;
+    async fn download_block_full(
+        &self,
+        id: &BlockIdExt,
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<(BlockStuff, BlockProofStuff)>;
     async fn check_persistent_state(
         &self,
         block_id: &BlockIdExt,
@@ -65,13 +83,65 @@ pub trait FullNodeOverlayClient : Sync + Send {
     ) -> Result<Vec<u8>>;
     async fn download_zero_state(&self, id: &BlockIdExt) -> Result<(ShardStateStuff, Vec<u8>)>;
     async fn download_next_key_blocks_ids(&self, block_id: &BlockIdExt, max_size: i32) -> Result<Vec<BlockIdExt>>;
-    async fn download_next_block_full(&self, prev_id: &BlockIdExt) -> Result<(BlockStuff, BlockProofStuff)>;
+    async fn download_next_block_full(
+        &self,
+        prev_id: &BlockIdExt,
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<(BlockStuff, BlockProofStuff)>;
+    // Ids of block with given seqno answered by a few neighbours, not checked yet.
+    // Empty if neighbours answered they have no such block.
+    async fn lookup_block_by_seqno(
+        &self,
+        shard: &ShardIdent,
+        seqno: u32
+    ) -> Result<Vec<(BlockIdExt, Arc<KeyId>)>>;
+    // Download slower than `min_speed` (bytes per second, 0 - unlimited) is dropped.
+    // Each downloaded slice is passed through `limiter`.
     async fn download_archive(
         &self, 
         mc_seq_no: u32,
+        min_speed: u64,
+        limiter: &BandwidthLimiter,
         active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<Option<Vec<u8>>>;
     async fn wait_broadcast(&self) -> Result<(Broadcast, Arc<KeyId>)>;
+    // Result of data got from the peer which is checked by caller
+    fn report_peer(&self, peer: &Arc<KeyId>, outcome: PeerOutcome);
+}
+
+// Answer of neighbour to liteServer.lookupBlock
+#[derive(Debug, PartialEq)]
+enum LookupAnswer {
+    Found(BlockIdExt),
+    // liteServer.error with "not ready" code
+    NotFound,
+    // Other errors and answers: the query is not implemented by the peer
+    Unsupported(String),
+    Bad(String),
+}
+
+impl LookupAnswer {
+    fn with_answer(answer: TLObject) -> Self {
+        let answer = match answer.downcast::<BlockHeader>() {
+            Ok(header) => return match convert_block_id_ext_api2blk(header.id()) {
+                Ok(id) => LookupAnswer::Found(id),
+                Err(e) => LookupAnswer::Bad(format!("bad block id: {}", e))
+            },
+            Err(answer) => answer
+        };
+        match answer.downcast::<LiteError>() {
+            Ok(LiteError::LiteServer_Error(error)) if error.code == ERROR_CODE_NOTREADY =>
+                LookupAnswer::NotFound,
+            Ok(LiteError::LiteServer_Error(error)) =>
+                LookupAnswer::Unsupported(format!("error {}: {}", error.code, error.message)),
+            Err(answer) => LookupAnswer::Unsupported(format!("{:?}", answer))
+        }
+    }
+}
+
+struct ExtMessageToBroadcast {
+    data: Vec<u8>,
+    result: tokio::sync::oneshot::Sender<Result<BroadcastSendInfo>>,
 }
 
 #[derive(Clone)]
@@ -80,6 +150,7 @@ pub struct NodeClientOverlay {
     overlay: Arc<OverlayNode>,
     rldp: Arc<RldpNode>,
     peers: Arc<Neighbours>,
+    ext_msg_batcher: Option<tokio::sync::mpsc::UnboundedSender<ExtMessageToBroadcast>>,
     #[cfg(feature = "telemetry")]
     telemetry: Arc<FullNodeNetworkTelemetry>,
     #[cfg(feature = "telemetry")]
@@ -101,14 +172,14 @@ pub struct NodeClientOverlay {
     #[cfg(feature = "telemetry")]
     tag_download_zero_state: u32,
     #[cfg(feature = "telemetry")]
-    tag_external_message_broadcast: u32,
-    #[cfg(feature = "telemetry")]
     tag_get_archive_info: u32,
     #[cfg(feature = "telemetry")]
     tag_get_archive_slice: u32,
     #[cfg(feature = "telemetry")]
     tag_get_next_key_block_ids: u32,
     #[cfg(feature = "telemetry")]
+    tag_lookup_block: u32,
+    #[cfg(feature = "telemetry")]
     tag_new_shard_block_broadcast: u32,
     #[cfg(feature = "telemetry")]
     tag_prepare_block: u32,
@@ -125,6 +196,7 @@ pub struct NodeClientOverlay {
 impl NodeClientOverlay {
 
     const ADNL_ATTEMPTS: u32 = 50;
+    const LOOKUP_PEERS: usize = 3;
     const TIMEOUT_PREPARE: u64 = 6000; // Milliseconds
     const TIMEOUT_DELTA: u64 = 50; // Milliseconds
     const TIMEOUT_NO_NEIGHBOURS: u64 = 1000; // Milliseconds
@@ -134,14 +206,29 @@ impl NodeClientOverlay {
         overlay: Arc<OverlayNode>,
         rldp: Arc<RldpNode>,
         peers: Arc<Neighbours>,
+        ext_messages_config: &ExtMessagesConfig,
         #[cfg(feature = "telemetry")]
         telemetry: Arc<FullNodeNetworkTelemetry>,
     ) -> Self {
+        let ext_msg_batcher = if ext_messages_config.broadcast_batch_window_ms > 0 {
+            let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+            Self::start_ext_msg_batcher(
+                overlay_id.clone(),
+                overlay.clone(),
+                receiver,
+                Duration::from_millis(ext_messages_config.broadcast_batch_window_ms),
+                ext_messages_config.broadcast_batch_max_bytes
+            );
+            Some(sender)
+        } else {
+            None
+        };
         Self {
             overlay_id,
             overlay,
             rldp,
             peers,
+            ext_msg_batcher,
             #[cfg(feature = "telemetry")]
             telemetry,
             #[cfg(feature = "telemetry")]
@@ -164,14 +251,14 @@ impl NodeClientOverlay {
             #[cfg(feature = "telemetry")]
             tag_download_zero_state: tag_from_boxed_type::<DownloadZeroState>(),
             #[cfg(feature = "telemetry")]
-            tag_external_message_broadcast: tag_from_unboxed_type::<ExternalMessageBroadcast>(),
-            #[cfg(feature = "telemetry")]
             tag_get_archive_info: tag_from_boxed_type::<GetArchiveInfo>(),
             #[cfg(feature = "telemetry")]
             tag_get_archive_slice: tag_from_boxed_type::<GetArchiveSlice>(),
             #[cfg(feature = "telemetry")]
             tag_get_next_key_block_ids: tag_from_boxed_type::<GetNextKeyBlockIds>(),
             #[cfg(feature = "telemetry")]
+            tag_lookup_block: tag_from_boxed_type::<LookupBlock>(),
+            #[cfg(feature = "telemetry")]
             tag_new_shard_block_broadcast: tag_from_unboxed_type::<NewShardBlockBroadcast>(),
             #[cfg(feature = "telemetry")]
             tag_prepare_block: tag_from_boxed_type::<PrepareBlock>(),
@@ -186,6 +273,67 @@ impl NodeClientOverlay {
         }
     }
 
+    // Accumulates external messages during window or until size limit and sends them together.
+    // Protocol has no combined broadcast for external messages, so messages of a batch 
+    // are sent individually (but at once); every message is sent exactly once.
+    fn start_ext_msg_batcher(
+        overlay_id: Arc<OverlayShortId>,
+        overlay: Arc<OverlayNode>,
+        mut receiver: tokio::sync::mpsc::UnboundedReceiver<ExtMessageToBroadcast>,
+        window: Duration,
+        max_bytes: usize
+    ) {
+        tokio::spawn(async move {
+            while let Some(first) = receiver.recv().await {
+                let deadline = tokio::time::Instant::now() + window;
+                let mut bytes = first.data.len();
+                let mut batch = vec![first];
+                while bytes < max_bytes {
+                    match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                        Ok(Some(msg)) => {
+                            bytes += msg.data.len();
+                            batch.push(msg);
+                        }
+                        Ok(None) | Err(_) => break
+                    }
+                }
+                log::trace!(
+                    "Broadcasting batch of {} external messages ({} bytes) to overlay {}", 
+                    batch.len(), bytes, overlay_id
+                );
+                STATSD.histogram("ext_msg_broadcast_batch_size", batch.len() as f64);
+                STATSD.histogram("ext_msg_broadcast_batch_bytes", bytes as f64);
+                let sends = batch.into_iter().map(|msg| {
+                    let overlay = overlay.clone();
+                    let overlay_id = overlay_id.clone();
+                    async move {
+                        let result = Self::send_ext_msg_broadcast(&overlay, &overlay_id, &msg.data).await;
+                        msg.result.send(result).ok();
+                    }
+                });
+                futures::future::join_all(sends).await;
+            }
+        });
+    }
+
+    async fn send_ext_msg_broadcast(
+        overlay: &OverlayNode,
+        overlay_id: &Arc<OverlayShortId>,
+        msg: &[u8]
+    ) -> Result<BroadcastSendInfo> {
+        let broadcast = ExternalMessageBroadcast {
+            message: ExternalMessage {
+                data: ton::bytes(msg.to_vec())
+            }
+        }.into_boxed();
+        let broadcast = TaggedByteSlice {
+            object: &serialize(&broadcast)?,
+            #[cfg(feature = "telemetry")]
+            tag: tag_from_unboxed_type::<ExternalMessageBroadcast>()
+        };
+        overlay.broadcast(overlay_id, &broadcast, None).await
+    }
+
     pub fn overlay_id(&self) -> &Arc<OverlayShortId> {
         &self.overlay_id
     }
@@ -225,6 +373,7 @@ impl NodeClientOverlay {
             match answer.downcast::<D>() {
                 Ok(answer) => {
                     peer.query_success(roundtrip, false);
+                    self.peers.report(peer.id(), PeerOutcome::Success);
                     #[cfg(feature = "telemetry")]
                     self.telemetry.consumed_query(request_str, true, now.elapsed(), 0); // TODO data size (need to patch overlay)
                     return Ok(Some(answer))
@@ -232,12 +381,14 @@ impl NodeClientOverlay {
                 Err(obj) => {
                     #[cfg(feature = "telemetry")]
                     self.telemetry.consumed_query(request_str, false, now.elapsed(), 0);
+                    self.peers.report(peer.id(), PeerOutcome::BadData);
                     log::warn!("Wrong answer {:?} to {:?} from {}", obj, data.object, peer.id())
                 }
             }
         } else {
             #[cfg(feature = "telemetry")]
             self.telemetry.consumed_query(request_str, false, now.elapsed(), 0);
+            self.peers.report(peer.id(), PeerOutcome::Timeout);
             log::warn!("No reply to {:?} from {}", data.object, peer.id())
         }
 
@@ -267,11 +418,15 @@ impl NodeClientOverlay {
         let attempts = attempts.unwrap_or(Self::ADNL_ATTEMPTS);
 
         for _ in 0..attempts {
-            let peer = if let Some(p) = self.peers.choose_neighbour()? {
+            let peer = if let Some(p) = self.peers.choose_neighbour_except(
+                active_peers.map(|active_peers| active_peers.as_ref())
+            )? {
                 p
             } else {
                 tokio::time::sleep(Duration::from_millis(Self::TIMEOUT_NO_NEIGHBOURS)).await;
-                fail!("neighbour is not found!")
+                fail!(NodeError::Network(
+                    NetworkError::NoNeighbours, "neighbour is not found!".to_string()
+                ))
             };
             if let Some(active_peers) = &active_peers {
                 if active_peers.insert(peer.id().clone()).is_err() {
@@ -292,7 +447,10 @@ impl NodeClientOverlay {
             }
         }
 
-        fail!("Cannot send query {:?} in {} attempts", data.object, attempts)
+        fail!(NodeError::Network(
+            NetworkError::OutOfAttempts,
+            format!("Cannot send query {:?} in {} attempts", data.object, attempts)
+        ))
  
     }
 
@@ -307,6 +465,7 @@ impl NodeClientOverlay {
     {
         let (answer, peer, roundtrip) = self.send_rldp_query(request, peer, attempt).await?;
         peer.query_success(roundtrip, true);
+        self.peers.report(peer.id(), PeerOutcome::Success);
         Ok(answer)
     }
 
@@ -324,9 +483,11 @@ impl NodeClientOverlay {
         match Deserializer::new(&mut Cursor::new(answer)).read_boxed() {
             Ok(data) => {
                 peer.query_success(roundtrip, true);
+                self.peers.report(peer.id(), PeerOutcome::Success);
                 Ok((data, peer))
             },
             Err(e) => {
+                self.peers.report(peer.id(), PeerOutcome::BadData);
                 self.peers.update_neighbour_stats(peer.id(), roundtrip, false, true, true)?;
                 fail!(e)
             }
@@ -374,29 +535,35 @@ impl NodeClientOverlay {
         } else {
             #[cfg(feature = "telemetry")]
             self.telemetry.consumed_query(request_str, false, now.elapsed(), 0);
+            self.peers.report(peer.id(), PeerOutcome::Timeout);
             self.peers.update_neighbour_stats(peer.id(), roundtrip, false, true, true)?;
             fail!("No RLDP answer to {:?} from {}", request.object, peer.id())
         }
 
     }
 
+    // Malformed or mismatching data lowers score of the peer it is got from
+    fn check_data<T>(&self, peer: &Arc<Neighbour>, data: Result<T>) -> Result<T> {
+        if data.is_err() {
+            self.peers.report(peer.id(), PeerOutcome::BadData);
+        }
+        data
+    }
+
 }
 
 #[async_trait::async_trait]
 impl FullNodeOverlayClient for NodeClientOverlay {
 
     async fn broadcast_external_message(&self, msg: &[u8]) -> Result<BroadcastSendInfo> {
-        let broadcast = ExternalMessageBroadcast {
-            message: ExternalMessage {
-                data: ton::bytes(msg.to_vec())
-            }
-        }.into_boxed();
-        let broadcast = TaggedByteSlice {
-            object: &serialize(&broadcast)?,
-            #[cfg(feature = "telemetry")]
-            tag: self.tag_external_message_broadcast
-        };
-        self.overlay.broadcast(&self.overlay_id, &broadcast, None).await
+        if let Some(batcher) = self.ext_msg_batcher.as_ref() {
+            let (result, receiver) = tokio::sync::oneshot::channel();
+            batcher.send(ExtMessageToBroadcast { data: msg.to_vec(), result })
+                .map_err(|_| error!("External messages batcher of overlay {} is stopped", self.overlay_id))?;
+            return receiver.await
+                .map_err(|_| error!("External message broadcast to overlay {} was dropped", self.overlay_id))?
+        }
+        Self::send_ext_msg_broadcast(&self.overlay, &self.overlay_id, msg).await
     }
 
     async fn send_block_broadcast(&self, broadcast: BlockBroadcast) -> Result<()> {
@@ -443,6 +610,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
         block_id: &BlockIdExt, 
         is_link: bool, 
         key_block: bool, 
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<BlockProofStuff> {
 
         // Prepare
@@ -458,7 +626,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                 },
                 None, 
                 Some(Self::TIMEOUT_PREPARE), 
-                None
+                Some(active_peers)
             ).await?
         } else {
             self.send_adnl_query(
@@ -472,14 +640,17 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                 },
                 None, 
                 Some(Self::TIMEOUT_PREPARE), 
-                None
+                Some(active_peers)
             ).await?
         };
 
         // Download
         let (proof, is_link) = match prepare {
             PreparedProof::TonNode_PreparedProofEmpty => {
-                fail!("Got `TonNode_PreparedProofEmpty` from {}", good_peer.id())
+                fail!(NodeError::Network(
+                    NetworkError::NotFound,
+                    format!("Got `TonNode_PreparedProofEmpty` from {}", good_peer.id())
+                ))
             },
             PreparedProof::TonNode_PreparedProof => {
                 let proof = if key_block {
@@ -491,7 +662,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                             #[cfg(feature = "telemetry")]
                             tag: self.tag_download_key_block_proof
                         },
-                        good_peer,
+                        good_peer.clone(),
                         0
                     ).await?
                 } else {
@@ -503,7 +674,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                             #[cfg(feature = "telemetry")]
                             tag: self.tag_download_block_proof
                         },
-                        good_peer,
+                        good_peer.clone(),
                         0
                     ).await?
                 };
@@ -519,7 +690,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                             #[cfg(feature = "telemetry")]
                             tag: self.tag_download_key_block_proof_link
                         },
-                        good_peer,
+                        good_peer.clone(),
                         0
                     ).await?
                 } else {
@@ -531,7 +702,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
                             #[cfg(feature = "telemetry")]
                             tag: self.tag_download_block_proof_link
                         },
-                        good_peer,
+                        good_peer.clone(),
                         0
                     ).await?
                 };
@@ -539,7 +710,7 @@ impl FullNodeOverlayClient for NodeClientOverlay {
             }
         };
          
-        BlockProofStuff::deserialize(block_id, proof, is_link)
+        self.check_data(&good_peer, BlockProofStuff::deserialize(block_id, proof, is_link))
 
 /*
 Ok(if key_block {
@@ -582,6 +753,7 @@ Ok(if key_block {
     async fn download_block_full(
         &self, 
         id: &BlockIdExt, 
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<(BlockStuff, BlockProofStuff)> {
 
         // Prepare
@@ -595,15 +767,17 @@ Ok(if key_block {
             },
             Some(1),
             None,
-            None
+            Some(active_peers)
         ).await?;
         log::trace!("USE PEER {}, PREPARE {} FINISHED", peer.id(), id);
 
         // Download
         match prepare {
-            Prepared::TonNode_NotFound => fail!("Got `TonNode_NotFound` from {}", peer.id()),
+            Prepared::TonNode_NotFound => fail!(NodeError::Network(
+                NetworkError::NotFound, format!("Got `TonNode_NotFound` from {}", peer.id())
+            )),
             Prepared::TonNode_Prepared => {
-                let (data_full, _): (DataFull, _) = self.send_rldp_query_typed(
+                let (data_full, peer): (DataFull, _) = self.send_rldp_query_typed(
                     &TaggedObject {
                         object: DownloadBlockFull {
                             block: convert_block_id_ext_blk2api(id),
@@ -616,17 +790,28 @@ Ok(if key_block {
                 ).await?;
                 match data_full {
                     DataFull::TonNode_DataFullEmpty => {
-                        fail!("prepareBlock receives Prepared, but DownloadBlockFull receives DataFullEmpty");
+                        fail!(NodeError::Network(
+                            NetworkError::BadData,
+                            "prepareBlock receives Prepared, but DownloadBlockFull receives DataFullEmpty"
+                                .to_string()
+                        ))
                     },
                     DataFull::TonNode_DataFull(data_full) => {
                         if !compare_block_ids(&id, &data_full.id) {
-                            fail!("Block with another id was received");
+                            self.peers.report(peer.id(), PeerOutcome::BadData);
+                            fail!(NodeError::Network(
+                                NetworkError::BadData,
+                                format!("Block with another id was received from {}", peer.id())
+                            ))
                         }
-                        let block = BlockStuff::deserialize_checked(id.clone(), data_full.block.0)?;
-                        let proof = BlockProofStuff::deserialize(
-                            block.id(),
-                            data_full.proof.0,
-                            data_full.is_link.into())?;
+                        let block = self.check_data(
+                            &peer,
+                            BlockStuff::deserialize_checked(id.clone(), data_full.block.0)
+                        )?;
+                        let proof = self.check_data(
+                            &peer,
+                            BlockProofStuff::deserialize(block.id(), data_full.proof.0, data_full.is_link.into())
+                        )?;
                         Ok((block, proof))
                     }
                 }
@@ -718,7 +903,10 @@ Ok(if key_block {
         // Download
         match prepare {
             PreparedState::TonNode_NotFoundState => {
-                fail!("Got `TonNode_NotFoundState` from {}", good_peer.id())
+                fail!(NodeError::Network(
+                    NetworkError::NotFound,
+                    format!("Got `TonNode_NotFoundState` from {}", good_peer.id())
+                ))
             },
             PreparedState::TonNode_PreparedState => {
                 let state_bytes = self.send_rldp_query_raw(
@@ -729,10 +917,14 @@ Ok(if key_block {
                         #[cfg(feature = "telemetry")]
                         tag: self.tag_download_zero_state
                     },
-                    good_peer,
+                    good_peer.clone(),
                     0
                 ).await?;
-                Ok((ShardStateStuff::deserialize_zerostate(id.clone(), &state_bytes)?, state_bytes))
+                let state = self.check_data(
+                    &good_peer,
+                    ShardStateStuff::deserialize_zerostate(id.clone(), &state_bytes)
+                )?;
+                Ok((state, state_bytes))
             }
         }
     }
@@ -760,10 +952,77 @@ Ok(if key_block {
             }))
     }
     
+    // liteServer.lookupBlock mode:# id:tonNode.blockId lt:mode.1?long utime:mode.2?int = liteServer.BlockHeader;
+    // Neighbour without the block answers liteServer.error
+    async fn lookup_block_by_seqno(
+        &self,
+        shard: &ShardIdent,
+        seqno: u32
+    ) -> Result<Vec<(BlockIdExt, Arc<KeyId>)>> {
+        let data = TaggedTlObject {
+            object: TLObject::new(LookupBlock {
+                mode: 1,
+                id: BlockId {
+                    workchain: shard.workchain_id(),
+                    shard: shard.shard_prefix_with_tag() as i64,
+                    seqno: seqno as i32
+                },
+                lt: None,
+                utime: None
+            }),
+            #[cfg(feature = "telemetry")]
+            tag: self.tag_lookup_block
+        };
+        let mut asked = HashSet::new();
+        let mut answered = 0;
+        let mut found = Vec::new();
+        for _ in 0..Self::LOOKUP_PEERS * 3 {
+            if asked.len() >= Self::LOOKUP_PEERS {
+                break
+            }
+            let peer = match self.peers.choose_neighbour()? {
+                Some(peer) => peer,
+                None => break
+            };
+            if !asked.insert(peer.id().clone()) {
+                continue
+            }
+            let timeout = Some(AdnlNode::calc_timeout(peer.roundtrip_adnl()));
+            let answer = match self.overlay.query(peer.id(), &data, &self.overlay_id, timeout).await {
+                Ok(Some(answer)) => answer,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::debug!("lookupBlock {}:{} to {} failed: {}", shard, seqno, peer.id(), e);
+                    continue
+                }
+            };
+            match LookupAnswer::with_answer(answer) {
+                LookupAnswer::Found(id) => {
+                    answered += 1;
+                    found.push((id, peer.id().clone()))
+                },
+                LookupAnswer::NotFound => answered += 1,
+                LookupAnswer::Unsupported(answer) => {
+                    // The query is not standard, honest peer may not support it
+                    log::debug!("lookupBlock {}:{} to {}: {}", shard, seqno, peer.id(), answer)
+                },
+                LookupAnswer::Bad(e) => {
+                    self.peers.report(peer.id(), PeerOutcome::BadData);
+                    log::warn!("Bad answer to lookupBlock from {}: {}", peer.id(), e)
+                }
+            }
+        }
+        if answered == 0 {
+            fail!("No neighbour answered lookup of block {}:{}", shard, seqno)
+        }
+        Ok(found)
+    }
+
     // tonNode.downloadNextBlockFull prev_block:tonNode.blockIdExt = tonNode.DataFull;
     async fn download_next_block_full(
         &self, 
         prev_id: &BlockIdExt, 
+        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<(BlockStuff, BlockProofStuff)> {
 
         let request = TaggedObject { 
@@ -775,12 +1034,13 @@ Ok(if key_block {
         };
 
         // Set neighbor
-        let peer = if let Some(p) = self.peers.choose_neighbour()? {
+        let peer = if let Some(p) = self.peers.choose_neighbour_except(Some(active_peers.as_ref()))? {
             p
         } else {
             tokio::time::sleep(Duration::from_millis(Self::TIMEOUT_NO_NEIGHBOURS)).await;
-            fail!("neighbour is not found!")
+            fail!(NodeError::Network(NetworkError::NoNeighbours, "neighbour is not found!".to_string()))
         };
+        active_peers.insert(peer.id().clone()).ok();
         log::trace!("USE PEER {}, REQUEST {:?}", peer.id(), request.object);
         
         // Download
@@ -793,15 +1053,23 @@ Ok(if key_block {
         // Parse
         match data_full {
             DataFull::TonNode_DataFullEmpty =>  {
-                fail!("Got `TonNode_DataFullEmpty` from {}", peer.id())
+                fail!(NodeError::Network(
+                    NetworkError::NotFound, format!("Got `TonNode_DataFullEmpty` from {}", peer.id())
+                ))
             },
             DataFull::TonNode_DataFull(data_full) => {
                 let id = convert_block_id_ext_api2blk(&data_full.id)?;
-                let block = BlockStuff::deserialize_checked(id, data_full.block.to_vec())?;
-                let proof = BlockProofStuff::deserialize(
-                    block.id(), 
-                    data_full.proof.to_vec(), 
-                    data_full.is_link.clone().into()
+                let block = self.check_data(
+                    &peer,
+                    BlockStuff::deserialize_checked(id, data_full.block.to_vec())
+                )?;
+                let proof = self.check_data(
+                    &peer,
+                    BlockProofStuff::deserialize(
+                        block.id(),
+                        data_full.proof.to_vec(),
+                        data_full.is_link.clone().into()
+                    )
                 )?;
                 Ok((block, proof))
             }
@@ -812,10 +1080,23 @@ Ok(if key_block {
     async fn download_archive(
         &self, 
         mc_seq_no: u32,
+        min_speed: u64,
+        limiter: &BandwidthLimiter,
         active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
     ) -> Result<Option<Vec<u8>>> {
 
         const CHUNK_SIZE: i32 = 1 << 21;
+        // Failed or slow peer is not picked for other archives for a while
+        const BAD_PEER_PAUSE_MS: u64 = 60_000;
+        fn release_bad_peer(peer: &Arc<Neighbour>, active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>) {
+            let id = peer.id().clone();
+            let active_peers = active_peers.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(BAD_PEER_PAUSE_MS)).await;
+                active_peers.remove(&id);
+            });
+        }
+
         // tonNode.getArchiveInfo masterchain_seqno:int = tonNode.ArchiveInfo;
         let (archive_info, peer) = self.send_adnl_query(
             TaggedObject {
@@ -848,14 +1129,38 @@ Ok(if key_block {
                         #[cfg(feature = "telemetry")]
                         tag: self.tag_get_archive_slice
                     };
+                    let now = Instant::now();
                     match self.send_rldp_query_raw(&slice, peer.clone(), peer_attempt).await {
                         Ok(mut block_bytes) => {
                             let actual_size = block_bytes.len() as i32;
+                            let elapsed = now.elapsed().as_millis() as u64;
+                            let speed = actual_size as u64 * 1000 / elapsed.max(1);
+                            peer.update_download_speed(speed);
+                            // Throttling is not counted in peer speed
+                            limiter.consume(block_bytes.len()).await;
                             result.append(&mut block_bytes);
                             if actual_size < CHUNK_SIZE {
+                                // Truncated package would stall sync
+                                if let Err(e) = check_package(&result).await {
+                                    self.peers.report(peer.id(), PeerOutcome::BadData);
+                                    release_bad_peer(&peer, active_peers);
+                                    fail!(NodeError::Network(NetworkError::BadData, format!(
+                                        "download_archive {}: broken package from peer {}: {}",
+                                        info.id, peer.id(), e
+                                    )))
+                                }
                                 active_peers.remove(peer.id());
                                 return Ok(Some(result))
                             }
+                            if min_speed > 0 && speed < min_speed {
+                                peer.query_failed(elapsed, true);
+                                self.peers.report(peer.id(), PeerOutcome::Timeout);
+                                release_bad_peer(&peer, active_peers);
+                                fail!(
+                                    "download_archive {}: peer {} is too slow, {} bytes/s",
+                                    info.id, peer.id(), speed
+                                )
+                            }
                             offset += actual_size as i64;
                             part_attempt = 0;
                         },
@@ -867,7 +1172,7 @@ Ok(if key_block {
                                 info.id, e, offset, part_attempt
                             );
                             if part_attempt > 10 {
-                                active_peers.remove(peer.id());
+                                release_bad_peer(&peer, active_peers);
                                 fail!(
                                     "Error download_archive after {} attempts : {}", 
                                     part_attempt, e
@@ -880,6 +1185,10 @@ Ok(if key_block {
         }
     }
 
+    fn report_peer(&self, peer: &Arc<KeyId>, outcome: PeerOutcome) {
+        self.peers.report(peer, outcome)
+    }
+
     async fn wait_broadcast(&self) -> Result<(Broadcast, Arc<KeyId>)> {
         let receiver = self.overlay.clone();
         let id = self.overlay_id.clone();
@@ -897,3 +1206,35 @@ Ok(if key_block {
     }
 
 }
+
+// Walks all entries of archive package, fails if it is truncated or malformed
+async fn check_package(data: &[u8]) -> Result<()> {
+    let mut reader = read_package_from(data).await?;
+    while reader.next().await?.is_some() {}
+    Ok(())
+}
+
+#[test]
+fn test_lookup_answer() {
+    use ton_api::ton::lite_server::{blockheader, error as lite_error};
+    use ton_types::UInt256;
+
+    let id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), 10, UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[2; 32])
+    );
+    let header = TLObject::new(BlockHeader::LiteServer_BlockHeader(Box::new(blockheader::BlockHeader {
+        id: convert_block_id_ext_blk2api(&id),
+        mode: 1,
+        header_proof: ton::bytes(Vec::new())
+    })));
+    assert_eq!(LookupAnswer::with_answer(header), LookupAnswer::Found(id));
+    let error = |code: i32| TLObject::new(LiteError::LiteServer_Error(Box::new(lite_error::Error {
+        code,
+        message: "error".to_string()
+    })));
+    assert_eq!(LookupAnswer::with_answer(error(ERROR_CODE_NOTREADY)), LookupAnswer::NotFound);
+    // Peers not supporting the query are not reported
+    assert!(matches!(LookupAnswer::with_answer(error(601)), LookupAnswer::Unsupported(_)));
+    let other = TLObject::new(ton_api::ton::ton_node::Prepared::TonNode_NotFound);
+    assert!(matches!(LookupAnswer::with_answer(other), LookupAnswer::Unsupported(_)));
+}
@@ -1,13 +1,20 @@
 use crate::{
-    engine_traits::EngineOperations, 
-    network::neighbours::{PROTOCOL_CAPABILITIES, PROTOCOL_VERSION}
+    block::convert_block_id_ext_blk2api, engine::STATSD, engine_traits::EngineOperations,
+    network::{
+        lite_server::{ERROR_CODE_ERROR, ERROR_CODE_NOTREADY, ERROR_CODE_PROTOVIOLATION},
+        neighbours::{PROTOCOL_CAPABILITIES, PROTOCOL_VERSION}
+    }
 };
 
 use adnl::common::{
-    AdnlPeers, Answer, QueryResult, tag_from_boxed_type, tag_from_object, TaggedByteVec, TaggedObject
+    AdnlPeers, Answer, KeyId, QueryResult, tag_from_boxed_type, tag_from_object, TaggedByteVec,
+    TaggedObject
 };
 use overlay::QueriesConsumer;
-use std::{sync::Arc, convert::TryInto, cmp::min, fmt::Debug};
+use std::{
+    sync::{Arc, Mutex}, collections::HashMap, convert::TryInto, cmp::min, fmt::Debug,
+    time::{Duration, Instant}
+};
 use ton_api::{BoxedSerialize};
 use ton_api::{
     AnyBoxedSerialize, IntoBoxed, 
@@ -24,6 +31,10 @@ use ton_api::{
 
             }
         },
+        lite_server::{
+            BlockHeader, Error as LiteError, blockheader, error as lite_error
+        },
+        rpc::lite_server::LookupBlock,
         ton_node::{
             self,
             ArchiveInfo as ArchiveInfoBoxed, BlockDescription, Capabilities as CapabilitiesBoxed, 
@@ -32,11 +43,21 @@ use ton_api::{
         }
     }
 };
-use ton_block::BlockIdExt;
-use ton_types::{fail, Result};
+use ton_block::{AccountIdPrefixFull, BlockIdExt, ShardIdent};
+use ton_types::{error, fail, Result};
+
+// Unique peers served are counted within this period
+const SERVE_PEERS_WINDOW: Duration = Duration::from_secs(3600);
+
+fn lookup_error(code: i32, message: String) -> LiteError {
+    LiteError::LiteServer_Error(Box::new(lite_error::Error { code, message }))
+}
 
 pub struct FullNodeOverlayService {
     engine: Arc<dyn EngineOperations>,
+    serve_queries: tokio::sync::Semaphore,
+    max_serve_queries: usize,
+    serve_peers: Mutex<HashMap<Arc<KeyId>, Instant>>,
     #[cfg(feature = "telemetry")]
     tag_capabilities: u32,
     #[cfg(feature = "telemetry")]
@@ -46,8 +67,17 @@ pub struct FullNodeOverlayService {
 impl FullNodeOverlayService {
 
     pub fn new(engine: Arc<dyn EngineOperations>) -> Self {
+        let archive_node = engine.archive_node_config();
+        let max_serve_queries = if archive_node.enabled {
+            archive_node.max_serve_queries
+        } else {
+            engine.max_serve_queries()
+        };
         Self{
             engine,
+            serve_queries: tokio::sync::Semaphore::new(max_serve_queries),
+            max_serve_queries,
+            serve_peers: Mutex::new(HashMap::new()),
             #[cfg(feature = "telemetry")]
             tag_capabilities: tag_from_boxed_type::<CapabilitiesBoxed>(),
             #[cfg(feature = "telemetry")]
@@ -396,11 +426,15 @@ impl FullNodeOverlayService {
         let block_id = (&query.block).try_into()?;
         if let Some(handle) = self.engine.load_block_handle(&block_id)? {
             if handle.has_persistent_state() {
+                let _permit = self.serve_permit()?;
                 let data = self.engine.load_persistent_state_slice(
                     &handle,
                     query.offset as u64,
                     query.max_size as u64
                 ).await?;
+                self.engine.consume_serve_bandwidth(data.len()).await;
+                STATSD.incr("serve_state_slices");
+                STATSD.count("serve_state_bytes", data.len() as f64);
                 let answer = TaggedByteVec {
                     object: data,
                     #[cfg(feature = "telemetry")]
@@ -527,12 +561,17 @@ impl FullNodeOverlayService {
 
     // tonNode.getArchiveSlice archive_id:long offset:long max_size:int = tonNode.Data;
     async fn get_archive_slice(&self, query: GetArchiveSlice) -> Result<TaggedByteVec> {
+        let _permit = self.serve_permit()?;
+        let data = self.engine.get_archive_slice(
+            query.archive_id as u64,
+            query.offset as u64,
+            query.max_size as u32
+        ).await?;
+        self.engine.consume_serve_bandwidth(data.len()).await;
+        STATSD.incr("serve_archive_slices");
+        STATSD.count("serve_archive_bytes", data.len() as f64);
         let answer = TaggedByteVec {
-            object: self.engine.get_archive_slice(
-                query.archive_id as u64, 
-                query.offset as u64, 
-                query.max_size as u32
-            ).await?,
+            object: data,
             #[cfg(feature = "telemetry")]
             tag: 0x8000000E // Raw reply to download archive slice
         };
@@ -555,6 +594,86 @@ impl FullNodeOverlayService {
         Ok(answer)
     }
 
+    // liteServer.lookupBlock mode:# id:tonNode.blockId lt:mode.1?long utime:mode.2?int = liteServer.BlockHeader;
+    // Only lookup by seqno is supported. Missing block is answered with liteServer.error
+    // "not ready" as lite server does, other errors have their own codes.
+    async fn lookup_block(&self, query: LookupBlock) -> Result<TaggedByteVec> {
+        let object = match self.lookup_block_by_seqno(&query).await {
+            Ok(Some(id)) => adnl::common::serialize(&BlockHeader::LiteServer_BlockHeader(
+                Box::new(blockheader::BlockHeader {
+                    id: convert_block_id_ext_blk2api(&id),
+                    mode: query.mode,
+                    header_proof: ton::bytes(Vec::new())
+                })
+            ))?,
+            Ok(None) => adnl::common::serialize(&lookup_error(
+                ERROR_CODE_NOTREADY, format!("Block {}:{} is not found", query.id.shard, query.id.seqno)
+            ))?,
+            Err(e) => {
+                let code = if query.mode & 1 == 0 {
+                    ERROR_CODE_PROTOVIOLATION
+                } else {
+                    ERROR_CODE_ERROR
+                };
+                adnl::common::serialize(&lookup_error(code, e.to_string()))?
+            }
+        };
+        Ok(TaggedByteVec {
+            object,
+            #[cfg(feature = "telemetry")]
+            tag: 0x8000000F // Raw reply to lookup block
+        })
+    }
+
+    // Block is answered only if it has proof (or proof link) to be checked by requester
+    async fn lookup_block_by_seqno(&self, query: &LookupBlock) -> Result<Option<BlockIdExt>> {
+        if query.mode & 1 == 0 {
+            fail!("Only lookup by seqno is supported")
+        }
+        let shard = ShardIdent::with_tagged_prefix(query.id.workchain, query.id.shard as u64)?;
+        let acc_pfx = AccountIdPrefixFull {
+            workchain_id: shard.workchain_id(),
+            prefix: shard.shard_prefix_with_tag()
+        };
+        let handle = match self.engine.find_block_by_seq_no(&acc_pfx, query.id.seqno as u32).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                log::trace!("lookupBlock {}:{}: {}", shard, query.id.seqno, e);
+                return Ok(None)
+            }
+        };
+        // Index returns block of the shard containing prefix, it may be split or merged one
+        if (handle.id().shard() != &shard) || (handle.id().seq_no() != query.id.seqno as u32) {
+            return Ok(None)
+        }
+        let mut is_link = false;
+        if !handle.has_proof_or_link(&mut is_link) {
+            return Ok(None)
+        }
+        Ok(Some(handle.id().clone()))
+    }
+
+    // Peer asking when all slots are busy retries with another node
+    fn serve_permit(&self) -> Result<tokio::sync::SemaphorePermit> {
+        let permit = self.serve_queries.try_acquire().map_err(|_| {
+            STATSD.incr("serve_queries_rejected");
+            error!("Too many archive and persistent state slices are being served, try later")
+        })?;
+        STATSD.gauge(
+            "serve_queries_in_flight",
+            (self.max_serve_queries - self.serve_queries.available_permits()) as f64
+        );
+        Ok(permit)
+    }
+
+    fn report_served_peer(&self, peer: &Arc<KeyId>) {
+        let now = Instant::now();
+        let mut peers = self.serve_peers.lock().unwrap();
+        peers.insert(peer.clone(), now);
+        peers.retain(|_, served| now.duration_since(*served) < SERVE_PEERS_WINDOW);
+        STATSD.gauge("serve_unique_peers", peers.len() as f64);
+    }
+
     async fn consume_query<'a, Q, A, F>(
         &'a self,
         query: TLObject,
@@ -658,7 +777,7 @@ impl FullNodeOverlayService {
 #[async_trait::async_trait]
 impl QueriesConsumer for FullNodeOverlayService {
     #[allow(dead_code)]
-    async fn try_consume_query(&self, query: TLObject, _adnl_peers: &AdnlPeers) -> Result<QueryResult> {
+    async fn try_consume_query(&self, query: TLObject, adnl_peers: &AdnlPeers) -> Result<QueryResult> {
 
         log::debug!("try_consume_query {:?}", query);
 
@@ -754,7 +873,10 @@ impl QueriesConsumer for FullNodeOverlayService {
             query,
             &Self::download_persistent_state_slice
         ).await? {
-            Ok(answer) => return Ok(answer),
+            Ok(answer) => {
+                self.report_served_peer(adnl_peers.other());
+                return Ok(answer)
+            },
             Err(query) => query
         };
 
@@ -810,7 +932,10 @@ impl QueriesConsumer for FullNodeOverlayService {
             query,
             &Self::get_archive_slice
         ).await? {
-            Ok(answer) => return Ok(answer),
+            Ok(answer) => {
+                self.report_served_peer(adnl_peers.other());
+                return Ok(answer)
+            },
             Err(query) => query
         };
 
@@ -822,7 +947,101 @@ impl QueriesConsumer for FullNodeOverlayService {
             Err(query) => query
         };
 
+        let query = match self.consume_query_raw::<LookupBlock, _>(
+            query,
+            &Self::lookup_block
+        ).await? {
+            Ok(answer) => return Ok(answer),
+            Err(query) => query
+        };
+
         log::warn!("Unsupported full node query {:?}", query);
         failure::bail!("Unsupported full node query {:?}", query);
     }
 }
+
+#[test]
+fn test_lookup_block() {
+    use std::sync::Weak;
+    use storage::types::BlockHandle;
+    use ton_api::ton::ton_node::blockid::BlockId;
+    use ton_types::UInt256;
+
+    struct TestEngine {
+        handle_cache: Arc<lockfree::map::Map<BlockIdExt, Weak<BlockHandle>>>,
+    }
+    #[async_trait::async_trait]
+    impl EngineOperations for TestEngine {
+        fn archive_node_config(&self) -> crate::config::ArchiveNodeConfig {
+            Default::default()
+        }
+        fn max_serve_queries(&self) -> usize {
+            2
+        }
+        // Blocks 10 and 11 are in DB, only the first one has proof
+        async fn find_block_by_seq_no(
+            &self,
+            _acc_pfx: &AccountIdPrefixFull,
+            seqno: u32
+        ) -> Result<Arc<BlockHandle>> {
+            if (seqno != 10) && (seqno != 11) {
+                fail!("Block with seqno {} is not found", seqno)
+            }
+            let id = BlockIdExt::with_params(
+                ShardIdent::masterchain(), seqno, UInt256::from_slice(&[1; 32]), UInt256::from_slice(&[2; 32])
+            );
+            let handle = BlockHandle::new(id, self.handle_cache.clone());
+            if seqno == 10 {
+                handle.set_proof();
+            }
+            Ok(Arc::new(handle))
+        }
+    }
+    let service = FullNodeOverlayService::new(
+        Arc::new(TestEngine { handle_cache: Arc::new(lockfree::map::Map::new()) })
+    );
+    let lookup = |mode: i32, seqno: i32| {
+        let query = LookupBlock {
+            mode,
+            id: BlockId { workchain: -1, shard: ton_block::SHARD_FULL as i64, seqno },
+            lt: None,
+            utime: None
+        };
+        let answer = tokio::runtime::Runtime::new().unwrap()
+            .block_on(service.lookup_block(query)).unwrap();
+        ton_api::Deserializer::new(&mut std::io::Cursor::new(answer.object)).read_boxed::<TLObject>().unwrap()
+    };
+    let error_code = |answer: TLObject| match answer.downcast::<LiteError>() {
+        Ok(LiteError::LiteServer_Error(error)) => error.code,
+        Err(answer) => panic!("unexpected answer {:?}", answer)
+    };
+    match lookup(1, 10).downcast::<BlockHeader>() {
+        Ok(header) => assert_eq!(header.id().seqno, 10),
+        Err(answer) => panic!("unexpected answer {:?}", answer)
+    }
+    // Missing block and block without proof are not found
+    assert_eq!(error_code(lookup(1, 11)), ERROR_CODE_NOTREADY);
+    assert_eq!(error_code(lookup(1, 12)), ERROR_CODE_NOTREADY);
+    // Lookup by lt is not supported
+    assert_eq!(error_code(lookup(2, 10)), ERROR_CODE_PROTOVIOLATION);
+}
+
+#[test]
+fn test_serve_queries_limit() {
+    struct TestEngine;
+    #[async_trait::async_trait]
+    impl EngineOperations for TestEngine {
+        fn archive_node_config(&self) -> crate::config::ArchiveNodeConfig {
+            Default::default()
+        }
+        fn max_serve_queries(&self) -> usize {
+            2
+        }
+    }
+    let service = FullNodeOverlayService::new(Arc::new(TestEngine));
+    let first = service.serve_permit().unwrap();
+    let _second = service.serve_permit().unwrap();
+    assert!(service.serve_permit().is_err());
+    drop(first);
+    assert!(service.serve_permit().is_ok());
+}
@@ -0,0 +1,250 @@
+use crate::{config::HealthServerConfig, engine_traits::EngineOperations};
+use std::{sync::{Arc, Mutex}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}};
+use ton_block::BlockIdExt;
+use ton_types::{fail, Result};
+
+const MAX_REQUEST_LEN: usize = 4096;
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+// Plain HTTP server for orchestrators: `/live` answers while the node applies
+// masterchain blocks, `/ready` - while the node is synced and its databases accept writes
+pub struct HealthServer {
+    engine: Arc<dyn EngineOperations>,
+    max_timediff: u32,
+    max_stall: Duration,
+    progress: Mutex<Progress>,
+    started_at: Instant,
+}
+
+// Last applied masterchain block seen by `/live` and when it was changed
+struct Progress {
+    last_mc_block: Option<BlockIdExt>,
+    changed_at: Instant,
+}
+
+impl HealthServer {
+
+    pub async fn listen(
+        config: &HealthServerConfig,
+        engine: Arc<dyn EngineOperations>
+    ) -> Result<()> {
+        let address = match &config.address {
+            Some(address) => address,
+            None => return Ok(())
+        };
+        let listener = TcpListener::bind(address).await?;
+        log::info!("Health server is listening on {}", address);
+        let server = Arc::new(Self {
+            engine,
+            max_timediff: config.ready_max_timediff_sec,
+            max_stall: Duration::from_secs(config.live_max_stall_sec as u64),
+            progress: Mutex::new(Progress { last_mc_block: None, changed_at: Instant::now() }),
+            started_at: Instant::now(),
+        });
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        let server = server.clone();
+                        tokio::spawn(async move {
+                            let timeout = Duration::from_millis(REQUEST_TIMEOUT_MS);
+                            match tokio::time::timeout(timeout, server.serve(stream)).await {
+                                Ok(Err(e)) => log::debug!("Health request failed: {}", e),
+                                Err(_) => log::debug!("Health request timed out"),
+                                Ok(Ok(())) => ()
+                            }
+                        });
+                    }
+                    Err(e) => log::warn!("Health server can't accept connection: {}", e)
+                }
+            }
+        });
+        Ok(())
+    }
+
+    async fn serve(&self, mut stream: TcpStream) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let len = stream.read(&mut buf).await?;
+            if len == 0 {
+                break
+            }
+            request.extend_from_slice(&buf[..len]);
+            if request.len() > MAX_REQUEST_LEN {
+                fail!("Health request is too long")
+            }
+        }
+        let (status, body) = match request_path(&request) {
+            Some("/live") => {
+                let (live, body) = self.live();
+                (if live { 200 } else { 503 }, body)
+            }
+            Some("/ready") => {
+                let (ready, body) = self.ready().await;
+                (if ready { 200 } else { 503 }, body)
+            }
+            _ => (404, serde_json::json!({ "error": "not found" }))
+        };
+        let body = body.to_string();
+        let response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+            Connection: close\r\n\r\n{}",
+            status, status_text(status), body.len(), body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.shutdown().await?;
+        Ok(())
+    }
+
+    fn live(&self) -> (bool, serde_json::Value) {
+        let last_mc_block = self.engine.load_last_applied_mc_block_id().ok().flatten();
+        let mut progress = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+        let (live, mut body) = liveness(
+            &mut progress, last_mc_block.as_deref(), Instant::now(), self.max_stall
+        );
+        body["uptime_sec"] = serde_json::json!(self.started_at.elapsed().as_secs());
+        (live, body)
+    }
+
+    // The same data as engine's stats in console
+    async fn ready(&self) -> (bool, serde_json::Value) {
+        let timediff = self.engine.load_last_applied_mc_block_time().map(|last| {
+            last.map(|(_, gen_utime)| {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+                now.as_secs() as i64 - gen_utime as i64
+            })
+        });
+        // Probe write must not block the runtime if the DB is stuck
+        let engine = self.engine.clone();
+        let db_writable = match tokio::task::spawn_blocking(move || engine.check_db_writable()).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string())
+        };
+        readiness(
+            timediff.map_err(|e| e.to_string()),
+            self.max_timediff,
+            db_writable,
+            &self.engine.external_db_health()
+        )
+    }
+}
+
+// Node is not live if last applied masterchain block has not changed for `max_stall`
+// (zero - not checked). Before the first block is applied (boot) it is live.
+fn liveness(
+    progress: &mut Progress,
+    last_mc_block: Option<&BlockIdExt>,
+    now: Instant,
+    max_stall: Duration
+) -> (bool, serde_json::Value) {
+    if progress.last_mc_block.as_ref() != last_mc_block {
+        progress.last_mc_block = last_mc_block.cloned();
+        progress.changed_at = now;
+    }
+    let stalled_for = now.saturating_duration_since(progress.changed_at);
+    let live = last_mc_block.is_none() || max_stall.is_zero() || stalled_for <= max_stall;
+    let body = serde_json::json!({
+        "live": live,
+        "last_applied_mc_block": last_mc_block.map(|id| id.to_string()),
+        "stalled_sec": stalled_for.as_secs(),
+        "max_stall_sec": max_stall.as_secs(),
+    });
+    (live, body)
+}
+
+fn readiness(
+    timediff: std::result::Result<Option<i64>, String>,
+    max_timediff: u32,
+    db_writable: std::result::Result<(), String>,
+    external_db_health: &str
+) -> (bool, serde_json::Value) {
+    let synced = matches!(timediff, Ok(Some(diff)) if diff <= max_timediff as i64);
+    // Checked only if configured, otherwise it is "not checked"
+    let external_db_healthy = !external_db_health.starts_with("unhealthy");
+    let ready = synced && db_writable.is_ok() && external_db_healthy;
+    let body = serde_json::json!({
+        "ready": ready,
+        "timediff": match &timediff {
+            Ok(diff) => serde_json::json!(diff),
+            Err(e) => serde_json::json!(e)
+        },
+        "max_timediff": max_timediff,
+        "db_writable": match &db_writable {
+            Ok(()) => serde_json::json!(true),
+            Err(e) => serde_json::json!(e)
+        },
+        "external_db_health": external_db_health,
+    });
+    (ready, body)
+}
+
+fn request_path(request: &[u8]) -> Option<&str> {
+    let line = std::str::from_utf8(request).ok()?.lines().next()?;
+    let mut parts = line.split_whitespace();
+    match (parts.next()?, parts.next()?) {
+        ("GET", path) => Some(path.split('?').next().unwrap_or(path)),
+        _ => None
+    }
+}
+
+fn status_text(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        503 => "Service Unavailable",
+        _ => "Not Found"
+    }
+}
+
+#[test]
+fn test_health_readiness() {
+    let (ready, body) = readiness(Ok(Some(5)), 60, Ok(()), "not checked");
+    assert!(ready);
+    assert_eq!(body["timediff"], 5);
+
+    // not synced yet, before boot, db and external db problems
+    assert!(!readiness(Ok(Some(61)), 60, Ok(()), "not checked").0);
+    assert!(!readiness(Ok(None), 60, Ok(()), "not checked").0);
+    let (ready, body) = readiness(
+        Ok(Some(5)), 60, Err("Internal DB is in read-only mode".to_string()), "not checked"
+    );
+    assert!(!ready);
+    assert_eq!(body["db_writable"], "Internal DB is in read-only mode");
+    assert!(!readiness(Ok(Some(5)), 60, Ok(()), "unhealthy, checked at 1: timeout").0);
+    assert!(readiness(Ok(Some(5)), 60, Ok(()), "healthy, checked at 1").0);
+
+    assert_eq!(request_path(b"GET /ready HTTP/1.1\r\nHost: x\r\n\r\n"), Some("/ready"));
+    assert_eq!(request_path(b"GET /live?verbose=1 HTTP/1.0\r\n\r\n"), Some("/live"));
+    assert_eq!(request_path(b"POST /live HTTP/1.1\r\n\r\n"), None);
+}
+
+#[test]
+fn test_health_liveness() {
+    let start = Instant::now();
+    let max_stall = Duration::from_secs(600);
+    let id = |seq_no| BlockIdExt {
+        shard_id: ton_block::ShardIdent::masterchain(),
+        seq_no,
+        ..Default::default()
+    };
+    let mut progress = Progress { last_mc_block: None, changed_at: start };
+
+    // boot is in progress
+    assert!(liveness(&mut progress, None, start + max_stall * 2, max_stall).0);
+
+    let now = start + max_stall * 2;
+    assert!(liveness(&mut progress, Some(&id(1)), now, max_stall).0);
+    assert!(liveness(&mut progress, Some(&id(1)), now + max_stall, max_stall).0);
+    let (live, body) = liveness(&mut progress, Some(&id(1)), now + max_stall * 2, max_stall);
+    assert!(!live);
+    assert_eq!(body["stalled_sec"], 1200);
+
+    // blocks are applied again
+    let (live, body) = liveness(&mut progress, Some(&id(2)), now + max_stall * 3, max_stall);
+    assert!(live);
+    assert_eq!(body["stalled_sec"], 0);
+
+    // not checked
+    assert!(liveness(&mut progress, Some(&id(2)), now + max_stall * 10, Duration::ZERO).0);
+}
@@ -0,0 +1,717 @@
+use crate::{
+    block::{convert_block_id_ext_api2blk, convert_block_id_ext_blk2api},
+    engine::STATSD, engine_traits::EngineOperations, shard_state::ShardStateStuff,
+};
+use adnl::{
+    common::{QueryResult, Subscriber, AdnlPeers},
+    server::{AdnlServer, AdnlServerConfig}
+};
+use num_bigint::BigInt;
+use std::{io::Cursor, sync::Arc, time::Duration};
+use ton_api::{Deserializer, ton::{
+    self, TLObject,
+    lite_server::{
+        AccountState, CurrentTime, Error as LiteError, MasterchainInfo, RunMethodResult,
+        SendMsgStatus, TransactionList, accountid::AccountId as LiteAccountId,
+        accountstate, currenttime, error as lite_error, masterchaininfo, runmethodresult,
+        sendmsgstatus, transactionlist
+    },
+    rpc::lite_server::{
+        GetAccountState, GetMasterchainInfo, GetTime, GetTransactions, Query, RunSmcMethod,
+        SendMessage, WaitMasterchainSeqno
+    },
+    ton_node::zerostateidext::ZeroStateIdExt
+}};
+use ton_block::{
+    Account, AccountIdPrefixFull, Block, BlockIdExt, Deserializable, MerkleProof,
+    Serializable, ShardAccount, ShardStateUnsplit, MASTERCHAIN_ID
+};
+use ton_types::{
+    error, fail, AccountId, BuilderData, Cell, Result, SliceData, UInt256, UsageTree,
+    cells_serialization::{BagOfCells, deserialize_tree_of_cells, serialize_tree_of_cells}
+};
+use ton_vm::{
+    SmartContractInfo,
+    error::tvm_exception_or_custom_code,
+    executor::{Engine as VmEngine, gas::gas_state::Gas},
+    stack::{
+        Stack, StackItem, savelist::SaveList,
+        integer::{IntegerData, serialization::SignedIntegerBigEndianEncoding}
+    }
+};
+
+// Standard liteserver error codes
+pub(crate) const ERROR_CODE_ERROR: i32 = 601;
+pub(crate) const ERROR_CODE_PROTOVIOLATION: i32 = 621;
+// Also data which is not found
+pub(crate) const ERROR_CODE_NOTREADY: i32 = 651;
+const ERROR_CODE_TIMEOUT: i32 = 652;
+
+const MAX_TRANSACTIONS_PER_QUERY: i32 = 16;
+const GET_METHOD_GAS_LIMIT: i64 = 1_000_000;
+// Get method is bounded by gas, its answer isn't waited longer anyway
+const GET_METHOD_TIMEOUT_MS: u64 = 5000;
+// Exit code of get method of missing or not active account
+const EXIT_CODE_NO_ACCOUNT: i32 = -256;
+const WAIT_MASTERCHAIN_SEQNO_POLL_MS: u64 = 50;
+
+#[derive(Debug, failure::Fail)]
+enum LiteServerError {
+    // Data is not in DB: pruned by GC or not downloaded yet
+    #[fail(display = "{}", 0)]
+    NotReady(String),
+    #[fail(display = "{}", 0)]
+    ProtoViolation(String),
+    #[fail(display = "{}", 0)]
+    Timeout(String),
+}
+
+pub struct LiteServer {
+    adnl: AdnlServer
+}
+
+impl LiteServer {
+    pub async fn with_config(
+        config: AdnlServerConfig,
+        engine: Arc<dyn EngineOperations>
+    ) -> Result<Self> {
+        let ret = Self {
+            adnl: AdnlServer::listen(
+                config,
+                vec![Arc::new(LiteServerQuerySubscriber { engine })]
+            ).await?
+        };
+        Ok(ret)
+    }
+    pub async fn shutdown(self) {
+        self.adnl.shutdown().await
+    }
+}
+
+// Account found in shard state referred by masterchain block, with proofs of the way
+struct AccountLookup {
+    shard_block_id: BlockIdExt,
+    // Masterchain block header and shard hashes in its state, empty for masterchain account
+    shard_proof: Vec<u8>,
+    // Shard block header and account in its state
+    proof: Vec<u8>,
+    shard_state: ShardStateStuff,
+    mc_state: ShardStateStuff,
+    account: Option<ShardAccount>,
+}
+
+struct LiteServerQuerySubscriber {
+    engine: Arc<dyn EngineOperations>
+}
+
+impl LiteServerQuerySubscriber {
+
+    async fn get_masterchain_info(&self) -> Result<MasterchainInfo> {
+        let last = self.engine.load_last_applied_mc_block_id()?.ok_or_else(
+            || LiteServerError::NotReady("No masterchain blocks applied yet".to_string())
+        )?;
+        let state = self.load_state(&last).await?;
+        let zero_state_id = self.engine.zero_state_id();
+        Ok(MasterchainInfo::LiteServer_MasterchainInfo(Box::new(masterchaininfo::MasterchainInfo {
+            last: convert_block_id_ext_blk2api(&last),
+            state_root_hash: ton::int256(state.root_cell().repr_hash().as_slice().to_owned()),
+            init: ZeroStateIdExt {
+                workchain: zero_state_id.shard().workchain_id(),
+                root_hash: ton::int256(zero_state_id.root_hash.as_slice().to_owned()),
+                file_hash: ton::int256(zero_state_id.file_hash.as_slice().to_owned()),
+            }
+        })))
+    }
+
+    fn get_time(&self) -> CurrentTime {
+        CurrentTime::LiteServer_CurrentTime(Box::new(currenttime::CurrentTime {
+            now: self.engine.now() as i32
+        }))
+    }
+
+    async fn get_account_state(
+        &self,
+        mc_block_id: BlockIdExt,
+        account: &LiteAccountId
+    ) -> Result<AccountState> {
+        let lookup = self.lookup_account(&mc_block_id, account).await?;
+        let mut state = Vec::new();
+        if let Some(account) = lookup.account.as_ref() {
+            serialize_tree_of_cells(&account.account_cell(), &mut state)?;
+        }
+        Ok(AccountState::LiteServer_AccountState(Box::new(accountstate::AccountState {
+            id: convert_block_id_ext_blk2api(&mc_block_id),
+            shardblk: convert_block_id_ext_blk2api(&lookup.shard_block_id),
+            shard_proof: ton::bytes(lookup.shard_proof),
+            proof: ton::bytes(lookup.proof),
+            state: ton::bytes(state),
+        })))
+    }
+
+    async fn run_smc_method(&self, query: RunSmcMethod) -> Result<RunMethodResult> {
+        let mc_block_id = convert_block_id_ext_api2blk(&query.id)?;
+        let lookup = self.lookup_account(&mc_block_id, &query.account).await?;
+        let account = match lookup.account.as_ref() {
+            Some(account) => Some(account.read_account()?),
+            None => None
+        };
+        let (exit_code, result) = match account {
+            Some(account) if account.get_code().is_some() && account.get_data().is_some() => {
+                let gen_lt = lookup.shard_state.state().gen_lt();
+                let gen_time = lookup.shard_state.state().gen_time();
+                let mc_state = lookup.mc_state.clone();
+                let method_id = query.method_id;
+                let params = query.params.0.clone();
+                run_blocking_with_timeout(
+                    Duration::from_millis(GET_METHOD_TIMEOUT_MS),
+                    move || run_get_method(&account, &mc_state, gen_time, gen_lt, method_id, &params)
+                ).await?
+            },
+            _ => (EXIT_CODE_NO_ACCOUNT, Vec::new())
+        };
+        // Initial c7 and library extras are not provided
+        let mode = query.mode & 0x7;
+        let state_proof = match lookup.account.as_ref() {
+            Some(account) if mode & 2 != 0 => {
+                let root = account.account_cell();
+                let usage_tree = UsageTree::with_root(root.clone());
+                Account::construct_from(&mut usage_tree.root_slice())?;
+                serialize_cells(&[MerkleProof::create_by_usage_tree(&root, usage_tree)?.serialize()?])?
+            },
+            _ => Vec::new()
+        };
+        Ok(RunMethodResult::LiteServer_RunMethodResult(Box::new(runmethodresult::RunMethodResult {
+            mode,
+            id: query.id,
+            shardblk: convert_block_id_ext_blk2api(&lookup.shard_block_id),
+            shard_proof: if mode & 1 != 0 { Some(ton::bytes(lookup.shard_proof)) } else { None },
+            proof: if mode & 1 != 0 { Some(ton::bytes(lookup.proof)) } else { None },
+            state_proof: if mode & 2 != 0 { Some(ton::bytes(state_proof)) } else { None },
+            init_c7: None,
+            lib_extras: None,
+            exit_code,
+            result: if mode & 4 != 0 { Some(ton::bytes(result)) } else { None },
+        })))
+    }
+
+    async fn get_transactions(&self, query: &GetTransactions) -> Result<TransactionList> {
+        if query.count <= 0 || query.count > MAX_TRANSACTIONS_PER_QUERY {
+            fail!(LiteServerError::ProtoViolation(format!(
+                "Count of transactions must be from 1 to {}", MAX_TRANSACTIONS_PER_QUERY
+            )))
+        }
+        let account = UInt256::from(query.account.id.0);
+        let transactions = self.engine.load_account_transactions(
+            query.account.workchain, &account, query.lt as u64, query.count as usize, true
+        ).await.map_err(|e| LiteServerError::NotReady(format!("Can't load transactions: {}", e)))?;
+        match transactions.first() {
+            Some((tr, _)) if tr.lt == query.lt as u64 && tr.hash.as_slice() == &query.hash.0 => (),
+            _ => fail!(LiteServerError::NotReady(format!(
+                "Transaction of account {}:{} with lt {} is not found",
+                query.account.workchain, account.to_hex_string(), query.lt
+            )))
+        }
+        let mut ids = Vec::new();
+        let mut roots = Vec::new();
+        for (tr, boc) in transactions {
+            let boc = boc.ok_or_else(|| error!("INTERNAL ERROR: no BOC of transaction {}", tr.hash))?;
+            roots.push(deserialize_tree_of_cells(&mut Cursor::new(&boc))?);
+            ids.push(convert_block_id_ext_blk2api(&tr.block_id));
+        }
+        Ok(TransactionList::LiteServer_TransactionList(Box::new(transactionlist::TransactionList {
+            ids: ids.into(),
+            transactions: ton::bytes(serialize_cells(&roots)?),
+        })))
+    }
+
+    // Clients of lite server are not trusted, so precheck is never skipped for them
+    async fn send_message(&self, body: &[u8]) -> Result<SendMsgStatus> {
+        self.engine.redirect_external_message(body, false).await
+            .map_err(|e| LiteServerError::ProtoViolation(format!("Message is rejected: {}", e)))?;
+        Ok(SendMsgStatus::LiteServer_SendMsgStatus(Box::new(sendmsgstatus::SendMsgStatus {
+            status: 1
+        })))
+    }
+
+    async fn lookup_account(
+        &self,
+        mc_block_id: &BlockIdExt,
+        account: &LiteAccountId
+    ) -> Result<AccountLookup> {
+        if !mc_block_id.shard().is_masterchain() {
+            fail!(LiteServerError::ProtoViolation(format!(
+                "Block {} is not a masterchain one", mc_block_id
+            )))
+        }
+        let applied = self.engine.load_block_handle(mc_block_id)?
+            .map(|handle| handle.is_applied())
+            .unwrap_or(false);
+        if !applied {
+            fail!(LiteServerError::NotReady(format!("Block {} is not applied", mc_block_id)))
+        }
+        let mc_state = self.load_state(mc_block_id).await?;
+        let account_id = UInt256::from(account.id.0);
+        let (shard_block_id, shard_proof, shard_state) = if account.workchain == MASTERCHAIN_ID {
+            (mc_block_id.clone(), Vec::new(), mc_state.clone())
+        } else {
+            let mut prefix = [0; 8];
+            prefix.copy_from_slice(&account_id.as_slice()[..8]);
+            let prefix = AccountIdPrefixFull::workchain(account.workchain, u64::from_be_bytes(prefix));
+            let shard_block_id = mc_state.shard_hashes()?.top_blocks(&[account.workchain])?
+                .into_iter()
+                .find(|id| id.shard().contains_full_prefix(&prefix))
+                .ok_or_else(|| LiteServerError::ProtoViolation(format!(
+                    "Workchain {} is not found in masterchain state {}", account.workchain, mc_block_id
+                )))?;
+            let shards_proof = state_proof(&mc_state, |state| {
+                let extra = state.read_custom()?.ok_or_else(
+                    || error!("Masterchain state of {} has no extra", mc_block_id)
+                )?;
+                if let Some(shards) = extra.shards().get(&account.workchain)? {
+                    shards.0.iterate(|_, _| Ok(true))?;
+                }
+                Ok(())
+            })?;
+            let header_proof = self.block_header_proof(mc_block_id).await?;
+            let shard_proof = serialize_cells(&[header_proof, shards_proof])?;
+            let shard_state = self.load_state(&shard_block_id).await?;
+            (shard_block_id, shard_proof, shard_state)
+        };
+        let account_proof = state_proof(&shard_state, |state| {
+            state.read_accounts()?.account(&AccountId::from(account_id.clone()))?;
+            Ok(())
+        })?;
+        let proof = serialize_cells(&[self.block_header_proof(&shard_block_id).await?, account_proof])?;
+        let account = shard_state.state().read_accounts()?.account(&AccountId::from(account_id))?;
+        Ok(AccountLookup { shard_block_id, shard_proof, proof, shard_state, mc_state, account })
+    }
+
+    async fn load_state(&self, id: &BlockIdExt) -> Result<ShardStateStuff> {
+        self.engine.load_state(id).await.map_err(|e| error!(LiteServerError::NotReady(
+            format!("State of block {} is not in DB: {}", id, e)
+        )))
+    }
+
+    // Merkle proof of block header with state update
+    async fn block_header_proof(&self, id: &BlockIdExt) -> Result<Cell> {
+        let handle = self.engine.load_block_handle(id)?.ok_or_else(
+            || LiteServerError::NotReady(format!("Block {} is not in DB", id))
+        )?;
+        let block = self.engine.load_block(&handle).await.map_err(|e| error!(
+            LiteServerError::NotReady(format!("Block {} is not in DB: {}", id, e))
+        ))?;
+        let usage_tree = UsageTree::with_root(block.root_cell().clone());
+        let virt_block = Block::construct_from(&mut usage_tree.root_slice())?;
+        virt_block.read_info()?;
+        virt_block.read_state_update()?;
+        MerkleProof::create_by_usage_tree(block.root_cell(), usage_tree)?.serialize()
+    }
+
+    // liteServer.waitMasterchainSeqno prefix: query is answered after the masterchain block
+    // is applied
+    async fn wait_masterchain_seqno(&self, wait: &WaitMasterchainSeqno) -> Result<()> {
+        if wait.seqno < 0 || wait.timeout_ms < 0 {
+            fail!(LiteServerError::ProtoViolation(format!(
+                "Invalid seqno {} or timeout {}ms to wait", wait.seqno, wait.timeout_ms
+            )))
+        }
+        let applied = || -> Result<bool> {
+            Ok(self.engine.load_last_applied_mc_block_id()?
+                .map(|id| id.seq_no() >= wait.seqno as u32)
+                .unwrap_or(false))
+        };
+        let wait_applied = async {
+            while !applied()? {
+                tokio::time::sleep(Duration::from_millis(WAIT_MASTERCHAIN_SEQNO_POLL_MS)).await;
+            }
+            Ok::<_, failure::Error>(())
+        };
+        match tokio::time::timeout(Duration::from_millis(wait.timeout_ms as u64), wait_applied).await {
+            Ok(result) => result,
+            Err(_) => fail!(LiteServerError::Timeout(format!(
+                "Masterchain block {} is not applied in {}ms", wait.seqno, wait.timeout_ms
+            )))
+        }
+    }
+
+    async fn process_query(&self, query: TLObject) -> Result<QueryResult> {
+        let query = match query.downcast::<GetMasterchainInfo>() {
+            Ok(_) => return QueryResult::consume_boxed(self.get_masterchain_info().await?, None),
+            Err(query) => query
+        };
+        let query = match query.downcast::<GetTime>() {
+            Ok(_) => return QueryResult::consume_boxed(self.get_time(), None),
+            Err(query) => query
+        };
+        let query = match query.downcast::<GetAccountState>() {
+            Ok(query) => {
+                let mc_block_id = convert_block_id_ext_api2blk(&query.id)?;
+                return QueryResult::consume_boxed(
+                    self.get_account_state(mc_block_id, &query.account).await?,
+                    None
+                )
+            },
+            Err(query) => query
+        };
+        let query = match query.downcast::<RunSmcMethod>() {
+            Ok(query) => return QueryResult::consume_boxed(self.run_smc_method(query).await?, None),
+            Err(query) => query
+        };
+        let query = match query.downcast::<GetTransactions>() {
+            Ok(query) => return QueryResult::consume_boxed(self.get_transactions(&query).await?, None),
+            Err(query) => query
+        };
+        let query = match query.downcast::<SendMessage>() {
+            Ok(query) => {
+                return QueryResult::consume_boxed(self.send_message(&query.body.0).await?, None)
+            },
+            Err(query) => query
+        };
+        fail!(LiteServerError::ProtoViolation(format!("Unsupported query {:?}", query)))
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscriber for LiteServerQuerySubscriber {
+    async fn try_consume_query(&self, object: TLObject, _peers: &AdnlPeers) -> Result<QueryResult> {
+        let (wait, query) = match object.downcast::<Query>() {
+            Ok(query) => parse_query(&query.data[..])?,
+            Err(object) => return Ok(QueryResult::Rejected(object))
+        };
+        log::debug!("query (lite server): {:?}, wait: {:?}", query, wait);
+        STATSD.incr("lite_server_queries");
+        let answer = match wait {
+            Some(wait) => match self.wait_masterchain_seqno(&wait).await {
+                Ok(()) => self.process_query(query).await,
+                Err(e) => Err(e)
+            },
+            None => self.process_query(query).await
+        };
+        match answer {
+            Ok(answer) => Ok(answer),
+            Err(e) => {
+                log::debug!("lite server query error: {}", e);
+                STATSD.incr("lite_server_errors");
+                QueryResult::consume_boxed(error_answer(&e), None)
+            }
+        }
+    }
+}
+
+// Query may be prefixed with liteServer.waitMasterchainSeqno
+fn parse_query(data: &[u8]) -> Result<(Option<WaitMasterchainSeqno>, TLObject)> {
+    let mut cursor = Cursor::new(data);
+    let object: TLObject = Deserializer::new(&mut cursor).read_boxed()?;
+    match object.downcast::<WaitMasterchainSeqno>() {
+        Ok(wait) => Ok((Some(wait), Deserializer::new(&mut cursor).read_boxed()?)),
+        Err(query) => Ok((None, query))
+    }
+}
+
+fn error_answer(e: &failure::Error) -> LiteError {
+    let code = match e.downcast_ref::<LiteServerError>() {
+        Some(LiteServerError::NotReady(_)) => ERROR_CODE_NOTREADY,
+        Some(LiteServerError::ProtoViolation(_)) => ERROR_CODE_PROTOVIOLATION,
+        Some(LiteServerError::Timeout(_)) => ERROR_CODE_TIMEOUT,
+        None => ERROR_CODE_ERROR
+    };
+    LiteError::LiteServer_Error(Box::new(lite_error::Error { code, message: e.to_string() }))
+}
+
+// Merkle proof of state cells visited by `visit`
+fn state_proof(
+    state: &ShardStateStuff,
+    visit: impl FnOnce(&ShardStateUnsplit) -> Result<()>
+) -> Result<Cell> {
+    let usage_tree = UsageTree::with_root(state.root_cell().clone());
+    visit(&ShardStateUnsplit::construct_from(&mut usage_tree.root_slice())?)?;
+    MerkleProof::create_by_usage_tree(state.root_cell(), usage_tree)?.serialize()
+}
+
+fn serialize_cells(roots: &[Cell]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    BagOfCells::with_roots(roots.iter().collect()).write_to(&mut data, false)?;
+    Ok(data)
+}
+
+// TVM is run out of async threads. Its answer is given up after timeout, the run itself
+// is bounded by gas.
+async fn run_blocking_with_timeout<R, F>(timeout: Duration, run: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static
+{
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(run)).await {
+        Ok(result) => result?,
+        Err(_) => fail!(LiteServerError::Timeout(format!(
+            "Get method is not finished in {}ms", timeout.as_millis()
+        )))
+    }
+}
+
+// Runs get method with parameters and result as serialized VmStack
+fn run_get_method(
+    account: &Account,
+    mc_state: &ShardStateStuff,
+    now: u32,
+    lt: u64,
+    method_id: i64,
+    params: &[u8]
+) -> Result<(i32, Vec<u8>)> {
+    let (code, data) = match (account.get_code(), account.get_data()) {
+        (Some(code), Some(data)) => (code, data),
+        _ => fail!("Account is not active")
+    };
+    let address = account.get_addr().ok_or_else(|| error!("Account has no address"))?;
+    let config = mc_state.config_params()?;
+
+    let mut stack = Stack::new();
+    if !params.is_empty() {
+        for item in deserialize_stack(&deserialize_tree_of_cells(&mut Cursor::new(params))?)? {
+            stack.push(item);
+        }
+    }
+    stack.push(StackItem::Integer(Arc::new(IntegerData::from_i64(method_id))));
+
+    let mut sci = SmartContractInfo::with_myself(address.serialize()?.into());
+    sci.unix_time = now;
+    sci.block_lt = lt;
+    sci.trans_lt = lt;
+    sci.balance = account.balance().cloned().unwrap_or_default();
+    sci.config_params = config.config_params.data().cloned();
+    let mut ctrls = SaveList::new();
+    ctrls.put(4, &mut StackItem::Cell(data))?;
+    ctrls.put(7, &mut sci.into_temp_data_item())?;
+
+    let gas = Gas::new(GET_METHOD_GAS_LIMIT, 0, GET_METHOD_GAS_LIMIT, 10);
+    let mut engine = VmEngine::with_capabilities(config.capabilities()).setup_with_libraries(
+        SliceData::from(code),
+        Some(ctrls),
+        Some(stack),
+        Some(gas),
+        vec![mc_state.state().libraries().clone().inner()]
+    );
+    let exit_code = match engine.execute() {
+        Ok(exit_code) => exit_code,
+        Err(e) => tvm_exception_or_custom_code(&e)
+    };
+    let mut result = Vec::new();
+    serialize_tree_of_cells(&serialize_stack(&engine.stack().storage)?, &mut result)?;
+    Ok((exit_code, result))
+}
+
+// VmStack: depth:(## 24) stack:(VmStackList depth), the top item goes first
+fn serialize_stack(items: &[StackItem]) -> Result<Cell> {
+    let mut stack = BuilderData::new();
+    stack.append_bits(items.len(), 24)?;
+    write_stack_list(items, &mut stack)?;
+    stack.into_cell()
+}
+
+fn write_stack_list(items: &[StackItem], builder: &mut BuilderData) -> Result<()> {
+    if let Some((top, rest)) = items.split_last() {
+        let mut rest_builder = BuilderData::new();
+        write_stack_list(rest, &mut rest_builder)?;
+        builder.checked_append_reference(rest_builder.into_cell()?)?;
+        write_stack_value(top, builder)?;
+    }
+    Ok(())
+}
+
+fn write_stack_value(item: &StackItem, builder: &mut BuilderData) -> Result<()> {
+    match item {
+        StackItem::None => {
+            builder.append_u8(0x00)?;
+        },
+        StackItem::Integer(value) if value.is_nan() => {
+            builder.append_u16(0x02ff)?;
+        },
+        StackItem::Integer(value) => {
+            // vm_stk_int#0201_ value:int257
+            builder.append_bits(0x0100, 15)?;
+            builder.append_builder(&value.as_builder::<SignedIntegerBigEndianEncoding>(257)?)?;
+        },
+        StackItem::Cell(cell) => {
+            builder.append_u8(0x03)?;
+            builder.checked_append_reference(cell.clone())?;
+        },
+        StackItem::Slice(slice) => {
+            // Slice is stored as a whole cell
+            builder.append_u8(0x04)?;
+            builder.checked_append_reference(slice.into_cell())?;
+            builder.append_bits(0, 10)?;
+            builder.append_bits(slice.remaining_bits(), 10)?;
+            builder.append_bits(0, 3)?;
+            builder.append_bits(slice.remaining_references(), 3)?;
+        },
+        StackItem::Builder(value) => {
+            builder.append_u8(0x05)?;
+            builder.checked_append_reference(value.as_ref().clone().into_cell()?)?;
+        },
+        StackItem::Tuple(items) => {
+            builder.append_u8(0x07)?;
+            builder.append_u16(items.len() as u16)?;
+            write_tuple(items, builder)?;
+        },
+        _ => fail!("Stack item {} can't be serialized", item)
+    }
+    Ok(())
+}
+
+// VmTuple: head:(VmTupleRef n - 1) tail:^VmStackValue
+fn write_tuple(items: &[StackItem], builder: &mut BuilderData) -> Result<()> {
+    if let Some((last, head)) = items.split_last() {
+        match head.len() {
+            0 => (),
+            1 => {
+                builder.checked_append_reference(stack_value_cell(&head[0])?)?;
+            },
+            _ => {
+                let mut head_builder = BuilderData::new();
+                write_tuple(head, &mut head_builder)?;
+                builder.checked_append_reference(head_builder.into_cell()?)?;
+            }
+        }
+        builder.checked_append_reference(stack_value_cell(last)?)?;
+    }
+    Ok(())
+}
+
+fn stack_value_cell(item: &StackItem) -> Result<Cell> {
+    let mut builder = BuilderData::new();
+    write_stack_value(item, &mut builder)?;
+    builder.into_cell()
+}
+
+// Parameters of get method: nulls, integers, cells and slices are supported
+fn deserialize_stack(root: &Cell) -> Result<Vec<StackItem>> {
+    let mut slice = SliceData::from(root);
+    let depth = slice.get_next_int(24)? as usize;
+    let mut items = Vec::with_capacity(depth);
+    for _ in 0..depth {
+        let rest = slice.checked_drain_reference()?;
+        items.push(read_stack_value(&mut slice)?);
+        slice = SliceData::from(rest);
+    }
+    // Top item is read first
+    items.reverse();
+    Ok(items)
+}
+
+fn read_stack_value(slice: &mut SliceData) -> Result<StackItem> {
+    let item = match slice.get_next_byte()? {
+        0x00 => StackItem::None,
+        0x01 => StackItem::Integer(Arc::new(IntegerData::from_i64(slice.get_next_u64()? as i64))),
+        0x02 if slice.get_next_int(7)? == 0 => {
+            let bits = slice.get_next_bits(257)?;
+            // 257 bits are aligned to the top of 33 bytes
+            let value: BigInt = BigInt::from_signed_bytes_be(&bits) >> 7;
+            StackItem::Integer(Arc::new(IntegerData::from(value)?))
+        },
+        0x03 => StackItem::Cell(slice.checked_drain_reference()?),
+        0x04 => {
+            let cell = slice.checked_drain_reference()?;
+            let st_bits = slice.get_next_int(10)? as usize;
+            let end_bits = slice.get_next_int(10)? as usize;
+            let st_ref = slice.get_next_int(3)? as usize;
+            let end_ref = slice.get_next_int(3)? as usize;
+            let mut value = SliceData::from(cell);
+            value.shrink_data(st_bits..end_bits);
+            value.shrink_references(st_ref..end_ref);
+            StackItem::Slice(value)
+        },
+        tag => fail!(LiteServerError::ProtoViolation(format!(
+            "Unsupported get method parameter with tag {:02x}", tag
+        )))
+    };
+    Ok(item)
+}
+
+#[test]
+fn test_vm_stack_serialization() {
+    let cell = SliceData::new(vec![0xab, 0x80]).into_cell();
+    let items = vec!(
+        StackItem::None,
+        StackItem::Integer(Arc::new(IntegerData::from_i64(-7))),
+        StackItem::Integer(Arc::new(IntegerData::from_i64(1 << 40))),
+        StackItem::Cell(cell.clone()),
+        StackItem::Slice(SliceData::from(cell)),
+    );
+    let root = serialize_stack(&items).unwrap();
+    assert_eq!(SliceData::from(&root).get_next_int(24).unwrap(), 5);
+    assert_eq!(deserialize_stack(&root).unwrap(), items);
+
+    // Results may contain tuples, parameters may not
+    let tuple = StackItem::Tuple(Arc::new(items.clone()));
+    let root = serialize_stack(&[tuple]).unwrap();
+    assert!(deserialize_stack(&root).is_err());
+}
+
+#[test]
+fn test_get_method_timeout() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let result = rt.block_on(run_blocking_with_timeout(Duration::from_millis(1000), || Ok(7)));
+    assert_eq!(result.unwrap(), 7);
+    let result = rt.block_on(run_blocking_with_timeout(Duration::from_millis(10), || {
+        std::thread::sleep(Duration::from_millis(200));
+        Ok(7)
+    }));
+    match error_answer(&result.unwrap_err()) {
+        LiteError::LiteServer_Error(error) => assert_eq!(error.code, ERROR_CODE_TIMEOUT)
+    }
+}
+
+#[test]
+fn test_wait_masterchain_seqno_prefix() {
+    struct TestEngine(std::sync::atomic::AtomicU32);
+    impl EngineOperations for TestEngine {
+        fn load_last_applied_mc_block_id(&self) -> Result<Option<Arc<BlockIdExt>>> {
+            let seq_no = self.0.load(std::sync::atomic::Ordering::Relaxed);
+            Ok(Some(Arc::new(BlockIdExt::with_params(
+                ton_block::ShardIdent::masterchain(), seq_no, UInt256::default(), UInt256::default()
+            ))))
+        }
+    }
+    let serialize = |objects: &[TLObject]| {
+        let mut data = Vec::new();
+        let mut serializer = ton_api::Serializer::new(&mut data);
+        for object in objects {
+            serializer.write_boxed(object).unwrap();
+        }
+        data
+    };
+    let wait = |seqno: i32, timeout_ms: i32| TLObject::new(WaitMasterchainSeqno { seqno, timeout_ms });
+
+    // Plain and prefixed queries
+    let (prefix, query) = parse_query(&serialize(&[TLObject::new(GetTime)])).unwrap();
+    assert!(prefix.is_none());
+    assert!(query.downcast::<GetTime>().is_ok());
+    let (prefix, query) = parse_query(&serialize(&[wait(10, 1000), TLObject::new(GetTime)])).unwrap();
+    let prefix = prefix.unwrap();
+    assert_eq!((prefix.seqno, prefix.timeout_ms), (10, 1000));
+    assert!(query.downcast::<GetTime>().is_ok());
+    // Prefix without query
+    assert!(parse_query(&serialize(&[wait(10, 1000)])).is_err());
+
+    let engine = Arc::new(TestEngine(std::sync::atomic::AtomicU32::new(5)));
+    let subscriber = LiteServerQuerySubscriber { engine: engine.clone() };
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        // Already applied
+        subscriber.wait_masterchain_seqno(&WaitMasterchainSeqno { seqno: 5, timeout_ms: 0 }).await.unwrap();
+        // Not applied in time
+        let err = subscriber.wait_masterchain_seqno(
+            &WaitMasterchainSeqno { seqno: 6, timeout_ms: 200 }
+        ).await.unwrap_err();
+        match error_answer(&err) {
+            LiteError::LiteServer_Error(error) => assert_eq!(error.code, ERROR_CODE_TIMEOUT)
+        }
+        // Applied while waiting
+        let waiting = subscriber.wait_masterchain_seqno(&WaitMasterchainSeqno { seqno: 6, timeout_ms: 10000 });
+        let applying = async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            engine.0.store(6, std::sync::atomic::Ordering::Relaxed);
+        };
+        let (waited, _) = futures::join!(waiting, applying);
+        waited.unwrap();
+    });
+}
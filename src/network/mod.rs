@@ -1,10 +1,16 @@
+pub mod bandwidth;
 pub mod catchain_client;
 pub mod node_network;
 pub mod neighbours;
+pub mod peer_scores;
 pub mod full_node_client;
 pub mod full_node_service;
+pub mod health;
 #[cfg(feature = "local_test")]
 pub mod node_network_stub;
 pub mod control;
+pub mod control_ext;
+pub mod lite_server;
 #[cfg(feature = "telemetry")]
 pub mod telemetry;
+pub mod validators_overlay;
@@ -2,7 +2,9 @@ use adnl::{
     common::{KeyId, KeyOption, Query, tag_from_boxed_type, TaggedTlObject, Wait}, 
     node::{AdnlNode, AddressCache}
 };
-use crate::engine::STATSD;
+use crate::{
+    config::NetworkConfig, engine::STATSD, network::peer_scores::{PeerOutcome, PeerScores}
+};
 use dht::DhtNode;
 use overlay::{OverlayShortId, OverlayNode};
 use rand::{Rng};
@@ -31,7 +33,9 @@ pub struct Neighbour {
     fail_attempts: AtomicU64,
     fines_points: AtomicU32,
     active_check: AtomicBool,
-    unreliability: AtomicI32
+    unreliability: AtomicI32,
+    // Average speed of downloads (bytes per second)
+    download_speed: AtomicU64
 }
 
 pub struct Neighbours {
@@ -40,6 +44,8 @@ pub struct Neighbours {
     overlay_id: Arc<OverlayShortId>,
     overlay: Arc<OverlayNode>,
     dht: Arc<DhtNode>,
+    scores: Arc<PeerScores>,
+    config: NetworkConfig,
     fail_attempts: AtomicU64,
     all_attempts: AtomicU64,
     start: Instant,
@@ -74,6 +80,7 @@ impl Neighbour {
             //roundtrip_relax_at: 0,
             //roundtrip_weight: 0.0,
             unreliability: AtomicI32::new(0),
+            download_speed: AtomicU64::new(0),
         })
     }
 
@@ -135,6 +142,14 @@ impl Neighbour {
         Self::roundtrip(&self.roundtrip_rldp)
     }
 
+    pub fn download_speed(&self) -> Option<u64> {
+        Self::roundtrip(&self.download_speed)
+    }
+
+    pub fn update_download_speed(&self, speed: u64) {
+        Self::set_roundtrip(&self.download_speed, speed.max(1))
+    }
+
     pub fn update_roundtrip_adnl(&self, roundtrip: u64) {
         Self::set_roundtrip(&self.roundtrip_adnl, roundtrip)
     }
@@ -173,8 +188,6 @@ impl Neighbour {
 
 }
 
-pub const MAX_NEIGHBOURS: usize = 16;
-
 impl Neighbours {
 
     const TIMEOUT_PING_MAX: u64 = 1000;  // Milliseconds
@@ -184,13 +197,17 @@ impl Neighbours {
         start_peers: &Vec<Arc<KeyId>>,
         dht: &Arc<DhtNode>,
         overlay: &Arc<OverlayNode>,
-        overlay_id: Arc<OverlayShortId>
+        overlay_id: Arc<OverlayShortId>,
+        scores: Arc<PeerScores>,
+        config: NetworkConfig
     ) -> Result<Self> {
         let ret = Neighbours {
-            peers: NeighboursCache::new(start_peers)?,
+            peers: NeighboursCache::new(start_peers, config.max_neighbours)?,
             all_peers: lockfree::set::Set::new(),
             overlay: overlay.clone(),
             dht: dht.clone(),
+            scores,
+            config,
             overlay_id,
             fail_attempts: AtomicU64::new(0),
             all_attempts: AtomicU64::new(0),
@@ -205,8 +222,12 @@ impl Neighbours {
         self.peers.count()
     }
 
+    pub fn max_count(&self) -> usize {
+        self.config.max_neighbours
+    }
+
     pub fn add(&self, peer: Arc<KeyId>) -> Result<bool> {
-        if self.count() >= MAX_NEIGHBOURS {
+        if self.count() >= self.config.max_neighbours {
             return Ok(false);
         }
         self.peers.insert_ex(peer, false)
@@ -216,6 +237,10 @@ impl Neighbours {
         self.peers.contains(peer)
     }
 
+    pub fn report(&self, peer: &Arc<KeyId>, outcome: PeerOutcome) {
+        self.scores.update(peer, outcome)
+    }
+
     pub fn contains_overlay_peer(&self, id: &Arc<KeyId>) -> bool {
         self.all_peers.contains(id)
     }
@@ -231,7 +256,7 @@ impl Neighbours {
     pub fn got_neighbours(&self, peers: AddressCache) -> Result<()> {
         log::trace!("got_neighbours");
         let mut ex = false;
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::stream_for("neighbours", &self.overlay_id);
         let mut is_delete_peer = false;
 
         let (mut iter, mut current) = peers.first();
@@ -242,10 +267,10 @@ impl Neighbours {
             }
             let count = self.peers.count();
 
-            if count == MAX_NEIGHBOURS {
+            if count >= self.config.max_neighbours {
                 let mut a: Option<Arc<KeyId>> = None;
                 let mut b: Option<Arc<KeyId>> = None;
-                let mut cnt: u32 = 0;
+                let mut sum: i64 = 0;
                 let mut u:i32 = 0;
 
                 for current in self.peers.get_iter() {
@@ -254,10 +279,14 @@ impl Neighbours {
                         u = un;
                         a = Some(current.id.clone());
                     }
-                    if cnt == 0 || rng.gen_range(0, cnt) == 0 {
+                    // Neighbours with good peer scores are kept more likely
+                    let w = rotation_weight(
+                        self.scores.weight(current.id()), self.scores.is_banned(current.id())
+                    );
+                    sum += w;
+                    if rng.gen_range(0, sum) < w {
                         b = Some(current.id.clone());
-                    } 
-                    cnt += 1;
+                    }
                 }
                 let mut deleted_peer = b;
 
@@ -292,7 +321,18 @@ impl Neighbours {
     pub fn start_reload(self: Arc<Self>) {
         tokio::spawn(async move {
             loop {
-                let sleep_time = rand::thread_rng().gen_range(10, 30);
+                let count = self.count();
+                STATSD.gauge(&format!("neighbours.{}", self.overlay_id), count as f64);
+                let sleep_time = if count < self.config.min_neighbours {
+                    log::debug!(
+                        "Overlay {} has {} neighbours of {} required",
+                        self.overlay_id, count, self.config.min_neighbours
+                    );
+                    1
+                } else {
+                    let interval = self.config.neighbours_reselect_interval_sec;
+                    crate::rng::stream_for("neighbours", &self.overlay_id).gen_range(interval / 2, interval + interval / 2)
+                };
                 tokio::time::sleep(Duration::from_secs(sleep_time)).await;
                 if let Err(e) = self.reload_neighbours(&self.overlay_id).await {
                     log::warn!("reload neighbours err: {:?}", e);
@@ -314,8 +354,9 @@ impl Neighbours {
 
     pub async fn reload_neighbours(&self, overlay_id: &Arc<OverlayShortId>) -> Result<()> {
         log::trace!("start reload_neighbours (overlay: {})", overlay_id);
-        let neighbours_cache = AddressCache::with_limit((MAX_NEIGHBOURS * 2 + 1) as u32);
-        self.overlay.get_cached_random_peers(&neighbours_cache, overlay_id, (MAX_NEIGHBOURS * 2) as u32)?;
+        let max = self.config.max_neighbours;
+        let neighbours_cache = AddressCache::with_limit((max * 2 + 1) as u32);
+        self.overlay.get_cached_random_peers(&neighbours_cache, overlay_id, (max * 2) as u32)?;
         self.got_neighbours(neighbours_cache)?;
         log::trace!("finish reload_neighbours (overlay: {})", overlay_id);
         Ok(())
@@ -379,16 +420,32 @@ impl Neighbours {
     }
 
     pub fn choose_neighbour(&self) -> Result<Option<Arc<Neighbour>>> {
+        self.choose_neighbour_except(None)
+    }
+
+    // Same as choose_neighbour, but peers from `excluded` are never chosen
+    pub fn choose_neighbour_except(
+        &self,
+        excluded: Option<&lockfree::set::Set<Arc<KeyId>>>
+    ) -> Result<Option<Arc<Neighbour>>> {
         let count = self.peers.count();
         if count == 0 {
             return Ok(None)
         }
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::stream_for("neighbours", &self.overlay_id);
         let mut best: Option<Arc<Neighbour>> = None; 
         let mut sum = 0;
         let node_stat = self.fail_attempts.load(atomic::Ordering::Relaxed) as f64 / 
             self.all_attempts.load(atomic::Ordering::Relaxed) as f64;
+        let speeds = self.peers.get_iter()
+            .filter_map(|neighbour| neighbour.download_speed())
+            .collect::<Vec<_>>();
+        let average_speed = if speeds.is_empty() {
+            0
+        } else {
+            speeds.iter().sum::<u64>() / speeds.len() as u64
+        };
 
         log::trace!("Select neighbour for overlay {}", self.overlay_id);
         for neighbour in self.peers.get_iter() {
@@ -401,9 +458,15 @@ impl Neighbours {
                 neighbour.all_attempts.load(atomic::Ordering::Relaxed) as f64;
             let fines_points = neighbour.fines_points.load(atomic::Ordering::Relaxed);
 
+            if excluded.map(|excluded| excluded.contains(neighbour.id())).unwrap_or(false) {
+                continue
+            }
             if count == 1 {
                 return Ok(Some(neighbour.clone()))
             }
+            if self.scores.is_banned(neighbour.id()) {
+                continue
+            }
             if version < PROTOCOL_VERSION {
                 unr += 4;
             } else if (version == PROTOCOL_VERSION) && (capabilities < PROTOCOL_CAPABILITIES) {
@@ -435,7 +498,9 @@ impl Neighbours {
                     neighbour.active_check.store(true, atomic::Ordering::Relaxed);
                 }
 
-                let w = (1 << (FAIL_UNRELIABILITY - unr)) as i64;
+                let w = (1 << (FAIL_UNRELIABILITY - unr)) as i64 *
+                    self.scores.weight(neighbour.id()) *
+                    speed_weight(neighbour.download_speed(), average_speed);
                 sum += w;
 
                 if rng.gen_range(0, sum) < w {
@@ -574,14 +639,33 @@ impl Neighbours {
 
 }
 
+// Chance to be replaced during re-selection: score weight is from 1 (worst) to 9 (best),
+// banned neighbours are replaced first of all
+fn rotation_weight(score_weight: i64, banned: bool) -> i64 {
+    if banned {
+        100
+    } else {
+        10 - score_weight.min(9)
+    }
+}
+
+// Chance to be chosen by measured download speed against average one of neighbours:
+// from 1 (half of average and slower) to 4 (twice as fast and faster), not measured is 2
+fn speed_weight(speed: Option<u64>, average_speed: u64) -> i64 {
+    match speed {
+        Some(speed) if average_speed > 0 => (speed * 2 / average_speed).max(1).min(4) as i64,
+        _ => 2
+    }
+}
+
 #[derive(Clone)]
 pub struct NeighboursCache {
     cache: Arc<NeighboursCacheCore>
 }
 
 impl NeighboursCache {
-    pub fn new(start_peers: &Vec<Arc<KeyId>>) -> Result<Self> {
-        let cache = NeighboursCacheCore::new(start_peers)?;
+    pub fn new(start_peers: &Vec<Arc<KeyId>>, max_count: usize) -> Result<Self> {
+        let cache = NeighboursCacheCore::new(start_peers, max_count as u32)?;
         Ok(NeighboursCache {cache: Arc::new(cache)})
     }
 
@@ -619,6 +703,7 @@ impl NeighboursCache {
 }
 
 struct NeighboursCacheCore {
+    max_count: u32,
     count: AtomicU32, 
     next: AtomicU32,
     indices: lockfree::map::Map<u32, Arc<KeyId>>,
@@ -626,8 +711,9 @@ struct NeighboursCacheCore {
 }
 
 impl NeighboursCacheCore {
-    pub fn new(start_peers: &Vec<Arc<KeyId>>) -> Result<Self> {
+    pub fn new(start_peers: &Vec<Arc<KeyId>>, max_count: u32) -> Result<Self> {
         let instance = NeighboursCacheCore {
+            max_count,
             count: AtomicU32::new(0),
             next: AtomicU32::new(0),
             indices: lockfree::map::Map::new(),
@@ -636,7 +722,7 @@ impl NeighboursCacheCore {
 
         let mut index = 0;
         for peer in start_peers.iter() {
-            if index < MAX_NEIGHBOURS {
+            if index < max_count {
                 instance.insert(peer.clone())?;
                 index = index + 1;
             }
@@ -711,7 +797,7 @@ impl NeighboursCacheCore {
 
     fn insert_ex(&self, peer: Arc<KeyId>, silent_insert: bool) -> Result<bool> {
         let count = self.count.load(atomic::Ordering::Relaxed);
-        if !silent_insert && (count >= MAX_NEIGHBOURS as u32) {
+        if !silent_insert && (count >= self.max_count) {
             fail!("NeighboursCache overflow!");
         }
 
@@ -725,7 +811,7 @@ impl NeighboursCacheCore {
             } else {
                 if !silent_insert {
                     index = self.count.fetch_add(1, atomic::Ordering::Relaxed);
-                    if index >= MAX_NEIGHBOURS as u32 {
+                    if index >= self.max_count {
                         self.count.fetch_sub(1, atomic::Ordering::Relaxed);
                         is_overflow = true;
                     }
@@ -827,3 +913,29 @@ impl Iterator for NeighboursCacheIterator {
         result
     }
 }
+
+#[test]
+fn test_rotation_weight() {
+    assert_eq!(rotation_weight(9, false), 1);
+    assert_eq!(rotation_weight(1, false), 9);
+    assert!(rotation_weight(9, true) > rotation_weight(1, false));
+    // Weights are always positive to be used in random choice
+    for weight in 0..20 {
+        assert!(rotation_weight(weight, false) > 0);
+    }
+}
+
+#[test]
+fn test_speed_weight() {
+    assert_eq!(speed_weight(None, 1000), 2);
+    assert_eq!(speed_weight(Some(1000), 0), 2);
+    assert_eq!(speed_weight(Some(1000), 1000), 2);
+    assert_eq!(speed_weight(Some(2000), 1000), 4);
+    assert_eq!(speed_weight(Some(100_000), 1000), 4);
+    assert_eq!(speed_weight(Some(500), 1000), 1);
+    assert_eq!(speed_weight(Some(1), 1000), 1);
+    // Faster neighbour is never less likely to be chosen
+    for speed in 1..3000 {
+        assert!(speed_weight(Some(speed), 1000) <= speed_weight(Some(speed + 1), 1000));
+    }
+}
@@ -1,10 +1,15 @@
 use crate::{
-    config::{ ConfigEvent, NodeConfigHandler, NodeConfigSubscriber, TonNodeConfig, ConnectivityCheckBroadcastConfig},
+    config::{
+        ConfigEvent, NodeConfigHandler, NodeConfigSubscriber, TonNodeConfig, 
+        ConnectivityCheckBroadcastConfig, ExtMessagesConfig, NetworkConfig, TonNodeGlobalConfig
+    },
     engine_traits::{OverlayOperations, PrivateOverlayOperations},
     network::{
         catchain_client::CatchainClient,
         full_node_client::{NodeClientOverlay, FullNodeOverlayClient},
-        neighbours::{self, Neighbours}
+        neighbours::Neighbours,
+        peer_scores::{PeerScores, PEER_SCORES_FILE_NAME},
+        validators_overlay::ValidatorsOverlay
     },
     types::awaiters_pool::AwaitersPool,
 };
@@ -21,7 +26,7 @@ use overlay::{
 };
 use rldp::RldpNode;
 use std::{
-    hash::Hash, 
+    collections::{HashMap, HashSet}, fmt, hash::Hash, path::Path,
     sync::{Arc, atomic::{AtomicI32, AtomicU64, Ordering}}, 
     time::{Duration, SystemTime},
     convert::TryInto,
@@ -31,6 +36,7 @@ use ton_block::BlockIdExt;
 use ton_api::IntoBoxed;
 use ton_api::ton::{
     int256, bytes,
+    dht::node::Node as DhtNodeConfig,
     ton_node::broadcast::ConnectivityCheckBroadcast,
 };
 #[cfg(feature = "telemetry")]
@@ -51,6 +57,11 @@ pub struct NodeNetwork {
     runtime_handle: tokio::runtime::Handle,
     config_handler: Arc<NodeConfigHandler>,
     connectivity_check_config: ConnectivityCheckBroadcastConfig,
+    ext_messages_config: ExtMessagesConfig,
+    network_config: NetworkConfig,
+    peer_scores: Arc<PeerScores>,
+    // DHT nodes of global config, flag is set for ones known from DHT before they became static
+    dht_static_nodes: std::sync::Mutex<HashMap<Arc<KeyId>, bool>>,
     #[cfg(feature = "telemetry")]
     telemetry: Arc<FullNodeNetworkTelemetry>,
     #[cfg(feature = "telemetry")]
@@ -63,6 +74,28 @@ struct ValidatorContext {
     all_validator_peers: Arc<Cache<Arc<KeyId>, Arc<AtomicI32>>>,
     sets_contexts: Arc<Cache<UInt256, ValidatorSetContext>>,
     current_set: Arc<Cache<u8, UInt256>>, // zero or one element [0]
+    validators_overlays: Arc<Cache<UInt256, Arc<ValidatorsOverlay>>>,
+    // Serializes start and stop of validators overlays: a concurrently created duplicate
+    // would delete the shared private overlay on drop
+    validators_overlays_lock: std::sync::Mutex<()>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct DhtStaticNodesUpdate {
+    pub added: usize,
+    pub removed: usize,
+    pub kept: usize,
+    // Removed from global config, but known from DHT itself, so still queried
+    pub learned: usize,
+}
+
+impl fmt::Display for DhtStaticNodesUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f, "added {}, removed {}, kept {}, left as learned {}",
+            self.added, self.removed, self.kept, self.learned
+        )
+    }
 }
 
 #[derive(Default)]
@@ -98,9 +131,13 @@ impl NodeNetwork {
         let mut connectivity_check_config = config.connectivity_check_config().clone();
         connectivity_check_config.enabled = false;
         let connectivity_check_enabled = connectivity_check_config.enabled;
+        let ext_messages_config = config.ext_messages_config().clone();
+        let network_config = config.network_config().clone();
+        let peer_scores = Arc::new(PeerScores::new(Some(
+            Path::new(config.internal_db_path().unwrap_or("node_db")).join(PEER_SCORES_FILE_NAME)
+        )));
 
-        let adnl = AdnlNode::with_config(config.adnl_node()?).await?;
-        let dht = DhtNode::with_adnl_node(adnl.clone(), Self::TAG_DHT_KEY)?;
+        let (adnl, dht, dht_static_nodes) = Self::init_dht(&config, &global_config).await?;
         let overlay = OverlayNode::with_adnl_node_and_zero_state(
             adnl.clone(), 
             masterchain_zero_state_id.file_hash.as_slice(),
@@ -113,11 +150,6 @@ impl NodeNetwork {
         }
         let rldp = RldpNode::with_adnl_node(adnl.clone(), vec![overlay.clone()])?;
 
-        let nodes = global_config.dht_nodes()?;
-        for peer in nodes.iter() {
-            dht.add_peer(peer)?;
-        }
-
         let masterchain_overlay_id = overlay.calc_overlay_id(
             masterchain_zero_state_id.shard().workchain_id(),
             masterchain_zero_state_id.shard().shard_prefix_with_tag() as i64,
@@ -147,6 +179,8 @@ impl NodeNetwork {
             all_validator_peers: Arc::new(Cache::new()),
             sets_contexts: Arc::new(Cache::new()),
             current_set: Arc::new(Cache::new()),
+            validators_overlays: Arc::new(Cache::new()),
+            validators_overlays_lock: std::sync::Mutex::new(()),
         };
 
         let nn = Arc::new(NodeNetwork {
@@ -162,6 +196,12 @@ impl NodeNetwork {
             runtime_handle: tokio::runtime::Handle::current(),
             config_handler: config_handler,
             connectivity_check_config,
+            ext_messages_config,
+            network_config,
+            peer_scores,
+            dht_static_nodes: std::sync::Mutex::new(
+                dht_static_nodes.into_iter().map(|id| (id, false)).collect()
+            ),
             #[cfg(feature = "telemetry")]
             telemetry: Arc::new(
                 FullNodeNetworkTelemetry::new(FullNodeNetworkTelemetryKind::Client)
@@ -172,6 +212,7 @@ impl NodeNetwork {
         });
 
         NodeConfigHandler::start_sheduler(nn.config_handler.clone(), config_handler_context, vec![nn.clone()])?;
+        nn.peer_scores.clone().start_saver();
 
         if connectivity_check_enabled {
            Self::connectivity_broadcasts_sender(nn.clone());
@@ -182,6 +223,9 @@ impl NodeNetwork {
     }
 
     pub async fn stop(&self) {
+        if let Err(e) = self.peer_scores.save() {
+            log::warn!("Can't save peer scores: {}", e);
+        }
         self.adnl.stop().await
     }
 
@@ -189,6 +233,10 @@ impl NodeNetwork {
         self.config_handler.clone()
     }
 
+    pub fn peer_scores(&self) -> &PeerScores {
+        &self.peer_scores
+    }
+
     fn try_add_new_elem<K: Hash + Ord + Clone, T: Clone>(
         &self,
         id: &K,
@@ -438,6 +486,89 @@ impl NodeNetwork {
         Ok(())
     }
 
+    // ADNL and DHT nodes knowing static DHT nodes of global config, not started yet
+    async fn init_dht(
+        config: &TonNodeConfig,
+        global_config: &TonNodeGlobalConfig
+    ) -> Result<(Arc<AdnlNode>, Arc<DhtNode>, HashSet<Arc<KeyId>>)> {
+        let adnl = AdnlNode::with_config(config.adnl_node()?).await?;
+        let dht = DhtNode::with_adnl_node(adnl.clone(), Self::TAG_DHT_KEY)?;
+        let mut dht_static_nodes = HashSet::new();
+        for peer in global_config.dht_nodes()?.iter() {
+            dht.add_peer(peer)?;
+            dht_static_nodes.insert(KeyOption::from_tl_public_key(&peer.id)?.id().clone());
+        }
+        Ok((adnl, dht, dht_static_nodes))
+    }
+
+    // Connectivity check without starting the node: up to `count` static DHT nodes
+    // are pinged, ADNL port of the config must be free
+    pub async fn ping_dht_static_nodes(
+        config: &TonNodeConfig,
+        count: usize,
+        timeout: Duration
+    ) -> Result<Vec<(Arc<KeyId>, bool)>> {
+        let global_config = config.load_global_config()?;
+        let (adnl, dht, dht_static_nodes) = Self::init_dht(config, &global_config).await?;
+        AdnlNode::start(&adnl, vec![dht.clone()]).await?;
+        let mut pinged = Vec::new();
+        for id in dht_static_nodes.into_iter().take(count) {
+            let answered = matches!(tokio::time::timeout(timeout, dht.ping(&id)).await, Ok(Ok(true)));
+            pinged.push((id, answered));
+        }
+        Ok(pinged)
+    }
+
+    // Adds new DHT nodes of global config, vanished ones are removed from DHT buckets and from
+    // ADNL peers of DHT key, so they are not queried anymore. Vanished nodes which DHT knew
+    // before they became static are left as learned ones. Nothing is changed if some new node
+    // can't be added.
+    pub fn update_dht_static_nodes(&self, nodes: &[DhtNodeConfig]) -> Result<DhtStaticNodesUpdate> {
+        let mut new_nodes = HashMap::new();
+        for node in nodes {
+            new_nodes.insert(KeyOption::from_tl_public_key(&node.id)?.id().clone(), node);
+        }
+        let dht_key = self.adnl.key_by_tag(Self::TAG_DHT_KEY)?;
+        let mut static_nodes = self.dht_static_nodes.lock().unwrap();
+        let diff = DhtStaticNodesDiff::new(&static_nodes, &new_nodes);
+        let mut known = HashSet::new();
+        let mut iter = None;
+        while let Some(id) = self.dht.get_known_peer(&mut iter) {
+            known.insert(id);
+        }
+        let mut added = Vec::new();
+        for id in diff.add.iter() {
+            if let Err(e) = self.dht.add_peer(new_nodes[id]) {
+                for id in added.iter().filter(|id| !known.contains(*id)) {
+                    self.drop_dht_peer(dht_key.id(), id);
+                }
+                fail!("Can't add DHT static node {}: {}", id, e)
+            }
+            added.push(id.clone());
+        }
+        for id in added {
+            let learned = known.contains(&id);
+            static_nodes.insert(id, learned);
+        }
+        for id in diff.remove.iter() {
+            self.drop_dht_peer(dht_key.id(), id);
+            static_nodes.remove(id);
+        }
+        for id in diff.learned.iter() {
+            static_nodes.remove(id);
+        }
+        Ok(diff.update())
+    }
+
+    fn drop_dht_peer(&self, dht_key_id: &Arc<KeyId>, id: &Arc<KeyId>) {
+        if let Err(e) = self.dht.delete_peer(id) {
+            log::warn!("Can't delete DHT node {} from buckets: {}", id, e)
+        }
+        if let Err(e) = self.adnl.delete_peer(dht_key_id, id) {
+            log::warn!("Can't delete DHT node {} from ADNL peers: {}", id, e)
+        }
+    }
+
     fn find_dht_nodes(dht: Arc<DhtNode>) {
         tokio::spawn(async move {
             loop {
@@ -461,7 +592,7 @@ impl NodeNetwork {
                 if let Err(e) = self.update_peers(&client_overlay, &mut iter).await {
                     log::warn!("Error find overlay nodes by dht: {}", e);
                 }
-                if client_overlay.peers().count() >= neighbours::MAX_NEIGHBOURS {
+                if client_overlay.peers().count() >= client_overlay.peers().max_count() {
                     log::trace!("finish find overlay nodes.");
                     return;
                 }
@@ -487,7 +618,10 @@ impl NodeNetwork {
             log::warn!("No nodes were found in overlay {}", &overlay_id.0);
         }
 
-        let neighbours = Neighbours::new(&peers, &self.dht, &self.overlay, overlay_id.0.clone())?;
+        let neighbours = Neighbours::new(
+            &peers, &self.dht, &self.overlay, overlay_id.0.clone(), self.peer_scores.clone(),
+            self.network_config.clone()
+        )?;
         let peers = Arc::new(neighbours);
 
         let client_overlay = NodeClientOverlay::new(
@@ -495,6 +629,7 @@ impl NodeNetwork {
             self.overlay.clone(),
             self.rldp.clone(),
             Arc::clone(&peers),
+            &self.ext_messages_config,
             #[cfg(feature = "telemetry")]
             self.telemetry.clone()
         );
@@ -1004,6 +1139,73 @@ impl PrivateOverlayOperations for NodeNetwork {
         Ok(result  as Arc<dyn CatchainOverlay + Send>)
     }
 
+    fn start_validators_overlay(
+        &self,
+        validator_list_id: &UInt256,
+        consumer: Arc<dyn QueriesConsumer>
+    ) -> Result<Option<Arc<ValidatorsOverlay>>> {
+        start_once(
+            &self.validator_context.validators_overlays_lock,
+            &self.validator_context.validators_overlays,
+            validator_list_id,
+            || {
+                let context = self.validator_context.sets_contexts.get(validator_list_id)
+                    .ok_or_else(
+                        || error!("bad validator_list_id ({})!", validator_list_id.to_hex_string())
+                    )?;
+                Ok(Arc::new(ValidatorsOverlay::new(
+                    validator_list_id.clone(),
+                    &self.overlay,
+                    &self.rldp,
+                    &self.runtime_handle,
+                    &context.val().validator_adnl_key,
+                    context.val().validator_peers.clone(),
+                    consumer
+                )?))
+            }
+        )
+    }
+
+    fn stop_validators_overlay(&self, validator_list_id: &UInt256) -> bool {
+        let _lock = self.validator_context.validators_overlays_lock.lock()
+            .unwrap_or_else(|e| e.into_inner());
+        match self.validator_context.validators_overlays.remove(validator_list_id) {
+            Some(overlay) => {
+                overlay.val().stop();
+                true
+            },
+            None => false
+        }
+    }
+
+    fn validators_overlays(&self) -> Vec<Arc<ValidatorsOverlay>> {
+        self.validator_context.validators_overlays.iter().map(|item| item.val().clone()).collect()
+    }
+
+    async fn check_local_adnl_key(&self, adnl_id: &Arc<KeyId>) -> Result<()> {
+        let local_key = self.adnl.key_by_id(adnl_id).map_err(
+            |e| error!("ADNL key {} is not registered in local ADNL node: {}", adnl_id, e)
+        )?;
+        // Loopback check: our own address record must be resolvable through DHT 
+        // and point to this node
+        match self.dht.fetch_address(adnl_id).await? {
+            Some((addr, key)) => {
+                if key.id() != local_key.id() {
+                    fail!("DHT address record for ADNL key {} is signed by other key {}", adnl_id, key.id())
+                }
+                let local_addr = self.adnl.ip_address();
+                if addr.to_string() != local_addr.to_string() {
+                    fail!(
+                        "ADNL key {} is resolved to {} via DHT, but local address is {}", 
+                        adnl_id, addr, local_addr
+                    )
+                }
+                Ok(())
+            },
+            None => fail!("ADNL key {} is not resolvable via DHT", adnl_id)
+        }
+    }
+
     fn stop_catchain_client(&self, overlay_short_id: &Arc<PrivateOverlayShortId>) {
         if let Some(catchain_client) = self.validator_context.private_overlays.remove(overlay_short_id) {
             let client = catchain_client.val().clone();
@@ -1015,6 +1217,67 @@ impl PrivateOverlayOperations for NodeNetwork {
     }
 }
 
+// Changes of DHT static nodes set computed before any of them is applied
+#[derive(Debug, Default, PartialEq)]
+struct DhtStaticNodesDiff {
+    add: Vec<Arc<KeyId>>,
+    remove: Vec<Arc<KeyId>>,
+    learned: Vec<Arc<KeyId>>,
+    kept: usize,
+}
+
+impl DhtStaticNodesDiff {
+
+    fn new<T>(static_nodes: &HashMap<Arc<KeyId>, bool>, new_nodes: &HashMap<Arc<KeyId>, T>) -> Self {
+        let mut diff = Self::default();
+        for id in new_nodes.keys() {
+            if static_nodes.contains_key(id) {
+                diff.kept += 1;
+            } else {
+                diff.add.push(id.clone());
+            }
+        }
+        for (id, learned) in static_nodes.iter() {
+            if new_nodes.contains_key(id) {
+                continue
+            }
+            if *learned {
+                diff.learned.push(id.clone());
+            } else {
+                diff.remove.push(id.clone());
+            }
+        }
+        diff
+    }
+
+    fn update(&self) -> DhtStaticNodesUpdate {
+        DhtStaticNodesUpdate {
+            added: self.add.len(),
+            removed: self.remove.len(),
+            kept: self.kept,
+            learned: self.learned.len()
+        }
+    }
+
+}
+
+// Creates the value only if there is none for the id yet. Check and creation are done under
+// the lock, so the value is never created twice. Returns None if the value already exists.
+fn start_once<K: Hash + Ord + Clone, T: Clone>(
+    lock: &std::sync::Mutex<()>,
+    cache: &Cache<K, T>,
+    id: &K,
+    create: impl FnOnce() -> Result<T>
+) -> Result<Option<T>> {
+    let _lock = lock.lock().unwrap_or_else(|e| e.into_inner());
+    if cache.get(id).is_some() {
+        return Ok(None)
+    }
+    let value = create()?;
+    cache.insert(id.clone(), value.clone());
+    Ok(Some(value))
+}
+
 #[async_trait::async_trait]
 impl NodeConfigSubscriber for NodeNetwork {
     async fn event(&self, sender: ConfigEvent) -> Result<bool> {
@@ -1032,3 +1295,71 @@ impl NodeConfigSubscriber for NodeNetwork {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn key_id(n: u8) -> Arc<KeyId> {
+        KeyId::from_data([n; 32])
+    }
+
+    #[test]
+    fn test_dht_static_nodes_diff() {
+        let static_nodes = [(1, false), (2, false), (3, true), (4, false)].iter()
+            .map(|(n, learned)| (key_id(*n), *learned))
+            .collect::<HashMap<_, _>>();
+        let new_nodes = [2, 4, 5, 6].iter().map(|n| (key_id(*n), ())).collect::<HashMap<_, _>>();
+        let mut diff = DhtStaticNodesDiff::new(&static_nodes, &new_nodes);
+        diff.add.sort();
+        assert_eq!(diff.add, vec![key_id(5), key_id(6)]);
+        assert_eq!(diff.remove, vec![key_id(1)]);
+        assert_eq!(diff.learned, vec![key_id(3)]);
+        assert_eq!(
+            diff.update(),
+            DhtStaticNodesUpdate { added: 2, removed: 1, kept: 2, learned: 1 }
+        );
+        let diff = DhtStaticNodesDiff::new(&static_nodes, &HashMap::<_, ()>::new());
+        assert!(diff.add.is_empty());
+        assert_eq!(diff.remove.len() + diff.learned.len(), static_nodes.len());
+        let same = static_nodes.keys().map(|id| (id.clone(), ())).collect::<HashMap<_, _>>();
+        assert_eq!(
+            DhtStaticNodesDiff::new(&static_nodes, &same).update(),
+            DhtStaticNodesUpdate { kept: 4, ..Default::default() }
+        );
+    }
+
+    #[test]
+    fn test_start_once_concurrently() {
+        let lock = Arc::new(std::sync::Mutex::new(()));
+        let cache = Arc::new(Cache::<u32, Arc<u32>>::new());
+        let created = Arc::new(AtomicUsize::new(0));
+        let threads = (0..8).map(|_| {
+            let (lock, cache, created) = (lock.clone(), cache.clone(), created.clone());
+            std::thread::spawn(move || start_once(&lock, &cache, &1, || {
+                created.fetch_add(1, Ordering::Relaxed);
+                std::thread::sleep(Duration::from_millis(10));
+                Ok(Arc::new(1))
+            }).unwrap())
+        }).collect::<Vec<_>>();
+        let started = threads.into_iter()
+            .filter_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(created.load(Ordering::Relaxed), 1);
+        assert_eq!(started.len(), 1);
+        assert!(Arc::ptr_eq(&started[0], cache.get(&1).unwrap().val()));
+    }
+
+    #[test]
+    fn test_start_once_failed() {
+        let lock = std::sync::Mutex::new(());
+        let cache = Cache::<u32, u32>::new();
+        assert!(start_once(&lock, &cache, &1, || fail!("can't create")).is_err());
+        assert!(cache.get(&1).is_none());
+        assert_eq!(start_once(&lock, &cache, &1, || Ok(2)).unwrap(), Some(2));
+        assert_eq!(start_once(&lock, &cache, &1, || Ok(3)).unwrap(), None);
+        assert_eq!(*cache.get(&1).unwrap().val(), 2);
+    }
+}
@@ -194,6 +194,14 @@ impl FullNodeOverlayClient for OverlayClientStub {
         unimplemented!();
     }
 
+    async fn lookup_block_by_seqno(
+        &self,
+        _shard: &ShardIdent,
+        _seqno: u32
+    ) -> Result<Vec<(BlockIdExt, Arc<KeyId>)>> {
+        unimplemented!();
+    }
+
     async fn download_next_block_full(&self, prev_id: &BlockIdExt) -> Result<(BlockStuff, BlockProofStuff)> {
 
         let next: BlockIdExt = if prev_id.shard().is_masterchain() {
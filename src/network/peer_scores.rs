@@ -0,0 +1,215 @@
+use crate::engine::STATSD;
+use adnl::common::KeyId;
+use std::{
+    collections::{BTreeMap, HashMap}, convert::TryInto, fs, path::PathBuf,
+    sync::{Arc, Mutex}, time::{Duration, SystemTime, UNIX_EPOCH}
+};
+use ton_types::{error, Result};
+
+// Scores are stored in main DB directory to survive restarts
+pub const PEER_SCORES_FILE_NAME: &str = "peer_scores.json";
+const SAVE_INTERVAL_SEC: u64 = 60;
+
+const MAX_SCORE: i32 = 100;
+const MIN_SCORE: i32 = -100;
+const SUCCESS_REWARD: i32 = 1;
+const TIMEOUT_PENALTY: i32 = 5;
+const BAD_DATA_PENALTY: i32 = 25;
+// Peer is banned when its score falls to this value
+const BAN_SCORE: i32 = -50;
+const BASE_BAN_SEC: u64 = 60;
+const MAX_BAN_SEC: u64 = 24 * 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PeerOutcome {
+    Success,
+    // No answer in time or too slow download
+    Timeout,
+    // Malformed, truncated or mismatching data
+    BadData,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct PeerScore {
+    pub score: i32,
+    // Bans since the peer was good last time, each one is twice longer than previous
+    pub bans: u32,
+    // Unix time
+    pub banned_until: u64,
+}
+
+impl PeerScore {
+
+    // Returns true if the peer gets banned
+    fn update(&mut self, outcome: PeerOutcome, now: u64) -> bool {
+        match outcome {
+            PeerOutcome::Success => {
+                self.score = (self.score + SUCCESS_REWARD).min(MAX_SCORE);
+                if self.score >= 0 {
+                    self.bans = 0;
+                }
+                return false
+            },
+            PeerOutcome::Timeout => self.score = (self.score - TIMEOUT_PENALTY).max(MIN_SCORE),
+            PeerOutcome::BadData => self.score = (self.score - BAD_DATA_PENALTY).max(MIN_SCORE),
+        }
+        if self.score > BAN_SCORE || self.is_banned(now) {
+            return false
+        }
+        self.banned_until = now + (BASE_BAN_SEC << self.bans.min(16)).min(MAX_BAN_SEC);
+        self.bans += 1;
+        // Peer has one more chance after the ban
+        self.score = BAN_SCORE / 2;
+        true
+    }
+
+    pub fn is_banned(&self, now: u64) -> bool {
+        self.banned_until > now
+    }
+
+    // Selection weight multiplier: 1 for the worst score, 9 for the best one
+    pub fn weight(&self) -> i64 {
+        ((self.score - MIN_SCORE) / 25 + 1) as i64
+    }
+}
+
+// Download results of peers, shared by all overlays. Peers are chosen for downloads
+// proportionally to weight of their score, banned ones are not chosen.
+pub struct PeerScores {
+    scores: Mutex<HashMap<Arc<KeyId>, PeerScore>>,
+    path: Option<PathBuf>,
+}
+
+impl PeerScores {
+
+    // Loads scores stored in `path`, if any
+    pub fn new(path: Option<PathBuf>) -> Self {
+        let scores = match path.as_ref().map(|path| Self::load(path)).transpose() {
+            Ok(scores) => scores.unwrap_or_default(),
+            Err(e) => {
+                log::warn!("Can't load peer scores, starting from scratch: {}", e);
+                HashMap::new()
+            }
+        };
+        Self { scores: Mutex::new(scores), path }
+    }
+
+    pub fn update(&self, peer: &Arc<KeyId>, outcome: PeerOutcome) {
+        let now = unix_time();
+        let mut scores = self.scores.lock().unwrap();
+        let score = scores.entry(peer.clone()).or_default();
+        if score.update(outcome, now) {
+            log::warn!(
+                "Peer {} is banned for downloads until {} after {:?}, ban #{}",
+                peer, score.banned_until, outcome, score.bans
+            );
+            STATSD.incr("peer_bans");
+        }
+    }
+
+    pub fn is_banned(&self, peer: &Arc<KeyId>) -> bool {
+        let now = unix_time();
+        self.scores.lock().unwrap().get(peer).map(|score| score.is_banned(now)).unwrap_or(false)
+    }
+
+    pub fn weight(&self, peer: &Arc<KeyId>) -> i64 {
+        self.scores.lock().unwrap().get(peer).cloned().unwrap_or_default().weight()
+    }
+
+    // Worst peers first
+    pub fn scores(&self) -> Vec<(Arc<KeyId>, PeerScore)> {
+        let mut scores = self.scores.lock().unwrap()
+            .iter()
+            .map(|(peer, score)| (peer.clone(), score.clone()))
+            .collect::<Vec<_>>();
+        scores.sort_by_key(|(_, score)| score.score);
+        scores
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(())
+        };
+        let scores = self.scores.lock().unwrap()
+            .iter()
+            .map(|(peer, score)| (hex::encode(peer.data()), score.clone()))
+            .collect::<BTreeMap<_, _>>();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, serde_json::to_string_pretty(&scores)?)
+            .map_err(|e| error!("Can't write {}: {}", tmp_path.display(), e))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn start_saver(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(SAVE_INTERVAL_SEC)).await;
+                if let Err(e) = self.save() {
+                    log::warn!("Can't save peer scores: {}", e);
+                }
+            }
+        });
+    }
+
+    fn load(path: &PathBuf) -> Result<HashMap<Arc<KeyId>, PeerScore>> {
+        if !path.exists() {
+            return Ok(HashMap::new())
+        }
+        let data = fs::read_to_string(path)
+            .map_err(|e| error!("Can't read {}: {}", path.display(), e))?;
+        let stored: BTreeMap<String, PeerScore> = serde_json::from_str(&data)?;
+        let mut scores = HashMap::new();
+        for (peer, score) in stored {
+            let id: [u8; 32] = hex::decode(&peer)?.as_slice().try_into()
+                .map_err(|_| error!("Invalid peer id {} in {}", peer, path.display()))?;
+            scores.insert(KeyId::from_data(id), score);
+        }
+        Ok(scores)
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+#[test]
+fn test_peer_scores() {
+    let mut score = PeerScore::default();
+    assert_eq!(score.weight(), 5);
+    // Two bad packages ban the peer for the base time
+    assert!(!score.update(PeerOutcome::BadData, 1000));
+    assert!(score.update(PeerOutcome::BadData, 1000));
+    assert_eq!(score, PeerScore { score: BAN_SCORE / 2, bans: 1, banned_until: 1000 + BASE_BAN_SEC });
+    assert!(score.is_banned(1000 + BASE_BAN_SEC - 1));
+    assert!(!score.is_banned(1000 + BASE_BAN_SEC));
+    // Each next ban is twice longer, until the peer is good again
+    for _ in 0..4 {
+        score.update(PeerOutcome::Timeout, 2000);
+    }
+    assert!(score.update(PeerOutcome::Timeout, 2000));
+    assert_eq!(score.banned_until, 2000 + 2 * BASE_BAN_SEC);
+    for _ in 0..MAX_SCORE * 2 {
+        score.update(PeerOutcome::Success, 3000);
+    }
+    assert_eq!(score, PeerScore { score: MAX_SCORE, bans: 0, banned_until: 2000 + 2 * BASE_BAN_SEC });
+    assert_eq!(score.weight(), 9);
+
+    // Scores survive restart
+    let path = std::env::temp_dir().join(format!("peer_scores_{}.json", std::process::id()));
+    let peer = KeyId::from_data([7; 32]);
+    let scores = PeerScores::new(Some(path.clone()));
+    scores.update(&peer, PeerOutcome::BadData);
+    scores.update(&peer, PeerOutcome::BadData);
+    assert!(scores.is_banned(&peer));
+    scores.save().unwrap();
+    let loaded = PeerScores::new(Some(path.clone()));
+    assert_eq!(loaded.scores(), scores.scores());
+    assert!(loaded.is_banned(&peer));
+    assert_eq!(loaded.weight(&KeyId::from_data([8; 32])), 5);
+    fs::remove_file(&path).ok();
+}
@@ -0,0 +1,210 @@
+use crate::{
+    block::{compare_block_ids, convert_block_id_ext_blk2api, BlockStuff},
+    block_proof::BlockProofStuff, engine::STATSD,
+};
+use adnl::{
+    common::{serialize, serialize_append, KeyId, KeyOption, TaggedByteSlice, TaggedTlObject},
+    node::AdnlNode
+};
+#[cfg(feature = "telemetry")]
+use adnl::common::tag_from_boxed_type;
+use overlay::{OverlayNode, PrivateOverlayShortId, QueriesConsumer};
+use rand::seq::SliceRandom;
+use rldp::RldpNode;
+use std::{io::Cursor, sync::{Arc, atomic::{AtomicBool, Ordering}}};
+use ton_api::Deserializer;
+use ton_api::ton::{
+    TLObject,
+    rpc::ton_node::{DownloadBlockFull, PrepareBlock},
+    ton_node::{Broadcast, DataFull, Prepared}
+};
+use ton_block::BlockIdExt;
+use ton_types::{error, fail, Result, UInt256};
+
+// Validators asked for a block before falling back to public overlay
+const DOWNLOAD_PEERS: usize = 3;
+const MAX_BLOCK_SIZE: i64 = 10 * 1024 * 1024;
+
+// Private overlay of the validator list. Own blocks and top shard block descriptions are
+// mirrored into it, blocks needed for validation are asked from other validators first.
+pub struct ValidatorsOverlay {
+    validator_list_id: UInt256,
+    overlay_id: Arc<PrivateOverlayShortId>,
+    overlay: Arc<OverlayNode>,
+    rldp: Arc<RldpNode>,
+    peers: Vec<Arc<KeyId>>,
+    stopped: AtomicBool,
+}
+
+impl ValidatorsOverlay {
+
+    pub fn calc_overlay_id(validator_list_id: &UInt256) -> Arc<PrivateOverlayShortId> {
+        let mut data = b"validators_overlay".to_vec();
+        data.extend_from_slice(validator_list_id.as_slice());
+        KeyId::from_data(UInt256::calc_file_hash(&data).inner())
+    }
+
+    pub fn new(
+        validator_list_id: UInt256,
+        overlay: &Arc<OverlayNode>,
+        rldp: &Arc<RldpNode>,
+        runtime_handle: &tokio::runtime::Handle,
+        local_adnl_key: &Arc<KeyOption>,
+        peers: Vec<Arc<KeyId>>,
+        consumer: Arc<dyn QueriesConsumer>
+    ) -> Result<Self> {
+        let overlay_id = Self::calc_overlay_id(&validator_list_id);
+        overlay.add_private_overlay(Some(runtime_handle.clone()), &overlay_id, local_adnl_key, &peers)?;
+        overlay.add_consumer(&overlay_id, consumer)?;
+        log::info!(
+            "Validators overlay {} of list {:x} is started with {} peers",
+            overlay_id, validator_list_id, peers.len()
+        );
+        Ok(Self {
+            validator_list_id,
+            overlay_id,
+            overlay: overlay.clone(),
+            rldp: rldp.clone(),
+            peers,
+            stopped: AtomicBool::new(false)
+        })
+    }
+
+    pub fn validator_list_id(&self) -> &UInt256 {
+        &self.validator_list_id
+    }
+
+    pub fn stop(&self) {
+        if !self.stopped.swap(true, Ordering::Relaxed) {
+            if let Err(e) = self.overlay.delete_private_overlay(&self.overlay_id) {
+                log::warn!("Can't delete validators overlay {}: {}", self.overlay_id, e);
+            }
+            log::info!(
+                "Validators overlay {} of list {:x} is stopped", self.overlay_id, self.validator_list_id
+            );
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub async fn send_broadcast(&self, broadcast: Broadcast) -> Result<()> {
+        if self.is_stopped() {
+            fail!("Validators overlay {} is stopped", self.overlay_id)
+        }
+        let data = serialize(&broadcast)?;
+        let info = self.overlay.broadcast(
+            &self.overlay_id,
+            &TaggedByteSlice {
+                object: &data,
+                #[cfg(feature = "telemetry")]
+                tag: 0x80000003 // Validators overlay broadcast
+            },
+            None
+        ).await?;
+        log::trace!(
+            "Broadcast is mirrored into validators overlay {} to {} nodes", self.overlay_id, info.send_to
+        );
+        STATSD.incr("validators_overlay_broadcasts_sent");
+        Ok(())
+    }
+
+    // Returns None when the overlay is stopped
+    pub async fn wait_broadcast(&self) -> Result<Option<(Broadcast, Arc<KeyId>)>> {
+        while !self.is_stopped() {
+            match self.overlay.wait_for_broadcast(&self.overlay_id).await {
+                Ok(info) => {
+                    let broadcast = Deserializer::new(&mut Cursor::new(info.data))
+                        .read_boxed::<Broadcast>()?;
+                    return Ok(Some((broadcast, info.recv_from)))
+                }
+                Err(e) => {
+                    if self.is_stopped() {
+                        break
+                    }
+                    log::warn!("Validators overlay {} broadcast waiting error: {}", self.overlay_id, e);
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Asks a few random validators, fails if none of them has the block
+    pub async fn download_block_full(&self, id: &BlockIdExt) -> Result<(BlockStuff, BlockProofStuff)> {
+        let mut peers = self.peers.iter().collect::<Vec<_>>();
+        peers.shuffle(&mut crate::rng::stream_for("validators overlay", &self.overlay_id));
+        for peer in peers.into_iter().take(DOWNLOAD_PEERS) {
+            if self.is_stopped() {
+                break
+            }
+            match self.download_block_full_from(peer, id).await {
+                Ok(ret) => {
+                    STATSD.incr("validators_overlay_downloads");
+                    return Ok(ret)
+                },
+                Err(e) => log::debug!(
+                    "Can't download block {} from validator {} via private overlay: {}", id, peer, e
+                )
+            }
+        }
+        fail!("Block {} is not downloaded via validators overlay {}", id, self.overlay_id)
+    }
+
+    async fn download_block_full_from(
+        &self,
+        peer: &Arc<KeyId>,
+        id: &BlockIdExt
+    ) -> Result<(BlockStuff, BlockProofStuff)> {
+        let query = TaggedTlObject {
+            object: TLObject::new(PrepareBlock { block: convert_block_id_ext_blk2api(id) }),
+            #[cfg(feature = "telemetry")]
+            tag: tag_from_boxed_type::<PrepareBlock>()
+        };
+        let timeout = Some(AdnlNode::calc_timeout(None));
+        let answer = self.overlay.query(peer, &query, &self.overlay_id, timeout)
+            .await?
+            .ok_or_else(|| error!("No answer to prepareBlock"))?;
+        match answer.downcast::<Prepared>() {
+            Ok(Prepared::TonNode_Prepared) => (),
+            Ok(Prepared::TonNode_NotFound) => fail!("Block is not found"),
+            Err(answer) => fail!("Wrong answer to prepareBlock: {:?}", answer)
+        }
+
+        let mut query = self.overlay.get_query_prefix(&self.overlay_id)?;
+        serialize_append(&mut query, &DownloadBlockFull { block: convert_block_id_ext_blk2api(id) })?;
+        let (answer, _) = self.overlay.query_via_rldp(
+            &self.rldp,
+            peer,
+            &TaggedByteSlice {
+                object: &query[..],
+                #[cfg(feature = "telemetry")]
+                tag: tag_from_boxed_type::<DownloadBlockFull>()
+            },
+            Some(MAX_BLOCK_SIZE),
+            None,
+            &self.overlay_id
+        ).await?;
+        let answer = answer.ok_or_else(|| error!("No answer to downloadBlockFull"))?;
+        match Deserializer::new(&mut Cursor::new(answer)).read_boxed::<DataFull>()? {
+            DataFull::TonNode_DataFullEmpty => fail!("Got empty answer to downloadBlockFull"),
+            DataFull::TonNode_DataFull(data_full) => {
+                if !compare_block_ids(id, &data_full.id) {
+                    fail!("Block with another id was received")
+                }
+                let block = BlockStuff::deserialize_checked(id.clone(), data_full.block.0)?;
+                let proof = BlockProofStuff::deserialize(
+                    block.id(), data_full.proof.0, data_full.is_link.into()
+                )?;
+                Ok((block, proof))
+            }
+        }
+    }
+}
+
+impl Drop for ValidatorsOverlay {
+    fn drop(&mut self) {
+        self.stop()
+    }
+}
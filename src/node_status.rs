@@ -0,0 +1,154 @@
+use adnl::{
+    client::{AdnlClient, AdnlClientConfig}, common::{serialize, KeyOptionJson}
+};
+use std::{fmt, path::Path};
+use ton_api::ton::{
+    self, TLObject, engine::validator::{ControlQueryError, Stats},
+    rpc::engine::validator::{ControlQuery, GetStats}
+};
+use ton_types::{error, fail, Result};
+
+// Written by the node into configs dir along with config.json
+const CONSOLE_CONFIG_NAME: &str = "console_config.json";
+
+pub struct StatusParams {
+    // Control server address, the one of console config by default
+    pub ctl_addr: Option<String>,
+    // File with client private key, the one of console config by default
+    pub ctl_key: Option<String>,
+}
+
+// Stats of running node in the order the control server gives them
+pub struct NodeStatus {
+    stats: Vec<(String, String)>,
+}
+
+impl NodeStatus {
+
+    // Seconds since the last applied masterchain block, None if there is no such block
+    pub fn timediff(&self) -> Option<i64> {
+        self.get("timediff").and_then(|timediff| timediff.parse().ok())
+    }
+
+    pub fn check_timediff(&self, max_timediff: i64) -> Result<()> {
+        match self.timediff() {
+            Some(timediff) if timediff > max_timediff => {
+                fail!("timediff {} sec exceeds {} sec", timediff, max_timediff)
+            }
+            Some(_) => Ok(()),
+            None => fail!("no masterchain block is applied yet")
+        }
+    }
+
+    // Numbers and flags are typed, the rest are strings
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        for (key, value) in self.stats.iter() {
+            let value = if let Ok(number) = value.parse::<i64>() {
+                number.into()
+            } else if let Ok(flag) = value.parse::<bool>() {
+                flag.into()
+            } else {
+                value.trim_end().into()
+            };
+            json.insert(key.clone(), value);
+        }
+        json.into()
+    }
+
+    fn get(&self, key: &str) -> Option<&str> {
+        self.stats.iter().find(|(name, _)| name == key).map(|(_, value)| value.as_str())
+    }
+}
+
+impl fmt::Display for NodeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self.stats.iter().map(|(key, _)| key.len()).max().unwrap_or_default();
+        for (key, value) in self.stats.iter() {
+            let mut lines = value.trim_end().lines();
+            writeln!(f, "{:width$}  {}", key, lines.next().unwrap_or_default(), width = width)?;
+            for line in lines {
+                writeln!(f, "{:width$}  {}", "", line, width = width)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Asks running node for GetStats as console does
+pub async fn query_status(configs_dir: &str, params: &StatusParams) -> Result<NodeStatus> {
+    let config = client_config(configs_dir, params)?;
+    let mut client = AdnlClient::connect(&config).await
+        .map_err(|e| error!("Can't connect to control server: {}", e))?;
+    let query = serialize(&TLObject::new(GetStats))?;
+    let answer = client.query(&TLObject::new(ControlQuery { data: ton::bytes(query) })).await?;
+    let answer = match answer.downcast::<ControlQueryError>() {
+        Ok(ControlQueryError::Engine_Validator_ControlQueryError(error)) => {
+            fail!("Control server refused the query, code {}: {}", error.code, error.message)
+        }
+        Err(answer) => answer
+    };
+    let Stats::Engine_Validator_Stats(stats) = answer.downcast::<Stats>()
+        .map_err(|answer| error!("Unexpected answer of control server: {:?}", answer))?;
+    Ok(NodeStatus { stats: stats.stats.0.into_iter().map(|stat| (stat.key, stat.value)).collect() })
+}
+
+// Console config of configs dir with address and client key set by parameters.
+// Config of console tool (with "config" section) is accepted too.
+fn client_config(configs_dir: &str, params: &StatusParams) -> Result<AdnlClientConfig> {
+    let path = Path::new(configs_dir).join(CONSOLE_CONFIG_NAME);
+    let data = std::fs::read_to_string(&path)
+        .map_err(|e| error!("Can't read {}, it has key of control server: {}", path.display(), e))?;
+    let mut json: serde_json::Value = serde_json::from_str(&data)
+        .map_err(|e| error!("Can't parse {}: {}", path.display(), e))?;
+    if let Some(config) = json.get("config").filter(|config| config.is_object()) {
+        json = config.clone();
+    }
+    if let Some(ctl_addr) = &params.ctl_addr {
+        json["server_address"] = ctl_addr.as_str().into();
+    }
+    if let Some(ctl_key) = &params.ctl_key {
+        let key: KeyOptionJson = serde_json::from_str(
+            &std::fs::read_to_string(ctl_key).map_err(|e| error!("Can't read {}: {}", ctl_key, e))?
+        ).map_err(|e| error!("{} is not a private key: {}", ctl_key, e))?;
+        json["client_key"] = serde_json::to_value(key)?;
+    }
+    if json.get("client_key").map(|key| key.is_null()).unwrap_or(true) {
+        fail!("client key is set neither by --ctl-key nor in {}", path.display())
+    }
+    AdnlClientConfig::from_json_config(&serde_json::from_value(json)?)
+}
+
+#[test]
+fn test_node_status() {
+    let stats = |stats: &[(&str, &str)]| NodeStatus {
+        stats: stats.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    };
+    let status = stats(&[
+        ("masterchainblocknumber", "1000"),
+        ("timediff", "7"),
+        ("in_current_vset_p34", "false"),
+        ("external_db_stats", "sink: kafka\nretries: 0\n"),
+    ]);
+    assert_eq!(status.timediff(), Some(7));
+    assert!(status.check_timediff(10).is_ok());
+    assert!(status.check_timediff(5).is_err());
+    assert_eq!(status.to_json(), serde_json::json!({
+        "masterchainblocknumber": 1000,
+        "timediff": 7,
+        "in_current_vset_p34": false,
+        "external_db_stats": "sink: kafka\nretries: 0"
+    }));
+    assert_eq!(
+        status.to_string(),
+        "masterchainblocknumber  1000\n\
+        timediff                7\n\
+        in_current_vset_p34     false\n\
+        external_db_stats       sink: kafka\n\
+        \x20                       retries: 0\n"
+    );
+
+    let status = stats(&[("masterchainblock", "not set")]);
+    assert_eq!(status.timediff(), None);
+    assert!(status.check_timediff(i64::MAX).is_err());
+}
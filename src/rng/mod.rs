@@ -1 +1,141 @@
 pub mod random;
+
+use rand::{RngCore, SeedableRng, rngs::ThreadRng};
+use rand_chacha::ChaCha20Rng;
+use std::{collections::HashMap, sync::{Mutex, RwLock}};
+
+// Randomness of node's subsystems: neighbours selection, jitter of timeouts, collator
+// choices. OS RNG by default, ChaCha streams derived from one seed for reproducible test
+// runs. Keys are always generated from OS RNG by `random`.
+
+lazy_static::lazy_static! {
+    static ref STREAMS: RwLock<Option<Streams>> = RwLock::new(None);
+}
+
+// Deterministic mode for all subsystems, set before they start
+pub fn init(seed: Option<u64>) {
+    *STREAMS.write().unwrap() = seed.map(Streams::new);
+}
+
+// Rng of subsystem for one use, take it anew instead of keeping
+pub fn stream(label: &'static str) -> NodeRng {
+    stream_for(label, "")
+}
+
+// Rng of subsystem's instance (overlay, shard) for one use. Instances run concurrently,
+// so each one counts its uses apart and gets the same values whatever others do.
+pub fn stream_for(label: &'static str, instance: impl std::fmt::Display) -> NodeRng {
+    match STREAMS.read().unwrap().as_ref() {
+        Some(streams) => NodeRng::Seeded(Box::new(streams.next(label, &instance.to_string()))),
+        None => NodeRng::Os(rand::thread_rng())
+    }
+}
+
+// Every label and instance has its own ChaCha key, so subsystems don't shift values of
+// each other. N-th use of the label by the instance gets N-th stream of its key. Values
+// are reproducible as long as uses of one instance are ordered, e.g. they aren't for
+// a label taken by concurrent tasks without instance.
+struct Streams {
+    seed: u64,
+    used: Mutex<HashMap<(&'static str, String), u64>>,
+}
+
+impl Streams {
+
+    fn new(seed: u64) -> Self {
+        Self { seed, used: Mutex::new(HashMap::new()) }
+    }
+
+    fn next(&self, label: &'static str, instance: &str) -> ChaCha20Rng {
+        let index = {
+            let mut used = self.used.lock().unwrap();
+            let used = used.entry((label, instance.to_string())).or_insert(0);
+            *used += 1;
+            *used - 1
+        };
+        let mut key = self.seed.to_le_bytes().to_vec();
+        key.extend_from_slice(label.as_bytes());
+        if !instance.is_empty() {
+            key.push(0);
+            key.extend_from_slice(instance.as_bytes());
+        }
+        let mut rng = ChaCha20Rng::from_seed(openssl::sha::sha256(&key));
+        rng.set_stream(index);
+        rng
+    }
+}
+
+pub enum NodeRng {
+    Os(ThreadRng),
+    Seeded(Box<ChaCha20Rng>),
+}
+
+impl RngCore for NodeRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            NodeRng::Os(rng) => rng.next_u32(),
+            NodeRng::Seeded(rng) => rng.next_u32()
+        }
+    }
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            NodeRng::Os(rng) => rng.next_u64(),
+            NodeRng::Seeded(rng) => rng.next_u64()
+        }
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            NodeRng::Os(rng) => rng.fill_bytes(dest),
+            NodeRng::Seeded(rng) => rng.fill_bytes(dest)
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            NodeRng::Os(rng) => rng.try_fill_bytes(dest),
+            NodeRng::Seeded(rng) => rng.try_fill_bytes(dest)
+        }
+    }
+}
+
+#[test]
+fn test_deterministic_streams() {
+    let values = |streams: &Streams, label: &'static str| {
+        let mut rng = streams.next(label, "");
+        (rng.next_u64(), rng.next_u64())
+    };
+    let streams = Streams::new(7);
+    let first = values(&streams, "neighbours");
+    let second = values(&streams, "neighbours");
+    assert_ne!(first, second);
+    let collator = values(&streams, "collator");
+    assert_ne!(first, collator);
+
+    // the same seed gives the same values, other labels don't shift them
+    let streams = Streams::new(7);
+    assert_eq!(values(&streams, "collator"), collator);
+    assert_eq!(values(&streams, "neighbours"), first);
+    assert_eq!(values(&streams, "neighbours"), second);
+
+    assert_ne!(values(&Streams::new(8), "neighbours"), first);
+}
+
+#[test]
+fn test_instance_streams() {
+    let values = |streams: &Streams, instance: &str| {
+        let mut rng = streams.next("collator", instance);
+        (rng.next_u64(), rng.next_u64())
+    };
+    let streams = Streams::new(7);
+    let master = values(&streams, "-1:8000000000000000");
+    let master_next = values(&streams, "-1:8000000000000000");
+    let shard = values(&streams, "0:8000000000000000");
+    assert_ne!(master, shard);
+
+    // uses of other instance interleaved in another order don't shift values
+    let streams = Streams::new(7);
+    assert_eq!(values(&streams, "0:8000000000000000"), shard);
+    assert_ne!(values(&streams, "0:8000000000000000"), shard);
+    assert_eq!(values(&streams, "-1:8000000000000000"), master);
+    assert_eq!(values(&streams, "-1:8000000000000000"), master_next);
+    assert_ne!(values(&streams, ""), master);
+}
@@ -1,10 +1,12 @@
 use crate::{
     types::top_block_descr::{TopBlockDescrStuff, TopBlockDescrId},
+    engine::STATSD,
     engine_traits::EngineOperations,
+    error::{NodeError, ValidatorError},
     shard_state::ShardStateStuff,
 };
-use ton_block::{BlockIdExt, TopBlockDescr, Deserializable, BlockSignatures};
-use ton_types::{fail, Result};
+use ton_block::{BlockIdExt, TopBlockDescr, Deserializable, BlockSignatures, ShardHashes};
+use ton_types::{error, fail, Result};
 use std::{
     sync::{Arc, atomic::{AtomicU32, Ordering}},
     time::Duration,
@@ -13,6 +15,11 @@ use std::{
 };
 use rand::Rng;
 
+// Top shard block descriptions are checked in parallel, but not more than this at once
+const VERIFY_WORKERS: usize = 8;
+// Descriptions waiting for the check over this limit are rejected
+const VERIFY_QUEUE: usize = 256;
+
 
 pub enum StoreAction {
     Save(TopBlockDescrId, Arc<TopBlockDescrStuff>),
@@ -24,6 +31,14 @@ pub enum ShardBlockProcessingResult {
     MightBeAdded(Arc<TopBlockDescrStuff>)
 }
 
+// Shard block dropped from pool without being included into masterchain
+pub struct RevokedBlock {
+    pub id: BlockIdExt,
+    pub reason: String,
+    // false if masterchain went beyond block's seqno, so the block may be committed ancestor
+    pub confirmed: bool,
+}
+
 struct ShardBlocksPoolItem {
     pub top_block: Arc<TopBlockDescrStuff>,
     pub own: bool,
@@ -33,9 +48,41 @@ pub struct ShardBlocksPool {
     last_mc_seq_no: AtomicU32,
     shard_blocks: lockfree::map::Map<TopBlockDescrId, ShardBlocksPoolItem>,
     storage_sender: Option<tokio::sync::mpsc::UnboundedSender<StoreAction>>,
+    verifier: TsbVerifier,
     is_fake: bool,
 }
 
+// Runs signature checks on blocking threads, so many shards don't delay each other
+// and async tasks of the node
+pub struct TsbVerifier {
+    queue: tokio::sync::Semaphore,
+    workers: tokio::sync::Semaphore,
+}
+
+impl TsbVerifier {
+
+    pub fn new(workers: usize, queue: usize) -> Self {
+        Self {
+            queue: tokio::sync::Semaphore::new(queue),
+            workers: tokio::sync::Semaphore::new(workers),
+        }
+    }
+
+    pub async fn verify<T: Send + 'static>(
+        &self,
+        check: impl FnOnce() -> Result<T> + Send + 'static
+    ) -> Result<T> {
+        let _queued = self.queue.try_acquire().map_err(|_| {
+            STATSD.incr("tsb_verify_queue_overflow");
+            error!("Top shard block verification queue is full")
+        })?;
+        let _worker = self.workers.acquire().await
+            .map_err(|e| error!("Can't get top shard block verification worker: {}", e))?;
+        tokio::task::spawn_blocking(check).await
+            .map_err(|e| error!("Top shard block verification is aborted: {}", e))?
+    }
+}
+
 impl ShardBlocksPool {
 
     pub fn new(
@@ -53,6 +100,7 @@ impl ShardBlocksPool {
                 last_mc_seq_no: AtomicU32::new(last_mc_seqno),
                 shard_blocks: tsbs,
                 storage_sender: Some(sender.clone()),
+                verifier: TsbVerifier::new(VERIFY_WORKERS, VERIFY_QUEUE),
                 is_fake,
             },
             receiver
@@ -105,13 +153,17 @@ impl ShardBlocksPool {
                 }
             }
 
-            // validate top block descr
+            // validate top block descr once. Newer one of the same shard could be accepted
+            // while this one was checked, so duplication is checked again after it.
             if tbds.is_none() {
-                tbds = Some(factory()?);
-            }
-
-            if !self.is_fake {
-                tbds.as_ref().unwrap().validate(&engine.load_last_applied_mc_state().await?)?;
+                let tbd = factory()?;
+                if !self.is_fake {
+                    let last_mc_state = engine.load_last_applied_mc_state().await?;
+                    let check = tbd.clone();
+                    self.verifier.verify(move || check.validate(&last_mc_state)).await?;
+                }
+                tbds = Some(tbd);
+                continue;
             }
 
             if check_only {
@@ -170,7 +222,10 @@ impl ShardBlocksPool {
     pub fn get_shard_blocks(&self, last_mc_seq_no: u32, only_own: bool) -> Result<Vec<Arc<TopBlockDescrStuff>>> {
         if last_mc_seq_no != self.last_mc_seq_no.load(Ordering::Relaxed) {
             log::error!("get_shard_blocks: Given last_mc_seq_no {} is not actual", last_mc_seq_no);
-            fail!("Given last_mc_seq_no {} is not actual {}", last_mc_seq_no, self.last_mc_seq_no.load(Ordering::Relaxed));
+            fail!(NodeError::Validator(ValidatorError::NotActualMcState, format!(
+                "Given last_mc_seq_no {} is not actual {}",
+                last_mc_seq_no, self.last_mc_seq_no.load(Ordering::Relaxed)
+            )))
         } else {
             let mut returned_list = string_builder::Builder::default();
             let mut blocks = Vec::new();
@@ -186,19 +241,57 @@ impl ShardBlocksPool {
         }
     }
 
-    pub fn update_shard_blocks(&self, last_mc_state: &ShardStateStuff) -> Result<()> {
+    // Removes outdated top shard blocks. Returns blocks which were dropped 
+    // without being included into masterchain, with reason.
+    pub fn update_shard_blocks(&self, last_mc_state: &ShardStateStuff) -> Result<Vec<RevokedBlock>> {
         self.last_mc_seq_no.store(last_mc_state.block_id().seq_no(), Ordering::Relaxed);
         let mut removed_list = string_builder::Builder::default();
+        let mut revoked = Vec::new();
         for block in self.shard_blocks.iter() {
-            if block.val().top_block.validate(last_mc_state).is_err() {
+            if let Err(e) = block.val().top_block.validate(last_mc_state) {
                 self.shard_blocks.remove(block.key());
                 self.send_to_storage(StoreAction::Remove(block.key().clone()));
                 removed_list.append(format!("\n{} {}", block.key().cc_seqno, block.key().id));
+                let id = block.val().top_block.proof_for();
+                if let Some(block) = Self::check_revoked(last_mc_state.shards()?, id, e.to_string())? {
+                    revoked.push(block);
+                }
             }
         }
         log::trace!("update_shard_blocks last_mc_state {} removed: {}", 
             last_mc_state.block_id(), removed_list.string().unwrap_or_default());
-        Ok(())
+        Ok(revoked)
+    }
+
+    // Drops restored top shard blocks not actual for given masterchain state,
+    // returns count of remaining ones
+    pub fn drop_outdated(&self, last_mc_state: &ShardStateStuff) -> Result<usize> {
+        for block in self.update_shard_blocks(last_mc_state)? {
+            log::info!("Restored shard block {} is dropped: {}", block.id, block.reason);
+        }
+        Ok(self.shard_blocks.iter().count())
+    }
+
+    // Block is revoked if masterchain committed another block with the same seqno.
+    // If shard's top block is newer the block is either its ancestor or revoked one,
+    // so it is returned as unconfirmed to be checked against committed chain by caller.
+    // Blocks newer than shard's top block are not committed yet, they are not revoked.
+    fn check_revoked(shards: &ShardHashes, id: &BlockIdExt, error: String) -> Result<Option<RevokedBlock>> {
+        let top_id = match shards.find_shard(&id.shard().left_ancestor_mask()?)? {
+            Some(descr) => descr.block_id().clone(),
+            None => return Ok(None)
+        };
+        if top_id.seq_no() == id.seq_no() && top_id.shard() == id.shard() && top_id != *id {
+            Ok(Some(RevokedBlock {
+                id: id.clone(), reason: format!("superseded by {}", top_id), confirmed: true
+            }))
+        } else if top_id.seq_no() > id.seq_no() {
+            Ok(Some(RevokedBlock {
+                id: id.clone(), reason: format!("not included into masterchain: {}", error), confirmed: false
+            }))
+        } else {
+            Ok(None)
+        }
     }
 
     fn send_to_storage(&self, action: StoreAction) {
@@ -215,7 +308,7 @@ pub fn resend_top_shard_blocks_worker(engine: Arc<dyn EngineOperations>) {
     tokio::spawn(async move {
         loop {
              // 2..3 seconds
-            let delay = rand::thread_rng().gen_range(2000, 3000);
+            let delay = crate::rng::stream("shard blocks").gen_range(2000, 3000);
             futures_timer::Delay::new(Duration::from_millis(delay)).await;
             match resend_top_shard_blocks(engine.deref()).await {
                 Ok(_) => log::trace!("resend_top_shard_blocks: ok"),
@@ -261,3 +354,132 @@ pub fn save_top_shard_blocks_worker(
         }
     });
 }
+
+#[test]
+fn test_shard_blocks_restore() {
+    struct TestEngine;
+    impl EngineOperations for TestEngine {}
+
+    let shard = ton_block::ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    let id = |seq_no| BlockIdExt {
+        shard_id: shard.clone(),
+        seq_no,
+        root_hash: ton_types::UInt256::from([seq_no as u8; 32]),
+        ..Default::default()
+    };
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (pool, mut receiver) = ShardBlocksPool::new(HashMap::new(), 0, true);
+    rt.block_on(async {
+        pool.process_shard_block_raw(&id(1), 1, vec!(), false, false, &TestEngine).await.unwrap();
+        pool.process_shard_block_raw(&id(2), 1, vec!(), false, false, &TestEngine).await.unwrap();
+        pool.process_shard_block_raw(&id(5), 2, vec!(), false, false, &TestEngine).await.unwrap();
+    });
+
+    // Stored the same way as save_top_shard_blocks_worker does
+    let mut db = HashMap::new();
+    while let Ok(action) = receiver.try_recv() {
+        match action {
+            StoreAction::Save(id, tsb) => db.insert(id.to_bytes().unwrap(), tsb.to_bytes().unwrap()),
+            StoreAction::Remove(id) => db.remove(&id.to_bytes().unwrap())
+        };
+    }
+
+    // Node is restarted
+    let restored = db.iter().map(|(id, tsb)| (
+        TopBlockDescrId::from_bytes(id).unwrap(),
+        TopBlockDescrStuff::from_bytes(tsb, true).unwrap()
+    )).collect();
+    let (pool, mut receiver) = ShardBlocksPool::new(restored, 0, true);
+    let mut ids = pool.get_shard_blocks(0, false).unwrap().iter()
+        .map(|tsb| tsb.proof_for().seq_no())
+        .collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, vec!(2, 5));
+
+    // Masterchain has already committed shard block 3 while node was down
+    let mut extra = ton_block::McStateExtra::default();
+    extra.shards.add_workchain(0, 1, Default::default(), Default::default()).unwrap();
+    extra.shards.update_shard(&shard, |mut descr| {
+        descr.seq_no = 3;
+        Ok(descr)
+    }).unwrap();
+    let mut state = ton_block::ShardStateUnsplit::with_ident(ton_block::ShardIdent::masterchain());
+    state.write_custom(Some(&extra)).unwrap();
+    let mc_id = BlockIdExt {
+        shard_id: ton_block::ShardIdent::masterchain(),
+        seq_no: 10,
+        ..Default::default()
+    };
+    let last_mc_state = ShardStateStuff::with_state(mc_id, state).unwrap();
+
+    assert_eq!(pool.drop_outdated(&last_mc_state).unwrap(), 1);
+    let ids = pool.get_shard_blocks(10, false).unwrap().iter()
+        .map(|tsb| tsb.proof_for().seq_no())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec!(5));
+    match receiver.try_recv() {
+        Ok(StoreAction::Remove(key)) => assert_eq!(key.cc_seqno, 1),
+        _ => panic!("outdated shard block must be removed from storage")
+    }
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn test_tsb_verify_16_shards() {
+    struct TestEngine;
+    impl EngineOperations for TestEngine {}
+
+    const SHARDS: u64 = 16;
+    const BLOCKS: u32 = 4;
+
+    let running = Arc::new(AtomicU32::new(0));
+    let max_running = Arc::new(AtomicU32::new(0));
+    // The first VERIFY_WORKERS checks wait for each other, so they are running at once
+    // if workers allow. Timeout only breaks a hang of broken implementation.
+    let gate = Arc::new((std::sync::Mutex::new(0usize), std::sync::Condvar::new()));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let verifier = TsbVerifier::new(VERIFY_WORKERS, VERIFY_QUEUE);
+    let (pool, _receiver) = ShardBlocksPool::new(HashMap::new(), 0, true);
+    let mut tasks = Vec::new();
+    for seq_no in 1..=BLOCKS {
+        for i in 0..SHARDS {
+            let shard = ton_block::ShardIdent::with_tagged_prefix(
+                0, (i << 60) | 0x0800_0000_0000_0000
+            ).unwrap();
+            let id = BlockIdExt {
+                shard_id: shard,
+                seq_no,
+                root_hash: ton_types::UInt256::from([seq_no as u8; 32]),
+                ..Default::default()
+            };
+            let (verifier, pool) = (&verifier, &pool);
+            let (running, max_running, gate) = (running.clone(), max_running.clone(), gate.clone());
+            tasks.push(async move {
+                verifier.verify(move || {
+                    let now_running = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now_running, Ordering::SeqCst);
+                    let (entered, entered_changed) = &*gate;
+                    let mut entered = entered.lock().unwrap();
+                    *entered += 1;
+                    entered_changed.notify_all();
+                    let _ = entered_changed.wait_timeout_while(
+                        entered, Duration::from_secs(10), |entered| *entered < VERIFY_WORKERS
+                    ).unwrap();
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                }).await?;
+                pool.process_shard_block_raw(&id, 1, vec!(), false, false, &TestEngine).await
+            });
+        }
+    }
+    for result in rt.block_on(futures::future::join_all(tasks)) {
+        result.unwrap();
+    }
+    // Checks run in parallel, but not more than workers
+    assert_eq!(max_running.load(Ordering::SeqCst), VERIFY_WORKERS as u32);
+
+    // Whatever order checks are finished in, the newest description of each shard is kept
+    let tsbs = pool.get_shard_blocks(0, false).unwrap();
+    assert_eq!(tsbs.len(), SHARDS as usize);
+    assert!(tsbs.iter().all(|tsb| tsb.proof_for().seq_no() == BLOCKS));
+}
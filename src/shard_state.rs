@@ -7,7 +7,14 @@ use ton_block::{
     ConfigParams, McShardRecord, McStateExtra, ShardDescr, ShardHashes,
     HashmapAugType, InRefValue, BinTree, BinTreeType, WorkchainDescr,
 };
-use ton_types::{Cell, SliceData, error, fail, Result, deserialize_tree_of_cells, UInt256};
+use ton_types::{AccountId, Cell, SliceData, error, fail, Result, deserialize_tree_of_cells, UInt256};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
 
 
 /// It is a wrapper around various shard state's representations and properties.
@@ -63,11 +70,18 @@ impl ShardStateStuff {
         }        
         let file_hash = UInt256::calc_file_hash(&bytes);
         if file_hash != id.file_hash {
-            fail!("Wrong zero state's {} file hash", id);
+            fail!(
+                "Wrong zero state's {} file hash: expected {:x}, got {:x}",
+                id, id.file_hash, file_hash
+            );
         }
         let root = deserialize_tree_of_cells(&mut Cursor::new(bytes))?;
-        if &root.repr_hash() != id.root_hash() {
-            fail!("Wrong zero state's {} root hash", id);
+        let root_hash = root.repr_hash();
+        if &root_hash != id.root_hash() {
+            fail!(
+                "Wrong zero state's {} root hash: expected {:x}, got {:x}",
+                id, id.root_hash, root_hash
+            );
         }
         Self::new(id, root)
     }
@@ -153,6 +167,32 @@ impl ShardStateStuff {
     pub fn read_cur_validator_set_and_cc_conf(&self) -> Result<(ValidatorSet, CatchainConfig)> {
         self.config_params()?.read_cur_validator_set_and_cc_conf()
     }
+
+    // Accounts changed from this state to the other one, sorted by id. Subtrees with
+    // the same hash are skipped, so unchanged accounts are not visited.
+    pub fn diff_accounts(&self, other: &ShardStateStuff) -> Result<Vec<(AccountId, ChangeKind)>> {
+        if self.shard() != other.shard() {
+            fail!(
+                "Can't diff accounts of states of different shards {} and {}",
+                self.shard(), other.shard()
+            )
+        }
+        let mut changes = Vec::new();
+        self.state().read_accounts()?.scan_diff_with_aug(
+            &other.state().read_accounts()?,
+            |id: UInt256, old, new| {
+                let kind = match (old, new) {
+                    (None, Some(_)) => ChangeKind::Added,
+                    (Some(_), None) => ChangeKind::Removed,
+                    _ => ChangeKind::Modified
+                };
+                changes.push((id, kind));
+                Ok(true)
+            }
+        )?;
+        changes.sort_by(|(id1, _), (id2, _)| id1.cmp(id2));
+        Ok(changes.into_iter().map(|(id, kind)| (AccountId::from(id), kind)).collect())
+    }
 }
 
 
@@ -218,3 +258,49 @@ impl Deserializable for ShardHashesStuff {
         Ok(Self { shards })
     }
 }
+
+#[test]
+fn test_diff_accounts() {
+    use ton_block::{
+        Account, Augmentation, CurrencyCollection, MsgAddressInt, ShardAccount, ShardAccounts
+    };
+
+    let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    let state = |shard: &ShardIdent, accounts: &[(u8, u64)]| {
+        let mut shard_accounts = ShardAccounts::default();
+        for (id, balance) in accounts {
+            let id = AccountId::from([*id; 32]);
+            let address = MsgAddressInt::with_standart(None, 0, id.clone()).unwrap();
+            let account = Account::with_address_and_ballance(
+                &address, &CurrencyCollection::with_grams(*balance)
+            );
+            let shard_account = ShardAccount::with_account_root(
+                account.serialize().unwrap(), UInt256::default(), 0
+            );
+            shard_accounts.set_builder_serialized(
+                id, &shard_account.write_to_new_cell().unwrap(), &account.aug().unwrap()
+            ).unwrap();
+        }
+        let mut state = ShardStateUnsplit::with_ident(shard.clone());
+        state.write_accounts(&shard_accounts).unwrap();
+        let id = BlockIdExt { shard_id: shard.clone(), ..Default::default() };
+        ShardStateStuff::with_state(id, state).unwrap()
+    };
+    let id = |id: u8| AccountId::from([id; 32]);
+
+    let old = state(&shard, &[(1, 100), (2, 200), (3, 300)]);
+    // Account 2 is deleted, balance of 3 is changed, 4 is added
+    let new = state(&shard, &[(1, 100), (3, 350), (4, 400)]);
+    assert_eq!(
+        old.diff_accounts(&new).unwrap(),
+        vec!((id(2), ChangeKind::Removed), (id(3), ChangeKind::Modified), (id(4), ChangeKind::Added))
+    );
+    assert_eq!(
+        new.diff_accounts(&old).unwrap(),
+        vec!((id(2), ChangeKind::Added), (id(3), ChangeKind::Modified), (id(4), ChangeKind::Removed))
+    );
+    assert!(old.diff_accounts(&old).unwrap().is_empty());
+
+    let other_shard = ShardIdent::with_tagged_prefix(0, 0x4000_0000_0000_0000).unwrap();
+    assert!(old.diff_accounts(&state(&other_shard, &[(1, 100)])).is_err());
+}
@@ -0,0 +1,238 @@
+use std::{
+    future::Future,
+    sync::{Mutex, atomic::{AtomicBool, AtomicI32, Ordering}},
+    time::{Duration, Instant}
+};
+use futures::future::Either;
+use tokio::sync::watch;
+use ton_types::Result;
+
+// Exit code of the node which didn't manage to shut down within the deadline
+pub const SHUTDOWN_TIMEOUT_EXIT_CODE: i32 = 3;
+const COMPONENTS_CHECK_INTERVAL_MS: u64 = 100;
+
+lazy_static::lazy_static! {
+    pub static ref SHUTDOWN: Shutdown = Shutdown::new();
+}
+
+// Node-wide shutdown state. Long-running loops check or wait for the request and stop
+// taking new work, the engine drains the rest stage by stage and reports when it is done.
+pub struct Shutdown {
+    requested: AtomicBool,
+    requested_sender: watch::Sender<bool>,
+    requested_receiver: watch::Receiver<bool>,
+    finished_sender: watch::Sender<bool>,
+    finished_receiver: watch::Receiver<bool>,
+    // Set by the engine, without it there is nothing to drain
+    handled: AtomicBool,
+    // Components which finish their work after the request, e.g. validator manager
+    running: Mutex<Vec<&'static str>>,
+    stage: Mutex<&'static str>,
+    // Process exit code after drain, nonzero if the node itself gave up
+    exit_code: AtomicI32,
+}
+
+impl Shutdown {
+
+    pub(crate) fn new() -> Self {
+        let (requested_sender, requested_receiver) = watch::channel(false);
+        let (finished_sender, finished_receiver) = watch::channel(false);
+        Self {
+            requested: AtomicBool::new(false),
+            requested_sender,
+            requested_receiver,
+            finished_sender,
+            finished_receiver,
+            handled: AtomicBool::new(false),
+            running: Mutex::new(Vec::new()),
+            stage: Mutex::new("not started"),
+            exit_code: AtomicI32::new(0),
+        }
+    }
+
+    // False if shutdown has been requested already
+    pub fn request(&self, reason: &str) -> bool {
+        self.request_with_exit_code(reason, 0)
+    }
+
+    // Exit code is kept from the first request only
+    pub fn request_with_exit_code(&self, reason: &str, exit_code: i32) -> bool {
+        if self.requested.swap(true, Ordering::Relaxed) {
+            return false
+        }
+        self.exit_code.store(exit_code, Ordering::Relaxed);
+        log::warn!("Shutdown is requested: {}", reason);
+        self.requested_sender.send(true).ok();
+        true
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::Relaxed)
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code.load(Ordering::Relaxed)
+    }
+
+    pub async fn wait(&self) {
+        Self::wait_flag(&self.requested_receiver).await
+    }
+
+    pub fn set_handled(&self) {
+        self.handled.store(true, Ordering::Relaxed)
+    }
+
+    pub fn is_handled(&self) -> bool {
+        self.handled.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(&self) {
+        self.finished_sender.send(true).ok();
+    }
+
+    pub async fn wait_finished(&self) {
+        Self::wait_flag(&self.finished_receiver).await
+    }
+
+    async fn wait_flag(receiver: &watch::Receiver<bool>) {
+        let mut receiver = receiver.clone();
+        while !*receiver.borrow() {
+            if receiver.changed().await.is_err() {
+                return
+            }
+        }
+    }
+
+    // The component is waited for by shutdown until the guard is dropped
+    pub fn component(&'static self, name: &'static str) -> ComponentGuard {
+        self.running.lock().unwrap().push(name);
+        ComponentGuard { shutdown: self, name }
+    }
+
+    pub fn running_components(&self) -> Vec<&'static str> {
+        self.running.lock().unwrap().clone()
+    }
+
+    pub async fn wait_components(&self) {
+        loop {
+            let stopped = self.running.lock().unwrap().is_empty();
+            if stopped {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(COMPONENTS_CHECK_INTERVAL_MS)).await;
+        }
+    }
+
+    pub fn stage(&self) -> &'static str {
+        *self.stage.lock().unwrap()
+    }
+
+    // Stage is logged with its duration, its failure doesn't stop the next stages
+    pub async fn run_stage(&self, name: &'static str, stage: impl Future<Output = Result<()>>) {
+        *self.stage.lock().unwrap() = name;
+        log::info!("Shutdown stage {}: started", name);
+        let started = Instant::now();
+        match stage.await {
+            Ok(()) => log::info!(
+                "Shutdown stage {}: done in {} ms", name, started.elapsed().as_millis()
+            ),
+            Err(e) => log::error!(
+                "Shutdown stage {}: failed in {} ms: {}", name, started.elapsed().as_millis(), e
+            )
+        }
+    }
+}
+
+pub struct ComponentGuard {
+    shutdown: &'static Shutdown,
+    name: &'static str,
+}
+
+impl Drop for ComponentGuard {
+    fn drop(&mut self) {
+        let mut running = self.shutdown.running.lock().unwrap();
+        if let Some(pos) = running.iter().position(|name| *name == self.name) {
+            running.remove(pos);
+        }
+    }
+}
+
+// SIGTERM and SIGINT request shutdown, so does the shutdown control query. The process exits
+// with the requested code when the engine has drained, or with SHUTDOWN_TIMEOUT_EXIT_CODE
+// when the deadline is over.
+pub fn listen_signals(deadline: Duration) -> Result<()> {
+    #[cfg(unix)]
+    let mut sigterm = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            #[cfg(unix)]
+            let signal = {
+                let sigterm = Box::pin(async { sigterm.recv().await; "SIGTERM" });
+                let sigint = Box::pin(async { tokio::signal::ctrl_c().await.ok(); "SIGINT" });
+                match futures::future::select(sigterm, sigint).await {
+                    Either::Left((signal, _)) | Either::Right((signal, _)) => signal
+                }
+            };
+            #[cfg(not(unix))]
+            let signal = {
+                tokio::signal::ctrl_c().await.ok();
+                "Ctrl-C"
+            };
+            if !SHUTDOWN.request(signal) {
+                log::warn!("{} received, shutdown is in progress: {}", signal, SHUTDOWN.stage());
+            }
+        }
+    });
+    tokio::spawn(async move {
+        SHUTDOWN.wait().await;
+        start_watchdog(deadline);
+        if SHUTDOWN.is_handled() {
+            SHUTDOWN.wait_finished().await;
+        }
+        let exit_code = SHUTDOWN.exit_code();
+        log::info!("Node is stopped, exit code {}", exit_code);
+        log::logger().flush();
+        std::process::exit(exit_code)
+    });
+    Ok(())
+}
+
+// A thread of its own, so the deadline holds even if the runtime is stuck
+fn start_watchdog(deadline: Duration) {
+    std::thread::spawn(move || {
+        std::thread::sleep(deadline);
+        log::error!(
+            "Shutdown didn't finish in {} sec, stage: {}, running: {:?}; exiting forcibly",
+            deadline.as_secs(), SHUTDOWN.stage(), SHUTDOWN.running_components()
+        );
+        log::logger().flush();
+        std::process::exit(SHUTDOWN_TIMEOUT_EXIT_CODE)
+    });
+}
+
+#[test]
+fn test_shutdown_stages() {
+    let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let guard = shutdown.component("validator manager");
+        let waiting = tokio::spawn(async move {
+            shutdown.wait().await;
+            drop(guard);
+        });
+        assert!(!shutdown.is_requested());
+        assert!(shutdown.request_with_exit_code("test", 4));
+        assert!(!shutdown.request("test again"));
+        assert_eq!(shutdown.exit_code(), 4);
+        waiting.await.unwrap();
+        shutdown.wait_components().await;
+        assert!(shutdown.running_components().is_empty());
+
+        shutdown.run_stage("flush", async { Ok(()) }).await;
+        assert_eq!(shutdown.stage(), "flush");
+        shutdown.finish();
+        shutdown.wait_finished().await;
+        // already requested
+        shutdown.wait().await;
+    });
+}
@@ -0,0 +1,217 @@
+use crate::{
+    config::{SupervisorConfig, TaskFailurePolicy}, shutdown::{Shutdown, SHUTDOWN}
+};
+use futures::future::BoxFuture;
+use std::{
+    fmt::Write, sync::{Arc, Mutex}, time::{Duration, Instant}
+};
+use ton_types::{error, Result};
+
+// Exit code of the node whose critical task failed and wasn't restarted
+pub const TASK_FAILURE_EXIT_CODE: i32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaskState {
+    Running,
+    Restarting,
+    Failed,
+    Stopped,
+}
+
+struct TaskHealth {
+    name: &'static str,
+    state: TaskState,
+    restarts: u32,
+    last_error: Option<String>,
+}
+
+// Keeps critical tasks of the engine running. Task terminated before shutdown is
+// restarted or the node is shut down with TASK_FAILURE_EXIT_CODE, as the policy says.
+pub struct Supervisor {
+    config: SupervisorConfig,
+    shutdown: &'static Shutdown,
+    tasks: Mutex<Vec<TaskHealth>>,
+}
+
+impl Supervisor {
+
+    pub fn new(config: SupervisorConfig) -> Self {
+        Self::with_shutdown(config, &SHUTDOWN)
+    }
+
+    fn with_shutdown(config: SupervisorConfig, shutdown: &'static Shutdown) -> Self {
+        Self { config, shutdown, tasks: Mutex::new(Vec::new()) }
+    }
+
+    // Start is called for the first run and for every restart, so the task has to
+    // take its position from DB rather than from captured values
+    pub fn spawn<F>(self: &Arc<Self>, name: &'static str, start: F)
+    where F: Fn() -> BoxFuture<'static, Result<()>> + Send + Sync + 'static
+    {
+        self.spawn_with_policy(name, self.config.policy, start)
+    }
+
+    // Task which can't be restarted safely (e.g. its state outlives it) shuts the node
+    // down whatever the configured policy is
+    pub fn spawn_no_restart<F>(self: &Arc<Self>, name: &'static str, start: F)
+    where F: Fn() -> BoxFuture<'static, Result<()>> + Send + Sync + 'static
+    {
+        self.spawn_with_policy(name, TaskFailurePolicy::Exit, start)
+    }
+
+    fn spawn_with_policy<F>(self: &Arc<Self>, name: &'static str, policy: TaskFailurePolicy, start: F)
+    where F: Fn() -> BoxFuture<'static, Result<()>> + Send + Sync + 'static
+    {
+        self.tasks.lock().unwrap().push(TaskHealth {
+            name,
+            state: TaskState::Running,
+            restarts: 0,
+            last_error: None,
+        });
+        let supervisor = Arc::clone(self);
+        tokio::spawn(async move { supervisor.supervise(name, policy, start).await });
+    }
+
+    async fn supervise<F>(&self, name: &'static str, policy: TaskFailurePolicy, start: F)
+    where F: Fn() -> BoxFuture<'static, Result<()>>
+    {
+        let initial_backoff = Duration::from_millis(self.config.restart_backoff_ms);
+        let max_backoff = Duration::from_millis(self.config.max_restart_backoff_ms);
+        let stable_run = Duration::from_secs(self.config.stable_run_sec);
+        let mut backoff = initial_backoff;
+        let mut restarts = 0;
+        loop {
+            let started = Instant::now();
+            // Own tokio task, so panic is caught as an error too
+            let result = match tokio::spawn(start()).await {
+                Ok(result) => result,
+                Err(e) => Err(error!("panicked: {}", e))
+            };
+            if self.shutdown.is_requested() {
+                self.update(name, TaskState::Stopped, restarts, result.err());
+                return
+            }
+            let error = result.err().unwrap_or_else(|| error!("finished unexpectedly"));
+            log::error!("FATAL!!! Task {} is terminated: {}", name, error);
+            if started.elapsed() >= stable_run {
+                backoff = initial_backoff;
+                restarts = 0;
+            }
+            let exhausted = restarts >= self.config.max_restarts;
+            if (policy == TaskFailurePolicy::Exit) || exhausted {
+                let reason = format!("task {} is terminated: {}", name, error);
+                self.update(name, TaskState::Failed, restarts, Some(error));
+                self.shutdown.request_with_exit_code(&reason, TASK_FAILURE_EXIT_CODE);
+                return
+            }
+            restarts += 1;
+            log::warn!(
+                "Task {} is restarted in {} ms, restart {} of {}",
+                name, backoff.as_millis(), restarts, self.config.max_restarts
+            );
+            self.update(name, TaskState::Restarting, restarts, Some(error));
+            let delay = Box::pin(tokio::time::sleep(backoff));
+            if let futures::future::Either::Right(_) =
+                futures::future::select(delay, Box::pin(self.shutdown.wait())).await
+            {
+                self.update(name, TaskState::Stopped, restarts, None);
+                return
+            }
+            backoff = std::cmp::min(backoff * 2, max_backoff);
+            self.update(name, TaskState::Running, restarts, None);
+        }
+    }
+
+    // Last error is kept until the next one
+    fn update(&self, name: &str, state: TaskState, restarts: u32, error: Option<failure::Error>) {
+        let mut tasks = self.tasks.lock().unwrap();
+        if let Some(task) = tasks.iter_mut().find(|task| task.name == name) {
+            task.state = state;
+            task.restarts = restarts;
+            if let Some(error) = error {
+                task.last_error = Some(error.to_string());
+            }
+        }
+    }
+
+    // Line per task for GetStats
+    pub fn status(&self) -> String {
+        let mut status = String::new();
+        for task in self.tasks.lock().unwrap().iter() {
+            write!(status, "{}: {:?}, restarts: {}", task.name, task.state, task.restarts).ok();
+            if let Some(error) = &task.last_error {
+                write!(status, ", last error: {}", error).ok();
+            }
+            status.push('\n');
+        }
+        status
+    }
+}
+
+#[test]
+fn test_supervisor_restarts() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+    let config = SupervisorConfig {
+        policy: TaskFailurePolicy::Restart,
+        max_restarts: 2,
+        restart_backoff_ms: 1,
+        max_restart_backoff_ms: 2,
+        stable_run_sec: 600,
+    };
+    let supervisor = Arc::new(Supervisor::with_shutdown(config, shutdown));
+    let runs = Arc::new(AtomicU32::new(0));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let task_runs = Arc::clone(&runs);
+        supervisor.spawn("shards client", move || {
+            let runs = Arc::clone(&task_runs);
+            Box::pin(async move {
+                runs.fetch_add(1, Ordering::Relaxed);
+                Err(error!("test failure"))
+            })
+        });
+        shutdown.wait().await;
+    });
+    // first run and two restarts
+    assert_eq!(runs.load(Ordering::Relaxed), 3);
+    assert_eq!(shutdown.exit_code(), TASK_FAILURE_EXIT_CODE);
+    assert_eq!(
+        supervisor.status(),
+        "shards client: Failed, restarts: 2, last error: test failure\n"
+    );
+}
+
+#[test]
+fn test_supervisor_no_restart() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    let shutdown: &'static Shutdown = Box::leak(Box::new(Shutdown::new()));
+    let config = SupervisorConfig {
+        policy: TaskFailurePolicy::Restart,
+        max_restarts: 2,
+        restart_backoff_ms: 1,
+        max_restart_backoff_ms: 2,
+        stable_run_sec: 600,
+    };
+    let supervisor = Arc::new(Supervisor::with_shutdown(config, shutdown));
+    let runs = Arc::new(AtomicU32::new(0));
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let task_runs = Arc::clone(&runs);
+        supervisor.spawn_no_restart("validator manager", move || {
+            let runs = Arc::clone(&task_runs);
+            Box::pin(async move {
+                runs.fetch_add(1, Ordering::Relaxed);
+                Err(error!("test failure"))
+            })
+        });
+        shutdown.wait().await;
+    });
+    // Restart policy is ignored
+    assert_eq!(runs.load(Ordering::Relaxed), 1);
+    assert_eq!(shutdown.exit_code(), TASK_FAILURE_EXIT_CODE);
+    assert_eq!(
+        supervisor.status(),
+        "validator manager: Failed, restarts: 0, last error: test failure\n"
+    );
+}
@@ -1,9 +1,14 @@
 use crate::{
     block::{BlockIdExtExtention, BlockStuff}, block_proof::BlockProofStuff, boot,
-    engine_traits::EngineOperations
+    engine::STATSD, engine_traits::EngineOperations, error::is_retriable,
+    internal_db::sync_cursor::SyncCursor
 };
 use adnl::common::{KeyId, Wait};
-use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+use futures::{Stream, StreamExt};
+use std::{
+    collections::BTreeMap, fmt::Debug, future::Future, path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}, time::Instant
+};
 use storage::{
     archives::{
         ARCHIVE_PACKAGE_SIZE, package::read_package_from, 
@@ -12,12 +17,147 @@ use storage::{
     types::BlockHandle
 };
 use tokio::task::JoinHandle;
-use ton_block::BlockIdExt;
+use ton_block::{BlockIdExt, ShardIdent};
 use ton_types::{error, fail, Result};
 
 //type PreDownloadTask = (u32, JoinHandle<Result<Vec<u8>>>);
 
 const TARGET: &str = "sync";
+// Downloaded packages wait there to be applied in seq_no order
+const STAGING_DIR_NAME: &str = "sync_staging";
+
+// Shared by download tasks of one sync
+struct SyncDownloads {
+    active_peers: Arc<lockfree::set::Set<Arc<KeyId>>>,
+    staging_dir: PathBuf,
+    downloaded_bytes: AtomicU64,
+    started: Instant,
+}
+
+impl SyncDownloads {
+    fn new(staging_dir: PathBuf) -> Self {
+        Self {
+            active_peers: Arc::new(lockfree::set::Set::new()),
+            staging_dir,
+            downloaded_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    fn package_path(&self, seq_no: u32) -> PathBuf {
+        self.staging_dir.join(format!("archive_{}.pack", seq_no))
+    }
+
+    // Stores package and updates aggregate download rate. Package is renamed after write,
+    // so one interrupted by restart is never taken as complete.
+    async fn stage(&self, seq_no: u32, data: Vec<u8>) -> Result<PathBuf> {
+        let path = self.package_path(seq_no);
+        let tmp_path = path.with_extension("pack.tmp");
+        tokio::fs::write(&tmp_path, &data).await
+            .map_err(|e| error!("Can't write staged package {}: {}", tmp_path.display(), e))?;
+        tokio::fs::rename(&tmp_path, &path).await
+            .map_err(|e| error!("Can't write staged package {}: {}", path.display(), e))?;
+        let bytes = self.downloaded_bytes.fetch_add(data.len() as u64, Ordering::Relaxed) +
+            data.len() as u64;
+        let elapsed = self.started.elapsed().as_millis() as u64;
+        STATSD.gauge("sync_archives_download_rate", (bytes * 1000 / elapsed.max(1)) as f64);
+        Ok(path)
+    }
+}
+
+// Prepares staging directory for sync. Packages staged by interrupted sync starting from
+// the archive of the cursor are kept to be applied without download (up to `limit` ones),
+// the rest of files are removed.
+fn prepare_staging(
+    staging_dir: &Path,
+    cursor: Option<&SyncCursor>,
+    limit: usize
+) -> Result<Vec<(u32, PathBuf)>> {
+    std::fs::create_dir_all(staging_dir)?;
+    let mut staged = Vec::new();
+    for entry in std::fs::read_dir(staging_dir)? {
+        let path = entry?.path();
+        let seq_no = path.file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("archive_"))
+            .and_then(|name| name.strip_suffix(".pack"))
+            .and_then(|seq_no| seq_no.parse::<u32>().ok());
+        match (seq_no, cursor) {
+            (Some(seq_no), Some(cursor)) if seq_no >= cursor.archive_seq_no => staged.push((seq_no, path)),
+            _ => std::fs::remove_file(&path)?
+        }
+    }
+    staged.sort();
+    for (_, path) in staged.drain(limit.min(staged.len())..) {
+        std::fs::remove_file(&path)?;
+    }
+    Ok(staged)
+}
+
+// Staged package is removed only after it is imported, so the package interrupted by error
+// or restart is applied again from staging directory instead of being downloaded
+async fn import_staged_package<F, R>(path: &Path, import: F) -> Result<()>
+where
+    F: FnOnce(Arc<BlockMaps>) -> R,
+    R: futures::Future<Output = Result<()>>
+{
+    let data = tokio::fs::read(path).await
+        .map_err(|e| error!("Can't read staged package {}: {}", path.display(), e))?;
+    let maps = Arc::new(read_package(&data).await?);
+    drop(data);
+    import(maps).await?;
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        log::warn!(target: TARGET, "Can't remove staged package {}: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+// Cursor is trusted only if it is on the chain applied so far and its blocks are applied
+fn check_sync_cursor(
+    cursor: &SyncCursor,
+    last_mc_block_id: &BlockIdExt,
+    is_applied: impl Fn(&BlockIdExt) -> Result<bool>
+) -> Result<()> {
+    if cursor.mc_block_id.seq_no() > last_mc_block_id.seq_no() {
+        fail!("MC block {} is ahead of applied one {}", cursor.mc_block_id, last_mc_block_id)
+    }
+    if cursor.mc_block_id.seq_no() == last_mc_block_id.seq_no() && cursor.mc_block_id != *last_mc_block_id {
+        fail!("MC block {} differs from applied one {}", cursor.mc_block_id, last_mc_block_id)
+    }
+    for id in std::iter::once(&cursor.mc_block_id).chain(cursor.shard_block_ids.iter()) {
+        if !is_applied(id)? {
+            fail!("block {} is not applied", id)
+        }
+    }
+    Ok(())
+}
+
+// Cursor of interrupted sync, invalid one is removed
+fn load_sync_cursor(engine: &Arc<dyn EngineOperations>) -> Result<Option<SyncCursor>> {
+    let cursor = match engine.load_sync_cursor()? {
+        Some(cursor) => cursor,
+        None => return Ok(None)
+    };
+    let last_mc_block_id = last_synced_mc_block_id(engine)?;
+    let checked = check_sync_cursor(&cursor, &last_mc_block_id, |id| {
+        Ok(engine.load_block_handle(id)?.map(|handle| handle.is_applied()).unwrap_or(false))
+    });
+    match checked {
+        Ok(()) => {
+            log::info!(
+                target: TARGET,
+                "Resuming sync after MC block {}, archive for MC seq_no {}",
+                cursor.mc_block_id, cursor.archive_seq_no
+            );
+            Ok(Some(cursor))
+        },
+        Err(e) => {
+            log::warn!(target: TARGET, "Sync cursor is discarded: {}", e);
+            engine.clear_sync_cursor()?;
+            Ok(None)
+        }
+    }
+}
 
 #[async_trait::async_trait]
 pub trait StopSyncChecker {
@@ -33,34 +173,40 @@ pub(crate) async fn start_sync(
         engine: &Arc<dyn EngineOperations>, 
         seq_no: u32, 
         last_mc_block_id: &Arc<BlockIdExt>, 
-        data: &Vec<u8>
+        path: &Path
     ) -> Result<bool> {
         log::info!(target: TARGET, "Reading package for MC seq_no = {}", seq_no);
-        let maps = Arc::new(read_package(data).await?);
-        log::info!(
-            target: TARGET,
-            "Package contains {} masterchain blocks, {} blocks overall.",
-            maps.mc_blocks_ids.len(),
-            maps.blocks.len(),
-        );
-        import_package(maps, engine, last_mc_block_id).await?;
+        import_staged_package(path, |maps| async move {
+            log::info!(
+                target: TARGET,
+                "Package contains {} masterchain blocks, {} blocks overall.",
+                maps.mc_blocks_ids.len(),
+                maps.blocks.len(),
+            );
+            import_package(maps, engine, last_mc_block_id, seq_no).await
+        }).await?;
         log::info!(target: TARGET, "Package imported for MC seq_no = {}", seq_no);
         Ok(true)
     }
 
     fn download(
         engine: &Arc<dyn EngineOperations>, 
-        wait: &Arc<Wait<(u32, Result<Option<Vec<u8>>>)>>, 
+        wait: &Arc<Wait<(u32, Result<Option<PathBuf>>)>>,
         seq_no: u32,
-        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+        downloads: &Arc<SyncDownloads>
     ) {
         wait.request();
+        STATSD.gauge("sync_archives_window", wait.count() as f64);
         let engine = engine.clone();
         let wait = wait.clone();
-        let active_peers = active_peers.clone();
+        let downloads = downloads.clone();
         tokio::spawn(
             async move {
-                let res = download_archive(engine, seq_no, &active_peers).await;
+                let res = match download_archive(engine, seq_no, &downloads.active_peers).await {
+                    Ok(Some(data)) => downloads.stage(seq_no, data).await.map(Some),
+                    Ok(None) => Ok(None),
+                    Err(e) => Err(e)
+                };
                 wait.respond(Some((seq_no, res)));
             }
         );
@@ -69,9 +215,9 @@ pub(crate) async fn start_sync(
 
     async fn force_redownload(
         engine: &Arc<dyn EngineOperations>, 
-        wait: &Arc<Wait<(u32, Result<Option<Vec<u8>>>)>>, 
+        wait: &Arc<Wait<(u32, Result<Option<PathBuf>>)>>,
         queue: &mut Vec<(u32, ArchiveStatus)>,
-        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>
+        downloads: &Arc<SyncDownloads>
     ) -> Result<()> {
         // Find latest downloaded archive
         let mut latest = None;
@@ -93,7 +239,7 @@ pub(crate) async fn start_sync(
                         continue
                     }
                     *status = ArchiveStatus::Downloading;
-                    download(engine, wait, *seq_no, active_peers)
+                    download(engine, wait, *seq_no, downloads)
                 }
             }
         }
@@ -117,7 +263,7 @@ pub(crate) async fn start_sync(
                 for (seq_no, status) in queue.iter_mut() {
                     if earliest == *seq_no {
                         *status = ArchiveStatus::Downloading;
-                        download(engine, wait, *seq_no, active_peers);
+                        download(engine, wait, *seq_no, downloads);
                         break
                     }
                 }
@@ -128,13 +274,13 @@ pub(crate) async fn start_sync(
 
     async fn new_downloads(
         engine: &Arc<dyn EngineOperations>, 
-        wait: &Arc<Wait<(u32, Result<Option<Vec<u8>>>)>>, 
+        wait: &Arc<Wait<(u32, Result<Option<PathBuf>>)>>,
         queue: &mut Vec<(u32, ArchiveStatus)>,
-        active_peers: &Arc<lockfree::set::Set<Arc<KeyId>>>,
+        downloads: &Arc<SyncDownloads>,
         mut sync_mc_seq_no: u32,
         concurrency: usize
     ) -> Result<()> {
-        force_redownload(engine, wait, queue, active_peers).await?;
+        force_redownload(engine, wait, queue, downloads).await?;
         while wait.count() < concurrency {
             if queue.iter().count() > concurrency {
                 // Do not download too much in advance due to possible OOM
@@ -142,7 +288,7 @@ pub(crate) async fn start_sync(
             } 
             if queue.iter().position(|(seq_no, _)| seq_no == &sync_mc_seq_no).is_none() {
                 queue.push((sync_mc_seq_no, ArchiveStatus::Downloading));
-                download(engine, wait, sync_mc_seq_no, active_peers);
+                download(engine, wait, sync_mc_seq_no, downloads);
             }
             sync_mc_seq_no += ARCHIVE_PACKAGE_SIZE;
         }
@@ -152,16 +298,26 @@ pub(crate) async fn start_sync(
     enum ArchiveStatus {
         Downloading,
         NotFound,
-        Downloaded(Vec<u8>)
+        Downloaded(PathBuf)
     }
 
-    const MAX_CONCURRENCY: usize = 8;
-
     log::info!(target: TARGET, "Started sync");
-    let active_peers = Arc::new(lockfree::set::Set::new());
+    // K archives are downloaded at once from distinct peers
+    let max_concurrency = engine.archives_sync_config().download_window as usize;
+    let staging_dir = Path::new(engine.db_root_dir()?).join(STAGING_DIR_NAME);
+    let cursor = load_sync_cursor(&engine)?;
+    let staged = prepare_staging(&staging_dir, cursor.as_ref(), max_concurrency)?;
+    let downloads = Arc::new(SyncDownloads::new(staging_dir.clone()));
     let mut queue: Vec<(u32, ArchiveStatus)> = Vec::new();
     let (wait, mut reader) = Wait::new();
     let mut concurrency = 1;
+    if !staged.is_empty() {
+        log::info!(target: TARGET, "{} packages downloaded before restart are staged", staged.len());
+        concurrency = max_concurrency;
+        for (seq_no, path) in staged {
+            queue.push((seq_no, ArchiveStatus::Downloaded(path)));
+        }
+    }
 
     'check: while !engine.check_sync().await? {
 
@@ -173,33 +329,13 @@ pub(crate) async fn start_sync(
         }
 
         // Select sync block ID
-        let mc_block_id = if let Some(id) = engine.load_last_applied_mc_block_id()? {
-            id
-        } else {
-            fail!("INTERNAL ERROR: No last applied MC block in sync")
-        };
-        let sc_block_id = if let Some(id) = engine.load_shard_client_mc_block_id()? {
-            id
-        } else {
-            fail!("INTERNAL ERROR: No shard client MC block in sync")
-        };
-        let last_mc_block_id = if mc_block_id.seq_no() > sc_block_id.seq_no() {
-            Arc::clone(&sc_block_id)
-        } else {
-            Arc::clone(&mc_block_id)
-        };
-
-        log::info!(
-            target: TARGET,
-            "Last MC seq_no for sync = {} (MC = {}, SC = {})",
-            last_mc_block_id.seq_no(), mc_block_id, sc_block_id,
-        );
+        let last_mc_block_id = last_synced_mc_block_id(&engine)?;
 
         // Try to find proper # in queue
         let sync_mc_seq_no = last_mc_block_id.seq_no() + 1;
         loop {
             new_downloads(
-                &engine, &wait, &mut queue, &active_peers, sync_mc_seq_no, concurrency
+                &engine, &wait, &mut queue, &downloads, sync_mc_seq_no, concurrency
             ).await?;
             if let Some(index) = queue.iter().position(
                 |(seq_no, status)| match status {
@@ -207,8 +343,8 @@ pub(crate) async fn start_sync(
                     _ => false
                 }
             ) {
-                if let (seq_no, ArchiveStatus::Downloaded(data)) = queue.remove(index) {
-                    match apply(&engine, seq_no, &last_mc_block_id, &data).await {
+                if let (seq_no, ArchiveStatus::Downloaded(path)) = queue.remove(index) {
+                    match apply(&engine, seq_no, &last_mc_block_id, &path).await {
                         Ok(true) => continue 'check,
                         Ok(false) => (),
                         Err(e) => log::error!(
@@ -230,7 +366,7 @@ pub(crate) async fn start_sync(
         // Otherwise download
         while !engine.check_sync().await? {
             new_downloads(
-                &engine, &wait, &mut queue, &active_peers, sync_mc_seq_no, concurrency
+                &engine, &wait, &mut queue, &downloads, sync_mc_seq_no, concurrency
             ).await?;
 /*
             while wait.count() < concurrency {
@@ -245,23 +381,30 @@ pub(crate) async fn start_sync(
                 sync_mc_seq_no += ARCHIVE_PACKAGE_SIZE;
             }
 */
-            match wait.wait(&mut reader, false).await {
+            let received = wait.wait(&mut reader, false).await;
+            STATSD.gauge("sync_archives_window", wait.count() as f64);
+            match received {
                 Some(Some((seq_no, Err(e)))) => {
                     log::error!(
                         target: TARGET,
                         "Error while downloading package seq_no {}: {}",
                         seq_no, e
                     );
-                    download(&engine, &wait, seq_no, &active_peers)
+                    // Read-only or broken DB is not fixed by downloading once more
+                    if !is_retriable(&e) {
+                        return Err(e)
+                    }
+                    // Peer of failed or slow download is held, so another one is picked
+                    download(&engine, &wait, seq_no, &downloads)
                 },
-                Some(Some((seq_no_recv, Ok(data)))) => {
+                Some(Some((seq_no_recv, Ok(path)))) => {
                     if let Some(index) = queue.iter().position(
                         |(seq_no_send, status)| match status {
                             ArchiveStatus::Downloading => seq_no_send == &seq_no_recv,
                             _ => false
                         }
                     ) {
-                        if let Some(data) = data {
+                        if let Some(path) = path {
 /*
                             // Redownload all previously not found archives
                             for (seq_no, status) in queue.iter_mut() {
@@ -274,11 +417,11 @@ pub(crate) async fn start_sync(
                             }
 */
                             if seq_no_recv <= last_mc_block_id.seq_no() + 1 {
-                                match apply(&engine, seq_no_recv, &last_mc_block_id, &data).await {
+                                match apply(&engine, seq_no_recv, &last_mc_block_id, &path).await {
                                     Ok(ok) => {
                                         queue.remove(index);
                                         if ok {
-                                            concurrency = MAX_CONCURRENCY;
+                                            concurrency = max_concurrency;
                                             break
                                         }
                                     },
@@ -288,18 +431,21 @@ pub(crate) async fn start_sync(
                                             "Cannot apply downloaded package for MC seq_no = {}: {}",
                                             seq_no_recv, e
                                         );
-                                        download(&engine, &wait, seq_no_recv, &active_peers)
+                                        if !is_retriable(&e) {
+                                            return Err(e)
+                                        }
+                                        download(&engine, &wait, seq_no_recv, &downloads)
                                     }
                                 }
                             } else {
                                 let (_, status) = &mut queue[index];
-                                *status = ArchiveStatus::Downloaded(data);
-                                force_redownload(&engine, &wait, &mut queue, &active_peers).await?;
+                                *status = ArchiveStatus::Downloaded(path);
+                                force_redownload(&engine, &wait, &mut queue, &downloads).await?;
                             }
                         } else {
                             let (_, status) = &mut queue[index];
                             *status = ArchiveStatus::NotFound;
-                            force_redownload(&engine, &wait, &mut queue, &active_peers).await?;
+                            force_redownload(&engine, &wait, &mut queue, &downloads).await?;
 /*                            if queue.iter().position(
                                 |(seq_no, status)| match status {
                                     ArchiveStatus::Downloaded(_) => seq_no > &seq_no_recv,
@@ -325,11 +471,125 @@ pub(crate) async fn start_sync(
 
     }
 
+    engine.clear_sync_cursor()?;
+    if let Err(e) = std::fs::remove_dir_all(&staging_dir) {
+        log::warn!(target: TARGET, "Can't remove sync staging dir {}: {}", staging_dir.display(), e);
+    }
     log::info!(target: TARGET, "Sync complete");
     Ok(())
 
 }
 
+// Both masterchain and shard blocks are applied up to this block
+fn last_synced_mc_block_id(engine: &Arc<dyn EngineOperations>) -> Result<Arc<BlockIdExt>> {
+    let mc_block_id = if let Some(id) = engine.load_last_applied_mc_block_id()? {
+        id
+    } else {
+        fail!("INTERNAL ERROR: No last applied MC block in sync")
+    };
+    let sc_block_id = if let Some(id) = engine.load_shard_client_mc_block_id()? {
+        id
+    } else {
+        fail!("INTERNAL ERROR: No shard client MC block in sync")
+    };
+    let last_mc_block_id = if mc_block_id.seq_no() > sc_block_id.seq_no() {
+        Arc::clone(&sc_block_id)
+    } else {
+        Arc::clone(&mc_block_id)
+    };
+    log::info!(
+        target: TARGET,
+        "Last MC seq_no for sync = {} (MC = {}, SC = {})",
+        last_mc_block_id.seq_no(), mc_block_id, sc_block_id,
+    );
+    Ok(last_mc_block_id)
+}
+
+#[derive(Debug, Default)]
+pub struct ArchivesImportReport {
+    pub imported: Vec<String>,
+    pub already_applied: Vec<String>,
+    // File name and reason
+    pub skipped: Vec<(String, String)>,
+    pub last_mc_seq_no: u32,
+}
+
+// Imports archive packages (*.pack) found in directory as sync does with downloaded ones:
+// proofs are checked and blocks are applied in order of masterchain seq_no. Malformed
+// packages and ones not continuing the applied chain are reported and skipped.
+pub(crate) async fn import_archives(
+    engine: Arc<dyn EngineOperations>,
+    dir: &str
+) -> Result<ArchivesImportReport> {
+    let mut report = ArchivesImportReport::default();
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(|e| error!("Can't read archives dir {}: {}", dir, e))? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().map(|ext| ext == "pack").unwrap_or(false) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    log::info!(target: TARGET, "Found {} archive packages in {}", files.len(), dir);
+
+    // Packages are only scanned first not to keep all of them in memory
+    let mut packages = Vec::new();
+    for path in files {
+        let name = path.display().to_string();
+        match read_package_file(&path).await {
+            Ok(maps) => match (maps.mc_blocks_ids.keys().next(), maps.mc_blocks_ids.keys().last()) {
+                (Some(first), Some(last)) => packages.push((*first, *last, path)),
+                _ => skip_package(&mut report, name, "no masterchain blocks".to_string())
+            },
+            Err(e) => skip_package(&mut report, name, format!("malformed package: {}", e))
+        }
+    }
+    packages.sort_by_key(|(first, last, _)| (*first, *last));
+
+    for (first, last, path) in packages {
+        let name = path.display().to_string();
+        let last_mc_block_id = last_synced_mc_block_id(&engine)?;
+        if last <= last_mc_block_id.seq_no() {
+            log::info!(
+                target: TARGET,
+                "Archive package {} with MC blocks {}..{} is already applied", name, first, last
+            );
+            report.already_applied.push(name);
+            continue
+        }
+        if first > last_mc_block_id.seq_no() + 1 {
+            let reason = format!(
+                "out of order: MC blocks {}..{}, but next one to apply is {}",
+                first, last, last_mc_block_id.seq_no() + 1
+            );
+            skip_package(&mut report, name, reason);
+            continue
+        }
+        log::info!(target: TARGET, "Importing archive package {} with MC blocks {}..{}", name, first, last);
+        // Blocks applied before failure stay applied, the rest are taken from next packages
+        let result = match read_package_file(&path).await {
+            Ok(maps) => import_package(Arc::new(maps), &engine, &last_mc_block_id, first).await,
+            Err(e) => Err(e)
+        };
+        match result {
+            Ok(()) => report.imported.push(name),
+            Err(e) => skip_package(&mut report, name, format!("import failed: {}", e))
+        }
+    }
+    report.last_mc_seq_no = last_synced_mc_block_id(&engine)?.seq_no();
+    Ok(report)
+}
+
+async fn read_package_file(path: &std::path::Path) -> Result<BlockMaps> {
+    let data = tokio::fs::read(path).await?;
+    read_package(&data).await
+}
+
+fn skip_package(report: &mut ArchivesImportReport, name: String, reason: String) {
+    log::error!(target: TARGET, "Archive package {} is skipped: {}", name, reason);
+    report.skipped.push((name, reason));
+}
+
 async fn download_archive(
     engine: Arc<dyn EngineOperations>, 
     mc_seq_no: u32,
@@ -399,15 +659,58 @@ async fn import_package(
     maps: Arc<BlockMaps>,
     engine: &Arc<dyn EngineOperations>,
     last_mc_block_id: &Arc<BlockIdExt>,
+    archive_seq_no: u32
 ) -> Result<()> {
     if maps.mc_blocks_ids.keys().next().is_none() {
         fail!("Archive doesn't contain any masterchain blocks!");
     }
 
-    import_mc_blocks(engine, &maps, last_mc_block_id).await?;
-    import_shard_blocks(engine, maps).await?;
+    // Shard blocks of masterchain block are applied as soon as it is applied,
+    // concurrently with next masterchain blocks
+    let (mc_applied_sender, mc_applied) = tokio::sync::watch::channel(last_mc_block_id.seq_no());
+    let (mc_result, shards_result) = futures::join!(
+        import_mc_blocks(engine, &maps, last_mc_block_id, mc_applied_sender),
+        import_shard_blocks(engine, Arc::clone(&maps), archive_seq_no, mc_applied)
+    );
+    mc_result?;
+    shards_result
+}
 
-    Ok(())
+// Stores blocks of `shards` from archive package without applying them: shard client then
+// applies them from DB instead of downloading one by one. Returns number of stored blocks.
+pub(crate) async fn store_shard_blocks(
+    engine: &Arc<dyn EngineOperations>,
+    data: &Vec<u8>,
+    shards: &[ShardIdent]
+) -> Result<usize> {
+    let maps = Arc::new(read_package(data).await?);
+    let ids = maps.blocks.keys()
+        .filter(|id| {
+            !id.is_masterchain() && shards.iter().any(|shard| shard.intersect_with(id.shard()))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let depth = engine.archives_sync_config().apply_pipeline_depth;
+    let mut checked = {
+        let engine = Arc::clone(engine);
+        let maps = Arc::clone(&maps);
+        precheck_pipeline(ids, depth, move |id| precheck_block(
+            Arc::clone(&engine), Arc::clone(&maps), id, 0
+        ))
+    };
+    let mut stored = 0;
+    while let Some(id) = checked.next().await {
+        let id = id?;
+        if let Some(handle) = engine.load_block_handle(&id)? {
+            if handle.has_data() && handle.has_proof_link() {
+                continue
+            }
+        }
+        let entry = maps.blocks.get(&id).expect("Inconsistent BlocksMap");
+        save_block(engine, &id, entry).await?;
+        stored += 1;
+    }
+    Ok(stored)
 }
 
 async fn read_package(data: &Vec<u8>) -> Result<BlockMaps> {
@@ -479,12 +782,10 @@ async fn read_package(data: &Vec<u8>) -> Result<BlockMaps> {
     Ok(maps)
 }
 
-async fn save_block(
-    engine: &Arc<dyn EngineOperations>,
+fn block_and_proof(
     block_id: &BlockIdExt,
     entry: &BlocksEntry
-) -> Result<(Arc<BlockHandle>, Arc<BlockStuff>, Arc<BlockProofStuff>)> {
-    log::trace!(target: "sync", "save_block: id = {}", block_id);
+) -> Result<(Arc<BlockStuff>, Arc<BlockProofStuff>)> {
     let block = if let Some(ref block) = entry.block {
         Arc::clone(block)
     } else {
@@ -500,7 +801,70 @@ async fn save_block(
         };
         fail!("Proof{} not found in archive: {}", link_str, block_id);
     };
+    Ok((block, proof))
+}
+
+// Checks proof of block before it is stored. Previous key block of masterchain block may be
+// in the same package and not applied yet, then its proof is taken from the package: it is
+// checked by its own task, and the block is not stored if the check fails.
+async fn precheck_block(
+    engine: Arc<dyn EngineOperations>,
+    maps: Arc<BlockMaps>,
+    block_id: Arc<BlockIdExt>,
+    last_mc_seq_no: u32
+) -> Result<Arc<BlockIdExt>> {
+    let entry = maps.blocks.get(&block_id).expect("Inconsistent BlocksMap");
+    let (block, proof) = block_and_proof(&block_id, entry)?;
+    if engine.is_hardfork(&block_id) {
+        proof.check_hardfork_proof()?;
+        return Ok(block_id)
+    }
+    if block_id.is_masterchain() {
+        let prev_key_block_seqno = block.block().read_info()?.prev_key_block_seqno();
+        if prev_key_block_seqno > last_mc_seq_no {
+            let prev_key_block_proof = maps.mc_blocks_ids.get(&prev_key_block_seqno)
+                .and_then(|id| maps.blocks.get(id))
+                .and_then(|entry| entry.proof.clone())
+                .ok_or_else(|| error!(
+                    "Previous key block {} of {} is neither applied nor in archive",
+                    prev_key_block_seqno, block_id
+                ))?;
+            proof.check_with_prev_key_block_proof(&prev_key_block_proof)?;
+            return Ok(block_id)
+        }
+    }
     proof.check_proof(engine.as_ref()).await?;
+    Ok(block_id)
+}
+
+// Runs `check` of up to `depth` items ahead in background tasks, checked items are yielded
+// in order. Next checks are started only when checked items are taken, so the pipeline is
+// bounded by the consumer.
+fn precheck_pipeline<T, R, F>(
+    items: Vec<T>,
+    depth: usize,
+    check: impl Fn(T) -> F
+) -> impl Stream<Item = Result<R>> + Unpin
+where
+    F: Future<Output = Result<R>> + Send + 'static,
+    R: Send + 'static
+{
+    Box::pin(
+        futures::stream::iter(items)
+            .map(move |item| tokio::spawn(check(item)))
+            .buffered(depth)
+            .map(|result| result?)
+    )
+}
+
+// Proof must be checked by `precheck_block` before
+async fn save_block(
+    engine: &Arc<dyn EngineOperations>,
+    block_id: &BlockIdExt,
+    entry: &BlocksEntry
+) -> Result<(Arc<BlockHandle>, Arc<BlockStuff>, Arc<BlockProofStuff>)> {
+    log::trace!(target: "sync", "save_block: id = {}", block_id);
+    let (block, proof) = block_and_proof(block_id, entry)?;
     let handle = engine.store_block(&block).await?.as_non_created().ok_or_else(
         || error!("INTERNAL ERROR: mismatch in block {} store result during sync", block_id)
     )?;
@@ -536,10 +900,13 @@ async fn wait_for(tasks: Vec<JoinHandle<Result<()>>>) -> Result<()> {
 
 async fn import_mc_blocks(
     engine: &Arc<dyn EngineOperations>,
-    maps: &BlockMaps,
-    mut last_mc_block_id: &Arc<BlockIdExt>
+    maps: &Arc<BlockMaps>,
+    mut last_mc_block_id: &Arc<BlockIdExt>,
+    mc_applied: tokio::sync::watch::Sender<u32>
 ) -> Result<()> {
 
+    let last_applied_seq_no = last_mc_block_id.seq_no();
+    let mut ids = Vec::new();
     for id in maps.mc_blocks_ids.values() {
 
         if id.seq_no() <= last_mc_block_id.seq_no() {
@@ -558,8 +925,9 @@ async fn import_mc_blocks(
                 id.seq_no()
             );
         }
+        // Package from peer which didn't switch to hardfork is refused
+        boot::check_block_hardforks(engine.get_hardforks(), id, last_mc_block_id)?;
 
-        log::debug!(target: "sync", "Importing MC block: {}", id);
         last_mc_block_id = id;
         if let Some(handle) = engine.load_block_handle(&last_mc_block_id)? {
             if handle.is_applied() {
@@ -568,17 +936,32 @@ async fn import_mc_blocks(
                     "Skipped already applied MC block: {}", 
                     last_mc_block_id
                 );
+                mc_applied.send(id.seq_no()).ok();
                 continue
             }
         } 
+        ids.push(Arc::clone(id));
 
-        let entry = maps.blocks.get(last_mc_block_id).expect("Inconsistent BlocksMap");
-        let (handle, block, _proof) = save_block(engine, &last_mc_block_id, entry).await?;
-        log::debug!(target: "sync", "Applying masterchain block: {}...", last_mc_block_id);
-        Arc::clone(engine).apply_block(
-            &handle, &block, last_mc_block_id.seq_no(), false
-        ).await?;
+    }
 
+    // Proofs of next blocks are checked while the current one is applied
+    let depth = engine.archives_sync_config().apply_pipeline_depth;
+    let mut checked = {
+        let engine = Arc::clone(engine);
+        let maps = Arc::clone(maps);
+        precheck_pipeline(ids, depth, move |id| precheck_block(
+            Arc::clone(&engine), Arc::clone(&maps), id, last_applied_seq_no
+        ))
+    };
+    while let Some(id) = checked.next().await {
+        let id = id?;
+        log::debug!(target: "sync", "Importing MC block: {}", id);
+        let entry = maps.blocks.get(&id).expect("Inconsistent BlocksMap");
+        let (handle, block, _proof) = save_block(engine, &id, entry).await?;
+        log::debug!(target: "sync", "Applying masterchain block: {}...", id);
+        Arc::clone(engine).apply_block(&handle, &block, id.seq_no(), false).await?;
+        engine.update_sync_progress(id.seq_no(), block.gen_utime()?);
+        mc_applied.send(id.seq_no()).ok();
     }
  
     log::debug!(target: TARGET, "Last applied MC seq_no = {}", last_mc_block_id.seq_no());
@@ -589,12 +972,26 @@ async fn import_mc_blocks(
 async fn import_shard_blocks(
     engine: &Arc<dyn EngineOperations>,
     maps: Arc<BlockMaps>,
+    archive_seq_no: u32,
+    mut mc_applied: tokio::sync::watch::Receiver<u32>
 ) -> Result<()> {
 
-    for (id, entry) in maps.blocks.iter() {
-        if !id.is_masterchain() {
-            save_block(engine, id, entry).await?;
-        }
+    let ids = maps.blocks.keys()
+        .filter(|id| !id.is_masterchain())
+        .cloned()
+        .collect::<Vec<_>>();
+    let depth = engine.archives_sync_config().apply_pipeline_depth;
+    let mut checked = {
+        let engine = Arc::clone(engine);
+        let maps = Arc::clone(&maps);
+        precheck_pipeline(ids, depth, move |id| precheck_block(
+            Arc::clone(&engine), Arc::clone(&maps), id, 0
+        ))
+    };
+    while let Some(id) = checked.next().await {
+        let id = id?;
+        let entry = maps.blocks.get(&id).expect("Inconsistent BlocksMap");
+        save_block(engine, &id, entry).await?;
     }
 
     let mut shard_client_mc_block_id = 
@@ -614,6 +1011,13 @@ async fn import_shard_blocks(
             continue;
         }
 
+        // Shard blocks need only their masterchain block to be applied
+        while *mc_applied.borrow() < mc_seq_no {
+            mc_applied.changed().await.map_err(
+                |_| error!("Masterchain blocks import is stopped before {}", mc_block_id)
+            )?;
+        }
+
         log::debug!(target: "sync", "Importing shardchain blocks for MC block: {}...", mc_block_id);
 
         let mc_handle = engine.load_block_handle(&mc_block_id)?.ok_or_else(
@@ -621,7 +1025,9 @@ async fn import_shard_blocks(
         )?;
         let mc_block = engine.load_block(&mc_handle).await?;
 
-        let shard_blocks = mc_block.shards_blocks()?;
+        let mut shard_blocks = mc_block.shards_blocks()?;
+        shard_blocks.retain(|shard, _| engine.is_shard_tracked(shard));
+        let shard_block_ids = shard_blocks.iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
         let mut tasks = Vec::with_capacity(shard_blocks.len());
         for (_shard, id) in shard_blocks {
             let engine = Arc::clone(engine);
@@ -682,8 +1088,354 @@ async fn import_shard_blocks(
 
         shard_client_mc_block_id = Arc::clone(mc_block_id);
         engine.save_shard_client_mc_block_id(&mc_block_id)?;
+        engine.save_sync_cursor(&SyncCursor {
+            mc_block_id: (**mc_block_id).clone(),
+            shard_block_ids,
+            archive_seq_no,
+        })?;
     }
 
     Ok(())
 }
 
+
+// Averaging window of sync throughput. It is widened when throughput collapses, e.g. while
+// a big state is downloaded, so the collapse doesn't make ETA absurd at once.
+const SYNC_RATE_WINDOW_MIN_SEC: f64 = 60.0;
+const SYNC_RATE_WINDOW_MAX_SEC: f64 = 3600.0;
+// Throughput below this share of the average is a collapse
+const SYNC_RATE_COLLAPSE_RATIO: f64 = 0.1;
+// Seqno gap is estimated by time gap with this interval until the real one is measured
+const DEFAULT_MC_BLOCK_INTERVAL_SEC: f64 = 5.0;
+const SYNC_PROGRESS_LOG_INTERVAL_SEC: u64 = 60;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncEstimate {
+    pub mc_seq_no: u32,
+    pub mc_seq_no_remaining: u32,
+    pub blocks_per_sec: f64,
+    // None until throughput is measured
+    pub eta_secs: Option<u64>,
+    pub window_secs: f64,
+}
+
+struct SyncProgressState {
+    // Time, seq_no and gen_utime of the first and the last applied masterchain blocks
+    first: Option<(Instant, u32, u32)>,
+    last: Option<(Instant, u32, u32)>,
+    estimate: SyncEstimate,
+    logged_at: Option<Instant>,
+}
+
+// Moving average of masterchain blocks application throughput and ETA of sync.
+// Remaining gap is the larger of the one to the last known masterchain block and
+// the one estimated by the time gap between now and the last applied block.
+pub struct SyncProgress {
+    state: Mutex<SyncProgressState>,
+}
+
+impl Default for SyncProgress {
+    fn default() -> Self {
+        Self {
+            state: Mutex::new(SyncProgressState {
+                first: None,
+                last: None,
+                estimate: SyncEstimate { window_secs: SYNC_RATE_WINDOW_MIN_SEC, ..Default::default() },
+                logged_at: None,
+            })
+        }
+    }
+}
+
+impl SyncProgress {
+
+    pub fn update(&self, seq_no: u32, gen_utime: u32, last_known_seq_no: u32, now_unix: u32) {
+        let now = Instant::now();
+        let estimate = self.update_at(seq_no, gen_utime, last_known_seq_no, now_unix, now);
+        STATSD.gauge("sync_mc_seqno_remaining", estimate.mc_seq_no_remaining as f64);
+        STATSD.gauge("sync_blocks_per_sec", estimate.blocks_per_sec);
+        if let Some(eta) = estimate.eta_secs {
+            STATSD.gauge("sync_eta_secs", eta as f64);
+        }
+        let mut state = self.state.lock().unwrap();
+        let log = state.logged_at
+            .map(|at| now.saturating_duration_since(at).as_secs() >= SYNC_PROGRESS_LOG_INTERVAL_SEC)
+            .unwrap_or(true);
+        if log {
+            state.logged_at = Some(now);
+            log::info!(target: TARGET, "Sync progress: {}", Self::format(&estimate));
+        }
+    }
+
+    pub fn estimate(&self) -> Option<SyncEstimate> {
+        let state = self.state.lock().unwrap();
+        state.last.map(|_| state.estimate.clone())
+    }
+
+    pub fn status(&self) -> String {
+        match self.estimate() {
+            Some(estimate) => Self::format(&estimate),
+            None => "no masterchain blocks applied by sync".to_string()
+        }
+    }
+
+    fn format(estimate: &SyncEstimate) -> String {
+        let eta = match estimate.eta_secs {
+            Some(eta) => format!("{} sec", eta),
+            None => "unknown".to_string()
+        };
+        format!(
+            "MC seq_no {}, {} remaining, {:.2} blocks/sec (averaged over {} sec), ETA {}",
+            estimate.mc_seq_no, estimate.mc_seq_no_remaining, estimate.blocks_per_sec,
+            estimate.window_secs as u64, eta
+        )
+    }
+
+    fn update_at(
+        &self,
+        seq_no: u32,
+        gen_utime: u32,
+        last_known_seq_no: u32,
+        now_unix: u32,
+        now: Instant
+    ) -> SyncEstimate {
+        let mut state = self.state.lock().unwrap();
+        match state.last {
+            Some((_, last_seq_no, _)) if seq_no <= last_seq_no => return state.estimate.clone(),
+            Some((time, last_seq_no, _)) => {
+                let elapsed = now.saturating_duration_since(time).as_secs_f64();
+                if elapsed > 0.0 {
+                    let current = (seq_no - last_seq_no) as f64 / elapsed;
+                    let estimate = &mut state.estimate;
+                    if estimate.blocks_per_sec == 0.0 {
+                        estimate.blocks_per_sec = current;
+                    } else {
+                        if current < estimate.blocks_per_sec * SYNC_RATE_COLLAPSE_RATIO {
+                            estimate.window_secs = (estimate.window_secs * 2.0)
+                                .max(elapsed * 2.0)
+                                .min(SYNC_RATE_WINDOW_MAX_SEC);
+                        } else {
+                            estimate.window_secs = (estimate.window_secs / 2.0)
+                                .max(SYNC_RATE_WINDOW_MIN_SEC);
+                        }
+                        let alpha = 1.0 - (-elapsed / estimate.window_secs).exp();
+                        estimate.blocks_per_sec += alpha * (current - estimate.blocks_per_sec);
+                    }
+                }
+            },
+            None => state.first = Some((now, seq_no, gen_utime))
+        }
+        state.last = Some((now, seq_no, gen_utime));
+        let interval = match state.first {
+            Some((_, first_seq_no, first_utime)) if seq_no > first_seq_no && gen_utime > first_utime => {
+                (gen_utime - first_utime) as f64 / (seq_no - first_seq_no) as f64
+            },
+            _ => DEFAULT_MC_BLOCK_INTERVAL_SEC
+        };
+        let by_time = (now_unix.saturating_sub(gen_utime) as f64 / interval) as u32;
+        let estimate = &mut state.estimate;
+        estimate.mc_seq_no = seq_no;
+        estimate.mc_seq_no_remaining = last_known_seq_no.saturating_sub(seq_no).max(by_time);
+        estimate.eta_secs = if estimate.blocks_per_sec > 0.0 {
+            Some((estimate.mc_seq_no_remaining as f64 / estimate.blocks_per_sec) as u64)
+        } else {
+            None
+        };
+        estimate.clone()
+    }
+}
+
+#[cfg(test)]
+fn test_block_id(workchain_id: i32, seq_no: u32) -> BlockIdExt {
+    use ton_block::ShardIdent;
+    use ton_types::UInt256;
+    let shard = if workchain_id == -1 {
+        ShardIdent::masterchain()
+    } else {
+        ShardIdent::with_tagged_prefix(workchain_id, 0x8000_0000_0000_0000).unwrap()
+    };
+    BlockIdExt::with_params(
+        shard, seq_no, UInt256::from_slice(&[seq_no as u8; 32]), UInt256::from_slice(&[1; 32])
+    )
+}
+
+#[test]
+fn test_check_sync_cursor() {
+    let cursor = SyncCursor {
+        mc_block_id: test_block_id(-1, 10),
+        shard_block_ids: vec!(test_block_id(0, 20)),
+        archive_seq_no: 1,
+    };
+    let applied = vec!(test_block_id(-1, 10), test_block_id(0, 20));
+    let is_applied = |id: &BlockIdExt| Ok(applied.contains(id));
+    check_sync_cursor(&cursor, &test_block_id(-1, 10), is_applied).unwrap();
+    check_sync_cursor(&cursor, &test_block_id(-1, 15), is_applied).unwrap();
+    // Behind or forked applied chain
+    assert!(check_sync_cursor(&cursor, &test_block_id(-1, 9), is_applied).is_err());
+    let forked = BlockIdExt::with_params(
+        ton_block::ShardIdent::masterchain(), 10, ton_types::UInt256::from_slice(&[2; 32]),
+        ton_types::UInt256::from_slice(&[1; 32])
+    );
+    assert!(check_sync_cursor(&cursor, &forked, is_applied).is_err());
+    // Shard block is not applied
+    let applied = vec!(test_block_id(-1, 10));
+    assert!(check_sync_cursor(&cursor, &test_block_id(-1, 10), |id| Ok(applied.contains(id))).is_err());
+}
+
+#[test]
+fn test_staged_package_after_restart() {
+    let staging_dir = std::env::temp_dir().join(format!("sync_staging_{}", std::process::id()));
+    std::fs::remove_dir_all(&staging_dir).ok();
+    std::fs::create_dir_all(&staging_dir).unwrap();
+
+    // Packages are downloaded, node is stopped before they are applied,
+    // the last one is interrupted while being written
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let downloads = SyncDownloads::new(staging_dir.clone());
+    rt.block_on(downloads.stage(1, vec!(1; 100))).unwrap();
+    rt.block_on(downloads.stage(101, vec!(2; 100))).unwrap();
+    rt.block_on(downloads.stage(201, vec!(3; 100))).unwrap();
+    std::fs::write(downloads.package_path(301).with_extension("pack.tmp"), vec!(4; 50)).unwrap();
+    drop(downloads);
+
+    // Package of the cursor and next ones are applied after restart
+    let cursor = SyncCursor {
+        mc_block_id: test_block_id(-1, 150),
+        shard_block_ids: Vec::new(),
+        archive_seq_no: 101,
+    };
+    let staged = prepare_staging(&staging_dir, Some(&cursor), 8).unwrap();
+    assert_eq!(staged.iter().map(|(seq_no, _)| *seq_no).collect::<Vec<_>>(), vec!(101, 201));
+    assert_eq!(std::fs::read(&staged[0].1).unwrap(), vec!(2; 100));
+    assert_eq!(std::fs::read_dir(&staging_dir).unwrap().count(), 2);
+
+    // Not more than window
+    let staged = prepare_staging(&staging_dir, Some(&cursor), 1).unwrap();
+    assert_eq!(staged.iter().map(|(seq_no, _)| *seq_no).collect::<Vec<_>>(), vec!(101));
+
+    // Without cursor nothing is trusted
+    assert!(prepare_staging(&staging_dir, None, 8).unwrap().is_empty());
+    assert_eq!(std::fs::read_dir(&staging_dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(&staging_dir).ok();
+}
+
+#[test]
+fn test_staged_package_kept_until_imported() {
+    let staging_dir = std::env::temp_dir().join(format!("sync_import_{}", std::process::id()));
+    std::fs::remove_dir_all(&staging_dir).ok();
+    std::fs::create_dir_all(&staging_dir).unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let downloads = SyncDownloads::new(staging_dir.clone());
+    // Empty package: just the header
+    let path = rt.block_on(downloads.stage(1, 0xAE8F_DD01u32.to_le_bytes().to_vec())).unwrap();
+
+    // Failed import keeps the package
+    let result = rt.block_on(import_staged_package(&path, |maps| async move {
+        assert!(maps.blocks.is_empty());
+        fail!("import failed")
+    }));
+    assert!(result.is_err());
+    assert!(path.exists());
+
+    // Package with broken data is kept too
+    let broken = rt.block_on(downloads.stage(101, vec!(1; 100))).unwrap();
+    assert!(rt.block_on(import_staged_package(&broken, |_| async { Ok(()) })).is_err());
+    assert!(broken.exists());
+
+    // Imported one is removed
+    rt.block_on(import_staged_package(&path, |_| async { Ok(()) })).unwrap();
+    assert!(!path.exists());
+    std::fs::remove_dir_all(&staging_dir).ok();
+}
+
+#[test]
+fn test_precheck_pipeline() {
+    // Synthetic blocks: check and apply of a block take the same time.
+    // Clock is paused, so it is advanced by sleeps only.
+    const BLOCKS: u32 = 20;
+    let step = std::time::Duration::from_millis(20);
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    let import = |depth: usize| rt.block_on(async move {
+        let started_checks = Arc::new(AtomicU64::new(0));
+        let started = tokio::time::Instant::now();
+        let mut checked = {
+            let started_checks = started_checks.clone();
+            precheck_pipeline((1..=BLOCKS).collect(), depth, move |seq_no| {
+                started_checks.fetch_add(1, Ordering::Relaxed);
+                async move {
+                    tokio::time::sleep(step).await;
+                    Ok(seq_no)
+                }
+            })
+        };
+        let mut applied = Vec::new();
+        while let Some(seq_no) = checked.next().await {
+            // Checks don't run too far ahead of application
+            let ahead = started_checks.load(Ordering::Relaxed) - applied.len() as u64;
+            assert!(ahead <= depth as u64 + 1);
+            tokio::time::sleep(step).await;
+            applied.push(seq_no.unwrap());
+        }
+        // Blocks are applied strictly in order
+        assert_eq!(applied, (1..=BLOCKS).collect::<Vec<_>>());
+        started.elapsed()
+    });
+    let sequential = import(1);
+    let pipelined = import(8);
+    // Sequential import waits for each check, pipelined one is bound by application only
+    assert!(sequential >= step * 2 * BLOCKS);
+    assert!(pipelined <= step * (BLOCKS + 2));
+
+    // Failed check stops the pipeline
+    let results = rt.block_on(async {
+        let checked = precheck_pipeline(vec!(1, 2, 3), 2, |seq_no: u32| async move {
+            if seq_no == 2 {
+                fail!("bad proof")
+            }
+            Ok(seq_no)
+        });
+        checked.collect::<Vec<_>>().await
+    });
+    assert!(results[0].is_ok() && results[1].is_err());
+}
+
+#[test]
+fn test_sync_progress() {
+    use std::time::Duration;
+    let progress = SyncProgress::default();
+    let start = Instant::now();
+    assert!(progress.estimate().is_none());
+    // 10 blocks/sec, masterchain block every 5 sec, network is at seq_no 2000 by time
+    let utime = |seq_no: u32| 1000 + 5 * seq_no;
+    let now_unix = utime(2000);
+    for seq_no in 1..=100 {
+        let now = start + Duration::from_millis(100 * seq_no as u64);
+        progress.update_at(seq_no, utime(seq_no), 0, now_unix, now);
+    }
+    let estimate = progress.estimate().unwrap();
+    assert_eq!(estimate.mc_seq_no_remaining, 1900);
+    assert!((estimate.blocks_per_sec - 10.0).abs() < 1e-6);
+    assert!(matches!(estimate.eta_secs, Some(189..=190)));
+    // Known block is ahead of time estimation
+    let estimate = progress.update_at(100, utime(100), 2500, now_unix, start + Duration::from_secs(11));
+    assert_eq!(estimate.mc_seq_no_remaining, 1900);
+    let estimate = progress.update_at(101, utime(101), 2500, now_unix, start + Duration::from_millis(10100));
+    assert_eq!(estimate.mc_seq_no_remaining, 2399);
+
+    // Long stall widens the window instead of collapsing throughput
+    let stalled = start + Duration::from_millis(10100) + Duration::from_secs(1800);
+    let estimate = progress.update_at(102, utime(102), 0, now_unix, stalled);
+    assert_eq!(estimate.window_secs, SYNC_RATE_WINDOW_MAX_SEC);
+    assert!(estimate.blocks_per_sec > 5.0);
+    assert!(estimate.eta_secs.unwrap() < 400);
+    // Window shrinks back as throughput recovers
+    for i in 1..=10 {
+        let now = stalled + Duration::from_millis(100 * i as u64);
+        progress.update_at(102 + i, utime(102 + i), 0, now_unix, now);
+    }
+    assert_eq!(progress.estimate().unwrap().window_secs, SYNC_RATE_WINDOW_MIN_SEC);
+}
@@ -1,3 +1,4 @@
+use crate::types::storage_stat::{StorageStat, StorageStatTracker};
 use std::sync::{Arc, atomic::AtomicU64};
 use ton_block::{
     Serializable, Deserializable, ShardAccount, ShardAccounts,
@@ -6,6 +7,49 @@ use ton_block::{
 };
 use ton_types::{Result, AccountId, Cell, HashmapRemover, fail, UInt256};
 
+// Per-account limits within collated block. When any of them is exceeded, further messages
+// to the account are left for the next block, so one account can't take the whole block.
+#[derive(Clone, Debug, Default, serde::Deserialize, serde::Serialize)]
+pub struct AccountSoftLimits {
+    pub transactions: Option<u64>,
+    pub gas: Option<u64>,
+    // Total size of transactions in bytes
+    pub size: Option<u64>,
+}
+
+// Consumption of account within collated block
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AccountUsage {
+    transactions: u64,
+    gas: u64,
+    size: u64,
+}
+
+impl AccountUsage {
+    pub fn add_transaction(&mut self, gas: u64, size: u64) {
+        self.transactions += 1;
+        self.gas += gas;
+        self.size += size;
+    }
+    pub fn transactions(&self) -> u64 {
+        self.transactions
+    }
+    pub fn gas(&self) -> u64 {
+        self.gas
+    }
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+    pub fn exceeds(&self, limits: &AccountSoftLimits) -> bool {
+        let exceeds = |value: u64, limit: Option<u64>| {
+            limit.map(|limit| value >= limit).unwrap_or(false)
+        };
+        exceeds(self.transactions(), limits.transactions) ||
+            exceeds(self.gas(), limits.gas) ||
+            exceeds(self.size(), limits.size)
+    }
+}
+
 pub struct ShardAccountStuff {
     account_addr: AccountId,
     account_root: Cell,
@@ -15,6 +59,13 @@ pub struct ShardAccountStuff {
     transactions: Transactions,
     state_update: HashUpdate,
     orig_libs: StateInitLib,
+    usage: AccountUsage,
+    // Statistics only goes to trace log, so it isn't computed otherwise
+    track_storage_stat: bool,
+    // Is started by the first transaction
+    storage_stat: Option<StorageStatTracker>,
+    // Root of account's libraries the public cells were counted for
+    stat_libs: Option<Cell>,
 }
 
 impl ShardAccountStuff {
@@ -37,6 +88,10 @@ impl ShardAccountStuff {
             lt,
             transactions: Transactions::default(),
             state_update: HashUpdate::with_hashes(account_hash.clone(), account_hash),
+            usage: AccountUsage::default(),
+            track_storage_stat: log::log_enabled!(log::Level::Trace),
+            storage_stat: None,
+            stat_libs: None,
         })
     }
     pub fn update_shard_state(&mut self, new_accounts: &mut ShardAccounts) -> Result<AccountBlock> {
@@ -44,6 +99,12 @@ impl ShardAccountStuff {
         if account.is_none() {
             new_accounts.remove(self.account_addr().clone())?;
         } else {
+            if let Some(stat) = self.storage_stat() {
+                log::trace!(
+                    "Account {:x} storage: {} cells, {} bits, {} public cells",
+                    self.account_addr, stat.cells, stat.bits, stat.public_cells
+                );
+            }
             let shard_acc = ShardAccount::with_account_root(self.account_root(), self.last_trans_hash.clone(), self.last_trans_lt);
             let value = shard_acc.write_to_new_cell()?;
             new_accounts.set_builder_serialized(self.account_addr().clone(), &value, &account.aug()?)?;
@@ -53,6 +114,9 @@ impl ShardAccountStuff {
     pub fn lt(&self) -> Arc<AtomicU64> {
         self.lt.clone()
     }
+    pub fn usage(&self) -> AccountUsage {
+        self.usage
+    }
     pub fn read_account(&self) -> Result<Account> {
         Account::construct_from_cell(self.account_root())
     }
@@ -70,8 +134,11 @@ impl ShardAccountStuff {
         transaction.set_prev_trans_lt(self.last_trans_lt);
         // log::trace!("{} {}", self.collated_block_descr, debug_transaction(transaction.clone())?);
 
-        self.account_root = account_root;
+        let old_root = std::mem::replace(&mut self.account_root, account_root);
         self.state_update.new_hash = self.account_root.repr_hash();
+        if self.track_storage_stat {
+            self.update_storage_stat(&old_root, transaction)?;
+        }
 
         let tr_root = transaction.serialize()?;
         self.last_trans_hash = tr_root.repr_hash();
@@ -82,9 +149,53 @@ impl ShardAccountStuff {
             &tr_root,
             transaction.total_fees()
         )?;
+        self.usage.add_transaction(
+            transaction.gas_used().unwrap_or(0),
+            (tr_root.tree_bits_count() + 7) / 8
+        );
 
         Ok(())
     }
+    // Statistics of account's cell tree after the last transaction, None if there were no ones
+    pub fn storage_stat(&self) -> Option<StorageStat> {
+        self.storage_stat.as_ref().map(|tracker| tracker.stat())
+    }
+    fn update_storage_stat(&mut self, old_root: &Cell, transaction: &Transaction) -> Result<()> {
+        // Diff is applied if the transaction goes right after the tracked state,
+        // otherwise statistics is recomputed from scratch
+        let old_hash = transaction.read_state_update()?.old_hash;
+        let tracked = match self.storage_stat.as_mut() {
+            Some(tracker) if tracker.root() == &old_hash && old_root.repr_hash() == old_hash => {
+                tracker.update(old_root, &self.account_root)?;
+                true
+            }
+            _ => false
+        };
+        if !tracked {
+            self.storage_stat = Some(StorageStatTracker::with_root(&self.account_root)?);
+            self.stat_libs = None;
+        }
+        // Public libraries are rarely changed, so their cells are counted only then
+        let libs = self.read_account()?.libraries();
+        if self.stat_libs.as_ref() != libs.root() {
+            let public_cells = Self::count_public_cells(&libs)?;
+            if let Some(tracker) = self.storage_stat.as_mut() {
+                tracker.set_public_cells(public_cells);
+            }
+            self.stat_libs = libs.root().cloned();
+        }
+        Ok(())
+    }
+    fn count_public_cells(libs: &StateInitLib) -> Result<u64> {
+        let mut public_cells = 0;
+        libs.iterate_with_keys(|_key: UInt256, lib| {
+            if lib.is_public_library() {
+                public_cells += StorageStatTracker::with_root(&lib.root)?.stat().cells;
+            }
+            Ok(true)
+        })?;
+        Ok(public_cells)
+    }
     pub fn update_public_libraries(&self, libraries: &mut Libraries) -> Result<()> {
         let account = self.read_account()?;
         let new_libs = account.libraries();
@@ -165,3 +276,34 @@ impl ShardAccountStuff {
         return Ok(());
       }
 }
+
+#[test]
+fn test_account_usage() {
+    let accounts = ShardAccounts::default();
+    let mut shard_acc = ShardAccountStuff::from_shard_state(
+        AccountId::from([1; 32]), &accounts, Arc::new(AtomicU64::new(0))
+    ).unwrap();
+    shard_acc.track_storage_stat = true;
+    let limits = AccountSoftLimits { transactions: Some(2), ..Default::default() };
+    for lt in 1..=2 {
+        assert!(!shard_acc.usage().exceeds(&limits));
+        let mut transaction = Transaction::with_address_and_status(
+            AccountId::from([1; 32]), Default::default()
+        );
+        transaction.set_logical_time(lt);
+        let hash = shard_acc.account_root().repr_hash();
+        transaction.write_state_update(&HashUpdate::with_hashes(hash.clone(), hash)).unwrap();
+        shard_acc.add_transaction(&mut transaction, shard_acc.account_root()).unwrap();
+    }
+    // The second transaction is applied as a diff
+    assert_eq!(
+        shard_acc.storage_stat(),
+        Some(StorageStatTracker::with_root(&shard_acc.account_root()).unwrap().stat())
+    );
+    let usage = shard_acc.usage();
+    assert_eq!(usage.transactions(), 2);
+    assert!(usage.size() > 0);
+    assert!(usage.exceeds(&limits));
+    assert!(!usage.exceeds(&AccountSoftLimits::default()));
+    assert!(usage.exceeds(&AccountSoftLimits { size: Some(usage.size()), ..Default::default() }));
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+    sync::Mutex,
+};
+
+struct LruCacheInner<K, V> {
+    map: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+/// Simple thread safe cache with limited capacity.
+/// Least recently used entries are evicted when capacity is exceeded.
+pub struct LruCache<K, V> {
+    inner: Mutex<LruCacheInner<K, V>>,
+    capacity: usize,
+}
+
+impl<K, V> LruCache<K, V> where
+    K: Hash + Eq + Clone,
+    V: Clone
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(LruCacheInner {
+                map: HashMap::new(),
+                order: BTreeMap::new(),
+                tick: 0,
+            }),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().map.len()
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.inner.lock().unwrap().map.contains_key(key)
+    }
+
+    /// Returns value without touching the entry
+    pub fn peek(&self, key: &K) -> Option<V> {
+        self.inner.lock().unwrap().map.get(key).map(|(v, _)| v.clone())
+    }
+
+    /// Returns value and marks the entry as recently used
+    pub fn get(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        let (value, old_tick) = match inner.map.get_mut(key) {
+            Some((value, old_tick)) => (value.clone(), std::mem::replace(old_tick, tick)),
+            None => return None
+        };
+        inner.order.remove(&old_tick);
+        inner.order.insert(tick, key.clone());
+        Some(value)
+    }
+
+    /// Inserts or updates the value, returns evicted entries
+    pub fn insert(&self, key: K, value: V) -> Vec<(K, V)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((_, old_tick)) = inner.map.insert(key.clone(), (value, tick)) {
+            inner.order.remove(&old_tick);
+        }
+        inner.order.insert(tick, key);
+        let mut evicted = Vec::new();
+        while inner.map.len() > self.capacity {
+            let oldest = match inner.order.keys().next() {
+                Some(oldest) => *oldest,
+                None => break
+            };
+            if let Some(key) = inner.order.remove(&oldest) {
+                if let Some((value, _)) = inner.map.remove(&key) {
+                    evicted.push((key, value));
+                }
+            }
+        }
+        evicted
+    }
+
+    /// Returns up to `limit` most recently used entries, most recent first
+    pub fn recent(&self, limit: usize) -> Vec<(K, V)> {
+        let inner = self.inner.lock().unwrap();
+        inner.order.values().rev().take(limit).filter_map(|key| {
+            inner.map.get(key).map(|(value, _)| (key.clone(), value.clone()))
+        }).collect()
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        let (value, tick) = inner.map.remove(key)?;
+        inner.order.remove(&tick);
+        Some(value)
+    }
+}
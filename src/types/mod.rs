@@ -3,4 +3,6 @@ pub mod awaiters_pool;
 pub mod top_block_descr;
 pub mod limits;
 pub mod messages;
-pub mod lockfree_cache;
+pub mod lru_cache;
+pub mod states_cache;
+pub mod storage_stat;
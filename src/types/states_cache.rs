@@ -0,0 +1,252 @@
+use crate::shard_state::ShardStateStuff;
+use std::{
+    collections::{BTreeMap, HashMap}, sync::{Arc, Mutex}, time::{Duration, Instant}
+};
+use ton_block::BlockIdExt;
+
+// States of neighbouring blocks share most of their cells, which are held by cells cache
+// anyway, so every state is charged the same estimate instead of walking its tree
+const STATE_SIZE: u64 = 16 * 1024 * 1024;
+// Unpinned states not used for this time are dropped even if the budget is not exceeded
+const STATE_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Default, Clone)]
+pub struct StatesCacheStats {
+    pub entries: usize,
+    pub pinned: usize,
+    pub used_bytes: u64,
+    pub budget_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct StatesCacheEntry {
+    state: Arc<ShardStateStuff>,
+    size: u64,
+    tick: u64,
+    last_used: Instant,
+}
+
+#[derive(Default)]
+struct StatesCacheInner {
+    entries: HashMap<BlockIdExt, StatesCacheEntry>,
+    // Least recently used entries go first
+    order: BTreeMap<u64, BlockIdExt>,
+    // Pinned states are never evicted, state may be pinned before it is loaded
+    pins: HashMap<BlockIdExt, u32>,
+    next_tick: u64,
+    used_bytes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+// Shared cache of deserialized shard states with approximate memory accounting.
+// Least recently used states are evicted when the budget is exceeded, idle ones are
+// evicted after timeout.
+pub struct StatesCache {
+    inner: Mutex<StatesCacheInner>,
+    budget_bytes: u64,
+    idle_timeout: Duration,
+}
+
+// Keeps the state in cache until dropped
+pub struct StatePin {
+    cache: Arc<StatesCache>,
+    id: BlockIdExt,
+}
+
+impl Drop for StatePin {
+    fn drop(&mut self) {
+        self.cache.unpin(&self.id)
+    }
+}
+
+impl StatesCache {
+
+    pub fn new(budget_bytes: u64) -> Self {
+        Self::with_idle_timeout(budget_bytes, STATE_IDLE_TIMEOUT)
+    }
+
+    fn with_idle_timeout(budget_bytes: u64, idle_timeout: Duration) -> Self {
+        Self {
+            inner: Mutex::new(StatesCacheInner::default()),
+            budget_bytes,
+            idle_timeout,
+        }
+    }
+
+    pub fn get(&self, id: &BlockIdExt) -> Option<Arc<ShardStateStuff>> {
+        let mut inner = self.inner.lock().unwrap();
+        self.evict(&mut inner);
+        let tick = inner.next_tick;
+        let found = match inner.entries.get_mut(id) {
+            Some(entry) => {
+                entry.last_used = Instant::now();
+                Some((entry.state.clone(), std::mem::replace(&mut entry.tick, tick)))
+            }
+            None => None
+        };
+        match found {
+            Some((state, old_tick)) => {
+                inner.order.remove(&old_tick);
+                inner.order.insert(tick, id.clone());
+                inner.next_tick += 1;
+                inner.hits += 1;
+                Some(state)
+            }
+            None => {
+                inner.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Returns cached state if it is already there
+    pub fn insert(&self, state: Arc<ShardStateStuff>) -> Arc<ShardStateStuff> {
+        let id = state.block_id().clone();
+        let size = STATE_SIZE;
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.entries.get(&id) {
+            return entry.state.clone()
+        }
+        let tick = inner.next_tick;
+        inner.next_tick += 1;
+        inner.entries.insert(
+            id.clone(),
+            StatesCacheEntry { state: state.clone(), size, tick, last_used: Instant::now() }
+        );
+        inner.order.insert(tick, id);
+        inner.used_bytes += size;
+        self.evict(&mut inner);
+        state
+    }
+
+    // State may be pinned before it is loaded
+    pub fn pin(self: &Arc<Self>, id: &BlockIdExt) -> StatePin {
+        *self.inner.lock().unwrap().pins.entry(id.clone()).or_insert(0) += 1;
+        StatePin { cache: self.clone(), id: id.clone() }
+    }
+
+    fn unpin(&self, id: &BlockIdExt) {
+        let mut inner = self.inner.lock().unwrap();
+        let unpinned = match inner.pins.get_mut(id) {
+            Some(pins) => {
+                *pins -= 1;
+                *pins == 0
+            }
+            None => false
+        };
+        if unpinned {
+            inner.pins.remove(id);
+            self.evict(&mut inner);
+        }
+    }
+
+    pub fn stats(&self) -> StatesCacheStats {
+        let inner = self.inner.lock().unwrap();
+        StatesCacheStats {
+            entries: inner.entries.len(),
+            pinned: inner.pins.keys().filter(|id| inner.entries.contains_key(id)).count(),
+            used_bytes: inner.used_bytes,
+            budget_bytes: self.budget_bytes,
+            hits: inner.hits,
+            misses: inner.misses,
+            evictions: inner.evictions,
+        }
+    }
+
+    fn evict(&self, inner: &mut StatesCacheInner) {
+        let mut evicted = Vec::new();
+        let mut used_bytes = inner.used_bytes;
+        for (tick, id) in inner.order.iter() {
+            let entry = match inner.entries.get(id) {
+                Some(entry) => entry,
+                None => continue
+            };
+            // Entries go from the least recently used one, so the rest are not idle
+            if (used_bytes <= self.budget_bytes) && (entry.last_used.elapsed() < self.idle_timeout) {
+                break
+            }
+            if inner.pins.contains_key(id) {
+                continue
+            }
+            used_bytes -= entry.size;
+            evicted.push(*tick);
+        }
+        for tick in evicted {
+            if let Some(id) = inner.order.remove(&tick) {
+                if let Some(entry) = inner.entries.remove(&id) {
+                    inner.used_bytes -= entry.size;
+                    inner.evictions += 1;
+                    log::trace!("State {} is evicted from cache", id);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_state(seq_no: u32) -> Arc<ShardStateStuff> {
+    let id = BlockIdExt {
+        seq_no,
+        root_hash: ton_types::UInt256::from([seq_no as u8; 32]),
+        ..Default::default()
+    };
+    Arc::new(ShardStateStuff::with_state(id, Default::default()).unwrap())
+}
+
+#[test]
+fn test_states_cache_eviction_and_pinning() {
+    let state = test_state;
+    let size = STATE_SIZE;
+    let cache = Arc::new(StatesCache::new(size * 2));
+
+    cache.insert(state(1));
+    cache.insert(state(2));
+    assert!(cache.get(state(1).block_id()).is_some());
+    // State 2 is the least recently used one
+    cache.insert(state(3));
+    assert!(cache.get(state(2).block_id()).is_none());
+    assert!(cache.get(state(1).block_id()).is_some());
+
+    // Pinned state stays even being the least recently used
+    let pin3 = cache.pin(state(3).block_id());
+    cache.insert(state(4));
+    cache.insert(state(5));
+    assert!(cache.get(state(3).block_id()).is_some());
+    assert!(cache.get(state(4).block_id()).is_none());
+    assert!(cache.get(state(5).block_id()).is_some());
+
+    // Budget is exceeded by pinned states, extra one goes away after unpinning.
+    // State can be pinned before it is loaded.
+    let _pin5 = cache.pin(state(5).block_id());
+    let _pin6 = cache.pin(state(6).block_id());
+    cache.insert(state(6));
+    assert_eq!(cache.stats().entries, 3);
+    drop(pin3);
+    let stats = cache.stats();
+    assert_eq!(stats.entries, 2);
+    assert_eq!(stats.pinned, 2);
+    assert_eq!(stats.used_bytes, size * 2);
+    assert!(cache.get(state(3).block_id()).is_none());
+}
+
+#[test]
+fn test_states_cache_idle_timeout() {
+    let cache = Arc::new(StatesCache::with_idle_timeout(STATE_SIZE * 10, Duration::from_millis(50)));
+    let pin = cache.pin(test_state(1).block_id());
+    cache.insert(test_state(1));
+    cache.insert(test_state(2));
+    std::thread::sleep(Duration::from_millis(100));
+    cache.insert(test_state(3));
+    // Idle state goes away under budget, pinned and fresh ones stay
+    assert!(cache.get(test_state(2).block_id()).is_none());
+    assert!(cache.get(test_state(1).block_id()).is_some());
+    assert!(cache.get(test_state(3).block_id()).is_some());
+    drop(pin);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(cache.get(test_state(1).block_id()).is_none());
+    assert_eq!(cache.stats().entries, 0);
+}
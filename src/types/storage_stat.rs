@@ -0,0 +1,136 @@
+use std::collections::{hash_map::Entry, HashMap};
+use ton_types::{fail, Cell, Result, UInt256};
+
+// Cells and bits of a cell tree, every unique cell is counted once
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StorageStat {
+    pub cells: u64,
+    pub bits: u64,
+    pub public_cells: u64,
+}
+
+// Keeps count of references to every cell of the tree, so the statistics is updated
+// by visiting only the cells which differ between the old and the new trees
+pub struct StorageStatTracker {
+    root: UInt256,
+    refs: HashMap<UInt256, u32>,
+    stat: StorageStat,
+}
+
+impl StorageStatTracker {
+
+    pub fn with_root(root: &Cell) -> Result<Self> {
+        let mut tracker = Self {
+            root: root.repr_hash(),
+            refs: HashMap::new(),
+            stat: StorageStat::default(),
+        };
+        tracker.add_tree(root)?;
+        Ok(tracker)
+    }
+
+    pub fn root(&self) -> &UInt256 {
+        &self.root
+    }
+
+    pub fn stat(&self) -> StorageStat {
+        self.stat
+    }
+
+    pub fn set_public_cells(&mut self, public_cells: u64) {
+        self.stat.public_cells = public_cells;
+    }
+
+    // Old root must be the one the tracker has
+    pub fn update(&mut self, old_root: &Cell, new_root: &Cell) -> Result<()> {
+        if old_root.repr_hash() != self.root {
+            fail!("Tracked root {:x} differs from old one {:x}", self.root, old_root.repr_hash())
+        }
+        // New tree goes first so the common cells are not removed
+        self.add_tree(new_root)?;
+        self.remove_tree(old_root)?;
+        self.root = new_root.repr_hash();
+        Ok(())
+    }
+
+    fn add_tree(&mut self, root: &Cell) -> Result<()> {
+        let mut stack = vec![root.clone()];
+        while let Some(cell) = stack.pop() {
+            match self.refs.entry(cell.repr_hash()) {
+                Entry::Occupied(mut entry) => *entry.get_mut() += 1,
+                Entry::Vacant(entry) => {
+                    entry.insert(1);
+                    self.stat.cells += 1;
+                    self.stat.bits += cell.bit_length() as u64;
+                    for i in 0..cell.references_count() {
+                        stack.push(cell.reference(i)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_tree(&mut self, root: &Cell) -> Result<()> {
+        let mut stack = vec![root.clone()];
+        while let Some(cell) = stack.pop() {
+            if let Entry::Occupied(mut entry) = self.refs.entry(cell.repr_hash()) {
+                *entry.get_mut() -= 1;
+                if *entry.get() == 0 {
+                    entry.remove();
+                    self.stat.cells -= 1;
+                    self.stat.bits -= cell.bit_length() as u64;
+                    for i in 0..cell.references_count() {
+                        stack.push(cell.reference(i)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_tree(rng: &mut impl rand::Rng, leaves: &[Cell], depth: u32) -> Cell {
+    let mut builder = ton_types::BuilderData::new();
+    builder.append_bits(rng.gen_range(0, 1 << 16), rng.gen_range(1, 17)).unwrap();
+    if depth > 0 {
+        for _ in 0..rng.gen_range(0, 4) {
+            let child = if rng.gen_bool(0.3) {
+                // Subtrees are shared between versions and within the tree
+                leaves[rng.gen_range(0, leaves.len())].clone()
+            } else {
+                test_tree(rng, leaves, depth - 1)
+            };
+            builder.checked_append_reference(child).unwrap();
+        }
+    }
+    builder.into_cell().unwrap()
+}
+
+#[test]
+fn test_storage_stat_incremental() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(0x5707);
+    for _ in 0..20 {
+        let mut leaves = (0..8).map(|_| test_tree(&mut rng, &[Cell::default()], 2)).collect::<Vec<_>>();
+        let mut root = test_tree(&mut rng, &leaves, 4);
+        let mut tracker = StorageStatTracker::with_root(&root).unwrap();
+        for _ in 0..30 {
+            // New version keeps some old subtrees
+            if rng.gen_bool(0.5) {
+                let i = rng.gen_range(0, leaves.len());
+                leaves[i] = root.clone();
+            }
+            let new_root = test_tree(&mut rng, &leaves, 4);
+            tracker.update(&root, &new_root).unwrap();
+            root = new_root;
+            let full = StorageStatTracker::with_root(&root).unwrap();
+            assert_eq!(tracker.stat(), full.stat());
+            assert_eq!(tracker.refs, full.refs);
+        }
+        // Diff can't be applied to another tree
+        let stale = test_tree(&mut rng, &leaves, 4);
+        assert!(tracker.update(&stale, &root).is_err());
+    }
+}
@@ -259,6 +259,10 @@ impl TopBlockDescrStuff {
         let last_mc_state_extra = last_mc_state.state().read_custom()?
             .ok_or_else(|| error!("State for {} doesn't have McStateExtra", last_mc_state.block_id()))?;
 
+        if self.is_fake {
+            return self.validate_fake(&last_mc_state_extra)
+        }
+
         self.validate_internal(
             last_mc_block_id,
             &last_mc_state_extra,
@@ -268,6 +272,23 @@ impl TopBlockDescrStuff {
         )
     }
 
+    // Fake description has no proof chain, so it is only checked to be newer
+    // than the shard's top block known to masterchain
+    fn validate_fake(&self, last_mc_state_extra: &McStateExtra) -> Result<i32> {
+        let shard = self.proof_for().shard().left_ancestor_mask()?;
+        if let Some(descr) = last_mc_state_extra.shards().find_shard(&shard)? {
+            if descr.block_id().seq_no() >= self.proof_for().seq_no() {
+                fail!(
+                    "ShardTopBlockDescr for {} is too old: we already know \
+                    a newer shardchain block {}",
+                    self.proof_for(),
+                    descr.block_id()
+                )
+            }
+        }
+        Ok(0)
+    }
+
     fn read_one_proof(
         block_id: &BlockIdExt,
         cur_id: &BlockIdExt,
@@ -1,4 +1,6 @@
-use crate::validator::validator_utils::compute_validator_set_cc;
+use crate::{
+    error::{NodeError, ValidatorError}, validator::validator_utils::compute_validator_set_cc
+};
 use ton_block::{
     ShardIdent, BlockIdExt, ConfigParams, McStateExtra, ShardHashes, ValidatorSet, McShardRecord,
     FutureSplitMerge, INVALID_WORKCHAIN_ID, MASTERCHAIN_ID, GlobalCapabilities,
@@ -136,10 +138,12 @@ pub fn check_this_shard_mc_info(
                         shard, prev_blocks[0], left.block_id(), right.block_id())
             }
             if prev_blocks[0].seq_no >= cseqno + 8 {
-                fail!("cannot create new block for shard {} after previous block {} \
+                fail!(NodeError::Validator(ValidatorError::UnregisteredChain, format!(
+                    "cannot create new block for shard {} after previous block {} \
                     because this would lead to an unregistered chain of length > 8 \
                     (masterchain contains only {} and {})",
-                        shard, prev_blocks[0], left.block_id(), right.block_id())
+                    shard, prev_blocks[0], left.block_id(), right.block_id()
+                )))
             }
         }
     } else if left.shard.is_parent_for(shard) {
@@ -187,8 +191,11 @@ pub fn check_prev_block(listed: &BlockIdExt, prev: &BlockIdExt, chk_chain_len: b
             because masterchain configuration lists another block {} of the same height", prev, listed)
     }
     if chk_chain_len && prev.seq_no >= listed.seq_no + 8 {
-        fail!("cannot generate next block after {} because this would lead to \
-            an unregistered chain of length > 8 (only {} is registered in the masterchain)", prev, listed)
+        fail!(NodeError::Validator(ValidatorError::UnregisteredChain, format!(
+            "cannot generate next block after {} because this would lead to \
+            an unregistered chain of length > 8 (only {} is registered in the masterchain)",
+            prev, listed
+        )))
     }
     Ok(())
 }
@@ -215,9 +222,11 @@ pub fn check_cur_validator_set(
         fail!("Cannot compute masterchain validator set from old masterchain state")
     }
     if validator_set.catchain_seqno() != cc_seqno_with_delta {
-        fail!("Current validator set catchain seqno mismatch: this validator set has cc_seqno={}, \
+        fail!(NodeError::Validator(ValidatorError::WrongValidatorSet, format!(
+            "Current validator set catchain seqno mismatch: this validator set has cc_seqno={}, \
             only validator set with cc_seqno={} is entitled to create block {}",
-                validator_set.catchain_seqno(), cc_seqno_with_delta, block_id);
+            validator_set.catchain_seqno(), cc_seqno_with_delta, block_id
+        )))
     }
 
     // TODO: check compute_validator_set
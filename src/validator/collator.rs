@@ -3,7 +3,7 @@
 use adnl::common::Wait;
 use std::{
     cmp::{min, max},
-    collections::{HashMap, HashSet, BinaryHeap},
+    collections::{BTreeMap, HashMap, HashSet, BinaryHeap},
     ops::Deref,
     sync::{atomic::{Ordering, AtomicU64, AtomicBool}, Arc},
     time::{Instant, Duration},
@@ -11,9 +11,10 @@ use std::{
 use crate::{
     CHECK,
     engine_traits::EngineOperations,
+    ext_messages::ExtMessageStatus,
     shard_state::ShardStateStuff,
     types::{
-        accounts::ShardAccountStuff,
+        accounts::{AccountSoftLimits, AccountUsage, ShardAccountStuff},
         limits::BlockLimitStatus,
         messages::{MsgEnvelopeStuff, MsgEnqueueStuff},
         top_block_descr::{Mode as TbdMode, TopBlockDescrStuff, cmp_shard_block_descr},
@@ -186,6 +187,7 @@ struct CollatorData {
     block_create_count: HashMap<UInt256, u64>,
     new_messages: BinaryHeap<NewMessage>, // using for priority queue
     to_delay: Vec<UInt256>, // list of not compleeted external messages
+    collated_ext: Vec<UInt256>, // list of external messages included into block
     usage_tree: UsageTree,
 
     // determined fields
@@ -249,6 +251,7 @@ impl CollatorData {
             block_create_count: HashMap::new(),
             new_messages: Default::default(),
             to_delay: Default::default(),
+            collated_ext: Default::default(),
             usage_tree,
             gen_utime,
             config,
@@ -558,20 +561,49 @@ impl CollatorData {
     fn withdraw_ext_msg_to_delay(&mut self) -> Vec<UInt256> {
         std::mem::take(&mut self.to_delay)
     }
+
+    fn withdraw_ext_msg_collated(&mut self) -> Vec<UInt256> {
+        std::mem::take(&mut self.collated_ext)
+    }
 }
 
+// Result of message execution by account job
+struct ExecutionResult {
+    // Number of message in order of dispatching
+    seq_no: u64,
+    account_id: AccountId,
+    msg: Arc<AsyncMessage>,
+    transaction: Result<Transaction>,
+    // Account's logical time after the transaction
+    lt: u64,
+    usage: AccountUsage,
+}
+
+// Accounts are executed in parallel by their own jobs, each job owns its ShardAccountStuff.
+// Results are finalized strictly in order of dispatching, and the count of unfinalized
+// messages doesn't depend on execution timings, so the block is the same as in case of
+// sequential execution.
 struct ExecutionManager {
     changed_accounts: HashMap<
         AccountId, 
         (
-            tokio::sync::mpsc::UnboundedSender<Arc<AsyncMessage>>,
+            tokio::sync::mpsc::UnboundedSender<(u64, Arc<AsyncMessage>)>,
             tokio::task::JoinHandle<Result<ShardAccountStuff>>
         )
     >,
     
-    receive_tr: tokio::sync::mpsc::UnboundedReceiver<Option<(Arc<AsyncMessage>, Result<Transaction>)>>,
-    wait_tr: Arc<Wait<(Arc<AsyncMessage>, Result<Transaction>)>>,
+    receive_tr: tokio::sync::mpsc::UnboundedReceiver<Option<ExecutionResult>>,
+    wait_tr: Arc<Wait<ExecutionResult>>,
     max_collate_threads: usize,
+    // If false, every message is executed before the next one is dispatched
+    parallel: bool,
+    dispatched: u64,
+    finalized: u64,
+    // Received results waiting for the previous ones to be finalized
+    finished: BTreeMap<u64, ExecutionResult>,
+    // Usage is taken from finalized results only
+    accounts_usage: HashMap<AccountId, AccountUsage>,
+    account_limits: AccountSoftLimits,
     libraries: Libraries,
     gen_utime: u32,
 
@@ -598,6 +630,8 @@ impl ExecutionManager {
         libraries: Libraries,
         config: BlockchainConfig,
         max_collate_threads: usize,
+        parallel: bool,
+        account_limits: AccountSoftLimits,
         collated_block_descr: Arc<String>,
         debug: bool,
     ) -> Result<Self> {
@@ -608,6 +642,12 @@ impl ExecutionManager {
             receive_tr,
             wait_tr,
             max_collate_threads,
+            parallel,
+            dispatched: 0,
+            finalized: 0,
+            finished: BTreeMap::new(),
+            accounts_usage: HashMap::new(),
+            account_limits,
             libraries,
             config,
             start_lt,
@@ -624,7 +664,7 @@ impl ExecutionManager {
     // waits and finalizes all parallel tasks
     pub async fn wait_transactions(&mut self, collator_data: &mut CollatorData) -> Result<()> {
         log::trace!("{}: wait_transactions", self.collated_block_descr);
-        while self.wait_tr.count() > 0 {
+        while self.finalized < self.dispatched {
             self.wait_transaction(collator_data).await?;
         }
         self.min_lt.fetch_max(self.max_lt.load(Ordering::Relaxed), Ordering::Relaxed);
@@ -634,7 +674,7 @@ impl ExecutionManager {
     // checks if a number of parallel transactilns is not too big, waits and finalizes some if needed.
     pub async fn check_parallel_transactions(&mut self, collator_data: &mut CollatorData) -> Result<()> {
         log::trace!("{}: check_parallel_transactions", self.collated_block_descr);
-        if self.wait_tr.count() >= self.max_collate_threads {
+        if self.dispatched - self.finalized >= self.max_collate_threads as u64 {
             self.wait_transaction(collator_data).await?;
         }
         Ok(())
@@ -649,7 +689,8 @@ impl ExecutionManager {
     ) -> Result<()> {
         log::trace!("{}: execute (adding into queue): {:x}", self.collated_block_descr, account_id);
         self.wait_tr.request();
-        let msg = Arc::new(msg);
+        let msg = (self.dispatched, Arc::new(msg));
+        self.dispatched += 1;
         match self.changed_accounts.get(&account_id) {
             None => {
                 let (sender, handle) = self.start_account_job(
@@ -662,16 +703,31 @@ impl ExecutionManager {
             Some((sender, _handle)) => sender.send(msg)?
         }
 
+        if !self.parallel {
+            self.receive_result(self.dispatched - 1).await?;
+        }
         self.check_parallel_transactions(collator_data).await?;
 
         Ok(())
     }
 
+    // Checks if account has exceeded its soft limits in the block,
+    // further messages to it should be left for the next block
+    pub fn is_deferred(&self, account_id: &AccountId) -> bool {
+        match self.accounts_usage.get(account_id) {
+            Some(usage) => usage.exceeds(&self.account_limits),
+            None => false
+        }
+    }
+
     fn start_account_job(
         &self,
         account_addr: AccountId,
         accounts: &ShardAccounts,
-    ) -> Result<(tokio::sync::mpsc::UnboundedSender<Arc<AsyncMessage>>, tokio::task::JoinHandle<Result<ShardAccountStuff>>)> {
+    ) -> Result<(
+        tokio::sync::mpsc::UnboundedSender<(u64, Arc<AsyncMessage>)>,
+        tokio::task::JoinHandle<Result<ShardAccountStuff>>
+    )> {
         log::trace!("{}: start_account_job: {:x}", self.collated_block_descr, account_addr);
 
         let mut shard_acc = ShardAccountStuff::from_shard_state(
@@ -691,9 +747,9 @@ impl ExecutionManager {
         let min_lt = self.min_lt.clone();
         let max_lt = self.max_lt.clone();
         let libraries = self.libraries.clone().inner();
-        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<Arc<AsyncMessage>>();
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(u64, Arc<AsyncMessage>)>();
         let handle = tokio::spawn(async move {
-            while let Some(new_msg) = receiver.recv().await {
+            while let Some((seq_no, new_msg)) = receiver.recv().await {
                 log::trace!("{}: new message for {:x}", collated_block_descr, shard_acc.account_addr());
                 let config = config.clone(); // TODO: use Arc
 
@@ -734,8 +790,16 @@ impl ExecutionManager {
                 log::trace!("{}: account {:x} TIME execute {}μ;", 
                     collated_block_descr, shard_acc.account_addr(), duration);
 
-                max_lt.fetch_max(shard_acc.lt().load(Ordering::Relaxed), Ordering::Relaxed);
-                wait_tr.respond(Some((new_msg, transaction_res)));
+                let lt = shard_acc.lt().load(Ordering::Relaxed);
+                max_lt.fetch_max(lt, Ordering::Relaxed);
+                wait_tr.respond(Some(ExecutionResult {
+                    seq_no,
+                    account_id: shard_acc.account_addr().clone(),
+                    msg: new_msg,
+                    transaction: transaction_res,
+                    lt,
+                    usage: shard_acc.usage(),
+                }));
             }
             Ok(shard_acc)
         });
@@ -765,21 +829,38 @@ impl ExecutionManager {
         executor.execute_with_libs_and_params(msg_opt, account_root, params)
     }
 
+    // waits and finalizes the oldest unfinalized message
     async fn wait_transaction(&mut self, collator_data: &mut CollatorData) -> Result<()> {
         log::trace!("{}: wait_transaction", self.collated_block_descr);
-        let wait_op = self.wait_tr.wait(&mut self.receive_tr, false).await;
-        if let Some(Some((new_msg, transaction_res))) = wait_op {
-            self.finalize_transaction(new_msg, transaction_res, collator_data)?;
+        self.receive_result(self.finalized).await?;
+        let result = self.finished.remove(&self.finalized)
+            .ok_or_else(|| error!("INTERNAL ERROR: no result of message {}", self.finalized))?;
+        self.finalized += 1;
+        self.finalize_transaction(result, collator_data)
+    }
+
+    // receives results until the given one comes
+    async fn receive_result(&mut self, seq_no: u64) -> Result<()> {
+        while !self.finished.contains_key(&seq_no) {
+            match self.wait_tr.wait(&mut self.receive_tr, false).await {
+                Some(Some(result)) => {
+                    self.finished.insert(result.seq_no, result);
+                }
+                _ => fail!("INTERNAL ERROR: result of message {} is lost", seq_no)
+            }
         }
         Ok(())
     }
 
     fn finalize_transaction(
         &mut self,
-        new_msg: Arc<AsyncMessage>,
-        transaction_res: Result<Transaction>,
+        result: ExecutionResult,
         collator_data: &mut CollatorData
     ) -> Result<()> {
+        let ExecutionResult {
+            account_id, msg: new_msg, transaction: transaction_res, lt, usage, ..
+        } = result;
+        self.accounts_usage.insert(account_id, usage);
         if let AsyncMessage::Ext(ref msg) = new_msg.deref() {
             if let Err(err) = transaction_res {
                 let msg_id = msg.serialize()?.repr_hash();
@@ -817,6 +898,7 @@ impl ExecutionManager {
             }
             AsyncMessage::Ext(msg) => {
                 let in_msg = InMsg::external(&msg, &tr)?;
+                collator_data.collated_ext.push(msg.serialize()?.repr_hash());
                 Some(in_msg)
             }
             AsyncMessage::TickTock(_) => None
@@ -830,7 +912,7 @@ impl ExecutionManager {
         }
         collator_data.new_transaction(&tr, tr_cell, in_msg_opt.as_ref())?;
 
-        collator_data.update_lt(self.max_lt.load(Ordering::Relaxed));
+        collator_data.update_lt(lt);
 
         match new_msg.deref() {
             AsyncMessage::Mint(_) => collator_data.mint_msg = in_msg_opt,
@@ -981,6 +1063,10 @@ impl Collator {
         );
         self.init_timeout(timeout_ms);
 
+        // Previous states are kept in the engine's cache while collation is in progress
+        let _pinned = self.prev_blocks_ids.iter()
+            .map(|id| self.engine.pin_state(id))
+            .collect::<Vec<_>>();
         let imported_data = self.import_data().await?;
         let (mc_data, prev_data, mut collator_data) = self.prepare_data(imported_data).await?;
         let (candidate, state, exec_manager) = self.do_collate(&mc_data, &prev_data, &mut collator_data).await?;
@@ -1170,6 +1256,8 @@ impl Collator {
             mc_data.libraries().clone(),
             collator_data.config.clone(),
             self.collator_settings.max_collate_threads.unwrap_or(MAX_COLLATE_THREADS),
+            self.collator_settings.parallel_execution.unwrap_or(true),
+            self.collator_settings.account_limits.clone(),
             self.collated_block_descr.clone(),
             self.debug,
         )?;
@@ -1398,7 +1486,7 @@ impl Collator {
            now > (prev_now / lifetime + 1) * lifetime + 20 {
 
             let overdue = now - (prev_now / lifetime + 1) * lifetime;
-            let mut rng = rand::thread_rng();
+            let mut rng = crate::rng::stream_for("collator", &self.shard);
             let skip_topmsgdescr = rng.gen_range(0, 1024) < 256; // probability 1/4
             let skip_extmsg = rng.gen_range(0, 1024) < 256; // skip ext msg probability 1/4
             if skip_topmsgdescr {
@@ -1419,7 +1507,7 @@ impl Collator {
             }
         } else if self.shard.is_masterchain() && now > prev_now + 60 {
             let interval = now - prev_now;
-            let mut rng = rand::thread_rng();
+            let mut rng = crate::rng::stream_for("collator", &self.shard);
             let skip_topmsgdescr = rng.gen_range(0, 1024) < 128; // probability 1/8
             let skip_extmsg = rng.gen_range(0, 1024) < 128; // skip ext msg probability 1/8
             if skip_topmsgdescr {
@@ -1903,8 +1991,19 @@ impl Collator {
     ) -> Result<()> {
         log::trace!("{}: process_inbound_internal_messages", self.collated_block_descr);
         let mut iter = output_queue_manager.merge_out_queue_iter(&self.shard)?;
+        let mut deferred = false;
         while let Some(k_v) = iter.next() {
             let (key, enq, created_lt, block_id) = k_v?;
+            // Messages are processed strictly in order, so queue processing stops
+            // at the first message to the account which has exceeded its limits
+            if self.shard.contains_full_prefix(&enq.dst_prefix()) &&
+                exec_manager.is_deferred(&enq.dst_account_id()?)
+            {
+                log::debug!("{}: account {:x} exceeded its limits, stop processing internal messages",
+                    self.collated_block_descr, enq.dst_account_id()?);
+                deferred = true;
+                break
+            }
             collator_data.update_last_proc_int_msg((created_lt, enq.message_hash()))?;
             if collator_data.out_msg_queue_info.already_processed(&enq)? {
                 log::debug!("{}: inbound internal message with lt={} hash={:x} enqueued_lt={} \
@@ -1942,7 +2041,7 @@ impl Collator {
             self.check_stop_flag()?;
         }
         // all internal messages are processed
-        collator_data.inbound_queues_empty = iter.next().is_none();
+        collator_data.inbound_queues_empty = !deferred && iter.next().is_none();
         Ok(())
     }
 
@@ -1988,17 +2087,12 @@ impl Collator {
             return Ok(())
         }
 
-        //LK temporary: check external messaging delivery
-        {
-            use rand::thread_rng;
-            use rand::seq::SliceRandom;
-            let mut rng = thread_rng();
-            ext_messages.shuffle(&mut rng);
-            log::debug!("{}: try to process {} external messages", self.collated_block_descr, ext_messages.len());
-        }
-        //LK temporary end
+        // messages are already ordered by the pool according to configured policy
+        log::debug!("{}: try to process {} external messages", self.collated_block_descr, ext_messages.len());
 
         log::trace!("{}: process_inbound_external_messages", self.collated_block_descr);
+        let max_ext_messages = self.collator_settings.max_ext_messages.unwrap_or(usize::MAX);
+        let mut executed = 0;
         for (msg, id) in ext_messages.drain(..) {
             let header = msg.ext_in_header().ok_or_else(|| error!("message {:x} \
                 is not external inbound message", id))?;
@@ -2007,14 +2101,28 @@ impl Collator {
                     log::trace!("{}: BLOCK FULL, stop processing external messages", self.collated_block_descr);
                     break
                 }
+                if executed >= max_ext_messages {
+                    log::debug!("{}: {} external messages are taken, stop processing external messages",
+                        self.collated_block_descr, executed);
+                    break
+                }
                 if self.check_cutoff_timeout() {
                     log::warn!("{}: TIMEOUT ({}ms) is elapsed, stop processing external messages",
                         self.collated_block_descr, self.cutoff_timeout.as_millis());
                     break
                 }
                 let (_, account_id) = header.dst.extract_std_address(true)?;
+                if exec_manager.is_deferred(&account_id) {
+                    // the message stays in the pool for the next block
+                    log::trace!(
+                        "{}: account {:x} exceeded its limits, external message {:x} is deferred",
+                        self.collated_block_descr, account_id, id
+                    );
+                    continue
+                }
                 let msg = AsyncMessage::Ext(msg.deref().clone());
                 exec_manager.execute(account_id, msg, prev_data, collator_data).await?;
+                executed += 1;
             } else {
                 // usually node collates more than one shard, the message can belong another one,
                 // so we can't postpone it
@@ -2024,6 +2132,13 @@ impl Collator {
             self.check_stop_flag()?;
         }
         exec_manager.wait_transactions(collator_data).await?;
+        for id in collator_data.withdraw_ext_msg_collated() {
+            let status = ExtMessageStatus::Collated { 
+                shard: self.shard.clone(), 
+                seq_no: self.new_block_id_part.seq_no 
+            };
+            self.engine.set_external_message_status(&id, status);
+        }
         self.engine.complete_external_messages(collator_data.withdraw_ext_msg_to_delay(), vec![])?;
         Ok(())
     }
@@ -2048,7 +2163,11 @@ impl Collator {
                 let info = msg.int_header().ok_or_else(|| error!("message is not internal"))?;
                 let fwd_fee = info.fwd_fee().clone();
                 enqueue_only |= collator_data.block_full | self.check_cutoff_timeout();
-                if !self.shard.contains_address(&info.dst)? || enqueue_only {
+                let to_us = self.shard.contains_address(&info.dst)?;
+                // message to the account which exceeded its limits goes to the queue for the next block
+                let deferred = to_us &&
+                    exec_manager.is_deferred(&msg.int_dst_account_id().unwrap_or_default());
+                if !to_us || enqueue_only || deferred {
                     let enq = MsgEnqueueStuff::new(msg, &self.shard, fwd_fee)?;
                     collator_data.add_out_msg_to_state(&enq, true)?;
                     let out_msg = OutMsg::new(enq.envelope(), tr_cell)?;
@@ -2679,7 +2798,7 @@ impl Collator {
             )?;
         }
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::stream_for("collator", &self.shard);
         let key: [u8; 32] = rng.gen();
         let mut key: UInt256 = key.into();
         let mut scanned = 0;
@@ -2829,18 +2948,200 @@ pub fn report_collation_metrics(
     block_size: usize,
     time: u32,
 ) {
-    let mut pipeline = STATSD.pipeline();
-
-    pipeline.timer(&format!("collation_time_{}", shard), time as f64);
-    pipeline.gauge(&format!("dequeue_msg_count_{}", shard), dequeue_msg_count as f64);
-    pipeline.gauge(&format!("enqueue_msg_count_{}", shard), enqueue_msg_count as f64);
-    pipeline.gauge(&format!("in_msg_count_{}", shard), in_msg_count as f64);
-    pipeline.gauge(&format!("out_msg_count_{}", shard), out_msg_count as f64);
-    pipeline.gauge(&format!("transit_msg_count_{}", shard), transit_msg_count as f64);
-    pipeline.gauge(&format!("executed_trs_count_{}", shard), executed_trs_count as f64);
-    pipeline.gauge(&format!("gas_used_{}", shard), gas_used as f64);
-    pipeline.gauge(&format!("gas_rate_collator_{}", shard), gas_rate as f64);
-    pipeline.gauge(&format!("block_size_{}", shard), block_size as f64);
-
-    pipeline.send(&STATSD);
+    let mut pipeline = STATSD.shard(shard).pipeline();
+
+    pipeline.timer("collation_time", time as f64);
+    pipeline.gauge("dequeue_msg_count", dequeue_msg_count as f64);
+    pipeline.gauge("enqueue_msg_count", enqueue_msg_count as f64);
+    pipeline.gauge("in_msg_count", in_msg_count as f64);
+    pipeline.gauge("out_msg_count", out_msg_count as f64);
+    pipeline.gauge("transit_msg_count", transit_msg_count as f64);
+    pipeline.gauge("executed_trs_count", executed_trs_count as f64);
+    pipeline.gauge("gas_used", gas_used as f64);
+    pipeline.gauge("gas_rate_collator", gas_rate as f64);
+    pipeline.gauge("block_size", block_size as f64);
+
+    pipeline.send();
+}
+
+#[test]
+fn test_hot_account_does_not_starve_others() {
+    let mut exec_manager = ExecutionManager::new(
+        0,
+        0,
+        UInt256::default(),
+        Libraries::default(),
+        BlockchainConfig::default(),
+        MAX_COLLATE_THREADS,
+        true,
+        AccountSoftLimits { transactions: Some(10), gas: Some(100_000), size: None },
+        Arc::new("test".to_string()),
+        false,
+    ).unwrap();
+    let hot = AccountId::from([1; 32]);
+    let others = (2..6).map(|i| AccountId::from([i; 32])).collect::<Vec<_>>();
+    // Hot account is the destination of nine messages of every ten,
+    // only 40 messages fit the block
+    let mut executed = HashMap::new();
+    let mut deferred = 0;
+    for i in 0..1000 {
+        if executed.values().sum::<u32>() == 40 {
+            break
+        }
+        let account = if i % 10 == 9 { &others[i / 10 % others.len()] } else { &hot };
+        if exec_manager.is_deferred(account) {
+            deferred += 1;
+            continue
+        }
+        // the same as finalization of the transaction does
+        exec_manager.accounts_usage.entry(account.clone()).or_default().add_transaction(1000, 500);
+        *executed.entry(account.clone()).or_insert(0u32) += 1;
+    }
+    assert_eq!(executed[&hot], 10);
+    assert!(deferred > 0);
+    for account in others.iter() {
+        assert!(executed[account] >= 7, "{:x}: {}", account, executed[account]);
+    }
+
+    // Gas limit is taken into account as well
+    let heavy = AccountId::from([9; 32]);
+    exec_manager.accounts_usage.entry(heavy.clone()).or_default().add_transaction(100_000, 500);
+    assert!(exec_manager.is_deferred(&heavy));
+}
+
+#[cfg(test)]
+async fn execute_test_messages(parallel: bool, count: u8) -> Result<Vec<UInt256>> {
+    let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000)?;
+    let mut state = ShardStateUnsplit::with_ident(shard.clone());
+    state.set_gen_time(1_600_000_000);
+    let id = BlockIdExt::with_params(shard.clone(), 1, UInt256::default(), UInt256::default());
+    let state = ShardStateStuff::with_state(id, state)?;
+    let prev_data = PrevData::from_prev_states(
+        vec![state.clone()], vec![state], Cell::default(), None
+    )?;
+    let config = BlockchainConfig::default();
+    let gen_utime = 1_600_000_010;
+    let start_lt = 1_000_000;
+    let mut collator_data = CollatorData::new(
+        gen_utime, config.clone(), UsageTree::with_root(Cell::default()), &prev_data, false
+    )?;
+    collator_data.start_lt = Some(start_lt);
+    let mut exec_manager = ExecutionManager::new(
+        gen_utime,
+        start_lt,
+        UInt256::default(),
+        Libraries::default(),
+        config,
+        4,
+        parallel,
+        AccountSoftLimits::default(),
+        Arc::new(format!("test parallel={}", parallel)),
+        false,
+    )?;
+    // Several new messages to each of a few accounts
+    for i in 0..count {
+        let dst = AccountId::from([i % 5 + 1; 32]);
+        let mut hdr = InternalMessageHeader::with_addresses(
+            MsgAddressInt::with_standart(None, 0, AccountId::from([0xee; 32]))?,
+            MsgAddressInt::with_standart(None, 0, dst.clone())?,
+            CurrencyCollection::with_grams(1_000_000_000 + i as u64)
+        );
+        hdr.ihr_disabled = true;
+        hdr.bounce = false;
+        hdr.created_lt = start_lt + i as u64;
+        hdr.created_at = UnixTime32(gen_utime);
+        let env = MsgEnvelopeStuff::new(Message::with_int_header(hdr), &shard, Grams::default())?;
+        let msg = AsyncMessage::New(env, Cell::default());
+        exec_manager.execute(dst, msg, &prev_data, &mut collator_data).await?;
+    }
+    exec_manager.wait_transactions(&mut collator_data).await?;
+
+    let mut new_accounts = prev_data.accounts().clone();
+    let mut accounts = ShardAccountBlocks::default();
+    for (_, (sender, handle)) in exec_manager.changed_accounts.drain() {
+        std::mem::drop(sender);
+        let acc_block = handle.await??.update_shard_state(&mut new_accounts)?;
+        accounts.insert(&acc_block)?;
+    }
+    Ok(vec![
+        collator_data.in_msgs.serialize()?.repr_hash(),
+        collator_data.out_msgs.serialize()?.repr_hash(),
+        accounts.serialize()?.repr_hash(),
+        new_accounts.serialize()?.repr_hash(),
+    ])
+}
+
+#[test]
+fn test_parallel_execution_is_equivalent_to_sequential() {
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let sequential = execute_test_messages(false, 40).await.unwrap();
+        for _ in 0..3 {
+            assert_eq!(execute_test_messages(true, 40).await.unwrap(), sequential);
+        }
+    });
+}
+
+#[test]
+fn test_account_limits_defer_new_messages() {
+    struct TestEngine;
+    impl EngineOperations for TestEngine {}
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+        let prev_id = BlockIdExt::with_params(shard.clone(), 1, UInt256::default(), UInt256::default());
+        let mc_id = BlockIdExt::with_params(ShardIdent::masterchain(), 1, UInt256::default(), UInt256::default());
+        let limits = AccountSoftLimits { transactions: Some(3), gas: None, size: None };
+        let mut collator_settings = CollatorSettings::default();
+        collator_settings.account_limits = limits.clone();
+        let mut collator = Collator::new(
+            shard.clone(), mc_id, vec![prev_id.clone()], ValidatorSet::default(), UInt256::default(),
+            Arc::new(TestEngine), None, collator_settings
+        ).unwrap();
+        collator.cutoff_timeout = Duration::from_secs(60);
+
+        let mut state = ShardStateUnsplit::with_ident(shard.clone());
+        state.set_gen_time(1_600_000_000);
+        let state = ShardStateStuff::with_state(prev_id, state).unwrap();
+        let prev_data = PrevData::from_prev_states(
+            vec![state.clone()], vec![state], Cell::default(), None
+        ).unwrap();
+        let config = BlockchainConfig::default();
+        let gen_utime = 1_600_000_010;
+        let start_lt = 1_000_000;
+        let mut collator_data = CollatorData::new(
+            gen_utime, config.clone(), UsageTree::with_root(Cell::default()), &prev_data, false
+        ).unwrap();
+        collator_data.start_lt = Some(start_lt);
+        // Usage is known after finalization only, so with the single thread
+        // every transaction is finalized before the next message is dispatched
+        let mut exec_manager = ExecutionManager::new(
+            gen_utime, start_lt, UInt256::default(), Libraries::default(), config,
+            1, true, limits, Arc::new("test".to_string()), false,
+        ).unwrap();
+
+        // Hot account is the destination of three messages of every four
+        let hot = AccountId::from([1; 32]);
+        let other = AccountId::from([2; 32]);
+        for i in 0..12u64 {
+            let dst = if i % 4 == 3 { &other } else { &hot };
+            let mut hdr = InternalMessageHeader::with_addresses(
+                MsgAddressInt::with_standart(None, 0, AccountId::from([0xee; 32])).unwrap(),
+                MsgAddressInt::with_standart(None, 0, dst.clone()).unwrap(),
+                CurrencyCollection::with_grams(1_000_000_000 + i)
+            );
+            hdr.ihr_disabled = true;
+            hdr.bounce = false;
+            hdr.created_lt = start_lt + i;
+            hdr.created_at = UnixTime32(gen_utime);
+            let msg = Message::with_int_header(hdr);
+            let hash = msg.serialize().unwrap().repr_hash();
+            collator_data.new_messages.push(NewMessage::new((start_lt + i, hash), msg, Cell::default()));
+        }
+        collator.process_new_messages(false, &prev_data, &mut collator_data, &mut exec_manager).await.unwrap();
+
+        assert_eq!(exec_manager.accounts_usage[&hot].transactions(), 3);
+        assert_eq!(exec_manager.accounts_usage[&other].transactions(), 3);
+        // the rest of messages to the hot account are left for the next block
+        assert_eq!(collator_data.enqueue_count, 6);
+        assert!(collator_data.new_messages.is_empty());
+    });
 }
@@ -8,9 +8,10 @@ use std::{
 use crate::{
     CHECK,
     engine_traits::EngineOperations,
+    ext_messages::ExtMessageStatus,
     shard_state::ShardStateStuff,
     types::{
-        accounts::ShardAccountStuff,
+        accounts::{AccountSoftLimits, ShardAccountStuff},
         limits::BlockLimitStatus,
         messages::{MsgEnvelopeStuff, MsgEnqueueStuff},
         top_block_descr::{Mode as TbdMode, TopBlockDescrStuff, cmp_shard_block_descr},
@@ -292,6 +293,15 @@ impl CollatorData {
         self.changed_accounts.insert(acc.account_addr().clone(), acc);
     }
 
+    /// account has exceeded its soft limits in the block,
+    /// further messages to it should be left for the next block
+    fn is_deferred(&self, account_id: &AccountId, limits: &AccountSoftLimits) -> bool {
+        match self.changed_accounts.get(account_id) {
+            Some(acc) => acc.usage().exceeds(limits),
+            None => false
+        }
+    }
+
     fn in_msgs_root(&self) -> Result<Cell> {
         self.in_msgs.data().cloned().ok_or_else(|| error!("in msg descr is empty"))
     }
@@ -1092,7 +1102,7 @@ impl Collator {
            now > (prev_now / lifetime + 1) * lifetime + 20 {
 
             let overdue = now - (prev_now / lifetime + 1) * lifetime;
-            let mut rng = rand::thread_rng();
+            let mut rng = crate::rng::stream_for("collator", &self.shard);
             let skip_topmsgdescr = rng.gen_range(0, 1024) < 256; // probability 1/4
             let skip_extmsg = rng.gen_range(0, 1024) < 256; // skip ext msg probability 1/4
             if skip_topmsgdescr {
@@ -1115,7 +1125,7 @@ impl Collator {
             }
         } else if self.shard.is_masterchain() && now > prev_now + 60 {
             let interval = now - prev_now;
-            let mut rng = rand::thread_rng();
+            let mut rng = crate::rng::stream_for("collator", &self.shard);
             let skip_topmsgdescr = rng.gen_range(0, 1024) < 128; // probability 1/8
             let skip_extmsg = rng.gen_range(0, 1024) < 128; // skip ext msg probability 1/8
             if skip_topmsgdescr {
@@ -1599,7 +1609,9 @@ impl Collator {
         let config = collator_data.config.clone();
         let executor = OrdinaryTransactionExecutor::new(config);
 
+        let limits = &self.collator_settings.account_limits;
         let mut iter = output_queue_manager.merge_out_queue_iter(&self.shard)?;
+        let mut deferred = false;
         while let Some(k_v) = iter.next() {
             if collator_data.block_full {
                 log::trace!("{}: BLOCK FULL, stop processing internal messages", self.collated_block_descr);
@@ -1607,6 +1619,16 @@ impl Collator {
             }
             let (key, enq, created_lt, block_id) = k_v?;
             let account_id = enq.dst_account_id()?;
+            // Messages are processed strictly in order, so queue processing stops
+            // at the first message to the account which has exceeded its limits
+            if self.shard.contains_full_prefix(&enq.dst_prefix()) &&
+                collator_data.is_deferred(&account_id, limits)
+            {
+                log::debug!("{}: account {:x} exceeded its limits, stop processing internal messages",
+                    self.collated_block_descr, account_id);
+                deferred = true;
+                break
+            }
             collator_data.update_last_proc_int_msg(&account_id, (created_lt, enq.message_hash()))?;
             if collator_data.out_msg_queue_info.already_processed(&enq)? {
                 log::debug!("{}: inbound internal message with lt={} hash={:x} enqueued_lt={} \
@@ -1643,7 +1665,7 @@ impl Collator {
             }
         }
         // all internal messages are processed
-        collator_data.inbound_queues_empty = iter.next().is_none();
+        collator_data.inbound_queues_empty = !deferred && iter.next().is_none();
         Ok(())
     }
 
@@ -1694,6 +1716,8 @@ impl Collator {
         let executor = OrdinaryTransactionExecutor::new(config);
 
         let mut to_delay = vec![];
+        let mut collated = vec![];
+        let max_ext_messages = self.collator_settings.max_ext_messages.unwrap_or(usize::MAX);
         for (msg, id) in ext_messages.drain(..) {
             let header = msg.ext_in_header().ok_or_else(|| error!("message {:x} \
                 is not external inbound message", id))?;
@@ -1702,17 +1726,35 @@ impl Collator {
                     log::trace!("{}: BLOCK FULL, stop processing external messages", self.collated_block_descr);
                     break
                 }
+                if collated.len() + to_delay.len() >= max_ext_messages {
+                    log::debug!("{}: {} external messages are taken, stop processing external messages",
+                        self.collated_block_descr, collated.len() + to_delay.len());
+                    break
+                }
                 let (_, account_id) = header.dst.extract_std_address(true)?;
+                if collator_data.is_deferred(&account_id, &self.collator_settings.account_limits) {
+                    // the message stays in the pool for the next block
+                    log::trace!(
+                        "{}: account {:x} exceeded its limits, external message {:x} is deferred",
+                        self.collated_block_descr, account_id, id
+                    );
+                    continue
+                }
                 let msg_opt = Some(msg.as_ref());
                 match self.execute(&executor, account_id.clone(), msg_opt, prev_data, collator_data, req_lt) {
                     Err(err) => {
                         log::warn!("{}: account {:x} rejected inbound external message {:x} by reason: {}", 
                             self.collated_block_descr, account_id, id, err);
+                        self.engine.set_external_message_status(
+                            &id, 
+                            ExtMessageStatus::Rejected(format!("account rejected message: {}", err))
+                        );
                         to_delay.push(id);
                     }
                     Ok(transaction) => {
                         let in_msg = InMsg::external(&msg, &transaction)?;
                         collator_data.new_transaction(&transaction, Some(&in_msg))?;
+                        collated.push(id);
                     }
                 };
             } else {
@@ -1722,6 +1764,13 @@ impl Collator {
                 //to_delay.push(id);
             }
         }
+        for id in collated {
+            let status = ExtMessageStatus::Collated { 
+                shard: self.shard.clone(), 
+                seq_no: self.new_block_id_part.seq_no 
+            };
+            self.engine.set_external_message_status(&id, status);
+        }
         self.engine.complete_external_messages(to_delay, vec![])
     }
 
@@ -1739,7 +1788,13 @@ impl Collator {
             let info = msg.int_header().ok_or_else(|| error!("message is not internal"))?;
             let fwd_fee = info.fwd_fee().clone();
             enqueue_only |= collator_data.block_full;
-            let out_msg = if !self.shard.contains_address(&info.dst)? || enqueue_only {
+            let to_us = self.shard.contains_address(&info.dst)?;
+            // message to the account which exceeded its limits goes to the queue for the next block
+            let deferred = to_us && collator_data.is_deferred(
+                &msg.int_dst_account_id().unwrap_or_default(),
+                &self.collator_settings.account_limits
+            );
+            let out_msg = if !to_us || enqueue_only || deferred {
                 let enq = MsgEnqueueStuff::new(msg, &self.shard, fwd_fee)?;
                 collator_data.add_out_msg_to_state(&enq, true)?;
                 let out_msg = OutMsg::new(enq.envelope(), tr_cell)?;
@@ -2401,7 +2456,7 @@ impl Collator {
             )?;
         }
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::stream_for("collator", &self.shard);
         let key: [u8; 32] = rng.gen();
         let mut key: UInt256 = key.into();
         let mut scanned = 0;
@@ -2487,3 +2542,58 @@ impl Collator {
         }
     }
 }
+
+#[test]
+fn test_account_limits_defer_new_messages() {
+    struct TestEngine;
+    impl EngineOperations for TestEngine {}
+    let shard = ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap();
+    let prev_id = BlockIdExt::with_params(shard.clone(), 1, UInt256::default(), UInt256::default());
+    let mc_id = BlockIdExt::with_params(ShardIdent::masterchain(), 1, UInt256::default(), UInt256::default());
+    let mut collator_settings = CollatorSettings::default();
+    collator_settings.account_limits = AccountSoftLimits { transactions: Some(3), gas: None, size: None };
+    let collator = Collator::new(
+        shard.clone(), mc_id, vec![prev_id.clone()], ValidatorSet::default(), UInt256::default(),
+        Arc::new(TestEngine), None, collator_settings
+    ).unwrap();
+
+    let mut state = ShardStateUnsplit::with_ident(shard.clone());
+    state.set_gen_time(1_600_000_000);
+    let state = ShardStateStuff::with_state(prev_id, state).unwrap();
+    let prev_data = PrevData::from_prev_states(
+        vec![state.clone()], vec![state], Cell::default(), None
+    ).unwrap();
+    let gen_utime = 1_600_000_010;
+    let start_lt = 1_000_000;
+    let mut collator_data = CollatorData::new(
+        gen_utime, BlockchainConfig::default(), UsageTree::with_root(Cell::default()),
+        Libraries::default(), &prev_data, UInt256::default(), false, "test".to_string()
+    ).unwrap();
+    collator_data.start_lt = Some(start_lt);
+
+    // Hot account is the destination of three messages of every four
+    let hot = AccountId::from([1; 32]);
+    let other = AccountId::from([2; 32]);
+    for i in 0..12u64 {
+        let dst = if i % 4 == 3 { &other } else { &hot };
+        let mut hdr = InternalMessageHeader::with_addresses(
+            MsgAddressInt::with_standart(None, 0, AccountId::from([0xee; 32])).unwrap(),
+            MsgAddressInt::with_standart(None, 0, dst.clone()).unwrap(),
+            CurrencyCollection::with_grams(1_000_000_000 + i)
+        );
+        hdr.ihr_disabled = true;
+        hdr.bounce = false;
+        hdr.created_lt = start_lt + i;
+        hdr.created_at = UnixTime32(gen_utime);
+        let msg = Message::with_int_header(hdr);
+        let hash = msg.hash().unwrap();
+        collator_data.new_messages.push(NewMessage::new((start_lt + i, hash), msg, Cell::default()));
+    }
+    collator.process_new_messages(false, &prev_data, &mut collator_data, start_lt + 1).unwrap();
+
+    assert_eq!(collator_data.changed_accounts[&hot].usage().transactions(), 3);
+    assert_eq!(collator_data.changed_accounts[&other].usage().transactions(), 3);
+    // the rest of messages to the hot account are left for the next block
+    assert_eq!(collator_data.enqueue_count, 6);
+    assert!(collator_data.new_messages.is_empty());
+}
@@ -6,7 +6,7 @@ use std::{
 use super::validator_utils::{validator_query_candidate_to_validator_block_candidate, pairvec_to_cryptopair_vec};
 use crate::{
     collator_test_bundle::CollatorTestBundle, engine_traits::EngineOperations, 
-    validator::{CollatorSettings, validate_query::ValidateQuery, collator, collator_sync}
+    validator::{validate_query::ValidateQuery, collator, collator_sync, CollatorSettings}
 };
 use ton_block::{BlockIdExt, ShardIdent, ValidatorSet};
 use ton_types::{Result, UInt256};
@@ -36,7 +36,7 @@ pub async fn run_validate_query(
     );
 
     #[cfg(feature = "metrics")]
-    STATSD.incr(&format!("run_validators_{}", shard));
+    STATSD.shard(&shard).incr("run_validators");
 
     let test_bundles_config = &engine.test_bundles_config().validator;
     let validator_result = if !test_bundles_config.is_enable() {
@@ -68,6 +68,7 @@ pub async fn run_validate_query(
                 let id = block.block_id.clone();
                 if !CollatorTestBundle::exists(test_bundles_config.path(), &id) {
                     let path = test_bundles_config.path().to_string();
+                    let level = test_bundles_config.compression_level();
                     let engine = engine.clone();
                     tokio::spawn(
                         async move {
@@ -79,10 +80,13 @@ pub async fn run_validate_query(
                                 ),
                                 Ok(mut b) => {
                                     b.set_notes(err_str);
-                                    if let Err(e) = b.save(&path) {
-                                        log::error!("Error while test bundle for {} saving: {}", id, e)
-                                    } else {
-                                        log::info!("Built test bundle for {}", id)
+                                    match b.save(&path, Some(level)) {
+                                        Err(e) => log::error!(
+                                            "Error while test bundle for {} saving: {}", id, e
+                                        ),
+                                        Ok(size) => log::info!(
+                                            "Built test bundle for {} ({} bytes)", id, size
+                                        )
                                     }
                                 }
                             }
@@ -95,17 +99,22 @@ pub async fn run_validate_query(
     };
 
     #[cfg(feature = "metrics")]
-    STATSD.decr(&format!("run_validators_{}", shard));
+    STATSD.shard(&shard).decr("run_validators");
 
     match validator_result {
         Ok(_) => {
             #[cfg(feature = "metrics")]
-            STATSD.incr(&format!("succeessful_validations_{}", shard));
+            STATSD.shard(&shard).incr("succeessful_validations");
             Ok(SystemTime::now())
         }
         Err(e) =>  {
-            #[cfg(feature = "metrics")]
-            STATSD.incr(&format!("failed_validations_{}", shard));
+            #[cfg(feature = "metrics")] {
+                STATSD.shard(&shard).incr("failed_validations");
+                let reason = super::reject_reason::CandidateRejectReason::from_error(&e)
+                    .map(|reason| reason.name())
+                    .unwrap_or("unknown");
+                STATSD.tag("reason", reason).shard(&shard).incr("rejected_candidates");
+            }
 
             #[cfg(feature = "telemetry")]
             engine.validator_telemetry().failed_attempt(&shard, &e.to_string());
@@ -148,11 +157,12 @@ pub async fn run_collate_query (
     collator_id: PublicKey,
     set: ValidatorSet,
     engine: Arc<dyn EngineOperations>,
+    collator_settings: CollatorSettings,
     timeout: u32,
 ) -> Result<ValidatorBlockCandidate>
 {
     #[cfg(feature = "metrics")]
-    STATSD.incr(&format!("run_collators_{}", shard));
+    STATSD.shard(&shard).incr("run_collators");
 
     let collator_result = if cfg!(feature = "async_collator") {
         let collator = collator::Collator::new(
@@ -163,7 +173,7 @@ pub async fn run_collate_query (
             UInt256::from(collator_id.pub_key()?),
             engine.clone(),
             None,
-            CollatorSettings::default()
+            collator_settings
         )?;
         collator.collate(timeout).await
     } else {
@@ -175,24 +185,24 @@ pub async fn run_collate_query (
             UInt256::from(collator_id.pub_key()?),
             engine.clone(),
             None,
-            CollatorSettings::default()
+            collator_settings
         )?;
         collator.collate().await
     };
 
     #[cfg(feature = "metrics")]
-    STATSD.decr(&format!("run_collators_{}", shard));
+    STATSD.shard(&shard).decr("run_collators");
 
     match collator_result {
         Ok((candidate, _)) => {
             #[cfg(feature = "metrics")]
-            STATSD.incr(&format!("succeessful_collations_{}", shard));
+            STATSD.shard(&shard).incr("succeessful_collations");
 
             return Ok(validator_query_candidate_to_validator_block_candidate(collator_id, candidate))
         }
         Err(err) => {
             #[cfg(feature = "metrics")]
-            STATSD.incr(&format!("failed_collations_{}", shard));
+            STATSD.shard(&shard).incr("failed_collations");
             let test_bundles_config = &engine.test_bundles_config().collator;
 
             let err_str = if cfg!(feature = "local_test") || test_bundles_config.is_enable() {
@@ -214,16 +224,21 @@ pub async fn run_collate_query (
                     };
                     if !CollatorTestBundle::exists(test_bundles_config.path(), &id) {
                         let path = test_bundles_config.path().to_string();
+                        let level = test_bundles_config.compression_level();
+                    let level = test_bundles_config.compression_level();
                         let engine = engine.clone();
                         tokio::spawn(async move {
                             match CollatorTestBundle::build_for_collating_block(prev, engine.deref()).await {
                                 Err(e) => log::error!("Error while test bundle for {} building: {}", id, e),
                                 Ok(mut b) => {
                                     b.set_notes(err_str);
-                                    if let Err(e) = b.save(&path) {
-                                        log::error!("Error while test bundle for {} saving: {}", id, e);
-                                    } else {
-                                        log::info!("Built test bundle for {}", id);
+                                    match b.save(&path, Some(level)) {
+                                        Err(e) => log::error!(
+                                            "Error while test bundle for {} saving: {}", id, e
+                                        ),
+                                        Ok(size) => log::info!(
+                                            "Built test bundle for {} ({} bytes)", id, size
+                                        )
                                     }
                                 }
                             }
@@ -2,7 +2,7 @@ pub mod validate_query;
 mod fabric;
 mod log_parser;
 pub mod accept_block;
-mod validator_group;
+pub mod validator_group;
 pub mod validator_utils;
 pub mod validator_manager;
 pub mod validator_session_listener;
@@ -10,6 +10,8 @@ pub mod candidate_db;
 pub mod collator;
 pub mod collator_sync;
 pub mod out_msg_queue;
+pub mod reject_reason;
+pub mod timings;
 #[cfg(feature = "telemetry")]
 pub mod telemetry;
 #[cfg(feature = "slashing")]
@@ -20,7 +22,7 @@ use ton_block::{
     BlkMasterInfo, BlockIdExt, ConfigParams, CurrencyCollection,
     ExtBlkRef, McStateExtra, Libraries,
 };
-use crate::shard_state::ShardStateStuff;
+use crate::{shard_state::ShardStateStuff, types::accounts::AccountSoftLimits};
 
 #[derive(Clone, Default, Debug)]
 pub struct BlockCandidate {
@@ -36,6 +38,13 @@ pub struct CollatorSettings {
     pub want_split: Option<bool>,
     pub want_merge: Option<bool>,
     pub max_collate_threads: Option<usize>,
+    // Accounts are executed in parallel unless it is set to false, block is the same anyway
+    pub parallel_execution: Option<bool>,
+    #[serde(default)]
+    pub account_limits: AccountSoftLimits,
+    // Limits count of external messages taken into block,
+    // is set when oversized candidate is collated again
+    pub max_ext_messages: Option<usize>,
 }
 
 pub struct McData {
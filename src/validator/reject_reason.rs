@@ -0,0 +1,106 @@
+// Reason of candidate rejection produced by the validation. Its text form is sent
+// as reason of catchain reject, so other validators can parse it back.
+#[derive(Clone, Debug, PartialEq, failure::Fail)]
+pub enum CandidateRejectReason {
+    #[fail(display = "bad proof: {}", _0)]
+    BadProof(String),
+    #[fail(display = "state mismatch: {}", _0)]
+    StateMismatch(String),
+    #[fail(display = "limit exceeded: {}", _0)]
+    LimitExceeded(String),
+    #[fail(display = "ext message invalid at index {}: {}", index, reason)]
+    ExtMessageInvalid { index: usize, reason: String },
+    #[fail(display = "internal error: {}", _0)]
+    Internal(String),
+}
+
+const EXT_MESSAGE_INVALID_PREFIX: &str = "ext message invalid at index ";
+
+impl CandidateRejectReason {
+
+    // Stable name for metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            CandidateRejectReason::BadProof(_) => "bad_proof",
+            CandidateRejectReason::StateMismatch(_) => "state_mismatch",
+            CandidateRejectReason::LimitExceeded(_) => "limit_exceeded",
+            CandidateRejectReason::ExtMessageInvalid {..} => "ext_message_invalid",
+            CandidateRejectReason::Internal(_) => "internal",
+        }
+    }
+
+    pub fn from_error(err: &failure::Error) -> Option<Self> {
+        err.downcast_ref::<Self>().cloned()
+    }
+
+    // Keeps reason already given by the failed check, other errors get the default one
+    pub fn classify(err: &failure::Error, default: fn(String) -> Self) -> Self {
+        Self::from_error(err).unwrap_or_else(|| default(err.to_string()))
+    }
+
+    // Parses reason received with catchain reject, None for other nodes' free-form reasons
+    pub fn parse(reason: &str) -> Option<Self> {
+        if let Some(rest) = reason.strip_prefix(EXT_MESSAGE_INVALID_PREFIX) {
+            let mut parts = rest.splitn(2, ": ");
+            let (index, reason) = (parts.next()?, parts.next()?);
+            return Some(CandidateRejectReason::ExtMessageInvalid {
+                index: index.parse().ok()?,
+                reason: reason.to_string()
+            })
+        }
+        let mut parts = reason.splitn(2, ": ");
+        let (kind, reason) = (parts.next()?, parts.next()?.to_string());
+        match kind {
+            "bad proof" => Some(CandidateRejectReason::BadProof(reason)),
+            "state mismatch" => Some(CandidateRejectReason::StateMismatch(reason)),
+            "limit exceeded" => Some(CandidateRejectReason::LimitExceeded(reason)),
+            "internal error" => Some(CandidateRejectReason::Internal(reason)),
+            _ => None
+        }
+    }
+}
+
+// Maps error of the validation stage onto the reject reason
+pub fn reject_as(err: failure::Error, default: fn(String) -> CandidateRejectReason) -> failure::Error {
+    CandidateRejectReason::classify(&err, default).into()
+}
+
+#[test]
+fn test_reject_reason_payload() {
+    let reasons = vec![
+        CandidateRejectReason::BadProof("wrong root hash".to_string()),
+        CandidateRejectReason::StateMismatch("new state: value flow: 1 != 2".to_string()),
+        CandidateRejectReason::LimitExceeded("lt delta 10 > 5".to_string()),
+        CandidateRejectReason::ExtMessageInvalid { index: 7, reason: "not in shard: x".to_string() },
+        CandidateRejectReason::Internal("shard_states_awaiters: timeout".to_string()),
+    ];
+    for reason in reasons {
+        let err: failure::Error = reason.clone().into();
+        // catchain reject takes the error's text
+        let payload = format!("{}", err);
+        assert_eq!(CandidateRejectReason::parse(&payload), Some(reason.clone()));
+        assert_eq!(CandidateRejectReason::from_error(&err), Some(reason));
+    }
+    assert_eq!(CandidateRejectReason::parse("Stop flag was set"), None);
+    assert_eq!(CandidateRejectReason::parse("ext message invalid at index x: y"), None);
+}
+
+#[test]
+fn test_reject_reason_classify() {
+    use crate::error::NodeError;
+
+    // reason given by the check is kept
+    let err = reject_as(
+        CandidateRejectReason::LimitExceeded("lt".to_string()).into(),
+        CandidateRejectReason::StateMismatch
+    );
+    assert_eq!(CandidateRejectReason::from_error(&err).unwrap().name(), "limit_exceeded");
+
+    // plain rejects of the stage get its reason
+    let err = reject_as(
+        NodeError::ValidatorReject("bad hash".to_string()).into(), CandidateRejectReason::BadProof
+    );
+    assert_eq!(CandidateRejectReason::from_error(&err).unwrap().name(), "bad_proof");
+    let err = reject_as(failure::err_msg("db is closed"), CandidateRejectReason::Internal);
+    assert_eq!(CandidateRejectReason::from_error(&err).unwrap().name(), "internal");
+}
@@ -67,7 +67,7 @@ impl SlashingManager {
 
         log::info!(target: "validator", "Use slashing report function '{}' with id={:08X}", ELECTOR_REPORT_FUNC_NAME, report_fn.get_function_id());
 
-        let mut rng = rand::thread_rng();
+        let mut rng = crate::rng::stream("slashing");
 
         use rand::Rng;
 
@@ -390,7 +390,7 @@ impl SlashingManager {
                         .insert(message_id.clone(), message.clone());
                 }
 
-                if let Err(err) = engine.redirect_external_message(&serialized_message).await {
+                if let Err(err) = engine.redirect_external_message(&serialized_message, true).await {
                     log::warn!(target: "validator", "{}({}): SlashingManager::slash_validator: can't send message: {:?}, error: {:?}", file!(), line!(), message, err);
                 } else {
                     log::info!(target: "validator", "{}({}): SlashingManager::slash_validator: message: {:?} -> {} has been successfully {}", file!(), line!(), message_id, base64::encode(&serialized_message),
@@ -479,7 +479,7 @@ impl SlashingManager {
         pipeline.gauge("validation_score", validation_score);
         pipeline.gauge("slashing_score", slashing_score);
 
-        pipeline.send(&STATSD);
+        pipeline.send();
     }
 }
 
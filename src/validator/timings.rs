@@ -0,0 +1,170 @@
+use crate::engine::STATSD;
+use std::{collections::HashMap, sync::Mutex, time::{Duration, SystemTime, UNIX_EPOCH}};
+use ton_block::ShardIdent;
+
+// Percentiles are taken over this number of last operations of the shard
+const HISTOGRAM_LEN: usize = 128;
+const PERCENTILES: [u32; 3] = [50, 95, 99];
+
+// Ring buffer of the last durations (ms) and time of the last operation
+pub struct TimingHistogram {
+    durations: Vec<u64>,
+    capacity: usize,
+    next: usize,
+    last_time: u64,
+}
+
+impl TimingHistogram {
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            durations: Vec::with_capacity(capacity),
+            capacity: capacity.max(1),
+            next: 0,
+            last_time: 0,
+        }
+    }
+
+    pub fn push(&mut self, duration_ms: u64, at: u64) {
+        if self.durations.len() < self.capacity {
+            self.durations.push(duration_ms);
+        } else {
+            self.durations[self.next] = duration_ms;
+        }
+        self.next = (self.next + 1) % self.capacity;
+        self.last_time = self.last_time.max(at);
+    }
+
+    pub fn count(&self) -> usize {
+        self.durations.len()
+    }
+
+    // Unixtime of the last operation, 0 - never
+    pub fn last_time(&self) -> u64 {
+        self.last_time
+    }
+
+    // Nearest-rank percentiles, empty if nothing is recorded
+    pub fn percentiles(&self, percents: &[u32]) -> Vec<u64> {
+        if self.durations.is_empty() {
+            return Vec::new()
+        }
+        let mut sorted = self.durations.clone();
+        sorted.sort_unstable();
+        percents.iter().map(|p| {
+            let rank = (*p as usize * sorted.len() + 99) / 100;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        }).collect()
+    }
+}
+
+// Per-shard timings of validation of others' candidates or of our own collations
+pub struct ShardTimings {
+    // Prefix of metrics
+    name: &'static str,
+    shards: Mutex<HashMap<ShardIdent, TimingHistogram>>,
+}
+
+impl ShardTimings {
+
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            shards: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn report(&self, shard: &ShardIdent, duration: Duration) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut shards = self.shards.lock().unwrap();
+        let histogram = shards.entry(shard.clone())
+            .or_insert_with(|| TimingHistogram::with_capacity(HISTOGRAM_LEN));
+        histogram.push(duration.as_millis() as u64, now);
+        let values = histogram.percentiles(&PERCENTILES);
+        for (p, value) in PERCENTILES.iter().zip(values) {
+            STATSD.shard(shard).gauge(&format!("{}_time_p{}", self.name, p), value as f64);
+        }
+    }
+
+    // Shard is shown in status even if nothing is reported for it yet
+    pub fn add(&self, shard: &ShardIdent) {
+        self.shards.lock().unwrap().entry(shard.clone())
+            .or_insert_with(|| TimingHistogram::with_capacity(HISTOGRAM_LEN));
+    }
+
+    pub fn last_time(&self, shard: &ShardIdent) -> u64 {
+        self.shards.lock().unwrap().get(shard).map(|h| h.last_time()).unwrap_or(0)
+    }
+
+    pub fn remove(&self, shard: &ShardIdent) {
+        self.shards.lock().unwrap().remove(shard);
+    }
+
+    // One line per shard: time since the last operation and percentiles of durations
+    pub fn status(&self, now: u64) -> String {
+        let shards = self.shards.lock().unwrap();
+        let mut status = String::new();
+        for (shard, histogram) in shards.iter() {
+            if histogram.count() == 0 {
+                status.push_str(&format!("shard: {} - never\n", shard));
+                continue
+            }
+            let values = histogram.percentiles(&PERCENTILES);
+            status.push_str(&format!(
+                "shard: {} - {} sec ago, p50 {} ms, p95 {} ms, p99 {} ms of last {}\n",
+                shard, now.saturating_sub(histogram.last_time()),
+                values[0], values[1], values[2], histogram.count()
+            ));
+        }
+        status
+    }
+}
+
+#[test]
+fn test_timing_histogram_percentiles() {
+    let mut histogram = TimingHistogram::with_capacity(100);
+    assert!(histogram.percentiles(&PERCENTILES).is_empty());
+    histogram.push(42, 10);
+    assert_eq!(histogram.percentiles(&[0, 50, 100]), vec![42, 42, 42]);
+
+    let mut histogram = TimingHistogram::with_capacity(100);
+    for i in (1..=100).rev() {
+        histogram.push(i, 1000 - i);
+    }
+    assert_eq!(histogram.percentiles(&PERCENTILES), vec![50, 95, 99]);
+    assert_eq!(histogram.percentiles(&[1, 100]), vec![1, 100]);
+    assert_eq!(histogram.last_time(), 999);
+
+    // the oldest durations are replaced
+    for _ in 0..90 {
+        histogram.push(1000, 500);
+    }
+    assert_eq!(histogram.count(), 100);
+    assert_eq!(histogram.percentiles(&[10, 11, 50]), vec![10, 1000, 1000]);
+    assert_eq!(histogram.last_time(), 999);
+
+    let mut histogram = TimingHistogram::with_capacity(4);
+    for d in &[5, 1, 3, 2] {
+        histogram.push(*d, 1);
+    }
+    assert_eq!(histogram.percentiles(&[25, 50, 75, 100]), vec![1, 2, 3, 5]);
+}
+
+#[test]
+fn test_shard_timings_status() {
+    let timings = ShardTimings::new("validation");
+    let shard = ShardIdent::masterchain();
+    assert_eq!(timings.last_time(&shard), 0);
+    assert_eq!(timings.status(0), "");
+    timings.add(&shard);
+    assert_eq!(timings.status(0), format!("shard: {} - never\n", shard));
+    timings.report(&shard, Duration::from_millis(250));
+    assert!(timings.last_time(&shard) > 0);
+    let now = timings.last_time(&shard) + 3;
+    assert_eq!(
+        timings.status(now),
+        format!("shard: {} - 3 sec ago, p50 250 ms, p95 250 ms, p99 250 ms of last 1\n", shard)
+    );
+    timings.remove(&shard);
+    assert_eq!(timings.status(now), "");
+}
@@ -14,7 +14,10 @@ use crate::{
         supported_version, supported_capabilities,
     },
 };
-use super::{BlockCandidate, McData, validator_utils::calc_subset_for_workchain};
+use super::{
+    BlockCandidate, McData, validator_utils::calc_subset_for_workchain,
+    reject_reason::{reject_as, CandidateRejectReason},
+};
 use std::{collections::HashMap, io::Cursor, sync::{atomic::{AtomicU32, AtomicU64, Ordering}, Arc}};
 use ton_block::{
     AddSub, BlockError, HashmapAugType, Deserializable, Serializable,
@@ -70,6 +73,12 @@ macro_rules! reject_query {
     }
 }
 
+macro_rules! reject_query_as {
+    ($reason:ident, $($arg:tt)*) => {
+        return Err(failure::Error::from(CandidateRejectReason::$reason(format!("=====> {}:{} {}", file!(), line!(), format_args!($($arg)*)))))
+    }
+}
+
 macro_rules! soft_reject_query {
     ($($arg:tt)*) => {
         return Err(failure::Error::from(NodeError::ValidatorSoftReject(format!("=====> {}:{} {}", file!(), line!(), format_args!($($arg)*)))))
@@ -307,7 +316,8 @@ impl ValidateQuery {
         // 3. load state(s) corresponding to previous block(s)
         for i in 0..base.prev_blocks_ids.len() {
             log::debug!(target: "validate_query", "load state for prev block {} of {} {}", i + 1, base.prev_blocks_ids.len(), base.prev_blocks_ids[i]);
-            let prev_state = self.engine.load_state(&base.prev_blocks_ids[i]).await?;
+            let prev_state = self.engine.load_state(&base.prev_blocks_ids[i]).await
+                .map_err(|err| reject_as(err, CandidateRejectReason::Internal))?;
             if &self.shard == prev_state.shard() && prev_state.state().before_split() {
                 reject_query!("cannot accept new unsplit shardchain block for {} \
                     after previous block {} with before_split set", self.shard, prev_state.block_id())
@@ -317,9 +327,11 @@ impl ValidateQuery {
         // 5. request masterchain state referred to in the block
         if !base.shard().is_masterchain() {
             // 5.1. request corresponding block handle
-            let handle = self.engine.load_block_handle(mc_data.state.block_id())?.ok_or_else(
-                || error!("Cannot load handle for masterblock {}", mc_data.state.block_id())
-            )?;
+            let handle = self.engine.load_block_handle(mc_data.state.block_id())
+                .and_then(|handle| handle.ok_or_else(
+                    || error!("Cannot load handle for masterblock {}", mc_data.state.block_id())
+                ))
+                .map_err(|err| reject_as(err, CandidateRejectReason::Internal))?;
             if !self.is_fake && !handle.has_proof() && handle.id().seq_no() != 0 {
                 reject_query!("reference masterchain block {} for block {} does not have a valid proof",
                     handle.id(), base.block_id())
@@ -479,9 +491,9 @@ impl ValidateQuery {
 
     async fn get_ref_mc_state(&mut self, base: &ValidateBase) -> Result<McData> {
         let mc_state = match base.info.read_master_ref()? {
-            Some(master_ref) => self.engine.load_state(&master_ref.master.master_block_id().1).await?,
-            None => self.engine.load_state(&base.prev_blocks_ids[0]).await?
-        };
+            Some(master_ref) => self.engine.load_state(&master_ref.master.master_block_id().1).await,
+            None => self.engine.load_state(&base.prev_blocks_ids[0]).await
+        }.map_err(|err| reject_as(err, CandidateRejectReason::Internal))?;
         log::debug!(target: "validate_query", "in ValidateQuery::get_ref_mc_state() {}", mc_state.block_id());
         if mc_state.state().seq_no() < self.min_mc_seq_no {
             reject_query!("requested to validate a block referring to an unknown future masterchain block {} < {}",
@@ -1124,12 +1136,12 @@ impl ValidateQuery {
                 base.info.start_lt(), lt_bound + 1)
         }
         if base.shard().is_masterchain() && base.info.start_lt() - gen_lt > base.config_params.get_max_lt_growth() {
-            reject_query!("block increases logical time from previous state by {} which exceeds the limit ({})",
+            reject_query_as!(LimitExceeded, "block increases logical time from previous state by {} which exceeds the limit ({})",
                 base.info.start_lt() - gen_lt, base.config_params.get_max_lt_growth())
         }
         let delta_hard = base.config_params.block_limits(base.shard().is_masterchain())?.lt_delta().hard_limit() as u64;
         if base.info.end_lt() - base.info.start_lt() > delta_hard {
-            reject_query!("block increased logical time by {} which is larger than the hard limit {}",
+            reject_query_as!(LimitExceeded, "block increased logical time by {} which is larger than the hard limit {}",
                 base.info.end_lt() - base.info.start_lt(), delta_hard)
         }
     Ok(())
@@ -2004,13 +2016,28 @@ impl ValidateQuery {
 
     fn check_in_msg_descr(base: Arc<ValidateBase>, manager: Arc<MsgQueueManager>, tasks: &mut Vec<Box<dyn FnOnce() -> Result<()> + Send + 'static>>) -> Result<()> {
         log::debug!(target: "validate_query", "checking inbound messages listed in InMsgDescr");
+        let mut ext_index = 0;
         base.in_msg_descr.iterate_with_keys(|key, in_msg| {
             let base = base.clone();
             let manager = manager.clone();
+            // inbound external messages are numbered in order of InMsgDescr
+            let ext_index = match in_msg {
+                InMsg::External(_) => {
+                    ext_index += 1;
+                    Some(ext_index - 1)
+                }
+                _ => None
+            };
             Self::add_task(tasks, move || {
-                Self::check_in_msg(&base, &manager, &key, &in_msg)
-                    .map_err(|err| error!("invalid InMsg with key (message hash) {} in the new block {} : {}",
-                        key.to_hex_string(), base.block_id(), err))
+                Self::check_in_msg(&base, &manager, &key, &in_msg).map_err(|err| match ext_index {
+                    Some(index) => CandidateRejectReason::ExtMessageInvalid {
+                        index,
+                        reason: format!("inbound external message {} in the new block {} : {}",
+                            key.to_hex_string(), base.block_id(), err)
+                    }.into(),
+                    None => error!("invalid InMsg with key (message hash) {} in the new block {} : {}",
+                        key.to_hex_string(), base.block_id(), err)
+                })
             });
             Ok(true)
         }).map_err(|err| error!("invalid InMsgDescr dictionary in the new block {} : {}", base.block_id(), err))?;
@@ -3756,7 +3783,8 @@ impl ValidateQuery {
             let tasks = tasks.into_iter().map(|t| tokio::task::spawn_blocking(t));
             futures::future::join_all(tasks).await
                 .into_iter().find(|r| r.is_err())
-                .unwrap_or(Ok(Ok(())))??;
+                .unwrap_or(Ok(Ok(())))
+                .map_err(|err| CandidateRejectReason::Internal(err.to_string()))??;
         } else {
             for task in tasks {
                 task()?;
@@ -3769,10 +3797,15 @@ impl ValidateQuery {
         log::trace!("VALIDATE {}", self.block_candidate.as_ref().unwrap().block_id);
         let now = std::time::Instant::now();
 
-        let (base, mc_data) = self.common_preparation().await?;
+        // every stage rejects with its own reason unless the failed check gives more exact one
+        let bad_proof = |err| reject_as(err, CandidateRejectReason::BadProof);
+        let state_mismatch = |err| reject_as(err, CandidateRejectReason::StateMismatch);
 
-        let manager = self.init_output_queue_manager(&mc_data, &base).await?;
-        self.check_shard_layout(&base, &mc_data)?;
+        let (base, mc_data) = self.common_preparation().await.map_err(bad_proof)?;
+
+        let manager = self.init_output_queue_manager(&mc_data, &base).await
+            .map_err(|err| reject_as(err, CandidateRejectReason::Internal))?;
+        self.check_shard_layout(&base, &mc_data).map_err(bad_proof)?;
         check_cur_validator_set(
             &self.validator_set,
             base.block_id(),
@@ -3782,8 +3815,8 @@ impl ValidateQuery {
             &base.config_params,
             base.now(),
             base.is_fake
-        )?;
-        self.check_utime_lt(&base, &mc_data)?;
+        ).map_err(bad_proof)?;
+        self.check_utime_lt(&base, &mc_data).map_err(bad_proof)?;
         // stage 1
         // log::debug!(target: "validate_query", "running letmated validity checks for block candidate {}", base.block_id());
         // if (!block::gen::t_Block.validate_ref(1000000, block_root_)) {
@@ -3797,28 +3830,31 @@ impl ValidateQuery {
         Self::add_task(&mut tasks, move || Self::precheck_value_flow(b));
         let b = base.clone();
         Self::add_task(&mut tasks, move || Self::precheck_account_updates(b));
-        Self::precheck_account_transactions(base.clone(), &mut tasks)?;
-        Self::precheck_message_queue_update(base.clone(), &manager, &mut tasks)?;
+        Self::precheck_account_transactions(base.clone(), &mut tasks).map_err(state_mismatch)?;
+        Self::precheck_message_queue_update(base.clone(), &manager, &mut tasks).map_err(state_mismatch)?;
 
-        Self::check_in_msg_descr(base.clone(), manager.clone(), &mut tasks)?;
-        Self::check_out_msg_descr(base.clone(), manager.clone(), &mut tasks)?;
-        Self::check_transactions(base.clone(), mc_data.libraries().clone(), &mut tasks)?;
+        Self::check_in_msg_descr(base.clone(), manager.clone(), &mut tasks).map_err(state_mismatch)?;
+        Self::check_out_msg_descr(base.clone(), manager.clone(), &mut tasks).map_err(state_mismatch)?;
+        Self::check_transactions(base.clone(), mc_data.libraries().clone(), &mut tasks)
+            .map_err(state_mismatch)?;
 
-        self.run_tasks(tasks).await?;
+        self.run_tasks(tasks).await.map_err(state_mismatch)?;
 
         let mut base = Arc::try_unwrap(base)
-            .map_err(|base| error!("Somebody haven't released Arc: strong: {} weak: {}",
-                Arc::strong_count(&base), Arc::weak_count(&base)))?;
-
-        Self::check_processed_upto(&base, &manager, &mc_data)?;
-        Self::check_in_queue(&base, &manager)?;
-        Self::check_delivered_dequeued(&base, &manager)?;
-        Self::check_all_ticktock_processed(&base)?;
-        Self::check_message_processing_order(&mut base)?;
-        Self::check_special_messages(&base)?;
-        Self::check_new_state(&mut base, &mc_data, &manager)?;
-        Self::check_mc_block_extra(&base, &mc_data)?;
-        self.check_mc_state_extra(&base, &mc_data)?;
+            .map_err(|base| CandidateRejectReason::Internal(
+                format!("Somebody haven't released Arc: strong: {} weak: {}",
+                    Arc::strong_count(&base), Arc::weak_count(&base))
+            ))?;
+
+        Self::check_processed_upto(&base, &manager, &mc_data).map_err(state_mismatch)?;
+        Self::check_in_queue(&base, &manager).map_err(state_mismatch)?;
+        Self::check_delivered_dequeued(&base, &manager).map_err(state_mismatch)?;
+        Self::check_all_ticktock_processed(&base).map_err(state_mismatch)?;
+        Self::check_message_processing_order(&mut base).map_err(state_mismatch)?;
+        Self::check_special_messages(&base).map_err(state_mismatch)?;
+        Self::check_new_state(&mut base, &mc_data, &manager).map_err(state_mismatch)?;
+        Self::check_mc_block_extra(&base, &mc_data).map_err(state_mismatch)?;
+        self.check_mc_state_extra(&base, &mc_data).map_err(state_mismatch)?;
 
         let duration = now.elapsed().as_millis() as u64;
         let gas_used = base.gas_used.load(Ordering::Relaxed);
@@ -3830,7 +3866,7 @@ impl ValidateQuery {
         log::info!("ASYNC VALIDATED {} TIME {}ms GAS_RATE: {}", base.block_id(), duration, ratio);
 
         #[cfg(feature = "metrics")]
-        STATSD.gauge(&format!("gas_rate_validator_{}", base.block_id().shard()), ratio as f64);
+        STATSD.shard(base.block_id().shard()).gauge("gas_rate_validator", ratio as f64);
 
         #[cfg(not(test))]
         #[cfg(feature = "telemetry")]
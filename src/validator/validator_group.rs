@@ -1,11 +1,10 @@
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::sync::*;
-use std::sync::atomic::{Ordering, AtomicU64};
 use std::time::*;
 use crossbeam_channel::Receiver;
-use tokio::{runtime::Runtime, sync::Mutex};
+use tokio::{runtime::Runtime, sync::{broadcast, Mutex}};
 
-use crate::engine_traits::{EngineOperations, PrivateOverlayOperations};
+use crate::{block::BlockStuff, engine_traits::{EngineOperations, PrivateOverlayOperations}};
 use catchain::utils::get_hash;
 use ton_block::{BlockIdExt, ShardIdent, ValidatorSet};
 use ton_types::{fail, Result, UInt256};
@@ -13,7 +12,7 @@ use validator_session::{
     BlockHash, BlockPayloadPtr, CatchainOverlayManagerPtr, CatchainOverlayPtr, CatchainNode,
     SessionId, SlashingValidatorStat, SessionPtr, SessionListenerPtr, SessionFactory,
     SessionListener, SessionNode,SessionOptions,
-    PublicKey, PublicKeyHash,
+    PublicKey, PublicKeyHash, ValidatorBlockCandidate,
     ValidatorBlockCandidateCallback, ValidatorBlockCandidateDecisionCallback,
 };
 use validator_utils::{
@@ -28,8 +27,18 @@ use validator_session_listener::{
 use super::*;
 use super::fabric::*;
 use super::candidate_db::CandidateDb;
+use super::reject_reason::CandidateRejectReason;
 #[cfg(feature = "slashing")]
 use crate::validator::slashing::SlashingManagerPtr;
+#[cfg(feature = "metrics")]
+use crate::engine::STATSD;
+
+const COLLATION_TIMEOUT_MS: u32 = 1000;
+// Oversized candidate is collated again at most this number of times
+const MAX_SHRINK_ATTEMPTS: u32 = 2;
+// Next attempt takes this share (percents) of what would just fit the limits
+const SHRINK_MARGIN_PERCENT: u64 = 80;
+const VALIDATION_HISTORY_LEN: usize = 32;
 
 struct CatchainOverlayManagerImpl {
     network: Weak<dyn PrivateOverlayOperations>,
@@ -102,6 +111,79 @@ impl ValidatorGroupStatus {
             _ => (self <= of)
         }
     }
+
+    /// Stable status name (without countdown details), suitable for metrics and external DB
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValidatorGroupStatus::Created => "created",
+            ValidatorGroupStatus::Countdown {..} => "countdown",
+            ValidatorGroupStatus::Active => "active",
+            ValidatorGroupStatus::Stopping => "stopping",
+            ValidatorGroupStatus::Stopped => "stopped"
+        }
+    }
+}
+
+/// Notification about validator group status change
+#[derive(Clone)]
+pub struct ValidatorGroupStatusEvent {
+    pub session_id: SessionId,
+    pub shard: ShardIdent,
+    pub old_status: ValidatorGroupStatus,
+    pub new_status: ValidatorGroupStatus,
+    pub timestamp: u64, // unix time, milliseconds
+}
+
+pub type ValidatorGroupStatusSender = broadcast::Sender<ValidatorGroupStatusEvent>;
+
+impl std::fmt::Display for ValidatorGroupStatusEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "session_id {:x}, shard {}, {} -> {}, at {}",
+            self.session_id, self.shard, self.old_status.name(), self.new_status.name(), self.timestamp
+        )
+    }
+}
+
+fn notify_status_changed(
+    sender: &ValidatorGroupStatusSender,
+    session_id: &SessionId,
+    shard: &ShardIdent,
+    old_status: ValidatorGroupStatus,
+    new_status: ValidatorGroupStatus
+) {
+    if old_status == new_status {
+        return
+    }
+    let event = ValidatorGroupStatusEvent {
+        session_id: session_id.clone(),
+        shard: shard.clone(),
+        old_status,
+        new_status,
+        timestamp: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default().as_millis() as u64
+    };
+    // Error means there are no subscribers at the moment, it is not a problem
+    let _ = sender.send(event);
+}
+
+// Result of validation of somebody's candidate
+#[derive(Clone)]
+pub struct ValidationRecord {
+    pub round: u32,
+    pub source: PublicKeyHash,
+    pub root_hash: BlockHash,
+    pub reject_reason: Option<CandidateRejectReason>,
+}
+
+impl ValidationRecord {
+    // Line for GetStats
+    pub fn status(&self) -> String {
+        let result = match &self.reject_reason {
+            None => "accepted".to_string(),
+            Some(reason) => format!("rejected as {} ({})", reason.name(), reason)
+        };
+        format!("round {} candidate {:x} from {}: {}", self.round, self.root_hash, self.source, result)
+    }
 }
 
 pub struct ValidatorGroupImpl {
@@ -119,6 +201,8 @@ pub struct ValidatorGroupImpl {
     replay_finished: bool,
     on_generate_slot_invoked: bool,
     on_candidate_invoked: bool,
+    // recent validations, the oldest first
+    validation_history: std::collections::VecDeque<ValidationRecord>,
 
     status: ValidatorGroupStatus
 }
@@ -230,6 +314,7 @@ impl ValidatorGroupImpl {
 
             on_candidate_invoked: false,
             on_generate_slot_invoked: false,
+            validation_history: Default::default(),
             replay_finished: false
         }
     }
@@ -254,6 +339,13 @@ impl ValidatorGroupImpl {
         })
     }
 
+    fn add_validation_record(&mut self, record: ValidationRecord) {
+        if self.validation_history.len() >= VALIDATION_HISTORY_LEN {
+            self.validation_history.pop_front();
+        }
+        self.validation_history.push_back(record);
+    }
+
     pub fn update_round(&mut self, round: u32) -> (u32, Vec<BlockIdExt>, Option<BlockIdExt>, SystemTime)
     {
         self.last_known_round = max(self.last_known_round, round);
@@ -279,8 +371,7 @@ pub struct ValidatorGroup {
 
     #[cfg(feature = "slashing")]
     slashing_manager: SlashingManagerPtr,
-    last_validation_time: AtomicU64,
-    last_collation_time: AtomicU64,
+    status_sender: ValidatorGroupStatusSender,
 }
 
 impl ValidatorGroup {
@@ -295,6 +386,7 @@ impl ValidatorGroup {
         allow_unsafe_self_blocks_resync: bool,
         #[cfg(feature = "slashing")]
         slashing_manager: SlashingManagerPtr,
+        status_sender: ValidatorGroupStatusSender,
     ) -> Self {
         let group_impl = ValidatorGroupImpl::new(shard.clone(), session_id.clone(), 
             engine.db_root_dir().expect("Can't get db_root_dir from engine"));
@@ -314,8 +406,7 @@ impl ValidatorGroup {
             receiver: Arc::new(receiver),
             #[cfg(feature = "slashing")]
             slashing_manager,
-            last_validation_time: AtomicU64::new(0),
-            last_collation_time: AtomicU64::new(0)
+            status_sender,
         }
     }
 
@@ -323,14 +414,6 @@ impl ValidatorGroup {
         &self.shard
     }
 
-    pub fn last_validation_time(&self) -> u64 {
-        self.last_validation_time.load(Ordering::Relaxed)
-    }
-
-    pub fn last_collation_time(&self) -> u64 {
-        self.last_collation_time.load(Ordering::Relaxed)
-    }
-
     pub fn make_validator_session_callback(&self) -> SessionListenerPtr {
         Arc::downgrade(&self.callback)
     }
@@ -354,10 +437,16 @@ impl ValidatorGroup {
 
                 let callback = self.make_validator_session_callback();
                 let mut group_impl = self.group_impl.lock().await;
+                let old_status = group_impl.status;
                 if group_impl.status > ValidatorGroupStatus::Active {
                     log::trace!(target: "validator", "Session deleted before countdown: {}", self.info().await);
-                } else if let Err(e) = group_impl.start(callback, prev, min_masterchain_block_id, min_ts, self.clone(), rt) {
-                    log::error!(target: "validator", "{}", e);
+                } else {
+                    if let Err(e) = group_impl.start(callback, prev, min_masterchain_block_id, min_ts, self.clone(), rt) {
+                        log::error!(target: "validator", "{}", e);
+                    }
+                    notify_status_changed(
+                        &self.status_sender, &self.session_id, &self.shard, old_status, group_impl.status
+                    );
                 }
             }
         );
@@ -365,7 +454,8 @@ impl ValidatorGroup {
         Ok(())
     }
 
-    pub async fn stop(self: Arc<ValidatorGroup>, rt: Arc<Runtime>) -> Result<()> {
+    // Catchain DB is kept if the session is to be continued after restart
+    pub async fn stop(self: Arc<ValidatorGroup>, rt: Arc<Runtime>, destroy_db: bool) -> Result<()> {
         self.set_status(ValidatorGroupStatus::Stopping).await?;
         let group_impl = self.group_impl.clone();
         rt.spawn({
@@ -380,7 +470,9 @@ impl ValidatorGroup {
                 }
                 log::info!(target: "validator", "Group stopped: {}", self.info().await);
                 let _ = self.set_status(ValidatorGroupStatus::Stopped).await;
-                let _ = self.destroy_db().await;
+                if destroy_db {
+                    let _ = self.destroy_db().await;
+                }
             }
         });
         Ok(())
@@ -397,7 +489,9 @@ impl ValidatorGroup {
     pub async fn set_status(&self, status: ValidatorGroupStatus) -> Result<()> {
         let mut group_impl = self.group_impl.lock().await;
         if group_impl.status.before(&status) {
+            let old_status = group_impl.status;
             group_impl.status = status;
+            notify_status_changed(&self.status_sender, &self.session_id, &self.shard, old_status, status);
             Ok(())
         } else {
             fail!("Status cannot retreat, from {} to {}", group_impl.status, status)
@@ -417,6 +511,10 @@ impl ValidatorGroup {
         self.group_impl.lock().await.info()
     }
 
+    pub async fn validation_history(&self) -> Vec<ValidationRecord> {
+        self.group_impl.lock().await.validation_history.iter().cloned().collect()
+    }
+
     pub async fn on_generate_slot(&self, round: u32, callback: ValidatorBlockCandidateCallback) {
         log::info!(
             target: "validator", 
@@ -426,34 +524,20 @@ impl ValidatorGroup {
 
         let (_lk_round, prev_block_ids, mm_block_id, min_ts) = self.group_impl.lock().await.update_round (round);
 
+        let started = Instant::now();
         let result = match mm_block_id {
-            Some(mc) => {
-                match run_collate_query (
-                    self.shard,
-                    min_ts,
-                    mc.clone(),
-                    prev_block_ids,
-                    self.local_key.clone(),
-                    self.validator_set.clone(),
-                    self.engine.clone(),
-                    1000,
-                ).await {
-                    Ok(b) => Ok(Arc::new(b)),
-                    Err(x) => Err(x)
-                }
-            }
+            Some(mc) => self.collate_within_limits(min_ts, mc, prev_block_ids).await,
             None => Err(failure::err_msg("Min masterchain block id missing")),
         };
         let result_message = match &result {
-            Ok(_) => {
-                let now = std::time::SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
-                self.last_collation_time.fetch_max(now, Ordering::Relaxed);
+            Ok((_, limits)) => {
+                self.engine.collation_status().report(&self.shard, started.elapsed());
 
-                format!("Collation successful")
+                format!("Collation successful, {}", limits)
             }
             Err(x) => format!("Collation failed: `{}`", x)
         };
+        let result = result.map(|(candidate, _)| Arc::new(candidate));
         log::info!(target: "validator", "SessionListener::on_generate_slot: {}, {}",
             self.info_round(round).await, result_message
         );
@@ -462,6 +546,72 @@ impl ValidatorGroup {
         callback(result)
     }
 
+    // Other validators reject candidate exceeding size limits of the session, so it is
+    // collated again with fewer external messages and earlier cutoff instead of proposing.
+    // Returns candidate and description of limits it was collated with.
+    async fn collate_within_limits(
+        &self,
+        min_ts: SystemTime,
+        mc: BlockIdExt,
+        prev_block_ids: Vec<BlockIdExt>,
+    ) -> Result<(ValidatorBlockCandidate, String)> {
+        let mut settings = self.engine.collator_settings();
+        let mut timeout_ms = COLLATION_TIMEOUT_MS;
+        let mut attempt = 0;
+        loop {
+            let candidate = run_collate_query(
+                self.shard,
+                min_ts,
+                mc.clone(),
+                prev_block_ids.clone(),
+                self.local_key.clone(),
+                self.validator_set.clone(),
+                self.engine.clone(),
+                settings.clone(),
+                timeout_ms,
+            ).await?;
+            let limits = format!(
+                "limits: ext messages {:?}, cutoff {}ms", settings.max_ext_messages, timeout_ms
+            );
+            let block_size = candidate.data.data().len() as u64;
+            let collated_size = candidate.collated_data.data().len() as u64;
+            let fits_percent = min(
+                self.config.max_block_size as u64 * 100 / max(block_size, 1),
+                self.config.max_collated_data_size as u64 * 100 / max(collated_size, 1)
+            );
+            if fits_percent >= 100 {
+                return Ok((candidate, limits))
+            }
+
+            #[cfg(feature = "metrics")]
+            STATSD.shard(&self.shard).incr("shrunk_candidates");
+
+            if attempt == MAX_SHRINK_ATTEMPTS {
+                fail!(
+                    "candidate is too big after {} attempts: size {}, collated data size {}, {}",
+                    attempt + 1, block_size, collated_size, limits
+                )
+            }
+            attempt += 1;
+            let block_id = BlockIdExt::with_params(
+                self.shard, 0, candidate.id.root_hash.clone(), candidate.id.file_hash.clone()
+            );
+            let block = BlockStuff::deserialize(block_id, candidate.data.data().to_vec())?;
+            let ext_messages = block.ext_in_messages()?.len() as u64;
+            let share = fits_percent * SHRINK_MARGIN_PERCENT / 100;
+            settings.max_ext_messages = Some((ext_messages * share / 100) as usize);
+            timeout_ms = max(1, (timeout_ms as u64 * share / 100) as u32);
+            log::warn!(
+                target: "validator",
+                "Candidate is too big: size {} (max {}), collated data size {} (max {}), \
+                {} external messages, {}; collating again with ext messages {:?}, cutoff {}ms",
+                block_size, self.config.max_block_size,
+                collated_size, self.config.max_collated_data_size,
+                ext_messages, limits, settings.max_ext_messages, timeout_ms
+            );
+        }
+    }
+
     // Validate_query
     pub async fn on_candidate(
         &self,
@@ -473,6 +623,7 @@ impl ValidatorGroup {
         callback: ValidatorBlockCandidateDecisionCallback,
     ) {
         let candidate_id = format!("source {}, rh {:x}", source.id(), root_hash);
+        let candidate_root_hash = root_hash.clone();
         log::trace!(target: "validator", "SessionListener::on_candidate: {}, {}",
             candidate_id, self.info_round(round).await);
 
@@ -484,6 +635,7 @@ impl ValidatorGroup {
             created_by: UInt256::from(source.pub_key().expect("source must contain pub_key")),
         };
 
+        let started = Instant::now();
         let result = {
             let (prev_block_ids, mm_block_id, min_ts) = {
                 let mut group_impl = self.group_impl.lock().await;
@@ -529,10 +681,7 @@ impl ValidatorGroup {
 
                 match &res {
                     Ok(()) => {
-                        self.last_validation_time.fetch_max(
-                            x.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
-                            Ordering::Relaxed
-                        );
+                        self.engine.validation_status().report(&self.shard, started.elapsed());
                         format!("Validation successful: finished at {:?}", x)
                     },
                     Err(x) => format!("Validation successful, db error `{}`", x)
@@ -540,7 +689,25 @@ impl ValidatorGroup {
             },
             Err(x) => format!("Validation failed with verdict `{}`", x),
         };
-        self.group_impl.lock().await.on_candidate_invoked = true;
+        let reject_reason = match &result {
+            Ok(_) => None,
+            // errors not classified by validation are our own problems
+            Err(x) => Some(CandidateRejectReason::classify(x, CandidateRejectReason::Internal))
+        };
+        if let Some(reason) = &reject_reason {
+            log::warn!(target: "validator", "SessionListener::on_candidate: {} rejected as {}",
+                candidate_id, reason.name());
+        }
+        {
+            let mut group_impl = self.group_impl.lock().await;
+            group_impl.on_candidate_invoked = true;
+            group_impl.add_validation_record(ValidationRecord {
+                round,
+                source: source.id().clone(),
+                root_hash: candidate_root_hash,
+                reject_reason,
+            });
+        }
 
         log::info!(target: "validator", "SessionListener::on_candidate: {}, {}, {}",
             candidate_id, self.info_round(round).await, result_message
@@ -681,3 +848,49 @@ impl Drop for ValidatorGroup {
     }
 }
 
+
+#[test]
+fn test_status_events_order() {
+    let (sender, mut receiver) = broadcast::channel(16);
+    let session_id = SessionId::from([1; 32]);
+    let shard = ShardIdent::masterchain();
+    let lifecycle = [
+        ValidatorGroupStatus::Created,
+        ValidatorGroupStatus::Countdown { start_at: tokio::time::Instant::now() },
+        ValidatorGroupStatus::Active,
+        ValidatorGroupStatus::Stopping,
+        ValidatorGroupStatus::Stopped
+    ];
+    let mut status = lifecycle[0];
+    for next in lifecycle.iter().skip(1) {
+        assert!(status.before(next));
+        notify_status_changed(&sender, &session_id, &shard, status, *next);
+        status = *next;
+    }
+    // Unchanged status must not produce any event
+    notify_status_changed(&sender, &session_id, &shard, status, status);
+
+    for pair in lifecycle.windows(2) {
+        let event = receiver.try_recv().expect("status event expected");
+        assert_eq!(event.session_id, session_id);
+        assert_eq!(event.shard, shard);
+        assert_eq!(event.old_status.name(), pair[0].name());
+        assert_eq!(event.new_status.name(), pair[1].name());
+    }
+    assert!(receiver.try_recv().is_err());
+}
+
+#[test]
+fn test_validation_record_status() {
+    let mut record = ValidationRecord {
+        round: 7,
+        source: adnl::common::KeyId::from_data([1; 32]),
+        root_hash: UInt256::from([2; 32]),
+        reject_reason: None,
+    };
+    let status = record.status();
+    assert!(status.starts_with("round 7 candidate 0202"), "{}", status);
+    assert!(status.ends_with(": accepted"), "{}", status);
+    record.reject_reason = Some(CandidateRejectReason::LimitExceeded("gas".to_string()));
+    assert!(record.status().ends_with(": rejected as limit_exceeded (limit exceeded: gas)"));
+}
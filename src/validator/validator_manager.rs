@@ -3,14 +3,17 @@ use std::{
     convert::TryFrom,
     ops::Deref,
     sync::Arc,
-    time::{Duration, SystemTime}
+    time::{Duration, Instant, SystemTime}
 };
 use crate::{
+    config::ValidatorManagerConfig as ValidatorManagerGeneralConfig,
     engine::STATSD,
     engine_traits::EngineOperations,
-    shard_state::ShardStateStuff,
+    shard_state::ShardStateStuff, shutdown::SHUTDOWN,
     validator::{
-        validator_group::{ValidatorGroup, ValidatorGroupStatus},
+        validator_group::{
+            ValidatorGroup, ValidatorGroupStatus, ValidatorGroupStatusEvent, ValidatorGroupStatusSender
+        },
         validator_utils::{
             calc_subset_for_workchain,
             validatordescr_to_catchain_node,
@@ -22,9 +25,10 @@ use crate::{
 };
 #[cfg(feature = "slashing")]
 use crate::validator::slashing::{SlashingManager, SlashingManagerPtr};
-use catchain::{CatchainNode, PublicKey};
+use catchain::{CatchainNode, PublicKey, PublicKeyHash};
 use catchain::utils::serialize_tl_boxed_object;
-use tokio::{time::timeout, runtime::Runtime};
+use futures::future::Either;
+use tokio::{time::timeout, runtime::Runtime, sync::broadcast};
 use ton_api::IntoBoxed;
 use ton_block::{
     BlockIdExt, CatchainConfig, ConfigParamEnum, ConsensusConfig, 
@@ -33,6 +37,9 @@ use ton_block::{
 };
 use ton_types::{error, fail, Result, UInt256};
 
+// Local ADNL keys may be changed at runtime, so they are checked again with this interval
+const ADNL_KEYS_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
 fn get_validator_set_id_serialize(
     shard: &ShardIdent,
     val_set: &ValidatorSet,
@@ -125,13 +132,15 @@ fn get_session_options(opts: &ConsensusConfig) -> validator_session::SessionOpti
 }
 
 struct ValidatorManagerConfig {
-    update_interval: Duration
+    update_interval: Duration,
+    status_events_capacity: usize,
 }
 
 impl Default for ValidatorManagerConfig {
     fn default() -> Self {
         return ValidatorManagerConfig {
-            update_interval: Duration::from_secs(3)
+            update_interval: Duration::from_secs(3),
+            status_events_capacity: 256,
         }
     }
 }
@@ -221,6 +230,12 @@ struct ValidatorManagerImpl {
     #[cfg(feature = "slashing")]
     slashing_manager: SlashingManagerPtr,
     validation_status: ValidationStatus,
+    status_sender: ValidatorGroupStatusSender,
+    adnl_unreachable_lists: HashSet<ValidatorListHash>,
+    // ADNL key of the local validator and list name for every list it is in
+    local_adnl_ids: HashMap<ValidatorListHash, (PublicKeyHash, &'static str)>,
+    adnl_keys_checked_at: Option<Instant>,
+    validators_overlay: bool,
 }
 
 // struct ValidatorManagerData {
@@ -229,22 +244,60 @@ struct ValidatorManagerImpl {
 
 impl ValidatorManagerImpl {
 
-    fn new(engine: Arc<dyn EngineOperations>) -> Self {
-        let rt = tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .thread_stack_size(8 * 1024 * 1024)
-            .build()
-            .expect("Can't create validator groups runtime");
+    fn new(engine: Arc<dyn EngineOperations>, general_config: &ValidatorManagerGeneralConfig) -> Result<Self> {
+        let runtime = general_config.runtime.clone().unwrap_or_default();
+        log::info!(target: "validator", "Validator groups runtime: {}", runtime);
+        let rt = runtime.build()
+            .map_err(|e| error!("Can't create validator groups runtime: {}", e))?;
+        engine.set_validator_runtime_workers(rt.metrics().num_workers());
+        let rt = Arc::new(rt);
+
+        let config = ValidatorManagerConfig::default();
+        let (status_sender, _) = broadcast::channel(config.status_events_capacity);
 
-        ValidatorManagerImpl {
+        let manager = ValidatorManagerImpl {
             engine,
-            rt: Arc::new(rt),
+            rt,
             validator_sessions: HashMap::default(),
             validator_list_status: ValidatorListStatus::default(),
-            config: ValidatorManagerConfig::default(),
+            config,
             validation_status: ValidationStatus::Disabled,
             #[cfg(feature = "slashing")]
             slashing_manager: SlashingManager::create(),
+            status_sender,
+            adnl_unreachable_lists: HashSet::new(),
+            local_adnl_ids: HashMap::new(),
+            adnl_keys_checked_at: None,
+            validators_overlay: general_config.validators_overlay,
+        };
+        manager.start_status_subscribers();
+        Ok(manager)
+    }
+
+    /// Subscribes to validator groups status changes
+    pub fn subscribe_status_events(&self) -> broadcast::Receiver<ValidatorGroupStatusEvent> {
+        self.status_sender.subscribe()
+    }
+
+    fn start_status_subscribers(&self) {
+        start_status_subscriber("log", self.subscribe_status_events(), &self.rt, |event| {
+            log::info!(target: "validator", "Validator group status changed: {}", event);
+            None
+        });
+        start_status_subscriber("statsd", self.subscribe_status_events(), &self.rt, |event| {
+            STATSD.tag("status", event.new_status.name()).incr("validator_group_status");
+            None
+        });
+        #[cfg(feature = "external_db")] {
+            let engine = self.engine.clone();
+            start_status_subscriber("external_db", self.subscribe_status_events(), &self.rt, move |event| {
+                let engine = engine.clone();
+                Some(Box::pin(async move {
+                    if let Err(e) = engine.process_validator_group_status_in_ext_db(&event).await {
+                        log::warn!(target: "validator", "Can't put status event into external db: {}", e);
+                    }
+                }))
+            });
         }
     }
 
@@ -265,7 +318,7 @@ impl ValidatorManagerImpl {
         None
     }
 
-    async fn update_single_validator_list(&mut self, validator_list: &[ValidatorDescr], name: &str)
+    async fn update_single_validator_list(&mut self, validator_list: &[ValidatorDescr], name: &'static str)
     -> Result<Option<ValidatorListHash>> {
         let list_id = match compute_validator_list_id(validator_list) {
             None => return Ok(None),
@@ -294,6 +347,14 @@ impl ValidatorManagerImpl {
                     hex::encode(key.pub_key().unwrap()),
                     hex::encode(key.id().data())
                 );
+                self.check_local_adnl_key(&list_id, &key, &nodes_res, name).await;
+                if self.validators_overlay {
+                    if let Err(e) = self.engine.clone().start_validators_overlay(&list_id) {
+                        log::warn!(
+                            target: "validator", "Can't start validators overlay of {} list: {}", name, e
+                        );
+                    }
+                }
                 return Ok(Some(list_id));
             },
             None => {
@@ -303,6 +364,76 @@ impl ValidatorManagerImpl {
         }
     }
 
+    /// Checks that ADNL key of the local validator is actually served by the node
+    async fn check_local_adnl_key(
+        &mut self,
+        list_id: &ValidatorListHash,
+        key: &PublicKey,
+        nodes: &[CatchainNode],
+        name: &'static str
+    ) {
+        let adnl_id = match nodes.iter().find(|node| node.public_key.id() == key.id()) {
+            Some(node) => node.adnl_id.clone(),
+            None => {
+                log::warn!(target: "validator", "Local node is not found in {} validator list", name);
+                return
+            }
+        };
+        self.local_adnl_ids.insert(list_id.clone(), (adnl_id, name));
+        self.check_list_adnl_key(list_id).await;
+    }
+
+    async fn check_list_adnl_key(&mut self, list_id: &ValidatorListHash) {
+        let (adnl_id, name) = match self.local_adnl_ids.get(list_id) {
+            Some((adnl_id, name)) => (adnl_id.clone(), *name),
+            None => return
+        };
+        match self.engine.validator_network().check_local_adnl_key(&adnl_id).await {
+            Ok(()) => {
+                if self.adnl_unreachable_lists.remove(list_id) {
+                    log::info!(target: "validator", "Local ADNL key {} became reachable", adnl_id);
+                } else {
+                    log::debug!(target: "validator", "Local ADNL key {} is reachable", adnl_id);
+                }
+            },
+            Err(e) => {
+                log::error!(
+                    target: "validator", 
+                    "Local node is in {} validator list, but its ADNL key {} is unreachable: {}",
+                    name, adnl_id, e
+                );
+                self.adnl_unreachable_lists.insert(list_id.clone());
+            }
+        }
+    }
+
+    async fn recheck_local_adnl_keys(&mut self) {
+        if let Some(checked_at) = self.adnl_keys_checked_at {
+            if checked_at.elapsed() < ADNL_KEYS_CHECK_INTERVAL {
+                return
+            }
+        }
+        self.adnl_keys_checked_at = Some(Instant::now());
+        let lists = [&self.validator_list_status.curr, &self.validator_list_status.next]
+            .iter()
+            .filter_map(|list| list.as_ref().cloned())
+            .collect::<Vec<_>>();
+        for list_id in lists {
+            self.check_list_adnl_key(&list_id).await;
+        }
+        self.report_adnl_unreachable();
+    }
+
+    fn report_adnl_unreachable(&mut self) {
+        let actual_lists = [&self.validator_list_status.curr, &self.validator_list_status.next];
+        let is_actual = |id: &ValidatorListHash| actual_lists.iter().any(|l| l.as_ref() == Some(id));
+        self.adnl_unreachable_lists.retain(|id| is_actual(id));
+        self.local_adnl_ids.retain(|id, _| is_actual(id));
+        let adnl_unreachable = !self.adnl_unreachable_lists.is_empty();
+        STATSD.gauge("validator_adnl_unreachable", if adnl_unreachable { 1 } else { 0 } as f64);
+        self.engine.set_validator_adnl_unreachable(adnl_unreachable);
+    }
+
     async fn update_validator_lists(&mut self, mc_state: &ShardStateStuff) -> Result<bool> {
         let (validator_set, next_validator_set) = match mc_state.state().read_custom()? {
             None => return Ok(false),
@@ -317,6 +448,8 @@ impl ValidatorManagerImpl {
 
         STATSD.gauge("in_current_vset_p34", if self.validator_list_status.curr.is_some() { 1 } else { 0 } as f64);
         STATSD.gauge("in_next_vset_p36", if self.validator_list_status.next.is_some() { 1 } else { 0 } as f64);
+
+        self.report_adnl_unreachable();
         return Ok(!self.validator_list_status.curr.is_none() || !self.validator_list_status.next.is_none());
     }
 
@@ -332,6 +465,9 @@ impl ValidatorManagerImpl {
             if !self.validator_list_status.actual_or_coming (&id) {
                 log::trace!(target: "validator", "Removing validator list: {:x}", id);
                 self.validator_list_status.remove_list(&id);
+                if self.validators_overlay {
+                    self.engine.stop_validators_overlay(&id);
+                }
                 self.engine.remove_validator_list(id.clone()).await?;
                 log::trace!(target: "validator", "Validator list removed: {:x}", id);
             } else {
@@ -362,7 +498,7 @@ impl ValidatorManagerImpl {
                             }
                         }
                         _ => {
-                            if let Err(e) = session.clone().stop(self.rt.clone()).await {
+                            if let Err(e) = session.clone().stop(self.rt.clone(), true).await {
                                 log::error!(target: "validator",
                                     "Could not stop session {:x}: `{}`", id, e);
                                     self.validator_sessions.remove(id);
@@ -374,6 +510,34 @@ impl ValidatorManagerImpl {
         }
     }
 
+    // Sessions are stopped on shutdown keeping their catchain DBs to go on after restart
+    async fn stop_all_sessions(&mut self) -> Result<()> {
+        const SESSIONS_STOP_CHECK_INTERVAL_MS: u64 = 100;
+        log::info!(target: "validator", "Stopping {} validator sessions", self.validator_sessions.len());
+        for (id, session) in self.validator_sessions.iter() {
+            match session.get_status().await {
+                ValidatorGroupStatus::Stopping | ValidatorGroupStatus::Stopped => {}
+                _ => if let Err(e) = session.clone().stop(self.rt.clone(), false).await {
+                    log::error!(target: "validator", "Could not stop session {:x}: `{}`", id, e);
+                }
+            }
+        }
+        loop {
+            let mut stopping = 0;
+            for session in self.validator_sessions.values() {
+                if session.get_status().await != ValidatorGroupStatus::Stopped {
+                    stopping += 1;
+                }
+            }
+            if stopping == 0 {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(SESSIONS_STOP_CHECK_INTERVAL_MS)).await;
+        }
+        log::info!(target: "validator", "All validator sessions are stopped");
+        Ok(())
+    }
+
     async fn compute_session_options(&mut self, mc_state_extra: &McStateExtra)
     -> Result<(validator_session::SessionOptions, UInt256)> {
         let consensus_config = match mc_state_extra.config.config(29)? {
@@ -516,6 +680,7 @@ impl ValidatorManagerImpl {
                         false,
                         #[cfg(feature = "slashing")]
                         slashing_manager,
+                        self.status_sender.clone(),
                     ))
                 );
                 let session_status = session.get_status().await;
@@ -536,8 +701,8 @@ impl ValidatorManagerImpl {
         Ok(())
     }
 
-    async fn update_shards(&mut self, mc_state: ShardStateStuff) -> Result<()> {
-        if !self.update_validator_lists(&mc_state).await? {
+    async fn update_shards(&mut self, mc_state: &ShardStateStuff) -> Result<()> {
+        if !self.update_validator_lists(mc_state).await? {
             log::info!("Current validator list is empty, validation is disabled.");
             self.disable_validation().await?;
             return Ok(())
@@ -556,7 +721,7 @@ impl ValidatorManagerImpl {
         let catchain_config = mc_state_extra.config.catchain_config()?;
 
         self.enable_validation();
-        self.update_validation_status(&mc_state, &mc_state_extra).await?;
+        self.update_validation_status(mc_state, &mc_state_extra).await?;
 
         // Collect info about shards
         let mut gc_validator_sessions: HashSet<UInt256> =
@@ -713,6 +878,7 @@ impl ValidatorManagerImpl {
                             false,
                             #[cfg(feature = "slashing")]
                             self.slashing_manager.clone(),
+                            self.status_sender.clone(),
                         ));
                         self.validator_sessions.insert(session_id, session);
                     }
@@ -737,16 +903,23 @@ impl ValidatorManagerImpl {
         log::info!(target: "validator", "{:-64}", "");
 
         // Validation shards statistics
+        let mut history = String::new();
         for (_, group) in self.validator_sessions.iter() {
             log::info!(target: "validator", "{}", group.info().await);
             let status = group.get_status().await;
             if status == ValidatorGroupStatus::Active || status == ValidatorGroupStatus::Stopping {
-                self.engine.validation_status().insert(group.shard().clone(), group.last_validation_time());
-                self.engine.collation_status().insert(group.shard().clone(), group.last_collation_time());
+                self.engine.validation_status().add(group.shard());
+                self.engine.collation_status().add(group.shard());
+                for record in group.validation_history().await {
+                    history.push_str(&format!("{} {}\n", group.shard(), record.status()));
+                }
             }
         }
+        self.engine.set_validation_history_status(history);
 
         log::info!(target: "validator", "{:-64}", "");
+
+        STATSD.gauge("validator_runtime_alive_tasks", self.rt.metrics().num_alive_tasks() as f64);
     }
 
     /// infinte loop with possible error cancelation
@@ -771,41 +944,172 @@ impl ValidatorManagerImpl {
         let mut mc_handle = self.engine.load_block_handle(&mc_block_id)?.ok_or_else(
             || error!("Cannot load handle for master block {}", mc_block_id)
         )?;
+        let mut _pinned_mc_state = None;
         loop {
-            let mc_state = self.engine.load_state(mc_handle.id()).await?;
+            // Sessions are configured from this state, it is kept in cache until the next one
+            _pinned_mc_state = self.engine.pin_state(mc_handle.id());
+            let mc_state = self.engine.load_state_cached(mc_handle.id()).await?;
             log::info!(target: "validator", "Processing masterblock {}", mc_handle.id().seq_no);
             #[cfg(feature = "slashing")]
             if let Some(local_id) = self.validator_list_status.get_local_key() {
                 log::debug!(target: "validator", "Processing slashing masterblock {}", mc_handle.id().seq_no);
                 self.slashing_manager.handle_masterchain_block(&mc_handle, &mc_state, &local_id, &self.engine).await;
             }
-            self.update_shards(mc_state).await?;
+            self.update_shards(&mc_state).await?;
             
             mc_handle = loop {
+                self.recheck_local_adnl_keys().await;
                 self.stats().await;
-                match timeout(self.config.update_interval, self.engine.wait_next_applied_mc_block(&mc_handle, None)).await {
-                    Ok(r_res) => break r_res?.0,
-                    Err(tokio::time::error::Elapsed{..}) => {
+                let next = timeout(
+                    self.config.update_interval,
+                    self.engine.wait_next_applied_mc_block(&mc_handle, None)
+                );
+                match futures::future::select(Box::pin(next), Box::pin(SHUTDOWN.wait())).await {
+                    Either::Left((Ok(r_res), _)) => break r_res?.0,
+                    Either::Left((Err(tokio::time::error::Elapsed{..}), _)) => {
                         log::warn!(target: "validator", "Validator manager didn't receive next applied master block after {}", mc_handle.id());
                     }
+                    Either::Right(_) => return self.stop_all_sessions().await
                 }
             };
         }
     }
 }
 
+fn start_status_subscriber<F>(
+    name: &'static str,
+    mut receiver: broadcast::Receiver<ValidatorGroupStatusEvent>,
+    rt: &Runtime,
+    mut handler: F
+) where F: FnMut(ValidatorGroupStatusEvent) -> Option<futures::future::BoxFuture<'static, ()>> + Send + 'static {
+    rt.spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => if let Some(future) = handler(event) {
+                    future.await
+                },
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!(target: "validator", "Status subscriber {} skipped {} events", name, skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break
+            }
+        }
+    });
+}
+
 /// main entry point to validation process
-pub fn start_validator_manager(engine: Arc<dyn EngineOperations>) {
+// Runs until shutdown, the error is the one of validator manager
+pub async fn run_validator_manager(
+    engine: Arc<dyn EngineOperations>,
+    general_config: ValidatorManagerGeneralConfig
+) -> Result<()> {
     const CHECK_VALIDATOR_TIMEOUT: u64 = 60;    //secs
-    tokio::spawn(async move {
-        while !engine.get_validator_status() {
-            log::trace!("is not a validator");
-            tokio::time::sleep(Duration::from_secs(CHECK_VALIDATOR_TIMEOUT)).await;
+    while !engine.get_validator_status() {
+        log::trace!("is not a validator");
+        tokio::time::sleep(Duration::from_secs(CHECK_VALIDATOR_TIMEOUT)).await;
+    }
+    // Shutdown waits for the sessions to be stopped
+    let _running = SHUTDOWN.component("validator manager");
+    if SHUTDOWN.is_requested() {
+        return Ok(())
+    }
+    log::info!("starting validator manager...");
+    let result = match ValidatorManagerImpl::new(engine, &general_config) {
+        Ok(mut manager) => manager.invoke().await,
+        Err(e) => Err(e)
+    };
+    if let Err(e) = &result {
+        log::error!(target: "validator", "FATAL!!! Unexpected error in validator manager: {:?}", e);
+    }
+    result
+}
+
+
+#[test]
+fn test_recheck_local_adnl_keys() {
+    use crate::engine_traits::PrivateOverlayOperations;
+    use adnl::common::{KeyId, KeyOption};
+    use catchain::{CatchainOverlay, CatchainOverlayListenerPtr, CatchainOverlayLogReplayListenerPtr};
+    use overlay::PrivateOverlayShortId;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct TestNetwork {
+        reachable: AtomicBool
+    }
+    #[async_trait::async_trait]
+    impl PrivateOverlayOperations for TestNetwork {
+        async fn set_validator_list(
+            &self,
+            _validator_list_id: UInt256,
+            _validators: &Vec<CatchainNode>
+        ) -> Result<Option<Arc<KeyOption>>> {
+            unimplemented!()
         }
-        log::info!("starting validator manager...");
-        if let Err(e) = ValidatorManagerImpl::new(engine).invoke().await {
-            log::error!(target: "validator", "FATAL!!! Unexpected error in validator manager: {:?}", e);
+        fn activate_validator_list(&self, _validator_list_id: UInt256) -> Result<()> {
+            unimplemented!()
         }
-    });
-}
+        async fn remove_validator_list(&self, _validator_list_id: UInt256) -> Result<bool> {
+            unimplemented!()
+        }
+        fn create_catchain_client(
+            &self,
+            _validator_list_id: UInt256,
+            _overlay_short_id : &Arc<PrivateOverlayShortId>,
+            _nodes_public_keys : &Vec<CatchainNode>,
+            _listener : CatchainOverlayListenerPtr,
+            _log_replay_listener: CatchainOverlayLogReplayListenerPtr
+        ) -> Result<Arc<dyn CatchainOverlay + Send>> {
+            unimplemented!()
+        }
+        fn stop_catchain_client(&self, _overlay_short_id: &Arc<PrivateOverlayShortId>) {
+            unimplemented!()
+        }
+        async fn check_local_adnl_key(&self, adnl_id: &Arc<KeyId>) -> Result<()> {
+            if !self.reachable.load(Ordering::Relaxed) {
+                fail!("ADNL key {} is not resolvable via DHT", adnl_id)
+            }
+            Ok(())
+        }
+    }
+    struct TestEngine {
+        network: Arc<TestNetwork>,
+        adnl_unreachable: AtomicBool
+    }
+    impl EngineOperations for TestEngine {
+        fn validator_network(&self) -> Arc<dyn PrivateOverlayOperations> {
+            self.network.clone()
+        }
+        fn set_validator_adnl_unreachable(&self, unreachable: bool) {
+            self.adnl_unreachable.store(unreachable, Ordering::Relaxed)
+        }
+    }
 
+    let network = Arc::new(TestNetwork { reachable: AtomicBool::new(false) });
+    let engine = Arc::new(TestEngine { network: network.clone(), adnl_unreachable: AtomicBool::new(false) });
+    let mut manager = ValidatorManagerImpl::new(engine.clone(), &Default::default()).unwrap();
+    let list_id = UInt256::from([1; 32]);
+    manager.validator_list_status.curr = Some(list_id.clone());
+    manager.local_adnl_ids.insert(list_id.clone(), (KeyId::from_data([2; 32]), "current"));
+    let rt = manager.rt.clone();
+
+    rt.block_on(manager.recheck_local_adnl_keys());
+    assert!(engine.adnl_unreachable.load(Ordering::Relaxed));
+
+    // Key became reachable, but it is not checked again until the interval is elapsed
+    network.reachable.store(true, Ordering::Relaxed);
+    rt.block_on(manager.recheck_local_adnl_keys());
+    assert!(engine.adnl_unreachable.load(Ordering::Relaxed));
+    manager.adnl_keys_checked_at = Instant::now().checked_sub(ADNL_KEYS_CHECK_INTERVAL);
+    rt.block_on(manager.recheck_local_adnl_keys());
+    assert!(!engine.adnl_unreachable.load(Ordering::Relaxed));
+
+    // Unreachable key of the list which is not actual anymore is not reported
+    network.reachable.store(false, Ordering::Relaxed);
+    manager.validator_list_status.curr = None;
+    manager.adnl_keys_checked_at = Instant::now().checked_sub(ADNL_KEYS_CHECK_INTERVAL);
+    rt.block_on(manager.recheck_local_adnl_keys());
+    assert!(!engine.adnl_unreachable.load(Ordering::Relaxed));
+    assert!(manager.local_adnl_ids.is_empty());
+
+    drop(manager);
+}
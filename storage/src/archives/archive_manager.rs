@@ -6,7 +6,7 @@ use crate::{
     },
     types::BlockHandle
 };
-use std::{borrow::Borrow, hash::Hash, io::ErrorKind, path::PathBuf, sync::Arc};
+use std::{borrow::Borrow, hash::Hash, io::ErrorKind, path::{Path, PathBuf}, sync::Arc};
 use tokio::io::AsyncWriteExt;
 use ton_api::ton::PublicKey;
 use ton_block::BlockIdExt;
@@ -42,6 +42,27 @@ impl ArchiveManager {
         &self.unapplied_dir
     }
 
+    // Follows slices of primary instance, returns count of reopened ones
+    pub async fn catch_up_with_primary(&self) -> Result<usize> {
+        self.file_maps.catch_up(&self.db_root_path).await
+    }
+
+    // Copies archives to given root keeping their layout. Unapplied files go first:
+    // the ones moved to packages meanwhile are found in packages copied later.
+    pub async fn backup(&self, target_root: &Path) -> Result<()> {
+        let unapplied_dir = target_root.join("archive").join("unapplied");
+        tokio::fs::create_dir_all(&unapplied_dir).await?;
+        let mut entries = tokio::fs::read_dir(&*self.unapplied_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            match tokio::fs::copy(entry.path(), unapplied_dir.join(entry.file_name())).await {
+                Ok(_) => (),
+                Err(e) if e.kind() == ErrorKind::NotFound => (),
+                Err(e) => fail!("Can't copy {}: {}", entry.path().display(), e)
+            }
+        }
+        self.file_maps.backup(target_root).await
+    }
+
     pub async fn add_file<B, U256, PK>(&self, entry_id: &PackageEntryId<B, U256, PK>, data: Vec<u8>) -> Result<()>
     where
         B: Borrow<BlockIdExt> + Hash,
@@ -208,9 +229,14 @@ impl ArchiveManager {
         fd.archive_slice().get_slice(archive_id, offset, limit).await
     }
 
-    pub async fn gc(&self, front_for_gc_master_block_id: &BlockIdExt) {
-        if let Err(e) = self.file_maps.files().gc(front_for_gc_master_block_id).await {
-            log::info!(target: "storage", "archive_manager gc is error: {:?}", e);
+    // Returns size of removed packages and masterchain seq_no blocks are removed below
+    pub async fn gc(&self, front_for_gc_master_block_id: &BlockIdExt) -> (u64, u32) {
+        match self.file_maps.files().gc(front_for_gc_master_block_id).await {
+            Ok(result) => result,
+            Err(e) => {
+                log::info!(target: "storage", "archive_manager gc is error: {:?}", e);
+                (0, 0)
+            }
         }
     }
 
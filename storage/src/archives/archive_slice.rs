@@ -7,9 +7,9 @@ use crate::{
         package_offsets_db::PackageOffsetsDb, package_status_db::PackageStatusDb, 
         package_status_key::PackageStatusKey
     },
-    traits::Serializable, types::BlockHandle
+    db::rocksdb::is_secondary_mode, traits::Serializable, types::BlockHandle
 };
-use std::{borrow::Borrow, hash::Hash, io::SeekFrom, path::PathBuf, sync::Arc};
+use std::{borrow::Borrow, hash::Hash, io::SeekFrom, path::{Path, PathBuf}, sync::Arc};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use ton_api::ton::PublicKey;
 use ton_block::BlockIdExt;
@@ -127,10 +127,13 @@ impl ArchiveSlice {
         self.package_type
     }
 
-    pub async fn destroy(&mut self) -> Result<()> {
+    // Returns size of removed packages
+    pub async fn destroy(&mut self) -> Result<u64> {
+        let mut removed_bytes = 0;
         for pi in self.packages.write().await.drain(..) {
             let path = Arc::clone(pi.package().path());
             drop(pi);
+            removed_bytes += tokio::fs::metadata(&*path).await.map(|m| m.len()).unwrap_or_default();
             tokio::fs::remove_file(&*path).await?;
         }
 
@@ -146,6 +149,29 @@ impl ArchiveSlice {
 
         tokio::fs::remove_dir_all(&self.index_path).await?;
 
+        Ok(removed_bytes)
+    }
+
+    // Copies slice to the same place under given root. Entries are written to package
+    // before their metas and offsets, so offsets are saved first, then statuses and metas,
+    // and packages go last; data appended meanwhile is truncated when the copy is opened.
+    pub async fn backup(&self, target_root: &Path) -> Result<()> {
+        let index_path = PackageId::with_values(self.archive_id, self.package_type)
+            .full_path(target_root, "index");
+        tokio::fs::create_dir_all(&index_path).await?;
+        self.offsets_db.checkpoint(&index_path.join("offsets_db"))?;
+        self.package_status_db.checkpoint(&index_path.join("status_db"))?;
+        self.index_db.checkpoint(&index_path.join("entry_meta_db"))?;
+
+        let packages = self.packages.read().await.iter()
+            .map(|pi| (Arc::clone(pi.package().path()), pi.package_id().full_path(target_root, "pack")))
+            .collect::<Vec<_>>();
+        for (from, to) in packages {
+            if let Some(dir) = to.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::copy(&*from, &to).await?;
+        }
         Ok(())
     }
 
@@ -263,10 +289,12 @@ impl ArchiveSlice {
         let package_id = PackageId::with_values(seq_no, self.package_type);
         let path = Arc::new(package_id.full_path(self.db_root_path.as_ref(), "pack"));
 
-        let package = Package::open(Arc::clone(&path), false, true).await
+        // Packages of primary instance are never changed by secondary one
+        let read_only = is_secondary_mode();
+        let package = Package::open(Arc::clone(&path), read_only, !read_only).await
             .map_err(|err| error!("Failed to open or create archive \"{}\": {}", path.to_string_lossy(), err))?;
 
-        if !self.finalized && version >= DEFAULT_PKG_VERSION {
+        if !read_only && !self.finalized && version >= DEFAULT_PKG_VERSION {
             package.truncate(size).await?;
         }
 
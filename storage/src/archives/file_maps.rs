@@ -32,7 +32,7 @@ impl FileDescription {
         &self.archive_slice
     }
 
-    async fn destroy(&mut self) -> Result<()> {
+    async fn destroy(&mut self) -> Result<u64> {
         Arc::get_mut(&mut self.archive_slice)
             .ok_or_else(|| error!("Unable to get mutable reference to offsets_db"))?
             .destroy().await
@@ -86,6 +86,47 @@ impl FileMap {
         })
     }
 
+    // Adds slices created by primary instance, drops removed ones and reopens the last one,
+    // which may get new packages. Used by secondary instance only.
+    async fn catch_up(&self, db_root_path: &Arc<PathBuf>, package_type: PackageType) -> Result<usize> {
+        let mut index_pairs = Vec::new();
+        self.storage.for_each_deserialized(|key, value| {
+            index_pairs.push((key, value));
+            Ok(true)
+        })?;
+        index_pairs.sort_by_key(|pair| pair.0);
+        // Slices removed by GC of primary instance
+        self.elements.write().await.retain(
+            |entry| index_pairs.binary_search_by(|pair| pair.0.cmp(&entry.key)).is_ok()
+        );
+        let last_key = self.elements.read().await.last().map(|entry| entry.key);
+        let mut reopened = 0;
+        for (key, value) in index_pairs {
+            if last_key.map(|last_key| key < last_key).unwrap_or(false) {
+                continue
+            }
+            let archive_slice = Arc::new(ArchiveSlice::with_data(
+                Arc::clone(db_root_path),
+                key,
+                package_type,
+                value.finalized()
+            ).await?);
+            let file_description = Arc::new(FileDescription::with_data(
+                PackageId::with_values(key, package_type),
+                archive_slice,
+                value.deleted()
+            ));
+            let entry = FileMapEntry { key, value: file_description };
+            let mut guard = self.elements.write().await;
+            match guard.binary_search_by(|entry| entry.key.cmp(&key)) {
+                Ok(index) => guard[index] = entry,
+                Err(index) => guard.insert(index, entry),
+            }
+            reopened += 1;
+        }
+        Ok(reopened)
+    }
+
     pub async fn put(&self, package_id: u32, file_description: Arc<FileDescription>) -> Result<()> {
         let entry = FileMapEntry { key: package_id, value: file_description };
         let mut guard = self.elements.write().await;
@@ -98,9 +139,11 @@ impl FileMap {
         Ok(())
     }
 
-    async fn get_marked_entries(&self, front_for_gc_master_block_id: &BlockIdExt) -> Vec<u32> {
+    // Returns keys of packages to remove and masterchain seq_no they contain blocks below
+    async fn get_marked_entries(&self, front_for_gc_master_block_id: &BlockIdExt) -> (Vec<u32>, u32) {
         let elements = self.elements.read().await;
         let mut marked_packages = Vec::new();
+        let mut pruned_below = 0;
 
         for i in 0..elements.len() {
             let next_id = if i == elements.len() - 1 {
@@ -112,14 +155,18 @@ impl FileMap {
             if elements[i].value.archive_slice.package_type() == PackageType::Blocks &&
                next_id <= front_for_gc_master_block_id.seq_no() {
                 marked_packages.push(elements[i].key.clone());
+                pruned_below = pruned_below.max(next_id);
             }
         }
-        marked_packages
+        (marked_packages, pruned_below)
     }
 
-    pub async fn gc(&self, front_for_gc_master_block_id: &BlockIdExt) -> Result<()> {
+    // Removes block packages before given block. Returns size of removed packages
+    // and masterchain seq_no the removed packages contained blocks below
+    pub async fn gc(&self, front_for_gc_master_block_id: &BlockIdExt) -> Result<(u64, u32)> {
         log::info!(target: "storage", "file_maps gc started.");
-        let mut marked_packages = self.get_marked_entries(front_for_gc_master_block_id).await;
+        let (mut marked_packages, pruned_below) = self.get_marked_entries(front_for_gc_master_block_id).await;
+        let mut removed_bytes = 0;
 
         while let Some(key) = marked_packages.pop() {
             let mut guard = self.elements.write().await;
@@ -128,8 +175,9 @@ impl FileMap {
             let mut removed_entry = guard.remove(position);
             match Arc::get_mut(&mut removed_entry.value) {
                 Some(file_description) => {
-                    if let Err(e) = file_description.destroy().await {
-                        log::warn!(target: "storage", "destroy file_description is error: {:?}", e);
+                    match file_description.destroy().await {
+                        Ok(bytes) => removed_bytes += bytes,
+                        Err(e) => log::warn!(target: "storage", "destroy file_description is error: {:?}", e)
                     }
                 },
                 None => { 
@@ -137,7 +185,20 @@ impl FileMap {
                 }
             }
         }
-        log::info!(target: "storage", "file_maps gc finished.");
+        log::info!(target: "storage", "file_maps gc finished, removed {} bytes.", removed_bytes);
+        Ok((removed_bytes, pruned_below))
+    }
+
+    // Saves index, then slices listed in it, so the copy has every indexed slice
+    async fn backup(&self, index_path: &Path, target_root: &Path) -> Result<()> {
+        self.storage.checkpoint(index_path)?;
+        let keys = self.elements.read().await.iter().map(|entry| entry.key).collect::<Vec<_>>();
+        for key in keys {
+            // Slice may be removed by GC meanwhile
+            if let Some(file_description) = self.get(key).await {
+                file_description.archive_slice().backup(target_root).await?;
+            }
+        }
         Ok(())
     }
 
@@ -179,6 +240,20 @@ impl FileMaps {
         })
     }
 
+    pub async fn backup(&self, target_root: &Path) -> Result<()> {
+        let path = target_root.join("file_maps");
+        tokio::fs::create_dir_all(&path).await?;
+        self.files.backup(&path.join("files"), target_root).await?;
+        self.key_files.backup(&path.join("key_files"), target_root).await
+    }
+
+    pub async fn catch_up(&self, db_root_path: &Arc<PathBuf>) -> Result<usize> {
+        Ok(
+            self.files.catch_up(db_root_path, PackageType::Blocks).await? +
+            self.key_files.catch_up(db_root_path, PackageType::KeyBlocks).await?
+        )
+    }
+
     pub fn files(&self) -> &FileMap {
         &self.files
     }
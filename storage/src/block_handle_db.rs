@@ -1,92 +1,376 @@
 use crate::{
-    TARGET, db_impl_serializable, db::traits::KvcWriteable, node_state_db::NodeStateDb, 
+    TARGET, db_impl_serializable, db::traits::KvcTransactional, node_state_db::NodeStateDb, 
     traits::Serializable, types::{BlockHandle, BlockMeta}
 };
 use adnl::common::{add_object_to_map, add_object_to_map_with_update};
-use std::{io::Cursor, sync::{Arc, Weak}};
-use ton_block::BlockIdExt;
-use ton_types::{error, fail, Result};
+use futures::FutureExt;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet}, io::Cursor, time::Duration,
+    sync::{Arc, Mutex, Weak, atomic::{AtomicU64, Ordering}}
+};
+use ton_block::{BlockIdExt, ShardIdent};
+use ton_types::{error, fail, Result, UInt256};
 
 
-db_impl_serializable!(BlockHandleDb, KvcWriteable, BlockIdExt, BlockMeta);
+db_impl_serializable!(BlockHandleDb, KvcTransactional, BlockIdExt, BlockMeta);
 
 pub(crate) type BlockHandleCache = lockfree::map::Map<BlockIdExt, Weak<BlockHandle>>;
 
+const RECENT_HANDLES_SHARDS: usize = 16;
+
+#[derive(Debug, Default, Clone)]
+pub struct BlockHandleCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub size: u64,
+    pub capacity: u64,
+}
+
+#[derive(Default)]
+struct RecentHandlesShard {
+    entries: HashMap<BlockIdExt, (Arc<BlockHandle>, u64)>,
+    // Least recently used handles go first
+    order: BTreeMap<u64, BlockIdExt>,
+    next_tick: u64,
+}
+
+// Bounded LRU set keeping recently used handles alive, so they are not read from DB while
+// they are hot. A handle is the same object for all its holders (see `BlockHandleCache`),
+// so flags changed by anyone are seen by everyone and stored through `store_handle`.
+struct RecentHandles {
+    shards: Vec<Mutex<RecentHandlesShard>>,
+    capacity: usize,
+    size: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl RecentHandles {
+
+    fn new(capacity: usize) -> Self {
+        Self {
+            shards: (0..RECENT_HANDLES_SHARDS).map(|_| Mutex::new(RecentHandlesShard::default())).collect(),
+            capacity,
+            size: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self, handle: &Arc<BlockHandle>) {
+        if self.capacity == 0 {
+            return
+        }
+        let max_entries = (self.capacity + RECENT_HANDLES_SHARDS - 1) / RECENT_HANDLES_SHARDS;
+        let mut evicted = Vec::new();
+        {
+            let mut shard = self.shard(handle.id()).lock().expect("Poisoned Mutex");
+            let shard = &mut *shard;
+            let tick = shard.next_tick;
+            shard.next_tick += 1;
+            match shard.entries.get_mut(handle.id()) {
+                Some((_, old_tick)) => {
+                    shard.order.remove(old_tick);
+                    *old_tick = tick;
+                },
+                None => {
+                    shard.entries.insert(handle.id().clone(), (handle.clone(), tick));
+                    self.size.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            shard.order.insert(tick, handle.id().clone());
+            while shard.entries.len() > max_entries {
+                let tick = match shard.order.keys().next() {
+                    Some(tick) => *tick,
+                    None => break
+                };
+                if let Some(id) = shard.order.remove(&tick) {
+                    if let Some((handle, _)) = shard.entries.remove(&id) {
+                        evicted.push(handle);
+                    }
+                }
+            }
+        }
+        // Handles are dropped out of lock
+        self.account_eviction(evicted.len());
+    }
+
+    // Returns count of evicted handles
+    fn evict_where(&self, predicate: impl Fn(&BlockHandle) -> bool) -> usize {
+        let mut evicted = Vec::new();
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().expect("Poisoned Mutex");
+            let shard = &mut *shard;
+            let ids = shard.entries.iter()
+                .filter(|(_, (handle, _))| predicate(handle))
+                .map(|(id, _)| id.clone())
+                .collect::<Vec<_>>();
+            for id in ids {
+                if let Some((handle, tick)) = shard.entries.remove(&id) {
+                    shard.order.remove(&tick);
+                    evicted.push(handle);
+                }
+            }
+        }
+        self.account_eviction(evicted.len());
+        evicted.len()
+    }
+
+    fn clear(&self) {
+        self.evict_where(|_| true);
+    }
+
+    fn account_eviction(&self, evicted: usize) {
+        if evicted > 0 {
+            self.evictions.fetch_add(evicted as u64, Ordering::Relaxed);
+            self.size.fetch_sub(evicted as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn shard(&self, id: &BlockIdExt) -> &Mutex<RecentHandlesShard> {
+        &self.shards[id.root_hash().as_slice()[0] as usize % RECENT_HANDLES_SHARDS]
+    }
+}
+
 #[derive(Debug)]
 pub enum StoreJob {
     Handle(Arc<BlockHandle>),
-    State((&'static str, Arc<BlockIdExt>))
+    State((&'static str, Arc<BlockIdExt>)),
+    // Commits all queued jobs, callback is invoked after that
+    Flush
 }
 
 #[async_trait::async_trait]
 pub trait Callback: Sync + Send {
     async fn invoke(&self, job: StoreJob, ok: bool);
 }
+
+// Write-behind mode of handles storing: handle updates are batched across blocks and
+// committed after flush interval or when batch is full. States (e.g. last applied block
+// pointers) are never delayed: they flush all handle updates queued before them.
+#[derive(Clone, Debug)]
+pub struct WriteBehindConfig {
+    pub flush_interval: Duration,
+    pub max_batch: usize,
+}
+
+// Jobs committed at once: all updates of a handle go to one write with its latest flags
+#[derive(Default)]
+struct StoreBatch {
+    handles: HashMap<BlockIdExt, Arc<BlockHandle>>,
+    handle_callbacks: Vec<(Arc<BlockHandle>, Arc<dyn Callback>)>,
+    states: Vec<((&'static str, Arc<BlockIdExt>), Option<Arc<dyn Callback>>)>,
+    flush_callbacks: Vec<Arc<dyn Callback>>,
+}
+
+impl StoreBatch {
+
+    fn push(&mut self, job: StoreJob, callback: Option<Arc<dyn Callback>>) {
+        match job {
+            StoreJob::Handle(handle) => {
+                if let Some(callback) = callback {
+                    self.handle_callbacks.push((handle.clone(), callback));
+                }
+                self.handles.insert(handle.id().clone(), handle);
+            },
+            StoreJob::State(state) => self.states.push((state, callback)),
+            StoreJob::Flush => self.flush_callbacks.extend(callback)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.handles.is_empty() && self.states.is_empty() && self.flush_callbacks.is_empty()
+    }
+
+    // Batch can't wait for flush interval
+    fn is_urgent(&self) -> bool {
+        !self.states.is_empty() || !self.flush_callbacks.is_empty()
+    }
+
+    async fn flush(&mut self, handle_db: &BlockHandleDb, state_db: &NodeStateDb, commit_lock: &Mutex<()>) {
+        if self.is_empty() {
+            return
+        }
+        // Handles go first, so stored states never refer blocks with older flags
+        let handles_ok = if self.handles.is_empty() {
+            true
+        } else {
+            match Self::commit_handles(handle_db, &self.handles, commit_lock) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::error!(target: TARGET, "ERROR: {} while storing {} handles", e, self.handles.len());
+                    false
+                }
+            }
+        };
+        self.handles.clear();
+        for (handle, callback) in self.handle_callbacks.drain(..) {
+            callback.invoke(StoreJob::Handle(handle), handles_ok).await;
+        }
+        for ((key, id), callback) in self.states.drain(..) {
+            let mut buf = Vec::new();
+            let result = id
+                .serialize(&mut buf)            
+                .and_then(|_| state_db.put(&key, &buf[..]));
+            let ok = if let Err(e) = result {
+                log::error!(
+                    target: TARGET, 
+                    "ERROR: {} while storing state {}", 
+                    e, id
+                );
+                false
+            } else {
+                true
+            };
+            if let Some(callback) = callback {
+                callback.invoke(StoreJob::State((key, id)), ok).await;
+            }
+        }
+        for callback in self.flush_callbacks.drain(..) {
+            callback.invoke(StoreJob::Flush, handles_ok).await;
+        }
+    }
+
+    fn commit_handles(
+        handle_db: &BlockHandleDb,
+        handles: &HashMap<BlockIdExt, Arc<BlockHandle>>,
+        commit_lock: &Mutex<()>
+    ) -> Result<()> {
+        let _lock = commit_lock.lock().expect("Poisoned Mutex");
+        let mut transaction = handle_db.begin_transaction()?;
+        for handle in handles.values() {
+            transaction.put(handle.id(), &handle.meta().to_vec()?);
+        }
+        transaction.commit()
+    }
+}
  
+struct FlushCallback(Mutex<Option<tokio::sync::oneshot::Sender<bool>>>);
+
+#[async_trait::async_trait]
+impl Callback for FlushCallback {
+    async fn invoke(&self, _job: StoreJob, ok: bool) {
+        if let Some(sender) = self.0.lock().unwrap().take() {
+            sender.send(ok).ok();
+        }
+    }
+}
+
 pub struct BlockHandleStorage {
     handle_db: Arc<BlockHandleDb>,
     handle_cache: Arc<BlockHandleCache>,
+    recent_handles: RecentHandles,
+    hits: AtomicU64,
+    misses: AtomicU64,
     state_db: Arc<NodeStateDb>,
     state_cache: lockfree::map::Map<&'static str, Arc<BlockIdExt>>,
-    storer: tokio::sync::mpsc::UnboundedSender<(StoreJob, Option<Arc<dyn Callback>>)>
+    storer: tokio::sync::mpsc::UnboundedSender<(StoreJob, Option<Arc<dyn Callback>>)>,
+    flushes: Arc<AtomicU64>,
+    // Handle records are written either by storer or by pruning, never at once
+    commit_lock: Arc<Mutex<()>>,
 }
 
 impl BlockHandleStorage {
 
-    pub fn with_dbs(handle_db: Arc<BlockHandleDb>, state_db: Arc<NodeStateDb>) -> Self {
+    pub fn with_dbs(
+        handle_db: Arc<BlockHandleDb>,
+        state_db: Arc<NodeStateDb>,
+        write_behind: Option<WriteBehindConfig>,
+        cache_size: usize
+    ) -> Self {
         let (sender, mut reader) = tokio::sync::mpsc::unbounded_channel();
+        let flushes = Arc::new(AtomicU64::new(0));
+        let commit_lock = Arc::new(Mutex::new(()));
         let ret = Self {
             handle_db: handle_db.clone(),
             handle_cache: Arc::new(lockfree::map::Map::new()),
+            recent_handles: RecentHandles::new(cache_size),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
             state_db: state_db.clone(),
             state_cache: lockfree::map::Map::new(),
-            storer: sender
+            storer: sender,
+            flushes: flushes.clone(),
+            commit_lock: commit_lock.clone(),
         };
         tokio::spawn( 
             async move {
-                while let Some((job, callback)) = reader.recv().await {
-                    let ok = match &job {
-                        StoreJob::Handle(handle) => {
-                            if let Err(e) = handle_db.put_value(handle.id(), handle.meta()) {
-                                log::error!(
-                                    target: TARGET, 
-                                    "ERROR: {} while storing handle {}", 
-                                    e, handle.id()
-                                );
-                                false
-                            } else {
-                                true
+                let mut batch = StoreBatch::default();
+                let mut batch_size = 0;
+                let mut flush_deadline = None;
+                loop {
+                    let next = match flush_deadline {
+                        Some(deadline) => match tokio::time::timeout_at(deadline, reader.recv()).await {
+                            Ok(next) => next,
+                            Err(_) => {
+                                batch.flush(&handle_db, &state_db, &commit_lock).await;
+                                flushes.fetch_add(1, Ordering::Relaxed);
+                                batch_size = 0;
+                                flush_deadline = None;
+                                continue
                             }
                         },
-                        StoreJob::State((key, id)) => {
-                            let mut buf = Vec::new();
-                            let result = id
-                                .serialize(&mut buf)            
-                                .and_then(|_| state_db.put(key, &buf[..]));
-                            if let Err(e) = result {
-                                log::error!(
-                                    target: TARGET, 
-                                    "ERROR: {} while storing state {}", 
-                                    e, id
-                                );
-                                false
-                            } else {
-                                true
+                        None => reader.recv().await
+                    };
+                    let (job, callback) = match next {
+                        Some(next) => next,
+                        None => break
+                    };
+                    batch.push(job, callback);
+                    batch_size += 1;
+                    // Jobs already queued (e.g. all flags of just applied block) go to the same batch
+                    while let Some(Some((job, callback))) = reader.recv().now_or_never() {
+                        batch.push(job, callback);
+                        batch_size += 1;
+                    }
+                    match &write_behind {
+                        Some(config) if !batch.is_urgent() && batch_size < config.max_batch => {
+                            if flush_deadline.is_none() {
+                                flush_deadline = Some(tokio::time::Instant::now() + config.flush_interval);
                             }
+                        },
+                        _ => {
+                            batch.flush(&handle_db, &state_db, &commit_lock).await;
+                            flushes.fetch_add(1, Ordering::Relaxed);
+                            batch_size = 0;
+                            flush_deadline = None;
                         }
-                    };
-                    if let Some(callback) = callback {
-                        callback.invoke(job, ok).await;
                     }
                 }
                 // Graceful close
-                reader.close();
-                while let Some(_) = reader.recv().await {
-                }
+                batch.flush(&handle_db, &state_db, &commit_lock).await;
             }
         );
         ret
     }
+
+    // Commits handles batched in write-behind mode, resolves when they are stored
+    pub async fn flush(&self) -> Result<()> {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        let callback = Arc::new(FlushCallback(Mutex::new(Some(sender))));
+        self.storer.send((StoreJob::Flush, Some(callback))).map_err(
+            |_| error!("Cannot flush handles: storer thread dropped")
+        )?;
+        match receiver.await {
+            Ok(true) => Ok(()),
+            Ok(false) => fail!("Batched handles are not stored"),
+            Err(_) => fail!("Cannot flush handles: storer thread dropped")
+        }
+    }
+
+    // Count of batches committed by storer
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    pub fn cache_stats(&self) -> BlockHandleCacheStats {
+        BlockHandleCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.recent_handles.evictions.load(Ordering::Relaxed),
+            size: self.recent_handles.size.load(Ordering::Relaxed),
+            capacity: self.recent_handles.capacity as u64,
+        }
+    }
   	
     pub fn create_handle(
         &self, 
@@ -118,8 +402,11 @@ impl BlockHandleStorage {
         let ret = loop {
             let weak = self.handle_cache.get(id);
             if let Some(Some(handle)) = weak.map(|weak| weak.val().upgrade()) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                self.recent_handles.touch(&handle);
                 break Some(handle)
             }
+            self.misses.fetch_add(1, Ordering::Relaxed);
             if let Some(meta) = self.handle_db.try_get_value(id)? {
                 let handle = self.create_handle_and_store(id.clone(), meta, None, false)?;
                 if let Some(handle) = handle {
@@ -132,6 +419,18 @@ impl BlockHandleStorage {
         Ok(ret)
     }
 
+    /// Forgets cached handles and states, so they are read from DB again.
+    /// Secondary instance sees changes of primary one this way.
+    pub fn drop_caches(&self) {
+        self.recent_handles.clear();
+        for entry in self.handle_cache.iter() {
+            self.handle_cache.remove(entry.key());
+        }
+        for entry in self.state_cache.iter() {
+            self.state_cache.remove(entry.key());
+        }
+    }
+
     pub fn load_state(&self, key: &'static str) -> Result<Option<Arc<BlockIdExt>>> {
         log::trace!(target: TARGET, "load state {}", key);
         let ret = loop {
@@ -172,6 +471,69 @@ impl BlockHandleStorage {
         Ok(())
     }
 
+    // Resets data and proof flags of archived non-key blocks below given masterchain block
+    // (their packages are removed). Returns number of pruned handles. Handles in use are
+    // skipped, they are pruned next time.
+    pub fn prune_handles(&self, mc_seq_no: u32, mc_gen_utime: u32) -> Result<u64> {
+        let below_boundary = |meta: &BlockMeta| {
+            match meta.masterchain_ref_seq_no() {
+                0 => meta.gen_utime < mc_gen_utime, // masterchain block
+                ref_seq_no => ref_seq_no < mc_seq_no
+            }
+        };
+        let is_pruned = |meta: &BlockMeta| {
+            BlockHandle::is_pruning_candidate(meta) && below_boundary(meta)
+        };
+        let mut candidates = Vec::new();
+        self.handle_db.for_each(&mut |key, value| {
+            if is_pruned(&BlockMeta::from_slice(value)?) {
+                candidates.push(UInt256::from_slice(key));
+            }
+            Ok(true)
+        })?;
+        if candidates.is_empty() {
+            return Ok(0)
+        }
+        // Pruned blocks are not hot anymore, the rest of cached handles are in use
+        let evicted = self.recent_handles.evict_where(|handle| below_boundary(handle.meta()));
+        log::trace!(target: TARGET, "{} pruned block handles are evicted from cache", evicted);
+        let mut pruned = HashSet::new();
+        {
+            // Storer can't commit flags read before the records are changed
+            let _lock = self.commit_lock.lock().expect("Poisoned Mutex");
+            let in_use = self.handle_cache.iter()
+                .filter_map(|guard| guard.val().upgrade())
+                .map(|handle| handle.id().root_hash().clone())
+                .collect::<HashSet<_>>();
+            let mut transaction = self.handle_db.begin_transaction()?;
+            for root_hash in candidates {
+                if in_use.contains(&root_hash) {
+                    continue
+                }
+                // Handles are keyed by root hash only
+                let id = BlockIdExt::with_params(ShardIdent::default(), 0, root_hash, UInt256::default());
+                // Record might be changed after scanning
+                let meta = match self.handle_db.try_get_value(&id)? {
+                    Some(meta) if is_pruned(&meta) => meta,
+                    _ => continue
+                };
+                BlockHandle::reset_data_and_proofs_in_meta(&meta);
+                transaction.put(&id, &meta.to_vec()?);
+                pruned.insert(id.root_hash().clone());
+            }
+            transaction.commit()?;
+        }
+        // Handle might be read from DB before its record was changed and cached after check
+        for guard in self.handle_cache.iter() {
+            if let Some(handle) = guard.val().upgrade() {
+                if pruned.contains(handle.id().root_hash()) && handle.reset_data_and_proofs() {
+                    self.store_handle(&handle, None)?;
+                }
+            }
+        }
+        Ok(pruned.len() as u64)
+    }
+
     fn create_handle_and_store(
         &self, 
         id: BlockIdExt, 
@@ -186,6 +548,7 @@ impl BlockHandleStorage {
             || Ok(Arc::downgrade(&ret))
         )?;
         if added {
+            self.recent_handles.touch(&ret);
             if store {
                 self.store_handle(&ret, callback)?
             }
@@ -199,3 +562,284 @@ impl BlockHandleStorage {
 }
 
 
+
+#[cfg(test)]
+struct CountingCallback(AtomicU64);
+
+#[cfg(test)]
+#[async_trait::async_trait]
+impl Callback for CountingCallback {
+    async fn invoke(&self, _job: StoreJob, ok: bool) {
+        assert!(ok);
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+fn test_block_id(seqno: u32) -> BlockIdExt {
+    let mut root_hash = [0u8; 32];
+    root_hash[..4].copy_from_slice(&seqno.to_be_bytes());
+    root_hash[31] = 1;
+    BlockIdExt::with_params(ShardIdent::masterchain(), seqno, UInt256::from_slice(&root_hash), UInt256::default())
+}
+
+#[cfg(test)]
+fn test_db_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("block_handle_db_{}_{}", name, std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    path
+}
+
+#[cfg(test)]
+async fn store_applied_handles(storage: &BlockHandleStorage, count: u32, yield_every: bool) {
+    let callback = Arc::new(CountingCallback(AtomicU64::new(0)));
+    for seqno in 1..=count {
+        let handle = storage.create_handle(test_block_id(seqno), BlockMeta::default(), None)
+            .unwrap().unwrap();
+        handle.set_block_applied();
+        storage.store_handle(&handle, Some(callback.clone())).unwrap();
+        if yield_every {
+            // storer takes jobs of every handle separately
+            tokio::task::yield_now().await;
+        }
+    }
+    // Callbacks of handles are invoked before the one of flush
+    storage.flush().await.unwrap();
+    assert_eq!(callback.0.load(Ordering::Relaxed), count as u64);
+}
+
+#[test]
+fn test_batched_store() {
+    const COUNT: u32 = 5000;
+    const MAX_BATCH: usize = 1000;
+    let path = test_db_path("batched");
+    // Storer runs only when test yields, so batches are determined
+    tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+        // Creation and update of handle are two jobs.
+        // All queued jobs go to one batch;
+        // in write-behind mode only full batches and the final flush are committed
+        for (name, write_behind, yield_every, flushes) in [
+            ("batched", None, false, 1),
+            ("batched_yield", None, true, COUNT as u64 + 1),
+            (
+                "write_behind",
+                Some(WriteBehindConfig { flush_interval: Duration::from_secs(3600), max_batch: MAX_BATCH }),
+                true,
+                2 * COUNT as u64 / MAX_BATCH as u64 + 1
+            ),
+        ].iter() {
+            let storage = BlockHandleStorage::with_dbs(
+                Arc::new(BlockHandleDb::with_path(path.join(name))),
+                Arc::new(NodeStateDb::with_path(path.join(format!("{}_state", name)))),
+                write_behind.clone(),
+                1000
+            );
+            store_applied_handles(&storage, COUNT, *yield_every).await;
+            assert_eq!(storage.flushes(), *flushes, "{}", name);
+            // Every handle is stored with its latest flags
+            storage.drop_caches();
+            for seqno in 1..=COUNT {
+                let handle = storage.load_handle(&test_block_id(seqno)).unwrap()
+                    .unwrap_or_else(|| panic!("{}: handle {} is not stored", name, seqno));
+                assert!(handle.is_applied(), "{}: handle {} is stored with stale flags", name, seqno);
+            }
+        }
+    });
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_write_behind_keeps_pointers_ordered() {
+    let path = test_db_path("ordering");
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let storage = BlockHandleStorage::with_dbs(
+            Arc::new(BlockHandleDb::with_path(path.join("handles"))),
+            Arc::new(NodeStateDb::with_path(path.join("state"))),
+            Some(WriteBehindConfig { flush_interval: Duration::from_secs(3600), max_batch: 1000 }),
+            1000
+        );
+        let id = test_block_id(1);
+        let handle = storage.create_handle(id.clone(), BlockMeta::default(), None).unwrap().unwrap();
+        handle.set_block_applied();
+        storage.store_handle(&handle, None).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Not flushed yet: after crash the block is not applied
+        assert!(storage.handle_db.try_get_value(&id).unwrap().is_none());
+
+        // Pointer flushes all handle updates queued before it
+        storage.store_state("LastAppliedMcBlockId", &id).unwrap();
+        while storage.state_db.try_get(&"LastAppliedMcBlockId").unwrap().is_none() {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(handle);
+        assert!(storage.load_handle(&id).unwrap().unwrap().is_applied());
+    });
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_write_behind_flush() {
+    let path = test_db_path("flush");
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let storage = BlockHandleStorage::with_dbs(
+            Arc::new(BlockHandleDb::with_path(path.join("handles"))),
+            Arc::new(NodeStateDb::with_path(path.join("state"))),
+            Some(WriteBehindConfig { flush_interval: Duration::from_secs(3600), max_batch: 1000 }),
+            1000
+        );
+        // Nothing is queued
+        storage.flush().await.unwrap();
+        let id = test_block_id(1);
+        let handle = storage.create_handle(id.clone(), BlockMeta::default(), None).unwrap().unwrap();
+        handle.set_block_applied();
+        storage.store_handle(&handle, None).unwrap();
+        assert!(storage.handle_db.try_get_value(&id).unwrap().is_none());
+        // On shutdown the batch is committed without waiting for flush interval
+        storage.flush().await.unwrap();
+        assert!(storage.handle_db.try_get_value(&id).unwrap().is_some());
+    });
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_cached_handles_have_no_stale_flags() {
+    const COUNT: u32 = 3000;
+    const READERS: usize = 4;
+    let path = test_db_path("cache");
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    // Small cache, so handles are evicted and read from DB again all the time
+    let storage = Arc::new(runtime.block_on(async {
+        BlockHandleStorage::with_dbs(
+            Arc::new(BlockHandleDb::with_path(path.join("handles"))),
+            Arc::new(NodeStateDb::with_path(path.join("state"))),
+            None,
+            64
+        )
+    }));
+    let applied_upto = Arc::new(AtomicU64::new(0));
+    let readers = (0..READERS).map(|reader| {
+        let storage = storage.clone();
+        let applied_upto = applied_upto.clone();
+        std::thread::spawn(move || {
+            let mut seqno = reader as u64;
+            loop {
+                let upto = applied_upto.load(Ordering::Acquire);
+                if upto == COUNT as u64 {
+                    break
+                }
+                if upto == 0 {
+                    std::thread::yield_now();
+                    continue
+                }
+                seqno = (seqno * 7919 + 1) % upto + 1;
+                let handle = storage.load_handle(&test_block_id(seqno as u32)).unwrap()
+                    .expect("applied block handle must exist");
+                assert!(handle.is_applied(), "stale flags of block {}", seqno);
+            }
+        })
+    }).collect::<Vec<_>>();
+    runtime.block_on(async {
+        for seqno in 1..=COUNT {
+            let handle = storage.create_handle(test_block_id(seqno), BlockMeta::default(), None)
+                .unwrap().unwrap();
+            handle.set_block_applied();
+            storage.store_handle(&handle, None).unwrap();
+            drop(handle);
+            applied_upto.store(seqno as u64, Ordering::Release);
+            if seqno % 100 == 0 {
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    let stats = storage.cache_stats();
+    assert!(stats.size <= stats.capacity + RECENT_HANDLES_SHARDS as u64);
+    assert!(stats.evictions > 0);
+    assert!(stats.hits > 0);
+    drop(storage);
+    drop(runtime);
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[cfg(test)]
+fn create_archived_handle(storage: &BlockHandleStorage, seqno: u32) -> Arc<BlockHandle> {
+    // Masterchain blocks are pruned by generation time
+    let meta = BlockMeta::with_data(0, seqno, 0, 0);
+    let handle = storage.create_handle(test_block_id(seqno), meta, None).unwrap().unwrap();
+    handle.set_data();
+    handle.set_proof();
+    handle.set_archived();
+    storage.store_handle(&handle, None).unwrap();
+    handle
+}
+
+#[test]
+fn test_prune_handles_skips_handles_in_use() {
+    let path = test_db_path("prune");
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let storage = BlockHandleStorage::with_dbs(
+            Arc::new(BlockHandleDb::with_path(path.join("handles"))),
+            Arc::new(NodeStateDb::with_path(path.join("state"))),
+            None,
+            1000
+        );
+        let in_use = create_archived_handle(&storage, 3);
+        for seqno in [1, 2, 4, 5, 6, 7].iter() {
+            create_archived_handle(&storage, *seqno);
+        }
+        storage.flush().await.unwrap();
+
+        assert_eq!(storage.prune_handles(1, 6).unwrap(), 4);
+        assert!(in_use.has_data());
+        for seqno in 1..=7 {
+            let meta = storage.handle_db.try_get_value(&test_block_id(seqno)).unwrap().unwrap();
+            let handle = storage.load_handle(&test_block_id(seqno)).unwrap().unwrap();
+            let pruned = seqno < 6 && seqno != 3;
+            assert_eq!(BlockHandle::is_pruning_candidate(&meta), !pruned, "block {}", seqno);
+            assert_eq!(handle.has_data(), !pruned, "block {}", seqno);
+            assert!(handle.is_archived());
+        }
+
+        // Released handle is pruned next time
+        drop(in_use);
+        assert_eq!(storage.prune_handles(1, 6).unwrap(), 1);
+        assert!(!storage.load_handle(&test_block_id(3)).unwrap().unwrap().has_data());
+        assert_eq!(storage.prune_handles(1, 6).unwrap(), 0);
+    });
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_prune_handles_keeps_queued_flags() {
+    let path = test_db_path("prune_queued");
+    tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let storage = BlockHandleStorage::with_dbs(
+            Arc::new(BlockHandleDb::with_path(path.join("handles"))),
+            Arc::new(NodeStateDb::with_path(path.join("state"))),
+            Some(WriteBehindConfig { flush_interval: Duration::from_secs(3600), max_batch: 1000 }),
+            0
+        );
+        let id = test_block_id(1);
+        create_archived_handle(&storage, 1);
+        storage.flush().await.unwrap();
+
+        // Flag update is queued by storer while pruning runs
+        let handle = storage.load_handle(&id).unwrap().unwrap();
+        handle.set_block_applied();
+        storage.store_handle(&handle, None).unwrap();
+        drop(handle);
+        assert_eq!(storage.prune_handles(1, 2).unwrap(), 0);
+        storage.flush().await.unwrap();
+        let meta = storage.handle_db.try_get_value(&id).unwrap().unwrap();
+        assert!(BlockHandle::is_pruning_candidate(&meta));
+
+        assert_eq!(storage.prune_handles(1, 2).unwrap(), 1);
+        let handle = storage.load_handle(&id).unwrap().unwrap();
+        assert!(handle.is_applied());
+        assert!(!handle.has_data());
+        assert!(!handle.has_proof());
+    });
+    std::fs::remove_dir_all(&path).ok();
+}
@@ -29,6 +29,18 @@ impl BlockIndexDb {
         )
     }
 
+    pub fn compact(&self) -> Result<()> {
+        self.lt_desc_db.read().expect("Poisoned RwLock").compact()?;
+        self.lt_db.compact()
+    }
+
+    pub fn checkpoint(&self, lt_desc_db_path: &Path, lt_db_path: &Path) -> Result<()> {
+        // Indexing is done under write lock of descriptions, so both checkpoints are consistent
+        let lt_desc_db = self.lt_desc_db.read().expect("Poisoned RwLock");
+        lt_desc_db.checkpoint(lt_desc_db_path)?;
+        self.lt_db.checkpoint(lt_db_path)
+    }
+
 
 
     pub fn get_block_by_lt(&self, account_id: &AccountIdPrefixFull, lt: u64) -> Result<BlockIdExt> {
@@ -196,4 +208,22 @@ impl BlockIndexDb {
 
         Ok(())
     }
+
+    /// Returns up to `limit` ids of indexed blocks following given index key (all ones if
+    /// it is empty) with their keys, so the walk can be continued from the last of them
+    pub fn block_ids_after(&self, after: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, BlockIdExt)>> {
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result)
+        }
+        self.lt_db.for_each_ascending(after, &mut |key, value| {
+            if key == after {
+                return Ok(true)
+            }
+            let entry: LtDbEntry = serde_cbor::from_slice(value)?;
+            result.push((key.to_vec(), entry.block_id_ext().try_into()?));
+            Ok(result.len() < limit)
+        })?;
+        Ok(result)
+    }
 }
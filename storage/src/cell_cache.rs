@@ -0,0 +1,205 @@
+use crate::{db::traits::DbKey, types::CellId};
+use fnv::FnvHashMap;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}}
+};
+
+const SHARDS_COUNT: usize = 16;
+// Approximate memory taken by cache entry besides cell data
+const ENTRY_OVERHEAD: u64 = 128;
+
+#[derive(Debug, Default, Clone)]
+pub struct CellCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub used_bytes: u64,
+    pub budget_bytes: u64,
+}
+
+// Index of cells database and cell id
+type CacheKey = (u32, CellId);
+
+#[derive(Default)]
+struct CacheShard {
+    entries: FnvHashMap<CacheKey, (Arc<Vec<u8>>, u64)>,
+    // Least recently used entries go first
+    order: BTreeMap<u64, CacheKey>,
+    next_tick: u64,
+    bytes: u64,
+}
+
+impl CacheShard {
+    fn touch(&mut self, key: &CacheKey) -> Option<Arc<Vec<u8>>> {
+        let tick = self.next_tick;
+        let (data, old_tick) = self.entries.get_mut(key)?;
+        self.order.remove(old_tick);
+        *old_tick = tick;
+        let data = data.clone();
+        self.order.insert(tick, key.clone());
+        self.next_tick += 1;
+        Some(data)
+    }
+
+    // Returns size of removed entry
+    fn remove(&mut self, key: &CacheKey) -> Option<u64> {
+        let (data, tick) = self.entries.remove(key)?;
+        self.order.remove(&tick);
+        let size = entry_size(&data);
+        self.bytes -= size;
+        Some(size)
+    }
+
+    // Returns count of evicted entries and their size
+    fn evict(&mut self, max_bytes: u64) -> (u64, u64) {
+        let mut evicted = 0;
+        let mut evicted_bytes = 0;
+        while self.bytes > max_bytes {
+            let tick = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break
+            };
+            if let Some(key) = self.order.remove(&tick) {
+                if let Some((data, _)) = self.entries.remove(&key) {
+                    let size = entry_size(&data);
+                    self.bytes -= size;
+                    evicted_bytes += size;
+                    evicted += 1;
+                }
+            }
+        }
+        (evicted, evicted_bytes)
+    }
+}
+
+// Sharded LRU cache of serialized cells keyed by cells database index and cell hash.
+// Cells are swept by GC from one database only, so the cache never serves a cell
+// missing in the database it is asked for. Swept cells are removed from the cache.
+pub struct CellCache {
+    shards: Vec<Mutex<CacheShard>>,
+    budget_bytes: AtomicU64,
+    used_bytes: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CellCache {
+
+    /// Constructs new cache, zero budget disables it
+    pub fn new(budget_bytes: u64) -> Self {
+        Self {
+            shards: (0..SHARDS_COUNT).map(|_| Mutex::new(CacheShard::default())).collect(),
+            budget_bytes: AtomicU64::new(budget_bytes),
+            used_bytes: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, db_index: u32, cell_id: &CellId) -> Option<Arc<Vec<u8>>> {
+        if self.budget_bytes.load(Ordering::Relaxed) == 0 {
+            return None
+        }
+        let found = self.shard(cell_id).lock().expect("Poisoned Mutex")
+            .touch(&(db_index, cell_id.clone()));
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        found
+    }
+
+    pub fn put(&self, db_index: u32, cell_id: &CellId, data: &[u8]) {
+        let budget = self.budget_bytes.load(Ordering::Relaxed);
+        if budget == 0 {
+            return
+        }
+        let data = Arc::new(data.to_vec());
+        let size = entry_size(&data);
+        let key = (db_index, cell_id.clone());
+        let mut shard = self.shard(cell_id).lock().expect("Poisoned Mutex");
+        if shard.touch(&key).is_some() {
+            return
+        }
+        let tick = shard.next_tick;
+        shard.next_tick += 1;
+        shard.entries.insert(key.clone(), (data, tick));
+        shard.order.insert(tick, key);
+        shard.bytes += size;
+        self.used_bytes.fetch_add(size, Ordering::Relaxed);
+        let (evicted, evicted_bytes) = shard.evict(budget / SHARDS_COUNT as u64);
+        self.account_eviction(evicted, evicted_bytes);
+    }
+
+    /// Removes cell deleted from the database
+    pub fn remove(&self, db_index: u32, cell_id: &CellId) {
+        let removed = self.shard(cell_id).lock().expect("Poisoned Mutex")
+            .remove(&(db_index, cell_id.clone()));
+        if let Some(size) = removed {
+            self.used_bytes.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    /// Changes budget, extra entries are evicted immediately
+    pub fn set_budget(&self, budget_bytes: u64) -> u64 {
+        let prev = self.budget_bytes.swap(budget_bytes, Ordering::Relaxed);
+        for shard in self.shards.iter() {
+            let (evicted, evicted_bytes) = shard.lock().expect("Poisoned Mutex")
+                .evict(budget_bytes / SHARDS_COUNT as u64);
+            self.account_eviction(evicted, evicted_bytes);
+        }
+        prev
+    }
+
+    pub fn stats(&self) -> CellCacheStats {
+        CellCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            used_bytes: self.used_bytes.load(Ordering::Relaxed),
+            budget_bytes: self.budget_bytes.load(Ordering::Relaxed),
+        }
+    }
+
+    fn shard(&self, cell_id: &CellId) -> &Mutex<CacheShard> {
+        &self.shards[cell_id.key()[0] as usize % SHARDS_COUNT]
+    }
+
+    fn account_eviction(&self, evicted: u64, evicted_bytes: u64) {
+        if evicted > 0 {
+            self.evictions.fetch_add(evicted, Ordering::Relaxed);
+            self.used_bytes.fetch_sub(evicted_bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+impl std::fmt::Debug for CellCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CellCache {:?}", self.stats())
+    }
+}
+
+fn entry_size(data: &[u8]) -> u64 {
+    data.len() as u64 + ENTRY_OVERHEAD
+}
+
+#[test]
+fn test_cell_cache_keyed_by_db() {
+    let cache = CellCache::new(1 << 20);
+    let cell_id = CellId::new(ton_types::UInt256::from([1; 32]));
+    cache.put(0, &cell_id, &[1, 2, 3]);
+    assert_eq!(cache.get(0, &cell_id).unwrap().as_slice(), &[1, 2, 3]);
+    // Cell of one database is not served for another one
+    assert!(cache.get(1, &cell_id).is_none());
+    cache.put(1, &cell_id, &[1, 2, 3]);
+    cache.remove(0, &cell_id);
+    assert!(cache.get(0, &cell_id).is_none());
+    assert!(cache.get(1, &cell_id).is_some());
+    assert_eq!(cache.stats().used_bytes, entry_size(&[1, 2, 3]));
+    cache.remove(1, &cell_id);
+    assert_eq!(cache.stats().used_bytes, 0);
+}
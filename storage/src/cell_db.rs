@@ -1,5 +1,5 @@
 use crate::{
-    db_impl_base, db::traits::{KvcTransaction, KvcTransactional},
+    cell_cache::CellCache, db_impl_base, db::traits::{KvcTransaction, KvcTransactional},
     dynamic_boc_db::DynamicBocDb, types::{CellId, StorageCell}
 };
 use std::sync::Arc;
@@ -8,9 +8,15 @@ use ton_types::Result;
 db_impl_base!(CellDb, KvcTransactional, CellId);
 
 impl CellDb {
-    /// Gets cell from key-value storage by cell id
-    pub fn get_cell(&self, cell_id: &CellId, boc_db: Arc<DynamicBocDb>) -> Result<StorageCell> {
-        StorageCell::deserialize(boc_db, self.db.get(&cell_id)?.as_ref())
+    /// Gets cell from cache or key-value storage by cell id
+    pub fn get_cell(&self, cell_id: &CellId, boc_db: Arc<DynamicBocDb>, cache: &CellCache) -> Result<StorageCell> {
+        let db_index = boc_db.db_index();
+        if let Some(data) = cache.get(db_index, cell_id) {
+            return StorageCell::deserialize(boc_db, &data)
+        }
+        let data = self.db.get(&cell_id)?;
+        cache.put(db_index, cell_id, data.as_ref());
+        StorageCell::deserialize(boc_db, data.as_ref())
     }
 
     /// Puts cell into transaction
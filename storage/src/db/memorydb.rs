@@ -90,6 +90,48 @@ impl<K: DbKey + Send + Sync> KvcReadable<K> for MemoryDb {
         }
         Ok(true)
     }
+
+    fn for_each_descending(
+        &self,
+        from: &[u8],
+        predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        let mut pairs = Vec::new();
+        for (key, value) in self.map()?.lock().unwrap().iter() {
+            if &key[..] <= from {
+                pairs.push((key.clone(), value.clone()));
+            }
+        }
+        pairs.sort_by(|(key1, _), (key2, _)| key2.cmp(key1));
+
+        for (key, value) in pairs {
+            if !predicate(&key[..], &value[..])? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn for_each_ascending(
+        &self,
+        from: &[u8],
+        predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        let mut pairs = Vec::new();
+        for (key, value) in self.map()?.lock().unwrap().iter() {
+            if &key[..] >= from {
+                pairs.push((key.clone(), value.clone()));
+            }
+        }
+        pairs.sort_by(|(key1, _), (key2, _)| key1.cmp(key2));
+
+        for (key, value) in pairs {
+            if !predicate(&key[..], &value[..])? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
 }
 
 /// Implementation of wriatable key-value collection for MemoryDb. Actual implementation is blocking.
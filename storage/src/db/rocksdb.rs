@@ -4,9 +4,90 @@ use crate::{
     },
     error::StorageError, types::DbSlice
 };
-use rocksdb::{DB, IteratorMode, Options, Snapshot, WriteBatch};
+use rocksdb::{
+    BlockBasedOptions, Cache, DB, Direction, IteratorMode, Options, Snapshot, WriteBatch
+};
 use ton_types::{fail, Result};
-use std::{fmt::{Debug, Formatter}, path::{Path, PathBuf}, sync::Arc};
+use std::{fmt::{Debug, Formatter}, path::{Path, PathBuf}, sync::{Arc, Mutex, Weak}};
+
+lazy_static::lazy_static! {
+    static ref SECONDARY_MODE: Mutex<Option<SecondaryMode>> = Mutex::new(None);
+    static ref MEMORY_LIMITS: Mutex<MemoryLimits> = Mutex::new(MemoryLimits::default());
+    static ref PRIMARY_INSTANCES: Mutex<Vec<Weak<Option<DB>>>> = Mutex::new(Vec::new());
+}
+
+#[derive(Default)]
+struct MemoryLimits {
+    block_cache: Option<Cache>,
+    write_buffer_size: Option<usize>,
+}
+
+struct SecondaryMode {
+    root: PathBuf,
+    instances: Vec<Weak<Option<DB>>>,
+}
+
+/// Makes all instances created after the call secondary ones: they read data of primary
+/// instances opened by another process and fail on writes. Secondary instances keep
+/// their own info logs in subdirectories of given root.
+pub fn enable_secondary_mode(root: impl AsRef<Path>) {
+    *SECONDARY_MODE.lock().expect("Poisoned Mutex") = Some(SecondaryMode {
+        root: root.as_ref().to_path_buf(),
+        instances: Vec::new(),
+    });
+}
+
+/// Sets memory limits of instances created after the call: block cache of given size
+/// shared by all instances and write buffer size of each instance, in bytes.
+/// RocksDB defaults are used for unset limits.
+pub fn set_memory_limits(
+    block_cache_size: Option<usize>,
+    write_buffer_size: Option<usize>
+) -> Result<()> {
+    let block_cache = block_cache_size.map(Cache::new_lru_cache).transpose()?;
+    *MEMORY_LIMITS.lock().expect("Poisoned Mutex") = MemoryLimits {
+        block_cache,
+        write_buffer_size,
+    };
+    Ok(())
+}
+
+/// Flushes memtables of all open primary instances, so nothing is replayed from WAL
+/// on next open. Returns count of instances.
+pub fn flush_all() -> Result<usize> {
+    let mut instances = PRIMARY_INSTANCES.lock().expect("Poisoned Mutex");
+    instances.retain(|db| db.strong_count() > 0);
+    let mut count = 0;
+    for db in instances.iter().filter_map(|db| db.upgrade()) {
+        if let Some(db) = &*db {
+            db.flush()?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+pub fn is_secondary_mode() -> bool {
+    SECONDARY_MODE.lock().expect("Poisoned Mutex").is_some()
+}
+
+/// Catches up secondary instances with their primary ones, returns count of instances
+pub fn catch_up_with_primary() -> Result<usize> {
+    let mut mode = SECONDARY_MODE.lock().expect("Poisoned Mutex");
+    let mode = match mode.as_mut() {
+        Some(mode) => mode,
+        None => fail!("RocksDB instances are not opened as secondary ones")
+    };
+    mode.instances.retain(|db| db.strong_count() > 0);
+    let mut count = 0;
+    for db in mode.instances.iter().filter_map(|db| db.upgrade()) {
+        if let Some(db) = &*db {
+            db.try_catch_up_with_primary()?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
 
 #[derive(Debug)]
 pub struct RocksDb {
@@ -27,12 +108,43 @@ impl RocksDb {
         let mut options = Options::default();
         options.create_if_missing(true);
         options.set_max_total_wal_size(1024 * 1024 * 1024);
+        {
+            let limits = MEMORY_LIMITS.lock().expect("Poisoned Mutex");
+            if let Some(cache) = &limits.block_cache {
+                let mut block_options = BlockBasedOptions::default();
+                block_options.set_block_cache(cache);
+                options.set_block_based_table_factory(&block_options);
+            }
+            if let Some(size) = limits.write_buffer_size {
+                options.set_write_buffer_size(size);
+            }
+        }
 
         configure_options(&mut options);
 
+        let mut secondary_mode = SECONDARY_MODE.lock().expect("Poisoned Mutex");
+        let db = match secondary_mode.as_mut() {
+            Some(mode) => {
+                // Secondary instance has to keep all files of primary one open
+                options.set_max_open_files(-1);
+                let secondary_path = mode.root.join(
+                    pathbuf.to_string_lossy().trim_matches('/').replace('/', "_")
+                );
+                let db = Arc::new(Some(DB::open_as_secondary(&options, &pathbuf, &secondary_path)
+                    .expect(&format!("Cannot open DB {:?} as secondary", pathbuf))));
+                mode.instances.push(Arc::downgrade(&db));
+                db
+            },
+            None => {
+                let db = Arc::new(Some(DB::open(&options, path)
+                    .expect(&format!("Cannot open DB {:?}", pathbuf))));
+                PRIMARY_INSTANCES.lock().expect("Poisoned Mutex").push(Arc::downgrade(&db));
+                db
+            }
+        };
+
         Self {
-            db: Arc::new(Some(DB::open(&options, path)
-                .expect(&format!("Cannot open DB {:?}", pathbuf)))),
+            db,
             path: pathbuf
         }
     }
@@ -62,6 +174,17 @@ impl Kvc for RocksDb {
 
         Ok(DB::destroy(&Options::default(), &self.path)?)
     }
+
+    fn compact(&self) -> Result<()> {
+        self.db()?.compact_range(None::<&[u8]>, None::<&[u8]>);
+        Ok(())
+    }
+
+    fn checkpoint(&self, path: &Path) -> Result<()> {
+        // SST files are hard-linked if target is on the same filesystem
+        rocksdb::checkpoint::Checkpoint::new(self.db()?)?.create_checkpoint(path)?;
+        Ok(())
+    }
 }
 
 /// Implementation of readable key-value collection for RocksDB. Actual implementation is blocking.
@@ -85,6 +208,32 @@ impl<K: DbKey + Send + Sync> KvcReadable<K> for RocksDb {
         Ok(true)
     }
 
+    fn for_each_descending(
+        &self,
+        from: &[u8],
+        predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        for (key, value) in self.db()?.iterator(IteratorMode::From(from, Direction::Reverse)) {
+            if !predicate(key.as_ref(), value.as_ref())? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn for_each_ascending(
+        &self,
+        from: &[u8],
+        predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        for (key, value) in self.db()?.iterator(IteratorMode::From(from, Direction::Forward)) {
+            if !predicate(key.as_ref(), value.as_ref())? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
 }
 
 /// Implementation of writable key-value collection for RocksDB. Actual implementation is blocking.
@@ -1,6 +1,6 @@
 use crate::{db::traits::DbKey, error::StorageError, types::DbSlice};
-use std::{fmt::Debug, sync::Arc};
-use ton_types::Result;
+use std::{fmt::Debug, path::Path, sync::Arc};
+use ton_types::{fail, Result};
 
 /// Trait for key-value collections
 pub trait Kvc: Debug + Send + Sync {
@@ -14,6 +14,16 @@ pub trait Kvc: Debug + Send + Sync {
 
     /// Destroys this key-value collection and underlying database
     fn destroy(&mut self) -> Result<()>;
+
+    /// Compacts whole underlying database, if it is supported. Actual implementation is blocking.
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Creates consistent copy of underlying database in given directory, if it is supported
+    fn checkpoint(&self, _path: &Path) -> Result<()> {
+        fail!("checkpoint is not supported")
+    }
 }
 
 /// Trait for readable key-value collections
@@ -67,6 +77,26 @@ pub trait KvcReadable<K: DbKey + Send + Sync>: Kvc {
 
     /// Iterates over items in key-value collection, running predicate for each key-value pair
     fn for_each(&self, predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>) -> Result<bool>;
+
+    /// Iterates over items in descending order of keys, starting from the greatest key which
+    /// is not greater than given one, running predicate for each key-value pair
+    fn for_each_descending(
+        &self,
+        _from: &[u8],
+        _predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        fail!("for_each_descending() is not supported")
+    }
+
+    /// Iterates over items in ascending order of keys, starting from the least key which
+    /// is not less than given one, running predicate for each key-value pair
+    fn for_each_ascending(
+        &self,
+        _from: &[u8],
+        _predicate: &mut dyn FnMut(&[u8], &[u8]) -> Result<bool>
+    ) -> Result<bool> {
+        fail!("for_each_ascending() is not supported")
+    }
 }
 
 /// Trait for writable key-value collections
@@ -1,5 +1,5 @@
 use crate::{
-    cell_db::CellDb, /*dynamic_boc_diff_writer::{DynamicBocDiffFactory, DynamicBocDiffWriter},*/
+    cell_cache::CellCache, cell_db::CellDb, /*dynamic_boc_diff_writer::{DynamicBocDiffFactory, DynamicBocDiffWriter},*/
     types::{CellId, StorageCell},
     db::traits::KvcTransaction,
     TARGET,
@@ -14,6 +14,7 @@ use ton_types::{Cell, Result, error, CellImpl, MAX_LEVEL};
 pub struct DynamicBocDb {
     db: Arc<CellDb>,
     cells: Arc<RwLock<FnvHashMap<CellId, Weak<StorageCell>>>>,
+    cache: Arc<CellCache>,
     db_index: u32,
 }
 
@@ -26,11 +27,12 @@ impl DynamicBocDb {
 */
 
     /// Constructs new instance using given key-value collection implementation
-    pub(crate) fn with_db(db: CellDb, db_index: u32) -> Self {
+    pub(crate) fn with_db(db: CellDb, db_index: u32, cache: Arc<CellCache>) -> Self {
         let db = Arc::new(db);
         Self {
             db: Arc::clone(&db),
             cells: Arc::new(RwLock::new(FnvHashMap::default())),
+            cache,
             db_index,
         }
     }
@@ -47,6 +49,13 @@ impl DynamicBocDb {
         self.db_index
     }
 
+    /// Forgets cells deleted from the database, so they are not served from cache
+    pub(crate) fn forget_cells(&self, cell_ids: &[CellId]) {
+        for cell_id in cell_ids {
+            self.cache.remove(self.db_index, cell_id);
+        }
+    }
+
     /// Converts tree of cells into DynamicBoc
     pub fn save_as_dynamic_boc(self: &Arc<Self>, root_cell: &dyn CellImpl) -> Result<usize> {
         let mut transaction = self.db.begin_transaction()?;
@@ -94,7 +103,7 @@ impl DynamicBocDb {
             false
         };
         let storage_cell = Arc::new(
-            CellDb::get_cell(self.db.deref(), &cell_id, Arc::clone(self))
+            CellDb::get_cell(self.db.deref(), &cell_id, Arc::clone(self), &self.cache)
                 .map_err(|e| error!("Can't load cell  id {}  db_index {}  in_cache {}  error: {}", cell_id, self.db_index, in_cache, e))?
         );
         self.cells.write()
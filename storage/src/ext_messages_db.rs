@@ -0,0 +1,4 @@
+use crate::db_impl_base;
+use crate::db::traits::KvcWriteable;
+
+db_impl_base!(ExtMessagesDb, KvcWriteable, ton_types::types::UInt256);
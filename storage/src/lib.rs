@@ -4,6 +4,7 @@ pub mod block_handle_db;
 pub mod block_index_db;
 pub mod block_info_db;
 pub mod catchain_persistent_db;
+pub mod cell_cache;
 mod cell_db;
 pub mod db;
 mod dynamic_boc_db;
@@ -14,9 +15,14 @@ mod macros;
 pub mod node_state_db;
 pub mod shardstate_db;
 pub mod shardstate_persistent_db;
+pub mod state_serializer;
 pub mod traits;
 pub mod types;
 pub mod shard_top_blocks_db;
+pub mod ext_messages_db;
+pub mod transactions_index_db;
+
+pub use error::StorageError;
 
 use std::time::{Duration, Instant};
 
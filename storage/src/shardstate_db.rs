@@ -1,8 +1,8 @@
 use crate::{
-    cell_db::CellDb, 
+    cell_cache::CellCache, cell_db::CellDb, 
     db::{rocksdb::RocksDb, traits::{DbKey, KvcSnapshotable, KvcTransaction}},
     dynamic_boc_db::DynamicBocDb, /*dynamic_boc_diff_writer::DynamicBocDiffWriter,*/
-    traits::Serializable, types::{CellId, Reference, StorageCell},
+    state_serializer::DbCellsSource, traits::Serializable, types::{CellId, Reference, StorageCell},
     TARGET,
 };
 use fnv::FnvHashSet;
@@ -14,7 +14,7 @@ use std::{
     time::{Duration, Instant}
 };
 use ton_block::{BlockIdExt, UnixTime32};
-use ton_types::{ByteOrderRead, Cell, Result, fail};
+use ton_types::{ByteOrderRead, Cell, Result, UInt256, fail};
 
 pub(crate) struct DbEntry {
     pub cell_id: CellId,
@@ -54,6 +54,8 @@ pub struct ShardStateDb {
     dynamic_boc_db_0_writers: AtomicU32,
     dynamic_boc_db_1: Arc<DynamicBocDb>,
     dynamic_boc_db_1_writers: AtomicU32,
+    // Held by GC while collecting, checkpoints wait for it to keep states and cells consistent
+    gc_lock: tokio::sync::Mutex<()>,
 }
 
 impl ShardStateDb {
@@ -65,11 +67,13 @@ impl ShardStateDb {
         shardstate_db_path: P1,
         cell_db_path: P2,
         cell_db_path_additional: P2,
+        cell_cache: Arc<CellCache>,
     ) -> Result<Arc<Self>> {
         Self::with_dbs(
             Arc::new(RocksDb::with_path(shardstate_db_path)),
             CellDb::with_path(cell_db_path),
             CellDb::with_path(cell_db_path_additional),
+            cell_cache,
         )
     }
 
@@ -78,33 +82,50 @@ impl ShardStateDb {
         shardstate_db: Arc<dyn KvcSnapshotable<BlockIdExt>>,
         cell_db_0: CellDb,
         cell_db_1: CellDb,
+        cell_cache: Arc<CellCache>,
     )-> Result<Arc<Self>> {
         let instance = Arc::new(Self {
             shardstate_db,
             current_dynamic_boc_db_index: AtomicU32::new(0),
-            dynamic_boc_db_0: Arc::new(DynamicBocDb::with_db(cell_db_0, 0)),
+            dynamic_boc_db_0: Arc::new(DynamicBocDb::with_db(cell_db_0, 0, cell_cache.clone())),
             dynamic_boc_db_0_writers: AtomicU32::new(0),
-            dynamic_boc_db_1: Arc::new(DynamicBocDb::with_db(cell_db_1, 1)),
+            dynamic_boc_db_1: Arc::new(DynamicBocDb::with_db(cell_db_1, 1, cell_cache)),
             dynamic_boc_db_1_writers: AtomicU32::new(0),
+            gc_lock: tokio::sync::Mutex::new(()),
         });
         Ok(instance)
     }
 
+    /// Compacts states and both cells databases. Actual implementation is blocking.
+    pub fn compact(&self) -> Result<()> {
+        self.shardstate_db.compact()?;
+        self.dynamic_boc_db_0.cell_db().compact()?;
+        self.dynamic_boc_db_1.cell_db().compact()
+    }
+
+    /// Creates checkpoints of states and both cells databases. States go first, so every
+    /// saved root has its cells in the checkpoint; GC is not run meanwhile.
+    pub async fn checkpoint(
+        &self,
+        shardstate_db_path: &Path,
+        cell_db_path: &Path,
+        cell_db_path_additional: &Path
+    ) -> Result<()> {
+        let _gc_guard = self.gc_lock.lock().await;
+        self.shardstate_db.checkpoint(shardstate_db_path)?;
+        self.dynamic_boc_db_0.cell_db().checkpoint(cell_db_path)?;
+        self.dynamic_boc_db_1.cell_db().checkpoint(cell_db_path_additional)
+    }
+
     pub fn start_gc(
         self: Arc<Self>,
         gc_resolver: Arc<dyn AllowStateGcResolver>,
         run_interval_adjustable_ms: Arc<AtomicU32>,
-    ) {
-        // When test-blockchains deploys all validator-nodes start approx at one time, 
-        // so all GC start at one time all nodes. It can reduce whole blockchain's performans,
-        // so let's mix start time next way
-        let run_gc_interval = run_interval_adjustable_ms.load(Ordering::Relaxed) as u64;
-        let mut rng = rand::thread_rng();
-        let rand_initial_sleep = rand::Rng::gen_range(&mut rng, 0, run_gc_interval);
-
+        initial_delay: Duration,
+    ) -> tokio::task::JoinHandle<()> {
         tokio::spawn(async move {
 
-            tokio::time::sleep(Duration::from_millis(rand_initial_sleep)).await;
+            tokio::time::sleep(initial_delay).await;
 
             let mut last_gc_duration = Duration::from_secs(0);
             loop {
@@ -142,6 +163,7 @@ impl ShardStateDb {
                 }
 
                 // start GC
+                let gc_guard = self.gc_lock.lock().await;
                 log::info!(target: TARGET, "Statring GC for db {}", collected_db.db_index());
                 let collecting_start = Instant::now();
                 let result = gc(
@@ -149,6 +171,7 @@ impl ShardStateDb {
                     collected_db.clone(),
                     gc_resolver.clone(),
                 ).await;
+                drop(gc_guard);
                 last_gc_duration = collecting_start.elapsed();
                 match result {
                     Err(e) => {
@@ -177,13 +200,14 @@ impl ShardStateDb {
                             gc_stat.sweep_time.as_millis(),
                             (gc_stat.mark_time + gc_stat.sweep_time).as_millis()
                         );
+                        gc_resolver.gc_finished(&gc_stat);
                     }
                 }
 
                 // Change current db's index
                 self.current_dynamic_boc_db_index.store(collected_db.db_index(), Ordering::Relaxed);
             };
-        });
+        })
     }
 
     /// Returns reference to shardstates database
@@ -256,10 +280,36 @@ impl ShardStateDb {
         Ok(root_cell)
     }
 
+    /// Name of DB which keeps previously stored state: shardstate_db if there is no entry
+    /// for it, otherwise cells DB its entry refers to. Is used to report errors of `get`.
+    pub fn storage_name(&self, id: &BlockIdExt) -> &'static str {
+        let db_entry = self.shardstate_db.try_get(id).ok()
+            .flatten()
+            .and_then(|db_entry| DbEntry::from_slice(db_entry.as_ref()).ok());
+        match db_entry.map(|db_entry| db_entry.db_index) {
+            Some(1) => "cells_db1",
+            Some(0) => "cells_db",
+            _ => "shardstate_db"
+        }
+    }
+
+    /// Gets root hash and source of cells of previously stored state to serialize it
+    pub fn cells_source(&self, id: &BlockIdExt) -> Result<(UInt256, DbCellsSource)> {
+        let db_entry = DbEntry::from_slice(self.shardstate_db.get(id)?.as_ref())?;
+        let boc_db = match db_entry.db_index {
+            1 => &self.dynamic_boc_db_1,
+            0 => &self.dynamic_boc_db_0,
+            index => fail!("Invalid db's index {}", index)
+        };
+        Ok((db_entry.cell_id.into(), DbCellsSource::new(boc_db.clone())))
+    }
+
 }
 
 pub trait AllowStateGcResolver: Send + Sync {
     fn allow_state_gc(&self, block_id_ext: &BlockIdExt, gc_utime: UnixTime32) -> Result<bool>;
+    // Called after each successful GC run to report its results
+    fn gc_finished(&self, _gc_stat: &GcStatistic) {}
 }
 
 #[derive(Default)]
@@ -413,6 +463,7 @@ fn sweep(
     for (block_id, cell_id) in to_sweep {
 
         let mut transaction = dynamic_boc_db.begin_transaction()?;
+        let mut deleted = Vec::new();
 
         deleted_count += sweep_cells_recursive(
             dynamic_boc_db.as_ref(),
@@ -421,10 +472,12 @@ fn sweep(
             &cell_id,
             &marked,
             &mut sweeped,
+            &mut deleted,
         )?;
         log::trace!(target: TARGET, "GC::sweep  block_id {}", block_id);
 
         transaction.commit()?;
+        dynamic_boc_db.forget_cells(&deleted);
 
         shardstate_db.delete(&block_id)?;
     }
@@ -439,6 +492,7 @@ fn sweep_cells_recursive(
     root_id: &CellId,
     marked: &FnvHashSet<CellId>,
     sweeped: &mut FnvHashSet<CellId>,
+    deleted: &mut Vec<CellId>,
 ) -> Result<usize> {
 
     if marked.contains(cell_id) || sweeped.contains(cell_id) {
@@ -457,6 +511,7 @@ fn sweep_cells_recursive(
                         root_id,
                         marked,
                         sweeped,
+                        deleted,
                     )?;
                 }
 
@@ -467,6 +522,7 @@ fn sweep_cells_recursive(
                 );
 
                 transaction.delete(cell_id);
+                deleted.push(cell_id.clone());
 
                 deleted_count += 1;
             }
@@ -486,3 +542,66 @@ fn load_cell_references(dynamic_boc_db: &DynamicBocDb, cell_id: &CellId) -> Resu
     let slice = dynamic_boc_db.cell_db().get(cell_id)?;
     StorageCell::deserialize_references(slice.as_ref())
 }
+
+#[test]
+fn test_shardstate_db_errors() {
+    use crate::error::StorageError;
+    use ton_block::ShardIdent;
+
+    let path = std::env::temp_dir().join(format!("shardstate_db_{}", std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    let db = ShardStateDb::with_paths(
+        path.join("shardstate_db"), path.join("cells_db"), path.join("cells_db1"),
+        Arc::new(CellCache::new(1 << 20))
+    ).unwrap();
+    let id = |seq_no: u32| BlockIdExt::with_params(
+        ShardIdent::masterchain(), seq_no, UInt256::from_slice(&[seq_no as u8; 32]), UInt256::default()
+    );
+    let mut builder = ton_types::BuilderData::new();
+    builder.append_bits(0x5a, 8).unwrap();
+    let root = builder.into_cell().unwrap();
+    db.put(&id(1), root.clone()).unwrap();
+    assert_eq!(db.get(&id(1)).unwrap().repr_hash(), root.repr_hash());
+    assert_eq!(db.storage_name(&id(1)), "cells_db");
+
+    // Missing state is reported as storage error, so it is classified by caller
+    let err = db.get(&id(2)).unwrap_err();
+    assert!(matches!(err.downcast_ref::<StorageError>(), Some(StorageError::KeyNotFound(..))));
+    assert_eq!(db.storage_name(&id(2)), "shardstate_db");
+    drop(db);
+    std::fs::remove_dir_all(&path).ok();
+}
+
+#[test]
+fn test_swept_cells_leave_cache() {
+    use ton_block::ShardIdent;
+
+    let path = std::env::temp_dir().join(format!("shardstate_db_sweep_{}", std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    let cache = Arc::new(CellCache::new(1 << 20));
+    let db = ShardStateDb::with_paths(
+        path.join("shardstate_db"), path.join("cells_db"), path.join("cells_db1"), cache.clone()
+    ).unwrap();
+    let id = BlockIdExt::with_params(
+        ShardIdent::masterchain(), 1, UInt256::from_slice(&[1; 32]), UInt256::default()
+    );
+    let mut builder = ton_types::BuilderData::new();
+    builder.append_bits(0x5a, 8).unwrap();
+    let root = builder.into_cell().unwrap();
+    db.put(&id, root.clone()).unwrap();
+    db.get(&id).unwrap();
+    assert!(cache.stats().used_bytes > 0);
+
+    let cell_id = CellId::new(root.repr_hash());
+    let swept = sweep(
+        db.shardstate_db.clone(),
+        db.dynamic_boc_db_0.clone(),
+        vec![(id.clone(), cell_id.clone())],
+        FnvHashSet::default()
+    ).unwrap();
+    assert_eq!(swept, 1);
+    assert_eq!(cache.stats().used_bytes, 0);
+    assert!(db.dynamic_boc_db_0.load_cell(&cell_id).is_err());
+    drop(db);
+    std::fs::remove_dir_all(&path).ok();
+}
@@ -1,12 +1,20 @@
-use crate::{db::{filedb::FileDb, traits::KvcWriteableAsync}};
+use crate::{
+    db::{filedb::FileDb, traits::{DbKey, KvcWriteableAsync}},
+    state_serializer::{CellsSource, SerializationProgress, StateSerializer}
+};
 //#[cfg(test)]
 //use crate::db::db::{async_adapter::KvcWriteableAsyncAdapter, memorydb::MemoryDb}; 
-use std::{ops::{Deref, DerefMut}, path::Path};
+use std::{ops::{Deref, DerefMut}, path::{Path, PathBuf}, time::{Duration, Instant}};
+use tokio::io::AsyncWriteExt;
 use ton_block::BlockIdExt;
+use ton_types::{error, Result, UInt256};
+
+const THROTTLED_CHUNK_SIZE: usize = 1024 * 1024;
 
 #[derive(Debug)]
 pub struct ShardStatePersistentDb {
     db: Box<dyn KvcWriteableAsync<BlockIdExt>>,
+    path: PathBuf,
 }
 
 impl ShardStatePersistentDb {
@@ -18,10 +26,97 @@ impl ShardStatePersistentDb {
     /// Constructs new instance using FileDb with given path
     pub fn with_path<P: AsRef<Path>>(path: P) -> Self {
         Self {
-            db: Box::new(FileDb::with_path(path))
+            db: Box::new(FileDb::with_path(&path)),
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Writes state by chunks keeping given speed (bytes per second, 0 - unlimited).
+    /// Data goes to temporary file renamed at the end, so partially written state
+    /// is never read. `progress` is called with count of written bytes.
+    pub async fn put_throttled(
+        &self,
+        id: &BlockIdExt,
+        data: &[u8],
+        bytes_per_sec: u64,
+        progress: &(dyn Fn(u64) + Send + Sync)
+    ) -> Result<()> {
+        let path = FileDb::with_path(&self.path).make_path(id.key());
+        let dir = path.parent()
+            .ok_or_else(|| error!("Unable to get parent path"))?;
+        tokio::fs::create_dir_all(dir).await?;
+        let tmp_path = path.with_extension("tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        let started = Instant::now();
+        let mut written = 0;
+        for chunk in data.chunks(THROTTLED_CHUNK_SIZE) {
+            file.write_all(chunk).await?;
+            written += chunk.len() as u64;
+            progress(written);
+            if bytes_per_sec > 0 {
+                let expected = Duration::from_millis(written * 1000 / bytes_per_sec);
+                let elapsed = started.elapsed();
+                if expected > elapsed {
+                    tokio::time::sleep(expected - elapsed).await;
+                }
+            }
         }
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&tmp_path, &path).await?;
+        Ok(())
     }
 
+    /// Serializes state from given cells straight into the file keeping given speed
+    /// (bytes per second, 0 - unlimited). Interrupted serialization is resumed by the next
+    /// call for the same state. Actual implementation is blocking.
+    pub fn put_serialized(
+        &self,
+        id: &BlockIdExt,
+        source: &dyn CellsSource,
+        root: &UInt256,
+        estimate: u64,
+        bytes_per_sec: u64,
+        progress: &dyn Fn(SerializationProgress)
+    ) -> Result<()> {
+        let path = FileDb::with_path(&self.path).make_path(id.key());
+        let dir = path.parent()
+            .ok_or_else(|| error!("Unable to get parent path"))?;
+        std::fs::create_dir_all(dir)?;
+        StateSerializer::new(source, root.clone(), &path.with_extension("parts"))?
+            .serialize(&path, estimate, bytes_per_sec, progress)
+    }
+
+    /// Copies stored states into given directory. States are never changed once written,
+    /// so they are hard-linked when possible. Returns count of copied states.
+    pub async fn backup(&self, target: &Path) -> Result<u64> {
+        let mut copied = 0;
+        let mut dirs = vec!(PathBuf::new());
+        while let Some(dir) = dirs.pop() {
+            let source = self.path.join(&dir);
+            if !source.exists() {
+                continue
+            }
+            tokio::fs::create_dir_all(target.join(&dir)).await?;
+            let mut entries = tokio::fs::read_dir(&source).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let relative = dir.join(entry.file_name());
+                if entry.file_type().await?.is_dir() {
+                    // Work directories of serialization in progress are skipped
+                    if entry.path().extension().map(|ext| ext != "parts").unwrap_or(true) {
+                        dirs.push(relative);
+                    }
+                } else if entry.path().extension().map(|ext| ext != "tmp").unwrap_or(true) {
+                    let to = target.join(&relative);
+                    if tokio::fs::hard_link(entry.path(), &to).await.is_err() {
+                        tokio::fs::copy(entry.path(), &to).await?;
+                    }
+                    copied += 1;
+                }
+            }
+        }
+        Ok(copied)
+    }
 }
 
 impl Deref for ShardStatePersistentDb {
@@ -0,0 +1,501 @@
+use crate::{
+    db::{rocksdb::RocksDb, traits::{KvcReadable, KvcTransactional}},
+    dynamic_boc_db::DynamicBocDb, types::CellId,
+};
+use fnv::FnvHashMap;
+use std::{
+    fs::{File, OpenOptions}, io::{BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}
+};
+use ton_types::{CellImpl, CellType, Result, UInt256, error, fail};
+
+const BOC_MAGIC: [u8; 4] = [0xb5, 0xee, 0x9c, 0x72];
+// Traversal state is saved after this count of cells
+const CHECKPOINT_CELLS: u64 = 100_000;
+// Records read from work files at once while writing BOC
+const READ_CHUNK_RECORDS: u64 = 65_536;
+const WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Cell in BOC representation with hashes of its children
+pub struct RawCell {
+    d1: u8,
+    d2: u8,
+    data: Vec<u8>,
+    references: Vec<UInt256>,
+}
+
+impl RawCell {
+    pub fn with_cell(cell: &dyn CellImpl, references: Vec<UInt256>) -> Self {
+        let bits = cell.bit_length();
+        let exotic = cell.cell_type() != CellType::Ordinary;
+        Self {
+            d1: references.len() as u8 | (exotic as u8) << 3 | cell.level_mask().mask() << 5,
+            d2: (bits / 8 + (bits + 7) / 8) as u8,
+            data: cell.data()[..(bits + 7) / 8].to_vec(),
+            references,
+        }
+    }
+}
+
+/// Cells of state to serialize are loaded by their hashes
+pub trait CellsSource: Send + Sync {
+    fn load_cell(&self, hash: &UInt256) -> Result<RawCell>;
+}
+
+/// Cells of stored shard state. Children are not loaded with the cell,
+/// so visited cells are not kept in memory by their parents.
+pub struct DbCellsSource {
+    boc_db: Arc<DynamicBocDb>,
+}
+
+impl DbCellsSource {
+    pub(crate) fn new(boc_db: Arc<DynamicBocDb>) -> Self {
+        Self { boc_db }
+    }
+}
+
+impl CellsSource for DbCellsSource {
+    fn load_cell(&self, hash: &UInt256) -> Result<RawCell> {
+        let cell = self.boc_db.load_cell(&CellId::new(hash.clone()))?;
+        let references = (0..cell.references_count())
+            .map(|i| cell.reference_hash(i))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RawCell::with_cell(cell.as_ref(), references))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SerializationProgress {
+    /// Count of visited cells and estimated total count (0 if unknown)
+    Traversal { cells: u64, estimate: u64 },
+    /// Count of cells written into the file and total count
+    Writing { cells: u64, total: u64 },
+}
+
+struct Frame {
+    hash: UInt256,
+    cell: RawCell,
+    next: usize,
+}
+
+#[derive(Default)]
+struct Checkpoint {
+    // Count of written cell records
+    cells: u64,
+    cells_file_len: u64,
+    data_bytes: u64,
+    references: u64,
+    // Path from root: hash of cell and index of its next child to visit
+    stack: Vec<(UInt256, u8)>,
+}
+
+impl Checkpoint {
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(36 + self.stack.len() * 33);
+        data.extend_from_slice(&self.cells.to_be_bytes());
+        data.extend_from_slice(&self.cells_file_len.to_be_bytes());
+        data.extend_from_slice(&self.data_bytes.to_be_bytes());
+        data.extend_from_slice(&self.references.to_be_bytes());
+        data.extend_from_slice(&(self.stack.len() as u32).to_be_bytes());
+        for (hash, next) in self.stack.iter() {
+            data.extend_from_slice(hash.as_slice());
+            data.push(*next);
+        }
+        data
+    }
+
+    fn deserialize(data: &[u8]) -> Result<Self> {
+        let mut reader = std::io::Cursor::new(data);
+        let mut read_u64 = || -> Result<u64> {
+            let mut buf = [0; 8];
+            reader.read_exact(&mut buf)?;
+            Ok(u64::from_be_bytes(buf))
+        };
+        let mut checkpoint = Self {
+            cells: read_u64()?,
+            cells_file_len: read_u64()?,
+            data_bytes: read_u64()?,
+            references: read_u64()?,
+            stack: Vec::new(),
+        };
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        for _ in 0..u32::from_be_bytes(len) {
+            let mut hash = [0; 32];
+            let mut next = [0; 1];
+            reader.read_exact(&mut hash)?;
+            reader.read_exact(&mut next)?;
+            checkpoint.stack.push((UInt256::from(hash), next[0]));
+        }
+        Ok(checkpoint)
+    }
+}
+
+/// Writes state BOC to file without building it in memory. Cells are visited in depth-first
+/// order, their records go to work files, and index of visited cells is kept in RocksDB.
+/// Traversal state is saved periodically, so interrupted serialization is resumed using
+/// the same work directory. Then BOC is assembled from work files in reverse order,
+/// so every cell goes before its children.
+pub struct StateSerializer<'a> {
+    source: &'a dyn CellsSource,
+    root: UInt256,
+    work_dir: PathBuf,
+    checkpoint_cells: u64,
+    index: RocksDb,
+    pending: FnvHashMap<UInt256, u64>,
+}
+
+impl<'a> StateSerializer<'a> {
+
+    pub fn new(source: &'a dyn CellsSource, root: UInt256, work_dir: &Path) -> Result<Self> {
+        Self::with_checkpoint_cells(source, root, work_dir, CHECKPOINT_CELLS)
+    }
+
+    fn with_checkpoint_cells(
+        source: &'a dyn CellsSource,
+        root: UInt256,
+        work_dir: &Path,
+        checkpoint_cells: u64
+    ) -> Result<Self> {
+        std::fs::create_dir_all(work_dir)?;
+        Ok(Self {
+            source,
+            root,
+            work_dir: work_dir.to_path_buf(),
+            checkpoint_cells,
+            index: RocksDb::with_path(work_dir.join("index")),
+            pending: FnvHashMap::default(),
+        })
+    }
+
+    /// Writes BOC into given file keeping given speed (bytes per second, 0 - unlimited).
+    /// Work directory is removed after success.
+    pub fn serialize(
+        mut self,
+        path: &Path,
+        estimate: u64,
+        bytes_per_sec: u64,
+        progress: &dyn Fn(SerializationProgress)
+    ) -> Result<()> {
+        let checkpoint = self.traverse(estimate, progress)?;
+        self.write_boc(&checkpoint, path, bytes_per_sec, progress)?;
+        let work_dir = self.work_dir.clone();
+        drop(self);
+        std::fs::remove_dir_all(&work_dir)?;
+        Ok(())
+    }
+
+    fn traverse(
+        &mut self,
+        estimate: u64,
+        progress: &dyn Fn(SerializationProgress)
+    ) -> Result<Checkpoint> {
+        let mut checkpoint = match self.index.try_get(&checkpoint_key())? {
+            Some(data) => Checkpoint::deserialize(data.as_ref())?,
+            None => Checkpoint::default()
+        };
+        if checkpoint.cells > 0 && checkpoint.stack.is_empty() {
+            return Ok(checkpoint)
+        }
+        if checkpoint.cells > 0 {
+            log::info!(
+                "Serialization of state {:x} is resumed from {} cells", self.root, checkpoint.cells
+            );
+        }
+
+        // Records written after the checkpoint are dropped
+        let mut cells_file = OpenOptions::new().create(true).write(true)
+            .open(self.work_dir.join("cells"))?;
+        cells_file.set_len(checkpoint.cells_file_len)?;
+        cells_file.seek(SeekFrom::End(0))?;
+        let mut cells_file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, cells_file);
+        let mut offsets_file = OpenOptions::new().create(true).write(true)
+            .open(self.work_dir.join("offsets"))?;
+        offsets_file.set_len(checkpoint.cells * 8)?;
+        offsets_file.seek(SeekFrom::End(0))?;
+        let mut offsets_file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, offsets_file);
+
+        let mut stack = Vec::new();
+        for (hash, next) in checkpoint.stack.drain(..) {
+            let cell = self.source.load_cell(&hash)?;
+            stack.push(Frame { hash, cell, next: next as usize });
+        }
+        if checkpoint.cells == 0 {
+            let cell = self.source.load_cell(&self.root)?;
+            stack.push(Frame { hash: self.root.clone(), cell, next: 0 });
+        }
+
+        while let Some(frame) = stack.last_mut() {
+            if let Some(child) = frame.cell.references.get(frame.next) {
+                frame.next += 1;
+                if self.lookup(child)?.is_none() {
+                    let hash = child.clone();
+                    let cell = self.source.load_cell(&hash)?;
+                    stack.push(Frame { hash, cell, next: 0 });
+                }
+                continue
+            }
+
+            // All children are written, so they have numbers
+            let frame = stack.pop().ok_or_else(|| error!("INTERNAL ERROR: empty stack"))?;
+            offsets_file.write_all(&checkpoint.cells_file_len.to_be_bytes())?;
+            cells_file.write_all(&[frame.cell.d1, frame.cell.d2])?;
+            cells_file.write_all(&frame.cell.data)?;
+            for child in frame.cell.references.iter() {
+                let number = self.lookup(child)?
+                    .ok_or_else(|| error!("INTERNAL ERROR: child {:x} is not written", child))?;
+                cells_file.write_all(&number.to_be_bytes())?;
+            }
+            checkpoint.cells_file_len +=
+                2 + frame.cell.data.len() as u64 + frame.cell.references.len() as u64 * 8;
+            checkpoint.data_bytes += frame.cell.data.len() as u64;
+            checkpoint.references += frame.cell.references.len() as u64;
+            self.pending.insert(frame.hash, checkpoint.cells);
+            checkpoint.cells += 1;
+
+            if checkpoint.cells % self.checkpoint_cells == 0 || stack.is_empty() {
+                checkpoint.stack = stack.iter()
+                    .map(|frame| (frame.hash.clone(), frame.next as u8))
+                    .collect();
+                self.save_checkpoint(&checkpoint, &mut cells_file, &mut offsets_file)?;
+                progress(SerializationProgress::Traversal { cells: checkpoint.cells, estimate });
+            }
+        }
+        Ok(checkpoint)
+    }
+
+    fn lookup(&self, hash: &UInt256) -> Result<Option<u64>> {
+        if let Some(number) = self.pending.get(hash) {
+            return Ok(Some(*number))
+        }
+        match self.index.try_get(&CellId::new(hash.clone()))? {
+            Some(data) => {
+                let mut number = [0; 8];
+                number.copy_from_slice(data.as_ref());
+                Ok(Some(u64::from_be_bytes(number)))
+            }
+            None => Ok(None)
+        }
+    }
+
+    // Work files are synced before the index, and the index is written together
+    // with the checkpoint, so they always match each other
+    fn save_checkpoint(
+        &mut self,
+        checkpoint: &Checkpoint,
+        cells_file: &mut BufWriter<File>,
+        offsets_file: &mut BufWriter<File>,
+    ) -> Result<()> {
+        cells_file.flush()?;
+        cells_file.get_ref().sync_data()?;
+        offsets_file.flush()?;
+        offsets_file.get_ref().sync_data()?;
+        let mut transaction = KvcTransactional::<CellId>::begin_transaction(&self.index)?;
+        for (hash, number) in self.pending.drain() {
+            transaction.put(&CellId::new(hash), &number.to_be_bytes());
+        }
+        transaction.put(&checkpoint_key(), &checkpoint.serialize());
+        transaction.commit()
+    }
+
+    fn write_boc(
+        &self,
+        checkpoint: &Checkpoint,
+        path: &Path,
+        bytes_per_sec: u64,
+        progress: &dyn Fn(SerializationProgress)
+    ) -> Result<()> {
+        let total = checkpoint.cells;
+        let ref_size = bytes_for(total);
+        let cells_size = total * 2 + checkpoint.data_bytes + checkpoint.references * ref_size as u64;
+        let offset_size = bytes_for(cells_size);
+
+        let tmp_path = path.with_extension("tmp");
+        let mut file = BufWriter::with_capacity(WRITE_BUFFER_SIZE, File::create(&tmp_path)?);
+        file.write_all(&BOC_MAGIC)?;
+        // No index, no CRC
+        file.write_all(&[ref_size as u8, offset_size as u8])?;
+        write_be(&mut file, total, ref_size)?;
+        // One root, no absent cells
+        write_be(&mut file, 1, ref_size)?;
+        write_be(&mut file, 0, ref_size)?;
+        write_be(&mut file, cells_size, offset_size)?;
+        // Root is written last, so it goes first in reverse order
+        write_be(&mut file, 0, ref_size)?;
+
+        let mut cells_file = File::open(self.work_dir.join("cells"))?;
+        let mut offsets_file = File::open(self.work_dir.join("offsets"))?;
+        let started = Instant::now();
+        let mut written = 0;
+        let mut end = total;
+        while end > 0 {
+            let begin = end.saturating_sub(READ_CHUNK_RECORDS);
+            let mut offsets = vec![0; ((end - begin) * 8) as usize];
+            offsets_file.seek(SeekFrom::Start(begin * 8))?;
+            offsets_file.read_exact(&mut offsets)?;
+            let offsets = offsets.chunks(8).map(|offset| {
+                let mut buf = [0; 8];
+                buf.copy_from_slice(offset);
+                u64::from_be_bytes(buf)
+            }).collect::<Vec<_>>();
+            let end_offset = if end == total {
+                checkpoint.cells_file_len
+            } else {
+                let mut buf = [0; 8];
+                offsets_file.seek(SeekFrom::Start(end * 8))?;
+                offsets_file.read_exact(&mut buf)?;
+                u64::from_be_bytes(buf)
+            };
+            let mut records = vec![0; (end_offset - offsets[0]) as usize];
+            cells_file.seek(SeekFrom::Start(offsets[0]))?;
+            cells_file.read_exact(&mut records)?;
+
+            for i in (0..offsets.len()).rev() {
+                let record_end = offsets.get(i + 1).copied().unwrap_or(end_offset) - offsets[0];
+                let record = &records[(offsets[i] - offsets[0]) as usize..record_end as usize];
+                let data_len = (record[1] as usize + 1) / 2;
+                if record.len() != 2 + data_len + (record[0] & 7) as usize * 8 {
+                    fail!(
+                        "Broken cell record {} in work files of state {:x}", begin + i as u64, self.root
+                    )
+                }
+                file.write_all(&record[..2 + data_len])?;
+                for number in record[2 + data_len..].chunks(8) {
+                    let mut buf = [0; 8];
+                    buf.copy_from_slice(number);
+                    write_be(&mut file, total - 1 - u64::from_be_bytes(buf), ref_size)?;
+                }
+                written += 1;
+            }
+            end = begin;
+
+            progress(SerializationProgress::Writing { cells: written, total });
+            if bytes_per_sec > 0 {
+                let bytes = file.get_ref().metadata()?.len();
+                let expected = Duration::from_millis(bytes * 1000 / bytes_per_sec);
+                let elapsed = started.elapsed();
+                if expected > elapsed {
+                    std::thread::sleep(expected - elapsed);
+                }
+            }
+        }
+        file.flush()?;
+        file.get_ref().sync_all()?;
+        drop(file);
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+// No cell has zero hash, so the key is used for traversal checkpoint
+fn checkpoint_key() -> CellId {
+    CellId::new(UInt256::default())
+}
+
+// Minimal count of bytes to store given value
+fn bytes_for(value: u64) -> usize {
+    let mut bytes = 1;
+    while bytes < 8 && value >> (bytes * 8) != 0 {
+        bytes += 1;
+    }
+    bytes
+}
+
+fn write_be(dst: &mut impl Write, value: u64, size: usize) -> Result<()> {
+    dst.write_all(&value.to_be_bytes()[8 - size..])?;
+    Ok(())
+}
+
+#[cfg(test)]
+struct TestCellsSource {
+    cells: std::collections::HashMap<UInt256, ton_types::Cell>,
+    loads: std::sync::atomic::AtomicU64,
+    fail_after: u64,
+}
+
+#[cfg(test)]
+impl CellsSource for TestCellsSource {
+    fn load_cell(&self, hash: &UInt256) -> Result<RawCell> {
+        let loads = self.loads.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if loads >= self.fail_after {
+            fail!("Loading is interrupted")
+        }
+        let cell = self.cells.get(hash).ok_or_else(|| error!("Cell {:x} is not found", hash))?;
+        let references = (0..cell.references_count())
+            .map(|i| Ok(cell.reference(i)?.repr_hash()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RawCell::with_cell(&**cell, references))
+    }
+}
+
+#[cfg(test)]
+fn test_cells_source(root: &ton_types::Cell, fail_after: u64) -> TestCellsSource {
+    let mut cells = std::collections::HashMap::new();
+    let mut stack = vec![root.clone()];
+    while let Some(cell) = stack.pop() {
+        for i in 0..cell.references_count() {
+            stack.push(cell.reference(i).unwrap());
+        }
+        cells.insert(cell.repr_hash(), cell);
+    }
+    TestCellsSource { cells, loads: Default::default(), fail_after }
+}
+
+#[test]
+fn test_state_serializer_resume() {
+    let cell = |value: usize, bits: usize, references: &[ton_types::Cell]| {
+        let mut builder = ton_types::BuilderData::new();
+        builder.append_bits(value, bits).unwrap();
+        for reference in references {
+            builder.checked_append_reference(reference.clone()).unwrap();
+        }
+        builder.into_cell().unwrap()
+    };
+    // Leaves have odd bit lengths, one of them is shared by all upper cells
+    let mut level = (0..64).map(|i| cell(i, 7 + i % 9, &[])).collect::<Vec<_>>();
+    let shared = level[3].clone();
+    while level.len() > 1 {
+        let len = level.len();
+        level = level.chunks(2).enumerate()
+            .map(|(i, pair)| {
+                cell(len * 100 + i, 17, &[pair[0].clone(), pair[1].clone(), shared.clone()])
+            })
+            .collect();
+    }
+    let root = level.remove(0);
+    let total = test_cells_source(&root, u64::MAX).cells.len() as u64;
+
+    let path = std::env::temp_dir().join(format!("state_serializer_{}", std::process::id()));
+    std::fs::remove_dir_all(&path).ok();
+    std::fs::create_dir_all(&path).unwrap();
+    let work_dir = path.join("parts");
+    let boc_path = path.join("state.boc");
+
+    // Interrupted serialization leaves work files
+    let source = test_cells_source(&root, total / 2);
+    let serializer = StateSerializer::with_checkpoint_cells(&source, root.repr_hash(), &work_dir, 10)
+        .unwrap();
+    assert!(serializer.serialize(&boc_path, total, 0, &|_| ()).is_err());
+    assert!(!boc_path.exists());
+
+    // Visited cells are not loaded again
+    let source = test_cells_source(&root, u64::MAX);
+    let written = std::sync::Mutex::new(0);
+    let serializer = StateSerializer::with_checkpoint_cells(&source, root.repr_hash(), &work_dir, 10)
+        .unwrap();
+    serializer.serialize(&boc_path, total, 0, &|progress| {
+        if let SerializationProgress::Writing { cells, .. } = progress {
+            *written.lock().unwrap() = cells;
+        }
+    }).unwrap();
+    let loads = source.loads.load(std::sync::atomic::Ordering::Relaxed);
+    assert!(loads < total, "{} loads of {} cells", loads, total);
+    assert_eq!(*written.lock().unwrap(), total);
+    assert!(!work_dir.exists());
+
+    let data = std::fs::read(&boc_path).unwrap();
+    let restored = ton_types::deserialize_tree_of_cells(&mut std::io::Cursor::new(&data)).unwrap();
+    assert_eq!(restored.repr_hash(), root.repr_hash());
+    std::fs::remove_dir_all(&path).ok();
+}
@@ -0,0 +1,148 @@
+use crate::{db_impl_base, db::traits::KvcTransactional, traits::Serializable};
+use std::{convert::TryInto, io::{Read, Write}};
+use ton_block::BlockIdExt;
+use ton_types::{ByteOrderRead, Result, UInt256};
+
+db_impl_base!(TransactionsIndexDb, KvcTransactional, Vec<u8>);
+
+// Key: workchain id (4 bytes BE), account id (32 bytes), lt (8 bytes BE),
+// so transactions of an account are ordered by lt
+const ACCOUNT_PREFIX_LEN: usize = 36;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexedTransaction {
+    pub lt: u64,
+    pub hash: UInt256,
+    pub block_id: BlockIdExt,
+    pub gen_utime: u32,
+}
+
+impl IndexedTransaction {
+    fn serialize_value<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(self.hash.as_slice())?;
+        self.block_id.serialize(writer)?;
+        self.gen_utime.serialize(writer)
+    }
+
+    fn deserialize_value<R: Read>(lt: u64, reader: &mut R) -> Result<Self> {
+        let hash = UInt256::from(reader.read_u256()?);
+        let block_id = BlockIdExt::deserialize(reader)?;
+        let gen_utime = u32::deserialize(reader)?;
+        Ok(Self { lt, hash, block_id, gen_utime })
+    }
+}
+
+impl TransactionsIndexDb {
+
+    fn account_prefix(workchain_id: i32, account: &UInt256) -> Vec<u8> {
+        let mut key = Vec::with_capacity(ACCOUNT_PREFIX_LEN + 8);
+        key.extend_from_slice(&workchain_id.to_be_bytes());
+        key.extend_from_slice(account.as_slice());
+        key
+    }
+
+    fn key(workchain_id: i32, account: &UInt256, lt: u64) -> Vec<u8> {
+        let mut key = Self::account_prefix(workchain_id, account);
+        key.extend_from_slice(&lt.to_be_bytes());
+        key
+    }
+
+    /// Adds transactions (account, lt, hash) of one block with a single batch
+    pub fn add_block_transactions(
+        &self,
+        block_id: &BlockIdExt,
+        gen_utime: u32,
+        transactions: &[(UInt256, u64, UInt256)]
+    ) -> Result<()> {
+        let workchain_id = block_id.shard().workchain_id();
+        let mut transaction = self.begin_transaction()?;
+        for (account, lt, hash) in transactions {
+            let tr = IndexedTransaction { lt: *lt, hash: hash.clone(), block_id: block_id.clone(), gen_utime };
+            let mut value = Vec::new();
+            tr.serialize_value(&mut value)?;
+            transaction.put(&Self::key(workchain_id, account, *lt), &value);
+        }
+        transaction.commit()
+    }
+
+    /// Returns up to `limit` transactions of account with lt not greater than `from_lt`,
+    /// the newest go first
+    pub fn account_transactions(
+        &self,
+        workchain_id: i32,
+        account: &UInt256,
+        from_lt: u64,
+        limit: usize
+    ) -> Result<Vec<IndexedTransaction>> {
+        let prefix = Self::account_prefix(workchain_id, account);
+        let mut result = Vec::new();
+        if limit == 0 {
+            return Ok(result)
+        }
+        self.for_each_descending(&Self::key(workchain_id, account, from_lt), &mut |key, value| {
+            if key.len() != ACCOUNT_PREFIX_LEN + 8 || !key.starts_with(&prefix) {
+                return Ok(false)
+            }
+            let lt = u64::from_be_bytes(key[ACCOUNT_PREFIX_LEN..].try_into()?);
+            result.push(IndexedTransaction::deserialize_value(lt, &mut std::io::Cursor::new(value))?);
+            Ok(result.len() < limit)
+        })?;
+        Ok(result)
+    }
+
+    /// Removes transactions of blocks generated before given time, returns their count
+    pub fn prune(&self, gen_utime: u32) -> Result<u64> {
+        let mut pruned = Vec::new();
+        self.for_each(&mut |key, value| {
+            let mut reader = std::io::Cursor::new(value);
+            reader.read_u256()?;
+            BlockIdExt::deserialize(&mut reader)?;
+            if u32::deserialize(&mut reader)? < gen_utime {
+                pruned.push(key.to_vec());
+            }
+            Ok(true)
+        })?;
+        let mut transaction = self.begin_transaction()?;
+        for key in pruned.iter() {
+            transaction.delete(key);
+        }
+        transaction.commit()?;
+        Ok(pruned.len() as u64)
+    }
+}
+
+#[test]
+fn test_account_transactions() {
+    use ton_block::ShardIdent;
+
+    let db = TransactionsIndexDb::in_memory();
+    let block_id = |seq_no: u32| BlockIdExt::with_params(
+        ShardIdent::with_tagged_prefix(0, 0x8000_0000_0000_0000).unwrap(),
+        seq_no, UInt256::from_slice(&[seq_no as u8; 32]), UInt256::default()
+    );
+    let account = UInt256::from_slice(&[1; 32]);
+    let other = UInt256::from_slice(&[2; 32]);
+    for seq_no in 1..=3u32 {
+        let lt = seq_no as u64 * 1000;
+        db.add_block_transactions(&block_id(seq_no), seq_no * 10, &[
+            (account.clone(), lt, UInt256::from_slice(&[10 + seq_no as u8; 32])),
+            (account.clone(), lt + 1, UInt256::from_slice(&[20 + seq_no as u8; 32])),
+            (other.clone(), lt, UInt256::from_slice(&[30 + seq_no as u8; 32])),
+        ]).unwrap();
+    }
+
+    let lts = |from_lt, limit| db.account_transactions(0, &account, from_lt, limit).unwrap()
+        .iter().map(|tr| tr.lt).collect::<Vec<_>>();
+    assert_eq!(lts(u64::MAX, 10), vec!(3001, 3000, 2001, 2000, 1001, 1000));
+    assert_eq!(lts(2000, 2), vec!(2000, 1001));
+    assert_eq!(lts(999, 10), Vec::<u64>::new());
+    assert!(db.account_transactions(-1, &account, u64::MAX, 10).unwrap().is_empty());
+
+    let tr = &db.account_transactions(0, &account, 2001, 1).unwrap()[0];
+    assert_eq!(tr.block_id, block_id(2));
+    assert_eq!(tr.hash, UInt256::from_slice(&[22; 32]));
+
+    // Transactions of blocks 1 and 2 for both accounts
+    assert_eq!(db.prune(30).unwrap(), 6);
+    assert_eq!(lts(u64::MAX, 10), vec!(3001, 3000));
+}
@@ -133,6 +133,34 @@ impl BlockHandle {
         self.set_flag(FLAG_APPLIED)
     }
 
+    // Block data and proofs are pruned, so their flags are reset. Returns true if any was set.
+    pub fn reset_data_and_proofs(&self) -> bool {
+        Self::reset_data_and_proofs_in_meta(&self.meta)
+    }
+
+    // Block is going to be applied again, e.g. after its state is found broken.
+    // Returns true if any flag was set.
+    pub fn reset_applied_and_state(&self) -> bool {
+        let flags = FLAG_APPLIED | FLAG_STATE;
+        (self.meta.reset_flags(flags) & flags) != 0
+    }
+
+    // Persistent state is removed by retention settings. Returns true if flag was set.
+    pub fn reset_persistent_state(&self) -> bool {
+        (self.meta.reset_flags(FLAG_PERSISTENT_STATE) & FLAG_PERSISTENT_STATE) != 0
+    }
+
+    pub(crate) fn reset_data_and_proofs_in_meta(meta: &BlockMeta) -> bool {
+        let flags = FLAG_DATA | FLAG_PROOF | FLAG_PROOF_LINK;
+        (meta.reset_flags(flags) & flags) != 0
+    }
+
+    pub(crate) fn is_pruning_candidate(meta: &BlockMeta) -> bool {
+        let flags = meta.flags();
+        (flags & FLAG_MOVED_TO_ARCHIVE != 0) && (flags & FLAG_KEY_BLOCK == 0) &&
+            (flags & (FLAG_DATA | FLAG_PROOF | FLAG_PROOF_LINK) != 0)
+    }
+
     pub fn id(&self) -> &BlockIdExt {
         &self.id
     }
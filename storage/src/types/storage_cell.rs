@@ -2,7 +2,7 @@ use crate::{dynamic_boc_db::DynamicBocDb, types::{CellId, Reference}};
 use std::{io::{Cursor, Write}, sync::{Arc, RwLock, atomic::{AtomicU64, Ordering}}};
 use ton_types::{
     ByteOrderRead, Cell, CellData, CellImpl, CellType, LevelMask,
-    Result, UInt256,
+    Result, UInt256, error,
     MAX_LEVEL, MAX_REFERENCES_COUNT
 };
 
@@ -88,6 +88,14 @@ impl StorageCell {
         self.hash(MAX_LEVEL as usize)
     }
 
+    /// Gets hash of child cell without loading it
+    pub fn reference_hash(&self, index: usize) -> Result<UInt256> {
+        self.references.read().expect("Poisoned RwLock")
+            .get(index)
+            .map(|reference| reference.hash())
+            .ok_or_else(|| error!("Reference index {} is out of range", index))
+    }
+
     pub(crate) fn reference(&self, index: usize) -> Result<Arc<StorageCell>> {
         let hash = match &self.references.read().expect("Poisoned RwLock")[index] {
             Reference::Loaded(cell) => return Ok(Arc::clone(cell)),